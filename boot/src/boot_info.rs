@@ -0,0 +1,123 @@
+//! Kernel Boot Info Handoff
+
+use crate::uefi::{
+    EFI_ACPI_20_TABLE_GUID, EFI_ACPI_TABLE_GUID, EFI_SYSTEM_TABLE,
+    console::EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+};
+use crate::memory_setup::{BootMemory, PAGE_TABLE_PAGES, STACK_PAGES};
+use crate::println;
+
+pub use cosmos_bootinfo::{BootInfo, BOOT_INFO_ADDRESS, BOOT_INFO_MAGIC, BOOT_INFO_VERSION, MEMORY_MAP_ADDRESS};
+use cosmos_bootinfo::{
+    encode_version, BOOT_MODE_UEFI, CONSOLE_SERIAL, FIRMWARE_VENDOR_SIZE,
+    LOADER_BOOT_MEMORY, LOADER_KERNEL_HEADER, LOADER_SERIAL_LOG,
+};
+
+/// Decimal digits of a Cargo version component
+const fn number(digits: &str) -> u64 {
+    let digits = digits.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits[i] - b'0') as u64;
+        i += 1;
+    }
+    value
+}
+
+/// This loader's version, passed to the kernel
+pub const LOADER_VERSION: u64 = encode_version(
+    number(env!("CARGO_PKG_VERSION_MAJOR")),
+    number(env!("CARGO_PKG_VERSION_MINOR")),
+    number(env!("CARGO_PKG_VERSION_PATCH")),
+);
+
+/// Find the RSDP in the configuration tables, preferring ACPI 2.0
+unsafe fn find_rsdp(system_table: *mut EFI_SYSTEM_TABLE) -> u64 {
+    let tables = core::slice::from_raw_parts(
+        (*system_table).configuration_table,
+        (*system_table).number_of_table_entries,
+    );
+    let find = |guid| {
+        tables
+            .iter()
+            .find(|table| table.vendor_guid.matches(guid))
+            .map_or(0, |table| table.vendor_table as u64)
+    };
+    match find(&EFI_ACPI_20_TABLE_GUID) {
+        0 => find(&EFI_ACPI_TABLE_GUID),
+        rsdp => rsdp,
+    }
+}
+
+/// Firmware vendor from the system table, non-ASCII characters as '?'
+unsafe fn firmware_vendor(system_table: *mut EFI_SYSTEM_TABLE) -> [u8; FIRMWARE_VENDOR_SIZE] {
+    let mut vendor = [0u8; FIRMWARE_VENDOR_SIZE];
+    let mut name = (*system_table).firmware_vendor;
+    if name.is_null() {
+        return vendor;
+    }
+    // Leave the last byte NUL so the kernel always finds a terminator
+    for byte in vendor.iter_mut().take(FIRMWARE_VENDOR_SIZE - 1) {
+        match *name {
+            0 => break,
+            c @ 0x20..=0x7E => *byte = c as u8,
+            _ => *byte = b'?',
+        }
+        name = name.add(1);
+    }
+    vendor
+}
+
+/// Store the boot info block for the kernel
+///
+/// Points at the E820 map already stored at 0x9000, at the page tables and
+/// stack the kernel is entered on, and at the kernel image itself. Also
+/// records the Secure Boot state the loader saw and which firmware and
+/// consoles the kernel is left with. After ExitBootServices the screen is
+/// whatever GOP mode firmware chose, never VGA text, so only COM1 is
+/// offered.
+pub unsafe fn store_boot_info(
+    system_table: *mut EFI_SYSTEM_TABLE,
+    e820_count: usize,
+    boot_memory: &BootMemory,
+    kernel_base: u64,
+    kernel_size: u64,
+    secure_boot: u64,
+    console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+) {
+    let rsdp = find_rsdp(system_table);
+    *(BOOT_INFO_ADDRESS as *mut BootInfo) = BootInfo {
+        magic: BOOT_INFO_MAGIC,
+        version: BOOT_INFO_VERSION,
+        memory_map_count: e820_count as u32,
+        memory_map: MEMORY_MAP_ADDRESS + 4,
+        rsdp,
+        command_line: 0,
+        uefi_runtime: (*system_table).runtime_services as u64,
+        page_tables: boot_memory.page_tables,
+        page_tables_size: (PAGE_TABLE_PAGES * 4096) as u64,
+        stack: boot_memory.stack,
+        stack_size: (STACK_PAGES * 4096) as u64,
+        loader_version: LOADER_VERSION,
+        loader_capabilities: LOADER_BOOT_MEMORY | LOADER_KERNEL_HEADER
+            | if crate::serial::is_ready() { LOADER_SERIAL_LOG } else { 0 },
+        kernel_base,
+        kernel_size,
+        secure_boot,
+        boot_mode: BOOT_MODE_UEFI,
+        firmware_revision: (*system_table).firmware_revision,
+        consoles: if crate::serial::is_ready() { CONSOLE_SERIAL } else { 0 },
+        firmware_vendor: firmware_vendor(system_table),
+    };
+    
+    println!(console, "Boot info stored at 0x8000");
+    if rsdp == 0 {
+        println!(console, "No ACPI RSDP in configuration tables");
+    }
+}
+
+/// Drop the runtime services pointer when they could not be virtualized
+pub unsafe fn clear_uefi_runtime() {
+    (*(BOOT_INFO_ADDRESS as *mut BootInfo)).uefi_runtime = 0;
+}
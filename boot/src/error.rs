@@ -63,14 +63,6 @@ pub unsafe fn display_simple_error_and_halt(
 
 /// Pprint EFI_STATUS as hexadecimal
 unsafe fn print_hex_status(console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL, status: EFI_STATUS) {
-    let hex_chars = b"0123456789ABCDEF";
-    let mut buffer = [0u16; 17]; // 16 hex digits + null terminator
-    
-    for i in 0..16 {
-        let nibble = (status >> (60 - i * 4)) & 0xF;
-        buffer[i] = hex_chars[nibble] as u16;
-    }
-    buffer[16] = 0; // Null terminator
-    
-    ((*console).output_string)(console, buffer.as_ptr());
+    let mut buffer = [0; cosmos_common::fmt::HEX_DIGITS];
+    crate::uefi::console::print(console, cosmos_common::fmt::format_hex(status as u64, 16, &mut buffer));
 }
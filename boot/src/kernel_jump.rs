@@ -1,92 +1,87 @@
 //! Kernel Jump Module
 
 use crate::uefi::{
-    EFI_BOOT_SERVICES, EFI_HANDLE, EFI_SUCCESS,
+    EFI_BOOT_SERVICES, EFI_HANDLE, EFI_RUNTIME_SERVICES, EFI_STATUS, EFI_SUCCESS,
     console::EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+    memory::{EFI_MEMORY_DESCRIPTOR, EFI_MEMORY_RUNTIME},
 };
-use crate::{println, error};
+use crate::memory_setup::{self, MemoryMapInfo};
+use crate::{boot_info, println, error, log};
 
 /// Static buffer for memory map during boot services exit
 static mut EXIT_MEMORY_MAP_BUFFER: [u8; 8192] = [0; 8192];
 
-/// Initialize COM1 serial port for bare-metal
-pub fn init_serial() {
-    unsafe {
-        // Disable interrupts
-        outb(0x3F9, 0x00);
-        // Enable DLAB (set baud rate divisor)
-        outb(0x3FB, 0x80);
-        // Set divisor to 3 (38400 baud)
-        outb(0x3F8, 0x03);
-        outb(0x3F9, 0x00);
-        // 8 bits, no parity, one stop bit
-        outb(0x3FB, 0x03);
-        // Enable FIFO
-        outb(0x3FA, 0xC7);
-        // IRQs enabled, RTS/DSR set
-        outb(0x3FC, 0x0B);
-    }
-}
-
-/// Write a string directly to COM1 serial port
-pub fn serial_write_str(s: &str) {
-    unsafe {
-        for byte in s.bytes() {
-            // Wait for transmit buffer to be empty
-            while (inb(0x3FD) & 0x20) == 0 {}
-            outb(0x3F8, byte);
+/// Identity map runtime services so the kernel can call them
+///
+/// Must run after ExitBootServices with the map that was used to exit.
+unsafe fn virtualize_runtime_services(
+    runtime_services: *mut EFI_RUNTIME_SERVICES,
+    map: *mut u8,
+    map_size: usize,
+    descriptor_size: usize,
+    descriptor_version: u32,
+) -> EFI_STATUS {
+    for i in 0..map_size / descriptor_size {
+        let desc = map.add(i * descriptor_size) as *mut EFI_MEMORY_DESCRIPTOR;
+        if (*desc).attribute & EFI_MEMORY_RUNTIME != 0 {
+            (*desc).virtual_start = (*desc).physical_start;
         }
     }
-}
-
-#[inline(always)]
-unsafe fn outb(port: u16, value: u8) {
-    core::arch::asm!(
-        "out dx, al",
-        in("dx") port,
-        in("al") value,
-        options(nomem, nostack, preserves_flags)
-    );
-}
-
-#[inline(always)]
-unsafe fn inb(port: u16) -> u8 {
-    let value: u8;
-    core::arch::asm!(
-        "in al, dx",
-        out("al") value,
-        in("dx") port,
-        options(nomem, nostack, preserves_flags)
-    );
-    value
+    ((*runtime_services).set_virtual_address_map)(
+        map_size,
+        descriptor_size,
+        descriptor_version,
+        map as *mut EFI_MEMORY_DESCRIPTOR,
+    )
 }
 
 /// Exit UEFI boot services and immediately set up CPU for kernel
 pub unsafe fn exit_boot_services_and_setup_cpu(
     boot_services: *mut EFI_BOOT_SERVICES,
+    runtime_services: *mut EFI_RUNTIME_SERVICES,
     image_handle: EFI_HANDLE,
-    map_key: usize,
+    memory_info: &MemoryMapInfo,
     console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
     page_table_base: u64,
     stack_top: u64,
+    kernel_entry: u64,
 ) -> ! {
     println!(console, "Exiting UEFI boot services...");
     
     // Exit boot services
-    let mut current_map_key = map_key;
+    let mut current_map_key = memory_info.map_key;
+    let mut current_map = memory_setup::memory_map_buffer();
+    let mut current_map_size = memory_info.map_size;
+    let mut current_descriptor_size = memory_info.descriptor_size;
+    let mut current_descriptor_version = memory_info.descriptor_version;
     let max_retries = 3;
     
+    // The console may be gone after the first attempt, even a failed one
+    crate::uefi::console::clear_panic_console();
     for attempt in 0..max_retries {
         let status = ((*boot_services).exit_boot_services)(image_handle, current_map_key);
         
         if status == EFI_SUCCESS {
             
-            // Initialize serial immediately
-            init_serial();
-            serial_write_str("\nCosmosBootloaderUEFI\n");
+            // Only COM1 is left from here on
+            log!("Boot services exited");
+            
+            // Runtime services still run on the firmware page tables here
+            log!("Mapping runtime services...");
+            let status = virtualize_runtime_services(
+                runtime_services,
+                current_map,
+                current_map_size,
+                current_descriptor_size,
+                current_descriptor_version,
+            );
+            if status != EFI_SUCCESS {
+                log!("SetVirtualAddressMap failed, runtime services disabled");
+                boot_info::clear_uefi_runtime();
+            }
             
             // Load our page tables
-            serial_write_str("Loading page tables into CR3...\n");
+            log!("Loading page tables into CR3 at {:#x}...", page_table_base);
             core::arch::asm!(
                 "mov cr3, {}",
                 in(reg) page_table_base,
@@ -94,7 +89,7 @@ pub unsafe fn exit_boot_services_and_setup_cpu(
             );
             
             // Set up CPU state
-            serial_write_str("Setting up CPU state...\n");
+            log!("Setting up CPU state, stack top {:#x}...", stack_top);
             core::arch::asm!("cli", options(nomem, nostack));
             core::arch::asm!(
                 "mov rsp, {}",
@@ -102,13 +97,14 @@ pub unsafe fn exit_boot_services_and_setup_cpu(
                 options(nomem)
             );
             core::arch::asm!("cld", options(nomem, nostack));
-            serial_write_str("Jumping to kernel...\n");
+            log!("Jumping to kernel at {:#x}...", kernel_entry);
             
-            // Jump to kernel
-            jump_to_kernel(0x200000);
+            // Jump to kernel, the header at its start branches to _start
+            jump_to_kernel(kernel_entry, boot_info::BOOT_INFO_ADDRESS);
         }
         
         // Failed, try to get updated memory map
+        log!("ExitBootServices attempt {} failed: {}", attempt + 1, error::status_to_string(status));
         if attempt < max_retries - 1 {
             let mut map_size = EXIT_MEMORY_MAP_BUFFER.len();
             let mut new_map_key: usize = 0;
@@ -125,6 +121,10 @@ pub unsafe fn exit_boot_services_and_setup_cpu(
             
             if map_status == EFI_SUCCESS {
                 current_map_key = new_map_key;
+                current_map = (&raw mut EXIT_MEMORY_MAP_BUFFER).cast();
+                current_map_size = map_size;
+                current_descriptor_size = descriptor_size;
+                current_descriptor_version = descriptor_version;
                 continue;
             }
         }
@@ -151,6 +151,9 @@ pub unsafe fn exit_boot_services_and_jump(
     let max_retries = 3;
     let mut last_status = EFI_SUCCESS;
     
+    // The console may be gone after the first attempt, even a failed one
+    crate::uefi::console::clear_panic_console();
+    
     // Try to exit boot services, with retry logic
     for attempt in 0..max_retries {
         let status = ((*boot_services).exit_boot_services)(image_handle, current_map_key);
@@ -219,7 +222,7 @@ pub unsafe fn setup_cpu_state_minimal(stack_top: u64) {
     core::arch::asm!("cli", options(nomem, nostack));
     
     // Set stack pointer to top of stack
-    // Stack grows downward from 0xA0000 to 0x90000 (64KB)
+    // Stack grows downward from the top of its allocation
     core::arch::asm!(
         "mov rsp, {}",
         in(reg) stack_top,
@@ -230,17 +233,16 @@ pub unsafe fn setup_cpu_state_minimal(stack_top: u64) {
     core::arch::asm!("cld", options(nomem, nostack));
 }
 
-/// Jump to kernel entry point
+/// Jump to kernel entry point, passing the boot info address in RDI
 #[inline(never)]
-pub unsafe fn jump_to_kernel(kernel_entry: u64) -> ! {
-    // Clear all general-purpose registers except RSP
+pub unsafe fn jump_to_kernel(kernel_entry: u64, boot_info: u64) -> ! {
+    // Clear all general-purpose registers except RSP, RAX and RDI, then
+    // indirect jmp rax
     core::arch::asm!(
-        "xor rax, rax",
         "xor rbx, rbx",
         "xor rcx, rcx",
         "xor rdx, rdx",
         "xor rsi, rsi",
-        "xor rdi, rdi",
         "xor r8, r8",
         "xor r9, r9",
         "xor r10, r10",
@@ -249,14 +251,9 @@ pub unsafe fn jump_to_kernel(kernel_entry: u64) -> ! {
         "xor r13, r13",
         "xor r14, r14",
         "xor r15, r15",
-        options(nomem, nostack)
-    );
-    
-    // Jump to kernel entry point, indirect jmp rax
-    core::arch::asm!(
-        "mov rax, {entry}",
         "jmp rax",
-        entry = in(reg) kernel_entry,
+        in("rax") kernel_entry,
+        in("rdi") boot_info,
         options(noreturn)
     );
 }
@@ -10,11 +10,15 @@ use crate::uefi::{
 };
 use crate::{println, error};
 use core::ffi::c_void;
+use core::fmt::Write;
+use cosmos_bootinfo::{KernelHeader, BOOT_INFO_VERSION};
+use cosmos_common::fmt::FixedBuf;
 
 /// Kernel buffer information
 pub struct KernelBuffer {
     pub data_ptr: *const u8,
     pub size: usize,
+    pub header: KernelHeader,
 }
 
 /// Locate the File System Protocol
@@ -140,6 +144,9 @@ pub unsafe fn get_file_size(
     file_size
 }
 
+/// Bytes read from the kernel file per call
+const READ_CHUNK: usize = 1024 * 1024;
+
 /// Read kernel file into buffer
 pub unsafe fn read_kernel_into_buffer(
     file: *mut EFI_FILE_PROTOCOL,
@@ -170,25 +177,37 @@ pub unsafe fn read_kernel_into_buffer(
         );
     }
     
-    // Read file into buffer
-    let mut read_size = file_size;
-    let status = ((*file).read)(file, &mut read_size, buffer);
-    
-    if status != EFI_SUCCESS {
-        ((*boot_services).free_pool)(buffer);
-        error::display_error_and_halt(
-            console,
-            "Failed to read kernel file from disk",
-            status,
-        );
-    }
-    
-    if read_size != file_size {
-        ((*boot_services).free_pool)(buffer);
-        error::display_simple_error_and_halt(
-            console,
-            "Incomplete kernel read - File size mismatch",
-        );
+    // Read file into buffer a chunk at a time, some firmware fails large
+    // reads and the progress shows where a slow disk is
+    let mut offset = 0;
+    let mut reported = 0;
+    while offset < file_size {
+        let mut read_size = (file_size - offset).min(READ_CHUNK);
+        let status = ((*file).read)(file, &mut read_size, buffer.add(offset));
+        
+        if status != EFI_SUCCESS {
+            ((*boot_services).free_pool)(buffer);
+            error::display_error_and_halt(
+                console,
+                "Failed to read kernel file from disk",
+                status,
+            );
+        }
+        
+        if read_size == 0 {
+            ((*boot_services).free_pool)(buffer);
+            error::display_simple_error_and_halt(
+                console,
+                "Incomplete kernel read - File size mismatch",
+            );
+        }
+        
+        offset += read_size;
+        let percent = offset * 100 / file_size;
+        if percent >= reported + 10 || offset == file_size {
+            println!(console, "  {}% ({} of {} bytes)", percent, offset, file_size);
+            reported = percent;
+        }
     }
     
     buffer
@@ -226,74 +245,62 @@ pub unsafe fn load_kernel_from_esp_root(
     
     println!(console, "Kernel loaded successfully");
     
-    // Verify CosmOS signature
-    if !verify_cosmos_signature(buffer, file_size) {
-        ((*boot_services).free_pool)(buffer);
-        error::display_simple_error_and_halt(
-            console,
-            "Kernel verification failed - CosmOS signature not found",
-        );
-    }
+    // Refuse kernels this loader cannot hand over to
+    let header = match check_kernel_header(buffer, file_size, console) {
+        Ok(header) => header,
+        Err(message) => {
+            ((*boot_services).free_pool)(buffer);
+            error::display_simple_error_and_halt(console, message.as_str());
+        }
+    };
     
-    println!(console, "Kernel signature verified");
+    println!(console, "Kernel header verified");
     
     KernelBuffer {
         data_ptr: buffer as *const u8,
         size: file_size,
+        header,
     }
 }
 
-/// Verify CosmOS kernel signature (0xFxxFxxFxxFC05305)
-unsafe fn verify_cosmos_signature(buffer: *const u8, size: usize) -> bool {
-    const COSMOS_MAGIC: u32 = 0x0FC05305; // Lower 28 bits of signature
-    
-    // Search first 64KB or entire kernel if smaller
-    let search_size = if size > 65536 { 65536 } else { size };
-    
-    // Search for signature, 8 byte aligned
-    let mut offset = 0;
-    while offset + 8 <= search_size {
-        let ptr = buffer.add(offset) as *const u64;
-        let value = *ptr;
-        
-        // Check if lower 28 bits match CosmOS magic, 0x0FFFFFFF mask
-        if (value & 0x0FFFFFFF) as u32 == COSMOS_MAGIC {
-            return true;
-        }
-        offset += 8;
+/// Check the kernel header at the start of the image
+///
+/// Refuses images that are not CosmOS kernels, and kernels built for a
+/// different boot info version than this loader writes.
+unsafe fn check_kernel_header(
+    buffer: *const u8,
+    size: usize,
+    console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+) -> Result<KernelHeader, FixedBuf<128>> {
+    let mut message = FixedBuf::new();
+    if size < core::mem::size_of::<KernelHeader>() {
+        let _ = write!(message, "Kernel image is {} bytes, too small for a header", size);
+        return Err(message);
+    }
+    let header = (buffer as *const KernelHeader).read_unaligned();
+    if !header.is_valid() {
+        let _ = write!(message, "No CosmOS kernel header at the start of kernel.bin");
+        return Err(message);
     }
-    false
+    let (major, minor, patch) = header.kernel_version();
+    println!(console, "Kernel v{}.{}.{}, boot info version {}", major, minor, patch, header.boot_info_version);
+    if header.boot_info_version != BOOT_INFO_VERSION {
+        let _ = write!(
+            message,
+            "Kernel v{}.{}.{} expects boot info version {}, this loader writes version {}",
+            major, minor, patch, header.boot_info_version, BOOT_INFO_VERSION,
+        );
+        return Err(message);
+    }
+    if (size as u64) > header.memory_size {
+        let _ = write!(message, "kernel.bin is {} bytes, its header says {}", size, header.memory_size);
+        return Err(message);
+    }
+    Ok(header)
 }
 
 /// Print a number to console
 unsafe fn print_number(console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL, num: usize) {
-    let mut buffer = [0u16; 32];
-    let mut n = num;
-    let mut i = 0;
-    
-    if n == 0 {
-        buffer[0] = '0' as u16;
-        buffer[1] = 0;
-        ((*console).output_string)(console, buffer.as_ptr());
-        return;
-    }
-    
-    // Convert number to string, reverse order
-    while n > 0 {
-        buffer[i] = ('0' as u16) + ((n % 10) as u16);
-        n /= 10;
-        i += 1;
-    }
-    
-    // Reverse the string
-    let mut j = 0;
-    while j < i / 2 {
-        let temp = buffer[j];
-        buffer[j] = buffer[i - 1 - j];
-        buffer[i - 1 - j] = temp;
-        j += 1;
-    }
-    
-    buffer[i] = 0; // Null terminator
-    ((*console).output_string)(console, buffer.as_ptr());
+    let mut buffer = [0; cosmos_common::fmt::DEC_DIGITS];
+    crate::uefi::console::print(console, cosmos_common::fmt::format_u64_dec(num as u64, &mut buffer));
 }
@@ -4,20 +4,45 @@ use crate::uefi::{
     EFI_BOOT_SERVICES, EFI_SUCCESS, EFI_BUFFER_TOO_SMALL,
     console::EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
     memory::{
-        EFI_MEMORY_DESCRIPTOR, E820Entry,
+        EFI_MEMORY_DESCRIPTOR, ALLOCATE_ADDRESS, ALLOCATE_MAX_ADDRESS,
         EFI_CONVENTIONAL_MEMORY, EFI_LOADER_CODE, EFI_LOADER_DATA,
         EFI_BOOT_SERVICES_CODE, EFI_BOOT_SERVICES_DATA,
         EFI_ACPI_RECLAIM_MEMORY, EFI_ACPI_MEMORY_NVS,
-        E820_USABLE, E820_RESERVED, E820_ACPI_RECLAIMABLE, E820_ACPI_NVS,
     },
 };
+use cosmos_bootinfo::{E820Entry, Elf64Rela, KernelHeader, MemoryType, MEMORY_MAP_ADDRESS, PSTORE_ADDRESS, PSTORE_SIZE, R_X86_64_RELATIVE};
+use cosmos_common::fmt::HumanBytes;
 use crate::{println, error};
 
 /// Memory map information returned from UEFI
 pub struct MemoryMapInfo {
     pub map_key: usize,
+    pub map_size: usize,
     pub descriptor_size: usize,
     pub descriptor_count: usize,
+    pub descriptor_version: u32,
+}
+
+/// Page table frames: PML4, PDPT and up to 4 page directories
+pub const PAGE_TABLE_PAGES: usize = 6;
+/// Kernel entry stack, 64KB
+pub const STACK_PAGES: usize = 16;
+/// Boot allocations stay below 256MB, which the page tables always map
+const BOOT_ALLOCATION_LIMIT: u64 = 0x0FFF_FFFF;
+/// Alignment of a relocated kernel, one large page
+const KERNEL_ALIGNMENT: u64 = 0x200000;
+
+/// Page tables and stack the kernel is entered on
+pub struct BootMemory {
+    pub page_tables: u64,
+    pub stack: u64,
+}
+
+impl BootMemory {
+    /// Initial stack pointer, the end of the stack
+    pub fn stack_top(&self) -> u64 {
+        self.stack + (STACK_PAGES * 4096) as u64
+    }
 }
 
 /// Static buffer for memory map
@@ -25,10 +50,10 @@ static mut MEMORY_MAP_BUFFER: [u8; 8192] = [0; 8192];
 
 /// Static buffer for E820 entries, 128 entries
 static mut E820_BUFFER: [E820Entry; 128] = [E820Entry {
-    base: 0,
+    base_addr: 0,
     length: 0,
     entry_type: 0,
-    acpi: 0,
+    attributes: 0,
 }; 128];
 
 /// Get UEFI memory map
@@ -84,22 +109,169 @@ pub unsafe fn get_uefi_memory_map(
     
     MemoryMapInfo {
         map_key,
+        map_size,
         descriptor_size,
         descriptor_count,
+        descriptor_version,
+    }
+}
+
+/// Allocate `pages` pages of loader data below the boot allocation limit
+unsafe fn allocate_boot_pages(
+    boot_services: *mut EFI_BOOT_SERVICES,
+    pages: usize,
+    console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+    failure: &str,
+) -> u64 {
+    let mut address = BOOT_ALLOCATION_LIMIT;
+    let status = ((*boot_services).allocate_pages)(
+        ALLOCATE_MAX_ADDRESS,
+        EFI_LOADER_DATA,
+        pages,
+        &mut address,
+    );
+    if status != EFI_SUCCESS {
+        error::display_error_and_halt(console, failure, status);
+    }
+    address
+}
+
+/// Claim the kernel's persistent store so no boot allocation lands on it
+///
+/// Must run before the loader allocates anything. The store keeps the
+/// previous boot's console and panic record, so it is claimed as it is,
+/// never cleared. Firmware already using the range leaves the store to
+/// whatever the memory map says about it.
+pub unsafe fn reserve_pstore(
+    boot_services: *mut EFI_BOOT_SERVICES,
+    console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+) {
+    let mut address = PSTORE_ADDRESS;
+    let pages = (PSTORE_SIZE / 4096) as usize;
+    let status = ((*boot_services).allocate_pages)(ALLOCATE_ADDRESS, EFI_LOADER_DATA, pages, &mut address);
+    if status != EFI_SUCCESS {
+        println!(
+            console,
+            "Persistent store at 0x{:X} unavailable ({})",
+            PSTORE_ADDRESS,
+            error::status_to_string(status),
+        );
+    }
+}
+
+/// Allocate the page tables and kernel stack from conventional memory
+///
+/// Must run before the final memory map is read, so the map records them
+/// as loader data. Their locations go to the kernel in the boot info.
+pub unsafe fn allocate_boot_memory(
+    boot_services: *mut EFI_BOOT_SERVICES,
+    console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+) -> BootMemory {
+    let page_tables = allocate_boot_pages(
+        boot_services,
+        PAGE_TABLE_PAGES,
+        console,
+        "Failed to allocate page tables below 256MB",
+    );
+    let stack = allocate_boot_pages(
+        boot_services,
+        STACK_PAGES,
+        console,
+        "Failed to allocate kernel stack below 256MB",
+    );
+    println!(console, "Page tables at 0x");
+    print_hex_word(console, page_tables as u32);
+    println!(console, "Kernel stack at 0x");
+    print_hex_word(console, stack as u32);
+    BootMemory { page_tables, stack }
+}
+
+/// Reserve memory for the kernel image, at its link address if free
+///
+/// Falls back to a 2MB-aligned region below the boot allocation limit when
+/// firmware already uses the link address. Must run before the final
+/// memory map is read, like `allocate_boot_memory`.
+pub unsafe fn allocate_kernel(
+    boot_services: *mut EFI_BOOT_SERVICES,
+    header: &KernelHeader,
+    console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+) -> u64 {
+    let pages = (header.memory_size as usize).div_ceil(4096);
+    let mut base = header.load_address;
+    let status = ((*boot_services).allocate_pages)(ALLOCATE_ADDRESS, EFI_LOADER_DATA, pages, &mut base);
+    if status == EFI_SUCCESS {
+        println!(console, "Kernel placed at its link address 0x{:X}", base);
+        return base;
+    }
+    println!(
+        console,
+        "Link address 0x{:X} unavailable ({}), relocating the kernel",
+        header.load_address,
+        error::status_to_string(status),
+    );
+    
+    // Over-allocate by the alignment, then give back what it skipped
+    let slack = (KERNEL_ALIGNMENT / 4096) as usize;
+    let start = allocate_boot_pages(
+        boot_services,
+        pages + slack,
+        console,
+        "Failed to allocate memory for the kernel below 256MB",
+    );
+    let base = (start + KERNEL_ALIGNMENT - 1) & !(KERNEL_ALIGNMENT - 1);
+    let head = ((base - start) / 4096) as usize;
+    if head > 0 {
+        ((*boot_services).free_pages)(start, head);
+    }
+    if slack > head {
+        ((*boot_services).free_pages)(base + (pages * 4096) as u64, slack - head);
+    }
+    println!(console, "Kernel placed at 0x{:X}", base);
+    base
+}
+
+/// Patch the kernel at `base` for running there
+///
+/// Only `R_X86_64_RELATIVE` relocations are expected. They are applied
+/// even at the link address, where they change nothing.
+unsafe fn relocate_kernel(
+    header: &KernelHeader,
+    base: u64,
+    console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+) {
+    let delta = base.wrapping_sub(header.load_address);
+    let table = (base + header.rela_offset) as *const Elf64Rela;
+    let count = header.rela_size as usize / core::mem::size_of::<Elf64Rela>();
+    for i in 0..count {
+        let rela = table.add(i).read_unaligned();
+        let offset = rela.offset.wrapping_sub(header.load_address);
+        if rela.info as u32 != R_X86_64_RELATIVE || offset.saturating_add(8) > header.memory_size {
+            error::display_simple_error_and_halt(
+                console,
+                "Kernel relocation failed - Unsupported or out of range relocation",
+            );
+        }
+        ((base + offset) as *mut u64).write_unaligned((rela.addend as u64).wrapping_add(delta));
     }
+    println!(console, "Applied {} kernel relocations", count);
+}
+
+/// Buffer holding the map returned by `get_uefi_memory_map`
+pub unsafe fn memory_map_buffer() -> *mut u8 {
+    (&raw mut MEMORY_MAP_BUFFER).cast()
 }
 
 /// Convert UEFI memory type to E820 type
-fn uefi_type_to_e820(uefi_type: u32) -> u32 {
+fn uefi_type_to_e820(uefi_type: u32) -> MemoryType {
     match uefi_type {
-        EFI_CONVENTIONAL_MEMORY => E820_USABLE,
-        EFI_LOADER_CODE => E820_USABLE,
-        EFI_LOADER_DATA => E820_USABLE,
-        EFI_BOOT_SERVICES_CODE => E820_USABLE,
-        EFI_BOOT_SERVICES_DATA => E820_USABLE,
-        EFI_ACPI_RECLAIM_MEMORY => E820_ACPI_RECLAIMABLE,
-        EFI_ACPI_MEMORY_NVS => E820_ACPI_NVS,
-        _ => E820_RESERVED,
+        EFI_CONVENTIONAL_MEMORY => MemoryType::Usable,
+        EFI_LOADER_CODE => MemoryType::Usable,
+        EFI_LOADER_DATA => MemoryType::Usable,
+        EFI_BOOT_SERVICES_CODE => MemoryType::Usable,
+        EFI_BOOT_SERVICES_DATA => MemoryType::Usable,
+        EFI_ACPI_RECLAIM_MEMORY => MemoryType::AcpiReclaimable,
+        EFI_ACPI_MEMORY_NVS => MemoryType::AcpiNvs,
+        _ => MemoryType::Reserved,
     }
 }
 
@@ -134,7 +306,7 @@ pub unsafe fn convert_uefi_to_e820(
         // Try to merge with previous entry if same type and adjacent
         if e820_count > 0 {
             let prev = &mut E820_BUFFER[e820_count - 1];
-            if prev.entry_type == e820_type && prev.base + prev.length == base {
+            if prev.entry_type == e820_type as u32 && prev.end() == base {
                 // Merge with previous entry
                 prev.length += length;
                 continue;
@@ -142,16 +314,13 @@ pub unsafe fn convert_uefi_to_e820(
         }
         
         // Create new E820 entry
-        E820_BUFFER[e820_count] = E820Entry {
-            base,
-            length,
-            entry_type: e820_type,
-            acpi: 0,
-        };
+        E820_BUFFER[e820_count] = E820Entry::new(base, length, e820_type);
         e820_count += 1;
     }
     
-    e820_count
+    // Firmware need not sort its map, and may overlap entries
+    let raw = E820_BUFFER;
+    cosmos_bootinfo::sanitize_memory_map(&raw[..e820_count], &mut *(&raw mut E820_BUFFER))
 }
 
 /// Store E820 memory map at physical address 0x9000
@@ -159,14 +328,12 @@ pub unsafe fn store_e820_map(
     e820_count: usize,
     console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
 ) {
-    const E820_MAP_ADDRESS: usize = 0x9000;
-    
     // Write entry count at 0x9000 (first 4 bytes)
-    let count_ptr = E820_MAP_ADDRESS as *mut u32;
+    let count_ptr = MEMORY_MAP_ADDRESS as *mut u32;
     *count_ptr = e820_count as u32;
     
     // Write E820 entries starting at 0x9004
-    let entries_ptr = (E820_MAP_ADDRESS + 4) as *mut E820Entry;
+    let entries_ptr = (MEMORY_MAP_ADDRESS + 4) as *mut E820Entry;
     for i in 0..e820_count {
         *entries_ptr.add(i) = E820_BUFFER[i];
     }
@@ -176,16 +343,41 @@ pub unsafe fn store_e820_map(
     print_decimal(console, e820_count);
 }
 
-/// Copy kernel from UEFI buffer to final address
+/// Check that `base..base + size` is loader data in the memory map
+///
+/// The kernel's region must be what `allocate_kernel` got from firmware,
+/// not memory that firmware or runtime services still use.
+unsafe fn is_loader_data(memory_info: &MemoryMapInfo, base: u64, size: u64) -> bool {
+    let end = base.saturating_add(size);
+    let mut covered = base;
+    // The map need not be sorted, find the descriptor holding each next byte
+    while covered < end {
+        let next = (0..memory_info.descriptor_count)
+            .map(|i| &*(MEMORY_MAP_BUFFER.as_ptr().add(i * memory_info.descriptor_size) as *const EFI_MEMORY_DESCRIPTOR))
+            .find(|desc| desc.physical_start <= covered && covered < desc.physical_start + desc.number_of_pages * 4096);
+        match next {
+            Some(desc) if desc.memory_type == EFI_LOADER_DATA => {
+                covered = desc.physical_start + desc.number_of_pages * 4096;
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Copy kernel from UEFI buffer to the base from `allocate_kernel`
+///
+/// Clears .bss and applies the kernel's relocations for that base. Any
+/// size works as long as the destination checks out in the memory map.
 pub unsafe fn copy_kernel_to_final_address(
     kernel_ptr: *const u8,
     kernel_size: usize,
+    header: &KernelHeader,
+    base: u64,
+    memory_info: &MemoryMapInfo,
     console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
 ) {
-    const KERNEL_LOAD_ADDRESS: usize = 0x200000;
-    const MAX_KERNEL_SIZE: usize = 10 * 1024 * 1024; // 10MB
-    
-    println!(console, "Copying kernel to 0x200000...");
+    println!(console, "Copying kernel to 0x{:X}...", base);
     
     // Verify source pointer is valid
     if kernel_ptr.is_null() {
@@ -203,19 +395,22 @@ pub unsafe fn copy_kernel_to_final_address(
         );
     }
     
-    if kernel_size > MAX_KERNEL_SIZE {
+    if !is_loader_data(memory_info, base, header.memory_size) {
         error::display_simple_error_and_halt(
             console,
-            "Kernel size exceeds maximum (10MB) - Kernel too large",
+            "Kernel destination is not loader memory in the UEFI memory map",
         );
     }
     
     // Get destination pointer
-    let dest_ptr = KERNEL_LOAD_ADDRESS as *mut u8;
+    let dest_ptr = base as *mut u8;
     
     // Copy kernel byte by byte
     core::ptr::copy_nonoverlapping(kernel_ptr, dest_ptr, kernel_size);
     
+    // .bss is not in kernel.bin
+    core::ptr::write_bytes(dest_ptr.add(kernel_size), 0, header.memory_size as usize - kernel_size);
+    
     // Verify copy by checking first few bytes
     let verify_ok = {
         let mut ok = true;
@@ -235,12 +430,12 @@ pub unsafe fn copy_kernel_to_final_address(
         );
     }
     
-    println!(console, "Kernel copied successfully (");
-    print_decimal(console, kernel_size);
-    println!(console, " bytes)");
+    println!(console, "Kernel copied successfully ({})", HumanBytes(kernel_size as u64));
+    
+    relocate_kernel(header, base, console);
     
     // Display first 4 bytes for verification
-    println!(console, "First bytes at 0x200000: 0x");
+    println!(console, "First bytes at 0x{:X}: 0x", base);
     print_hex_byte(console, *dest_ptr);
     print_hex_byte(console, *dest_ptr.add(1));
     print_hex_byte(console, *dest_ptr.add(2));
@@ -250,14 +445,8 @@ pub unsafe fn copy_kernel_to_final_address(
 
 /// Print a hexadecimal byte
 unsafe fn print_hex_byte(console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL, byte: u8) {
-    let hex_chars = b"0123456789ABCDEF";
-    let mut buffer = [0u16; 3]; // 2 hex digits + null terminator
-    
-    buffer[0] = hex_chars[(byte >> 4) as usize] as u16;
-    buffer[1] = hex_chars[(byte & 0x0F) as usize] as u16;
-    buffer[2] = 0; // Null terminator
-    
-    ((*console).output_string)(console, buffer.as_ptr());
+    let mut buffer = [0; cosmos_common::fmt::HEX_DIGITS];
+    crate::uefi::console::print(console, cosmos_common::fmt::format_hex(byte as u64, 2, &mut buffer));
 }
 
 /// Calculate total physical memory from UEFI memory map
@@ -283,29 +472,8 @@ unsafe fn calculate_total_memory(descriptor_size: usize, descriptor_count: usize
 
 /// Print a decimal number
 unsafe fn print_decimal(console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL, num: usize) {
-    if num == 0 {
-        let mut buffer = [b'0' as u16, 0];
-        ((*console).output_string)(console, buffer.as_ptr());
-        return;
-    }
-    
-    let mut buffer = [0u16; 21]; // Max 20 digits + null terminator
-    let mut n = num;
-    let mut i = 0;
-    
-    while n > 0 {
-        buffer[i] = (b'0' + (n % 10) as u8) as u16;
-        n /= 10;
-        i += 1;
-    }
-    
-    // Reverse the digits
-    for j in 0..i/2 {
-        buffer.swap(j, i - 1 - j);
-    }
-    
-    buffer[i] = 0; // Null terminator
-    ((*console).output_string)(console, buffer.as_ptr());
+    let mut buffer = [0; cosmos_common::fmt::DEC_DIGITS];
+    crate::uefi::console::print(console, cosmos_common::fmt::format_u64_dec(num as u64, &mut buffer));
 }
 
 /// Page table entry flags
@@ -313,15 +481,16 @@ const PAGE_PRESENT: u64 = 1 << 0;      // Page is present in memory
 const PAGE_WRITABLE: u64 = 1 << 1;     // Page is writable
 const PAGE_SIZE: u64 = 1 << 7;         // Page size bit, for 2MB pages in PD
 
-/// Set up page tables for long mode
+/// Set up page tables for long mode in the frames at `page_table_base`
 pub unsafe fn setup_page_tables(
     console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
     descriptor_size: usize,
     descriptor_count: usize,
+    page_table_base: u64,
 ) {
-    const PML4_ADDRESS: usize = 0x70000;
-    const PDPT_ADDRESS: usize = 0x71000;
-    const PD_BASE_ADDRESS: usize = 0x72000;
+    let pml4_address = page_table_base as usize;
+    let pdpt_address = pml4_address + 0x1000;
+    let pd_base_address = pml4_address + 0x2000;
     
     println!(console, "Setting up page tables...");
 
@@ -343,11 +512,11 @@ pub unsafe fn setup_page_tables(
     pages_to_map = pages_to_map.min(2048);
     
     // Calculate how many Page Directories we need, 512 entries per PD, each entry = 2MB
-    let mut pd_count = (pages_to_map + 511) / 512;
+    let pd_count = (pages_to_map + 511) / 512;
     
     // Zero out page tables
-    let pml4_ptr = PML4_ADDRESS as *mut u64;
-    let pdpt_ptr = PDPT_ADDRESS as *mut u64;
+    let pml4_ptr = pml4_address as *mut u64;
+    let pdpt_ptr = pdpt_address as *mut u64;
     
     // Zero out PML4
     for i in 0..512 {
@@ -361,18 +530,18 @@ pub unsafe fn setup_page_tables(
     
     // Zero out used page directories
     for pd_idx in 0..pd_count {
-        let pd_ptr = (PD_BASE_ADDRESS + pd_idx * 0x1000) as *mut u64;
+        let pd_ptr = (pd_base_address + pd_idx * 0x1000) as *mut u64;
         for i in 0..512 {
             *pd_ptr.add(i) = 0;
         }
     }
     
     // Set up PML4[0] to point to PDPT
-    *pml4_ptr = (PDPT_ADDRESS as u64) | PAGE_PRESENT | PAGE_WRITABLE;
+    *pml4_ptr = (pdpt_address as u64) | PAGE_PRESENT | PAGE_WRITABLE;
     
     // Set up PDPT entries to point to page directories
     for pd_idx in 0..pd_count {
-        let pd_address = PD_BASE_ADDRESS + pd_idx * 0x1000;
+        let pd_address = pd_base_address + pd_idx * 0x1000;
         *pdpt_ptr.add(pd_idx) = (pd_address as u64) | PAGE_PRESENT | PAGE_WRITABLE;
     }
     
@@ -380,39 +549,30 @@ pub unsafe fn setup_page_tables(
     for i in 0..pages_to_map {
         let pd_idx = i / 512; // Which PD
         let entry_idx = i % 512; // Which entry in that PD
-        let pd_ptr = (PD_BASE_ADDRESS + pd_idx * 0x1000) as *mut u64;
+        let pd_ptr = (pd_base_address + pd_idx * 0x1000) as *mut u64;
         let physical_address = (i * 2 * 1024 * 1024) as u64;
         *pd_ptr.add(entry_idx) = physical_address | PAGE_PRESENT | PAGE_WRITABLE | PAGE_SIZE;
     }
     
-    let mapped_mb = pages_to_map * 2;
-    
     println!(console, "Page tables created:");
-    println!(console, "  PML4 at 0x70000");
-    println!(console, "  PDPT at 0x71000");
+    println!(console, "  PML4 at 0x");
+    print_hex_word(console, pml4_address as u32);
+    println!(console, "  PDPT at 0x");
+    print_hex_word(console, pdpt_address as u32);
     
     // Print PD locations
     for pd_idx in 0..pd_count {
         println!(console, "  PD");
         print_decimal(console, pd_idx);
         println!(console, " at 0x");
-        print_hex_word(console, (PD_BASE_ADDRESS + pd_idx * 0x1000) as u32);
+        print_hex_word(console, (pd_base_address + pd_idx * 0x1000) as u32);
     }
     
-    println!(console, "  Identity mapped 0-");
-    print_decimal(console, mapped_mb);
-    println!(console, "MB (2MB pages)");
+    println!(console, "  Identity mapped 0-{} (2MB pages)", HumanBytes(pages_to_map as u64 * 2 * 1024 * 1024));
 }
 
 /// Print 32-bit hexadecimal word
 unsafe fn print_hex_word(console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL, value: u32) {
-    let hex_chars = b"0123456789ABCDEF";
-    let mut buffer = [0u16; 9]; // 8 hex digits + null terminator
-    
-    for i in 0..8 {
-        buffer[i] = hex_chars[((value >> (28 - i * 4)) & 0xF) as usize] as u16;
-    }
-    buffer[8] = 0; // Null terminator
-    
-    ((*console).output_string)(console, buffer.as_ptr());
+    let mut buffer = [0; cosmos_common::fmt::HEX_DIGITS];
+    crate::uefi::console::print(console, cosmos_common::fmt::format_hex(value as u64, 8, &mut buffer));
 }
@@ -0,0 +1,165 @@
+//! Secure Boot Module
+//!
+//! Firmware only checks the signature on this loader, not on kernel.bin,
+//! so with Secure Boot on the loader has to vouch for the kernel itself.
+//! `cargo xtask image` hashes kernel.bin and builds the hash into the
+//! loader through `COSMOS_KERNEL_SHA256`, extending the chain of trust
+//! from the signed loader to the kernel. Every image the loader reads
+//! goes through `verify_image`, so an initrd can be checked the same way.
+
+use crate::uefi::{
+    EFI_GLOBAL_VARIABLE, EFI_RUNTIME_SERVICES, EFI_SUCCESS,
+    console::EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+};
+use crate::{println, error};
+use cosmos_bootinfo::{SECURE_BOOT_ENABLED, SECURE_BOOT_KERNEL_VERIFIED, SECURE_BOOT_SETUP_MODE};
+use cosmos_common::sha256::{self, DIGEST_SIZE};
+
+/// SHA-256 of kernel.bin this loader was built for, if any
+pub const KERNEL_SHA256: Option<[u8; DIGEST_SIZE]> = match option_env!("COSMOS_KERNEL_SHA256") {
+    Some(hex) => Some(parse_digest(hex)),
+    None => None,
+};
+
+/// Decode a hex digest at compile time
+const fn parse_digest(hex: &str) -> [u8; DIGEST_SIZE] {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => panic!("COSMOS_KERNEL_SHA256 is not hex"),
+        }
+    }
+    let hex = hex.as_bytes();
+    assert!(hex.len() == DIGEST_SIZE * 2, "COSMOS_KERNEL_SHA256 is not a SHA-256 digest");
+    let mut out = [0u8; DIGEST_SIZE];
+    let mut i = 0;
+    while i < DIGEST_SIZE {
+        out[i] = nibble(hex[i * 2]) << 4 | nibble(hex[i * 2 + 1]);
+        i += 1;
+    }
+    out
+}
+
+/// NUL-terminated UTF-16 copy of an ASCII variable name
+const fn utf16<const N: usize>(name: &str) -> [u16; N] {
+    let name = name.as_bytes();
+    assert!(name.len() + 1 == N);
+    let mut out = [0u16; N];
+    let mut i = 0;
+    while i < name.len() {
+        out[i] = name[i] as u16;
+        i += 1;
+    }
+    out
+}
+
+const SECURE_BOOT: [u16; 11] = utf16("SecureBoot");
+const SETUP_MODE: [u16; 10] = utf16("SetupMode");
+
+/// Secure Boot state read from the firmware variables
+#[derive(Clone, Copy)]
+pub struct SecureBootState {
+    /// `SecureBoot` is 1, images are checked against the signature database
+    pub enabled: bool,
+    /// `SetupMode` is 1, keys can be enrolled without authentication
+    pub setup_mode: bool,
+    /// The kernel matched `KERNEL_SHA256`
+    pub kernel_verified: bool,
+}
+
+impl SecureBootState {
+    /// `cosmos_bootinfo::SECURE_BOOT_*` flags for the boot info
+    pub fn flags(&self) -> u64 {
+        let mut flags = 0;
+        if self.enabled {
+            flags |= SECURE_BOOT_ENABLED;
+        }
+        if self.setup_mode {
+            flags |= SECURE_BOOT_SETUP_MODE;
+        }
+        if self.kernel_verified {
+            flags |= SECURE_BOOT_KERNEL_VERIFIED;
+        }
+        flags
+    }
+}
+
+/// Read a one-byte global variable, `None` if firmware does not have it
+unsafe fn read_flag(runtime_services: *mut EFI_RUNTIME_SERVICES, name: &[u16]) -> Option<bool> {
+    let mut value: u8 = 0;
+    let mut size = 1;
+    let status = ((*runtime_services).get_variable)(
+        name.as_ptr(),
+        &EFI_GLOBAL_VARIABLE,
+        core::ptr::null_mut(),
+        &mut size,
+        &mut value as *mut u8 as *mut _,
+    );
+    (status == EFI_SUCCESS && size == 1).then_some(value == 1)
+}
+
+/// Read the Secure Boot state and report it
+///
+/// Firmware without the variables predates Secure Boot and counts as off.
+pub unsafe fn detect(
+    runtime_services: *mut EFI_RUNTIME_SERVICES,
+    console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+) -> SecureBootState {
+    let secure_boot = read_flag(runtime_services, &SECURE_BOOT);
+    let setup_mode = read_flag(runtime_services, &SETUP_MODE).unwrap_or(false);
+    let state = SecureBootState {
+        // Setup mode skips signature checks even with SecureBoot set
+        enabled: secure_boot == Some(true) && !setup_mode,
+        setup_mode,
+        kernel_verified: false,
+    };
+    match (secure_boot, state.enabled) {
+        (None, _) => println!(console, "Secure Boot: not supported by firmware"),
+        (Some(_), true) => println!(console, "Secure Boot: enabled"),
+        (Some(_), false) if setup_mode => println!(console, "Secure Boot: disabled, firmware in setup mode"),
+        (Some(_), false) => println!(console, "Secure Boot: disabled"),
+    }
+    state
+}
+
+/// Check an image the loader read against the hash it was built with
+///
+/// Returns whether the image was verified. With Secure Boot on an image
+/// that does not match, or that the loader has no hash for, stops the
+/// boot. With it off a mismatch is only reported, so a rebuilt kernel can
+/// be dropped onto the ESP during development.
+pub unsafe fn verify_image(
+    name: &str,
+    data: &[u8],
+    expected: Option<[u8; DIGEST_SIZE]>,
+    state: &SecureBootState,
+    console: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+) -> bool {
+    let Some(expected) = expected else {
+        if state.enabled {
+            println!(console, "No SHA-256 for {} was built into the loader", name);
+            error::display_simple_error_and_halt(
+                console,
+                "Secure Boot is enabled and an image cannot be verified - rebuild with cargo xtask image",
+            );
+        }
+        return false;
+    };
+
+    println!(console, "Verifying {} ({} bytes)...", name, data.len());
+    if sha256::digest(data) == expected {
+        println!(console, "{} matches the SHA-256 built into the loader", name);
+        return true;
+    }
+
+    println!(console, "WARNING: {} does not match the SHA-256 built into the loader", name);
+    if state.enabled {
+        error::display_simple_error_and_halt(
+            console,
+            "Secure Boot is enabled and an image failed verification",
+        );
+    }
+    false
+}
@@ -0,0 +1,109 @@
+//! COM1 Logging Module
+//!
+//! Everything printed to the UEFI console is copied here, and after
+//! ExitBootServices this is the only output left, so a serial capture
+//! shows every boot stage even without a working console.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cosmos_common::fmt::crlf;
+
+/// COM1 base I/O port
+const COM1: u16 = 0x3F8;
+
+/// Polls of the line status register before a byte is dropped
+const TRANSMIT_SPINS: u32 = 100_000;
+
+/// Set once a UART answered at COM1
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Program COM1 for 38400 8N1, leaving logging off if no UART is there
+pub fn init() {
+    unsafe {
+        // The scratch register reads back on any 16450 or later
+        outb(COM1 + 7, 0xA5);
+        if inb(COM1 + 7) != 0xA5 {
+            return;
+        }
+        // Disable interrupts
+        outb(COM1 + 1, 0x00);
+        // Enable DLAB (set baud rate divisor)
+        outb(COM1 + 3, 0x80);
+        // Set divisor to 3 (38400 baud)
+        outb(COM1, 0x03);
+        outb(COM1 + 1, 0x00);
+        // 8 bits, no parity, one stop bit
+        outb(COM1 + 3, 0x03);
+        // Enable FIFO
+        outb(COM1 + 2, 0xC7);
+        // IRQs enabled, RTS/DSR set
+        outb(COM1 + 4, 0x0B);
+    }
+    READY.store(true, Ordering::Release);
+}
+
+/// Check whether a UART answered at COM1
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Write a string to COM1, does nothing before `init` or without a UART
+pub fn write_str(s: &str) {
+    if !READY.load(Ordering::Acquire) {
+        return;
+    }
+    crlf(s, |byte| unsafe {
+        // Give up on a stuck transmitter rather than hang the boot
+        for _ in 0..TRANSMIT_SPINS {
+            if inb(COM1 + 5) & 0x20 != 0 {
+                break;
+            }
+        }
+        outb(COM1, byte);
+    });
+}
+
+/// `fmt::Write` sink for COM1
+pub struct Serial;
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_str(s);
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut Serial, args);
+}
+
+/// Write a line to COM1 only, usable after ExitBootServices
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::serial::_log(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+#[inline(always)]
+unsafe fn outb(port: u16, value: u8) {
+    core::arch::asm!(
+        "out dx, al",
+        in("dx") port,
+        in("al") value,
+        options(nomem, nostack, preserves_flags)
+    );
+}
+
+#[inline(always)]
+unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    core::arch::asm!(
+        "in al, dx",
+        out("al") value,
+        in("dx") port,
+        options(nomem, nostack, preserves_flags)
+    );
+    value
+}
@@ -2,6 +2,11 @@
 
 use super::EFI_STATUS;
 use core::ffi::c_void;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Console the panic handler writes to, null once boot services are gone
+static PANIC_CONSOLE: AtomicPtr<EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL> = AtomicPtr::new(ptr::null_mut());
 
 /// UEFI Simple Text Output Protocol
 #[repr(C)]
@@ -56,6 +61,24 @@ pub struct EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL {
     pub mode: *mut c_void,
 }
 
+/// Let the panic handler print to `protocol` as well as COM1
+pub fn set_panic_console(protocol: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL) {
+    PANIC_CONSOLE.store(protocol, Ordering::Release);
+}
+
+/// Stop the panic handler from calling into the console
+///
+/// Must happen before ExitBootServices, firmware may tear the console
+/// down even when the call fails.
+pub fn clear_panic_console() {
+    PANIC_CONSOLE.store(ptr::null_mut(), Ordering::Release);
+}
+
+/// Console registered for panics, null if there is none
+pub fn panic_console() -> *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL {
+    PANIC_CONSOLE.load(Ordering::Acquire)
+}
+
 /// Convert UTF-8 string to UTF-16 for UEFI
 pub fn utf8_to_utf16(input: &str, output: &mut [u16]) -> usize {
     let mut i = 0;
@@ -70,8 +93,9 @@ pub fn utf8_to_utf16(input: &str, output: &mut [u16]) -> usize {
     i + 1
 }
 
-/// Print a UTF-8 string to the UEFI console
+/// Print a UTF-8 string to the UEFI console, with a copy on COM1
 pub unsafe fn print(protocol: *mut EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL, s: &str) {
+    crate::serial::write_str(s);
     if protocol.is_null() {
         return;
     }
@@ -93,41 +117,7 @@ macro_rules! println {
     ($console:expr, $($arg:tt)*) => {{
         use core::fmt::Write;
         
-        // Create a temporary string buffer
-        struct StringBuffer {
-            buffer: [u8; 512],
-            len: usize,
-        }
-        
-        impl StringBuffer {
-            fn new() -> Self {
-                Self {
-                    buffer: [0; 512],
-                    len: 0,
-                }
-            }
-            
-            fn as_str(&self) -> &str {
-                unsafe {
-                    core::str::from_utf8_unchecked(&self.buffer[..self.len])
-                }
-            }
-        }
-        
-        impl core::fmt::Write for StringBuffer {
-            fn write_str(&mut self, s: &str) -> core::fmt::Result {
-                let bytes = s.as_bytes();
-                let remaining = self.buffer.len() - self.len;
-                let to_copy = core::cmp::min(bytes.len(), remaining);
-                
-                self.buffer[self.len..self.len + to_copy].copy_from_slice(&bytes[..to_copy]);
-                self.len += to_copy;
-                
-                Ok(())
-            }
-        }
-        
-        let mut buf = StringBuffer::new();
+        let mut buf = cosmos_common::fmt::FixedBuf::<512>::new();
         let _ = core::write!(&mut buf, $($arg)*);
         
         unsafe {
@@ -17,6 +17,9 @@ pub const EFI_MEMORY_MAPPED_IO_PORT_SPACE: u32 = 12;
 pub const EFI_PAL_CODE: u32 = 13;
 pub const EFI_PERSISTENT_MEMORY: u32 = 14;
 
+/// Memory attribute: region must be mapped for runtime services
+pub const EFI_MEMORY_RUNTIME: u64 = 1 << 63;
+
 /// UEFI Memory Descriptor
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -28,23 +31,6 @@ pub struct EFI_MEMORY_DESCRIPTOR {
     pub attribute: u64,
 }
 
-/// E820 Memory Map Entry
-#[repr(C, packed)]
-#[derive(Copy, Clone)]
-pub struct E820Entry {
-    pub base: u64,
-    pub length: u64,
-    pub entry_type: u32,
-    pub acpi: u32,
-}
-
-/// E820 Memory Types
-pub const E820_USABLE: u32 = 1;
-pub const E820_RESERVED: u32 = 2;
-pub const E820_ACPI_RECLAIMABLE: u32 = 3;
-pub const E820_ACPI_NVS: u32 = 4;
-pub const E820_BAD_MEMORY: u32 = 5;
-
 /// Memory allocation types
 pub const ALLOCATE_ANY_PAGES: u32 = 0;
 pub const ALLOCATE_MAX_ADDRESS: u32 = 1;
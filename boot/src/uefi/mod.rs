@@ -56,11 +56,70 @@ pub struct EFI_CONFIGURATION_TABLE {
     pub vendor_table: *mut c_void,
 }
 
+/// ACPI 2.0 RSDP configuration table GUID
+pub const EFI_ACPI_20_TABLE_GUID: EFI_GUID = EFI_GUID {
+    data1: 0x8868e871,
+    data2: 0xe4f1,
+    data3: 0x11d3,
+    data4: [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+};
+
+/// ACPI 1.0 RSDP configuration table GUID
+pub const EFI_ACPI_TABLE_GUID: EFI_GUID = EFI_GUID {
+    data1: 0xeb9d2d30,
+    data2: 0x2d88,
+    data3: 0x11d3,
+    data4: [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+};
+
+/// Vendor GUID of the architectural variables, `SecureBoot` among them
+pub const EFI_GLOBAL_VARIABLE: EFI_GUID = EFI_GUID {
+    data1: 0x8be4df61,
+    data2: 0x93ca,
+    data3: 0x11d2,
+    data4: [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+};
+
+impl EFI_GUID {
+    /// Compare two GUIDs field by field
+    pub fn matches(&self, other: &EFI_GUID) -> bool {
+        self.data1 == other.data1
+            && self.data2 == other.data2
+            && self.data3 == other.data3
+            && self.data4 == other.data4
+    }
+}
+
 /// UEFI Runtime Services
 #[repr(C)]
 pub struct EFI_RUNTIME_SERVICES {
     pub hdr: EFI_TABLE_HEADER,
-    // Not needed for bootloader
+    
+    // Time Services, used by the kernel
+    _get_time: usize,
+    _set_time: usize,
+    _get_wakeup_time: usize,
+    _set_wakeup_time: usize,
+    
+    // Virtual Memory Services
+    pub set_virtual_address_map: extern "efiapi" fn(
+        memory_map_size: usize,
+        descriptor_size: usize,
+        descriptor_version: u32,
+        virtual_map: *mut memory::EFI_MEMORY_DESCRIPTOR,
+    ) -> EFI_STATUS,
+    _convert_pointer: usize,
+    
+    // Variable Services
+    pub get_variable: extern "efiapi" fn(
+        variable_name: *const u16,
+        vendor_guid: *const EFI_GUID,
+        attributes: *mut u32,
+        data_size: *mut usize,
+        data: *mut c_void,
+    ) -> EFI_STATUS,
+    
+    // Remaining services are only called by the kernel
 }
 
 /// UEFI Boot Services Table
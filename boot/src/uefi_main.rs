@@ -11,6 +11,9 @@ mod error;
 mod kernel_loader;
 mod memory_setup;
 mod kernel_jump;
+mod boot_info;
+mod serial;
+mod secure_boot;
 
 use uefi::{EFI_SYSTEM_TABLE, EFI_STATUS, EFI_SUCCESS};
 
@@ -20,6 +23,10 @@ pub extern "efiapi" fn efi_main(
     image_handle: *mut c_void,
     system_table: *mut EFI_SYSTEM_TABLE,
 ) -> usize {
+    // COM1 first, so a serial capture sees every stage below
+    serial::init();
+    log!("CosmosBootloaderUEFI v{}", env!("CARGO_PKG_VERSION"));
+    
     // Verify system table is valid
     if system_table.is_null() {
         return 1; // EFI_LOAD_ERROR
@@ -46,16 +53,37 @@ pub extern "efiapi" fn efi_main(
             );
         }
         
+        // Panics can reach the screen until boot services are exited
+        uefi::console::set_panic_console(console);
+        
         // Display initialization message
-        println!(console, "CosmosBootloaderUEFI v0.0.3");
+        println!(console, "CosmosBootloaderUEFI v{}", env!("CARGO_PKG_VERSION"));
         println!(console, "Initializing...");
         
+        // Before anything is allocated, so nothing lands on the store
+        memory_setup::reserve_pstore(boot_services, console);
+        
         // Load kernel from ESP
         let kernel_buffer = kernel_loader::load_kernel_from_esp_root(boot_services, console);
         
         println!(console, "Kernel loaded at address: ");
         print_hex(console, kernel_buffer.data_ptr as usize);
         
+        // Firmware checked this loader's signature, the loader checks the kernel
+        let mut secure_boot = secure_boot::detect((*system_table).runtime_services, console);
+        secure_boot.kernel_verified = secure_boot::verify_image(
+            "kernel.bin",
+            core::slice::from_raw_parts(kernel_buffer.data_ptr, kernel_buffer.size),
+            secure_boot::KERNEL_SHA256,
+            &secure_boot,
+            console,
+        );
+        
+        // Page tables and stack come from firmware-managed memory, before
+        // the map is read so it records them
+        let boot_memory = memory_setup::allocate_boot_memory(boot_services, console);
+        let kernel_base = memory_setup::allocate_kernel(boot_services, &kernel_buffer.header, console);
+        
         // Get UEFI memory map
         println!(console, "Retrieving memory map...");
         let memory_info = memory_setup::get_uefi_memory_map(boot_services, console);
@@ -77,25 +105,46 @@ pub extern "efiapi" fn efi_main(
         // Store E820 map at 0x9000
         memory_setup::store_e820_map(e820_count, console);
         
+        // Point the kernel at the map, RSDP and runtime services
+        boot_info::store_boot_info(
+            system_table,
+            e820_count,
+            &boot_memory,
+            kernel_base,
+            kernel_buffer.header.memory_size,
+            secure_boot.flags(),
+            console,
+        );
+        
         // Copy kernel to final address
         memory_setup::copy_kernel_to_final_address(
             kernel_buffer.data_ptr,
             kernel_buffer.size,
+            &kernel_buffer.header,
+            kernel_base,
+            &memory_info,
             console,
         );
         
         // Setup page tables for long mode
-        memory_setup::setup_page_tables(console, memory_info.descriptor_size, memory_info.descriptor_count);
+        memory_setup::setup_page_tables(
+            console,
+            memory_info.descriptor_size,
+            memory_info.descriptor_count,
+            boot_memory.page_tables,
+        );
         
         // Exit boot services, switch page tables atomically at the same time
         println!(console, "Exiting boot services and loading page tables...");
         kernel_jump::exit_boot_services_and_setup_cpu(
             boot_services,
+            (*system_table).runtime_services,
             image_handle,
-            memory_info.map_key,
+            &memory_info,
             console,
-            0x70000,  // page table base
-            0xA0000,  // stack top
+            boot_memory.page_tables,
+            boot_memory.stack_top(),
+            kernel_base,
         );
     }
 
@@ -116,21 +165,24 @@ pub fn halt() -> ! {
 
 /// Print a hexadecimal number
 unsafe fn print_hex(console: *mut uefi::console::EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL, num: usize) {
-    let hex_chars = b"0123456789ABCDEF";
-    let mut buffer = [0u16; 17]; // 16 hex digits + null terminator
-    
-    for i in 0..16 {
-        let nibble = (num >> (60 - i * 4)) & 0xF;
-        buffer[i] = hex_chars[nibble] as u16;
-    }
-    buffer[16] = 0; // Null terminator
-    
-    ((*console).output_string)(console, buffer.as_ptr());
+    let mut buffer = [0; cosmos_common::fmt::HEX_DIGITS];
+    uefi::console::print(console, cosmos_common::fmt::format_hex(num as u64, 16, &mut buffer));
 }
 
 /// Panic handler for no_std environment
 #[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    // TODO: Display panic message via UEFI serial/console
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    // Take the console first, a panic while printing then falls back to COM1
+    let console = uefi::console::panic_console();
+    uefi::console::clear_panic_console();
+    
+    // print mirrors to COM1, and with a null console writes only there
+    println!(console, "");
+    println!(console, "!!! BOOTLOADER PANIC !!!");
+    println!(console, "{}", info.message());
+    if let Some(location) = info.location() {
+        println!(console, "at {}:{}:{}", location.file(), location.line(), location.column());
+    }
+    println!(console, "System halted.");
     halt();
 }
@@ -0,0 +1,373 @@
+//! Boot handoff definitions shared by the bootloader and the kernel
+//!
+//! The loader writes these structures to memory and the kernel reads them
+//! back, so both sides build against this crate rather than keeping two
+//! copies of each layout in step by hand.
+
+#![no_std]
+
+/// Physical address of the `BootInfo` block the UEFI loader fills in
+pub const BOOT_INFO_ADDRESS: u64 = 0x8000;
+
+/// `BootInfo::magic`, "CosmBoot" in little endian
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"CosmBoot");
+
+/// Current `BootInfo::version`, also the boot ABI version a kernel asks
+/// for in its header
+///
+/// Fields are only ever appended, and each says which version added it, so
+/// a kernel reads a block from an older loader up to the fields that
+/// version has.
+pub const BOOT_INFO_VERSION: u32 = 7;
+
+/// Oldest `BootInfo::version` a kernel reads
+pub const BOOT_INFO_MIN_VERSION: u32 = 1;
+
+/// Address the kernel is linked at, where the BIOS loader always puts it
+pub const KERNEL_LINK_ADDRESS: u64 = 0x200000;
+
+/// Physical address of the kernel's persistent store, the 64 KiB just
+/// below the kernel link address, which the UEFI loader claims first so no
+/// boot allocation lands on it
+pub const PSTORE_ADDRESS: u64 = KERNEL_LINK_ADDRESS - PSTORE_SIZE;
+
+/// Size of the persistent store
+pub const PSTORE_SIZE: u64 = 64 * 1024;
+
+/// `KernelHeader::magic`, "CosmKern" in little endian
+pub const KERNEL_HEADER_MAGIC: u64 = u64::from_le_bytes(*b"CosmKern");
+
+/// Low 28 bits of every kernel signature, what the BIOS stage2 scans for
+pub const KERNEL_SIGNATURE_MAGIC: u64 = 0x0FC0_5305;
+
+/// Kernel signature for a version, `0xFyzFyzFyzFC05305`
+///
+/// Only the handshake with the loaders uses this packing; the kernel's
+/// own build information lives in `cosmos::version`.
+pub const fn kernel_signature(major: u8, minor: u8, patch: u8) -> u64 {
+    0xF << 60
+        | (major as u64) << 52
+        | 0xF << 48
+        | (minor as u64) << 40
+        | 0xF << 36
+        | (patch as u64) << 28
+        | KERNEL_SIGNATURE_MAGIC
+}
+
+/// The loader wrote its progress to COM1
+pub const LOADER_SERIAL_LOG: u64 = 1 << 0;
+/// `BootInfo::page_tables` and `stack` were allocated from firmware
+pub const LOADER_BOOT_MEMORY: u64 = 1 << 1;
+/// The loader checked the kernel header before entering it
+pub const LOADER_KERNEL_HEADER: u64 = 1 << 2;
+
+/// Firmware reported Secure Boot on and not in setup mode
+pub const SECURE_BOOT_ENABLED: u64 = 1 << 0;
+/// Firmware is in setup mode, no platform key is enrolled
+pub const SECURE_BOOT_SETUP_MODE: u64 = 1 << 1;
+/// The kernel image matched the SHA-256 built into the loader
+pub const SECURE_BOOT_KERNEL_VERIFIED: u64 = 1 << 2;
+
+/// `BootInfo::boot_mode` when the loader ran under legacy BIOS
+pub const BOOT_MODE_BIOS: u32 = 1;
+/// `BootInfo::boot_mode` when the loader ran as a UEFI application
+pub const BOOT_MODE_UEFI: u32 = 2;
+
+/// The screen is in VGA text mode with the buffer at 0xB8000
+pub const CONSOLE_VGA_TEXT: u64 = 1 << 0;
+/// A linear framebuffer was set up, e.g. through GOP
+pub const CONSOLE_FRAMEBUFFER: u64 = 1 << 1;
+/// COM1 answered and can carry the console
+pub const CONSOLE_SERIAL: u64 = 1 << 2;
+
+/// Size of `BootInfo::firmware_vendor`
+pub const FIRMWARE_VENDOR_SIZE: usize = 32;
+
+/// Pack a version as `major << 16 | minor << 8 | patch`
+pub const fn encode_version(major: u64, minor: u64, patch: u64) -> u64 {
+    (major & 0xFF) << 16 | (minor & 0xFF) << 8 | (patch & 0xFF)
+}
+
+/// Unpack a version from `encode_version`
+pub const fn decode_version(version: u64) -> (u8, u8, u8) {
+    ((version >> 16) as u8, (version >> 8) as u8, version as u8)
+}
+
+/// `Elf64Rela::info` type of the only relocation a kernel may carry
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+/// Entry of the kernel's relocation table
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf64Rela {
+    /// Link address of the word to patch
+    pub offset: u64,
+    /// Relocation type in the low 32 bits, symbol above
+    pub info: u64,
+    /// Link address the word should point to
+    pub addend: i64,
+}
+
+/// Header at the start of the kernel image
+///
+/// Loaders read it before entering the kernel to refuse an image built for
+/// a different boot ABI. Entering at the start of the image still works,
+/// `jump` branches over the rest of the header to `_start`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelHeader {
+    /// `jmp _start`, padded with NOPs
+    pub jump: [u8; 8],
+    /// Must be `KERNEL_HEADER_MAGIC`
+    pub magic: u64,
+    /// Kernel version as `0xFyzFyzFyzFC05305`, see `kernel_signature`
+    pub signature: u64,
+    /// `BootInfo::version` the kernel understands
+    pub boot_info_version: u32,
+    /// Size of this header, for later extension
+    pub header_size: u32,
+    /// Link address, `KERNEL_LINK_ADDRESS`
+    pub load_address: u64,
+    /// Bytes from the start of the image to the end of .bss
+    pub memory_size: u64,
+    /// Offset of the `Elf64Rela` table from the start of the image
+    pub rela_offset: u64,
+    /// Size of the relocation table in bytes
+    pub rela_size: u64,
+}
+
+impl KernelHeader {
+    /// Check the magic numbers, not the ABI version
+    pub fn is_valid(&self) -> bool {
+        self.magic == KERNEL_HEADER_MAGIC
+            && self.signature & 0x0FFF_FFFF == KERNEL_SIGNATURE_MAGIC
+            && self.header_size as usize >= core::mem::size_of::<Self>()
+    }
+
+    /// Major, minor and patch version from the signature
+    pub fn kernel_version(&self) -> (u8, u8, u8) {
+        ((self.signature >> 52) as u8, (self.signature >> 40) as u8, (self.signature >> 28) as u8)
+    }
+}
+
+/// Where the in-tree loaders store the memory map, a 32-bit entry count
+/// followed by packed `E820Entry` records
+pub const MEMORY_MAP_ADDRESS: u64 = 0x9000;
+
+/// Handoff block filled in by the loader
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootInfo {
+    /// Must be `BOOT_INFO_MAGIC`
+    pub magic: u64,
+    /// `BOOT_INFO_MIN_VERSION` to `BOOT_INFO_VERSION`, which fields follow
+    pub version: u32,
+    /// Number of entries at `memory_map`
+    pub memory_map_count: u32,
+    /// Physical address of packed 24-byte E820 entries
+    pub memory_map: u64,
+    /// Physical address of the RSDP, 0 if unknown
+    pub rsdp: u64,
+    /// Physical address of a NUL-terminated command line, 0 if none
+    pub command_line: u64,
+    /// Identity-mapped UEFI runtime services table, 0 if none, version 2
+    pub uefi_runtime: u64,
+    /// Physical address and size of the page tables in CR3 at entry,
+    /// version 3
+    pub page_tables: u64,
+    pub page_tables_size: u64,
+    /// Physical address and size of the stack the kernel is entered on,
+    /// version 3
+    pub stack: u64,
+    pub stack_size: u64,
+    /// Loader version from `encode_version`, version 4
+    pub loader_version: u64,
+    /// `LOADER_*` flags, version 4
+    pub loader_capabilities: u64,
+    /// Physical address the kernel image was placed at, version 5
+    pub kernel_base: u64,
+    /// Size of the kernel image, .bss included, version 5
+    pub kernel_size: u64,
+    /// `SECURE_BOOT_*` flags, version 6
+    pub secure_boot: u64,
+    /// `BOOT_MODE_*`, the firmware interface the loader ran under, version 7
+    pub boot_mode: u32,
+    /// Firmware revision, 0 if unknown, version 7
+    pub firmware_revision: u32,
+    /// `CONSOLE_*` flags for the output devices left usable, version 7
+    pub consoles: u64,
+    /// Firmware vendor as NUL-padded ASCII, version 7
+    pub firmware_vendor: [u8; FIRMWARE_VENDOR_SIZE],
+}
+
+impl BootInfo {
+    /// Firmware vendor up to the first NUL, empty if unknown
+    pub fn firmware_vendor(&self) -> &str {
+        let len = self.firmware_vendor.iter().position(|&b| b == 0).unwrap_or(FIRMWARE_VENDOR_SIZE);
+        core::str::from_utf8(&self.firmware_vendor[..len]).unwrap_or("")
+    }
+}
+
+/// E820 memory map entry types
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Usable RAM
+    Usable = 1,
+    /// Reserved memory
+    Reserved = 2,
+    /// ACPI reclaimable memory
+    AcpiReclaimable = 3,
+    /// ACPI NVS memory
+    AcpiNvs = 4,
+    /// Bad memory
+    BadMemory = 5,
+}
+
+impl MemoryType {
+    /// Create a MemoryType from raw u32 value
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(MemoryType::Usable),
+            2 => Some(MemoryType::Reserved),
+            3 => Some(MemoryType::AcpiReclaimable),
+            4 => Some(MemoryType::AcpiNvs),
+            5 => Some(MemoryType::BadMemory),
+            _ => None,
+        }
+    }
+
+    /// Check if this memory type is usable for allocation
+    pub fn is_usable(self) -> bool {
+        matches!(self, MemoryType::Usable)
+    }
+}
+
+/// `E820Entry::attributes` bit marking an entry as valid, from ACPI 3.0
+pub const E820_ATTRIBUTE_VALID: u32 = 1;
+
+/// A single memory map entry from the loader, 24 bytes total
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct E820Entry {
+    /// Base address of the memory region, 8 bytes
+    pub base_addr: u64,
+    /// Length of the memory region in bytes, 8 bytes
+    pub length: u64,
+    /// Type of memory region, 4 bytes
+    pub entry_type: u32,
+    /// Extended attributes, 4 bytes, `E820_ATTRIBUTE_VALID` for valid entries
+    pub attributes: u32,
+}
+
+impl E820Entry {
+    /// Valid entry of `memory_type` covering `base_addr..base_addr + length`
+    pub const fn new(base_addr: u64, length: u64, memory_type: MemoryType) -> Self {
+        Self { base_addr, length, entry_type: memory_type as u32, attributes: E820_ATTRIBUTE_VALID }
+    }
+
+    /// Get the memory type for this entry
+    pub fn memory_type(&self) -> Option<MemoryType> {
+        MemoryType::from_u32(self.entry_type)
+    }
+
+    /// End address of this memory region, exclusive
+    pub fn end(&self) -> u64 {
+        self.base_addr + self.length
+    }
+
+    /// Check if this entry is valid and usable
+    pub fn is_usable(&self) -> bool {
+        self.attributes == E820_ATTRIBUTE_VALID &&
+        self.length > 0 &&
+        self.memory_type().map_or(false, |t| t.is_usable())
+    }
+
+    /// Check if entry represents system/hardware reserved memory
+    pub fn is_system_reserved(&self) -> bool {
+        // BIOS/VGA regions
+        if self.base_addr < 0x100000 {
+            return true;
+        }
+
+        // Check memory type
+        match self.memory_type() {
+            Some(MemoryType::Reserved) | Some(MemoryType::BadMemory) => true,
+            _ => false,
+        }
+    }
+
+    /// Check if this entry can be reclaimed later
+    pub fn is_reclaimable(&self) -> bool {
+        matches!(self.memory_type(), Some(MemoryType::AcpiReclaimable))
+    }
+
+    /// Get a human-readable description of this memory region
+    pub fn description(&self) -> &'static str {
+        match self.memory_type() {
+            Some(MemoryType::Usable) => "Usable RAM",
+            Some(MemoryType::Reserved) => "Reserved",
+            Some(MemoryType::AcpiReclaimable) => "ACPI Reclaimable",
+            Some(MemoryType::AcpiNvs) => "ACPI NVS",
+            Some(MemoryType::BadMemory) => "Bad Memory",
+            None => "Unknown",
+        }
+    }
+}
+
+/// Rank of a type where entries overlap, the highest wins
+fn restrictiveness(memory_type: Option<MemoryType>) -> u8 {
+    match memory_type {
+        Some(MemoryType::Usable) => 0,
+        Some(MemoryType::AcpiReclaimable) => 1,
+        Some(MemoryType::AcpiNvs) => 2,
+        Some(MemoryType::Reserved) | None => 3,
+        Some(MemoryType::BadMemory) => 4,
+    }
+}
+
+/// Sort a firmware memory map and resolve its overlaps into `output`
+///
+/// Where entries overlap, the most restrictive type wins, so RAM that any
+/// entry reserves is never called usable. Unknown types count as reserved,
+/// adjacent entries of one type are merged and entries without
+/// `E820_ATTRIBUTE_VALID` are ignored. The result is sorted and disjoint.
+/// Returns the number of entries written, dropping the highest regions if
+/// `output` fills up; twice the input length is always enough.
+pub fn sanitize_memory_map(input: &[E820Entry], output: &mut [E820Entry]) -> usize {
+    let present = |entry: &&E820Entry| entry.attributes & E820_ATTRIBUTE_VALID != 0 && entry.length > 0;
+    let end = |entry: &E820Entry| entry.base_addr.saturating_add(entry.length);
+
+    let Some(mut cursor) = input.iter().filter(present).map(|entry| entry.base_addr).min() else {
+        return 0;
+    };
+    let mut count = 0;
+    // Walk every boundary in order, typing the span up to the next one
+    while let Some(next) = input
+        .iter()
+        .filter(present)
+        .flat_map(|entry| [entry.base_addr, end(entry)])
+        .filter(|&address| address > cursor)
+        .min()
+    {
+        let winner = input
+            .iter()
+            .filter(present)
+            .filter(|entry| entry.base_addr <= cursor && cursor < end(entry))
+            .map(|entry| entry.memory_type())
+            .max_by_key(|&memory_type| restrictiveness(memory_type));
+        if let Some(memory_type) = winner {
+            let memory_type = memory_type.unwrap_or(MemoryType::Reserved);
+            if count > 0 && output[count - 1].entry_type == memory_type as u32 && output[count - 1].end() == cursor {
+                output[count - 1].length += next - cursor;
+            } else if count < output.len() {
+                output[count] = E820Entry::new(cursor, next - cursor, memory_type);
+                count += 1;
+            } else {
+                break;
+            }
+        }
+        cursor = next;
+    }
+    count
+}
@@ -0,0 +1,216 @@
+//! Text formatting helpers for early output
+//!
+//! The number formatters fill a caller's stack buffer and return the
+//! digits as `&str`, so panic and error paths can print numbers without
+//! the heap or the `core::fmt` machinery.
+
+use core::fmt;
+
+/// Digits in the longest decimal `u64`
+pub const DEC_DIGITS: usize = 20;
+/// Digits in the longest hexadecimal `u64`
+pub const HEX_DIGITS: usize = 16;
+
+/// Write `value` in decimal into the end of `buf`
+pub fn format_u64_dec(mut value: u64, buf: &mut [u8; DEC_DIGITS]) -> &str {
+    let mut start = DEC_DIGITS;
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    // Only ASCII digits were written
+    unsafe { core::str::from_utf8_unchecked(&buf[start..]) }
+}
+
+/// Write `value` in uppercase hexadecimal into the end of `buf`, padded
+/// with zeros to at least `width` digits
+pub fn format_hex(mut value: u64, width: usize, buf: &mut [u8; HEX_DIGITS]) -> &str {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    let width = width.clamp(1, HEX_DIGITS);
+    let mut start = HEX_DIGITS;
+    while value != 0 || HEX_DIGITS - start < width {
+        start -= 1;
+        buf[start] = DIGITS[(value & 0xF) as usize];
+        value >>= 4;
+    }
+    unsafe { core::str::from_utf8_unchecked(&buf[start..]) }
+}
+
+/// Byte count shown in the largest binary unit it reaches
+///
+/// One decimal is kept and rounded to nearest, dropped when it is zero:
+/// `512 B`, `1.5 KiB`, `16 GiB`. A value that rounds up to 1024 of a unit
+/// is shown in the next one. Width and alignment apply to the whole text,
+/// so columns of sizes line up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanBytes(pub u64);
+
+impl HumanBytes {
+    fn write(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut unit = 0;
+        while unit + 1 < UNITS.len() && self.0 >= 1u64 << (10 * (unit + 1)) {
+            unit += 1;
+        }
+        if unit == 0 {
+            return write!(f, "{} B", self.0);
+        }
+        let divisor = 1u128 << (10 * unit);
+        let mut tenths = (self.0 as u128 * 10 + divisor / 2) / divisor;
+        if tenths >= 1024 * 10 && unit + 1 < UNITS.len() {
+            unit += 1;
+            tenths = (tenths + 512) / 1024;
+        }
+        match tenths % 10 {
+            0 => write!(f, "{} {}", tenths / 10, UNITS[unit]),
+            tenth => write!(f, "{}.{} {}", tenths / 10, tenth, UNITS[unit]),
+        }
+    }
+}
+
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // "16777215.9 TiB", just under u64::MAX, is the longest
+        let mut text = FixedBuf::<16>::new();
+        self.write(&mut text)?;
+        f.pad(text.as_str())
+    }
+}
+
+/// Feed `s` to `put` a byte at a time, turning `\n` into `\r\n`
+///
+/// Serial terminals need the carriage return. A `\n` that already follows
+/// a `\r` is passed through unchanged.
+pub fn crlf(s: &str, mut put: impl FnMut(u8)) {
+    let mut last = 0;
+    for byte in s.bytes() {
+        if byte == b'\n' && last != b'\r' {
+            put(b'\r');
+        }
+        put(byte);
+        last = byte;
+    }
+}
+
+/// Fixed-size `fmt::Write` buffer, output past `N` bytes is dropped
+pub struct FixedBuf<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FixedBuf<N> {
+    /// Create an empty buffer
+    pub const fn new() -> Self {
+        Self { buffer: [0; N], len: 0 }
+    }
+
+    /// Text written so far
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are ever copied in
+        unsafe { core::str::from_utf8_unchecked(&self.buffer[..self.len]) }
+    }
+
+    /// Forget the contents
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for FixedBuf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for FixedBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut take = s.len().min(N - self.len);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.buffer[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::fmt::Write;
+    use std::format;
+
+    #[test]
+    fn dec_extremes() {
+        let mut buf = [0; DEC_DIGITS];
+        assert_eq!(format_u64_dec(0, &mut buf), "0");
+        assert_eq!(format_u64_dec(7, &mut buf), "7");
+        assert_eq!(format_u64_dec(u64::MAX, &mut buf), "18446744073709551615");
+    }
+
+    #[test]
+    fn hex_width() {
+        let mut buf = [0; HEX_DIGITS];
+        assert_eq!(format_hex(0, 0, &mut buf), "0");
+        assert_eq!(format_hex(0xAB, 4, &mut buf), "00AB");
+        assert_eq!(format_hex(0x12345, 2, &mut buf), "12345");
+        assert_eq!(format_hex(u64::MAX, 0, &mut buf), "FFFFFFFFFFFFFFFF");
+        // Widths past 16 digits are clamped to the buffer
+        assert_eq!(format_hex(1, 40, &mut buf), "0000000000000001");
+    }
+
+    #[test]
+    fn human_bytes_rounding() {
+        assert_eq!(format!("{}", HumanBytes(0)), "0 B");
+        assert_eq!(format!("{}", HumanBytes(1023)), "1023 B");
+        assert_eq!(format!("{}", HumanBytes(1024)), "1 KiB");
+        assert_eq!(format!("{}", HumanBytes(1536)), "1.5 KiB");
+        // 1.04 KiB rounds down, 1.05 KiB up
+        assert_eq!(format!("{}", HumanBytes(1065)), "1 KiB");
+        assert_eq!(format!("{}", HumanBytes(1076)), "1.1 KiB");
+        assert_eq!(format!("{}", HumanBytes(640 * 1024)), "640 KiB");
+        assert_eq!(format!("{}", HumanBytes(16 << 30)), "16 GiB");
+        assert_eq!(format!("{}", HumanBytes(u64::MAX)), "16777216 TiB");
+        assert_eq!(format!("{}", HumanBytes(u64::MAX - (1 << 39))), "16777215.5 TiB");
+    }
+
+    #[test]
+    fn human_bytes_carry() {
+        // 1023.95 KiB rounds to 1024.0 and moves to the next unit
+        assert_eq!(format!("{}", HumanBytes(1024 * 1024 - 51)), "1 MiB");
+        assert_eq!(format!("{}", HumanBytes(1024 * 1024 - 52)), "1023.9 KiB");
+        assert_eq!(format!("{}", HumanBytes((1 << 30) - 1)), "1 GiB");
+    }
+
+    #[test]
+    fn human_bytes_padding() {
+        assert_eq!(format!("{:>8}", HumanBytes(1536)), " 1.5 KiB");
+        assert_eq!(format!("{:<8}|", HumanBytes(512)), "512 B   |");
+    }
+
+    #[test]
+    fn crlf_newlines() {
+        let mut out = FixedBuf::<16>::new();
+        crlf("a\nb\r\nc\n\n", |byte| out.write_char(byte as char).unwrap());
+        assert_eq!(out.as_str(), "a\r\nb\r\nc\r\n\r\n");
+    }
+
+    #[test]
+    fn fixed_buf_truncates() {
+        let mut text = FixedBuf::<8>::new();
+        write!(text, "{}", 1234).unwrap();
+        text.write_str("56789").unwrap();
+        assert_eq!(text.as_str(), "12345678");
+        text.clear();
+        assert_eq!(text.as_str(), "");
+        // A character that doesn't fit whole is dropped
+        write!(text, "abcdefgé").unwrap();
+        assert_eq!(text.as_str(), "abcdefg");
+    }
+}
@@ -0,0 +1,9 @@
+//! Code shared by the bootloader, the kernel and xtask
+//!
+//! Everything here is `no_std` and allocation free, so it works before
+//! the bootloader or the kernel has a heap.
+
+#![no_std]
+
+pub mod fmt;
+pub mod sha256;
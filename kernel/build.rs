@@ -1,9 +1,183 @@
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     let dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
     let linker_script = dir.join("linker.ld");
     println!("cargo:rustc-link-arg=-T{}", linker_script.display());
     println!("cargo:rerun-if-changed=linker.ld");
+    // Relocated values are also written in place, so loaders that enter at
+    // the link address without relocating still run a working kernel
+    println!("cargo:rustc-link-arg=--apply-dynamic-relocs");
+    println!("cargo:rustc-link-arg=-znorelro");
+
+    // The map file sits next to the kernel binary, outside this script's
+    // hashed OUT_DIR so it survives build script changes
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let profile_dir = out_dir.ancestors().nth(3).unwrap();
+    let map = profile_dir.join("cosmos.map");
+    println!("cargo:rustc-link-arg=-Map={}", map.display());
+    println!("cargo:rerun-if-changed={}", map.display());
+
+    // Symbols come from the previous link. Only .bss follows .ksyms, so the
+    // table's size never moves a function and the next build converges.
+    let symbols = fs::read_to_string(&map)
+        .map(|text| parse_map(&text))
+        .unwrap_or_default();
+    fs::write(out_dir.join("ksyms.bin"), encode(&symbols)).unwrap();
+
+    build_info(&dir, &out_dir);
+}
+
+/// Write `build_info.rs`, the constants behind `cosmos::version`, and
+/// `build_info.txt` for the `.buildinfo` section
+fn build_info(dir: &Path, out_dir: &Path) {
+    let version = env::var("CARGO_PKG_VERSION").unwrap();
+    let part = |name: &str| env::var(name).unwrap().parse::<u8>().expect("version part above 255");
+    let (major, minor, patch) = (part("CARGO_PKG_VERSION_MAJOR"), part("CARGO_PKG_VERSION_MINOR"), part("CARGO_PKG_VERSION_PATCH"));
+
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let git_hash = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    let git_dirty = git(&["status", "--porcelain", "--untracked-files=no"]).is_some_and(|status| !status.is_empty());
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        println!("cargo:rerun-if-changed={}/index", git_dir);
+    }
+
+    // SOURCE_DATE_EPOCH pins the timestamp for reproducible builds
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()));
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(|feature| feature.to_lowercase().replace('_', "-")))
+        .filter(|feature| feature != "default")
+        .collect();
+    features.sort();
+    let profile = env::var("PROFILE").unwrap_or_default();
+
+    // Also embedded as text in .buildinfo, so `strings kernel.bin` shows
+    // what a kernel image is without booting it
+    let text = format!(
+        "CosmOS build info\nversion={}\ngit={}{}\ntimestamp={}\nrustc={}\nprofile={}\nfeatures={}\n",
+        version,
+        git_hash,
+        if git_dirty { "-dirty" } else { "" },
+        timestamp,
+        rustc_version,
+        profile,
+        features.join(","),
+    );
+
+    let source = format!(
+        "pub const VERSION_STRING: &str = {:?};\n\
+         pub const MAJOR: u8 = {};\n\
+         pub const MINOR: u8 = {};\n\
+         pub const PATCH: u8 = {};\n\
+         pub const GIT_HASH: &str = {:?};\n\
+         pub const GIT_DIRTY: bool = {};\n\
+         pub const BUILD_TIMESTAMP: u64 = {};\n\
+         pub const RUSTC_VERSION: &str = {:?};\n\
+         pub const PROFILE: &str = {:?};\n\
+         pub const FEATURES: &[&str] = &{:?};\n",
+        version, major, minor, patch, git_hash, git_dirty, timestamp, rustc_version, profile, features,
+    );
+    fs::write(out_dir.join("build_info.rs"), source).unwrap();
+    fs::write(out_dir.join("build_info.txt"), text).unwrap();
+}
+
+/// Column where the `Out`/`In`/`Symbol` names of an lld map file start
+const MAP_NAME_COLUMN: usize = 49;
+
+/// Collect `.text` function symbols as (address, size, name) from an lld map
+fn parse_map(text: &str) -> Vec<(u64, u32, String)> {
+    let mut in_text = false;
+    let mut section_end = 0;
+    let mut symbols: Vec<(u64, u64, String)> = Vec::new();
+
+    for line in text.lines().skip(1) {
+        let Some(name) = line.get(MAP_NAME_COLUMN..) else {
+            continue;
+        };
+        let mut fields = line[..MAP_NAME_COLUMN].split_whitespace();
+        let (Some(vma), Some(_lma), Some(size)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        let (Ok(vma), Ok(size)) = (u64::from_str_radix(vma, 16), u64::from_str_radix(size, 16)) else {
+            continue;
+        };
+
+        match name.len() - name.trim_start().len() {
+            // Output section
+            0 => in_text = name == ".text",
+            // Input section, bounds the symbols it holds
+            8 => section_end = vma + size,
+            16 if in_text && !name.contains(" = ") => {
+                symbols.push((vma, section_end, demangled_name(name.trim())));
+            }
+            _ => {}
+        }
+    }
+
+    symbols.sort_by_key(|&(address, _, _)| address);
+    symbols.dedup_by_key(|&mut (address, _, _)| address);
+
+    let mut sized = Vec::with_capacity(symbols.len());
+    for i in 0..symbols.len() {
+        let (address, end, ref name) = symbols[i];
+        let next = symbols.get(i + 1).map_or(end, |s| s.0.min(end));
+        let size = u32::try_from(next.saturating_sub(address)).unwrap_or(u32::MAX);
+        sized.push((address, size, name.clone()));
+    }
+    sized
+}
+
+/// Drop the hash suffix and LLVM uniquing tag lld leaves on demangled names
+fn demangled_name(name: &str) -> String {
+    let name = name.split(" (.llvm.").next().unwrap();
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            path.to_string()
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// Table layout, all little-endian:
+/// `count: u32`, then `count` entries of `address: u64, size: u32,
+/// name_offset: u32` sorted by address, then the NUL-free name bytes, each
+/// name running to the next entry's offset (or the end of the table).
+fn encode(symbols: &[(u64, u32, String)]) -> Vec<u8> {
+    let mut table = Vec::new();
+    let mut names = Vec::new();
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    for (address, size, name) in symbols {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+    table
 }
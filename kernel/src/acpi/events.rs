@@ -0,0 +1,98 @@
+//! ACPI fixed events and the SCI interrupt
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use super::{fadt, pm, AcpiError, Fadt};
+use crate::arch::x86_64::interrupts;
+use crate::arch::x86_64::port::Port;
+use crate::task::deferred;
+
+/// PM1 status/enable: power button
+pub const PWRBTN: u16 = 1 << 8;
+
+/// PM1 status: wake, set when resuming from a sleep state
+pub const WAK_STS: u16 = 1 << 15;
+
+/// Power button presses seen
+static POWER_BUTTON_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Enable ACPI mode, arm the power button event and claim the SCI
+pub fn init() -> Result<(), AcpiError> {
+    let fadt = fadt::fadt().ok_or(AcpiError::NotInitialized)?;
+    if fadt.pm1a_event_block == 0 {
+        return Err(AcpiError::Unsupported);
+    }
+    pm::enable_acpi_mode()?;
+
+    // Stale status would fire the moment the event is enabled
+    for block in event_blocks(&fadt) {
+        unsafe {
+            status_port(block).write(PWRBTN);
+            let enable = enable_port(&fadt, block);
+            let value = enable.read();
+            enable.write(value | PWRBTN);
+        }
+    }
+
+    let sci = fadt.sci_interrupt as u8;
+    interrupts::register_irq(sci, sci_interrupt).map_err(|_| AcpiError::Unsupported)?;
+    crate::serial_println!("ACPI SCI on IRQ {}, power button armed", sci);
+    Ok(())
+}
+
+/// Number of power button presses handled
+pub fn power_button_events() -> u64 {
+    POWER_BUTTON_EVENTS.load(Ordering::Relaxed)
+}
+
+/// PM1a and, if present, PM1b event block ports
+fn event_blocks(fadt: &Fadt) -> impl Iterator<Item = u16> {
+    [fadt.pm1a_event_block, fadt.pm1b_event_block]
+        .into_iter()
+        .filter(|&block| block != 0)
+        .map(|block| block as u16)
+}
+
+/// Status register, the first half of an event block
+fn status_port(block: u16) -> Port<u16> {
+    Port::new(block)
+}
+
+/// Enable register, the second half of an event block
+fn enable_port(fadt: &Fadt, block: u16) -> Port<u16> {
+    Port::new(block + fadt.pm1_event_length as u16 / 2)
+}
+
+fn sci_interrupt(_frame: &InterruptStackFrame) {
+    let Some(fadt) = fadt::fadt() else {
+        return;
+    };
+
+    let mut pressed = false;
+    for block in event_blocks(&fadt) {
+        unsafe {
+            let status = status_port(block);
+            let enabled = enable_port(&fadt, block).read();
+            let pending = status.read() & (enabled | WAK_STS);
+            if pending == 0 {
+                continue;
+            }
+            // Status bits are write-one-to-clear
+            status.write(pending);
+            pressed |= pending & PWRBTN != 0;
+        }
+    }
+
+    if pressed {
+        POWER_BUTTON_EVENTS.fetch_add(1, Ordering::Relaxed);
+        // Shut down from task context, not with the SCI in service
+        if !deferred::queue_work(power_button_pressed, 0) {
+            crate::serial_println!("ACPI: power button work dropped");
+        }
+    }
+}
+
+fn power_button_pressed(_: usize) {
+    crate::serial_println!("ACPI: power button pressed");
+    crate::power::shutdown();
+}
@@ -0,0 +1,168 @@
+//! Fixed ACPI Description Table and DSDT sleep objects
+
+use super::{AcpiError, SdtHeader};
+use crate::sync::SeqLock;
+
+/// ACPI Generic Address Structure
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Address space ID for system I/O ports
+    pub const SYSTEM_IO: u8 = 1;
+}
+
+/// Fixed ACPI Description Table, up to the ACPI 2.0 X_DSDT field
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub header: SdtHeader,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    reserved0: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_request: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_length: u8,
+    pub gpe1_length: u8,
+    pub gpe1_base: u8,
+    pub cstate_control: u8,
+    pub worst_c2_latency: u16,
+    pub worst_c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    pub century: u8,
+    pub boot_architecture_flags: u16,
+    reserved1: u8,
+    pub flags: u32,
+    pub reset_register: GenericAddress,
+    pub reset_value: u8,
+    pub arm_boot_architecture_flags: u16,
+    pub minor_version: u8,
+    pub x_firmware_ctrl: u64,
+    pub x_dsdt: u64,
+}
+
+impl Fadt {
+    /// RESET_REG is supported
+    pub const RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+    /// Physical address of the DSDT, preferring the 64-bit field
+    pub fn dsdt_address(&self) -> u64 {
+        if self.x_dsdt != 0 {
+            self.x_dsdt
+        } else {
+            self.dsdt as u64
+        }
+    }
+}
+
+/// SLP_TYP values for PM1a and PM1b control registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Parsed FADT, read lock-free so the panic path can use it
+static FADT: SeqLock<Option<Fadt>> = SeqLock::new(None);
+
+/// Locate and cache the FADT
+pub(super) fn init() -> Result<(), AcpiError> {
+    let addr = super::find_table(b"FACP")?;
+    let length = unsafe { super::read_header(addr) }.length as usize;
+
+    // Fields beyond an old table's length read as zero
+    let mut fadt = [0u8; core::mem::size_of::<Fadt>()];
+    let copy = length.min(fadt.len());
+    unsafe {
+        core::ptr::copy_nonoverlapping(addr as *const u8, fadt.as_mut_ptr(), copy);
+    }
+    let fadt = unsafe { core::ptr::read_unaligned(fadt.as_ptr() as *const Fadt) };
+    FADT.write(Some(fadt));
+    Ok(())
+}
+
+/// Cached FADT
+pub fn fadt() -> Option<Fadt> {
+    FADT.read()
+}
+
+/// Look up the `\_Sx` package in the DSDT
+pub fn sleep_type(state: u8) -> Result<SleepType, AcpiError> {
+    let dsdt = fadt().ok_or(AcpiError::NotInitialized)?.dsdt_address();
+    super::validate_table(dsdt)?;
+    let header_size = core::mem::size_of::<SdtHeader>();
+    let length = unsafe { super::read_header(dsdt) }.length as usize;
+    let aml = unsafe {
+        core::slice::from_raw_parts((dsdt + header_size as u64) as *const u8, length - header_size)
+    };
+
+    let name = [b'_', b'S', b'0' + state, b'_'];
+    for i in 1..aml.len().saturating_sub(name.len()) {
+        if aml[i..i + name.len()] != name {
+            continue;
+        }
+        // NameOp, optionally followed by a root prefix
+        let named = aml[i - 1] == 0x08 || (i >= 2 && aml[i - 2] == 0x08 && aml[i - 1] == b'\\');
+        if !named {
+            continue;
+        }
+
+        let mut pos = i + name.len();
+        if aml.get(pos) != Some(&0x12) {
+            continue; // Not a PackageOp
+        }
+        pos += 1;
+        // PkgLength lead byte bits 6-7 count the extra length bytes
+        let lead = *aml.get(pos).ok_or(AcpiError::InvalidAml)?;
+        pos += 1 + (lead >> 6) as usize;
+        pos += 1; // NumElements
+
+        let a = read_integer(aml, &mut pos)?;
+        let b = read_integer(aml, &mut pos)?;
+        return Ok(SleepType { a, b });
+    }
+    Err(AcpiError::TableNotFound)
+}
+
+/// Read a small AML integer, keeping the low byte
+fn read_integer(aml: &[u8], pos: &mut usize) -> Result<u8, AcpiError> {
+    let (value, size) = match aml.get(*pos) {
+        Some(0x00) => (Some(0), 1),                    // ZeroOp
+        Some(0x01) => (Some(1), 1),                    // OneOp
+        Some(0x0A) => (aml.get(*pos + 1).copied(), 2), // BytePrefix
+        Some(0x0B) => (aml.get(*pos + 1).copied(), 3), // WordPrefix
+        Some(0x0C) => (aml.get(*pos + 1).copied(), 5), // DWordPrefix
+        _ => return Err(AcpiError::InvalidAml),
+    };
+    *pos += size;
+    value.ok_or(AcpiError::InvalidAml)
+}
@@ -0,0 +1,243 @@
+//! ACPI table discovery
+//!
+//! Tables are read in place through the identity map.
+
+pub mod events;
+pub mod fadt;
+pub mod pm;
+pub mod sleep;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::mm::paging;
+
+// Re-export core types
+pub use fadt::{Fadt, GenericAddress, SleepType};
+
+/// Errors that can occur while reading ACPI tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No RSDP from the bootloader or in the BIOS search areas
+    RsdpNotFound,
+    /// Table checksum mismatch
+    BadChecksum,
+    /// Requested table is not present
+    TableNotFound,
+    /// Table lies outside mapped memory
+    Unmapped,
+    /// ACPI has not been initialized
+    NotInitialized,
+    /// AML object missing or in an unsupported encoding
+    InvalidAml,
+    /// Feature not provided by this platform
+    Unsupported,
+    /// Hardware did not respond in time
+    Timeout,
+}
+
+impl core::fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AcpiError::RsdpNotFound => write!(f, "RSDP not found"),
+            AcpiError::BadChecksum => write!(f, "ACPI table checksum mismatch"),
+            AcpiError::TableNotFound => write!(f, "ACPI table not found"),
+            AcpiError::Unmapped => write!(f, "ACPI table not mapped"),
+            AcpiError::NotInitialized => write!(f, "ACPI not initialized"),
+            AcpiError::InvalidAml => write!(f, "Unsupported AML encoding"),
+            AcpiError::Unsupported => write!(f, "Not supported by platform"),
+            AcpiError::Timeout => write!(f, "ACPI hardware timeout"),
+        }
+    }
+}
+
+/// Root System Description Pointer, ACPI 2.0 layout
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Size of the ACPI 1.0 part of the RSDP
+const RSDP_V1_SIZE: usize = 20;
+
+/// Common header of every system description table
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Physical address of the RSDT or XSDT, 0 before `init`
+static ROOT_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// Root table is an XSDT with 64-bit entries
+static ROOT_IS_XSDT: AtomicBool = AtomicBool::new(false);
+
+/// Locate the root table and parse the FADT
+pub fn init() -> Result<(), AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let rsdp = unsafe { core::ptr::read_unaligned(rsdp as *const Rsdp) };
+
+    let (root, is_xsdt) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, true)
+    } else {
+        (rsdp.rsdt_address as u64, false)
+    };
+    validate_table(root)?;
+    ROOT_IS_XSDT.store(is_xsdt, Ordering::Relaxed);
+    ROOT_TABLE.store(root, Ordering::Release);
+
+    fadt::init()?;
+    crate::serial_println!(
+        "ACPI revision {} with {} root table",
+        rsdp.revision,
+        if is_xsdt { "XSDT" } else { "RSDT" }
+    );
+
+    // Tables stay usable even if the SCI cannot be set up
+    if let Err(e) = events::init() {
+        crate::serial_println!("ACPI events unavailable: {}", e);
+    }
+    Ok(())
+}
+
+/// Check if ACPI tables are available
+pub fn is_initialized() -> bool {
+    ROOT_TABLE.load(Ordering::Acquire) != 0
+}
+
+/// Find a table by signature, returning its physical address
+pub fn find_table(signature: &[u8; 4]) -> Result<u64, AcpiError> {
+    let root = ROOT_TABLE.load(Ordering::Acquire);
+    if root == 0 {
+        return Err(AcpiError::NotInitialized);
+    }
+
+    let header = unsafe { read_header(root) };
+    let entry_size = if ROOT_IS_XSDT.load(Ordering::Relaxed) { 8 } else { 4 };
+    let entries = (header.length as usize - core::mem::size_of::<SdtHeader>()) / entry_size;
+    let base = root + core::mem::size_of::<SdtHeader>() as u64;
+
+    for i in 0..entries {
+        let entry = base + (i * entry_size) as u64;
+        let table = unsafe {
+            if entry_size == 8 {
+                core::ptr::read_unaligned(entry as *const u64)
+            } else {
+                core::ptr::read_unaligned(entry as *const u32) as u64
+            }
+        };
+        if !is_readable(table, core::mem::size_of::<SdtHeader>()) {
+            continue;
+        }
+        if unsafe { read_header(table) }.signature == *signature {
+            validate_table(table)?;
+            return Ok(table);
+        }
+    }
+    Err(AcpiError::TableNotFound)
+}
+
+/// Read a table header
+///
+/// # Safety
+/// `addr` must point to a mapped table.
+pub unsafe fn read_header(addr: u64) -> SdtHeader {
+    core::ptr::read_unaligned(addr as *const SdtHeader)
+}
+
+/// Check mapping and checksum of a whole table
+pub fn validate_table(addr: u64) -> Result<(), AcpiError> {
+    if !is_readable(addr, core::mem::size_of::<SdtHeader>()) {
+        return Err(AcpiError::Unmapped);
+    }
+    let length = unsafe { read_header(addr) }.length as usize;
+    if !is_readable(addr, length) {
+        return Err(AcpiError::Unmapped);
+    }
+    if checksum(addr, length) != 0 {
+        return Err(AcpiError::BadChecksum);
+    }
+    Ok(())
+}
+
+/// Check that every page of a physical range is identity mapped
+pub fn is_readable(addr: u64, len: usize) -> bool {
+    if addr == 0 || len == 0 {
+        return false;
+    }
+    let Some(last) = addr.checked_add(len as u64 - 1) else {
+        return false;
+    };
+    let mut page = addr & !0xFFF;
+    while page <= last {
+        if !paging::is_mapped(page) {
+            return false;
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+fn checksum(addr: u64, len: usize) -> u8 {
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Take the RSDP from the bootloader, or scan the EBDA and BIOS ROM area
+///
+/// UEFI has no BIOS data area to find the EBDA through and need not put
+/// the RSDP below 1MB, so the scan is only done under BIOS.
+fn find_rsdp() -> Option<u64> {
+    let protocol = crate::boot::protocol();
+    if let Some(rsdp) = protocol.rsdp().filter(|&rsdp| is_valid_rsdp(rsdp)) {
+        return Some(rsdp);
+    }
+    if protocol.boot_mode() == Some(crate::boot::BootMode::Uefi) {
+        return None;
+    }
+    let ebda = unsafe { core::ptr::read_volatile(0x40E as *const u16) } as u64 * 16;
+    if ebda != 0 {
+        if let Some(rsdp) = scan_rsdp(ebda, 1024) {
+            return Some(rsdp);
+        }
+    }
+    scan_rsdp(0xE0000, 0x20000)
+}
+
+fn scan_rsdp(start: u64, len: u64) -> Option<u64> {
+    if !is_readable(start, len as usize) {
+        return None;
+    }
+    (start..start + len).step_by(16).find(|&addr| is_valid_rsdp(addr))
+}
+
+/// Check the signature and checksums of a mapped RSDP
+fn is_valid_rsdp(addr: u64) -> bool {
+    if !is_readable(addr, RSDP_V1_SIZE) {
+        return false;
+    }
+    let signature = unsafe { core::slice::from_raw_parts(addr as *const u8, 8) };
+    if signature != b"RSD PTR " || checksum(addr, RSDP_V1_SIZE) != 0 {
+        return false;
+    }
+    // ACPI 2.0+ also checksums the extended part
+    let rsdp = unsafe { core::ptr::read_unaligned(addr as *const Rsdp) };
+    rsdp.revision < 2
+        || (is_readable(addr, rsdp.length as usize) && checksum(addr, rsdp.length as usize) == 0)
+}
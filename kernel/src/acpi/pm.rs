@@ -0,0 +1,87 @@
+//! ACPI power management registers
+
+use crate::arch::x86_64::port::Port;
+use super::{fadt, AcpiError, Fadt, GenericAddress};
+
+/// PM1 control: SCI enabled, set once the chipset is in ACPI mode
+pub const SCI_EN: u16 = 1 << 0;
+
+/// PM1 control: sleep enable, starts the transition to SLP_TYP
+pub const SLP_EN: u16 = 1 << 13;
+
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+
+/// Polls of SCI_EN after requesting ACPI mode
+const ACPI_ENABLE_POLLS: usize = 1_000_000;
+
+/// Switch the chipset from legacy to ACPI mode, if firmware has not already
+pub fn enable_acpi_mode() -> Result<(), AcpiError> {
+    let fadt = fadt::fadt().ok_or(AcpiError::NotInitialized)?;
+    if fadt.pm1a_control_block == 0 {
+        return Err(AcpiError::Unsupported);
+    }
+
+    let pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if unsafe { pm1a.read() } & SCI_EN != 0 {
+        return Ok(());
+    }
+    // No SMI command port means the system is always in ACPI mode
+    if fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return Ok(());
+    }
+
+    unsafe {
+        Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable);
+    }
+    for _ in 0..ACPI_ENABLE_POLLS {
+        if unsafe { pm1a.read() } & SCI_EN != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(AcpiError::Timeout)
+}
+
+/// Enter sleep state `Sx`, only returns on failure or after waking
+pub fn enter_sleep_state(state: u8) -> Result<(), AcpiError> {
+    let fadt = fadt::fadt().ok_or(AcpiError::NotInitialized)?;
+    let sleep = fadt::sleep_type(state)?;
+    enable_acpi_mode()?;
+
+    // SLP_TYP is written first, then SLP_EN triggers the transition
+    write_sleep_type(&fadt, sleep.a, sleep.b, 0);
+    write_sleep_type(&fadt, sleep.a, sleep.b, SLP_EN);
+    Ok(())
+}
+
+fn write_sleep_type(fadt: &Fadt, typ_a: u8, typ_b: u8, enable: u16) {
+    let blocks = [(fadt.pm1a_control_block, typ_a), (fadt.pm1b_control_block, typ_b)];
+    for (block, typ) in blocks {
+        if block == 0 {
+            continue;
+        }
+        let port: Port<u16> = Port::new(block as u16);
+        unsafe {
+            let value = port.read() & !(SLP_TYP_MASK | SLP_EN);
+            port.write(value | ((typ as u16) << SLP_TYP_SHIFT) | enable);
+        }
+    }
+}
+
+/// Reset the system through the FADT reset register, if it is an I/O port
+pub fn reset() -> Result<(), AcpiError> {
+    let fadt = fadt::fadt().ok_or(AcpiError::NotInitialized)?;
+    let register: GenericAddress = fadt.reset_register;
+    if fadt.flags & Fadt::RESET_REG_SUPPORTED == 0
+        || register.address_space != GenericAddress::SYSTEM_IO
+        || register.address == 0
+    {
+        return Err(AcpiError::Unsupported);
+    }
+
+    unsafe {
+        Port::<u8>::new(register.address as u16).write(fadt.reset_value);
+    }
+    Ok(())
+}
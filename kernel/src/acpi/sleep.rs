@@ -0,0 +1,117 @@
+//! S3 suspend-to-RAM scaffolding
+//!
+//! Firmware resumes from S3 in real mode at the FACS waking vector. The
+//! 16-bit trampoline that gets back to long mode and calls `restore_cpu_state`
+//! is not in place yet, so `suspend_to_ram` refuses to sleep without one.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::structures::DescriptorTablePointer;
+use super::{fadt, pm, AcpiError};
+
+/// Firmware ACPI Control Structure
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Facs {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub hardware_signature: u32,
+    pub firmware_waking_vector: u32,
+    pub global_lock: u32,
+    pub flags: u32,
+    pub x_firmware_waking_vector: u64,
+    pub version: u8,
+}
+
+/// CPU state that S3 loses and the resume path must reload
+#[derive(Debug, Clone, Copy)]
+pub struct CpuState {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub gdt: DescriptorTablePointer,
+    pub idt: DescriptorTablePointer,
+}
+
+/// State saved by the last suspend
+static SAVED_STATE: Mutex<Option<CpuState>> = Mutex::new(None);
+
+/// Physical address of the real-mode resume trampoline, 0 if none
+static RESUME_TRAMPOLINE: AtomicU32 = AtomicU32::new(0);
+
+/// Physical address of the FACS
+pub fn facs_address() -> Result<u64, AcpiError> {
+    let fadt = fadt::fadt().ok_or(AcpiError::NotInitialized)?;
+    let addr = if fadt.x_firmware_ctrl != 0 {
+        fadt.x_firmware_ctrl
+    } else {
+        fadt.firmware_ctrl as u64
+    };
+    if !super::is_readable(addr, core::mem::size_of::<Facs>()) {
+        return Err(AcpiError::Unmapped);
+    }
+    let signature = unsafe { core::ptr::read_unaligned(addr as *const [u8; 4]) };
+    if signature != *b"FACS" {
+        return Err(AcpiError::TableNotFound);
+    }
+    Ok(addr)
+}
+
+/// Register the real-mode resume trampoline, which must lie below 1MB
+pub fn set_resume_trampoline(addr: u32) {
+    RESUME_TRAMPOLINE.store(addr, Ordering::Relaxed);
+}
+
+/// Snapshot the control registers and descriptor tables
+pub fn save_cpu_state() -> CpuState {
+    use x86_64::instructions::tables::{sgdt, sidt};
+    use x86_64::registers::control::{Cr0, Cr3, Cr4};
+    use x86_64::registers::model_specific::Efer;
+
+    let state = CpuState {
+        cr0: Cr0::read_raw(),
+        cr3: Cr3::read_raw().0.start_address().as_u64(),
+        cr4: Cr4::read_raw(),
+        efer: Efer::read_raw(),
+        gdt: sgdt(),
+        idt: sidt(),
+    };
+    *SAVED_STATE.lock() = Some(state);
+    state
+}
+
+/// Reload the descriptor tables saved by the last suspend
+///
+/// # Safety
+/// Must run on the resume path, with paging and long mode already
+/// re-established by the trampoline.
+pub unsafe fn restore_cpu_state() -> Result<(), AcpiError> {
+    let state = (*SAVED_STATE.lock()).ok_or(AcpiError::NotInitialized)?;
+    x86_64::instructions::tables::lgdt(&state.gdt);
+    x86_64::instructions::tables::lidt(&state.idt);
+    Ok(())
+}
+
+/// Enter S3, returns once the machine wakes or if sleeping fails
+pub fn suspend_to_ram() -> Result<(), AcpiError> {
+    let trampoline = RESUME_TRAMPOLINE.load(Ordering::Relaxed);
+    if trampoline == 0 {
+        return Err(AcpiError::Unsupported);
+    }
+    let facs = facs_address()?;
+    // Probe early so a missing \_S3 fails before any state is touched
+    fadt::sleep_type(3)?;
+
+    let were_enabled = crate::arch::x86_64::interrupts::save_and_disable();
+    save_cpu_state();
+    unsafe {
+        let vector = core::ptr::addr_of_mut!((*(facs as *mut Facs)).firmware_waking_vector);
+        core::ptr::write_unaligned(vector, trampoline);
+        // Memory stays powered, but dirty cache lines do not
+        core::arch::asm!("wbinvd", options(nostack));
+    }
+    let result = pm::enter_sleep_state(3);
+    crate::arch::x86_64::interrupts::restore(were_enabled);
+    result
+}
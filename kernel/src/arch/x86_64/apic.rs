@@ -0,0 +1,292 @@
+//! Local APIC
+//!
+//! Runs in x2APIC mode through MSRs when the CPU supports it and xAPIC
+//! mode through the MMIO page otherwise; callers see the same API either
+//! way. Legacy IRQs keep arriving from the 8259 PICs through LINT0, as the
+//! firmware set it up, so the local APIC only carries IPIs and its own
+//! interrupts for now.
+
+use core::arch::x86_64::__cpuid;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::registers::model_specific::Msr;
+use crate::mm::mmio::{self, Mmio};
+use crate::mm::PhysicalAddress;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+/// IA32_APIC_BASE: x2APIC mode
+const APIC_BASE_EXTD: u64 = 1 << 10;
+/// IA32_APIC_BASE: APIC globally enabled
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// First x2APIC MSR, register offsets map to `X2APIC_MSR_BASE + offset / 16`
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// Register offsets in the xAPIC MMIO page
+const REG_ID: u32 = 0x020;
+const REG_VERSION: u32 = 0x030;
+const REG_TPR: u32 = 0x080;
+const REG_EOI: u32 = 0x0B0;
+const REG_SVR: u32 = 0x0F0;
+const REG_ESR: u32 = 0x280;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+
+/// Spurious vector register: APIC software enable
+const SVR_ENABLE: u32 = 1 << 8;
+/// ICR: previous IPI not yet accepted, xAPIC only
+const ICR_SEND_PENDING: u32 = 1 << 12;
+/// ICR: level assert, required for everything but INIT de-assert
+const ICR_ASSERT: u32 = 1 << 14;
+
+/// CPUID leaf 1 EDX: local APIC present
+const CPUID_APIC: u32 = 1 << 9;
+/// CPUID leaf 1 ECX: x2APIC supported
+const CPUID_X2APIC: u32 = 1 << 21;
+
+/// Vector of spurious APIC interrupts, which must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Access mode of the local APIC
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    XApic = 1,
+    X2Apic = 2,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::XApic => "xAPIC",
+            Mode::X2Apic => "x2APIC",
+        }
+    }
+}
+
+/// Errors that can occur while enabling the local APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// CPU has no local APIC
+    NotPresent,
+    /// xAPIC register page could not be mapped
+    MapFailed,
+}
+
+impl core::fmt::Display for ApicError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ApicError::NotPresent => write!(f, "No local APIC"),
+            ApicError::MapFailed => write!(f, "Cannot map local APIC registers"),
+        }
+    }
+}
+
+/// Target of an inter-processor interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// One CPU by APIC ID
+    Apic(u32),
+    SelfOnly,
+    AllIncludingSelf,
+    AllExcludingSelf,
+}
+
+/// What an inter-processor interrupt delivers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Interrupt on the given vector
+    Fixed(u8),
+    Nmi,
+    /// Reset the target into wait-for-SIPI
+    Init,
+    /// Start the target in real mode at `page * 4096`
+    Startup(u8),
+}
+
+/// `Mode` as u8, 0 until `init`
+static MODE: AtomicU8 = AtomicU8::new(0);
+
+/// xAPIC register page
+static MMIO: spin::Once<Mmio> = spin::Once::new();
+
+/// Enable the local APIC of the executing CPU
+///
+/// Prefers x2APIC unless the command line has `nox2apic`.
+pub fn init() -> Result<Mode, ApicError> {
+    let leaf1 = unsafe { __cpuid(1) };
+    if leaf1.edx & CPUID_APIC == 0 {
+        return Err(ApicError::NotPresent);
+    }
+
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let mut base = unsafe { base_msr.read() };
+    let mode = if leaf1.ecx & CPUID_X2APIC != 0 && !crate::cmdline::has_flag("nox2apic") {
+        // x2APIC can only be entered from enabled xAPIC mode
+        unsafe {
+            if base & APIC_BASE_ENABLE == 0 {
+                base |= APIC_BASE_ENABLE;
+                base_msr.write(base);
+            }
+            base_msr.write(base | APIC_BASE_EXTD);
+        }
+        Mode::X2Apic
+    } else if base & APIC_BASE_EXTD != 0 {
+        // Firmware left x2APIC on, leaving it needs a full APIC reset
+        Mode::X2Apic
+    } else {
+        let address = base & APIC_BASE_ADDRESS_MASK;
+        let registers = mmio::map_region(PhysicalAddress::new(address), 0x1000)
+            .map_err(|_| ApicError::MapFailed)?;
+        MMIO.call_once(|| registers);
+        unsafe {
+            base_msr.write(base | APIC_BASE_ENABLE);
+        }
+        Mode::XApic
+    };
+    MODE.store(mode as u8, Ordering::Release);
+
+    // Accept every priority, clear stale errors and software-enable
+    write(REG_TPR, 0);
+    write(REG_ESR, 0);
+    write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+    crate::serial_println!("Local APIC {} in {} mode, version {:#x}",
+        id(), mode.name(), read(REG_VERSION) & 0xFF);
+    Ok(mode)
+}
+
+/// Access mode, `None` before `init`
+pub fn mode() -> Option<Mode> {
+    match MODE.load(Ordering::Acquire) {
+        1 => Some(Mode::XApic),
+        2 => Some(Mode::X2Apic),
+        _ => None,
+    }
+}
+
+/// Physical range of the xAPIC register page, `None` in x2APIC mode
+pub fn mmio_range() -> Option<Range<u64>> {
+    MMIO.get().map(|registers| {
+        let base = registers.phys().as_u64();
+        base..base + registers.len() as u64
+    })
+}
+
+/// Check if the local APIC has been enabled
+pub fn is_enabled() -> bool {
+    mode().is_some()
+}
+
+fn read(reg: u32) -> u32 {
+    match mode() {
+        Some(Mode::X2Apic) => unsafe { Msr::new(X2APIC_MSR_BASE + reg / 16).read() as u32 },
+        Some(Mode::XApic) => MMIO.get().map_or(0, |registers| registers.read(reg as usize)),
+        None => 0,
+    }
+}
+
+fn write(reg: u32, value: u32) {
+    match mode() {
+        Some(Mode::X2Apic) => unsafe { Msr::new(X2APIC_MSR_BASE + reg / 16).write(value as u64) },
+        Some(Mode::XApic) => {
+            if let Some(registers) = MMIO.get() {
+                registers.write(reg as usize, value);
+            }
+        }
+        None => {}
+    }
+}
+
+/// APIC ID of the executing CPU
+pub fn id() -> u32 {
+    match mode() {
+        Some(Mode::X2Apic) => read(REG_ID),
+        Some(Mode::XApic) => read(REG_ID) >> 24,
+        None => super::cpuid::apic_id(),
+    }
+}
+
+/// Signal end of interrupt for an APIC-delivered vector
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+/// Send a fixed interrupt to `destination`
+pub fn send_ipi(destination: Destination, vector: u8) {
+    send(destination, Delivery::Fixed(vector));
+}
+
+/// Send an inter-processor interrupt
+pub fn send(destination: Destination, delivery: Delivery) {
+    let (mode_bits, vector) = match delivery {
+        Delivery::Fixed(vector) => (0b000, vector),
+        Delivery::Nmi => (0b100, 0),
+        Delivery::Init => (0b101, 0),
+        Delivery::Startup(page) => (0b110, page),
+    };
+    let (shorthand, target) = match destination {
+        Destination::Apic(id) => (0b00, id),
+        Destination::SelfOnly => (0b01, 0),
+        Destination::AllIncludingSelf => (0b10, 0),
+        Destination::AllExcludingSelf => (0b11, 0),
+    };
+    let low = vector as u32 | mode_bits << 8 | ICR_ASSERT | shorthand << 18;
+
+    match mode() {
+        // One 64-bit MSR write, no delivery status to poll
+        Some(Mode::X2Apic) => unsafe {
+            Msr::new(X2APIC_MSR_BASE + REG_ICR_LOW / 16).write((target as u64) << 32 | low as u64);
+        },
+        Some(Mode::XApic) => {
+            x86_64::instructions::interrupts::without_interrupts(|| {
+                wait_for_delivery();
+                write(REG_ICR_HIGH, target << 24);
+                write(REG_ICR_LOW, low);
+                wait_for_delivery();
+            });
+        }
+        None => {}
+    }
+}
+
+fn wait_for_delivery() {
+    while read(REG_ICR_LOW) & ICR_SEND_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+static SELFTEST_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Self-IPI on a free vector must reach its handler
+fn selftest() -> Result<(), &'static str> {
+    use super::interrupts;
+
+    if !is_enabled() {
+        return Err("local APIC not enabled");
+    }
+    let vector = (interrupts::FIRST_DYNAMIC_VECTOR..SPURIOUS_VECTOR)
+        .rev()
+        .find(|&vector| !interrupts::is_registered(vector))
+        .ok_or("no free vector")?;
+    interrupts::register_handler(vector, |_| {
+        SELFTEST_HITS.fetch_add(1, Ordering::Relaxed);
+    })
+    .map_err(|_| "cannot register test vector")?;
+
+    let before = SELFTEST_HITS.load(Ordering::Relaxed);
+    send_ipi(Destination::SelfOnly, vector);
+    let mut delivered = false;
+    for _ in 0..1_000_000 {
+        if SELFTEST_HITS.load(Ordering::Relaxed) != before {
+            delivered = true;
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    let _ = interrupts::unregister_handler(vector);
+    if delivered { Ok(()) } else { Err("self-IPI not delivered") }
+}
+
+crate::selftest!("apic", selftest);
@@ -0,0 +1,47 @@
+//! Kernel context switching
+
+/// Callee-saved registers pushed by `switch_context`
+const SAVED_REGISTERS: usize = 6;
+
+/// Switch stacks, saving the current stack pointer into `old_rsp`
+///
+/// # Safety
+/// `new_rsp` must point to a stack prepared by `init_stack` or saved by a
+/// previous call, and interrupts must be disabled.
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch_context(old_rsp: *mut u64, new_rsp: u64) {
+    core::arch::naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    );
+}
+
+/// Prepare a fresh stack so the first switch to it returns into `entry`
+///
+/// Returns the initial stack pointer.
+pub fn init_stack(stack: &mut [u8], entry: extern "C" fn() -> !) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xF;
+    let words = top as *mut u64;
+    unsafe {
+        // Alignment slot so `entry` starts with the ABI stack alignment
+        *words.sub(1) = 0;
+        *words.sub(2) = entry as usize as u64;
+        for i in 0..SAVED_REGISTERS {
+            *words.sub(3 + i) = 0;
+        }
+    }
+    top - ((2 + SAVED_REGISTERS) * 8) as u64
+}
@@ -0,0 +1,36 @@
+//! Per-CPU identification
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Maximum number of CPUs with per-CPU state
+pub const MAX_CPUS: usize = 1;
+
+/// CPUs running kernel code, the boot CPU until SMP bring-up
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// Index of the executing CPU, always 0 until SMP bring-up
+#[inline]
+pub fn current_id() -> usize {
+    0
+}
+
+/// Number of CPUs currently online
+pub fn online_count() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// Count a newly started CPU as online
+///
+/// Must be called once the CPU can take interrupts, since cross-CPU
+/// requests such as TLB shootdowns wait for every online CPU.
+pub fn mark_online() {
+    ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Package, core and thread of the executing CPU
+///
+/// SMP bring-up should number CPUs in this order so SMT siblings and the
+/// cores of one package get adjacent IDs.
+pub fn current_location() -> super::cpuid::CpuLocation {
+    super::cpuid::cpuinfo().topology.locate(super::cpuid::apic_id())
+}
@@ -0,0 +1,481 @@
+//! CPUID Identification
+//!
+//! Vendor, model, topology, caches and TLBs, read once on first use.
+//! Topology comes from the extended topology leaves (0x1F, then 0xB) and
+//! falls back to AMD leaf 0x80000008 or the legacy leaf 1 and leaf 4
+//! counts. TLBs are listed from Intel leaf 0x18 or the AMD extended
+//! leaves; older Intel parts only describe them through leaf 2
+//! descriptors, which are not decoded.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt::Write;
+use cosmos_common::fmt::HumanBytes;
+use spin::Once;
+
+/// Most cache levels and TLBs kept per CPU
+pub const MAX_CACHES: usize = 8;
+pub const MAX_TLBS: usize = 8;
+
+/// Page sizes a TLB caches
+pub const PAGE_4K: u8 = 1 << 0;
+pub const PAGE_2M: u8 = 1 << 1;
+pub const PAGE_4M: u8 = 1 << 2;
+pub const PAGE_1G: u8 = 1 << 3;
+
+/// Leaf 1 EDX: more than one logical processor per package
+const HTT: u32 = 1 << 28;
+/// Leaf 0x80000001 ECX: topology extensions, leaves 0x8000001D/E
+const TOPOEXT: u32 = 1 << 22;
+
+/// Extended topology level types
+const LEVEL_SMT: u32 = 1;
+
+/// Kind of cache or TLB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+impl CacheKind {
+    /// Short name as used in reports
+    pub fn name(self) -> &'static str {
+        match self {
+            CacheKind::Data => "data",
+            CacheKind::Instruction => "instruction",
+            CacheKind::Unified => "unified",
+        }
+    }
+}
+
+/// One cache level
+#[derive(Debug, Clone, Copy)]
+pub struct Cache {
+    pub level: u8,
+    pub kind: CacheKind,
+    /// Total size in bytes
+    pub size: usize,
+    /// Associativity, 0 for fully associative
+    pub ways: u32,
+    pub line_size: u32,
+    /// Logical processors sharing this cache, 0 if unknown
+    pub shared_by: u32,
+}
+
+/// One TLB
+#[derive(Debug, Clone, Copy)]
+pub struct Tlb {
+    pub level: u8,
+    pub kind: CacheKind,
+    /// `PAGE_*` bits
+    pub page_sizes: u8,
+    pub entries: u32,
+    /// Associativity, 0 for fully associative
+    pub ways: u32,
+}
+
+/// How APIC IDs split into package, core and thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Topology {
+    /// Low APIC ID bits selecting the thread within a core
+    pub thread_bits: u32,
+    /// APIC ID bits above `thread_bits` selecting the core in a package
+    pub core_bits: u32,
+    pub threads_per_core: u32,
+    pub logical_per_package: u32,
+}
+
+/// Position of a logical processor in the topology
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuLocation {
+    pub package: u32,
+    pub core: u32,
+    pub thread: u32,
+}
+
+impl Topology {
+    /// Cores in each package
+    pub fn cores_per_package(&self) -> u32 {
+        (self.logical_per_package / self.threads_per_core.max(1)).max(1)
+    }
+
+    /// Split an APIC ID into package, core and thread
+    ///
+    /// Numbering CPUs by location keeps SMT siblings and cores of one
+    /// package together, whatever order the firmware lists them in.
+    pub fn locate(&self, apic_id: u32) -> CpuLocation {
+        let package_shift = self.thread_bits + self.core_bits;
+        CpuLocation {
+            package: apic_id.checked_shr(package_shift).unwrap_or(0),
+            core: apic_id.checked_shr(self.thread_bits).unwrap_or(0) & mask(self.core_bits),
+            thread: apic_id & mask(self.thread_bits),
+        }
+    }
+}
+
+/// Low `bits` bits set
+fn mask(bits: u32) -> u32 {
+    1u32.checked_shl(bits).map_or(u32::MAX, |bit| bit - 1)
+}
+
+/// Identification of the boot CPU
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub topology: Topology,
+    caches: [Option<Cache>; MAX_CACHES],
+    tlbs: [Option<Tlb>; MAX_TLBS],
+}
+
+impl CpuInfo {
+    /// Vendor string, e.g. "GenuineIntel"
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("?")
+    }
+
+    /// Processor brand string, empty if the CPU has none
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|&b| b == 0).unwrap_or(self.brand.len());
+        core::str::from_utf8(&self.brand[..len]).unwrap_or("?").trim()
+    }
+
+    /// Cache levels, innermost first
+    pub fn caches(&self) -> impl Iterator<Item = &Cache> {
+        self.caches.iter().flatten()
+    }
+
+    /// TLBs, innermost first
+    pub fn tlbs(&self) -> impl Iterator<Item = &Tlb> {
+        self.tlbs.iter().flatten()
+    }
+
+    fn is_amd(&self) -> bool {
+        &self.vendor == b"AuthenticAMD" || &self.vendor == b"HygonGenuine"
+    }
+
+    /// Print identification, topology, caches and TLBs
+    pub fn report(&self, out: &mut dyn Write) -> core::fmt::Result {
+        writeln!(out, "vendor:   {}", self.vendor())?;
+        writeln!(out, "model:    {}", self.brand())?;
+        writeln!(out, "family {:#x} model {:#x} stepping {}", self.family, self.model, self.stepping)?;
+        let topology = &self.topology;
+        writeln!(out, "topology: {} cores x {} threads per package (APIC ID bits: thread {}, core {})",
+            topology.cores_per_package(), topology.threads_per_core,
+            topology.thread_bits, topology.core_bits)?;
+        for cache in self.caches() {
+            write!(out, "L{} {:<11} {:>9}  ", cache.level, cache.kind.name(), HumanBytes(cache.size as u64))?;
+            write_ways(out, cache.ways)?;
+            write!(out, ", {}-byte lines", cache.line_size)?;
+            if cache.shared_by > 0 {
+                write!(out, ", shared by {}", cache.shared_by)?;
+            }
+            writeln!(out)?;
+        }
+        for tlb in self.tlbs() {
+            write!(out, "L{} {:<11} TLB {:>5} entries  ", tlb.level, tlb.kind.name(), tlb.entries)?;
+            write_ways(out, tlb.ways)?;
+            write!(out, ",")?;
+            for (bit, name) in [(PAGE_4K, "4K"), (PAGE_2M, "2M"), (PAGE_4M, "4M"), (PAGE_1G, "1G")] {
+                if tlb.page_sizes & bit != 0 {
+                    write!(out, " {}", name)?;
+                }
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_ways(out: &mut dyn Write, ways: u32) -> core::fmt::Result {
+    if ways == 0 {
+        write!(out, "fully associative")
+    } else {
+        write!(out, "{}-way", ways)
+    }
+}
+
+static CPU_INFO: Once<CpuInfo> = Once::new();
+
+/// Identification of the boot CPU
+pub fn cpuinfo() -> &'static CpuInfo {
+    CPU_INFO.call_once(detect)
+}
+
+/// APIC ID of the executing CPU, the x2APIC ID where available
+pub fn apic_id() -> u32 {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= 0xB {
+        let leaf = unsafe { __cpuid_count(0xB, 0) };
+        if leaf.ebx != 0 {
+            return leaf.edx;
+        }
+    }
+    unsafe { __cpuid(1) }.ebx >> 24
+}
+
+fn detect() -> CpuInfo {
+    let leaf0 = unsafe { __cpuid(0) };
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+    // Family and model include the extended fields where the SDM says so
+    let signature = unsafe { __cpuid(1) }.eax;
+    let base_family = (signature >> 8) & 0xF;
+    let family = if base_family == 0xF { base_family + ((signature >> 20) & 0xFF) } else { base_family };
+    let model = if base_family == 0x6 || base_family == 0xF {
+        ((signature >> 4) & 0xF) | ((signature >> 12) & 0xF0)
+    } else {
+        (signature >> 4) & 0xF
+    };
+
+    let mut info = CpuInfo {
+        vendor,
+        brand: [0; 48],
+        family,
+        model,
+        stepping: signature & 0xF,
+        topology: Topology { thread_bits: 0, core_bits: 0, threads_per_core: 1, logical_per_package: 1 },
+        caches: [None; MAX_CACHES],
+        tlbs: [None; MAX_TLBS],
+    };
+
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended >= 0x8000_0004 {
+        for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+            let regs = unsafe { __cpuid(leaf) };
+            for (j, reg) in [regs.eax, regs.ebx, regs.ecx, regs.edx].iter().enumerate() {
+                let offset = i * 16 + j * 4;
+                info.brand[offset..offset + 4].copy_from_slice(&reg.to_le_bytes());
+            }
+        }
+    }
+
+    info.topology = detect_topology(&info, leaf0.eax, max_extended);
+    detect_caches(&mut info, leaf0.eax, max_extended);
+    detect_tlbs(&mut info, leaf0.eax, max_extended);
+    info
+}
+
+/// Bits needed to number `count` items
+fn bits_for(count: u32) -> u32 {
+    count.max(1).next_power_of_two().trailing_zeros()
+}
+
+fn has_topoext(max_extended: u32) -> bool {
+    max_extended >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.ecx & TOPOEXT != 0
+}
+
+fn detect_topology(info: &CpuInfo, max_leaf: u32, max_extended: u32) -> Topology {
+    // Extended topology enumeration, 0x1F adds die and module levels to 0xB
+    for leaf in [0x1F, 0xB] {
+        if max_leaf < leaf || unsafe { __cpuid_count(leaf, 0) }.ebx == 0 {
+            continue;
+        }
+        let mut topology = Topology { thread_bits: 0, core_bits: 0, threads_per_core: 1, logical_per_package: 1 };
+        let mut package_bits = 0;
+        for subleaf in 0..8 {
+            let regs = unsafe { __cpuid_count(leaf, subleaf) };
+            let level_type = (regs.ecx >> 8) & 0xFF;
+            if level_type == 0 {
+                break;
+            }
+            if level_type == LEVEL_SMT {
+                topology.thread_bits = regs.eax & 0x1F;
+                topology.threads_per_core = (regs.ebx & 0xFFFF).max(1);
+            }
+            // The last level's shift and count cover the whole package
+            package_bits = regs.eax & 0x1F;
+            topology.logical_per_package = (regs.ebx & 0xFFFF).max(1);
+        }
+        topology.core_bits = package_bits.saturating_sub(topology.thread_bits);
+        return topology;
+    }
+
+    let leaf1 = unsafe { __cpuid(1) };
+    let logical = if leaf1.edx & HTT != 0 { ((leaf1.ebx >> 16) & 0xFF).max(1) } else { 1 };
+
+    if info.is_amd() && max_extended >= 0x8000_0008 {
+        let size = unsafe { __cpuid(0x8000_0008) }.ecx;
+        let cores = (size & 0xFF) + 1;
+        let threads = if has_topoext(max_extended) {
+            ((unsafe { __cpuid(0x8000_001E) }.ebx >> 8) & 0xFF) + 1
+        } else {
+            1
+        };
+        // Zero means the legacy core count sizes the field
+        let package_bits = match (size >> 12) & 0xF {
+            0 => bits_for(cores),
+            bits => bits,
+        };
+        let thread_bits = bits_for(threads);
+        return Topology {
+            thread_bits,
+            core_bits: package_bits.saturating_sub(thread_bits),
+            threads_per_core: threads,
+            logical_per_package: cores.max(logical),
+        };
+    }
+
+    // Legacy: leaf 1 logical count and leaf 4 core count
+    let cores = if max_leaf >= 4 { (unsafe { __cpuid_count(4, 0) }.eax >> 26) + 1 } else { 1 };
+    let threads = (logical / cores).max(1);
+    Topology {
+        thread_bits: bits_for(threads),
+        core_bits: bits_for(cores),
+        threads_per_core: threads,
+        logical_per_package: logical,
+    }
+}
+
+/// Decode the cache type field of leaves 4 and 0x8000001D
+fn cache_kind(value: u32) -> Option<CacheKind> {
+    match value {
+        1 => Some(CacheKind::Data),
+        2 => Some(CacheKind::Instruction),
+        3 => Some(CacheKind::Unified),
+        _ => None,
+    }
+}
+
+/// Decode the associativity field of AMD leaf 0x80000006
+fn amd_ways(value: u32) -> Option<u32> {
+    match value {
+        0 => None,
+        1 | 2 | 4 => Some(value),
+        6 => Some(8),
+        8 => Some(16),
+        0xA => Some(32),
+        0xB => Some(48),
+        0xC => Some(64),
+        0xD => Some(96),
+        0xE => Some(128),
+        _ => Some(0),
+    }
+}
+
+fn push<T: Copy>(slots: &mut [Option<T>], value: T) {
+    if let Some(slot) = slots.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(value);
+    }
+}
+
+fn detect_caches(info: &mut CpuInfo, max_leaf: u32, max_extended: u32) {
+    // Deterministic cache parameters, the AMD leaf uses the same layout
+    let leaf = if info.is_amd() {
+        has_topoext(max_extended).then_some(0x8000_001D)
+    } else {
+        (max_leaf >= 4).then_some(4)
+    };
+    if let Some(leaf) = leaf {
+        for subleaf in 0..MAX_CACHES as u32 {
+            let regs = unsafe { __cpuid_count(leaf, subleaf) };
+            let Some(kind) = cache_kind(regs.eax & 0x1F) else {
+                break;
+            };
+            let ways = (regs.ebx >> 22) + 1;
+            let partitions = ((regs.ebx >> 12) & 0x3FF) + 1;
+            let line_size = (regs.ebx & 0xFFF) + 1;
+            let sets = regs.ecx + 1;
+            let fully_associative = regs.eax & (1 << 9) != 0;
+            push(&mut info.caches, Cache {
+                level: ((regs.eax >> 5) & 0x7) as u8,
+                kind,
+                size: ways as usize * partitions as usize * line_size as usize * sets as usize,
+                ways: if fully_associative { 0 } else { ways },
+                line_size,
+                shared_by: ((regs.eax >> 14) & 0xFFF) + 1,
+            });
+        }
+    }
+    if info.caches().next().is_some() || !info.is_amd() || max_extended < 0x8000_0006 {
+        return;
+    }
+
+    // Older AMD parts only report sizes through the L1/L2/L3 leaves
+    let l1 = unsafe { __cpuid(0x8000_0005) };
+    for (reg, kind) in [(l1.ecx, CacheKind::Data), (l1.edx, CacheKind::Instruction)] {
+        let ways = (reg >> 16) & 0xFF;
+        push(&mut info.caches, Cache {
+            level: 1,
+            kind,
+            size: (reg >> 24) as usize * 1024,
+            ways: if ways == 0xFF { 0 } else { ways },
+            line_size: reg & 0xFF,
+            shared_by: 0,
+        });
+    }
+    let l2 = unsafe { __cpuid(0x8000_0006) };
+    let levels = [(2, (l2.ecx >> 16) as usize * 1024, l2.ecx), (3, (l2.edx >> 18) as usize * 512 * 1024, l2.edx)];
+    for (level, size, reg) in levels {
+        if let Some(ways) = amd_ways((reg >> 12) & 0xF) {
+            push(&mut info.caches, Cache {
+                level,
+                kind: CacheKind::Unified,
+                size,
+                ways,
+                line_size: reg & 0xFF,
+                shared_by: 0,
+            });
+        }
+    }
+}
+
+fn detect_tlbs(info: &mut CpuInfo, max_leaf: u32, max_extended: u32) {
+    if !info.is_amd() {
+        if max_leaf < 0x18 {
+            return;
+        }
+        let max_subleaf = unsafe { __cpuid_count(0x18, 0) }.eax;
+        for subleaf in 0..=max_subleaf.min(31) {
+            let regs = unsafe { __cpuid_count(0x18, subleaf) };
+            // Load-only and store-only TLBs are reported as data TLBs
+            let kind = match regs.edx & 0x1F {
+                1 | 4 | 5 => CacheKind::Data,
+                2 => CacheKind::Instruction,
+                3 => CacheKind::Unified,
+                _ => continue,
+            };
+            let ways = regs.ebx >> 16;
+            push(&mut info.tlbs, Tlb {
+                level: ((regs.edx >> 5) & 0x7) as u8,
+                kind,
+                page_sizes: (regs.ebx & 0xF) as u8,
+                entries: ways * regs.ecx,
+                ways: if regs.edx & (1 << 8) != 0 { 0 } else { ways },
+            });
+        }
+        return;
+    }
+
+    if max_extended < 0x8000_0006 {
+        return;
+    }
+    // Each register holds a data TLB in the high half and an instruction
+    // TLB in the low half
+    let l1 = unsafe { __cpuid(0x8000_0005) };
+    let l2 = unsafe { __cpuid(0x8000_0006) };
+    let large = PAGE_2M | PAGE_4M;
+    for (reg, page_sizes) in [(l1.ebx, PAGE_4K), (l1.eax, large)] {
+        for (half, kind) in [(reg >> 16, CacheKind::Data), (reg & 0xFFFF, CacheKind::Instruction)] {
+            let (ways, entries) = (half >> 8, half & 0xFF);
+            if entries != 0 {
+                push(&mut info.tlbs, Tlb { level: 1, kind, page_sizes, entries, ways: if ways == 0xFF { 0 } else { ways } });
+            }
+        }
+    }
+    for (reg, page_sizes) in [(l2.ebx, PAGE_4K), (l2.eax, large)] {
+        for (half, kind) in [(reg >> 16, CacheKind::Data), (reg & 0xFFFF, CacheKind::Instruction)] {
+            let entries = half & 0xFFF;
+            if let (Some(ways), true) = (amd_ways(half >> 12), entries != 0) {
+                push(&mut info.tlbs, Tlb { level: 2, kind, page_sizes, entries, ways });
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! CPU exception reporting and error code decoding
+//!
+//! An exception the handler cannot resolve ends in `fault`. A fault in
+//! user mode is the task's own doing, so only that task is terminated,
+//! with the signal POSIX systems send for it, and the rest of the system
+//! carries on. A fault in the kernel may have left locks held and state
+//! half-updated, so it panics and the panic action decides what happens.
+
+use core::fmt;
+use x86_64::PrivilegeLevel;
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::mm::paging;
+use crate::task::signal::{self, Signal};
+
+/// Instruction bytes dumped from the faulting RIP
+const CODE_BYTES: usize = 16;
+
+/// Decoded page fault error code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultError(pub u64);
+
+impl PageFaultError {
+    pub const PRESENT: u64 = 1 << 0;
+    pub const WRITE: u64 = 1 << 1;
+    pub const USER: u64 = 1 << 2;
+    pub const RESERVED_BIT: u64 = 1 << 3;
+    pub const INSTRUCTION_FETCH: u64 = 1 << 4;
+    pub const PROTECTION_KEY: u64 = 1 << 5;
+    pub const SHADOW_STACK: u64 = 1 << 6;
+    pub const SGX: u64 = 1 << 15;
+
+    fn has(self, bit: u64) -> bool {
+        self.0 & bit != 0
+    }
+}
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.has(Self::PRESENT) {
+            write!(f, "protection violation")?;
+        } else {
+            write!(f, "page not present")?;
+        }
+        if self.has(Self::INSTRUCTION_FETCH) {
+            write!(f, ", instruction fetch")?;
+        } else if self.has(Self::WRITE) {
+            write!(f, ", write")?;
+        } else {
+            write!(f, ", read")?;
+        }
+        if self.has(Self::USER) {
+            write!(f, ", user mode")?;
+        } else {
+            write!(f, ", kernel mode")?;
+        }
+        if self.has(Self::RESERVED_BIT) {
+            write!(f, ", reserved bit set")?;
+        }
+        if self.has(Self::PROTECTION_KEY) {
+            write!(f, ", protection key")?;
+        }
+        if self.has(Self::SHADOW_STACK) {
+            write!(f, ", shadow stack")?;
+        }
+        if self.has(Self::SGX) {
+            write!(f, ", SGX")?;
+        }
+        Ok(())
+    }
+}
+
+/// Decoded selector error code (GPF, invalid TSS, segment faults)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorError(pub u64);
+
+impl SelectorError {
+    /// Fault was caused by an event external to the program
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    /// Descriptor table the selector refers to
+    pub fn table(self) -> &'static str {
+        match (self.0 >> 1) & 0b11 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",
+        }
+    }
+
+    /// Descriptor index within the table
+    pub fn index(self) -> u16 {
+        ((self.0 >> 3) & 0x1FFF) as u16
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "not segment related");
+        }
+        if self.table() == "IDT" {
+            write!(f, "IDT vector {}", self.index())?;
+        } else {
+            write!(f, "{} index {} (selector {:#x})", self.table(), self.index(), self.0 & 0xFFF8)?;
+        }
+        if self.external() {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// Exception error code, decoded where the format is known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    None,
+    Raw(u64),
+    PageFault(PageFaultError),
+    Selector(SelectorError),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::None => write!(f, "none"),
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+            ErrorCode::PageFault(e) => write!(f, "{:#x} ({})", e.0, e),
+            ErrorCode::Selector(e) => write!(f, "{:#x} ({})", e.0, e),
+        }
+    }
+}
+
+/// Instruction bytes at `rip`, if every byte is mapped
+fn code_bytes(rip: u64) -> Option<[u8; CODE_BYTES]> {
+    let last = rip.checked_add(CODE_BYTES as u64 - 1)?;
+    if !paging::is_mapped(rip) || !paging::is_mapped(last) {
+        return None;
+    }
+    let mut bytes = [0u8; CODE_BYTES];
+    unsafe {
+        core::ptr::copy_nonoverlapping(rip as *const u8, bytes.as_mut_ptr(), CODE_BYTES);
+    }
+    Some(bytes)
+}
+
+/// Print an exception report: decoded error code, control registers,
+/// the stack frame and the faulting instruction bytes
+pub fn report(name: &str, frame: &InterruptStackFrame, error: ErrorCode) {
+    let rip = frame.instruction_pointer.as_u64();
+
+    crate::serial_println!("[EXCEPTION] {} at {}", name, crate::ksyms::Address(rip as usize));
+    if error != ErrorCode::None {
+        crate::serial_println!("Error Code: {}", error);
+    }
+    crate::serial_println!(
+        "CR2: {:#018x}  CR3: {:#018x}",
+        Cr2::read_raw(),
+        Cr3::read().0.start_address().as_u64()
+    );
+    crate::serial_println!("{:#?}", frame);
+
+    crate::serial_print!("Code at {:#x}:", rip);
+    match code_bytes(rip) {
+        Some(bytes) => {
+            for byte in bytes {
+                crate::serial_print!(" {:02x}", byte);
+            }
+            crate::serial_println!();
+        }
+        None => crate::serial_println!(" <unmapped>"),
+    }
+}
+
+/// Whether the exception interrupted user mode, by the privilege level of
+/// the interrupted code segment
+pub fn from_user(frame: &InterruptStackFrame) -> bool {
+    frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
+/// Report an exception the handler could not resolve, then terminate the
+/// task with `signal` if it came from user mode or panic if it came from
+/// the kernel
+pub fn fault(name: &str, frame: &InterruptStackFrame, error: ErrorCode, signal: Signal) -> ! {
+    report(name, frame, error);
+    if from_user(frame) {
+        signal::terminate(signal);
+    }
+    panic!("{} in kernel mode at {:#x}", name, frame.instruction_pointer.as_u64());
+}
@@ -0,0 +1,243 @@
+//! FPU, SSE and AVX State
+//!
+//! The kernel itself is built soft-float and never touches these registers,
+//! so task state is switched lazily: a context switch only sets CR0.TS, and
+//! the first FPU or SIMD instruction of the new task raises #NM, which
+//! saves the previous owner's registers and loads the task's own. Tasks
+//! that never use the FPU never pay for a save. XSAVE is used when the CPU
+//! has it, covering AVX; otherwise FXSAVE covers x87 and SSE.
+
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::XCr0;
+
+/// CPUID leaf 1 ECX: XSAVE supported
+const CPUID_XSAVE: u32 = 1 << 26;
+
+// XCR0 components the kernel manages
+const XSTATE_X87: u64 = 1 << 0;
+const XSTATE_SSE: u64 = 1 << 1;
+const XSTATE_AVX: u64 = 1 << 2;
+
+/// Size of the legacy FXSAVE area, also the start of the XSAVE header
+const FXSAVE_SIZE: usize = 512;
+/// XSAVE areas must be 64-byte aligned, FXSAVE areas 16-byte
+const STATE_ALIGN: usize = 64;
+
+/// Reset values of the x87 control word and MXCSR
+const FCW_DEFAULT: u16 = 0x037F;
+const MXCSR_DEFAULT: u32 = 0x1F80;
+const MXCSR_OFFSET: usize = 24;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+/// XCR0 value, the component mask for XSAVE and XRSTOR
+static XSTATE_MASK: AtomicU64 = AtomicU64::new(0);
+static STATE_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+/// State of the running task, null before tasking
+static CURRENT: AtomicPtr<u8> = AtomicPtr::new(null_mut());
+/// State whose values are loaded in the registers, null if none is
+static OWNER: AtomicPtr<u8> = AtomicPtr::new(null_mut());
+
+/// Lazy restores performed by the #NM handler
+static RESTORES: AtomicU64 = AtomicU64::new(0);
+
+/// Enable x87, SSE and, where present, XSAVE and AVX
+///
+/// Clears CR0.EM so FPU instructions run instead of trapping and sets
+/// CR0.MP and CR0.NE so CR0.TS traps WAIT and errors report as #MF.
+pub fn init() {
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    let leaf1 = unsafe { __cpuid(1) };
+    if leaf1.ecx & CPUID_XSAVE != 0 {
+        let leaf_d = unsafe { __cpuid_count(0xD, 0) };
+        let supported = (leaf_d.edx as u64) << 32 | leaf_d.eax as u64;
+        let mask = supported & (XSTATE_X87 | XSTATE_SSE | XSTATE_AVX);
+        unsafe {
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            XCr0::write_raw(mask);
+        }
+        // EBX reports the area size for the components now enabled
+        let size = unsafe { __cpuid_count(0xD, 0) }.ebx as usize;
+        XSTATE_MASK.store(mask, Ordering::Relaxed);
+        STATE_SIZE.store(size.max(FXSAVE_SIZE + 64), Ordering::Relaxed);
+        USE_XSAVE.store(true, Ordering::Release);
+    }
+
+    unsafe {
+        asm!("fninit", options(nomem, nostack));
+    }
+}
+
+/// Check if state is saved with XSAVE rather than FXSAVE
+pub fn uses_xsave() -> bool {
+    USE_XSAVE.load(Ordering::Acquire)
+}
+
+/// Enabled XCR0 components, 0 without XSAVE
+pub fn xstate_mask() -> u64 {
+    XSTATE_MASK.load(Ordering::Relaxed)
+}
+
+/// Size in bytes of one task's saved state
+pub fn state_size() -> usize {
+    STATE_SIZE.load(Ordering::Relaxed)
+}
+
+/// Number of lazy state restores so far
+pub fn restores() -> u64 {
+    RESTORES.load(Ordering::Relaxed)
+}
+
+/// Saved FPU and vector registers of one task
+pub struct FpuState {
+    area: *mut u8,
+    size: usize,
+}
+
+// The area is only accessed by the owning task or with interrupts disabled
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// State as after reset: empty x87 stack, all exceptions masked
+    pub fn new() -> Self {
+        let size = state_size();
+        let layout = Layout::from_size_align(size, STATE_ALIGN).expect("invalid FPU state layout");
+        let area = unsafe { alloc_zeroed(layout) };
+        if area.is_null() {
+            handle_alloc_error(layout);
+        }
+        unsafe {
+            // A zero XSAVE header selects the init state for every component,
+            // FXRSTOR loads these fields as they are
+            (area as *mut u16).write(FCW_DEFAULT);
+            (area.add(MXCSR_OFFSET) as *mut u32).write(MXCSR_DEFAULT);
+        }
+        FpuState { area, size }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // Registers of a freed task must not be saved into its freed area
+        let _ = OWNER.compare_exchange(self.area, null_mut(), Ordering::AcqRel, Ordering::Relaxed);
+        let _ = CURRENT.compare_exchange(self.area, null_mut(), Ordering::AcqRel, Ordering::Relaxed);
+        let layout = Layout::from_size_align(self.size, STATE_ALIGN).expect("invalid FPU state layout");
+        unsafe { dealloc(self.area, layout) };
+    }
+}
+
+/// Make `next` the state of the running task, called on every task switch
+///
+/// Sets CR0.TS unless `next` is already loaded, so the next FPU or SIMD
+/// instruction traps into `handle_device_not_available`. Must be called
+/// with interrupts disabled.
+pub fn switch_to(next: &FpuState) {
+    CURRENT.store(next.area, Ordering::Release);
+    let loaded = OWNER.load(Ordering::Acquire) == next.area;
+    unsafe {
+        Cr0::update(|cr0| cr0.set(Cr0Flags::TASK_SWITCHED, !loaded));
+    }
+}
+
+/// Load the running task's state after a #NM exception
+///
+/// Returns `false` if the exception was not caused by a lazy switch, for
+/// example because the FPU was never enabled.
+pub fn handle_device_not_available() -> bool {
+    if !Cr0::read().contains(Cr0Flags::TASK_SWITCHED)
+        || Cr0::read().contains(Cr0Flags::EMULATE_COPROCESSOR)
+    {
+        return false;
+    }
+    unsafe {
+        asm!("clts", options(nomem, nostack));
+    }
+
+    let current = CURRENT.load(Ordering::Acquire);
+    let owner = OWNER.load(Ordering::Acquire);
+    if current.is_null() || current == owner {
+        return true;
+    }
+    // Both areas belong to live tasks, `Drop` clears the pointers first
+    unsafe {
+        if !owner.is_null() {
+            save(owner);
+        }
+        restore(current);
+    }
+    OWNER.store(current, Ordering::Release);
+    RESTORES.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Store the registers into a state area
+///
+/// # Safety
+/// `area` must be a live `FpuState` area and CR0.TS must be clear.
+unsafe fn save(area: *mut u8) {
+    if uses_xsave() {
+        let mask = xstate_mask();
+        asm!("xsave64 [{}]", in(reg) area,
+            in("eax") mask as u32, in("edx") (mask >> 32) as u32, options(nostack));
+    } else {
+        asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+    }
+}
+
+/// Load the registers from a state area
+///
+/// # Safety
+/// `area` must be a live `FpuState` area and CR0.TS must be clear.
+unsafe fn restore(area: *mut u8) {
+    if uses_xsave() {
+        let mask = xstate_mask();
+        asm!("xrstor64 [{}]", in(reg) area,
+            in("eax") mask as u32, in("edx") (mask >> 32) as u32, options(nostack));
+    } else {
+        asm!("fxrstor64 [{}]", in(reg) area, options(nostack));
+    }
+}
+
+/// SSE use with CR0.TS set must trap and resume with TS clear
+fn selftest() -> Result<(), &'static str> {
+    if !Cr4::read().contains(Cr4Flags::OSFXSR) {
+        return Err("SSE not enabled");
+    }
+    let value: u64 = 0x0123_4567_89AB_CDEF;
+    let result = x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let result: u64;
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::TASK_SWITCHED));
+        asm!(
+            "movq xmm15, {value}",
+            "movq {result}, xmm15",
+            value = in(reg) value,
+            result = out(reg) result,
+            options(nomem, nostack),
+        );
+        result
+    });
+    if Cr0::read().contains(Cr0Flags::TASK_SWITCHED) {
+        return Err("CR0.TS still set after #NM");
+    }
+    if result != value { Err("SSE register lost its value") } else { Ok(()) }
+}
+
+crate::selftest!("fpu", selftest);
@@ -4,53 +4,140 @@ use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
+use cosmos_common::fmt::HumanBytes;
+use super::cpu::{self, MAX_CPUS};
+use crate::mm::{frame_allocator, paging, PhysicalFrame};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 8;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
-        tss
-    };
+/// IST slots backed by dedicated stacks
+const IST_INDICES: [u16; 3] = [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX];
+
+/// Usable pages per IST stack, each sits above an unmapped guard page
+pub const IST_STACK_PAGES: u64 = 4;
+
+/// Shared IST stack for early boot, before the frame allocator exists
+const BOOT_STACK_SIZE: usize = 4096 * 8;
+static mut BOOT_IST_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
+
+/// Per-CPU task state segments
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [const { TaskStateSegment::new() }; MAX_CPUS];
+
+/// Null, code and data entries plus a two-slot TSS descriptor per CPU
+const GDT_ENTRIES: usize = 3 + 2 * MAX_CPUS;
+
+/// Errors setting up IST stacks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IstError {
+    /// No contiguous frames for a stack
+    OutOfMemory,
+    /// Stack lies outside the identity map or the guard page could not be unmapped
+    Unmapped,
+}
+
+impl core::fmt::Display for IstError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            IstError::OutOfMemory => write!(f, "Out of memory for IST stack"),
+            IstError::Unmapped => write!(f, "IST stack not mappable"),
+        }
+    }
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
+    static ref GDT: (GlobalDescriptorTable<GDT_ENTRIES>, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::empty();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
+        let data_selector = gdt.append(Descriptor::kernel_data_segment());
+        let mut tss_selectors = [SegmentSelector(0); MAX_CPUS];
+        for (cpu, selector) in tss_selectors.iter_mut().enumerate() {
+            // TSS is a static, so the descriptor never dangles
+            *selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(&raw const TSS[cpu]) });
+        }
+        (gdt, Selectors { code_selector, data_selector, tss_selectors })
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    tss_selectors: [SegmentSelector; MAX_CPUS],
 }
 
 /// Initialize the GDT
 /// 
 /// - Kernel Code Segment
-/// - Task State Segment
-/// - A 32KB stack for large double fault handling
+/// - Kernel Data Segment
+/// - One Task State Segment per CPU
+/// - IST stacks for double fault, NMI and machine check, on a shared
+///   32KB boot stack until `init_ist_stacks` moves them
 /// - Proper segment selectors for kernel mode
-/// - TSS for interrupt stack switching
 pub fn init() {
     use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
+
+    let cpu = cpu::current_id();
+    let boot_stack_top = VirtAddr::from_ptr(&raw const BOOT_IST_STACK) + BOOT_STACK_SIZE as u64;
+    for index in IST_INDICES {
+        set_ist(cpu, index, boot_stack_top);
+    }
 
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        // Stale bootloader selectors would fault on the first iretq
+        SS::set_reg(GDT.1.data_selector);
+        DS::set_reg(GDT.1.data_selector);
+        ES::set_reg(GDT.1.data_selector);
+        load_tss(GDT.1.tss_selectors[cpu]);
     }
-    crate::serial_println!("GDT loaded with kernel code segment and TSS");
+    crate::serial_println!("GDT loaded with kernel code/data segments and TSS");
+}
+
+/// Give the current CPU dedicated, guarded IST stacks from the frame allocator
+///
+/// Must run after the heap is reserved in the frame allocator.
+pub fn init_ist_stacks() -> Result<(), IstError> {
+    let cpu = cpu::current_id();
+    for index in IST_INDICES {
+        let top = allocate_ist_stack()?;
+        set_ist(cpu, index, top);
+    }
+    crate::serial_println!(
+        "IST stacks: {} x {} with guard pages",
+        IST_INDICES.len(),
+        HumanBytes(IST_STACK_PAGES * PhysicalFrame::SIZE)
+    );
+    Ok(())
+}
+
+/// Allocate a stack with an unmapped guard page below it, returning its top
+fn allocate_ist_stack() -> Result<VirtAddr, IstError> {
+    let guard = frame_allocator::allocate_contiguous_frames(IST_STACK_PAGES + 1)
+        .map_err(|_| IstError::OutOfMemory)?;
+    let top = (guard + IST_STACK_PAGES + 1).start_address().as_u64();
+    if top as usize > paging::get_mapped_memory() {
+        return Err(IstError::Unmapped);
+    }
+
+    // Overflowing the stack now faults instead of corrupting memory
+    paging::unmap_page(guard.start_address()).map_err(|_| IstError::Unmapped)?;
+    Ok(VirtAddr::new(top))
+}
+
+/// Addresses of the stack an IST slot of a CPU's TSS points at
+pub fn ist_stack(cpu: usize, index: u16) -> core::ops::Range<u64> {
+    let top = unsafe { TSS[cpu].interrupt_stack_table[index as usize] }.as_u64();
+    let boot_stack_top = VirtAddr::from_ptr(&raw const BOOT_IST_STACK).as_u64() + BOOT_STACK_SIZE as u64;
+    let size = if top == boot_stack_top { BOOT_STACK_SIZE as u64 } else { IST_STACK_PAGES * PhysicalFrame::SIZE };
+    top - size..top
+}
+
+/// Point an IST slot of a CPU's TSS at a new stack
+fn set_ist(cpu: usize, index: u16, top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        TSS[cpu].interrupt_stack_table[index as usize] = top;
+    });
 }
@@ -2,7 +2,9 @@
 
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use lazy_static::lazy_static;
-use crate::arch::x86_64::gdt;
+use crate::arch::x86_64::{exception, gdt, inject, interrupts};
+use crate::arch::x86_64::exception::{ErrorCode, PageFaultError, SelectorError};
+use crate::task::signal::Signal;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -11,7 +13,6 @@ lazy_static! {
         // CPU Exception handlers (critical for security and stability)
         idt.divide_error.set_handler_fn(divide_error_handler);
         idt.debug.set_handler_fn(debug_handler);
-        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.overflow.set_handler_fn(overflow_handler);
         idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
@@ -24,16 +25,27 @@ lazy_static! {
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
         idt.alignment_check.set_handler_fn(alignment_check_handler);
-        idt.machine_check.set_handler_fn(machine_check_handler);
         idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
         idt.virtualization.set_handler_fn(virtualization_handler);
         idt.security_exception.set_handler_fn(security_exception_handler);
         
-        // Double fault handler with separate stack (prevents triple fault)
+        // Double fault, NMI and machine check run on their own IST stacks,
+        // since they can arrive with a corrupt or overflowed kernel stack
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        
+        // Vectors 32-255 dispatch through the runtime registration table
+        for (i, stub) in interrupts::STUBS.iter().enumerate() {
+            idt[interrupts::FIRST_DYNAMIC_VECTOR + i as u8].set_handler_fn(*stub);
         }
         
         idt
@@ -62,152 +74,160 @@ lazy_static! {
 /// - SIMD Floating Point Exception
 /// - Virtualization Exception
 /// - Security Exception
+/// - Vectors 32-255 routed to runtime-registered handlers
 pub fn init() {
     IDT.load();
     crate::serial_println!("IDT loaded with {} exception handlers", 21);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] BREAKPOINT\n{:#?}", stack_frame);
+    inject::catch(inject::BREAKPOINT, &stack_frame, 0);
+    // Execution can go on after a trap, but a user task has no debugger
+    // to stop for it
+    if exception::from_user(&stack_frame) {
+        exception::fault("BREAKPOINT", &stack_frame, ErrorCode::None, Signal::Trap);
+    }
+    exception::report("BREAKPOINT", &stack_frame, ErrorCode::None);
 }
 
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: x86_64::structures::idt::PageFaultErrorCode,
 ) {
-    use x86_64::registers::control::Cr2;
-
-    crate::serial_println!("[EXCEPTION] PAGE FAULT");
-    crate::serial_println!("Accessed Address: {:?}", Cr2::read());
-    crate::serial_println!("Error Code: {:?}", error_code);
-    crate::serial_println!("{:#?}", stack_frame);
-    
-    crate::hlt_loop();
+    use x86_64::structures::idt::PageFaultErrorCode;
+
+    let address = x86_64::registers::control::Cr2::read_raw();
+    crate::trace_event!(PageFault, address, error_code.bits());
+    // Write to a copy-on-write page, retry once the writer has its own copy
+    let cow = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(cow) && crate::mm::paging::resolve_cow_fault(address) {
+        return;
+    }
+    // First touch of a demand-zero page
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        use crate::mm::vma::Protection;
+        let access = if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Protection::WRITE
+        } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Protection::EXEC
+        } else {
+            Protection::READ
+        };
+        if crate::mm::vma::handle_fault(address, access) {
+            return;
+        }
+    }
+    inject::catch(inject::PAGE_FAULT, &stack_frame, error_code.bits());
+    let error = ErrorCode::PageFault(PageFaultError(error_code.bits()));
+    exception::fault("PAGE FAULT", &stack_frame, error, Signal::SegmentationFault)
 }
 
 extern "x86-interrupt" fn general_protection_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
-    crate::serial_println!("[EXCEPTION] GENERAL PROTECTION FAULT");
-    crate::serial_println!("Error Code: {}", error_code);
-    crate::serial_println!("{:#?}", stack_frame);
-    
-    crate::hlt_loop();
+    inject::catch(inject::GENERAL_PROTECTION_FAULT, &stack_frame, error_code);
+    let error = ErrorCode::Selector(SelectorError(error_code));
+    exception::fault("GENERAL PROTECTION FAULT", &stack_frame, error, Signal::SegmentationFault)
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
-    panic!("DOUBLE FAULT (error code: {})\n{:#?}", error_code, stack_frame);
+    inject::catch(inject::DOUBLE_FAULT, &stack_frame, error_code);
+    exception::report("DOUBLE FAULT", &stack_frame, ErrorCode::Raw(error_code));
+    panic!("DOUBLE FAULT at {:#x}", stack_frame.instruction_pointer.as_u64());
 }
 
 extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] DIVIDE BY ZERO ERROR");
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    inject::catch(inject::DIVIDE_ERROR, &stack_frame, 0);
+    exception::fault("DIVIDE BY ZERO ERROR", &stack_frame, ErrorCode::None, Signal::FloatingPoint)
 }
 
 extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] DEBUG");
-    crate::serial_println!("{:#?}", stack_frame);
+    if exception::from_user(&stack_frame) {
+        exception::fault("DEBUG", &stack_frame, ErrorCode::None, Signal::Trap);
+    }
+    exception::report("DEBUG", &stack_frame, ErrorCode::None);
 }
 
 extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] NON-MASKABLE INTERRUPT");
-    crate::serial_println!("{:#?}", stack_frame);
+    exception::report("NON-MASKABLE INTERRUPT", &stack_frame, ErrorCode::None);
 }
 
 extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] OVERFLOW");
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    exception::fault("OVERFLOW", &stack_frame, ErrorCode::None, Signal::SegmentationFault)
 }
 
 extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] BOUND RANGE EXCEEDED");
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    exception::fault("BOUND RANGE EXCEEDED", &stack_frame, ErrorCode::None, Signal::SegmentationFault)
 }
 
 extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] INVALID OPCODE");
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    exception::fault("INVALID OPCODE", &stack_frame, ErrorCode::None, Signal::IllegalInstruction)
 }
 
 extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] DEVICE NOT AVAILABLE");
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    // Lazy FPU switch, the faulting instruction reruns with the task's state
+    if super::fpu::handle_device_not_available() {
+        return;
+    }
+    exception::fault("DEVICE NOT AVAILABLE", &stack_frame, ErrorCode::None, Signal::IllegalInstruction)
 }
 
-extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    crate::serial_println!("[EXCEPTION] INVALID TSS");
-    crate::serial_println!("Error Code: {}", error_code);
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+extern "x86-interrupt" fn invalid_tss_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let error = ErrorCode::Selector(SelectorError(error_code));
+    exception::fault("INVALID TSS", &stack_frame, error, Signal::SegmentationFault)
 }
 
 extern "x86-interrupt" fn segment_not_present_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
-    crate::serial_println!("[EXCEPTION] SEGMENT NOT PRESENT");
-    crate::serial_println!("Error Code: {}", error_code);
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    let error = ErrorCode::Selector(SelectorError(error_code));
+    exception::fault("SEGMENT NOT PRESENT", &stack_frame, error, Signal::Bus)
 }
 
 extern "x86-interrupt" fn stack_segment_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
-    crate::serial_println!("[EXCEPTION] STACK SEGMENT FAULT");
-    crate::serial_println!("Error Code: {}", error_code);
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    inject::catch(inject::STACK_SEGMENT_FAULT, &stack_frame, error_code);
+    let error = ErrorCode::Selector(SelectorError(error_code));
+    exception::fault("STACK SEGMENT FAULT", &stack_frame, error, Signal::Bus)
 }
 
 extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] x87 FLOATING POINT");
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    exception::fault("x87 FLOATING POINT", &stack_frame, ErrorCode::None, Signal::FloatingPoint)
 }
 
 extern "x86-interrupt" fn alignment_check_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
-    crate::serial_println!("[EXCEPTION] ALIGNMENT CHECK");
-    crate::serial_println!("Error Code: {}", error_code);
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    exception::fault("ALIGNMENT CHECK", &stack_frame, ErrorCode::Raw(error_code), Signal::Bus)
 }
 
 extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
-    panic!("MACHINE CHECK\n{:#?}", stack_frame);
+    exception::report("MACHINE CHECK", &stack_frame, ErrorCode::None);
+    panic!("MACHINE CHECK at {:#x}", stack_frame.instruction_pointer.as_u64());
 }
 
 extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] SIMD FLOATING POINT");
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    exception::fault("SIMD FLOATING POINT", &stack_frame, ErrorCode::None, Signal::FloatingPoint)
 }
 
 extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("[EXCEPTION] VIRTUALIZATION");
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    exception::fault("VIRTUALIZATION", &stack_frame, ErrorCode::None, Signal::SegmentationFault)
 }
 
 extern "x86-interrupt" fn security_exception_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
-    crate::serial_println!("[EXCEPTION] SECURITY EXCEPTION");
-    crate::serial_println!("Error Code: {}", error_code);
-    crate::serial_println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    exception::fault("SECURITY EXCEPTION", &stack_frame, ErrorCode::Raw(error_code), Signal::SegmentationFault)
 }
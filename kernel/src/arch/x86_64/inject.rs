@@ -0,0 +1,270 @@
+//! Exception Injection
+//!
+//! Raises CPU exceptions on purpose, to check on real hardware that the
+//! handlers run and that faults which must not trust the kernel stack get
+//! their IST stack. An injection arms the executing CPU, triggers the
+//! exception and notes where to resume. The handler sees the CPU is
+//! armed, records what arrived and returns to the injection instead of
+//! reporting a crash.
+//!
+//! Exceptions that are not injected are handled as before, even while
+//! an injection is in progress on another CPU.
+
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::InterruptStackFrame;
+use super::{cpu, gdt};
+
+pub const DIVIDE_ERROR: u8 = 0;
+pub const BREAKPOINT: u8 = 3;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+
+/// A stack pointer that faults on any push, and faults again when the
+/// CPU pushes the exception frame for that
+const BAD_STACK: u64 = 0x8000_0000_0000_0000;
+
+/// CPU an injection is running on plus one, 0 when none is
+static ARMED: AtomicUsize = AtomicUsize::new(0);
+/// Where the handler returns to, and with what stack pointer
+static RESUME_RIP: AtomicU64 = AtomicU64::new(0);
+static RESUME_RSP: AtomicU64 = AtomicU64::new(0);
+
+/// What the handler saw, for the injection to pick up
+static CAUGHT: Mutex<Option<Caught>> = Mutex::new(None);
+
+/// Exceptions that can be raised
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Injection {
+    /// `int3`
+    Breakpoint,
+    /// `div` by zero
+    DivideError,
+    /// A read of this address
+    PageFault(u64),
+    /// A push through a bad stack pointer, so the fault cannot be
+    /// delivered on the current stack
+    DoubleFault,
+}
+
+impl Injection {
+    /// Name the `test` command uses
+    pub fn name(self) -> &'static str {
+        match self {
+            Injection::Breakpoint => "int3",
+            Injection::DivideError => "divzero",
+            Injection::PageFault(_) => "pagefault",
+            Injection::DoubleFault => "df",
+        }
+    }
+
+    /// Exception the injection should raise
+    pub fn vector(self) -> u8 {
+        match self {
+            Injection::Breakpoint => BREAKPOINT,
+            Injection::DivideError => DIVIDE_ERROR,
+            Injection::PageFault(_) => PAGE_FAULT,
+            Injection::DoubleFault => DOUBLE_FAULT,
+        }
+    }
+}
+
+/// Errors that can occur injecting an exception
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectError {
+    /// Another injection is running
+    Busy,
+    /// The trigger ran without raising anything
+    NotRaised,
+}
+
+impl fmt::Display for InjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InjectError::Busy => write!(f, "Another injection is running"),
+            InjectError::NotRaised => write!(f, "No exception was raised"),
+        }
+    }
+}
+
+/// An exception taken during an injection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caught {
+    pub vector: u8,
+    pub error_code: u64,
+    /// Faulting instruction
+    pub rip: u64,
+    pub cr2: u64,
+    /// Stack pointer inside the handler
+    pub handler_rsp: u64,
+}
+
+impl Caught {
+    /// Check the exception is the one `injection` should raise, taken on
+    /// the right stack
+    pub fn check(&self, injection: Injection) -> Result<(), &'static str> {
+        if self.vector != injection.vector() {
+            return Err("wrong exception");
+        }
+        match injection {
+            Injection::PageFault(addr) if self.cr2 != addr => Err("CR2 is not the faulting address"),
+            Injection::DoubleFault
+                if !gdt::ist_stack(cpu::current_id(), gdt::DOUBLE_FAULT_IST_INDEX).contains(&self.handler_rsp) =>
+            {
+                Err("double fault not on its IST stack")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Name of an exception vector injections can raise
+pub fn vector_name(vector: u8) -> &'static str {
+    match vector {
+        DIVIDE_ERROR => "DIVIDE ERROR",
+        BREAKPOINT => "BREAKPOINT",
+        DOUBLE_FAULT => "DOUBLE FAULT",
+        STACK_SEGMENT_FAULT => "STACK SEGMENT FAULT",
+        GENERAL_PROTECTION_FAULT => "GENERAL PROTECTION FAULT",
+        PAGE_FAULT => "PAGE FAULT",
+        _ => "EXCEPTION",
+    }
+}
+
+/// Return to the injection if this CPU is running one, called first by
+/// the handlers of exceptions an injection can raise
+///
+/// Does not return in that case: the handler's own stack is abandoned and
+/// `iretq` resumes after the trigger with the stack pointer it had.
+pub fn catch(vector: u8, frame: &InterruptStackFrame, error_code: u64) {
+    if ARMED.load(Ordering::Acquire) != cpu::current_id() + 1 {
+        return;
+    }
+    let handler_rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) handler_rsp, options(nomem, nostack, preserves_flags)) };
+    if let Some(mut caught) = CAUGHT.try_lock() {
+        *caught = Some(Caught {
+            vector,
+            error_code,
+            rip: frame.instruction_pointer.as_u64(),
+            cr2: Cr2::read_raw(),
+            handler_rsp,
+        });
+    }
+    ARMED.store(0, Ordering::Release);
+
+    unsafe {
+        asm!(
+            "push {ss}",
+            "push {rsp}",
+            "push {rflags}",
+            "push {cs}",
+            "push {rip}",
+            "iretq",
+            ss = in(reg) frame.stack_segment.0 as u64,
+            rsp = in(reg) RESUME_RSP.load(Ordering::Relaxed),
+            rflags = in(reg) frame.cpu_flags.bits(),
+            cs = in(reg) frame.code_segment.0 as u64,
+            rip = in(reg) RESUME_RIP.load(Ordering::Relaxed),
+            options(noreturn),
+        );
+    }
+}
+
+/// Save the registers the handler does not preserve, note where to
+/// resume, run the trigger lines and land after them
+///
+/// `rdi` and `rsi` hold the resume slots; `rdx` is free for the
+/// trigger's operand, given after a `;`.
+macro_rules! trigger {
+    ($($line:literal),+ ; $($operand:tt)*) => {
+        asm!(
+            "push rbx",
+            "push rbp",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "lea rax, [rip + 2f]",
+            "mov [rdi], rax",
+            "mov [rsi], rsp",
+            $($line,)+
+            "2:",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbp",
+            "pop rbx",
+            in("rdi") RESUME_RIP.as_ptr(),
+            in("rsi") RESUME_RSP.as_ptr(),
+            $($operand)*
+            clobber_abi("C"),
+        )
+    };
+}
+
+/// Raise the exception of `injection` on this CPU and return what the
+/// handler saw
+pub fn inject(injection: Injection) -> Result<Caught, InjectError> {
+    let cpu = cpu::current_id();
+    ARMED.compare_exchange(0, cpu + 1, Ordering::AcqRel, Ordering::Acquire).map_err(|_| InjectError::Busy)?;
+    *CAUGHT.lock() = None;
+
+    // An interrupt on the bad stack would double fault too
+    interrupts::without_interrupts(|| unsafe {
+        match injection {
+            Injection::Breakpoint => trigger!("int3";),
+            Injection::DivideError => trigger!("xor eax, eax", "xor edx, edx", "div edx";),
+            Injection::PageFault(addr) => trigger!("mov rax, [rdx]"; in("rdx") addr,),
+            Injection::DoubleFault => trigger!("mov rsp, rdx", "push rax"; in("rdx") BAD_STACK,),
+        }
+    });
+
+    ARMED.store(0, Ordering::Release);
+    CAUGHT.lock().take().ok_or(InjectError::NotRaised)
+}
+
+/// Inject and print what arrived, and whether it was what should have
+pub fn report(injection: Injection, out: &mut dyn Write) -> fmt::Result {
+    let caught = match inject(injection) {
+        Ok(caught) => caught,
+        Err(e) => return writeln!(out, "FAIL  {}: {}", injection.name(), e),
+    };
+    write!(out, "{} (vector {}) at {}", vector_name(caught.vector), caught.vector,
+        crate::ksyms::Address(caught.rip as usize))?;
+    if caught.vector == PAGE_FAULT {
+        write!(out, ", CR2 {:#x}", caught.cr2)?;
+    }
+    writeln!(out, ", error code {:#x}, handler stack {:#x}", caught.error_code, caught.handler_rsp)?;
+    match caught.check(injection) {
+        Ok(()) => writeln!(out, "PASS  {}", injection.name()),
+        Err(reason) => writeln!(out, "FAIL  {}: {}", injection.name(), reason),
+    }
+}
+
+/// Every injection is caught as the right exception, and the kernel
+/// carries on afterwards
+fn selftest() -> Result<(), &'static str> {
+    use crate::mm::vma::{self, Protection};
+
+    let page = vma::map_anonymous(crate::mm::PhysicalFrame::SIZE, Protection::NONE).map_err(|_| "mmap failed")?;
+    let injections = [Injection::Breakpoint, Injection::DivideError, Injection::PageFault(page), Injection::DoubleFault];
+    let mut result = Ok(());
+    for injection in injections {
+        result = inject(injection).map_err(|_| "exception not raised").and_then(|caught| caught.check(injection));
+        if result.is_err() {
+            break;
+        }
+    }
+    let _ = vma::unmap(page, crate::mm::PhysicalFrame::SIZE);
+    result
+}
+
+crate::selftest!("inject", selftest);
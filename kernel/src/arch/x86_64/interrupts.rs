@@ -1,10 +1,56 @@
 //! Interrupt handling initialization
 
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use super::{apic, pic};
+use crate::stats;
+
+/// Nesting depth of interrupt handlers currently executing
+static INTERRUPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// First vector available for runtime registration
+pub const FIRST_DYNAMIC_VECTOR: u8 = 32;
+
+/// Handler invoked for a registered vector
+pub type InterruptHandler = fn(&InterruptStackFrame);
+
+/// Registered handlers, indexed by vector, null when unclaimed
+static HANDLERS: [AtomicPtr<()>; 256] = [const { AtomicPtr::new(core::ptr::null_mut()) }; 256];
+
+/// Errors that can occur when registering interrupt handlers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptError {
+    /// Vector is reserved for CPU exceptions
+    ReservedVector,
+    /// IRQ line is out of range
+    InvalidIrq,
+    /// Vector already has a handler
+    AlreadyRegistered,
+    /// Vector has no handler
+    NotRegistered,
+}
+
+impl core::fmt::Display for InterruptError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InterruptError::ReservedVector => write!(f, "Vector reserved for CPU exceptions"),
+            InterruptError::InvalidIrq => write!(f, "Invalid IRQ line"),
+            InterruptError::AlreadyRegistered => write!(f, "Vector already registered"),
+            InterruptError::NotRegistered => write!(f, "Vector not registered"),
+        }
+    }
+}
+
 /// Initialize interrupt handling
 pub fn init() {
     x86_64::instructions::interrupts::enable();
 }
 
+/// Enable interrupts
+pub fn enable() {
+    x86_64::instructions::interrupts::enable();
+}
+
 /// Disable interrupts
 pub fn disable() {
     x86_64::instructions::interrupts::disable();
@@ -14,3 +60,166 @@ pub fn disable() {
 pub fn are_enabled() -> bool {
     x86_64::instructions::interrupts::are_enabled()
 }
+
+/// Disable interrupts, returning whether they were enabled before
+pub fn save_and_disable() -> bool {
+    let were_enabled = are_enabled();
+    if were_enabled {
+        disable();
+    }
+    were_enabled
+}
+
+/// Restore the interrupt state returned by `save_and_disable`
+pub fn restore(were_enabled: bool) {
+    if were_enabled {
+        enable();
+    }
+}
+
+/// Mark entry into an interrupt handler
+pub fn enter() {
+    let depth = INTERRUPT_DEPTH.fetch_add(1, Ordering::Relaxed) + 1;
+    if depth == 1 {
+        stats::cpu::enter_interrupt();
+    }
+    stats::interrupts::record_nesting(depth);
+}
+
+/// Mark exit from an interrupt handler
+pub fn exit() {
+    if INTERRUPT_DEPTH.fetch_sub(1, Ordering::Relaxed) == 1 {
+        stats::cpu::exit_interrupt();
+    }
+}
+
+/// Check if the CPU is currently running an interrupt handler
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH.load(Ordering::Relaxed) != 0
+}
+
+/// Claim an interrupt vector
+pub fn register_handler(vector: u8, handler: InterruptHandler) -> Result<(), InterruptError> {
+    if vector < FIRST_DYNAMIC_VECTOR {
+        return Err(InterruptError::ReservedVector);
+    }
+    HANDLERS[vector as usize]
+        .compare_exchange(
+            core::ptr::null_mut(),
+            handler as *mut (),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .map(|_| ())
+        .map_err(|_| InterruptError::AlreadyRegistered)
+}
+
+/// Release an interrupt vector
+pub fn unregister_handler(vector: u8) -> Result<(), InterruptError> {
+    if vector < FIRST_DYNAMIC_VECTOR {
+        return Err(InterruptError::ReservedVector);
+    }
+    let old = HANDLERS[vector as usize].swap(core::ptr::null_mut(), Ordering::AcqRel);
+    if old.is_null() {
+        Err(InterruptError::NotRegistered)
+    } else {
+        Ok(())
+    }
+}
+
+/// Claim a legacy IRQ line and unmask it at the PIC
+pub fn register_irq(irq: u8, handler: InterruptHandler) -> Result<(), InterruptError> {
+    if irq >= pic::IRQ_COUNT {
+        return Err(InterruptError::InvalidIrq);
+    }
+    register_handler(pic::irq_to_vector(irq), handler)?;
+    pic::unmask(irq);
+    Ok(())
+}
+
+/// Mask a legacy IRQ line and release its vector
+pub fn unregister_irq(irq: u8) -> Result<(), InterruptError> {
+    if irq >= pic::IRQ_COUNT {
+        return Err(InterruptError::InvalidIrq);
+    }
+    pic::mask(irq);
+    unregister_handler(pic::irq_to_vector(irq))
+}
+
+/// Check if a vector has a registered handler
+pub fn is_registered(vector: u8) -> bool {
+    !HANDLERS[vector as usize].load(Ordering::Acquire).is_null()
+}
+
+/// Common path for every dynamically registered vector
+fn dispatch(vector: u8, frame: &InterruptStackFrame) {
+    if vector == apic::SPURIOUS_VECTOR && apic::is_enabled() {
+        stats::interrupts::record_spurious();
+        return;
+    }
+
+    let irq = pic::vector_to_irq(vector);
+    if let Some(irq) = irq {
+        if pic::is_spurious(irq) {
+            stats::interrupts::record_spurious();
+            // Spurious IRQ 15 still needs an EOI on the primary PIC
+            if irq == 15 {
+                pic::acknowledge_cascade();
+            }
+            return;
+        }
+    }
+
+    enter();
+    crate::trace_event!(IrqEnter, vector as u64, 0);
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    let handler = HANDLERS[vector as usize].load(Ordering::Acquire);
+    if handler.is_null() {
+        stats::interrupts::record_unhandled();
+    } else {
+        let handler: InterruptHandler = unsafe { core::mem::transmute(handler) };
+        handler(frame);
+    }
+    let cycles = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(start);
+    stats::interrupts::record(vector, cycles);
+    crate::crypto::rng::add_interrupt_entropy(vector, start);
+    crate::trace_event!(IrqExit, vector as u64, 0);
+    exit();
+
+    if irq.is_some() {
+        pic::end_of_interrupt(vector);
+    } else if apic::is_enabled() {
+        apic::eoi();
+    }
+}
+
+macro_rules! interrupt_stubs {
+    ($($vector:literal),* $(,)?) => {
+        /// Entry stubs for vectors 32-255, each forwarding its vector number
+        pub(crate) static STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); 224] = [
+            $({
+                extern "x86-interrupt" fn stub(frame: InterruptStackFrame) {
+                    dispatch($vector, &frame);
+                }
+                stub
+            }),*
+        ];
+    };
+}
+
+interrupt_stubs!(
+    32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47,
+    48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63,
+    64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79,
+    80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95,
+    96, 97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111,
+    112, 113, 114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126, 127,
+    128, 129, 130, 131, 132, 133, 134, 135, 136, 137, 138, 139, 140, 141, 142, 143,
+    144, 145, 146, 147, 148, 149, 150, 151, 152, 153, 154, 155, 156, 157, 158, 159,
+    160, 161, 162, 163, 164, 165, 166, 167, 168, 169, 170, 171, 172, 173, 174, 175,
+    176, 177, 178, 179, 180, 181, 182, 183, 184, 185, 186, 187, 188, 189, 190, 191,
+    192, 193, 194, 195, 196, 197, 198, 199, 200, 201, 202, 203, 204, 205, 206, 207,
+    208, 209, 210, 211, 212, 213, 214, 215, 216, 217, 218, 219, 220, 221, 222, 223,
+    224, 225, 226, 227, 228, 229, 230, 231, 232, 233, 234, 235, 236, 237, 238, 239,
+    240, 241, 242, 243, 244, 245, 246, 247, 248, 249, 250, 251, 252, 253, 254, 255,
+);
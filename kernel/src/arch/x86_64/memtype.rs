@@ -0,0 +1,292 @@
+//! Memory Types
+//!
+//! Firmware programs the MTRRs to give every physical range a memory
+//! type, and the PAT entry a page table entry selects can then make a
+//! mapping stricter or, for write-combining, looser. The PAT keeps its
+//! power-on layout in the four entries reachable without the PAT bit,
+//! which the loader's tables and the identity map rely on, and gains
+//! write-combining and write-protect in the upper four. The MTRRs are
+//! only read; their layout is left as firmware set it.
+
+use core::arch::x86_64::__cpuid;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+const IA32_MTRRCAP: u32 = 0xFE;
+const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+const IA32_MTRR_FIX64K_00000: u32 = 0x250;
+const IA32_MTRR_FIX16K_80000: u32 = 0x258;
+const IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+const IA32_PAT: u32 = 0x277;
+const IA32_MTRR_DEF_TYPE: u32 = 0x2FF;
+
+// Leaf 1 EDX bits
+const MTRR: u32 = 1 << 12;
+const PAT: u32 = 1 << 16;
+
+/// IA32_MTRRCAP: fixed range MTRRs supported
+const MTRRCAP_FIX: u64 = 1 << 8;
+/// IA32_MTRR_DEF_TYPE: fixed range MTRRs enabled
+const DEF_TYPE_FE: u64 = 1 << 10;
+/// IA32_MTRR_DEF_TYPE: MTRRs enabled
+const DEF_TYPE_E: u64 = 1 << 11;
+/// IA32_MTRR_PHYSMASKn: range enabled
+const PHYSMASK_VALID: u64 = 1 << 11;
+
+/// PAT entries 0 to 7: WB, WT, UC-, UC as at power-on, then WC, WP, UC-, UC
+const PAT_LAYOUT: u64 = 0x0007_0501_0007_0406;
+
+// Entry bits selecting a PAT entry
+const PAGE_WRITE_THROUGH: u64 = 1 << 3;
+const PAGE_CACHE_DISABLE: u64 = 1 << 4;
+const PAGE_PAT: u64 = 1 << 7;
+const LARGE_PAGE_PAT: u64 = 1 << 12;
+
+/// Set once the PAT holds `PAT_LAYOUT`
+static PAT_READY: AtomicBool = AtomicBool::new(false);
+
+/// x86 memory types, with their MTRR and PAT encoding
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Strong uncacheable, for device registers
+    Uncached = 0,
+    /// Writes are buffered and combined, for framebuffers
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtect = 5,
+    /// Normal RAM
+    WriteBack = 6,
+    /// Uncacheable unless an MTRR says write-combining
+    UncachedMinus = 7,
+}
+
+impl CacheMode {
+    fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0 => Some(CacheMode::Uncached),
+            1 => Some(CacheMode::WriteCombining),
+            4 => Some(CacheMode::WriteThrough),
+            5 => Some(CacheMode::WriteProtect),
+            6 => Some(CacheMode::WriteBack),
+            7 => Some(CacheMode::UncachedMinus),
+            _ => None,
+        }
+    }
+
+    /// Short name as used in reports
+    pub fn name(self) -> &'static str {
+        match self {
+            CacheMode::Uncached => "UC",
+            CacheMode::WriteCombining => "WC",
+            CacheMode::WriteThrough => "WT",
+            CacheMode::WriteProtect => "WP",
+            CacheMode::WriteBack => "WB",
+            CacheMode::UncachedMinus => "UC-",
+        }
+    }
+
+    /// Index of the PAT entry for this type
+    fn pat_index(self) -> u64 {
+        match self {
+            CacheMode::WriteBack => 0,
+            CacheMode::WriteThrough => 1,
+            CacheMode::UncachedMinus => 2,
+            CacheMode::Uncached => 3,
+            // Without the upper entries, fall back to the nearest safe type
+            CacheMode::WriteCombining if !PAT_READY.load(Ordering::Relaxed) => 2,
+            CacheMode::WriteProtect if !PAT_READY.load(Ordering::Relaxed) => 3,
+            CacheMode::WriteCombining => 4,
+            CacheMode::WriteProtect => 5,
+        }
+    }
+}
+
+/// PWT, PCD and PAT bits selecting `mode` in a 4KB page table entry
+pub fn page_flags(mode: CacheMode) -> u64 {
+    let index = mode.pat_index();
+    let mut flags = 0;
+    if index & 1 != 0 {
+        flags |= PAGE_WRITE_THROUGH;
+    }
+    if index & 2 != 0 {
+        flags |= PAGE_CACHE_DISABLE;
+    }
+    if index & 4 != 0 {
+        flags |= PAGE_PAT;
+    }
+    flags
+}
+
+/// Same as `page_flags` for a 2MB entry, where the PAT bit is bit 12
+pub fn large_page_flags(mode: CacheMode) -> u64 {
+    let flags = page_flags(mode);
+    if flags & PAGE_PAT != 0 {
+        flags & !PAGE_PAT | LARGE_PAGE_PAT
+    } else {
+        flags
+    }
+}
+
+/// Program the PAT and report the MTRR layout
+pub fn init() {
+    let features = unsafe { __cpuid(1) }.edx;
+    if features & PAT != 0 {
+        unsafe {
+            Msr::new(IA32_PAT).write(PAT_LAYOUT);
+            // Nothing should select the upper entries yet, but write back
+            // anything cached under their old types
+            core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        }
+        x86_64::instructions::tlb::flush_all();
+        PAT_READY.store(true, Ordering::Relaxed);
+        crate::serial_println!("PAT: write-combining available");
+    } else {
+        crate::serial_println!("PAT: not supported, write-combining falls back to UC-");
+    }
+
+    if features & MTRR != 0 {
+        let def_type = unsafe { Msr::new(IA32_MTRR_DEF_TYPE).read() };
+        crate::serial_println!("MTRRs: {}, default {}, {} variable ranges in use",
+            if def_type & DEF_TYPE_E != 0 { "enabled" } else { "disabled" },
+            CacheMode::from_bits(def_type & 0xFF).map_or("?", CacheMode::name),
+            variable_ranges().filter(Option::is_some).count());
+    }
+}
+
+/// Check whether write-combining mappings really are write-combining
+pub fn write_combining_available() -> bool {
+    PAT_READY.load(Ordering::Relaxed)
+}
+
+/// One enabled variable range MTRR
+#[derive(Debug, Clone, Copy)]
+pub struct VariableRange {
+    pub base: u64,
+    /// Mask of the address bits that must match `base`
+    pub mask: u64,
+    pub mode: Option<CacheMode>,
+}
+
+impl VariableRange {
+    fn contains(&self, addr: u64) -> bool {
+        addr & self.mask == self.base & self.mask
+    }
+
+    /// Size of the range, exact when the mask is contiguous
+    pub fn size(&self) -> u64 {
+        (!self.mask & physical_address_mask()).wrapping_add(1)
+    }
+}
+
+/// Mask of the addresses the CPU can generate
+fn physical_address_mask() -> u64 {
+    let max = unsafe { __cpuid(0x8000_0000) }.eax;
+    let bits = if max >= 0x8000_0008 { unsafe { __cpuid(0x8000_0008) }.eax & 0xFF } else { 36 };
+    (1u64 << bits) - 1
+}
+
+fn mtrr_supported() -> bool {
+    unsafe { __cpuid(1) }.edx & MTRR != 0
+}
+
+/// Every variable range MTRR, `None` for disabled ones
+pub fn variable_ranges() -> impl Iterator<Item = Option<VariableRange>> {
+    let count = if mtrr_supported() { unsafe { Msr::new(IA32_MTRRCAP).read() & 0xFF } } else { 0 };
+    (0..count as u32).map(|i| {
+        let base = unsafe { Msr::new(IA32_MTRR_PHYSBASE0 + i * 2).read() };
+        let mask = unsafe { Msr::new(IA32_MTRR_PHYSBASE0 + i * 2 + 1).read() };
+        (mask & PHYSMASK_VALID != 0).then(|| VariableRange {
+            base: base & !0xFFF,
+            mask: mask & !0xFFF,
+            mode: CacheMode::from_bits(base & 0xFF),
+        })
+    })
+}
+
+/// Type a fixed range MTRR gives an address below 1MB
+fn fixed_type(addr: u64) -> Option<CacheMode> {
+    // Each MSR holds eight one-byte types
+    let (msr, byte) = match addr {
+        0..=0x7FFFF => (IA32_MTRR_FIX64K_00000, addr >> 16),
+        0x80000..=0xBFFFF => (IA32_MTRR_FIX16K_80000 + ((addr - 0x80000) >> 17) as u32, (addr >> 14) & 7),
+        0xC0000..=0xFFFFF => (IA32_MTRR_FIX4K_C0000 + ((addr - 0xC0000) >> 15) as u32, (addr >> 12) & 7),
+        _ => return None,
+    };
+    CacheMode::from_bits(unsafe { Msr::new(msr).read() } >> (byte * 8) & 0xFF)
+}
+
+/// Memory type the MTRRs give `addr`, `None` without MTRRs
+///
+/// The type of a mapping is this combined with its PAT entry: UC in either
+/// wins, and a WC MTRR under a UC- entry gives WC.
+pub fn mtrr_type(addr: u64) -> Option<CacheMode> {
+    if !mtrr_supported() {
+        return None;
+    }
+    let def_type = unsafe { Msr::new(IA32_MTRR_DEF_TYPE).read() };
+    if def_type & DEF_TYPE_E == 0 {
+        return Some(CacheMode::Uncached);
+    }
+    let fixed = unsafe { Msr::new(IA32_MTRRCAP).read() } & MTRRCAP_FIX != 0 && def_type & DEF_TYPE_FE != 0;
+    if fixed && addr < 0x100000 {
+        return fixed_type(addr);
+    }
+
+    // Overlaps resolve to UC, or to WT where only WT and WB overlap
+    let mut found = None;
+    for range in variable_ranges().flatten().filter(|range| range.contains(addr)) {
+        found = match (found, range.mode) {
+            (_, Some(CacheMode::Uncached)) | (Some(CacheMode::Uncached), _) => Some(CacheMode::Uncached),
+            (None, mode) => mode,
+            (Some(CacheMode::WriteThrough), Some(CacheMode::WriteBack)) => Some(CacheMode::WriteThrough),
+            (Some(found), _) => Some(found),
+        };
+    }
+    found.or_else(|| CacheMode::from_bits(def_type & 0xFF))
+}
+
+/// Print the PAT state and every MTRR
+pub fn report(out: &mut dyn Write) -> core::fmt::Result {
+    writeln!(out, "PAT: {}", if write_combining_available() { "WB WT UC- UC WC WP UC- UC" } else { "not programmed" })?;
+    if !mtrr_supported() {
+        return writeln!(out, "MTRRs: not supported");
+    }
+    let def_type = unsafe { Msr::new(IA32_MTRR_DEF_TYPE).read() };
+    writeln!(out, "MTRRs: {}, fixed ranges {}, default {}",
+        if def_type & DEF_TYPE_E != 0 { "enabled" } else { "disabled" },
+        if def_type & DEF_TYPE_FE != 0 { "enabled" } else { "disabled" },
+        CacheMode::from_bits(def_type & 0xFF).map_or("?", CacheMode::name))?;
+    for (i, range) in variable_ranges().enumerate() {
+        if let Some(range) = range {
+            writeln!(out, "  {}: {:#014x} size {:#x} {}",
+                i, range.base, range.size(), range.mode.map_or("?", CacheMode::name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Page table bits for each type, and the MTRR type of low RAM
+fn selftest() -> Result<(), &'static str> {
+    if page_flags(CacheMode::WriteBack) != 0
+        || page_flags(CacheMode::UncachedMinus) != PAGE_CACHE_DISABLE
+        || page_flags(CacheMode::Uncached) != PAGE_CACHE_DISABLE | PAGE_WRITE_THROUGH
+    {
+        return Err("power-on PAT entries");
+    }
+    if write_combining_available()
+        && (page_flags(CacheMode::WriteCombining) != PAGE_PAT
+            || large_page_flags(CacheMode::WriteCombining) != LARGE_PAGE_PAT)
+    {
+        return Err("write-combining entry");
+    }
+    // The kernel runs from RAM, which firmware must make write-back
+    let kernel = selftest as usize as u64;
+    if mtrr_type(kernel).is_some_and(|mode| mode != CacheMode::WriteBack) {
+        return Err("kernel RAM not write-back");
+    }
+    Ok(())
+}
+
+crate::selftest!("memtype", selftest);
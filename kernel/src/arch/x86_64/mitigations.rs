@@ -0,0 +1,220 @@
+//! Speculative Execution Mitigations
+//!
+//! Detects CPU bugs from CPUID and IA32_ARCH_CAPABILITIES and enables the
+//! mitigations that apply to a kernel-only system. Mitigations that guard
+//! the user/kernel boundary, such as KPTI and buffer clearing on return to
+//! user mode, are recorded as required here and applied once user mode
+//! exists. `mitigations=off` on the command line leaves everything off.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt::Write;
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use super::cpuid;
+
+const IA32_SPEC_CTRL: u32 = 0x48;
+const IA32_ARCH_CAPABILITIES: u32 = 0x10A;
+
+/// IA32_SPEC_CTRL: indirect branch restricted speculation
+const SPEC_CTRL_IBRS: u64 = 1 << 0;
+
+// IA32_ARCH_CAPABILITIES bits
+const ARCH_CAP_RDCL_NO: u64 = 1 << 0;
+const ARCH_CAP_IBRS_ALL: u64 = 1 << 1;
+const ARCH_CAP_SSB_NO: u64 = 1 << 4;
+const ARCH_CAP_MDS_NO: u64 = 1 << 5;
+
+// CPUID leaf 7 EDX bits
+const MD_CLEAR: u32 = 1 << 10;
+const SPEC_CTRL: u32 = 1 << 26;
+const ARCH_CAPABILITIES: u32 = 1 << 29;
+const SSBD: u32 = 1 << 31;
+
+/// AMD leaf 0x80000008 EBX: not affected by speculative store bypass
+const AMD_SSB_NO: u32 = 1 << 26;
+
+/// CPU bugs the kernel knows about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vulnerability {
+    /// Rogue data cache load, CVE-2017-5754
+    Meltdown,
+    /// Branch target injection, CVE-2017-5715
+    SpectreV2,
+    /// Speculative store bypass, CVE-2018-3639
+    SpeculativeStoreBypass,
+    /// Microarchitectural data sampling, CVE-2018-12130 and related
+    Mds,
+}
+
+impl Vulnerability {
+    pub const ALL: [Vulnerability; 4] = [
+        Vulnerability::Meltdown,
+        Vulnerability::SpectreV2,
+        Vulnerability::SpeculativeStoreBypass,
+        Vulnerability::Mds,
+    ];
+
+    /// Name as shown in the status report
+    pub fn name(self) -> &'static str {
+        match self {
+            Vulnerability::Meltdown => "meltdown",
+            Vulnerability::SpectreV2 => "spectre_v2",
+            Vulnerability::SpeculativeStoreBypass => "spec_store_bypass",
+            Vulnerability::Mds => "mds",
+        }
+    }
+}
+
+/// Mitigation state of one vulnerability
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    NotAffected,
+    /// Mitigation active, with its name
+    Mitigated(&'static str),
+    /// Mitigation chosen but only applied once user mode exists
+    Pending(&'static str),
+    /// Affected and not mitigated, with the reason
+    Vulnerable(&'static str),
+}
+
+impl core::fmt::Display for Status {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Status::NotAffected => write!(f, "Not affected"),
+            Status::Mitigated(how) => write!(f, "Mitigation: {}", how),
+            Status::Pending(how) => write!(f, "Mitigation pending user mode: {}", how),
+            Status::Vulnerable(why) => write!(f, "Vulnerable: {}", why),
+        }
+    }
+}
+
+struct State {
+    enabled: bool,
+    statuses: [Status; Vulnerability::ALL.len()],
+}
+
+static STATE: Once<State> = Once::new();
+
+/// What the CPU reports about itself
+struct Capabilities {
+    leaf7_edx: u32,
+    arch: u64,
+}
+
+impl Capabilities {
+    fn read() -> Self {
+        let max_leaf = unsafe { __cpuid(0) }.eax;
+        let leaf7_edx = if max_leaf >= 7 { unsafe { __cpuid_count(7, 0) }.edx } else { 0 };
+        let arch = if leaf7_edx & ARCH_CAPABILITIES != 0 {
+            unsafe { Msr::new(IA32_ARCH_CAPABILITIES).read() }
+        } else {
+            0
+        };
+        Capabilities { leaf7_edx, arch }
+    }
+
+    fn has(&self, bit: u32) -> bool {
+        self.leaf7_edx & bit != 0
+    }
+
+    fn arch(&self, bit: u64) -> bool {
+        self.arch & bit != 0
+    }
+}
+
+/// Detect vulnerabilities and apply mitigations
+///
+/// Mitigations are on unless the command line has `mitigations=off`.
+pub fn init() {
+    let enabled = crate::cmdline::get("mitigations") != Some("off");
+    let state = STATE.call_once(|| {
+        let caps = Capabilities::read();
+        let amd = matches!(cpuid::cpuinfo().vendor(), "AuthenticAMD" | "HygonGenuine");
+        let mut statuses = [Status::NotAffected; Vulnerability::ALL.len()];
+        for (status, vulnerability) in statuses.iter_mut().zip(Vulnerability::ALL) {
+            *status = apply(vulnerability, &caps, amd, enabled);
+        }
+        State { enabled, statuses }
+    });
+    for (vulnerability, status) in Vulnerability::ALL.iter().zip(&state.statuses) {
+        if *status != Status::NotAffected {
+            crate::serial_println!("{}: {}", vulnerability.name(), status);
+        }
+    }
+}
+
+fn apply(vulnerability: Vulnerability, caps: &Capabilities, amd: bool, enabled: bool) -> Status {
+    let affected = match vulnerability {
+        Vulnerability::Meltdown => !amd && !caps.arch(ARCH_CAP_RDCL_NO),
+        Vulnerability::SpectreV2 => true,
+        Vulnerability::SpeculativeStoreBypass => !caps.arch(ARCH_CAP_SSB_NO) && !amd_ssb_no(amd),
+        Vulnerability::Mds => !amd && !caps.arch(ARCH_CAP_MDS_NO),
+    };
+    if !affected {
+        return Status::NotAffected;
+    }
+    if !enabled {
+        return Status::Vulnerable("mitigations=off");
+    }
+
+    match vulnerability {
+        Vulnerability::Meltdown => Status::Pending("PTI"),
+        Vulnerability::SpectreV2 => {
+            // Enhanced IBRS is set once and covers all later speculation
+            if caps.has(SPEC_CTRL) && caps.arch(ARCH_CAP_IBRS_ALL) {
+                unsafe {
+                    let mut spec_ctrl = Msr::new(IA32_SPEC_CTRL);
+                    spec_ctrl.write(spec_ctrl.read() | SPEC_CTRL_IBRS);
+                }
+                Status::Mitigated("Enhanced IBRS")
+            } else {
+                Status::Vulnerable("no enhanced IBRS, kernel built without retpolines")
+            }
+        }
+        // Like Linux, SSBD is left for per-task opt-in
+        Vulnerability::SpeculativeStoreBypass if caps.has(SSBD) => {
+            Status::Vulnerable("SSBD available, not forced")
+        }
+        Vulnerability::SpeculativeStoreBypass => Status::Vulnerable("no SSBD"),
+        Vulnerability::Mds if caps.has(MD_CLEAR) => Status::Pending("VERW buffer clearing"),
+        Vulnerability::Mds => Status::Vulnerable("no MD_CLEAR microcode"),
+    }
+}
+
+fn amd_ssb_no(amd: bool) -> bool {
+    amd && cpuid_extended(0x8000_0008).is_some_and(|ebx| ebx & AMD_SSB_NO != 0)
+}
+
+/// EBX of an extended leaf, if the CPU has it
+fn cpuid_extended(leaf: u32) -> Option<u32> {
+    let max = unsafe { __cpuid(0x8000_0000) }.eax;
+    (max >= leaf).then(|| unsafe { __cpuid(leaf) }.ebx)
+}
+
+/// State of one vulnerability, `None` before `init`
+pub fn status(vulnerability: Vulnerability) -> Option<Status> {
+    let index = Vulnerability::ALL.iter().position(|&v| v == vulnerability)?;
+    STATE.get().map(|state| state.statuses[index])
+}
+
+/// Check if user mode must run on separate page tables
+pub fn kpti_required() -> bool {
+    matches!(status(Vulnerability::Meltdown), Some(Status::Pending(_)))
+}
+
+/// Check if buffers must be cleared with VERW on return to user mode
+pub fn mds_clear_required() -> bool {
+    matches!(status(Vulnerability::Mds), Some(Status::Pending(_)))
+}
+
+/// Print the status of every known vulnerability
+pub fn report(out: &mut dyn Write) -> core::fmt::Result {
+    let Some(state) = STATE.get() else {
+        return writeln!(out, "mitigations not initialized");
+    };
+    writeln!(out, "mitigations: {}", if state.enabled { "auto" } else { "off" })?;
+    for (vulnerability, status) in Vulnerability::ALL.iter().zip(&state.statuses) {
+        writeln!(out, "  {:<18} {}", vulnerability.name(), status)?;
+    }
+    Ok(())
+}
@@ -1,12 +1,27 @@
 //! x86_64 architecture-specific implementations
 
+pub mod apic;
+pub mod context;
+pub mod cpu;
+pub mod cpuid;
+pub mod exception;
+pub mod fpu;
 pub mod gdt;
 pub mod idt;
+pub mod inject;
 pub mod interrupts;
+pub mod memtype;
+pub mod mitigations;
+pub mod pic;
+pub mod pit;
+pub mod port;
 
 /// Initialize architecture-specific components
 pub fn init() {
     gdt::init();
+    fpu::init();
     idt::init();
+    pic::init();
+    pit::init();
     interrupts::init();
 }
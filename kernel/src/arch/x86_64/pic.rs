@@ -0,0 +1,105 @@
+//! Legacy 8259 PIC setup
+
+use pic8259::ChainedPics;
+use spin::Mutex;
+use super::port::{self, Port};
+
+/// First vector used by the primary PIC
+pub const PIC_1_OFFSET: u8 = 32;
+/// First vector used by the secondary PIC
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+/// Number of legacy IRQ lines
+pub const IRQ_COUNT: u8 = 16;
+
+/// IRQ line the secondary PIC cascades through
+const CASCADE_IRQ: u8 = 2;
+
+/// OCW3 command to read the in-service register
+const READ_ISR: u8 = 0x0B;
+
+static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Remap the PICs above the exception vectors with every line masked
+pub fn init() {
+    for (base, owner) in [(0x20, "pic1"), (0xA0, "pic2")] {
+        if let Err(e) = port::claim(base, 2, owner) {
+            crate::serial_println!("PIC: {}", e);
+        }
+    }
+    let mut pics = PICS.lock();
+    unsafe {
+        pics.initialize();
+        // Only the cascade line stays open until drivers claim IRQs
+        pics.write_masks(!(1 << CASCADE_IRQ), 0xFF);
+    }
+}
+
+/// Convert an IRQ line to its interrupt vector
+pub const fn irq_to_vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// Convert an interrupt vector to its IRQ line, if it belongs to the PICs
+pub fn vector_to_irq(vector: u8) -> Option<u8> {
+    if (PIC_1_OFFSET..PIC_1_OFFSET + IRQ_COUNT).contains(&vector) {
+        Some(vector - PIC_1_OFFSET)
+    } else {
+        None
+    }
+}
+
+/// Allow an IRQ line to raise interrupts
+pub fn unmask(irq: u8) {
+    update_mask(irq, false);
+}
+
+/// Stop an IRQ line from raising interrupts
+pub fn mask(irq: u8) {
+    update_mask(irq, true);
+}
+
+fn update_mask(irq: u8, masked: bool) {
+    let mut pics = PICS.lock();
+    unsafe {
+        let mut masks = pics.read_masks();
+        let (index, bit) = if irq < 8 { (0, irq) } else { (1, irq - 8) };
+        if masked {
+            masks[index] |= 1 << bit;
+        } else {
+            masks[index] &= !(1 << bit);
+        }
+        pics.write_masks(masks[0], masks[1]);
+    }
+}
+
+/// Check if an IRQ 7/15 is spurious, i.e. not actually in service
+pub fn is_spurious(irq: u8) -> bool {
+    if irq != 7 && irq != 15 {
+        return false;
+    }
+    let command = if irq == 7 { 0x20 } else { 0xA0 };
+    let port: Port<u8> = Port::new(command);
+    let isr = unsafe {
+        port.write(READ_ISR);
+        port.read()
+    };
+    // IRQ 7 and 15 are both the highest line of their PIC
+    isr & (1 << 7) == 0
+}
+
+/// Acknowledge a spurious IRQ 15 on the primary PIC's cascade line
+pub fn acknowledge_cascade() {
+    let port: Port<u8> = Port::new(0x20);
+    unsafe {
+        // Non-specific EOI
+        port.write(0x20);
+    }
+}
+
+/// Signal end of interrupt for a PIC vector
+pub fn end_of_interrupt(vector: u8) {
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(vector);
+    }
+}
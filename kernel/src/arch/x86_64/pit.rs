@@ -0,0 +1,61 @@
+//! Programmable Interval Timer (8253/8254) system tick
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use super::interrupts;
+use super::port::{self, Port};
+
+/// PIT input clock in Hz
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// System tick rate in Hz
+pub const TICK_HZ: u32 = 100;
+
+/// IRQ line of channel 0
+pub const TIMER_IRQ: u8 = 0;
+
+const CHANNEL0_PORT: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+/// Channels 0-2 and the command port, channel 2 is driven by the speaker
+const PORT_COUNT: u16 = 4;
+
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary
+const CHANNEL0_RATE_GENERATOR: u8 = 0b0011_0100;
+
+/// Ticks since the timer was started
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Program channel 0 for periodic ticks and claim IRQ 0
+pub fn init() {
+    if let Err(e) = port::claim(CHANNEL0_PORT, PORT_COUNT, "pit") {
+        crate::serial_println!("PIT: {}", e);
+    }
+    let divisor = (PIT_FREQUENCY / TICK_HZ) as u16;
+    unsafe {
+        let command: Port<u8> = Port::new(COMMAND_PORT);
+        let channel0: Port<u8> = Port::new(CHANNEL0_PORT);
+        command.write(CHANNEL0_RATE_GENERATOR);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+
+    match interrupts::register_irq(TIMER_IRQ, timer_interrupt) {
+        Ok(()) => crate::serial_println!("PIT running at {} Hz", TICK_HZ),
+        Err(e) => crate::serial_println!("PIT: {}", e),
+    }
+}
+
+/// Ticks since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn timer_interrupt(frame: &InterruptStackFrame) {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    crate::time::timers::tick(now);
+    crate::task::preempt::tick(now);
+    crate::task::scheduler::tick(now);
+    crate::watchdog::check(now, frame);
+    #[cfg(feature = "profiler")]
+    crate::profiler::sample(frame.instruction_pointer.as_u64());
+}
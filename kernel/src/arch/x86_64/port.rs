@@ -0,0 +1,243 @@
+//! Port I/O
+//!
+//! `Port<T>` is a single I/O port accessed `T` bits at a time. Drivers
+//! claim the range their device decodes with `claim` before touching it,
+//! and the registry records the owner, so two drivers programming the
+//! same hardware fail at init rather than corrupting each other's state.
+
+use core::fmt::{self, Write};
+use core::marker::PhantomData;
+use spin::Mutex;
+
+/// A value moved by a single `in` or `out`
+pub trait PortValue: Copy {
+    /// # Safety
+    /// Reading a port can have side effects on the device behind it.
+    unsafe fn read_from(port: u16) -> Self;
+    /// # Safety
+    /// Writing a port can reprogram any device, including the memory
+    /// controller.
+    unsafe fn write_to(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+    unsafe fn read_from(port: u16) -> u8 {
+        let value: u8;
+        core::arch::asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        value
+    }
+
+    unsafe fn write_to(port: u16, value: u8) {
+        core::arch::asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+impl PortValue for u16 {
+    unsafe fn read_from(port: u16) -> u16 {
+        let value: u16;
+        core::arch::asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        value
+    }
+
+    unsafe fn write_to(port: u16, value: u16) {
+        core::arch::asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+impl PortValue for u32 {
+    unsafe fn read_from(port: u16) -> u32 {
+        let value: u32;
+        core::arch::asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        value
+    }
+
+    unsafe fn write_to(port: u16, value: u32) {
+        core::arch::asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// I/O port accessed as `T`
+#[derive(Debug, Clone, Copy)]
+pub struct Port<T: PortValue = u8> {
+    port: u16,
+    _width: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    pub const fn new(port: u16) -> Self {
+        Port { port, _width: PhantomData }
+    }
+
+    /// Port number
+    pub const fn number(&self) -> u16 {
+        self.port
+    }
+
+    /// # Safety
+    /// The port must belong to a device the caller drives.
+    pub unsafe fn read(&self) -> T {
+        T::read_from(self.port)
+    }
+
+    /// # Safety
+    /// The port must belong to a device the caller drives.
+    pub unsafe fn write(&self, value: T) {
+        T::write_to(self.port, value)
+    }
+}
+
+/// Errors that can occur when claiming ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortError {
+    /// Range is empty or runs past port 0xFFFF
+    InvalidRange,
+    /// Part of the range belongs to another driver
+    Conflict { owner: &'static str, base: u16, len: u16 },
+    /// No room left in the registry
+    RegistryFull,
+}
+
+impl fmt::Display for PortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortError::InvalidRange => write!(f, "Invalid port range"),
+            PortError::Conflict { owner, base, len } => {
+                write!(f, "Ports {:#06x}-{:#06x} owned by {}", base, base + (len - 1), owner)
+            }
+            PortError::RegistryFull => write!(f, "Port registry full"),
+        }
+    }
+}
+
+/// Ports owned by one driver
+///
+/// Handles are plain numbers and can be copied into whatever needs to
+/// reach the device; the claim lasts until `release`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    base: u16,
+    len: u16,
+}
+
+impl PortRange {
+    /// First port of the range
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    /// Number of ports in the range
+    pub fn len(&self) -> u16 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn contains(&self, port: u16) -> bool {
+        port >= self.base && port - self.base < self.len
+    }
+
+    fn overlaps(&self, other: &PortRange) -> bool {
+        self.contains(other.base) || other.contains(self.base)
+    }
+
+    /// Port at `offset`, accessed as `T`, panicking outside the range
+    pub fn port<T: PortValue>(&self, offset: u16) -> Port<T> {
+        let size = core::mem::size_of::<T>() as u16;
+        assert!(
+            offset.checked_add(size).is_some_and(|end| end <= self.len),
+            "port access at {:#x}+{:#x} outside {} ports",
+            self.base,
+            offset,
+            self.len
+        );
+        Port::new(self.base + offset)
+    }
+
+    /// Read the port at `offset`
+    pub fn read<T: PortValue>(&self, offset: u16) -> T {
+        // The range was claimed, so its ports belong to the caller
+        unsafe { self.port::<T>(offset).read() }
+    }
+
+    /// Write the port at `offset`
+    pub fn write<T: PortValue>(&self, offset: u16, value: T) {
+        unsafe { self.port::<T>(offset).write(value) }
+    }
+}
+
+/// Registered port ranges
+const MAX_CLAIMS: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Claim {
+    range: PortRange,
+    owner: &'static str,
+}
+
+static CLAIMS: Mutex<[Option<Claim>; MAX_CLAIMS]> = Mutex::new([None; MAX_CLAIMS]);
+
+/// Claim `len` ports from `base` for `owner`
+///
+/// Fails if any of them is already claimed, naming the driver that has
+/// them.
+pub fn claim(base: u16, len: u16, owner: &'static str) -> Result<PortRange, PortError> {
+    if len == 0 || base.checked_add(len - 1).is_none() {
+        return Err(PortError::InvalidRange);
+    }
+    let range = PortRange { base, len };
+    let mut claims = CLAIMS.lock();
+    if let Some(existing) = claims.iter().flatten().find(|claim| claim.range.overlaps(&range)) {
+        return Err(PortError::Conflict { owner: existing.owner, base: existing.range.base, len: existing.range.len });
+    }
+    let slot = claims.iter_mut().find(|slot| slot.is_none()).ok_or(PortError::RegistryFull)?;
+    *slot = Some(Claim { range, owner });
+    Ok(range)
+}
+
+/// Give up a claimed range
+pub fn release(range: PortRange) {
+    let mut claims = CLAIMS.lock();
+    if let Some(slot) = claims.iter_mut().find(|slot| slot.is_some_and(|claim| claim.range == range)) {
+        *slot = None;
+    }
+}
+
+/// Owner of `port`, if it is claimed
+pub fn owner(port: u16) -> Option<&'static str> {
+    CLAIMS.lock().iter().flatten().find(|claim| claim.range.contains(port)).map(|claim| claim.owner)
+}
+
+/// Print every claimed range in port order
+pub fn report(out: &mut dyn Write) -> fmt::Result {
+    let mut claims = *CLAIMS.lock();
+    claims.sort_unstable_by_key(|claim| claim.map_or(u32::MAX, |claim| claim.range.base as u32));
+    for claim in claims.iter().flatten() {
+        writeln!(out, "  {:#06x}-{:#06x} : {}", claim.range.base, claim.range.base + (claim.range.len - 1), claim.owner)?;
+    }
+    Ok(())
+}
+
+/// Overlapping claims are refused and released ranges can be claimed again
+fn selftest() -> Result<(), &'static str> {
+    // Port 0xFFF0 and up is decoded by nothing on a PC
+    let range = claim(0xFFF0, 8, "selftest").map_err(|_| "claim failed")?;
+    let conflict = claim(0xFFF4, 8, "selftest-conflict");
+    let owner_seen = owner(0xFFF7);
+    release(range);
+    if !matches!(conflict, Err(PortError::Conflict { owner: "selftest", .. })) {
+        return Err("overlapping claim allowed");
+    }
+    if owner_seen != Some("selftest") {
+        return Err("owner not recorded");
+    }
+    if claim(0xFFFF, 2, "selftest") != Err(PortError::InvalidRange) {
+        return Err("range past 0xFFFF allowed");
+    }
+    let again = claim(0xFFF4, 8, "selftest").map_err(|_| "released range still claimed")?;
+    release(again);
+    Ok(())
+}
+
+crate::selftest!("port", selftest);
@@ -0,0 +1,95 @@
+//! The Benchmarks
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use super::{Bench, Bencher};
+use crate::ipc::{pipe, PipeReader, PipeWriter};
+use crate::mm::frame_allocator;
+use crate::task;
+
+/// Every benchmark, in the order a full run reports them
+pub static BENCHES: &[Bench] = &[
+    Bench { name: "frame_alloc", run: frame_alloc },
+    Bench { name: "heap_alloc", run: heap_alloc },
+    Bench { name: "context_switch", run: context_switch },
+    Bench { name: "pipe_round_trip", run: pipe_round_trip },
+];
+
+/// Allocate a physical frame and free it again
+fn frame_alloc(bencher: &mut Bencher) -> Result<(), &'static str> {
+    let frame = frame_allocator::allocate_frame().map_err(|_| "no free frames")?;
+    frame_allocator::deallocate_frame(frame).map_err(|_| "frame not freed")?;
+    bencher.iter(|| {
+        if let Ok(frame) = frame_allocator::allocate_frame() {
+            let _ = frame_allocator::deallocate_frame(frame);
+        }
+    });
+    Ok(())
+}
+
+/// Allocate 64 bytes on the heap and free them again
+fn heap_alloc(bencher: &mut Bencher) -> Result<(), &'static str> {
+    bencher.iter(|| Box::new([0u8; 64]));
+    Ok(())
+}
+
+/// Keeps the partner task of `context_switch` yielding
+static YIELDING: AtomicBool = AtomicBool::new(false);
+
+fn yield_partner() {
+    while YIELDING.load(Ordering::Acquire) {
+        task::yield_now();
+    }
+}
+
+/// Yield to a partner task that yields straight back, two switches
+fn context_switch(bencher: &mut Bencher) -> Result<(), &'static str> {
+    task::current_id().ok_or("tasking not initialized")?;
+    YIELDING.store(true, Ordering::Release);
+    if task::spawn("bench-yield", yield_partner).is_none() {
+        YIELDING.store(false, Ordering::Release);
+        return Err("spawn failed");
+    }
+    bencher.iter(task::yield_now);
+    YIELDING.store(false, Ordering::Release);
+    // Let the partner see the flag and end
+    task::yield_now();
+    Ok(())
+}
+
+/// Pipe ends handed to the echo task
+static ECHO: Mutex<Option<(PipeReader, PipeWriter)>> = Mutex::new(None);
+
+/// Send back every byte read, until the request pipe closes
+fn echo() {
+    let Some((requests, replies)) = ECHO.lock().take() else {
+        return;
+    };
+    let mut byte = [0u8; 1];
+    while requests.read(&mut byte) > 0 {
+        if replies.write(&byte).is_err() {
+            break;
+        }
+    }
+}
+
+/// Send a byte through a pipe to a task that returns it through another
+fn pipe_round_trip(bencher: &mut Bencher) -> Result<(), &'static str> {
+    task::current_id().ok_or("tasking not initialized")?;
+    let (requests, request_writer) = pipe();
+    let (reply_reader, replies) = pipe();
+    *ECHO.lock() = Some((requests, replies));
+    if task::spawn("bench-echo", echo).is_none() {
+        *ECHO.lock() = None;
+        return Err("spawn failed");
+    }
+    let mut byte = [0u8; 1];
+    bencher.iter(|| {
+        let _ = request_writer.write(&[1]);
+        reply_reader.read(&mut byte)
+    });
+    // The echo task sees the end of its input and exits
+    drop(request_writer);
+    Ok(())
+}
@@ -0,0 +1,173 @@
+//! Micro-benchmarks
+//!
+//! Each benchmark times one operation many times with the TSC and reports
+//! the spread in cycles, one line per benchmark:
+//!
+//! ```text
+//! BENCH frame_alloc samples=1000 min=412 median=438 mean=451 max=2710
+//! ```
+//!
+//! The format is fixed so the lines of two builds can be compared
+//! directly. Reads of the TSC are fenced so the timed operation cannot
+//! drift across them, and the cost of an empty measurement is taken off
+//! every sample. Interrupts stay enabled, as the context switch and pipe
+//! benchmarks need them; the median is the figure to compare, the maximum
+//! shows what interrupts cost.
+//!
+//! A scripted run is requested through fw_cfg, like the self-tests:
+//!
+//! ```text
+//! qemu-system-x86_64 ... -fw_cfg name=opt/cosmos/bench,string=1
+//! ```
+
+mod benches;
+
+use alloc::vec::Vec;
+use core::arch::x86_64::{__rdtscp, _mm_lfence, _rdtsc};
+use core::fmt::Write;
+use core::hint::black_box;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub use benches::BENCHES;
+
+/// Untimed runs before sampling, to warm caches and branch predictors
+pub const WARMUP: usize = 100;
+/// Timed runs per benchmark
+pub const SAMPLES: usize = 1000;
+
+/// Benchmark body, times its operation with `Bencher::iter`
+pub type BenchFn = fn(&mut Bencher) -> Result<(), &'static str>;
+
+/// Named benchmark
+pub struct Bench {
+    pub name: &'static str,
+    pub run: BenchFn,
+}
+
+/// TSC at the start of a measurement, after earlier instructions finished
+fn start() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let tsc = _rdtsc();
+        _mm_lfence();
+        tsc
+    }
+}
+
+/// TSC at the end of a measurement, once the measured code finished
+fn stop() -> u64 {
+    let mut aux = 0;
+    unsafe {
+        let tsc = __rdtscp(&mut aux);
+        _mm_lfence();
+        tsc
+    }
+}
+
+/// Cycles of an empty measurement, 0 until calibrated
+static OVERHEAD: AtomicU64 = AtomicU64::new(0);
+
+/// Measure the cost of `start` and `stop` back to back, keeping the least
+fn calibrate() -> u64 {
+    let overhead = (0..SAMPLES)
+        .map(|_| {
+            let begin = start();
+            stop().wrapping_sub(begin)
+        })
+        .min()
+        .unwrap_or(0);
+    OVERHEAD.store(overhead, Ordering::Relaxed);
+    overhead
+}
+
+/// Times the operation of one benchmark
+pub struct Bencher {
+    samples: Vec<u64>,
+    overhead: u64,
+}
+
+impl Bencher {
+    /// Run `operation` `WARMUP` times, then time it `SAMPLES` times
+    pub fn iter<R>(&mut self, mut operation: impl FnMut() -> R) {
+        for _ in 0..WARMUP {
+            black_box(operation());
+        }
+        self.samples.clear();
+        for _ in 0..SAMPLES {
+            let begin = start();
+            black_box(operation());
+            let end = stop();
+            self.samples.push(end.wrapping_sub(begin).saturating_sub(self.overhead));
+        }
+    }
+}
+
+/// Spread of a benchmark's samples, in TSC cycles
+#[derive(Debug, Clone, Copy)]
+pub struct Summary {
+    pub samples: usize,
+    pub min: u64,
+    pub median: u64,
+    pub mean: u64,
+    pub max: u64,
+}
+
+impl Summary {
+    fn of(samples: &mut [u64]) -> Option<Summary> {
+        samples.sort_unstable();
+        let count = samples.len();
+        Some(Summary {
+            samples: count,
+            min: *samples.first()?,
+            median: samples[count / 2],
+            mean: samples.iter().sum::<u64>() / count as u64,
+            max: *samples.last()?,
+        })
+    }
+}
+
+/// Look up a benchmark by name
+pub fn find(name: &str) -> Option<&'static Bench> {
+    BENCHES.iter().find(|bench| bench.name == name)
+}
+
+/// Run one benchmark, `Err` if it could not set up or timed nothing
+pub fn run(bench: &Bench) -> Result<Summary, &'static str> {
+    let overhead = match OVERHEAD.load(Ordering::Relaxed) {
+        0 => calibrate(),
+        overhead => overhead,
+    };
+    let mut bencher = Bencher { samples: Vec::with_capacity(SAMPLES), overhead };
+    (bench.run)(&mut bencher)?;
+    Summary::of(&mut bencher.samples).ok_or("nothing timed")
+}
+
+/// Print the result line of a finished benchmark
+pub fn report(bench: &Bench, result: &Result<Summary, &'static str>, out: &mut dyn Write) -> core::fmt::Result {
+    match result {
+        Ok(summary) => writeln!(
+            out,
+            "BENCH {} samples={} min={} median={} mean={} max={}",
+            bench.name, summary.samples, summary.min, summary.median, summary.mean, summary.max
+        ),
+        Err(reason) => writeln!(out, "BENCH {} error={:?}", bench.name, reason),
+    }
+}
+
+/// Run one benchmark and print its line, returns whether it ran
+pub fn run_and_report(bench: &Bench, out: &mut dyn Write) -> Result<bool, core::fmt::Error> {
+    let result = run(bench);
+    report(bench, &result, out)?;
+    Ok(result.is_ok())
+}
+
+/// Run every benchmark, returns how many failed to run
+pub fn run_all(out: &mut dyn Write) -> Result<usize, core::fmt::Error> {
+    let mut failed = 0;
+    for bench in BENCHES {
+        if !run_and_report(bench, out)? {
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
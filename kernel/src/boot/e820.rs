@@ -0,0 +1,52 @@
+//! Legacy E820 Handoff
+//!
+//! The BIOS stage2 leaves a 32-bit entry count at 0x9000 followed by
+//! 24-byte E820 entries, and enters with no arguments. The UEFI loader
+//! stores the same layout but also passes a `BootInfo`.
+
+use core::ops::Range;
+use super::{BootMode, BootProtocol};
+use crate::mm::memory_map::{MemoryMapEntry, MemoryMapError};
+
+/// Fixed location where the in-tree loaders store the memory map
+pub const MEMORY_MAP_LOCATION: u64 = cosmos_bootinfo::MEMORY_MAP_ADDRESS;
+
+/// Page tables the BIOS stage2 builds: PML4, PDPT and up to four page
+/// directories for the 4GB it identity maps
+pub const STAGE2_PAGE_TABLES: Range<u64> = 0x70000..0x76000;
+
+/// Entry stack the BIOS stage2 sets up, growing down from 0x90000
+pub const STAGE2_STACK: Range<u64> = 0x80000..0x90000;
+
+/// Legacy protocol used when nothing else is detected
+pub struct E820;
+
+impl BootProtocol for E820 {
+    fn name(&self) -> &'static str {
+        "E820"
+    }
+
+    fn memory_map(&self) -> Result<&'static [MemoryMapEntry], MemoryMapError> {
+        unsafe {
+            let raw_entry_count = *(MEMORY_MAP_LOCATION as *const u32);
+
+            // Check if location contains reasonable data
+            if raw_entry_count == 0 || raw_entry_count == 0xFFFFFFFF {
+                return Err(MemoryMapError::NoMemoryMap);
+            }
+            let entry_count = raw_entry_count as usize;
+            if entry_count > super::MAX_MEMORY_MAP_ENTRIES {
+                return Err(MemoryMapError::InvalidMemoryMap);
+            }
+
+            // Entries start right after the count, 4 byte alignment
+            let entries = (MEMORY_MAP_LOCATION + 4) as *const MemoryMapEntry;
+            Ok(core::slice::from_raw_parts(entries, entry_count))
+        }
+    }
+
+    fn boot_mode(&self) -> Option<BootMode> {
+        // Only the BIOS stage2 enters without a boot info
+        Some(BootMode::Bios)
+    }
+}
@@ -0,0 +1,109 @@
+//! CosmOS Boot Info
+//!
+//! A loader that knows about CosmOS can pass the physical address of a
+//! `BootInfo` in RDI. Unlike the fixed 0x9000 layout it can also carry the
+//! RSDP, which UEFI firmware does not place in the BIOS search area, and
+//! the UEFI runtime services table. The UEFI loader stores it at 0x8000,
+//! along with where it put the page tables and stack the kernel starts on,
+//! the firmware it ran under and the consoles it left usable. A block from
+//! an older loader is read up to the fields its version has, the rest
+//! count as not passed.
+
+use core::ops::Range;
+use super::{BootMode, BootProtocol};
+use crate::mm::memory_map::{MemoryMapEntry, MemoryMapError};
+
+pub use cosmos_bootinfo::{BootInfo, BOOT_INFO_MAGIC, BOOT_INFO_MIN_VERSION, BOOT_INFO_VERSION};
+
+/// `base..base + size`, or `None` if the loader left it out
+fn range(base: u64, size: u64) -> Option<Range<u64>> {
+    (base != 0 && size != 0).then(|| base..base.saturating_add(size))
+}
+
+/// Boot info found through RDI
+pub struct Info(&'static BootInfo);
+
+impl Info {
+    /// `value` if the block's version has the field, added in `version`
+    fn since<T>(&self, version: u32, value: T) -> Option<T> {
+        (self.0.version >= version).then_some(value)
+    }
+}
+
+static INFO: spin::Once<Info> = spin::Once::new();
+
+/// Check whether `arg0` points to a valid `BootInfo`
+pub fn detect(arg0: u64) -> Option<&'static dyn BootProtocol> {
+    if !arg0.is_multiple_of(8) || !super::is_readable(arg0, core::mem::size_of::<BootInfo>() as u64) {
+        return None;
+    }
+    let info = unsafe { &*(arg0 as *const BootInfo) };
+    if info.magic != BOOT_INFO_MAGIC || !(BOOT_INFO_MIN_VERSION..=BOOT_INFO_VERSION).contains(&info.version) {
+        return None;
+    }
+    Some(INFO.call_once(|| Info(info)))
+}
+
+impl BootProtocol for Info {
+    fn name(&self) -> &'static str {
+        "CosmOS boot info"
+    }
+
+    fn memory_map(&self) -> Result<&'static [MemoryMapEntry], MemoryMapError> {
+        let count = self.0.memory_map_count as usize;
+        if count == 0 {
+            return Err(MemoryMapError::NoMemoryMap);
+        }
+        let size = (count * core::mem::size_of::<MemoryMapEntry>()) as u64;
+        if count > super::MAX_MEMORY_MAP_ENTRIES || !super::is_readable(self.0.memory_map, size) {
+            return Err(MemoryMapError::InvalidMemoryMap);
+        }
+        Ok(unsafe {
+            core::slice::from_raw_parts(self.0.memory_map as *const MemoryMapEntry, count)
+        })
+    }
+
+    fn rsdp(&self) -> Option<u64> {
+        Some(self.0.rsdp).filter(|&rsdp| rsdp != 0)
+    }
+
+    fn command_line(&self) -> Option<&'static str> {
+        unsafe { super::c_str(self.0.command_line) }
+    }
+
+    fn uefi_runtime_services(&self) -> Option<u64> {
+        self.since(2, self.0.uefi_runtime).filter(|&table| table != 0)
+    }
+
+    fn boot_page_tables(&self) -> Option<Range<u64>> {
+        self.since(3, ()).and_then(|()| range(self.0.page_tables, self.0.page_tables_size))
+    }
+
+    fn boot_stack(&self) -> Option<Range<u64>> {
+        self.since(3, ()).and_then(|()| range(self.0.stack, self.0.stack_size))
+    }
+
+    fn loader_version(&self) -> Option<u64> {
+        self.since(4, self.0.loader_version)
+    }
+
+    fn loader_capabilities(&self) -> u64 {
+        self.since(4, self.0.loader_capabilities).unwrap_or(0)
+    }
+
+    fn secure_boot(&self) -> u64 {
+        self.since(6, self.0.secure_boot).unwrap_or(0)
+    }
+
+    fn boot_mode(&self) -> Option<BootMode> {
+        self.since(7, self.0.boot_mode).and_then(BootMode::from_u32)
+    }
+
+    fn firmware_vendor(&self) -> Option<&'static str> {
+        self.since(7, self.0.firmware_vendor()).filter(|vendor| !vendor.is_empty())
+    }
+
+    fn consoles(&self) -> u64 {
+        self.since(7, self.0.consoles).unwrap_or(cosmos_bootinfo::CONSOLE_VGA_TEXT | cosmos_bootinfo::CONSOLE_SERIAL)
+    }
+}
@@ -0,0 +1,353 @@
+//! Limine Handoff
+//!
+//! Limine finds request structures by scanning the kernel image for their
+//! IDs and fills in the response pointers before entry. The memory map
+//! request is answered for any kernel Limine loads, so a response there
+//! means Limine started us.
+//!
+//! Limine only loads higher half kernels, relocating a position
+//! independent one into the higher half. The entry point request sends it
+//! to `entry`, which identity maps the first 4GB, moves the kernel to run
+//! from its physical address like every other loader leaves it, and calls
+//! `_start`. Limine's higher half stays mapped, so the responses, which
+//! point into the higher half direct map (HHDM), can still be read when
+//! `detect` copies what the kernel keeps.
+
+use core::cell::UnsafeCell;
+use super::{BootProtocol, ConvertedMap, CopiedStr};
+use crate::mm::memory_map::{MemoryMapEntry, MemoryMapError, MemoryType};
+
+const COMMON_MAGIC: [u64; 2] = [0xc7b1_dd30_df4c_8b88, 0x0a82_e883_a194_f07b];
+
+const MEMMAP_ID: [u64; 4] =
+    [COMMON_MAGIC[0], COMMON_MAGIC[1], 0x67cf_3d9d_378a_806f, 0xe304_acdf_c50c_3c62];
+const RSDP_ID: [u64; 4] =
+    [COMMON_MAGIC[0], COMMON_MAGIC[1], 0xc5e7_7b6b_397e_7b43, 0x2763_7845_accd_cf3c];
+const KERNEL_FILE_ID: [u64; 4] =
+    [COMMON_MAGIC[0], COMMON_MAGIC[1], 0xad97_e90e_83f1_ed67, 0x31eb_5d1c_5ff2_3b69];
+const HHDM_ID: [u64; 4] =
+    [COMMON_MAGIC[0], COMMON_MAGIC[1], 0x48dc_f1cb_8ad2_b852, 0x6398_4e95_9a98_244b];
+const KERNEL_ADDRESS_ID: [u64; 4] =
+    [COMMON_MAGIC[0], COMMON_MAGIC[1], 0x71ba_7686_3cc5_5f63, 0xb264_4a48_c516_a487];
+const ENTRY_POINT_ID: [u64; 4] =
+    [COMMON_MAGIC[0], COMMON_MAGIC[1], 0x13d8_6c03_5a1c_d3e1, 0x2b0c_aa89_d8f3_026a];
+
+/// Base revision tag, Limine zeroes the last word if it supports it
+const BASE_REVISION_TAG: [u64; 2] = [0xf956_2b2d_5c95_a6c8, 0x6a7b_3849_4453_6bdc];
+/// Revision asked for, the first with a physical RSDP address
+const BASE_REVISION: u64 = 3;
+
+// Limine memory map entry types
+const LIMINE_USABLE: u64 = 0;
+const LIMINE_ACPI_RECLAIMABLE: u64 = 2;
+const LIMINE_ACPI_NVS: u64 = 3;
+const LIMINE_BAD_MEMORY: u64 = 4;
+
+/// R_X86_64_RELATIVE, the only relocation type the kernel links with
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// Request header shared by all Limine requests
+#[repr(C)]
+struct Request<R, E = ()> {
+    id: [u64; 4],
+    revision: u64,
+    /// Written by the loader before entry
+    response: UnsafeCell<*const R>,
+    /// Request-specific fields after the response
+    extra: E,
+}
+
+// The loader writes the response once, before any CPU runs kernel code
+unsafe impl<R, E: Sync> Sync for Request<R, E> {}
+
+impl<R> Request<R> {
+    const fn new(id: [u64; 4]) -> Self {
+        Request { id, revision: 0, response: UnsafeCell::new(core::ptr::null()), extra: () }
+    }
+}
+
+impl<R, E> Request<R, E> {
+    /// The loader's answer, read through the HHDM it points into
+    fn response(&self) -> Option<&'static R> {
+        unsafe { core::ptr::read_volatile(self.response.get()).as_ref() }
+    }
+}
+
+/// Base revision tag, the last word written by the loader
+#[repr(C)]
+struct BaseRevision(UnsafeCell<[u64; 3]>);
+
+unsafe impl Sync for BaseRevision {}
+
+impl BaseRevision {
+    fn is_supported(&self) -> bool {
+        unsafe { core::ptr::read_volatile(&raw const (*self.0.get())[2]) == 0 }
+    }
+}
+
+#[repr(C)]
+struct MemmapResponse {
+    revision: u64,
+    entry_count: u64,
+    entries: *const *const MemmapEntry,
+}
+
+#[repr(C)]
+struct MemmapEntry {
+    base: u64,
+    length: u64,
+    kind: u64,
+}
+
+#[repr(C)]
+struct RsdpResponse {
+    revision: u64,
+    address: u64,
+}
+
+#[repr(C)]
+struct KernelFile {
+    revision: u64,
+    address: u64,
+    size: u64,
+    path: u64,
+    command_line: u64,
+}
+
+#[repr(C)]
+struct KernelFileResponse {
+    revision: u64,
+    kernel_file: *const KernelFile,
+}
+
+#[repr(C)]
+struct HhdmResponse {
+    revision: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+struct KernelAddressResponse {
+    revision: u64,
+    physical_base: u64,
+    virtual_base: u64,
+}
+
+#[repr(C)]
+struct EntryPointResponse {
+    revision: u64,
+}
+
+#[used]
+static BASE_REVISION_REQUEST: BaseRevision =
+    BaseRevision(UnsafeCell::new([BASE_REVISION_TAG[0], BASE_REVISION_TAG[1], BASE_REVISION]));
+#[used]
+static MEMMAP_REQUEST: Request<MemmapResponse> = Request::new(MEMMAP_ID);
+#[used]
+static RSDP_REQUEST: Request<RsdpResponse> = Request::new(RSDP_ID);
+#[used]
+static KERNEL_FILE_REQUEST: Request<KernelFileResponse> = Request::new(KERNEL_FILE_ID);
+#[used]
+static HHDM_REQUEST: Request<HhdmResponse> = Request::new(HHDM_ID);
+#[used]
+static KERNEL_ADDRESS_REQUEST: Request<KernelAddressResponse> = Request::new(KERNEL_ADDRESS_ID);
+#[used]
+static ENTRY_POINT_REQUEST: Request<EntryPointResponse, extern "C" fn() -> !> = Request {
+    id: ENTRY_POINT_ID,
+    revision: 0,
+    response: UnsafeCell::new(core::ptr::null()),
+    extra: entry,
+};
+
+/// Booted by Limine
+pub struct Limine;
+
+/// What the kernel keeps of the responses
+struct Copied {
+    memory_map: ConvertedMap,
+    rsdp: Option<u64>,
+    command_line: Option<CopiedStr>,
+}
+
+static COPIED: spin::Once<Copied> = spin::Once::new();
+
+/// Check whether Limine answered the memory map request
+pub fn detect() -> Option<&'static dyn BootProtocol> {
+    let memmap = MEMMAP_REQUEST.response()?;
+    let hhdm = HHDM_REQUEST.response()?;
+    COPIED.call_once(|| Copied::from_responses(memmap, hhdm.offset));
+    Some(&Limine)
+}
+
+/// Translate a Limine entry type, treating loader memory as reserved
+fn e820_type(kind: u64) -> u32 {
+    let memory_type = match kind {
+        LIMINE_USABLE => MemoryType::Usable,
+        LIMINE_ACPI_RECLAIMABLE => MemoryType::AcpiReclaimable,
+        LIMINE_ACPI_NVS => MemoryType::AcpiNvs,
+        LIMINE_BAD_MEMORY => MemoryType::BadMemory,
+        // Includes bootloader-reclaimable memory, which holds Limine's
+        // higher half page tables the kernel keeps using
+        _ => MemoryType::Reserved,
+    };
+    memory_type as u32
+}
+
+impl Copied {
+    fn from_responses(memmap: &MemmapResponse, hhdm_offset: u64) -> Self {
+        let mut memory_map = ConvertedMap::new();
+        for i in 0..memmap.entry_count as usize {
+            let entry = unsafe { &**memmap.entries.add(i) };
+            if !memory_map.push(entry.base, entry.length, e820_type(entry.kind)) {
+                break;
+            }
+        }
+        // Base revisions before 3 pass the RSDP as an HHDM address
+        let rsdp = RSDP_REQUEST.response()
+            .map(|response| response.address)
+            .filter(|&rsdp| rsdp != 0)
+            .map(|rsdp| if BASE_REVISION_REQUEST.is_supported() { rsdp } else { rsdp.wrapping_sub(hhdm_offset) });
+        let command_line = KERNEL_FILE_REQUEST.response()
+            .and_then(|response| unsafe { response.kernel_file.as_ref() })
+            .and_then(|file| unsafe { hhdm_str(file.command_line) })
+            .and_then(CopiedStr::copy);
+        Copied { memory_map, rsdp, command_line }
+    }
+}
+
+/// Read a NUL-terminated string Limine points to in the HHDM
+///
+/// # Safety
+/// `addr` must be 0 or a string in a response.
+unsafe fn hhdm_str(addr: u64) -> Option<&'static str> {
+    if addr == 0 {
+        return None;
+    }
+    let bytes = core::ffi::CStr::from_ptr(addr as *const core::ffi::c_char).to_bytes();
+    core::str::from_utf8(bytes).ok()
+}
+
+impl BootProtocol for Limine {
+    fn name(&self) -> &'static str {
+        "Limine"
+    }
+
+    fn memory_map(&self) -> Result<&'static [MemoryMapEntry], MemoryMapError> {
+        COPIED.get().ok_or(MemoryMapError::NoMemoryMap)?.memory_map.entries()
+    }
+
+    fn rsdp(&self) -> Option<u64> {
+        COPIED.get()?.rsdp
+    }
+
+    fn command_line(&self) -> Option<&'static str> {
+        COPIED.get()?.command_line.as_ref().map(CopiedStr::as_str)
+    }
+}
+
+/// Page tables the entry path identity maps with: PML4, PDPT and four
+/// page directories of 2MB pages
+#[repr(C, align(4096))]
+struct EntryTables([[u64; 512]; 6]);
+
+static mut ENTRY_TABLES: EntryTables = EntryTables([[0; 512]; 6]);
+
+/// Stack `_start` runs on, Limine's sits in the higher half
+#[repr(C, align(16))]
+struct EntryStack([u8; ENTRY_STACK_SIZE]);
+
+const ENTRY_STACK_SIZE: usize = 64 * 1024;
+
+static mut ENTRY_STACK: EntryStack = EntryStack([0; ENTRY_STACK_SIZE]);
+
+/// Limine enters here, in the higher half with its own page tables
+///
+/// Runs relocated for the higher half, so plain Rust works until
+/// `enter_identity` moves the kernel.
+extern "C" fn entry() -> ! {
+    const PRESENT_WRITABLE: u64 = 0x3;
+    const LARGE_PAGE: u64 = 0x80;
+    const FOUR_GB: u64 = 4 << 30;
+    extern "C" {
+        static __kernel_size: u8;
+    }
+
+    let (Some(kernel), Some(hhdm)) = (KERNEL_ADDRESS_REQUEST.response(), HHDM_REQUEST.response()) else {
+        halt();
+    };
+    // The identity map has to cover the whole image
+    let size = &raw const __kernel_size as u64;
+    if kernel.physical_base.saturating_add(size) > FOUR_GB {
+        halt();
+    }
+    let to_physical = kernel.physical_base.wrapping_sub(kernel.virtual_base);
+
+    unsafe {
+        let tables = &mut *core::ptr::addr_of_mut!(ENTRY_TABLES.0);
+        let physical = |table: &[u64; 512]| table.as_ptr() as u64 + to_physical;
+        for (i, entry) in tables[2..].as_flattened_mut().iter_mut().enumerate() {
+            *entry = (i as u64) << 21 | PRESENT_WRITABLE | LARGE_PAGE;
+        }
+        for i in 0..4 {
+            tables[1][i] = physical(&tables[2 + i]) | PRESENT_WRITABLE;
+        }
+        tables[0][0] = physical(&tables[1]) | PRESENT_WRITABLE;
+        // Keep Limine's higher half: the kernel mapping until the jump,
+        // and the HHDM the responses point into
+        let (root, _) = x86_64::registers::control::Cr3::read();
+        let root = (root.start_address().as_u64() + hhdm.offset) as *const u64;
+        tables[0][256..].copy_from_slice(core::slice::from_raw_parts(root.add(256), 256));
+        let pml4 = physical(&tables[0]);
+        let relocation = kernel.physical_base.wrapping_sub(cosmos_bootinfo::KERNEL_LINK_ADDRESS);
+        enter_identity(pml4, to_physical, relocation)
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Switch to `pml4`, continue in the identity-mapped copy of the kernel
+/// `to_physical` bytes away, and call `_start` once every relocation is
+/// redone for running `relocation` bytes from the link address
+///
+/// # Safety
+/// `pml4` must map the kernel both where it runs and at its physical
+/// address, which `relocation` must match.
+#[unsafe(naked)]
+unsafe extern "C" fn enter_identity(pml4: u64, to_physical: u64, relocation: u64) -> ! {
+    core::arch::naked_asm!(
+        "mov cr3, rdi",
+        "lea rax, [rip + 2f]",
+        "add rax, rsi",
+        "jmp rax",
+        "2:",
+        // RELATIVE entries get their addend plus the load offset
+        "lea rcx, [rip + __rela_start]",
+        "lea r8, [rip + __rela_end]",
+        "3:",
+        "cmp rcx, r8",
+        "jae 5f",
+        "cmp dword ptr [rcx + 8], {relative}",
+        "jne 4f",
+        "mov rax, [rcx + 16]",
+        "add rax, rdx",
+        "mov r9, [rcx]",
+        "mov [r9 + rdx], rax",
+        "4:",
+        "add rcx, 24",
+        "jmp 3b",
+        "5:",
+        "lea rsp, [rip + {stack} + {stack_size}]",
+        "xor ebp, ebp",
+        "xor edi, edi",
+        "xor esi, esi",
+        "call _start",
+        "ud2",
+        relative = const R_X86_64_RELATIVE,
+        stack = sym ENTRY_STACK,
+        stack_size = const ENTRY_STACK_SIZE,
+    );
+}
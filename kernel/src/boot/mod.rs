@@ -0,0 +1,254 @@
+//! Boot Protocol Handoff
+//!
+//! `_start` passes its first two argument registers to `init`, which works
+//! out which loader started the kernel: Limine, a Multiboot2 loader, the
+//! UEFI loader's boot info, or the fixed layout the BIOS loader leaves.
+//! The memory map, RSDP and command line are then read through the
+//! detected `BootProtocol` instead of from loader-specific addresses.
+
+pub mod e820;
+pub mod info;
+pub mod limine;
+pub mod multiboot2;
+
+use core::ops::Range;
+use spin::Once;
+use crate::mm::memory_map::{MemoryMapEntry, MemoryMapError};
+use crate::mm::paging;
+
+/// Maximum number of memory map entries kept after conversion
+pub const MAX_MEMORY_MAP_ENTRIES: usize = 64;
+
+/// Longest loader string copied into the kernel, longer ones are dropped
+pub const MAX_COPIED_STR: usize = 1024;
+
+/// Firmware interface the loader ran under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    /// Legacy BIOS, the BIOS data area and ROM are present
+    Bios,
+    /// UEFI, nothing below 1MB can be assumed
+    Uefi,
+}
+
+impl BootMode {
+    /// Mode for a `cosmos_bootinfo::BOOT_MODE_*` value
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            cosmos_bootinfo::BOOT_MODE_BIOS => Some(BootMode::Bios),
+            cosmos_bootinfo::BOOT_MODE_UEFI => Some(BootMode::Uefi),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BootMode::Bios => "BIOS",
+            BootMode::Uefi => "UEFI",
+        }
+    }
+}
+
+/// Information handed over by a bootloader
+pub trait BootProtocol: Sync {
+    /// Short protocol name for boot messages
+    fn name(&self) -> &'static str;
+
+    /// Memory map as E820 entries
+    fn memory_map(&self) -> Result<&'static [MemoryMapEntry], MemoryMapError>;
+
+    /// Physical address of the ACPI RSDP, if the loader knows it
+    fn rsdp(&self) -> Option<u64> {
+        None
+    }
+
+    /// Kernel command line, if the loader passed one
+    fn command_line(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Address of the UEFI runtime services table, identity mapped
+    fn uefi_runtime_services(&self) -> Option<u64> {
+        None
+    }
+
+    /// Loader version from `cosmos_bootinfo::encode_version`
+    fn loader_version(&self) -> Option<u64> {
+        None
+    }
+
+    /// `cosmos_bootinfo::LOADER_*` flags the loader set
+    fn loader_capabilities(&self) -> u64 {
+        0
+    }
+
+    /// `cosmos_bootinfo::SECURE_BOOT_*` flags, 0 if the loader did not say
+    fn secure_boot(&self) -> u64 {
+        0
+    }
+
+    /// Firmware interface the loader ran under, if it said
+    fn boot_mode(&self) -> Option<BootMode> {
+        None
+    }
+
+    /// Firmware vendor string, if the loader passed one
+    fn firmware_vendor(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// `cosmos_bootinfo::CONSOLE_*` flags for the usable output devices
+    ///
+    /// A loader that does not say is assumed to leave the PC the way BIOS
+    /// does, in VGA text mode with COM1 present.
+    fn consoles(&self) -> u64 {
+        cosmos_bootinfo::CONSOLE_VGA_TEXT | cosmos_bootinfo::CONSOLE_SERIAL
+    }
+
+    /// Physical range of the loader's page tables, if they sit in memory
+    /// the map calls usable
+    fn boot_page_tables(&self) -> Option<Range<u64>> {
+        None
+    }
+
+    /// Physical range of the entry stack, if it sits in memory the map
+    /// calls usable
+    fn boot_stack(&self) -> Option<Range<u64>> {
+        None
+    }
+}
+
+static PROTOCOL: Once<&'static dyn BootProtocol> = Once::new();
+
+/// Detect the boot protocol from the registers `_start` was entered with
+///
+/// Must run before anything reclaims loader memory. Later calls return the
+/// protocol found by the first one.
+pub fn init(arg0: u64, arg1: u64) -> &'static dyn BootProtocol {
+    *PROTOCOL.call_once(|| {
+        if let Some(protocol) = limine::detect() {
+            return protocol;
+        }
+        if let Some(protocol) = multiboot2::detect(arg0, arg1) {
+            return protocol;
+        }
+        if let Some(protocol) = info::detect(arg0) {
+            return protocol;
+        }
+        &e820::E820
+    })
+}
+
+/// The detected boot protocol, legacy E820 before `init`
+pub fn protocol() -> &'static dyn BootProtocol {
+    PROTOCOL.get().copied().unwrap_or(&e820::E820)
+}
+
+/// Whether the screen is known to be in VGA text mode
+pub fn has_vga_text() -> bool {
+    protocol().consoles() & cosmos_bootinfo::CONSOLE_VGA_TEXT != 0
+}
+
+/// Memory map translated from a foreign format into E820 entries
+pub(crate) struct ConvertedMap {
+    entries: [MemoryMapEntry; MAX_MEMORY_MAP_ENTRIES],
+    len: usize,
+}
+
+impl ConvertedMap {
+    pub(crate) const fn new() -> Self {
+        ConvertedMap {
+            entries: [MemoryMapEntry { base_addr: 0, length: 0, entry_type: 0, attributes: 0 };
+                MAX_MEMORY_MAP_ENTRIES],
+            len: 0,
+        }
+    }
+
+    /// Append an entry, returning false once the map is full
+    pub(crate) fn push(&mut self, base_addr: u64, length: u64, entry_type: u32) -> bool {
+        if self.len == MAX_MEMORY_MAP_ENTRIES {
+            return false;
+        }
+        self.entries[self.len] = MemoryMapEntry { base_addr, length, entry_type, attributes: 1 };
+        self.len += 1;
+        true
+    }
+
+    pub(crate) fn entries(&self) -> Result<&[MemoryMapEntry], MemoryMapError> {
+        if self.len == 0 {
+            return Err(MemoryMapError::NoMemoryMap);
+        }
+        Ok(&self.entries[..self.len])
+    }
+}
+
+/// Loader string copied into the kernel, for loaders whose memory is
+/// handed out or unmapped once the kernel takes over
+pub(crate) struct CopiedStr {
+    bytes: [u8; MAX_COPIED_STR],
+    len: usize,
+}
+
+impl CopiedStr {
+    pub(crate) const fn new() -> Self {
+        CopiedStr { bytes: [0; MAX_COPIED_STR], len: 0 }
+    }
+
+    /// Copy `text`, returning `None` if it does not fit
+    pub(crate) fn copy(text: &str) -> Option<Self> {
+        let mut copied = CopiedStr::new();
+        copied.bytes.get_mut(..text.len())?.copy_from_slice(text.as_bytes());
+        copied.len = text.len();
+        Some(copied)
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        // Copied from a `&str`
+        unsafe { core::str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+}
+
+/// Check that every page of a loader structure is mapped
+///
+/// Entry registers may hold leftovers from the loader, so pointers are
+/// checked before the first read.
+pub(crate) fn is_readable(addr: u64, len: u64) -> bool {
+    if addr == 0 || len == 0 {
+        return false;
+    }
+    let Some(last) = addr.checked_add(len - 1) else {
+        return false;
+    };
+    let mut page = addr & !0xFFF;
+    while page <= last {
+        if !paging::is_mapped(page) {
+            return false;
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+/// Read a NUL-terminated string left by the loader
+///
+/// # Safety
+/// `addr` must be 0 or point to loader memory that stays reserved.
+pub(crate) unsafe fn c_str(addr: u64) -> Option<&'static str> {
+    const MAX_LEN: usize = 4096;
+    if !is_readable(addr, 1) {
+        return None;
+    }
+    let mut len = 0;
+    while len < MAX_LEN {
+        // Stop at the first unmapped page rather than faulting
+        if (addr + len as u64) & 0xFFF == 0 && !paging::is_mapped(addr + len as u64) {
+            return None;
+        }
+        if *((addr + len as u64) as *const u8) == 0 {
+            break;
+        }
+        len += 1;
+    }
+    let bytes = core::slice::from_raw_parts(addr as *const u8, len);
+    core::str::from_utf8(bytes).ok()
+}
@@ -0,0 +1,164 @@
+//! Multiboot2 Handoff
+//!
+//! The header in `main.rs` asks the loader to enter at a 32-bit trampoline,
+//! which identity maps the first 4GB with 2MB pages, switches to long mode
+//! and calls `_start` with the magic from EAX in RDI and the boot
+//! information address from EBX in RSI.
+//!
+//! The information block sits wherever the loader put it, in memory the
+//! map calls usable, so everything the kernel keeps is copied out of it
+//! before the frame allocator can hand that memory out.
+
+use super::{BootMode, BootProtocol, ConvertedMap, CopiedStr};
+use crate::mm::memory_map::{MemoryMapEntry, MemoryMapError};
+
+/// Value of EAX when entered by a Multiboot2 loader
+pub const BOOTLOADER_MAGIC: u64 = 0x36d7_6289;
+/// First word of the header the loader searches for
+pub const HEADER_MAGIC: u32 = 0xE852_50D6;
+
+const TAG_END: u32 = 0;
+const TAG_COMMAND_LINE: u32 = 1;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_EFI32_SYSTEM_TABLE: u32 = 11;
+const TAG_EFI64_SYSTEM_TABLE: u32 = 12;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+/// Size of an ACPI 2.0 RSDP, the most either ACPI tag holds
+const RSDP_SIZE: usize = 36;
+
+/// Booted by a Multiboot2 loader
+pub struct Multiboot2;
+
+/// What the kernel keeps of the boot information block
+struct Copied {
+    memory_map: ConvertedMap,
+    /// Copy of the RSDP from the ACPI tags, zero-length if there was none
+    rsdp: [u8; RSDP_SIZE],
+    rsdp_len: usize,
+    command_line: Option<CopiedStr>,
+    uefi: bool,
+}
+
+static COPIED: spin::Once<Copied> = spin::Once::new();
+
+/// Check for the Multiboot2 magic and a readable information block
+pub fn detect(arg0: u64, arg1: u64) -> Option<&'static dyn BootProtocol> {
+    if arg0 != BOOTLOADER_MAGIC || !arg1.is_multiple_of(8) || !super::is_readable(arg1, 8) {
+        return None;
+    }
+    let size = unsafe { *(arg1 as *const u32) };
+    if size < 16 || !super::is_readable(arg1, size as u64) {
+        return None;
+    }
+    let info = Info { address: arg1, size };
+    COPIED.call_once(|| Copied::from_info(&info));
+    Some(&Multiboot2)
+}
+
+/// Boot information block as the loader left it
+struct Info {
+    address: u64,
+    size: u32,
+}
+
+impl Info {
+    /// Find the first tag of type `tag_type`, returning its address and size
+    fn find_tag(&self, tag_type: u32) -> Option<(u64, u32)> {
+        let end = self.address + self.size as u64;
+        // Tags start after the total size and reserved fields
+        let mut tag = self.address + 8;
+        while tag + 8 <= end {
+            let (kind, size) = unsafe { (*(tag as *const u32), *((tag + 4) as *const u32)) };
+            if kind == TAG_END || size < 8 || tag + size as u64 > end {
+                break;
+            }
+            if kind == tag_type {
+                return Some((tag, size));
+            }
+            // Tags are padded to 8 bytes
+            tag += (size as u64 + 7) & !7;
+        }
+        None
+    }
+
+    /// Memory map tag entries, which use the E820 type numbers
+    fn memory_map(&self) -> ConvertedMap {
+        let mut map = ConvertedMap::new();
+        let Some((tag, size)) = self.find_tag(TAG_MEMORY_MAP) else {
+            return map;
+        };
+        let entry_size = unsafe { *((tag + 8) as *const u32) } as u64;
+        if entry_size < 24 {
+            return map;
+        }
+        // Entries are base, length, type and a reserved word
+        let mut entry = tag + 16;
+        while entry + entry_size <= tag + size as u64 {
+            let (base, length, kind) = unsafe {
+                (
+                    core::ptr::read_unaligned(entry as *const u64),
+                    core::ptr::read_unaligned((entry + 8) as *const u64),
+                    core::ptr::read_unaligned((entry + 16) as *const u32),
+                )
+            };
+            if !map.push(base, length, kind) {
+                break;
+            }
+            entry += entry_size;
+        }
+        map
+    }
+}
+
+impl Copied {
+    fn from_info(info: &Info) -> Self {
+        let mut rsdp = [0; RSDP_SIZE];
+        let mut rsdp_len = 0;
+        // The ACPI tags hold a copy of the RSDP rather than its address
+        if let Some((tag, size)) = info.find_tag(TAG_ACPI_NEW).or_else(|| info.find_tag(TAG_ACPI_OLD)) {
+            rsdp_len = (size as usize - 8).min(RSDP_SIZE);
+            let bytes = unsafe { core::slice::from_raw_parts((tag + 8) as *const u8, rsdp_len) };
+            rsdp[..rsdp_len].copy_from_slice(bytes);
+        }
+        let command_line = info.find_tag(TAG_COMMAND_LINE)
+            .and_then(|(tag, _)| unsafe { super::c_str(tag + 8) })
+            .and_then(CopiedStr::copy);
+        // Loaders started by UEFI pass the system table, BIOS ones cannot
+        let uefi = info.find_tag(TAG_EFI64_SYSTEM_TABLE).or_else(|| info.find_tag(TAG_EFI32_SYSTEM_TABLE)).is_some();
+        Copied { memory_map: info.memory_map(), rsdp, rsdp_len, command_line, uefi }
+    }
+}
+
+impl BootProtocol for Multiboot2 {
+    fn name(&self) -> &'static str {
+        "Multiboot2"
+    }
+
+    fn memory_map(&self) -> Result<&'static [MemoryMapEntry], MemoryMapError> {
+        COPIED.get().ok_or(MemoryMapError::NoMemoryMap)?.memory_map.entries()
+    }
+
+    fn rsdp(&self) -> Option<u64> {
+        // acpi reads the copy in place, kernel statics are identity mapped
+        let copied = COPIED.get()?;
+        (copied.rsdp_len != 0).then_some(copied.rsdp.as_ptr() as u64)
+    }
+
+    fn command_line(&self) -> Option<&'static str> {
+        COPIED.get()?.command_line.as_ref().map(CopiedStr::as_str)
+    }
+
+    fn boot_mode(&self) -> Option<BootMode> {
+        Some(if COPIED.get()?.uefi { BootMode::Uefi } else { BootMode::Bios })
+    }
+
+    fn consoles(&self) -> u64 {
+        if COPIED.get().is_some_and(|copied| copied.uefi) {
+            cosmos_bootinfo::CONSOLE_SERIAL
+        } else {
+            cosmos_bootinfo::CONSOLE_VGA_TEXT | cosmos_bootinfo::CONSOLE_SERIAL
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Machine-Readable Boot Report
+//!
+//! With `bootreport` on the command line the kernel finishes boot by
+//! writing a summary to COM1 that scripts can parse instead of grepping
+//! the log: memory totals, what was mapped, the heap, the devices found
+//! and every error recorded along the way. `bootreport=json` gives one
+//! JSON object per line, `bootreport` or `bootreport=kv` gives lines of
+//! `key=value` pairs:
+//!
+//! ```text
+//! bootreport memory physical=1073741824 usable=1072168960 mapped=1073741824
+//! {"record":"memory","physical":1073741824,"usable":1072168960,"mapped":1073741824}
+//! ```
+//!
+//! Every line starts with the record type, `begin` comes first and `end`
+//! last, so a reader knows when it has the whole report.
+
+use core::fmt::{self, Write};
+use cosmos_common::fmt::FixedBuf;
+use spin::Mutex;
+
+/// Version of the report layout, bumped when records change
+pub const VERSION: u32 = 1;
+
+/// Errors kept for the report, later ones are counted but dropped
+pub const MAX_ERRORS: usize = 16;
+
+/// Longest error message kept, the rest is cut off
+const MESSAGE_LEN: usize = 80;
+
+/// Report encodings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `bootreport <record> key=value ...`
+    KeyValue,
+    /// `{"record":"<record>","key":value,...}`
+    Json,
+}
+
+impl Format {
+    /// Format asked for on the command line, `None` if the report is off
+    pub fn from_cmdline() -> Option<Self> {
+        match crate::cmdline::get("bootreport") {
+            Some("json") => Some(Format::Json),
+            Some("kv") => Some(Format::KeyValue),
+            Some(_) => None,
+            None => crate::cmdline::has_flag("bootreport").then_some(Format::KeyValue),
+        }
+    }
+}
+
+/// A failure during boot
+struct BootError {
+    stage: &'static str,
+    message: FixedBuf<MESSAGE_LEN>,
+}
+
+struct Errors {
+    errors: [Option<BootError>; MAX_ERRORS],
+    /// Errors recorded, including dropped ones
+    count: usize,
+}
+
+static ERRORS: Mutex<Errors> = Mutex::new(Errors {
+    errors: [const { None }; MAX_ERRORS],
+    count: 0,
+});
+
+/// Record that `stage` failed, for the report's error records
+///
+/// Needs no heap, so it works before the heap is up.
+pub fn error(stage: &'static str, message: fmt::Arguments) {
+    let mut text = FixedBuf::new();
+    let _ = text.write_fmt(message);
+    let mut errors = ERRORS.lock();
+    let count = errors.count;
+    if count < MAX_ERRORS {
+        errors.errors[count] = Some(BootError { stage, message: text });
+    }
+    errors.count += 1;
+}
+
+/// Text as a JSON string body, escaping quotes, backslashes and controls
+struct JsonEscaped<'a>(&'a str);
+
+impl fmt::Display for JsonEscaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One line of the report, written a field at a time
+struct Record<'a> {
+    out: &'a mut dyn Write,
+    format: Format,
+}
+
+impl<'a> Record<'a> {
+    fn begin(out: &'a mut dyn Write, format: Format, record: &str) -> Result<Self, fmt::Error> {
+        match format {
+            Format::KeyValue => write!(out, "bootreport {}", record)?,
+            Format::Json => write!(out, "{{\"record\":\"{}\"", record)?,
+        }
+        Ok(Record { out, format })
+    }
+
+    fn number(self, key: &str, value: u64) -> Result<Self, fmt::Error> {
+        match self.format {
+            Format::KeyValue => write!(self.out, " {}={}", key, value)?,
+            Format::Json => write!(self.out, ",\"{}\":{}", key, value)?,
+        }
+        Ok(self)
+    }
+
+    /// A string field, quoted in key=value form unless it is one plain word
+    fn text(self, key: &str, value: &str) -> Result<Self, fmt::Error> {
+        match self.format {
+            Format::KeyValue if value.is_empty() || value.contains(|c: char| c == ' ' || c == '"' || c.is_control()) => {
+                write!(self.out, " {}=\"{}\"", key, KvQuoted(value))?
+            }
+            Format::KeyValue => write!(self.out, " {}={}", key, value)?,
+            Format::Json => write!(self.out, ",\"{}\":\"{}\"", key, JsonEscaped(value))?,
+        }
+        Ok(self)
+    }
+
+    fn finish(self) -> fmt::Result {
+        match self.format {
+            Format::KeyValue => writeln!(self.out),
+            Format::Json => writeln!(self.out, "}}"),
+        }
+    }
+}
+
+/// Text inside key=value quotes, double quotes become single ones and
+/// control characters spaces, so a value never ends early or breaks the
+/// line
+struct KvQuoted<'a>(&'a str);
+
+impl fmt::Display for KvQuoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            f.write_char(match c {
+                '"' => '\'',
+                c if c.is_control() => ' ',
+                c => c,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the whole report in `format`
+pub fn report(out: &mut dyn Write, format: Format) -> fmt::Result {
+    let version = crate::version::VERSION;
+    let mut kernel = FixedBuf::<16>::new();
+    write!(kernel, "{}.{}.{}", version.major, version.minor, version.patch)?;
+    let protocol = crate::boot::protocol();
+    let boot_mode = protocol.boot_mode().map_or("unknown", |mode| mode.name());
+    Record::begin(out, format, "begin")?
+        .number("version", VERSION as u64)?
+        .text("kernel", kernel.as_str())?
+        .text("protocol", protocol.name())?
+        .text("boot_mode", boot_mode)?
+        .finish()?;
+
+    let (physical, usable) = crate::mm::memory_map::MemoryMap::boot_map()
+        .map_or((0, 0), |map| (map.total_physical_memory(), map.total_usable_memory()));
+    Record::begin(out, format, "memory")?
+        .number("physical", physical)?
+        .number("usable", usable)?
+        .number("mapped", crate::mm::paging::get_mapped_memory() as u64)?
+        .finish()?;
+
+    let heap = crate::mm::heap::heap_stats();
+    Record::begin(out, format, "heap")?
+        .number("total", heap.total_size as u64)?
+        .number("used", heap.used_size as u64)?
+        .number("free", heap.free_size as u64)?
+        .finish()?;
+
+    if let Some(frames) = crate::mm::frame_allocator::get_stats() {
+        Record::begin(out, format, "frames")?
+            .number("total", frames.total_frames)?
+            .number("allocated", frames.allocated_frames)?
+            .number("free", frames.free_frames)?
+            .finish()?;
+    }
+
+    #[cfg(any(feature = "pci", feature = "usb"))]
+    let mut address = FixedBuf::<16>::new();
+    #[cfg(feature = "pci")]
+    for device in crate::pci::devices() {
+        address.clear();
+        write!(address, "{}", device.address)?;
+        Record::begin(out, format, "device")?
+            .text("bus", "pci")?
+            .text("address", address.as_str())?
+            .number("vendor", device.vendor_id as u64)?
+            .number("device", device.device_id as u64)?
+            .text("class", device.class_name())?
+            .finish()?;
+    }
+    #[cfg(feature = "usb")]
+    for (controller, port, vendor, product, _, driver) in crate::usb::xhci::devices() {
+        address.clear();
+        write!(address, "{}/{}", controller, port)?;
+        Record::begin(out, format, "device")?
+            .text("bus", "usb")?
+            .text("address", address.as_str())?
+            .number("vendor", vendor as u64)?
+            .number("device", product as u64)?
+            .text("driver", driver)?
+            .finish()?;
+    }
+
+    let errors = ERRORS.lock();
+    for error in errors.errors.iter().flatten() {
+        Record::begin(out, format, "error")?
+            .text("stage", error.stage)?
+            .text("message", error.message.as_str())?
+            .finish()?;
+    }
+    let count = errors.count;
+    drop(errors);
+
+    let (stages, stage_count) = crate::bootstat::stages();
+    let boot_us = match (stages[..stage_count].first(), stages[..stage_count].last(), crate::time::tsc_hz()) {
+        (Some(first), Some(last), Some(hz)) => ((last.tsc - first.tsc) as u128 * 1_000_000 / hz as u128) as u64,
+        _ => 0,
+    };
+    Record::begin(out, format, "end")?
+        .text("status", if count == 0 { "ok" } else { "degraded" })?
+        .number("errors", count as u64)?
+        .number("boot_us", boot_us)?
+        .finish()
+}
+
+/// Write the report to COM1 if the command line asks for it
+///
+/// Goes straight to the port rather than through the console multiplexer,
+/// so it reaches COM1 whatever `console=` selected and no other sink gets
+/// a copy.
+pub fn emit() {
+    if let Some(format) = Format::from_cmdline() {
+        let _ = report(&mut crate::serial::Serial, format);
+    }
+}
+
+/// Both encodings quote and escape text the way their readers expect
+fn selftest() -> Result<(), &'static str> {
+    let mut line = FixedBuf::<128>::new();
+    Record::begin(&mut line, Format::Json, "error")
+        .and_then(|record| record.text("message", "bad \"map\"\n"))
+        .and_then(|record| record.number("code", 7))
+        .and_then(|record| record.finish())
+        .map_err(|_| "json record did not fit")?;
+    if line.as_str() != "{\"record\":\"error\",\"message\":\"bad \\\"map\\\"\\n\",\"code\":7}\n" {
+        return Err("json record malformed");
+    }
+    line.clear();
+    Record::begin(&mut line, Format::KeyValue, "error")
+        .and_then(|record| record.text("stage", "heap"))
+        .and_then(|record| record.text("message", "no \"room\""))
+        .and_then(|record| record.finish())
+        .map_err(|_| "key=value record did not fit")?;
+    if line.as_str() != "bootreport error stage=heap message=\"no 'room'\"\n" {
+        return Err("key=value record malformed");
+    }
+    Ok(())
+}
+
+crate::selftest!("bootreport", selftest);
@@ -0,0 +1,74 @@
+//! Boot Stage Timestamps
+//!
+//! `_start` marks each init stage with the TSC. The TSC counts from CPU
+//! reset, so the first mark also shows the time spent in firmware and the
+//! bootloader.
+
+use core::fmt::Write;
+use spin::Mutex;
+
+/// Maximum number of recorded stages, later marks are dropped
+pub const MAX_STAGES: usize = 32;
+
+/// A completed boot stage
+#[derive(Debug, Clone, Copy)]
+pub struct Stage {
+    pub name: &'static str,
+    /// TSC value when the stage finished
+    pub tsc: u64,
+}
+
+struct Stages {
+    stages: [Stage; MAX_STAGES],
+    count: usize,
+}
+
+static STAGES: Mutex<Stages> = Mutex::new(Stages {
+    stages: [Stage { name: "", tsc: 0 }; MAX_STAGES],
+    count: 0,
+});
+
+/// Record that the stage `name` just finished
+///
+/// Needs no heap, so it works from the first instruction of `_start`.
+pub fn mark(name: &'static str) {
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    let mut stages = STAGES.lock();
+    let count = stages.count;
+    if count < MAX_STAGES {
+        stages.stages[count] = Stage { name, tsc };
+        stages.count += 1;
+    }
+}
+
+/// Copy of the recorded stages, in boot order
+pub fn stages() -> ([Stage; MAX_STAGES], usize) {
+    let stages = STAGES.lock();
+    (stages.stages, stages.count)
+}
+
+/// Print each stage with its time since reset and its own duration
+///
+/// Times are in microseconds once the TSC has been calibrated, raw cycles
+/// before that.
+pub fn report(out: &mut dyn Write) -> core::fmt::Result {
+    let (stages, count) = stages();
+    let tsc_hz = crate::time::tsc_hz();
+    let unit = if tsc_hz.is_some() { "us" } else { "cycles" };
+    let scale = |cycles: u64| match tsc_hz {
+        Some(hz) => (cycles as u128 * 1_000_000 / hz as u128) as u64,
+        None => cycles,
+    };
+
+    writeln!(out, "STAGE                 SINCE RESET ({0})    DURATION ({0})", unit)?;
+    let mut previous = 0;
+    for stage in &stages[..count] {
+        writeln!(out, "{:<20}  {:>16}  {:>16}",
+            stage.name, scale(stage.tsc), scale(stage.tsc.saturating_sub(previous)))?;
+        previous = stage.tsc;
+    }
+    if let (Some(first), Some(last)) = (stages[..count].first(), stages[..count].last()) {
+        writeln!(out, "kernel init: {} {}", scale(last.tsc - first.tsc), unit)?;
+    }
+    Ok(())
+}
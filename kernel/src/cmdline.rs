@@ -0,0 +1,26 @@
+//! Kernel Command Line
+//!
+//! Space-separated `key=value` options and bare flags, as passed by the
+//! boot protocol. Without a command line every lookup misses.
+
+/// All options as `(key, value)`, `None` for bare flags
+pub fn options() -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+    crate::boot::protocol()
+        .command_line()
+        .unwrap_or("")
+        .split_ascii_whitespace()
+        .map(|option| match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        })
+}
+
+/// Value of the last `key=value` option
+pub fn get(key: &str) -> Option<&'static str> {
+    options().filter(|&(k, _)| k == key).filter_map(|(_, value)| value).last()
+}
+
+/// Check for a bare `flag`
+pub fn has_flag(flag: &str) -> bool {
+    options().any(|(key, value)| key == flag && value.is_none())
+}
@@ -0,0 +1,42 @@
+//! QEMU debugcon
+//!
+//! `-debugcon file:log.txt` (or `stdio`) attaches a write-only port at 0xE9
+//! that takes a whole string per `rep outsb`, where a UART takes one exit
+//! per byte. Reading the port returns 0xE9 when the device is there.
+
+use core::fmt;
+use crate::arch::x86_64::port::Port;
+
+/// Bochs and QEMU debug console port
+pub const DEBUGCON_PORT: u16 = 0xE9;
+
+/// Check for the debugcon device
+///
+/// Only probed under a hypervisor, on real hardware the port may belong to
+/// something else.
+pub fn detect() -> bool {
+    crate::qemu::is_virtualized() && unsafe { Port::<u8>::new(DEBUGCON_PORT).read() } == DEBUGCON_PORT as u8
+}
+
+/// Write bytes to the port
+pub fn write_bytes(bytes: &[u8]) {
+    unsafe {
+        core::arch::asm!(
+            "rep outsb",
+            in("dx") DEBUGCON_PORT,
+            inout("rsi") bytes.as_ptr() => _,
+            inout("rcx") bytes.len() => _,
+            options(nostack, preserves_flags, readonly)
+        );
+    }
+}
+
+/// Lock-free `fmt::Write` sink for debugcon
+pub struct Debugcon;
+
+impl fmt::Write for Debugcon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
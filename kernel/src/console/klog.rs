@@ -0,0 +1,89 @@
+//! Kernel Log Ring
+//!
+//! Every byte printed to the console is also kept here, the newest
+//! `KLOG_SIZE` bytes, for `dmesg` and for crash records written after the
+//! screen and serial output are gone.
+
+use spin::Mutex;
+
+/// Bytes of log kept
+pub const KLOG_SIZE: usize = 64 * 1024;
+
+struct LogRing<const N: usize> {
+    bytes: [u8; N],
+    /// Total bytes ever written, the next write goes to `written % N`
+    written: u64,
+}
+
+impl<const N: usize> LogRing<N> {
+    fn write(&mut self, data: &[u8]) {
+        // Only the last N bytes of an oversized write survive anyway
+        let data = &data[data.len().saturating_sub(N)..];
+        let start = (self.written % N as u64) as usize;
+        let first = data.len().min(N - start);
+        self.bytes[start..start + first].copy_from_slice(&data[..first]);
+        self.bytes[..data.len() - first].copy_from_slice(&data[first..]);
+        self.written += data.len() as u64;
+    }
+
+    /// Copy the newest bytes that fit into `out`, oldest first
+    fn tail(&self, out: &mut [u8]) -> usize {
+        let count = out.len().min(N).min(self.written as usize);
+        let end = (self.written % N as u64) as usize;
+        let start = (end + N - count) % N;
+        let first = count.min(N - start);
+        out[..first].copy_from_slice(&self.bytes[start..start + first]);
+        out[first..count].copy_from_slice(&self.bytes[..count - first]);
+        count
+    }
+}
+
+static KLOG: Mutex<LogRing<KLOG_SIZE>> = Mutex::new(LogRing { bytes: [0; KLOG_SIZE], written: 0 });
+
+/// Append to the log, dropped if the lock is held, as on a panic mid-print
+pub fn write(data: &[u8]) {
+    if let Some(mut ring) = KLOG.try_lock() {
+        ring.write(data);
+    }
+}
+
+/// Copy the newest log bytes into `out`, returns how many
+///
+/// Fails rather than spins when the lock is held, so the panic path can
+/// call it.
+pub fn tail(out: &mut [u8]) -> Option<usize> {
+    KLOG.try_lock().map(|ring| ring.tail(out))
+}
+
+/// Total bytes logged since boot
+pub fn written() -> u64 {
+    KLOG.lock().written
+}
+
+/// `fmt::Write` sink for the log ring
+pub struct Klog;
+
+impl core::fmt::Write for Klog {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Writes wrap around and the tail comes back oldest first
+fn selftest() -> Result<(), &'static str> {
+    let mut ring = LogRing { bytes: [0; 16], written: 0 };
+    ring.write(b"hello");
+    let mut out = [0u8; 8];
+    if ring.tail(&mut out) != 5 || &out[..5] != b"hello" {
+        return Err("short tail");
+    }
+    ring.written = 14;
+    ring.write(b"wrap");
+    if ring.tail(&mut out[..4]) != 4 || &out[..4] != b"wrap" || &ring.bytes[..2] != b"ap" {
+        return Err("wrapped tail");
+    }
+    Ok(())
+}
+
+crate::selftest!("klog", selftest);
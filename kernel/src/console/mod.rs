@@ -0,0 +1,226 @@
+//! Console Multiplexer
+//!
+//! Kernel log output fans out to every selected sink: COM1, QEMU's debugcon
+//! port and the virtio console. The last two reach the host far faster than
+//! the emulated UART, which costs a VM exit per byte. `console=ttyS0,hvc0`
+//! on the command line picks sinks; by default every sink present is used.
+//!
+//! Everything printed is also kept in the [`klog`] ring and mirrored to
+//! the [`crate::pstore`] region.
+//!
+//! Scripted runs also send machine-readable records, one JSON object per
+//! line, through [`Host`], which only writes to the host channels.
+
+pub mod debugcon;
+pub mod klog;
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+use crate::serial::SERIAL1;
+
+/// Output sink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    /// COM1
+    Serial,
+    /// QEMU `-debugcon`, port 0xE9
+    Debugcon,
+    /// Virtio console port 0
+    Virtio,
+}
+
+impl Sink {
+    pub const ALL: [Sink; 3] = [Sink::Serial, Sink::Debugcon, Sink::Virtio];
+
+    /// Sink name, as used by `console=`
+    pub fn name(self) -> &'static str {
+        match self {
+            Sink::Serial => "ttyS0",
+            Sink::Debugcon => "debugcon",
+            Sink::Virtio => "hvc0",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Sink::ALL.into_iter().find(|sink| sink.name() == name)
+    }
+
+    const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Errors that can occur selecting sinks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    /// No sink of that name
+    UnknownSink,
+    /// None of the sinks is present
+    NotPresent,
+}
+
+impl fmt::Display for ConsoleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsoleError::UnknownSink => write!(f, "Unknown console sink"),
+            ConsoleError::NotPresent => write!(f, "Console sink not present"),
+        }
+    }
+}
+
+/// Sinks that exist, COM1 is assumed
+static PRESENT: AtomicU8 = AtomicU8::new(Sink::Serial.bit());
+/// Sinks chosen on the command line or from the shell
+static SELECTED: AtomicU8 = AtomicU8::new(u8::MAX);
+/// Sinks held quiet by a `Quiet` guard
+static QUIET: AtomicU8 = AtomicU8::new(0);
+
+/// Detect debugcon and apply `console=`
+pub fn init() {
+    if debugcon::detect() {
+        attach(Sink::Debugcon);
+    }
+    if let Some(names) = crate::cmdline::get("console") {
+        if let Err(e) = select(names) {
+            crate::serial_println!("console={}: {}", names, e);
+        }
+    }
+}
+
+/// Mark a sink present, called by its driver
+pub fn attach(sink: Sink) {
+    PRESENT.fetch_or(sink.bit(), Ordering::AcqRel);
+}
+
+/// Select the comma-separated sinks in `names`, at least one must be present
+pub fn select(names: &str) -> Result<(), ConsoleError> {
+    let mut mask = 0;
+    for name in names.split(',') {
+        mask |= Sink::from_name(name).ok_or(ConsoleError::UnknownSink)?.bit();
+    }
+    if mask & PRESENT.load(Ordering::Acquire) == 0 {
+        return Err(ConsoleError::NotPresent);
+    }
+    SELECTED.store(mask, Ordering::Release);
+    Ok(())
+}
+
+/// Every sink as `(sink, present, selected)`
+pub fn sinks() -> [(Sink, bool, bool); 3] {
+    let present = PRESENT.load(Ordering::Acquire);
+    let selected = SELECTED.load(Ordering::Acquire);
+    Sink::ALL.map(|sink| (sink, present & sink.bit() != 0, selected & sink.bit() != 0))
+}
+
+/// Sinks output goes to
+fn active() -> u8 {
+    PRESENT.load(Ordering::Acquire) & SELECTED.load(Ordering::Acquire) & !QUIET.load(Ordering::Acquire)
+}
+
+/// Keeps log output off a sink while it carries something else
+pub struct Quiet(Sink);
+
+/// Keep log output off `sink` until the guard is dropped, for a binary
+/// transfer over it
+///
+/// Output still goes to the log ring and the other sinks. Guards for the
+/// same sink do not nest.
+pub fn quiet(sink: Sink) -> Quiet {
+    QUIET.fetch_or(sink.bit(), Ordering::AcqRel);
+    Quiet(sink)
+}
+
+impl Drop for Quiet {
+    fn drop(&mut self) {
+        QUIET.fetch_and(!self.0.bit(), Ordering::AcqRel);
+    }
+}
+
+/// Host channel for records: the virtio console, else debugcon
+pub fn host_sink() -> Option<Sink> {
+    let present = PRESENT.load(Ordering::Acquire);
+    [Sink::Virtio, Sink::Debugcon].into_iter().find(|sink| present & sink.bit() != 0)
+}
+
+fn write_sink(sink: Sink, args: fmt::Arguments) {
+    match sink {
+        Sink::Serial => {
+            let _ = SERIAL1.lock().write_fmt(args);
+        }
+        Sink::Debugcon => {
+            let _ = debugcon::Debugcon.write_fmt(args);
+        }
+        #[cfg(feature = "virtio")]
+        Sink::Virtio => crate::virtio::console::write_fmt(args),
+        // Only the virtio driver attaches it
+        #[cfg(not(feature = "virtio"))]
+        Sink::Virtio => {}
+    }
+}
+
+/// Write to every active sink, with interrupts disabled
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    interrupts::without_interrupts(|| {
+        let _ = klog::Klog.write_fmt(args);
+        let _ = crate::pstore::PstoreConsole.write_fmt(args);
+        let active = active();
+        for sink in Sink::ALL {
+            if active & sink.bit() != 0 {
+                write_sink(sink, args);
+            }
+        }
+    });
+}
+
+/// Unlocked `fmt::Write` sink for the console, locks per write
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        _print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+/// `fmt::Write` sink for the host channel, discards output without one
+pub struct Host;
+
+impl Write for Host {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(sink) = host_sink() {
+            interrupts::without_interrupts(|| write_sink(sink, format_args!("{}", s)));
+        }
+        Ok(())
+    }
+}
+
+/// Write `s` as a JSON string literal
+pub fn write_json_string(out: &mut dyn Write, s: &str) -> fmt::Result {
+    out.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.write_char(c)?,
+        }
+    }
+    out.write_char('"')
+}
+
+/// Sink names round-trip and records escape what JSON requires
+fn selftest() -> Result<(), &'static str> {
+    if Sink::ALL.into_iter().any(|sink| Sink::from_name(sink.name()) != Some(sink)) || Sink::from_name("tty0").is_some() {
+        return Err("sink names");
+    }
+    let mut out = alloc::string::String::new();
+    write_json_string(&mut out, "a\"b\\c\n").map_err(|_| "format")?;
+    if out != "\"a\\\"b\\\\c\\u000a\"" {
+        return Err("JSON escaping");
+    }
+    Ok(())
+}
+
+crate::selftest!("console", selftest);
@@ -0,0 +1,380 @@
+//! Crash Dumps
+//!
+//! With `crashdump=serial` or `crashdump=<block device>` on the command
+//! line, the panic handler writes a minidump: registers, the top of the
+//! stack, the tail of the kernel log and memory statistics. On a block
+//! device, meant to be a partition set aside for it, the dump starts at
+//! block 0; on serial it is hex encoded between marker lines so it
+//! survives a terminal:
+//!
+//! ```text
+//! -----BEGIN COSMOS CRASHDUMP-----
+//! 43534d44554d500001000000...   (32 bytes per line)
+//! -----END COSMOS CRASHDUMP-----
+//! ```
+//!
+//! Layout, every integer little-endian:
+//!
+//! ```text
+//! header   magic  "CSMDUMP\0"
+//!          u32    version, 1
+//!          u32    total length in bytes, header included
+//!          u64    uptime in milliseconds
+//!          u32    CRC-32 of everything after the header
+//!          u32    reserved, 0
+//! section  u32    tag
+//!          u32    payload length
+//!          ..     payload
+//! ```
+//!
+//! Sections follow the header until the end tag:
+//!
+//! | tag | payload                                                        |
+//! |-----|----------------------------------------------------------------|
+//! | 0   | end, empty                                                     |
+//! | 1   | panic message, UTF-8                                           |
+//! | 2   | u64 rip, rsp, rbp, rflags, cr0, cr2, cr3, cr4                   |
+//! | 3   | u64 address of the first byte, then stack bytes from `rsp` up    |
+//! | 4   | kernel log tail, oldest byte first                             |
+//! | 5   | u64 heap total, heap used, frames total, frames allocated      |
+//!
+//! Readers should skip tags they do not know.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use crate::dev::{self, BlockDevice, DevError, Device};
+
+/// Dump magic
+pub const MAGIC: [u8; 8] = *b"CSMDUMP\0";
+/// Layout version
+pub const VERSION: u32 = 1;
+/// Header bytes
+pub const HEADER_SIZE: usize = 32;
+
+/// Section tags
+pub const TAG_END: u32 = 0;
+pub const TAG_MESSAGE: u32 = 1;
+pub const TAG_REGISTERS: u32 = 2;
+pub const TAG_STACK: u32 = 3;
+pub const TAG_LOG: u32 = 4;
+pub const TAG_MEMORY: u32 = 5;
+
+/// Largest dump, the log gets whatever the other sections leave
+const DUMP_SIZE: usize = 32 * 1024;
+/// Longest panic message kept
+const MESSAGE_SIZE: usize = 512;
+/// Stack bytes kept above `rsp`
+const STACK_SIZE: usize = 4096;
+/// Hex-encoded bytes per serial line
+const SERIAL_LINE_BYTES: usize = 32;
+
+/// Where dumps go
+#[derive(Clone)]
+pub enum Target {
+    Serial,
+    Block(String, Arc<dyn BlockDevice>),
+}
+
+impl Target {
+    pub fn name(&self) -> &str {
+        match self {
+            Target::Serial => "serial",
+            Target::Block(name, _) => name,
+        }
+    }
+}
+
+/// Errors that can occur writing a dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpError {
+    /// No target configured
+    NoTarget,
+    /// A dump is already being written
+    Busy,
+    /// The named device is not a block device
+    NotBlockDevice,
+    /// The target device failed
+    Device(DevError),
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpError::NoTarget => write!(f, "No crash dump target"),
+            DumpError::Busy => write!(f, "Crash dump already in progress"),
+            DumpError::NotBlockDevice => write!(f, "Crash dump target is not a block device"),
+            DumpError::Device(e) => write!(f, "Crash dump device: {}", e),
+        }
+    }
+}
+
+static TARGET: Mutex<Option<Target>> = Mutex::new(None);
+
+/// Dump staging buffer, static so the panic path does not allocate
+static BUFFER: Mutex<[u8; DUMP_SIZE]> = Mutex::new([0; DUMP_SIZE]);
+
+/// Apply `crashdump=`, after block devices are registered
+pub fn init() {
+    if let Some(name) = crate::cmdline::get("crashdump") {
+        match set_target(name) {
+            Ok(()) => crate::serial_println!("Crash dumps go to {}", name),
+            Err(e) => crate::serial_println!("crashdump={}: {}", name, e),
+        }
+    }
+}
+
+/// Send dumps to `serial` or a block device, `off` disables them
+pub fn set_target(name: &str) -> Result<(), DumpError> {
+    let target = match name {
+        "off" => None,
+        "serial" => Some(Target::Serial),
+        _ => match dev::open(name).map_err(DumpError::Device)? {
+            Device::Block(device) => Some(Target::Block(String::from(name), device)),
+            Device::Char(_) => return Err(DumpError::NotBlockDevice),
+        },
+    };
+    *TARGET.lock() = target;
+    Ok(())
+}
+
+/// Current target
+pub fn target() -> Option<Target> {
+    TARGET.lock().clone()
+}
+
+/// Write a dump for a panic, if a target is set
+///
+/// Never blocks: a dump already in progress, as on a panic while dumping,
+/// makes this return without writing.
+pub fn on_panic(info: &core::panic::PanicInfo) {
+    let Some(target) = TARGET.try_lock() else {
+        return;
+    };
+    let Some(target) = target.as_ref() else {
+        return;
+    };
+    let result = match info.location() {
+        Some(location) => write_to(target, format_args!("{} at {}", info.message(), location)),
+        None => write_to(target, format_args!("{}", info.message())),
+    };
+    if let Err(e) = result {
+        crate::serial::write_str("Crash dump failed\n");
+        let _ = crate::serial::Serial.write_fmt(format_args!("{}\n", e));
+    }
+}
+
+/// Write a dump now, with `message` in place of a panic message
+pub fn write(message: fmt::Arguments) -> Result<usize, DumpError> {
+    let target = target().ok_or(DumpError::NoTarget)?;
+    write_to(&target, message)
+}
+
+fn write_to(target: &Target, message: fmt::Arguments) -> Result<usize, DumpError> {
+    let mut buffer = BUFFER.try_lock().ok_or(DumpError::Busy)?;
+    let length = build(&mut buffer[..], message);
+    match target {
+        Target::Serial => {
+            crate::serial::write_str("\n-----BEGIN COSMOS CRASHDUMP-----\n");
+            let mut serial = crate::serial::Serial;
+            for line in buffer[..length].chunks(SERIAL_LINE_BYTES) {
+                for byte in line {
+                    let _ = write!(serial, "{:02x}", byte);
+                }
+                crate::serial::write_str("\n");
+            }
+            crate::serial::write_str("-----END COSMOS CRASHDUMP-----\n");
+        }
+        Target::Block(_, device) => {
+            let block_size = device.block_size();
+            let blocks = length.div_ceil(block_size);
+            if blocks as u64 > device.block_count() || blocks * block_size > DUMP_SIZE {
+                return Err(DumpError::Device(DevError::InvalidArgument));
+            }
+            buffer[length..blocks * block_size].fill(0);
+            device.write_blocks(0, &buffer[..blocks * block_size]).map_err(DumpError::Device)?;
+        }
+    }
+    Ok(length)
+}
+
+/// Append-only cursor over the staging buffer
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn remaining(&self) -> usize {
+        self.buffer.len() - self.len
+    }
+
+    fn bytes(&mut self, data: &[u8]) {
+        let count = data.len().min(self.remaining());
+        self.buffer[self.len..self.len + count].copy_from_slice(&data[..count]);
+        self.len += count;
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Start a section, returns where its length goes
+    fn begin(&mut self, tag: u32) -> usize {
+        self.u32(tag);
+        let at = self.len;
+        self.u32(0);
+        at
+    }
+
+    fn end(&mut self, at: usize) {
+        let length = (self.len - at - 4) as u32;
+        self.buffer[at..at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+}
+
+/// Message bytes past `MESSAGE_SIZE` are dropped
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.remaining().min(MESSAGE_SIZE.saturating_sub(self.len - HEADER_SIZE - 8));
+        // Cut on a character boundary so the section stays valid UTF-8
+        let mut count = s.len().min(room);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.bytes(&s.as_bytes()[..count]);
+        Ok(())
+    }
+}
+
+/// Register snapshot of the caller
+#[inline(always)]
+fn registers() -> [u64; 8] {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+    let (rip, rsp, rbp): (u64, u64, u64);
+    unsafe {
+        core::arch::asm!(
+            "lea {}, [rip]",
+            "mov {}, rsp",
+            "mov {}, rbp",
+            out(reg) rip,
+            out(reg) rsp,
+            out(reg) rbp,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    [
+        rip,
+        rsp,
+        rbp,
+        x86_64::registers::rflags::read_raw(),
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read_raw().0.start_address().as_u64(),
+        Cr4::read_raw(),
+    ]
+}
+
+/// Lay out a dump in `buffer`, returns its length
+fn build(buffer: &mut [u8], message: fmt::Arguments) -> usize {
+    let registers = registers();
+    let mut out = Writer { buffer, len: HEADER_SIZE };
+
+    // The message section comes first so `write_str` can bound it
+    let at = out.begin(TAG_MESSAGE);
+    let _ = out.write_fmt(message);
+    out.end(at);
+
+    let at = out.begin(TAG_REGISTERS);
+    for value in registers {
+        out.u64(value);
+    }
+    out.end(at);
+
+    let rsp = registers[1];
+    let at = out.begin(TAG_STACK);
+    out.u64(rsp);
+    let mut address = rsp;
+    while address < rsp + STACK_SIZE as u64 && crate::mm::paging::is_mapped(address) {
+        let page_end = (address | 0xFFF) + 1;
+        let count = (page_end.min(rsp + STACK_SIZE as u64) - address) as usize;
+        out.bytes(unsafe { core::slice::from_raw_parts(address as *const u8, count) });
+        address = page_end;
+    }
+    out.end(at);
+
+    let at = out.begin(TAG_MEMORY);
+    let heap = crate::mm::heap::try_heap_stats();
+    let frames = crate::mm::frame_allocator::get_stats();
+    out.u64(heap.map_or(0, |heap| heap.total_size as u64));
+    out.u64(heap.map_or(0, |heap| heap.used_size as u64));
+    out.u64(frames.map_or(0, |frames| frames.total_frames));
+    out.u64(frames.map_or(0, |frames| frames.allocated_frames));
+    out.end(at);
+
+    // Leave room for the end tag
+    let at = out.begin(TAG_LOG);
+    let room = out.remaining().saturating_sub(8);
+    let start = out.len;
+    let count = crate::console::klog::tail(&mut out.buffer[start..start + room]).unwrap_or(0);
+    out.len += count;
+    out.end(at);
+
+    out.u32(TAG_END);
+    out.u32(0);
+
+    let length = out.len;
+    let crc = crate::crypto::crc32::checksum(&out.buffer[HEADER_SIZE..length]);
+    let header = &mut out.buffer[..HEADER_SIZE];
+    header[0..8].copy_from_slice(&MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(length as u32).to_le_bytes());
+    header[16..24].copy_from_slice(&crate::time::uptime_ms().to_le_bytes());
+    header[24..28].copy_from_slice(&crc.to_le_bytes());
+    header[28..32].fill(0);
+    length
+}
+
+/// Dumps parse back into their sections and the checksum covers them
+fn selftest() -> Result<(), &'static str> {
+    let mut buffer = alloc::vec![0u8; DUMP_SIZE];
+    let length = build(&mut buffer, format_args!("selftest {}", 42));
+    if buffer[..8] != MAGIC || u32::from_le_bytes(buffer[12..16].try_into().unwrap()) as usize != length {
+        return Err("bad header");
+    }
+    let crc = u32::from_le_bytes(buffer[24..28].try_into().unwrap());
+    if crate::crypto::crc32::checksum(&buffer[HEADER_SIZE..length]) != crc {
+        return Err("bad checksum");
+    }
+
+    let mut at = HEADER_SIZE;
+    let mut tags = 0u32;
+    loop {
+        let tag = u32::from_le_bytes(buffer[at..at + 4].try_into().unwrap());
+        let size = u32::from_le_bytes(buffer[at + 4..at + 8].try_into().unwrap()) as usize;
+        let payload = &buffer[at + 8..at + 8 + size];
+        if tag == TAG_MESSAGE && payload != b"selftest 42" {
+            return Err("bad message");
+        }
+        if tag == TAG_REGISTERS && size != 64 {
+            return Err("bad registers");
+        }
+        tags |= 1 << tag;
+        at += 8 + size;
+        if tag == TAG_END {
+            break;
+        }
+    }
+    if at != length || tags != 0b111111 {
+        return Err("bad sections");
+    }
+    Ok(())
+}
+
+crate::selftest!("crashdump", selftest);
@@ -0,0 +1,90 @@
+//! Per-boot random cookies
+//!
+//! Canaries and layout offsets only have to be unknown to an attacker,
+//! not fresh on every use. A `Cookie` is drawn from the kernel RNG the
+//! first time it is read and then stays fixed until reboot, so checking
+//! one costs a single load. The RNG uses RDRAND/RDSEED where CPUID
+//! reports them and TSC jitter otherwise.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::rng;
+
+/// Random value fixed for the rest of the boot
+///
+/// Read one after `rng::init`; before it the RNG is keyed from RDRAND and
+/// the TSC alone.
+pub struct Cookie {
+    value: AtomicU64,
+}
+
+impl Cookie {
+    pub const fn new() -> Self {
+        Cookie { value: AtomicU64::new(0) }
+    }
+
+    /// The cookie, drawn on first use, never zero
+    #[inline]
+    pub fn get(&self) -> u64 {
+        match self.value.load(Ordering::Relaxed) {
+            0 => self.draw(),
+            value => value,
+        }
+    }
+
+    #[cold]
+    fn draw(&self) -> u64 {
+        let fresh = core::iter::repeat_with(rng::next_u64).find(|&value| value != 0).unwrap_or(1);
+        // Whoever stores first wins, every reader sees the same value
+        match self.value.compare_exchange(0, fresh, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => fresh,
+            Err(current) => current,
+        }
+    }
+
+    /// Offset below `span`, a multiple of `align`, for placing a region at
+    /// a per-boot random position
+    pub fn offset(&self, span: u64, align: u64) -> u64 {
+        let slots = span / align.max(1);
+        if slots == 0 {
+            return 0;
+        }
+        self.get() % slots * align.max(1)
+    }
+}
+
+impl Default for Cookie {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Heap canary key, see `mm::heap`
+pub static HEAP_CANARY: Cookie = Cookie::new();
+
+/// Draw the kernel's own cookies now that the RNG is seeded
+pub fn init() {
+    HEAP_CANARY.get();
+}
+
+/// Cookies are stable, nonzero and independent, offsets stay in range
+fn selftest() -> Result<(), &'static str> {
+    let a = Cookie::new();
+    let b = Cookie::new();
+    let first = a.get();
+    if first == 0 || a.get() != first {
+        return Err("cookie not stable");
+    }
+    if b.get() == first {
+        return Err("cookies not independent");
+    }
+    let offset = a.offset(1 << 30, 1 << 21);
+    if offset >= 1 << 30 || !offset.is_multiple_of(1 << 21) {
+        return Err("offset out of range");
+    }
+    if a.offset(4096, 1 << 21) != 0 {
+        return Err("offset in a span smaller than the alignment");
+    }
+    Ok(())
+}
+
+crate::selftest!("cookie", selftest);
@@ -0,0 +1,29 @@
+//! CRC-16/XMODEM
+//!
+//! The checksum XMODEM and YMODEM put after each block. Polynomial 0x1021,
+//! not reflected, starting from zero.
+
+/// Polynomial, most significant bit first
+const POLYNOMIAL: u16 = 0x1021;
+
+/// Byte-at-a-time lookup table, built at compile time
+const TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ POLYNOMIAL } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-16 of `data` in one call
+pub fn checksum(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| (crc << 8) ^ TABLE[((crc >> 8) as u8 ^ byte) as usize])
+}
@@ -0,0 +1,73 @@
+//! HMAC-SHA256 (RFC 2104)
+
+use super::sha256::{self, Sha256};
+
+/// Tag size in bytes
+pub const TAG_SIZE: usize = sha256::DIGEST_SIZE;
+
+const IPAD: u8 = 0x36;
+const OPAD: u8 = 0x5c;
+
+/// Streaming HMAC-SHA256 state
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    /// Start a MAC under `key`, keys longer than a block are hashed first
+    pub fn new(key: &[u8]) -> Self {
+        let mut block = [0u8; sha256::BLOCK_SIZE];
+        if key.len() > sha256::BLOCK_SIZE {
+            block[..sha256::DIGEST_SIZE].copy_from_slice(&sha256::digest(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        let mut outer = Sha256::new();
+        let mut pad = [0u8; sha256::BLOCK_SIZE];
+        for (p, k) in pad.iter_mut().zip(block.iter()) {
+            *p = k ^ IPAD;
+        }
+        inner.update(&pad);
+        for (p, k) in pad.iter_mut().zip(block.iter()) {
+            *p = k ^ OPAD;
+        }
+        outer.update(&pad);
+
+        // Don't leave key material on the stack
+        unsafe {
+            core::ptr::write_volatile(&mut block, [0; sha256::BLOCK_SIZE]);
+            core::ptr::write_volatile(&mut pad, [0; sha256::BLOCK_SIZE]);
+        }
+
+        HmacSha256 { inner, outer }
+    }
+
+    /// Feed more message bytes
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// Produce the tag
+    pub fn finalize(self) -> [u8; TAG_SIZE] {
+        let inner = self.inner.finalize();
+        let mut outer = self.outer;
+        outer.update(&inner);
+        outer.finalize()
+    }
+
+    /// Check the message against an expected tag in constant time
+    pub fn verify(self, tag: &[u8]) -> bool {
+        super::ct_eq(&self.finalize(), tag)
+    }
+}
+
+/// MAC a complete message in one call
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; TAG_SIZE] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(data);
+    mac.finalize()
+}
@@ -0,0 +1,132 @@
+//! Kernel Cryptography
+
+pub mod chacha20;
+pub mod cookie;
+pub mod crc16;
+pub mod crc32;
+pub mod hmac;
+pub mod rng;
+pub use cosmos_common::sha256;
+
+/// Errors reported by the crypto subsystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    /// A known-answer test produced the wrong output
+    SelfTestFailed(&'static str),
+}
+
+impl core::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CryptoError::SelfTestFailed(name) => write!(f, "Self-test failed: {}", name),
+        }
+    }
+}
+
+/// Compare two byte strings in time independent of their contents
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    // Keep the compiler from turning the fold into an early-exit compare
+    core::hint::black_box(diff) == 0
+}
+
+/// Decode a hex string at compile time
+const fn hex<const N: usize>(s: &str) -> [u8; N] {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            _ => panic!("bad hex digit"),
+        }
+    }
+    let s = s.as_bytes();
+    assert!(s.len() == N * 2);
+    let mut out = [0u8; N];
+    let mut i = 0;
+    while i < N {
+        out[i] = (nibble(s[i * 2]) << 4) | nibble(s[i * 2 + 1]);
+        i += 1;
+    }
+    out
+}
+
+/// SHA-256 vectors from FIPS 180-4 examples
+const SHA256_VECTORS: [(&str, &[u8], [u8; 32]); 3] = [
+    ("sha256 empty", b"",
+        hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")),
+    ("sha256 abc", b"abc",
+        hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")),
+    ("sha256 two blocks", b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+        hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")),
+];
+
+/// Name, key, message and expected MAC of an HMAC test vector
+type HmacVector = (&'static str, &'static [u8], &'static [u8], [u8; 32]);
+
+/// HMAC-SHA256 vectors from RFC 4231 test cases 1, 2 and 6
+const HMAC_VECTORS: [HmacVector; 3] = [
+    ("hmac rfc4231 case 1", &[0x0b; 20], b"Hi There",
+        hex("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7")),
+    ("hmac rfc4231 case 2", b"Jefe", b"what do ya want for nothing?",
+        hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")),
+    ("hmac rfc4231 case 6", &[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First",
+        hex("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")),
+];
+
+/// CRC-32 check value from the Rocksoft catalogue, and the empty input
+const CRC32_VECTORS: [(&str, &[u8], u32); 2] = [
+    ("crc32 check", b"123456789", 0xCBF4_3926),
+    ("crc32 empty", b"", 0),
+];
+
+/// CRC-16/XMODEM check value from the Rocksoft catalogue, and the empty input
+const CRC16_VECTORS: [(&str, &[u8], u16); 2] = [
+    ("crc16 check", b"123456789", 0x31C3),
+    ("crc16 empty", b"", 0),
+];
+
+/// Run the SHA-256, HMAC, CRC-32 and CRC-16 known-answer tests
+pub fn self_test() -> Result<(), CryptoError> {
+    for (name, message, expected) in SHA256_VECTORS.iter() {
+        if !ct_eq(&sha256::digest(message), expected) {
+            return Err(CryptoError::SelfTestFailed(name));
+        }
+
+        // Same message fed a byte at a time exercises the buffering path
+        let mut hasher = sha256::Sha256::new();
+        for byte in message.iter() {
+            hasher.update(core::slice::from_ref(byte));
+        }
+        if !ct_eq(&hasher.finalize(), expected) {
+            return Err(CryptoError::SelfTestFailed(name));
+        }
+    }
+
+    for (name, key, message, expected) in HMAC_VECTORS.iter() {
+        if !ct_eq(&hmac::hmac_sha256(key, message), expected) {
+            return Err(CryptoError::SelfTestFailed(name));
+        }
+    }
+
+    for (name, data, expected) in CRC32_VECTORS.iter() {
+        if crc32::checksum(data) != *expected {
+            return Err(CryptoError::SelfTestFailed(name));
+        }
+    }
+
+    for (name, data, expected) in CRC16_VECTORS.iter() {
+        if crc16::checksum(data) != *expected {
+            return Err(CryptoError::SelfTestFailed(name));
+        }
+    }
+    Ok(())
+}
+
+fn selftest() -> Result<(), &'static str> {
+    self_test().map_err(|CryptoError::SelfTestFailed(name)| name)
+}
+
+crate::selftest!("crypto", selftest);
@@ -0,0 +1,266 @@
+//! Kernel random number generator
+//!
+//! An entropy pool collects RDSEED/RDRAND output, TSC jitter and interrupt
+//! timings. A ChaCha20 CSPRNG is keyed from the pool and rekeys itself after
+//! every request (fast key erasure), reseeding from the pool periodically.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use super::chacha20;
+use crate::sync::IrqMutex;
+
+/// Output bytes between reseeds from the entropy pool
+const RESEED_INTERVAL: u64 = 1024 * 1024;
+
+/// RDRAND/RDSEED attempts before giving up on a busy DRNG
+const HW_RETRIES: usize = 10;
+
+/// CPUID.1:ECX RDRAND support
+const CPUID_RDRAND: u32 = 1 << 30;
+/// CPUID.7.0:EBX RDSEED support
+const CPUID_RDSEED: u32 = 1 << 18;
+
+static HAS_RDRAND: AtomicBool = AtomicBool::new(false);
+static HAS_RDSEED: AtomicBool = AtomicBool::new(false);
+
+/// Interrupt timing entropy, folded in lock-free from interrupt handlers
+static IRQ_ENTROPY: AtomicU64 = AtomicU64::new(0);
+
+/// Entropy pool, mixed through ChaCha20 on extraction
+struct Pool {
+    key: [u32; 8],
+    /// Next word of `key` to fold input into
+    position: usize,
+    mixes: u32,
+}
+
+impl Pool {
+    fn add(&mut self, value: u64) {
+        self.key[self.position] ^= value as u32;
+        self.key[(self.position + 1) % 8] ^= (value >> 32) as u32;
+        self.position = (self.position + 2) % 8;
+    }
+
+    /// Derive a 32-byte seed, stirring the pool so the seed is not kept
+    fn extract(&mut self) -> [u32; 8] {
+        self.mixes = self.mixes.wrapping_add(1);
+        let block = chacha20::block(&self.key, self.mixes, &[0; 3]);
+        let mut seed = [0u8; 32];
+        let mut next = [0u8; 32];
+        seed.copy_from_slice(&block[..32]);
+        next.copy_from_slice(&block[32..]);
+        self.key = chacha20::key_from_bytes(&next);
+        chacha20::key_from_bytes(&seed)
+    }
+}
+
+/// ChaCha20-based CSPRNG
+struct ChaChaRng {
+    key: [u32; 8],
+    nonce: [u32; 3],
+    output_since_reseed: u64,
+}
+
+impl ChaChaRng {
+    fn fill(&mut self, dest: &mut [u8]) {
+        let mut counter = 0u32;
+        // First block's leading half becomes the next key
+        let first = chacha20::block(&self.key, counter, &self.nonce);
+        let mut next_key = [0u8; 32];
+        next_key.copy_from_slice(&first[..32]);
+
+        let mut written = 0;
+        let take = dest.len().min(32);
+        dest[..take].copy_from_slice(&first[32..32 + take]);
+        written += take;
+
+        while written < dest.len() {
+            counter = counter.wrapping_add(1);
+            let block = chacha20::block(&self.key, counter, &self.nonce);
+            let take = (dest.len() - written).min(block.len());
+            dest[written..written + take].copy_from_slice(&block[..take]);
+            written += take;
+        }
+
+        self.key = chacha20::key_from_bytes(&next_key);
+        self.output_since_reseed += dest.len() as u64;
+    }
+
+    fn reseed(&mut self, seed: [u32; 8]) {
+        for (word, fresh) in self.key.iter_mut().zip(seed) {
+            *word ^= fresh;
+        }
+        self.nonce[0] = self.nonce[0].wrapping_add(1);
+        self.output_since_reseed = 0;
+    }
+}
+
+struct Rng {
+    pool: Pool,
+    csprng: ChaChaRng,
+}
+
+static RNG: IrqMutex<Option<Rng>> = IrqMutex::named("rng", None);
+
+/// Detect hardware sources, gather initial entropy and key the CSPRNG
+pub fn init() {
+    let leaf1 = unsafe { core::arch::x86_64::__cpuid(1) };
+    let leaf7 = unsafe { core::arch::x86_64::__cpuid_count(7, 0) };
+    HAS_RDRAND.store(leaf1.ecx & CPUID_RDRAND != 0, Ordering::Relaxed);
+    HAS_RDSEED.store(leaf7.ebx & CPUID_RDSEED != 0, Ordering::Relaxed);
+
+    let mut pool = Pool { key: [0; 8], position: 0, mixes: 0 };
+    for _ in 0..8 {
+        if let Some(value) = hw_seed() {
+            pool.add(value);
+        }
+    }
+    for _ in 0..64 {
+        pool.add(tsc_jitter());
+    }
+    pool.add(IRQ_ENTROPY.swap(0, Ordering::Relaxed));
+
+    let key = pool.extract();
+    let csprng = ChaChaRng { key, nonce: [0; 3], output_since_reseed: 0 };
+    *RNG.lock() = Some(Rng { pool, csprng });
+
+    crate::serial_println!(
+        "RNG seeded (rdrand: {}, rdseed: {})",
+        HAS_RDRAND.load(Ordering::Relaxed),
+        HAS_RDSEED.load(Ordering::Relaxed)
+    );
+}
+
+/// Check if the CSPRNG has been seeded
+pub fn is_seeded() -> bool {
+    RNG.lock().is_some()
+}
+
+/// Mix caller-provided data into the entropy pool
+pub fn add_entropy(data: &[u8]) {
+    let mut rng = RNG.lock();
+    let Some(rng) = rng.as_mut() else {
+        return;
+    };
+    for chunk in data.chunks(8) {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        rng.pool.add(u64::from_le_bytes(word));
+    }
+}
+
+/// Fold an interrupt's vector and arrival time into the pool, lock-free
+#[inline]
+pub fn add_interrupt_entropy(vector: u8, tsc: u64) {
+    let sample = tsc ^ ((vector as u64) << 56);
+    let _ = IRQ_ENTROPY.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pool| {
+        Some(pool.rotate_left(7) ^ sample)
+    });
+}
+
+/// Reseed the CSPRNG from fresh pool entropy
+pub fn reseed() {
+    let mut rng = RNG.lock();
+    if let Some(rng) = rng.as_mut() {
+        reseed_locked(rng);
+    }
+}
+
+fn reseed_locked(rng: &mut Rng) {
+    if let Some(value) = hw_seed() {
+        rng.pool.add(value);
+    }
+    rng.pool.add(tsc_jitter());
+    rng.pool.add(IRQ_ENTROPY.swap(0, Ordering::Relaxed));
+    let seed = rng.pool.extract();
+    rng.csprng.reseed(seed);
+}
+
+/// Fill `dest` with cryptographically secure random bytes
+///
+/// Before `init` the output is keyed from RDRAND and the TSC only.
+pub fn fill_bytes(dest: &mut [u8]) {
+    let mut guard = RNG.lock();
+    let rng = guard.get_or_insert_with(|| {
+        let mut pool = Pool { key: [0; 8], position: 0, mixes: 0 };
+        pool.add(hw_random().unwrap_or(0));
+        pool.add(tsc_jitter());
+        let key = pool.extract();
+        Rng { pool, csprng: ChaChaRng { key, nonce: [0; 3], output_since_reseed: 0 } }
+    });
+    if rng.csprng.output_since_reseed >= RESEED_INTERVAL {
+        reseed_locked(rng);
+    }
+    rng.csprng.fill(dest);
+}
+
+/// Random u64
+pub fn next_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Random u32
+pub fn next_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    fill_bytes(&mut bytes);
+    u32::from_le_bytes(bytes)
+}
+
+/// Whether RDRAND is available
+pub fn has_rdrand() -> bool {
+    HAS_RDRAND.load(Ordering::Relaxed)
+}
+
+/// Whether RDSEED is available
+pub fn has_rdseed() -> bool {
+    HAS_RDSEED.load(Ordering::Relaxed)
+}
+
+/// Best hardware seed: RDSEED, falling back to RDRAND
+fn hw_seed() -> Option<u64> {
+    if HAS_RDSEED.load(Ordering::Relaxed) {
+        for _ in 0..HW_RETRIES {
+            let (value, ok): (u64, u8);
+            unsafe {
+                core::arch::asm!("rdseed {0}", "setc {1}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+            }
+            if ok != 0 {
+                return Some(value);
+            }
+            core::hint::spin_loop();
+        }
+    }
+    hw_random()
+}
+
+fn hw_random() -> Option<u64> {
+    if !HAS_RDRAND.load(Ordering::Relaxed) {
+        return None;
+    }
+    for _ in 0..HW_RETRIES {
+        let (value, ok): (u64, u8);
+        unsafe {
+            core::arch::asm!("rdrand {0}", "setc {1}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Timing noise of a short busy loop, measured with the TSC
+fn tsc_jitter() -> u64 {
+    let mut sample = 0u64;
+    for _ in 0..8 {
+        let start = unsafe { core::arch::x86_64::_rdtsc() };
+        let mut x = start;
+        for _ in 0..16 {
+            x = core::hint::black_box(x.rotate_left(5) ^ 0x9E37_79B9_7F4A_7C15);
+        }
+        let delta = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(start);
+        sample = sample.rotate_left(8) ^ delta ^ x;
+    }
+    sample
+}
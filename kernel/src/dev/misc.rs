@@ -0,0 +1,202 @@
+//! Built-in Character Devices
+//!
+//! `null`, `zero`, `random`, `urandom`, `kmsg` and `meminfo` in the memory major, and
+//! the terminals in the TTY major.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use spin::Mutex;
+use super::{register_char, CharDevice, DevError, DeviceId, MEM_MAJOR, TTY_MAJOR};
+use crate::tty::{self, Tty, TtyError};
+
+/// Discards writes, reads end of file
+struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, DevError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError> {
+        Ok(buf.len())
+    }
+}
+
+/// Discards writes, reads zeros
+struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, DevError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError> {
+        Ok(buf.len())
+    }
+}
+
+/// Reads the kernel CSPRNG, writes are mixed in as entropy
+struct Random;
+
+impl CharDevice for Random {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, DevError> {
+        crate::crypto::rng::fill_bytes(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError> {
+        crate::crypto::rng::add_entropy(buf);
+        Ok(buf.len())
+    }
+}
+
+/// Writes go to the kernel log on the serial console
+///
+/// Reads return end of file, there is no log buffer to read back yet.
+struct Kmsg;
+
+impl CharDevice for Kmsg {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, DevError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError> {
+        crate::serial_print!("{}", core::str::from_utf8(buf).map_err(|_| DevError::InvalidArgument)?);
+        Ok(buf.len())
+    }
+}
+
+/// Reads the allocator statistics report
+///
+/// The report is rendered on the first read and served from there until
+/// end of file, so a reader sees one consistent snapshot.
+struct Meminfo {
+    /// Rendered report and read offset into it
+    snapshot: Mutex<Option<(String, usize)>>,
+}
+
+impl CharDevice for Meminfo {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, DevError> {
+        let mut snapshot = self.snapshot.lock();
+        let (report, offset) = snapshot.get_or_insert_with(|| {
+            let mut report = String::new();
+            let _ = crate::stats::memory().write_report(&mut report);
+            (report, 0)
+        });
+        let remaining = &report.as_bytes()[*offset..];
+        if remaining.is_empty() {
+            *snapshot = None;
+            return Ok(0);
+        }
+        let count = remaining.len().min(buf.len());
+        buf[..count].copy_from_slice(&remaining[..count]);
+        *offset += count;
+        Ok(count)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, DevError> {
+        Err(DevError::NotSupported)
+    }
+}
+
+fn read_tty(tty: &Tty, buf: &mut [u8]) -> Result<usize, DevError> {
+    tty.read(buf).map_err(|e| match e {
+        TtyError::Interrupted => DevError::Interrupted,
+        TtyError::WouldBlock => DevError::Io,
+        TtyError::TimedOut => DevError::TimedOut,
+        TtyError::Cancelled => DevError::Cancelled,
+    })
+}
+
+/// Terminal device file
+struct TtyDevice(&'static Tty);
+
+impl CharDevice for TtyDevice {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, DevError> {
+        read_tty(self.0, buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/tty0`, whichever virtual console is on the screen
+struct ActiveTty;
+
+impl CharDevice for ActiveTty {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, DevError> {
+        read_tty(tty::active(), buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError> {
+        tty::active().write(buf);
+        Ok(buf.len())
+    }
+}
+
+pub(super) fn init() {
+    let devices: [(&str, DeviceId, Arc<dyn CharDevice>); 12] = [
+        ("null", DeviceId::new(MEM_MAJOR, 3), Arc::new(Null)),
+        ("zero", DeviceId::new(MEM_MAJOR, 5), Arc::new(Zero)),
+        ("random", DeviceId::new(MEM_MAJOR, 8), Arc::new(Random)),
+        ("urandom", DeviceId::new(MEM_MAJOR, 9), Arc::new(Random)),
+        ("kmsg", DeviceId::new(MEM_MAJOR, 11), Arc::new(Kmsg)),
+        ("meminfo", DeviceId::new(MEM_MAJOR, 12), Arc::new(Meminfo { snapshot: Mutex::new(None) })),
+        ("tty0", DeviceId::new(TTY_MAJOR, 0), Arc::new(ActiveTty)),
+        ("tty1", DeviceId::new(TTY_MAJOR, 1), Arc::new(TtyDevice(&tty::VTS[0]))),
+        ("tty2", DeviceId::new(TTY_MAJOR, 2), Arc::new(TtyDevice(&tty::VTS[1]))),
+        ("tty3", DeviceId::new(TTY_MAJOR, 3), Arc::new(TtyDevice(&tty::VTS[2]))),
+        ("tty4", DeviceId::new(TTY_MAJOR, 4), Arc::new(TtyDevice(&tty::VTS[3]))),
+        ("ttyS0", DeviceId::new(TTY_MAJOR, 64), Arc::new(TtyDevice(&tty::TTY_S0))),
+    ];
+    for (name, id, device) in devices {
+        if let Err(e) = register_char(name, id, device) {
+            crate::serial_println!("/dev/{}: {}", name, e);
+        }
+    }
+}
+
+/// Built-in devices resolve by path and behave as their names say
+fn selftest() -> Result<(), &'static str> {
+    use super::Device;
+
+    let Ok(Device::Char(zero)) = super::open("/dev/zero") else {
+        return Err("/dev/zero missing");
+    };
+    let mut buf = [0xFFu8; 32];
+    if zero.read(&mut buf) != Ok(32) || buf.iter().any(|&byte| byte != 0) {
+        return Err("/dev/zero returned non-zero bytes");
+    }
+    let Ok(Device::Char(null)) = super::open("null") else {
+        return Err("/dev/null missing");
+    };
+    if null.read(&mut buf) != Ok(0) || null.write(&buf) != Ok(32) {
+        return Err("/dev/null misbehaves");
+    }
+    if super::open_id(DeviceId::new(TTY_MAJOR, 64)).is_err() {
+        return Err("ttyS0 not found by number");
+    }
+    let Ok(Device::Char(meminfo)) = super::open("meminfo") else {
+        return Err("/dev/meminfo missing");
+    };
+    let mut report = alloc::vec::Vec::new();
+    loop {
+        match meminfo.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => report.extend_from_slice(&buf[..count]),
+            Err(_) => return Err("/dev/meminfo read failed"),
+        }
+    }
+    if !report.starts_with(b"Heap:") || !report.ends_with(b"\n") {
+        return Err("/dev/meminfo report");
+    }
+    if super::register_char("zero", DeviceId::new(MEM_MAJOR, 200), Arc::new(Zero)) != Err(DevError::AlreadyExists) {
+        return Err("duplicate name accepted");
+    }
+    Ok(())
+}
+
+crate::selftest!("dev", selftest);
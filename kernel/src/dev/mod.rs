@@ -0,0 +1,195 @@
+//! Device Files
+//!
+//! Drivers register character and block devices here under a name and a
+//! major/minor number, and consumers open them by `/dev/<name>` path. The
+//! registry stands in for a devfs mounted at `/dev` and keeps the same
+//! paths once a VFS exists.
+
+pub mod misc;
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::sync::WaitError;
+
+/// Path prefix of device files
+pub const DEV_PREFIX: &str = "/dev/";
+
+// Major numbers, as on Linux
+pub const MEM_MAJOR: u16 = 1;
+pub const IDE_MAJOR: u16 = 3;
+pub const TTY_MAJOR: u16 = 4;
+pub const NVME_MAJOR: u16 = 259;
+
+/// Device number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl DeviceId {
+    pub const fn new(major: u16, minor: u16) -> Self {
+        DeviceId { major, minor }
+    }
+}
+
+impl core::fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}", self.major, self.minor)
+    }
+}
+
+/// Errors that can occur on device files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DevError {
+    /// No device with that name
+    NotFound,
+    /// Name or device number already registered
+    AlreadyExists,
+    /// Device does not support the operation
+    NotSupported,
+    /// Offset or buffer not valid for the device
+    InvalidArgument,
+    /// Read interrupted before any data arrived
+    Interrupted,
+    /// Hardware reported an error
+    Io,
+    /// The device did not answer before the deadline
+    TimedOut,
+    /// The caller's token was cancelled
+    Cancelled,
+}
+
+impl core::fmt::Display for DevError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DevError::NotFound => write!(f, "No such device"),
+            DevError::AlreadyExists => write!(f, "Device already registered"),
+            DevError::NotSupported => write!(f, "Operation not supported by device"),
+            DevError::InvalidArgument => write!(f, "Invalid device argument"),
+            DevError::Interrupted => write!(f, "Device read interrupted"),
+            DevError::Io => write!(f, "Device I/O error"),
+            DevError::TimedOut => write!(f, "Device timed out"),
+            DevError::Cancelled => write!(f, "Device operation cancelled"),
+        }
+    }
+}
+
+impl From<WaitError> for DevError {
+    fn from(e: WaitError) -> Self {
+        match e {
+            WaitError::TimedOut => DevError::TimedOut,
+            WaitError::Cancelled => DevError::Cancelled,
+        }
+    }
+}
+
+/// Byte stream device such as a terminal or `/dev/null`
+pub trait CharDevice: Send + Sync {
+    /// Read up to `buf.len()` bytes, 0 at end of file
+    fn read(&self, buf: &mut [u8]) -> Result<usize, DevError>;
+    /// Write bytes, returning how many were accepted
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError>;
+}
+
+/// Random-access device addressed in fixed-size blocks
+pub trait BlockDevice: Send + Sync {
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> u64;
+    /// Read whole blocks starting at `lba`, `buf` is a multiple of the block size
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), DevError>;
+    /// Write whole blocks starting at `lba`, `buf` is a multiple of the block size
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), DevError>;
+}
+
+/// A registered device
+#[derive(Clone)]
+pub enum Device {
+    Char(Arc<dyn CharDevice>),
+    Block(Arc<dyn BlockDevice>),
+}
+
+impl Device {
+    /// `c` or `b`, as in the mode column of `ls -l`
+    pub fn kind(&self) -> char {
+        match self {
+            Device::Char(_) => 'c',
+            Device::Block(_) => 'b',
+        }
+    }
+}
+
+struct Node {
+    name: String,
+    id: DeviceId,
+    device: Device,
+}
+
+/// Registered devices, few enough for a linear search
+static DEVICES: Mutex<Vec<Node>> = Mutex::new(Vec::new());
+
+fn register(name: &str, id: DeviceId, device: Device) -> Result<(), DevError> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|node| node.name == name || node.id == id) {
+        return Err(DevError::AlreadyExists);
+    }
+    devices.push(Node { name: name.to_string(), id, device });
+    Ok(())
+}
+
+/// Register a character device as `/dev/<name>`
+pub fn register_char(name: &str, id: DeviceId, device: Arc<dyn CharDevice>) -> Result<(), DevError> {
+    register(name, id, Device::Char(device))
+}
+
+/// Register a block device as `/dev/<name>`
+pub fn register_block(name: &str, id: DeviceId, device: Arc<dyn BlockDevice>) -> Result<(), DevError> {
+    register(name, id, Device::Block(device))
+}
+
+/// Remove a device, open handles keep it alive until dropped
+pub fn unregister(name: &str) -> Result<(), DevError> {
+    let mut devices = DEVICES.lock();
+    let index = devices.iter().position(|node| node.name == name).ok_or(DevError::NotFound)?;
+    devices.remove(index);
+    Ok(())
+}
+
+/// Open a device by `/dev/<name>` path or bare name
+pub fn open(path: &str) -> Result<Device, DevError> {
+    let name = path.strip_prefix(DEV_PREFIX).unwrap_or(path);
+    DEVICES
+        .lock()
+        .iter()
+        .find(|node| node.name == name)
+        .map(|node| node.device.clone())
+        .ok_or(DevError::NotFound)
+}
+
+/// Open a device by number
+pub fn open_id(id: DeviceId) -> Result<Device, DevError> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|node| node.id == id)
+        .map(|node| node.device.clone())
+        .ok_or(DevError::NotFound)
+}
+
+/// Snapshot of all devices as (name, number, `c` or `b`), sorted by name
+pub fn list() -> Vec<(String, DeviceId, char)> {
+    let mut all: Vec<_> = DEVICES
+        .lock()
+        .iter()
+        .map(|node| (node.name.clone(), node.id, node.device.kind()))
+        .collect();
+    all.sort();
+    all
+}
+
+/// Register the devices the kernel always provides
+pub fn init() {
+    misc::init();
+}
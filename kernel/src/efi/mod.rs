@@ -0,0 +1,71 @@
+//! UEFI Firmware Interfaces
+//!
+//! Only runtime services outlive ExitBootServices. The UEFI loader maps
+//! them 1:1 with SetVirtualAddressMap and passes the table through the
+//! boot protocol, so calls work on the identity map.
+
+pub mod runtime;
+pub mod variables;
+
+/// UEFI status code
+pub type Status = usize;
+
+/// High bit set on every error status
+const ERROR_BIT: Status = 1 << 63;
+
+/// `EFI_GUID`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+/// Errors returned by firmware calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EfiError {
+    /// Not booted through UEFI, or runtime services were not mapped
+    NotAvailable,
+    /// Firmware does not implement the call at runtime
+    Unsupported,
+    /// Hardware error reported by the firmware
+    DeviceError,
+    /// Variable does not exist
+    NotFound,
+    /// Value is larger than the buffer
+    BufferTooSmall,
+    /// Another firmware call is in progress
+    Busy,
+    /// Any other error status, with the error bit cleared
+    Status(usize),
+}
+
+impl core::fmt::Display for EfiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            EfiError::NotAvailable => write!(f, "UEFI runtime services not available"),
+            EfiError::Unsupported => write!(f, "Unsupported by firmware"),
+            EfiError::DeviceError => write!(f, "Firmware device error"),
+            EfiError::NotFound => write!(f, "UEFI variable not found"),
+            EfiError::BufferTooSmall => write!(f, "UEFI variable too large"),
+            EfiError::Busy => write!(f, "Firmware busy"),
+            EfiError::Status(code) => write!(f, "UEFI error {}", code),
+        }
+    }
+}
+
+/// Convert a firmware status, treating warnings as success
+pub(crate) fn check(status: Status) -> Result<(), EfiError> {
+    if status & ERROR_BIT == 0 {
+        return Ok(());
+    }
+    match status & !ERROR_BIT {
+        3 => Err(EfiError::Unsupported),
+        5 => Err(EfiError::BufferTooSmall),
+        7 => Err(EfiError::DeviceError),
+        14 => Err(EfiError::NotFound),
+        code => Err(EfiError::Status(code)),
+    }
+}
@@ -0,0 +1,203 @@
+//! UEFI Runtime Services
+//!
+//! Firmware calls are not reentrant, so every call holds `LOCK` with
+//! interrupts disabled.
+
+use core::ffi::c_void;
+use spin::{Mutex, Once};
+use super::{check, EfiError, Guid, Status};
+use crate::time::DateTime;
+
+/// `EFI_RUNTIME_SERVICES` table signature, "RUNTSERV"
+const SIGNATURE: u64 = 0x5652_4553_544E_5552;
+
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+/// `EFI_TIME`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Time {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    pad1: u8,
+    nanosecond: u32,
+    time_zone: i16,
+    daylight: u8,
+    pad2: u8,
+}
+
+#[repr(C)]
+struct RuntimeServices {
+    hdr: TableHeader,
+    get_time: extern "efiapi" fn(time: *mut Time, capabilities: *mut c_void) -> Status,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: usize,
+    convert_pointer: usize,
+    get_variable: extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: *mut u32,
+        data_size: *mut usize,
+        data: *mut u8,
+    ) -> Status,
+    get_next_variable_name: usize,
+    set_variable: extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: u32,
+        data_size: usize,
+        data: *const u8,
+    ) -> Status,
+    get_next_high_monotonic_count: usize,
+    reset_system: extern "efiapi" fn(
+        reset_type: u32,
+        status: Status,
+        data_size: usize,
+        data: *const c_void,
+    ),
+}
+
+/// Kind of reset requested from `ResetSystem`
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetType {
+    Cold = 0,
+    Warm = 1,
+    Shutdown = 2,
+}
+
+static RUNTIME: Once<&'static RuntimeServices> = Once::new();
+
+/// Serializes firmware calls
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Pick up the runtime services table from the boot protocol
+///
+/// Returns false when the kernel was not started through UEFI or the
+/// table is not reachable through the current page tables.
+pub fn init() -> bool {
+    let Some(address) = crate::boot::protocol().uefi_runtime_services() else {
+        return false;
+    };
+    let size = core::mem::size_of::<RuntimeServices>() as u64;
+    if !crate::boot::is_readable(address, size) {
+        return false;
+    }
+    let table = unsafe { &*(address as *const RuntimeServices) };
+    if table.hdr.signature != SIGNATURE {
+        return false;
+    }
+    // Runtime code is only usable if the firmware regions are mapped too
+    let entries = [
+        table.get_time as usize,
+        table.get_variable as usize,
+        table.set_variable as usize,
+        table.reset_system as usize,
+    ];
+    if entries.iter().any(|&entry| !crate::mm::paging::is_mapped(entry as u64)) {
+        return false;
+    }
+    RUNTIME.call_once(|| table);
+    let revision = table.hdr.revision;
+    crate::serial_println!("UEFI runtime services {}.{}", revision >> 16, revision & 0xFFFF);
+    true
+}
+
+/// Check if runtime services can be called
+pub fn is_available() -> bool {
+    RUNTIME.get().is_some()
+}
+
+/// Run a firmware call with the lock held and interrupts disabled
+fn call<R>(f: impl FnOnce(&RuntimeServices) -> R) -> Result<R, EfiError> {
+    let table = RUNTIME.get().ok_or(EfiError::NotAvailable)?;
+    Ok(x86_64::instructions::interrupts::without_interrupts(|| {
+        let _guard = LOCK.lock();
+        f(table)
+    }))
+}
+
+/// Like `call`, but fails instead of waiting for another caller
+///
+/// For the panic path, where the lock holder may be the panicking code.
+fn try_call<R>(f: impl FnOnce(&RuntimeServices) -> R) -> Result<R, EfiError> {
+    let table = RUNTIME.get().ok_or(EfiError::NotAvailable)?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _guard = LOCK.try_lock().ok_or(EfiError::Busy)?;
+        Ok(f(table))
+    })
+}
+
+/// Read a variable into `data`, returning its size and attributes
+///
+/// `name` must be NUL-terminated UTF-16.
+pub(super) fn get_variable(
+    name: &[u16],
+    vendor: &Guid,
+    data: &mut [u8],
+) -> Result<(usize, u32), EfiError> {
+    let mut attributes = 0;
+    let mut size = data.len();
+    check(call(|rt| {
+        (rt.get_variable)(name.as_ptr(), vendor, &mut attributes, &mut size, data.as_mut_ptr())
+    })?)?;
+    Ok((size, attributes))
+}
+
+/// Write a variable, an empty `data` deletes it
+///
+/// `name` must be NUL-terminated UTF-16. With `wait` false the call fails
+/// with `Busy` rather than block on another firmware call.
+pub(super) fn set_variable(
+    name: &[u16],
+    vendor: &Guid,
+    attributes: u32,
+    data: &[u8],
+    wait: bool,
+) -> Result<(), EfiError> {
+    let set = |rt: &RuntimeServices| {
+        (rt.set_variable)(name.as_ptr(), vendor, attributes, data.len(), data.as_ptr())
+    };
+    check(if wait { call(set)? } else { try_call(set)? })
+}
+
+/// Read the firmware clock
+///
+/// The time zone field is ignored, the clock is assumed to run in UTC like
+/// the CMOS RTC.
+pub fn get_time() -> Result<DateTime, EfiError> {
+    let mut time = Time::default();
+    check(call(|rt| (rt.get_time)(&mut time, core::ptr::null_mut()))?)?;
+    Ok(DateTime {
+        year: time.year,
+        month: time.month,
+        day: time.day,
+        hour: time.hour,
+        minute: time.minute,
+        second: time.second,
+    })
+}
+
+/// Reset or power off through the firmware
+///
+/// Only returns if runtime services are unavailable or the firmware
+/// ignored the request.
+pub fn reset_system(reset_type: ResetType) -> EfiError {
+    match call(|rt| (rt.reset_system)(reset_type as u32, 0, 0, core::ptr::null())) {
+        Ok(()) => EfiError::DeviceError,
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,226 @@
+//! CosmOS UEFI Variables
+//!
+//! A few `CosmOS-*` variables under the CosmOS vendor GUID persist boot
+//! flags across reboots without a filesystem. The boot status and crash
+//! marker together detect crash loops: a boot that never reached `ready`,
+//! or that panicked, counts as failed.
+
+use log::LevelFilter;
+use spin::Once;
+use super::{runtime, EfiError, Guid};
+
+/// Vendor GUID of every CosmOS variable
+pub const VENDOR_GUID: Guid = Guid {
+    data1: 0xc053_05c0,
+    data2: 0x5e0f,
+    data3: 0x4c6b,
+    data4: [0x9d, 0x43, 0x6f, 0x73, 0x6d, 0x4f, 0x53, 0x01],
+};
+
+/// Non-volatile, visible to boot and runtime services
+const ATTRIBUTES: u32 = 0x1 | 0x2 | 0x4;
+
+/// Consecutive failed boots that count as a crash loop
+pub const CRASH_LOOP_THRESHOLD: u8 = 3;
+
+/// Longest variable value read or written through this module
+pub const MAX_VALUE_SIZE: usize = 64;
+
+/// Variables the kernel is allowed to touch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    /// Default log level, one byte
+    LogLevel,
+    /// `BootState` and consecutive failed boots, one byte each
+    BootStatus,
+    /// Unix time of the last panic, eight bytes
+    CrashMarker,
+}
+
+impl Variable {
+    /// Parse a variable name, with or without the `CosmOS-` prefix
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.strip_prefix("CosmOS-").unwrap_or(name) {
+            "LogLevel" => Some(Variable::LogLevel),
+            "BootStatus" => Some(Variable::BootStatus),
+            "CrashMarker" => Some(Variable::CrashMarker),
+            _ => None,
+        }
+    }
+
+    /// Full UEFI variable name
+    pub fn name(self) -> &'static str {
+        match self {
+            Variable::LogLevel => "CosmOS-LogLevel",
+            Variable::BootStatus => "CosmOS-BootStatus",
+            Variable::CrashMarker => "CosmOS-CrashMarker",
+        }
+    }
+
+    /// Name as NUL-terminated UTF-16
+    fn encode(self, buf: &mut [u16; 32]) -> &[u16] {
+        let name = self.name().as_bytes();
+        for (i, &b) in name.iter().enumerate() {
+            buf[i] = b as u16;
+        }
+        buf[name.len()] = 0;
+        &buf[..=name.len()]
+    }
+}
+
+/// How the previous boot ended
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootState {
+    /// No record, first boot or no UEFI
+    Unknown = 0,
+    /// Started but never reached `ready`
+    Booting = 1,
+    /// Reached `ready`
+    Complete = 2,
+}
+
+impl BootState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => BootState::Booting,
+            2 => BootState::Complete,
+            _ => BootState::Unknown,
+        }
+    }
+
+    /// State name
+    pub fn name(self) -> &'static str {
+        match self {
+            BootState::Unknown => "unknown",
+            BootState::Booting => "booting",
+            BootState::Complete => "complete",
+        }
+    }
+}
+
+/// Outcome of the previous boot, read by `begin_boot`
+#[derive(Debug, Clone, Copy)]
+pub struct BootRecord {
+    pub previous: BootState,
+    /// Unix time of a panic during the previous boot
+    pub crash_time: Option<u64>,
+    /// Failed boots in a row, including the previous one
+    pub failed_boots: u8,
+}
+
+impl BootRecord {
+    /// Check if enough boots failed in a row to call it a crash loop
+    pub fn is_crash_loop(&self) -> bool {
+        self.failed_boots >= CRASH_LOOP_THRESHOLD
+    }
+}
+
+static RECORD: Once<BootRecord> = Once::new();
+
+/// Read a variable into `buf`, returning the value length
+pub fn read(variable: Variable, buf: &mut [u8]) -> Result<usize, EfiError> {
+    let mut name = [0; 32];
+    let (size, _) = runtime::get_variable(variable.encode(&mut name), &VENDOR_GUID, buf)?;
+    Ok(size)
+}
+
+/// Write a variable, an empty value deletes it as in UEFI
+pub fn write(variable: Variable, data: &[u8]) -> Result<(), EfiError> {
+    if data.is_empty() {
+        return delete(variable);
+    }
+    if data.len() > MAX_VALUE_SIZE {
+        return Err(EfiError::BufferTooSmall);
+    }
+    let mut name = [0; 32];
+    runtime::set_variable(variable.encode(&mut name), &VENDOR_GUID, ATTRIBUTES, data, true)
+}
+
+/// Delete a variable, succeeding if it did not exist
+pub fn delete(variable: Variable) -> Result<(), EfiError> {
+    let mut name = [0; 32];
+    match runtime::set_variable(variable.encode(&mut name), &VENDOR_GUID, ATTRIBUTES, &[], true) {
+        Err(EfiError::NotFound) => Ok(()),
+        result => result,
+    }
+}
+
+/// Read a fixed-size variable, `None` if it is missing or has another size
+fn read_array<const N: usize>(variable: Variable) -> Option<[u8; N]> {
+    let mut buf = [0; N];
+    match read(variable, &mut buf) {
+        Ok(size) if size == N => Some(buf),
+        _ => None,
+    }
+}
+
+/// Stored default log level, `None` if unset or out of range
+pub fn log_level() -> Option<LevelFilter> {
+    let [level] = read_array::<1>(Variable::LogLevel)?;
+    LevelFilter::iter().nth(level as usize)
+}
+
+/// Store the default log level for the next boots
+pub fn set_log_level(level: LevelFilter) -> Result<(), EfiError> {
+    write(Variable::LogLevel, &[level as u8])
+}
+
+/// Raise or lower the `log` level to the stored default, if there is one
+pub fn apply_log_level() {
+    if let Some(level) = log_level() {
+        log::set_max_level(level);
+    }
+}
+
+/// Record the previous boot's outcome and mark this boot as started
+///
+/// Does nothing without UEFI runtime services.
+pub fn begin_boot() -> Option<&'static BootRecord> {
+    if !runtime::is_available() {
+        return None;
+    }
+    let [state, failures] = read_array::<2>(Variable::BootStatus).unwrap_or([0, 0]);
+    let previous = BootState::from_u8(state);
+    let crash_time = read_array::<8>(Variable::CrashMarker).map(u64::from_le_bytes);
+
+    let failed = previous == BootState::Booting || crash_time.is_some();
+    let failed_boots = if failed { failures.saturating_add(1) } else { 0 };
+    let record = RECORD.call_once(|| BootRecord { previous, crash_time, failed_boots });
+
+    if let Err(e) = delete(Variable::CrashMarker) {
+        crate::serial_println!("Cannot clear {}: {}", Variable::CrashMarker.name(), e);
+    }
+    if let Err(e) = write(Variable::BootStatus, &[BootState::Booting as u8, failed_boots]) {
+        crate::serial_println!("Cannot write {}: {}", Variable::BootStatus.name(), e);
+    }
+    Some(record)
+}
+
+/// Mark this boot as complete, resetting the failure count
+pub fn finish_boot() {
+    if RECORD.get().is_none() {
+        return;
+    }
+    if let Err(e) = write(Variable::BootStatus, &[BootState::Complete as u8, 0]) {
+        crate::serial_println!("Cannot write {}: {}", Variable::BootStatus.name(), e);
+    }
+}
+
+/// Set the crash marker from the panic handler
+///
+/// Gives up rather than wait if a firmware call was interrupted.
+pub fn record_crash() {
+    if !runtime::is_available() {
+        return;
+    }
+    let mut name = [0; 32];
+    let time = crate::time::unix_time().to_le_bytes();
+    let name = Variable::CrashMarker.encode(&mut name);
+    let _ = runtime::set_variable(name, &VENDOR_GUID, ATTRIBUTES, &time, false);
+}
+
+/// Outcome of the previous boot, if `begin_boot` found UEFI
+pub fn boot_record() -> Option<&'static BootRecord> {
+    RECORD.get()
+}
@@ -0,0 +1,472 @@
+//! Console Fonts
+//!
+//! Bitmap fonts for a pixel console: PSF1 and PSF2 files, the formats of
+//! the Linux console fonts, plus a built-in 8x16 font that is always
+//! available. One font is current, drawn at an integer scale so text stays
+//! legible on HiDPI screens. A font file handed to QEMU as the fw_cfg file
+//! `opt/cosmos/font` is loaded at boot until there is an initrd to read
+//! fonts from.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER: usize = 4;
+/// PSF1 mode: 512 glyphs instead of 256
+const PSF1_MODE512: u8 = 0x01;
+/// PSF1 mode: a Unicode table follows the glyphs
+const PSF1_MODEHASTAB: u8 = 0x02;
+const PSF1_MODESEQ: u8 = 0x04;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQ: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HEADER: usize = 32;
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQ: u8 = 0xFE;
+
+/// Largest glyph accepted in either dimension
+const MAX_GLYPH_SIZE: u32 = 64;
+/// Largest scale factor
+pub const MAX_SCALE: u32 = 4;
+/// Text rows the automatic scale aims for
+const TARGET_ROWS: u32 = 50;
+
+/// fw_cfg file a font is loaded from at boot
+pub const FW_CFG_FONT: &str = "opt/cosmos/font";
+/// Name of the built-in font
+pub const BUILTIN: &str = "builtin-8x16";
+
+/// Errors that can occur loading or selecting fonts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    /// Neither a PSF1 nor a PSF2 file
+    BadMagic,
+    /// File shorter than its header says
+    Truncated,
+    /// Glyph size zero or too large
+    BadGlyphSize,
+    /// A font with that name is already loaded
+    AlreadyExists,
+    /// No font with that name
+    NotFound,
+    /// Scale out of range
+    BadScale,
+}
+
+impl core::fmt::Display for FontError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FontError::BadMagic => write!(f, "Not a PSF font"),
+            FontError::Truncated => write!(f, "Font file truncated"),
+            FontError::BadGlyphSize => write!(f, "Unsupported glyph size"),
+            FontError::AlreadyExists => write!(f, "Font already loaded"),
+            FontError::NotFound => write!(f, "No such font"),
+            FontError::BadScale => write!(f, "Font scale out of range"),
+        }
+    }
+}
+
+/// A bitmap font, glyph rows padded to whole bytes, most significant bit
+/// leftmost
+pub struct Font {
+    name: String,
+    width: u32,
+    height: u32,
+    glyphs: Vec<u8>,
+    glyph_count: usize,
+    /// Characters to glyph indices, empty when glyphs are in code point order
+    unicode: BTreeMap<char, u32>,
+}
+
+impl Font {
+    /// Parse a PSF1 or PSF2 font file
+    pub fn parse_psf(name: &str, data: &[u8]) -> Result<Self, FontError> {
+        if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(name, data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(name, data)
+        } else {
+            Err(FontError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(name: &str, data: &[u8]) -> Result<Self, FontError> {
+        if data.len() < PSF1_HEADER {
+            return Err(FontError::Truncated);
+        }
+        let mode = data[2];
+        let height = data[3] as u32;
+        let glyph_count = if mode & PSF1_MODE512 != 0 { 512 } else { 256 };
+        let glyphs = glyph_data(data, PSF1_HEADER, 8, height, glyph_count)?;
+
+        let mut unicode = BTreeMap::new();
+        if mode & (PSF1_MODEHASTAB | PSF1_MODESEQ) != 0 {
+            let table = &data[PSF1_HEADER + glyphs.len()..];
+            let mut entries = table.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+            for glyph in 0..glyph_count as u32 {
+                let mut in_sequence = false;
+                for value in entries.by_ref() {
+                    match value {
+                        PSF1_SEPARATOR => break,
+                        PSF1_START_SEQ => in_sequence = true,
+                        // Combining sequences need a shaping console, skip them
+                        _ if in_sequence => {}
+                        _ => {
+                            if let Some(ch) = char::from_u32(value as u32) {
+                                unicode.entry(ch).or_insert(glyph);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Font { name: name.to_string(), width: 8, height, glyphs, glyph_count, unicode })
+    }
+
+    fn parse_psf2(name: &str, data: &[u8]) -> Result<Self, FontError> {
+        if data.len() < PSF2_HEADER {
+            return Err(FontError::Truncated);
+        }
+        let field = |index: usize| {
+            let offset = index * 4;
+            u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+        };
+        let header_size = field(2) as usize;
+        let flags = field(3);
+        let glyph_count = field(4) as usize;
+        let glyph_size = field(5) as usize;
+        let height = field(6);
+        let width = field(7);
+        if header_size < PSF2_HEADER {
+            return Err(FontError::Truncated);
+        }
+        if glyph_size != width.div_ceil(8) as usize * height as usize {
+            return Err(FontError::BadGlyphSize);
+        }
+        let glyphs = glyph_data(data, header_size, width, height, glyph_count)?;
+
+        let mut unicode = BTreeMap::new();
+        if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            let mut table = &data[header_size + glyphs.len()..];
+            for glyph in 0..glyph_count as u32 {
+                let end = table.iter().position(|&byte| byte == PSF2_SEPARATOR).unwrap_or(table.len());
+                let entry = &table[..end];
+                // Single characters come first, sequences after the marker
+                let singles = entry.split(|&byte| byte == PSF2_START_SEQ).next().unwrap_or(&[]);
+                if let Ok(text) = core::str::from_utf8(singles) {
+                    for ch in text.chars() {
+                        unicode.entry(ch).or_insert(glyph);
+                    }
+                }
+                table = table.get(end + 1..).unwrap_or(&[]);
+            }
+        }
+        Ok(Font { name: name.to_string(), width, height, glyphs, glyph_count, unicode })
+    }
+
+    /// The built-in 8x16 font, printable ASCII only
+    pub fn builtin() -> Self {
+        Font {
+            name: BUILTIN.to_string(),
+            width: 8,
+            height: 16,
+            glyphs: BUILTIN_GLYPHS.to_vec(),
+            glyph_count: 128,
+            unicode: BTreeMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Glyph cell size in pixels, unscaled
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn glyph_count(&self) -> usize {
+        self.glyph_count
+    }
+
+    fn bytes_per_row(&self) -> usize {
+        self.width.div_ceil(8) as usize
+    }
+
+    /// Bitmap of `ch`, `?` for characters the font lacks
+    pub fn glyph(&self, ch: char) -> &[u8] {
+        let index = self.index(ch).or_else(|| self.index('?')).unwrap_or(0);
+        let size = self.bytes_per_row() * self.height as usize;
+        &self.glyphs[index * size..(index + 1) * size]
+    }
+
+    fn index(&self, ch: char) -> Option<usize> {
+        if self.unicode.is_empty() {
+            Some(ch as usize).filter(|&index| index < self.glyph_count)
+        } else {
+            self.unicode.get(&ch).map(|&index| index as usize)
+        }
+    }
+
+    /// Draw `ch` at `scale`, calling `put(x, y, set)` for every pixel of
+    /// the scaled cell
+    pub fn draw<F: FnMut(u32, u32, bool)>(&self, ch: char, scale: u32, mut put: F) {
+        let glyph = self.glyph(ch);
+        let stride = self.bytes_per_row();
+        for y in 0..self.height * scale {
+            let row = &glyph[(y / scale) as usize * stride..];
+            for x in 0..self.width * scale {
+                let column = x / scale;
+                let set = row[column as usize / 8] & (0x80 >> (column % 8)) != 0;
+                put(x, y, set);
+            }
+        }
+    }
+}
+
+/// Copy out `count` glyphs of `width` by `height` starting at `offset`
+fn glyph_data(data: &[u8], offset: usize, width: u32, height: u32, count: usize) -> Result<Vec<u8>, FontError> {
+    if width == 0 || height == 0 || width > MAX_GLYPH_SIZE || height > MAX_GLYPH_SIZE || count == 0 {
+        return Err(FontError::BadGlyphSize);
+    }
+    let len = width.div_ceil(8) as usize * height as usize * count;
+    let end = offset.checked_add(len).ok_or(FontError::Truncated)?;
+    data.get(offset..end).map(<[u8]>::to_vec).ok_or(FontError::Truncated)
+}
+
+/// Printable ASCII drawn on a 5x7 grid
+const GLYPHS_5X7: [[u8; 7]; 95] = [
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000], // ' '
+    [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000, 0b00100], // '!'
+    [0b01010, 0b01010, 0b01010, 0b00000, 0b00000, 0b00000, 0b00000], // '"'
+    [0b01010, 0b01010, 0b11111, 0b01010, 0b11111, 0b01010, 0b01010], // '#'
+    [0b00100, 0b01111, 0b10100, 0b01110, 0b00101, 0b11110, 0b00100], // '$'
+    [0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011], // '%'
+    [0b01100, 0b10010, 0b10100, 0b01000, 0b10101, 0b10010, 0b01101], // '&'
+    [0b00100, 0b00100, 0b01000, 0b00000, 0b00000, 0b00000, 0b00000], // '\''
+    [0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010], // '('
+    [0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000], // ')'
+    [0b00000, 0b00100, 0b10101, 0b01110, 0b10101, 0b00100, 0b00000], // '*'
+    [0b00000, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0b00000], // '+'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000], // ','
+    [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000], // '-'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100], // '.'
+    [0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000], // '/'
+    [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110], // '0'
+    [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110], // '1'
+    [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111], // '2'
+    [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110], // '3'
+    [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010], // '4'
+    [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110], // '5'
+    [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110], // '6'
+    [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000], // '7'
+    [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110], // '8'
+    [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100], // '9'
+    [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000], // ':'
+    [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b00100, 0b01000], // ';'
+    [0b00010, 0b00100, 0b01000, 0b10000, 0b01000, 0b00100, 0b00010], // '<'
+    [0b00000, 0b00000, 0b11111, 0b00000, 0b11111, 0b00000, 0b00000], // '='
+    [0b01000, 0b00100, 0b00010, 0b00001, 0b00010, 0b00100, 0b01000], // '>'
+    [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100], // '?'
+    [0b01110, 0b10001, 0b00001, 0b01101, 0b10101, 0b10101, 0b01110], // '@'
+    [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001], // 'A'
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110], // 'B'
+    [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110], // 'C'
+    [0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100], // 'D'
+    [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111], // 'E'
+    [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000], // 'F'
+    [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111], // 'G'
+    [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001], // 'H'
+    [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110], // 'I'
+    [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100], // 'J'
+    [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001], // 'K'
+    [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111], // 'L'
+    [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001], // 'M'
+    [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001], // 'N'
+    [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110], // 'O'
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000], // 'P'
+    [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101], // 'Q'
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001], // 'R'
+    [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110], // 'S'
+    [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100], // 'T'
+    [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110], // 'U'
+    [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100], // 'V'
+    [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010], // 'W'
+    [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001], // 'X'
+    [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100], // 'Y'
+    [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111], // 'Z'
+    [0b01110, 0b01000, 0b01000, 0b01000, 0b01000, 0b01000, 0b01110], // '['
+    [0b00000, 0b10000, 0b01000, 0b00100, 0b00010, 0b00001, 0b00000], // '\\'
+    [0b01110, 0b00010, 0b00010, 0b00010, 0b00010, 0b00010, 0b01110], // ']'
+    [0b00100, 0b01010, 0b10001, 0b00000, 0b00000, 0b00000, 0b00000], // '^'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111], // '_'
+    [0b01000, 0b00100, 0b00010, 0b00000, 0b00000, 0b00000, 0b00000], // '`'
+    [0b00000, 0b00000, 0b01110, 0b00001, 0b01111, 0b10001, 0b01111], // 'a'
+    [0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b11110], // 'b'
+    [0b00000, 0b00000, 0b01110, 0b10000, 0b10000, 0b10001, 0b01110], // 'c'
+    [0b00001, 0b00001, 0b01101, 0b10011, 0b10001, 0b10001, 0b01111], // 'd'
+    [0b00000, 0b00000, 0b01110, 0b10001, 0b11111, 0b10000, 0b01110], // 'e'
+    [0b00110, 0b01001, 0b01000, 0b11100, 0b01000, 0b01000, 0b01000], // 'f'
+    [0b00000, 0b01111, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110], // 'g'
+    [0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001], // 'h'
+    [0b00100, 0b00000, 0b01100, 0b00100, 0b00100, 0b00100, 0b01110], // 'i'
+    [0b00010, 0b00000, 0b00110, 0b00010, 0b00010, 0b10010, 0b01100], // 'j'
+    [0b10000, 0b10000, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010], // 'k'
+    [0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110], // 'l'
+    [0b00000, 0b00000, 0b11010, 0b10101, 0b10101, 0b10001, 0b10001], // 'm'
+    [0b00000, 0b00000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001], // 'n'
+    [0b00000, 0b00000, 0b01110, 0b10001, 0b10001, 0b10001, 0b01110], // 'o'
+    [0b00000, 0b00000, 0b11110, 0b10001, 0b11110, 0b10000, 0b10000], // 'p'
+    [0b00000, 0b00000, 0b01101, 0b10011, 0b01111, 0b00001, 0b00001], // 'q'
+    [0b00000, 0b00000, 0b10110, 0b11001, 0b10000, 0b10000, 0b10000], // 'r'
+    [0b00000, 0b00000, 0b01110, 0b10000, 0b01110, 0b00001, 0b11110], // 's'
+    [0b01000, 0b01000, 0b11100, 0b01000, 0b01000, 0b01001, 0b00110], // 't'
+    [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b10011, 0b01101], // 'u'
+    [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100], // 'v'
+    [0b00000, 0b00000, 0b10001, 0b10001, 0b10101, 0b10101, 0b01010], // 'w'
+    [0b00000, 0b00000, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001], // 'x'
+    [0b00000, 0b00000, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110], // 'y'
+    [0b00000, 0b00000, 0b11111, 0b00010, 0b00100, 0b01000, 0b11111], // 'z'
+    [0b00010, 0b00100, 0b00100, 0b01000, 0b00100, 0b00100, 0b00010], // '{'
+    [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100], // '|'
+    [0b01000, 0b00100, 0b00100, 0b00010, 0b00100, 0b00100, 0b01000], // '}'
+    [0b00000, 0b00000, 0b01000, 0b10101, 0b00010, 0b00000, 0b00000], // '~'
+];
+
+/// `GLYPHS_5X7` placed in 8x16 cells: one blank column on the left, each
+/// row doubled, one blank row above and below
+const BUILTIN_GLYPHS: [u8; 128 * 16] = {
+    let mut glyphs = [0u8; 128 * 16];
+    let mut index = 0;
+    while index < GLYPHS_5X7.len() {
+        let base = (0x20 + index) * 16;
+        let mut row = 0;
+        while row < 7 {
+            let bits = GLYPHS_5X7[index][row] << 2;
+            glyphs[base + 1 + row * 2] = bits;
+            glyphs[base + 2 + row * 2] = bits;
+            row += 1;
+        }
+        index += 1;
+    }
+    glyphs
+};
+
+static FONTS: Mutex<Vec<Arc<Font>>> = Mutex::new(Vec::new());
+static CURRENT: Mutex<Option<Arc<Font>>> = Mutex::new(None);
+static SCALE: AtomicU32 = AtomicU32::new(1);
+
+/// Register the built-in font and any font passed in through fw_cfg
+pub fn init() {
+    let builtin = Arc::new(Font::builtin());
+    FONTS.lock().push(builtin.clone());
+    *CURRENT.lock() = Some(builtin);
+
+    if let Some(data) = crate::qemu::fw_cfg_file(FW_CFG_FONT) {
+        match load("fw_cfg", &data) {
+            Ok(font) => {
+                let (width, height) = font.size();
+                crate::serial_println!("font: loaded {}x{} font from fw_cfg", width, height);
+                let _ = select(font.name());
+            }
+            Err(e) => crate::serial_println!("font: {}: {}", FW_CFG_FONT, e),
+        }
+    }
+}
+
+/// Parse a PSF font and make it available under `name`
+pub fn load(name: &str, data: &[u8]) -> Result<Arc<Font>, FontError> {
+    let font = Arc::new(Font::parse_psf(name, data)?);
+    let mut fonts = FONTS.lock();
+    if fonts.iter().any(|loaded| loaded.name == name) {
+        return Err(FontError::AlreadyExists);
+    }
+    fonts.push(font.clone());
+    Ok(font)
+}
+
+/// Make a loaded font current
+pub fn select(name: &str) -> Result<(), FontError> {
+    let font = FONTS.lock().iter().find(|font| font.name == name).cloned().ok_or(FontError::NotFound)?;
+    *CURRENT.lock() = Some(font);
+    Ok(())
+}
+
+/// The current font, the built-in one before `init`
+pub fn current() -> Arc<Font> {
+    CURRENT.lock().get_or_insert_with(|| Arc::new(Font::builtin())).clone()
+}
+
+/// Loaded fonts as (name, width, height, glyph count)
+pub fn list() -> Vec<(String, u32, u32, usize)> {
+    FONTS
+        .lock()
+        .iter()
+        .map(|font| (font.name.clone(), font.width, font.height, font.glyph_count))
+        .collect()
+}
+
+pub fn scale() -> u32 {
+    SCALE.load(Ordering::Relaxed)
+}
+
+pub fn set_scale(scale: u32) -> Result<(), FontError> {
+    if !(1..=MAX_SCALE).contains(&scale) {
+        return Err(FontError::BadScale);
+    }
+    SCALE.store(scale, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Scale that gives a screen of `height` pixels about 50 rows of the
+/// current font, 2 on a 4K panel with an 8x16 font
+pub fn scale_for(height: u32) -> u32 {
+    let font_height = current().height;
+    (height / (font_height * TARGET_ROWS)).clamp(1, MAX_SCALE)
+}
+
+/// PSF2 and PSF1 parsing agree with the built-in font they encode
+fn selftest() -> Result<(), &'static str> {
+    let builtin = Font::builtin();
+    let glyph_a = builtin.glyph('A');
+
+    // PSF2 with one glyph mapped to both 'A' and U+0391 through the table
+    let mut psf2 = Vec::new();
+    for value in [0x864A_B572u32, 0, 32, PSF2_HAS_UNICODE_TABLE, 1, 16, 16, 8] {
+        psf2.extend_from_slice(&value.to_le_bytes());
+    }
+    psf2.extend_from_slice(glyph_a);
+    psf2.extend_from_slice("AΑ".as_bytes());
+    psf2.push(PSF2_SEPARATOR);
+    let font = Font::parse_psf("test2", &psf2).map_err(|_| "PSF2 rejected")?;
+    if font.size() != (8, 16) || font.glyph('Α') != glyph_a || font.glyph('A') != glyph_a {
+        return Err("PSF2 Unicode table not applied");
+    }
+
+    // PSF1 with 256 glyphs and no table, indexed by code point
+    let mut psf1 = Vec::from([PSF1_MAGIC[0], PSF1_MAGIC[1], 0, 16]);
+    psf1.extend_from_slice(&BUILTIN_GLYPHS);
+    psf1.resize(PSF1_HEADER + 256 * 16, 0);
+    let font = Font::parse_psf("test1", &psf1).map_err(|_| "PSF1 rejected")?;
+    if font.glyph('A') != glyph_a || font.glyph_count() != 256 {
+        return Err("PSF1 glyphs misplaced");
+    }
+    if Font::parse_psf("short", &psf1[..100]).err() != Some(FontError::Truncated) {
+        return Err("truncated PSF1 accepted");
+    }
+
+    let mut lit = 0;
+    builtin.draw('A', 2, |_, _, set| lit += set as u32);
+    if lit != glyph_a.iter().map(|row| row.count_ones()).sum::<u32>() * 4 {
+        return Err("scaled glyph has the wrong pixel count");
+    }
+    Ok(())
+}
+
+crate::selftest!("font", selftest);
@@ -0,0 +1,1092 @@
+//! ext2 Filesystem
+//!
+//! Read/write driver for revision 0 and 1 ext2 volumes on any registered
+//! block device. Supports path lookup, directory listing, file reads and
+//! writes through direct, indirect and doubly indirect blocks, creating
+//! files and directories, and unlinking files. Metadata is written through
+//! immediately, so a volume is consistent whenever no call is in progress.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::{read_bytes, write_bytes, FileKind, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+use crate::dev::{BlockDevice, DevError};
+
+/// Superblock magic number
+const EXT2_MAGIC: u16 = 0xEF53;
+/// Byte offset of the superblock from the start of the volume
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const GROUP_DESC_SIZE: usize = 32;
+/// Inode size of revision 0 volumes
+const GOOD_OLD_INODE_SIZE: usize = 128;
+/// First non-reserved inode of revision 0 volumes
+const GOOD_OLD_FIRST_INO: u32 = 11;
+
+/// Inode of the root directory
+pub const ROOT_INO: u32 = 2;
+
+/// Block pointers held in the inode itself
+const DIRECT_BLOCKS: u64 = 12;
+const INDIRECT_SLOT: usize = 12;
+const DOUBLE_INDIRECT_SLOT: usize = 13;
+const TRIPLE_INDIRECT_SLOT: usize = 14;
+
+/// Directory entries carry a file type byte
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const MAX_NAME_LEN: usize = 255;
+
+// Directory entry file types
+const FT_UNKNOWN: u8 = 0;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+/// Errors that can occur on ext2 volumes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext2Error {
+    /// Underlying block device failed
+    Device(DevError),
+    /// Superblock magic missing
+    NotExt2,
+    /// Volume uses incompatible features this driver does not implement
+    UnsupportedFeatures(u32),
+    /// Write to a volume mounted read-only
+    ReadOnly,
+    /// Metadata out of range or inconsistent
+    Corrupt,
+    /// No entry with that name
+    NotFound,
+    /// Entry with that name already exists
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    /// Name empty, too long, or containing `/`
+    InvalidName,
+    /// No free blocks or inodes left
+    NoSpace,
+    /// Offset beyond what the block map can address
+    FileTooLarge,
+}
+
+impl core::fmt::Display for Ext2Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Ext2Error::Device(e) => write!(f, "ext2 device error: {}", e),
+            Ext2Error::NotExt2 => write!(f, "Not an ext2 filesystem"),
+            Ext2Error::UnsupportedFeatures(bits) => write!(f, "Unsupported ext2 features: {:#x}", bits),
+            Ext2Error::ReadOnly => write!(f, "ext2 volume is read-only"),
+            Ext2Error::Corrupt => write!(f, "ext2 metadata is corrupt"),
+            Ext2Error::NotFound => write!(f, "No such file or directory"),
+            Ext2Error::AlreadyExists => write!(f, "File exists"),
+            Ext2Error::NotADirectory => write!(f, "Not a directory"),
+            Ext2Error::IsADirectory => write!(f, "Is a directory"),
+            Ext2Error::InvalidName => write!(f, "Invalid file name"),
+            Ext2Error::NoSpace => write!(f, "No space left on ext2 volume"),
+            Ext2Error::FileTooLarge => write!(f, "File too large for ext2 block map"),
+        }
+    }
+}
+
+impl From<DevError> for Ext2Error {
+    fn from(e: DevError) -> Self {
+        Ext2Error::Device(e)
+    }
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn put16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Inode attributes
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub ino: u32,
+    pub kind: FileKind,
+    /// Permission bits
+    pub mode: u16,
+    pub size: u64,
+    pub links: u16,
+    /// Last modification, Unix seconds
+    pub mtime: u32,
+}
+
+/// One directory entry
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub ino: u32,
+    pub name: String,
+    pub kind: FileKind,
+}
+
+/// On-disk inode, kept as raw bytes so fields this driver ignores survive
+/// a write back
+#[derive(Clone)]
+struct Inode {
+    raw: [u8; GOOD_OLD_INODE_SIZE],
+}
+
+impl Inode {
+    fn mode(&self) -> u16 { le16(&self.raw, 0) }
+    fn set_mode(&mut self, mode: u16) { put16(&mut self.raw, 0, mode) }
+
+    fn size(&self) -> u64 {
+        // i_dir_acl holds the high half for regular files on large-file volumes
+        let high = if self.mode() & S_IFMT == S_IFREG { le32(&self.raw, 108) as u64 } else { 0 };
+        (high << 32) | le32(&self.raw, 4) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        put32(&mut self.raw, 4, size as u32);
+        if self.mode() & S_IFMT == S_IFREG {
+            put32(&mut self.raw, 108, (size >> 32) as u32);
+        }
+    }
+
+    fn set_times(&mut self, now: u32) {
+        put32(&mut self.raw, 8, now);
+        put32(&mut self.raw, 12, now);
+        put32(&mut self.raw, 16, now);
+    }
+
+    fn set_mtime(&mut self, now: u32) {
+        put32(&mut self.raw, 12, now);
+        put32(&mut self.raw, 16, now);
+    }
+
+    fn set_dtime(&mut self, now: u32) { put32(&mut self.raw, 20, now) }
+
+    fn links(&self) -> u16 { le16(&self.raw, 26) }
+    fn set_links(&mut self, links: u16) { put16(&mut self.raw, 26, links) }
+
+    /// Allocated space in 512-byte sectors
+    fn sectors(&self) -> u32 { le32(&self.raw, 28) }
+    fn set_sectors(&mut self, sectors: u32) { put32(&mut self.raw, 28, sectors) }
+
+    fn block(&self, slot: usize) -> u32 { le32(&self.raw, 40 + slot * 4) }
+    fn set_block(&mut self, slot: usize, block: u32) { put32(&mut self.raw, 40 + slot * 4, block) }
+
+    fn metadata(&self, ino: u32) -> Metadata {
+        Metadata {
+            ino,
+            kind: FileKind::from_mode(self.mode()),
+            mode: self.mode() & 0o7777,
+            size: self.size(),
+            links: self.links(),
+            mtime: le32(&self.raw, 16),
+        }
+    }
+}
+
+/// Block group descriptor
+#[derive(Clone, Copy)]
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+/// Superblock fields and group table, the mutable part of a mount
+struct Volume {
+    superblock: [u8; SUPERBLOCK_SIZE],
+    groups: Vec<Group>,
+}
+
+impl Volume {
+    fn free_blocks(&self) -> u32 { le32(&self.superblock, 12) }
+    fn free_inodes(&self) -> u32 { le32(&self.superblock, 16) }
+
+    fn adjust_free_blocks(&mut self, delta: i32) {
+        let free = self.free_blocks().wrapping_add_signed(delta);
+        put32(&mut self.superblock, 12, free);
+    }
+
+    fn adjust_free_inodes(&mut self, delta: i32) {
+        let free = self.free_inodes().wrapping_add_signed(delta);
+        put32(&mut self.superblock, 16, free);
+    }
+}
+
+/// A mounted ext2 volume
+pub struct Ext2 {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    blocks_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inodes_count: u32,
+    inode_size: usize,
+    first_ino: u32,
+    filetype: bool,
+    large_file: bool,
+    read_only: bool,
+    volume: Mutex<Volume>,
+}
+
+impl Ext2 {
+    /// Mount the volume on `device`
+    ///
+    /// Volumes with unknown read-only-compatible features are mounted
+    /// read-only; unknown incompatible features refuse the mount.
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Self, Ext2Error> {
+        let mut superblock = [0u8; SUPERBLOCK_SIZE];
+        read_bytes(&*device, SUPERBLOCK_OFFSET, &mut superblock)?;
+        if le16(&superblock, 56) != EXT2_MAGIC {
+            return Err(Ext2Error::NotExt2);
+        }
+
+        let revision = le32(&superblock, 76);
+        let (incompat, ro_compat) = if revision >= 1 {
+            (le32(&superblock, 96), le32(&superblock, 100))
+        } else {
+            (0, 0)
+        };
+        if incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(Ext2Error::UnsupportedFeatures(incompat & !INCOMPAT_FILETYPE));
+        }
+        let known_ro = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+        let log_block_size = le32(&superblock, 24);
+        if log_block_size > 6 {
+            return Err(Ext2Error::Corrupt);
+        }
+        let block_size = 1024usize << log_block_size;
+        if !block_size.is_multiple_of(device.block_size()) {
+            return Err(Ext2Error::Device(DevError::InvalidArgument));
+        }
+
+        let (inode_size, first_ino) = if revision >= 1 {
+            (le16(&superblock, 88) as usize, le32(&superblock, 84))
+        } else {
+            (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INO)
+        };
+        let blocks_count = le32(&superblock, 4);
+        let first_data_block = le32(&superblock, 20);
+        let blocks_per_group = le32(&superblock, 32);
+        let inodes_per_group = le32(&superblock, 40);
+        if inode_size < GOOD_OLD_INODE_SIZE || blocks_per_group == 0 || inodes_per_group == 0
+            || blocks_count <= first_data_block
+        {
+            return Err(Ext2Error::Corrupt);
+        }
+
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group) as usize;
+        let mut table = vec![0u8; group_count * GROUP_DESC_SIZE];
+        let table_offset = (first_data_block as u64 + 1) * block_size as u64;
+        read_bytes(&*device, table_offset, &mut table)?;
+        let groups = table
+            .chunks_exact(GROUP_DESC_SIZE)
+            .map(|desc| Group {
+                block_bitmap: le32(desc, 0),
+                inode_bitmap: le32(desc, 4),
+                inode_table: le32(desc, 8),
+                free_blocks: le16(desc, 12),
+                free_inodes: le16(desc, 14),
+                used_dirs: le16(desc, 16),
+            })
+            .collect();
+
+        Ok(Ext2 {
+            device,
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inodes_count: le32(&superblock, 0),
+            inode_size,
+            first_ino,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            read_only: ro_compat & !known_ro != 0,
+            volume: Mutex::new(Volume { superblock, groups }),
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Free blocks and free inodes
+    pub fn free(&self) -> (u32, u32) {
+        let volume = self.volume.lock();
+        (volume.free_blocks(), volume.free_inodes())
+    }
+
+    /// Resolve an absolute path to an inode number
+    pub fn lookup(&self, path: &str) -> Result<u32, Ext2Error> {
+        let mut ino = ROOT_INO;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            ino = self.find_entry(ino, name)?;
+        }
+        Ok(ino)
+    }
+
+    pub fn metadata(&self, ino: u32) -> Result<Metadata, Ext2Error> {
+        Ok(self.read_inode(ino)?.metadata(ino))
+    }
+
+    /// List a directory, including `.` and `..`
+    pub fn read_dir(&self, ino: u32) -> Result<Vec<DirEntry>, Ext2Error> {
+        let mut entries = Vec::new();
+        self.walk_dir(ino, |_, _, entry| {
+            entries.push(entry);
+            false
+        })?;
+        Ok(entries)
+    }
+
+    /// Read file data at `offset`, returning the bytes read, 0 at end of file
+    pub fn read(&self, ino: u32, offset: u64, buf: &mut [u8]) -> Result<usize, Ext2Error> {
+        let inode = self.read_inode(ino)?;
+        if FileKind::from_mode(inode.mode()) == FileKind::Directory {
+            return Err(Ext2Error::IsADirectory);
+        }
+        let size = inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let block_size = self.block_size as u64;
+        let mut block = vec![0u8; self.block_size];
+        let mut done = 0;
+        while done < len {
+            crate::task::yield_if_needed();
+            let position = offset + done as u64;
+            let within = (position % block_size) as usize;
+            let count = (self.block_size - within).min(len - done);
+            match self.map_block(&inode, position / block_size)? {
+                Some(physical) => {
+                    self.read_block(physical, &mut block)?;
+                    buf[done..done + count].copy_from_slice(&block[within..within + count]);
+                }
+                // Hole in a sparse file
+                None => buf[done..done + count].fill(0),
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    /// Write file data at `offset`, allocating blocks and growing the file
+    /// as needed
+    pub fn write(&self, ino: u32, offset: u64, buf: &[u8]) -> Result<usize, Ext2Error> {
+        self.check_writable()?;
+        let mut inode = self.read_inode(ino)?;
+        if FileKind::from_mode(inode.mode()) == FileKind::Directory {
+            return Err(Ext2Error::IsADirectory);
+        }
+        let end = offset.checked_add(buf.len() as u64).ok_or(Ext2Error::FileTooLarge)?;
+        if end > u32::MAX as u64 && !self.large_file {
+            return Err(Ext2Error::FileTooLarge);
+        }
+
+        let block_size = self.block_size as u64;
+        let mut block = vec![0u8; self.block_size];
+        let mut done = 0;
+        let result = loop {
+            if done == buf.len() {
+                break Ok(());
+            }
+            let position = offset + done as u64;
+            let within = (position % block_size) as usize;
+            let count = (self.block_size - within).min(buf.len() - done);
+            let physical = match self.map_block_alloc(ino, &mut inode, position / block_size) {
+                Ok(physical) => physical,
+                Err(e) => break Err(e),
+            };
+            if count < self.block_size {
+                if let Err(e) = self.read_block(physical, &mut block) {
+                    break Err(e);
+                }
+            }
+            block[within..within + count].copy_from_slice(&buf[done..done + count]);
+            if let Err(e) = self.write_block(physical, &block) {
+                break Err(e);
+            }
+            done += count;
+        };
+
+        // Keep whatever was written even if a later block failed to allocate
+        let written_end = offset + done as u64;
+        if written_end > inode.size() {
+            inode.set_size(written_end);
+        }
+        inode.set_mtime(crate::time::unix_time() as u32);
+        self.write_inode(ino, &inode)?;
+        match result {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+
+    /// Create an empty file or directory named `name` in directory `parent`
+    pub fn create(&self, parent: u32, name: &str, kind: FileKind) -> Result<u32, Ext2Error> {
+        self.check_writable()?;
+        if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('/') || name == "." || name == ".." {
+            return Err(Ext2Error::InvalidName);
+        }
+        let mut parent_inode = self.read_inode(parent)?;
+        if FileKind::from_mode(parent_inode.mode()) != FileKind::Directory {
+            return Err(Ext2Error::NotADirectory);
+        }
+        match self.find_entry(parent, name) {
+            Ok(_) => return Err(Ext2Error::AlreadyExists),
+            Err(Ext2Error::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        let directory = kind == FileKind::Directory;
+        let ino = self.alloc_inode(self.group_of_inode(parent), directory)?;
+        let now = crate::time::unix_time() as u32;
+        let mut inode = Inode { raw: [0; GOOD_OLD_INODE_SIZE] };
+        inode.set_mode(match kind {
+            FileKind::Directory => S_IFDIR | 0o755,
+            FileKind::Symlink => S_IFLNK | 0o777,
+            _ => S_IFREG | 0o644,
+        });
+        inode.set_times(now);
+        inode.set_links(if directory { 2 } else { 1 });
+
+        let linked = self.init_inode(ino, parent, &mut inode, directory)
+            .and_then(|()| self.add_entry(parent, &mut parent_inode, name, ino, kind));
+        if let Err(e) = linked {
+            // Nothing links to the inode yet, hand back what it holds
+            let _ = self.free_blocks_of(&inode);
+            let _ = self.free_inode(ino, directory);
+            return Err(e);
+        }
+        if directory {
+            // The new directory's `..` links back to the parent
+            parent_inode.set_links(parent_inode.links() + 1);
+        }
+        parent_inode.set_mtime(now);
+        self.write_inode(parent, &parent_inode)?;
+        Ok(ino)
+    }
+
+    /// Remove the entry `name` from directory `parent`, freeing the inode
+    /// and its blocks once no link is left
+    ///
+    /// Directories are refused, they are never unlinked.
+    pub fn unlink(&self, parent: u32, name: &str) -> Result<(), Ext2Error> {
+        self.check_writable()?;
+        if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('/') || name == "." || name == ".." {
+            return Err(Ext2Error::InvalidName);
+        }
+        let mut found = None;
+        self.walk_dir(parent, |block, offset, entry| {
+            if entry.name == name {
+                found = Some((block, offset, entry.ino));
+            }
+            found.is_some()
+        })?;
+        let (block, offset, ino) = found.ok_or(Ext2Error::NotFound)?;
+        let mut inode = self.read_inode(ino)?;
+        if FileKind::from_mode(inode.mode()) == FileKind::Directory {
+            return Err(Ext2Error::IsADirectory);
+        }
+
+        let mut data = vec![0u8; self.block_size];
+        self.read_block(block, &mut data)?;
+        let rec_len = le16(&data, offset + 4) as usize;
+        if offset == 0 {
+            // The first entry of a block has nothing to merge into
+            put32(&mut data, 0, 0);
+        } else {
+            // Give the record to the entry before it, walk_dir checked the chain
+            let mut previous = 0;
+            while previous + (le16(&data, previous + 4) as usize) < offset {
+                previous += le16(&data, previous + 4) as usize;
+            }
+            let previous_len = le16(&data, previous + 4) as usize;
+            put16(&mut data, previous + 4, (previous_len + rec_len) as u16);
+        }
+        self.write_block(block, &data)?;
+
+        let now = crate::time::unix_time() as u32;
+        let mut parent_inode = self.read_inode(parent)?;
+        parent_inode.set_mtime(now);
+        self.write_inode(parent, &parent_inode)?;
+
+        let links = inode.links().saturating_sub(1);
+        inode.set_links(links);
+        if links == 0 {
+            inode.set_dtime(now);
+            self.free_blocks_of(&inode)?;
+            self.free_inode(ino, false)?;
+        }
+        self.write_inode(ino, &inode)
+    }
+
+    /// Flush the superblock and group descriptors to the device
+    pub fn sync(&self) -> Result<(), Ext2Error> {
+        if self.read_only {
+            return Ok(());
+        }
+        let volume = self.volume.lock();
+        self.write_volume(&volume)
+    }
+
+    fn check_writable(&self) -> Result<(), Ext2Error> {
+        if self.read_only {
+            Err(Ext2Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn dirent_type(&self, kind: FileKind) -> u8 {
+        if !self.filetype {
+            return FT_UNKNOWN;
+        }
+        match kind {
+            FileKind::File => FT_REG_FILE,
+            FileKind::Directory => FT_DIR,
+            FileKind::Symlink => FT_SYMLINK,
+            FileKind::Other => FT_UNKNOWN,
+        }
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), Ext2Error> {
+        if block >= self.blocks_count {
+            return Err(Ext2Error::Corrupt);
+        }
+        let sectors_per_block = (self.block_size / self.device.block_size()) as u64;
+        self.device.read_blocks(block as u64 * sectors_per_block, buf)?;
+        Ok(())
+    }
+
+    fn write_block(&self, block: u32, buf: &[u8]) -> Result<(), Ext2Error> {
+        if block >= self.blocks_count {
+            return Err(Ext2Error::Corrupt);
+        }
+        let sectors_per_block = (self.block_size / self.device.block_size()) as u64;
+        self.device.write_blocks(block as u64 * sectors_per_block, buf)?;
+        Ok(())
+    }
+
+    fn group_of_inode(&self, ino: u32) -> usize {
+        ((ino - 1) / self.inodes_per_group) as usize
+    }
+
+    /// Byte offset of an inode on the device
+    fn inode_offset(&self, ino: u32) -> Result<u64, Ext2Error> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(Ext2Error::Corrupt);
+        }
+        let volume = self.volume.lock();
+        let group = volume.groups.get(self.group_of_inode(ino)).ok_or(Ext2Error::Corrupt)?;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        Ok(group.inode_table as u64 * self.block_size as u64 + index * self.inode_size as u64)
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<Inode, Ext2Error> {
+        let mut inode = Inode { raw: [0; GOOD_OLD_INODE_SIZE] };
+        read_bytes(&*self.device, self.inode_offset(ino)?, &mut inode.raw)?;
+        Ok(inode)
+    }
+
+    fn write_inode(&self, ino: u32, inode: &Inode) -> Result<(), Ext2Error> {
+        write_bytes(&*self.device, self.inode_offset(ino)?, &inode.raw)?;
+        Ok(())
+    }
+
+    /// Write out a freshly allocated inode, with the `.` and `..` block of
+    /// a directory
+    fn init_inode(&self, ino: u32, parent: u32, inode: &mut Inode, directory: bool) -> Result<(), Ext2Error> {
+        if directory {
+            let block = self.map_block_alloc(ino, inode, 0)?;
+            let mut data = vec![0u8; self.block_size];
+            let dot_len = write_dirent(&mut data, 0, ino, ".", self.dirent_type(FileKind::Directory), 12);
+            write_dirent(&mut data, dot_len, parent, "..", self.dirent_type(FileKind::Directory), self.block_size - dot_len);
+            self.write_block(block, &data)?;
+            inode.set_size(self.block_size as u64);
+        }
+        // Zero the whole record, so a large inode's extra fields don't keep
+        // whatever the previous owner left there
+        let mut record = vec![0u8; self.inode_size];
+        record[..GOOD_OLD_INODE_SIZE].copy_from_slice(&inode.raw);
+        write_bytes(&*self.device, self.inode_offset(ino)?, &record)?;
+        Ok(())
+    }
+
+    fn write_volume(&self, volume: &Volume) -> Result<(), Ext2Error> {
+        write_bytes(&*self.device, SUPERBLOCK_OFFSET, &volume.superblock)?;
+        // Patch the counters into the on-disk table so reserved fields survive
+        let table_offset = (self.first_data_block as u64 + 1) * self.block_size as u64;
+        let mut table = vec![0u8; volume.groups.len() * GROUP_DESC_SIZE];
+        read_bytes(&*self.device, table_offset, &mut table)?;
+        for (desc, group) in table.chunks_exact_mut(GROUP_DESC_SIZE).zip(&volume.groups) {
+            put16(desc, 12, group.free_blocks);
+            put16(desc, 14, group.free_inodes);
+            put16(desc, 16, group.used_dirs);
+        }
+        write_bytes(&*self.device, table_offset, &table)?;
+        Ok(())
+    }
+
+    /// Physical block holding file block `index`, `None` for a hole
+    fn map_block(&self, inode: &Inode, index: u64) -> Result<Option<u32>, Ext2Error> {
+        let per_block = (self.block_size / 4) as u64;
+        let physical = if index < DIRECT_BLOCKS {
+            inode.block(index as usize)
+        } else if index < DIRECT_BLOCKS + per_block {
+            self.read_pointer(inode.block(INDIRECT_SLOT), index - DIRECT_BLOCKS)?
+        } else if index < DIRECT_BLOCKS + per_block + per_block * per_block {
+            let index = index - DIRECT_BLOCKS - per_block;
+            let table = self.read_pointer(inode.block(DOUBLE_INDIRECT_SLOT), index / per_block)?;
+            self.read_pointer(table, index % per_block)?
+        } else {
+            return Err(Ext2Error::FileTooLarge);
+        };
+        Ok((physical != 0).then_some(physical))
+    }
+
+    /// Entry `slot` of the pointer block `table`, 0 if `table` is a hole
+    fn read_pointer(&self, table: u32, slot: u64) -> Result<u32, Ext2Error> {
+        if table == 0 {
+            return Ok(0);
+        }
+        let mut pointer = [0u8; 4];
+        read_bytes(&*self.device, table as u64 * self.block_size as u64 + slot * 4, &mut pointer)?;
+        Ok(u32::from_le_bytes(pointer))
+    }
+
+    fn write_pointer(&self, table: u32, slot: u64, block: u32) -> Result<(), Ext2Error> {
+        write_bytes(&*self.device, table as u64 * self.block_size as u64 + slot * 4, &block.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Physical block for file block `index`, allocating it and any pointer
+    /// blocks on the way; the caller writes `inode` back
+    fn map_block_alloc(&self, ino: u32, inode: &mut Inode, index: u64) -> Result<u32, Ext2Error> {
+        let per_block = (self.block_size / 4) as u64;
+        let goal = self.group_of_inode(ino);
+        if index < DIRECT_BLOCKS {
+            let slot = index as usize;
+            if inode.block(slot) == 0 {
+                let block = self.alloc_block(goal, inode)?;
+                inode.set_block(slot, block);
+            }
+            return Ok(inode.block(slot));
+        }
+
+        let (table, slot) = if index < DIRECT_BLOCKS + per_block {
+            if inode.block(INDIRECT_SLOT) == 0 {
+                let block = self.alloc_block(goal, inode)?;
+                inode.set_block(INDIRECT_SLOT, block);
+            }
+            (inode.block(INDIRECT_SLOT), index - DIRECT_BLOCKS)
+        } else if index < DIRECT_BLOCKS + per_block + per_block * per_block {
+            let index = index - DIRECT_BLOCKS - per_block;
+            if inode.block(DOUBLE_INDIRECT_SLOT) == 0 {
+                let block = self.alloc_block(goal, inode)?;
+                inode.set_block(DOUBLE_INDIRECT_SLOT, block);
+            }
+            let outer = inode.block(DOUBLE_INDIRECT_SLOT);
+            let mut table = self.read_pointer(outer, index / per_block)?;
+            if table == 0 {
+                table = self.alloc_block(goal, inode)?;
+                self.write_pointer(outer, index / per_block, table)?;
+            }
+            (table, index % per_block)
+        } else {
+            return Err(Ext2Error::FileTooLarge);
+        };
+
+        let mut block = self.read_pointer(table, slot)?;
+        if block == 0 {
+            block = self.alloc_block(goal, inode)?;
+            self.write_pointer(table, slot, block)?;
+        }
+        Ok(block)
+    }
+
+    /// Claim the first clear bit of a bitmap block, `None` if all are set
+    fn claim_bit(&self, bitmap_block: u32, limit: u32) -> Result<Option<u32>, Ext2Error> {
+        let mut bitmap = vec![0u8; self.block_size];
+        self.read_block(bitmap_block, &mut bitmap)?;
+        let Some(bit) = (0..limit).find(|&bit| bitmap[(bit / 8) as usize] & (1 << (bit % 8)) == 0) else {
+            return Ok(None);
+        };
+        bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+        self.write_block(bitmap_block, &bitmap)?;
+        Ok(Some(bit))
+    }
+
+    /// Allocate a zeroed block, preferring block group `goal`, and charge
+    /// it to `inode`
+    fn alloc_block(&self, goal: usize, inode: &mut Inode) -> Result<u32, Ext2Error> {
+        let mut volume = self.volume.lock();
+        let count = volume.groups.len();
+        for group in (0..count).map(|i| (goal + i) % count) {
+            if volume.groups[group].free_blocks == 0 {
+                continue;
+            }
+            let first = self.first_data_block + group as u32 * self.blocks_per_group;
+            let limit = self.blocks_per_group.min(self.blocks_count - first);
+            let Some(bit) = self.claim_bit(volume.groups[group].block_bitmap, limit)? else {
+                continue;
+            };
+            volume.groups[group].free_blocks -= 1;
+            volume.adjust_free_blocks(-1);
+            self.write_volume(&volume)?;
+            drop(volume);
+
+            let block = first + bit;
+            self.write_block(block, &vec![0u8; self.block_size])?;
+            inode.set_sectors(inode.sectors() + (self.block_size / 512) as u32);
+            return Ok(block);
+        }
+        Err(Ext2Error::NoSpace)
+    }
+
+    /// Allocate an inode number, preferring block group `goal`
+    fn alloc_inode(&self, goal: usize, directory: bool) -> Result<u32, Ext2Error> {
+        let mut volume = self.volume.lock();
+        let count = volume.groups.len();
+        for group in (0..count).map(|i| (goal + i) % count) {
+            if volume.groups[group].free_inodes == 0 {
+                continue;
+            }
+            let Some(bit) = self.claim_bit(volume.groups[group].inode_bitmap, self.inodes_per_group)? else {
+                continue;
+            };
+            let ino = group as u32 * self.inodes_per_group + bit + 1;
+            if ino < self.first_ino {
+                // Reserved inodes are marked in use by mkfs, a clear bit
+                // here means the bitmap is damaged
+                return Err(Ext2Error::Corrupt);
+            }
+            volume.groups[group].free_inodes -= 1;
+            if directory {
+                volume.groups[group].used_dirs += 1;
+            }
+            volume.adjust_free_inodes(-1);
+            self.write_volume(&volume)?;
+            return Ok(ino);
+        }
+        Err(Ext2Error::NoSpace)
+    }
+
+    /// Release a block back to its group's bitmap
+    fn free_block(&self, block: u32) -> Result<(), Ext2Error> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(Ext2Error::Corrupt);
+        }
+        let group = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+        let bit = (block - self.first_data_block) % self.blocks_per_group;
+        let mut volume = self.volume.lock();
+        let bitmap = volume.groups.get(group).ok_or(Ext2Error::Corrupt)?.block_bitmap;
+        self.clear_bit(bitmap, bit)?;
+        volume.groups[group].free_blocks += 1;
+        volume.adjust_free_blocks(1);
+        self.write_volume(&volume)
+    }
+
+    /// Release a pointer block and everything below it, `depth` levels of
+    /// pointers deep
+    fn free_tree(&self, table: u32, depth: u32) -> Result<(), Ext2Error> {
+        if table == 0 {
+            return Ok(());
+        }
+        if depth > 0 {
+            let mut pointers = vec![0u8; self.block_size];
+            self.read_block(table, &mut pointers)?;
+            for slot in 0..self.block_size / 4 {
+                self.free_tree(le32(&pointers, slot * 4), depth - 1)?;
+            }
+        }
+        self.free_block(table)
+    }
+
+    /// Release every block of `inode`
+    fn free_blocks_of(&self, inode: &Inode) -> Result<(), Ext2Error> {
+        // A fast symlink keeps its target in the block map and owns no blocks
+        if inode.sectors() == 0 {
+            return Ok(());
+        }
+        for slot in 0..INDIRECT_SLOT {
+            self.free_tree(inode.block(slot), 0)?;
+        }
+        self.free_tree(inode.block(INDIRECT_SLOT), 1)?;
+        self.free_tree(inode.block(DOUBLE_INDIRECT_SLOT), 2)?;
+        self.free_tree(inode.block(TRIPLE_INDIRECT_SLOT), 3)
+    }
+
+    /// Release an inode number
+    fn free_inode(&self, ino: u32, directory: bool) -> Result<(), Ext2Error> {
+        if ino < self.first_ino || ino > self.inodes_count {
+            return Err(Ext2Error::Corrupt);
+        }
+        let group = self.group_of_inode(ino);
+        let mut volume = self.volume.lock();
+        let bitmap = volume.groups.get(group).ok_or(Ext2Error::Corrupt)?.inode_bitmap;
+        self.clear_bit(bitmap, (ino - 1) % self.inodes_per_group)?;
+        volume.groups[group].free_inodes += 1;
+        if directory {
+            volume.groups[group].used_dirs -= 1;
+        }
+        volume.adjust_free_inodes(1);
+        self.write_volume(&volume)
+    }
+
+    /// Clear a set bit of a bitmap block, a clear one means the bitmap and
+    /// the block map disagree
+    fn clear_bit(&self, bitmap_block: u32, bit: u32) -> Result<(), Ext2Error> {
+        let mut bitmap = vec![0u8; self.block_size];
+        self.read_block(bitmap_block, &mut bitmap)?;
+        let byte = bitmap.get_mut((bit / 8) as usize).ok_or(Ext2Error::Corrupt)?;
+        if *byte & (1 << (bit % 8)) == 0 {
+            return Err(Ext2Error::Corrupt);
+        }
+        *byte &= !(1 << (bit % 8));
+        self.write_block(bitmap_block, &bitmap)
+    }
+
+    /// Call `visit(block, offset, entry)` for each live entry of a directory
+    /// until it returns true
+    fn walk_dir<F>(&self, ino: u32, mut visit: F) -> Result<(), Ext2Error>
+    where
+        F: FnMut(u32, usize, DirEntry) -> bool,
+    {
+        let inode = self.read_inode(ino)?;
+        if FileKind::from_mode(inode.mode()) != FileKind::Directory {
+            return Err(Ext2Error::NotADirectory);
+        }
+        let blocks = inode.size().div_ceil(self.block_size as u64);
+        let mut data = vec![0u8; self.block_size];
+        for index in 0..blocks {
+            crate::task::yield_if_needed();
+            let Some(block) = self.map_block(&inode, index)? else {
+                continue;
+            };
+            self.read_block(block, &mut data)?;
+            let mut offset = 0;
+            while offset + 8 <= self.block_size {
+                let entry_ino = le32(&data, offset);
+                let rec_len = le16(&data, offset + 4) as usize;
+                let name_len = data[offset + 6] as usize;
+                if rec_len < 8 || offset + rec_len > self.block_size || 8 + name_len > rec_len {
+                    return Err(Ext2Error::Corrupt);
+                }
+                if entry_ino != 0 {
+                    let name = String::from_utf8_lossy(&data[offset + 8..offset + 8 + name_len]).into_owned();
+                    let kind = match data[offset + 7] {
+                        FT_REG_FILE if self.filetype => FileKind::File,
+                        FT_DIR if self.filetype => FileKind::Directory,
+                        FT_SYMLINK if self.filetype => FileKind::Symlink,
+                        _ if self.filetype => FileKind::Other,
+                        _ => self.read_inode(entry_ino).map(|i| FileKind::from_mode(i.mode()))?,
+                    };
+                    if visit(block, offset, DirEntry { ino: entry_ino, name, kind }) {
+                        return Ok(());
+                    }
+                }
+                offset += rec_len;
+            }
+        }
+        Ok(())
+    }
+
+    fn find_entry(&self, dir: u32, name: &str) -> Result<u32, Ext2Error> {
+        let mut found = None;
+        self.walk_dir(dir, |_, _, entry| {
+            if entry.name == name {
+                found = Some(entry.ino);
+            }
+            found.is_some()
+        })?;
+        found.ok_or(Ext2Error::NotFound)
+    }
+
+    /// Link `ino` into directory `dir` as `name`, splitting the slack of an
+    /// existing entry or appending a block
+    fn add_entry(&self, dir: u32, dir_inode: &mut Inode, name: &str, ino: u32, kind: FileKind) -> Result<(), Ext2Error> {
+        let needed = dirent_len(name.len());
+        let file_type = self.dirent_type(kind);
+        let blocks = dir_inode.size().div_ceil(self.block_size as u64);
+        let mut data = vec![0u8; self.block_size];
+        for index in 0..blocks {
+            let Some(block) = self.map_block(dir_inode, index)? else {
+                continue;
+            };
+            self.read_block(block, &mut data)?;
+            let mut offset = 0;
+            while offset + 8 <= self.block_size {
+                let entry_ino = le32(&data, offset);
+                let rec_len = le16(&data, offset + 4) as usize;
+                let used = if entry_ino == 0 { 0 } else { dirent_len(data[offset + 6] as usize) };
+                if rec_len < 8 || offset + rec_len > self.block_size || used > rec_len {
+                    return Err(Ext2Error::Corrupt);
+                }
+                if rec_len - used >= needed {
+                    if used == 0 {
+                        write_dirent(&mut data, offset, ino, name, file_type, rec_len);
+                    } else {
+                        put16(&mut data, offset + 4, used as u16);
+                        write_dirent(&mut data, offset + used, ino, name, file_type, rec_len - used);
+                    }
+                    return self.write_block(block, &data);
+                }
+                offset += rec_len;
+            }
+        }
+
+        // Every block is full, append one holding just the new entry
+        let block = self.map_block_alloc(dir, dir_inode, blocks)?;
+        data.fill(0);
+        write_dirent(&mut data, 0, ino, name, file_type, self.block_size);
+        self.write_block(block, &data)?;
+        dir_inode.set_size((blocks + 1) * self.block_size as u64);
+        Ok(())
+    }
+}
+
+/// Record length a directory entry with a name of `name_len` bytes needs
+fn dirent_len(name_len: usize) -> usize {
+    (8 + name_len).next_multiple_of(4)
+}
+
+/// Write a directory entry at `offset`, returning its record length
+fn write_dirent(data: &mut [u8], offset: usize, ino: u32, name: &str, file_type: u8, rec_len: usize) -> usize {
+    put32(data, offset, ino);
+    put16(data, offset + 4, rec_len as u16);
+    data[offset + 6] = name.len() as u8;
+    data[offset + 7] = file_type;
+    data[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+    rec_len
+}
+
+/// A 64 KiB revision 1 volume of 1 KiB blocks in one group, holding
+/// `/hello` as inode 12
+///
+/// Blocks: 1 superblock, 2 group descriptors, 3 and 4 bitmaps, 5 and 6 the
+/// inode table, 7 the root directory and 8 the file.
+fn selftest_image() -> Vec<u8> {
+    let mut image = vec![0u8; 64 * 1024];
+    let superblock = &mut image[1024..2048];
+    put32(superblock, 0, 16);
+    put32(superblock, 4, 64);
+    put32(superblock, 12, 55);
+    put32(superblock, 16, 4);
+    put32(superblock, 20, 1);
+    put32(superblock, 32, 8192);
+    put32(superblock, 40, 16);
+    put16(superblock, 56, EXT2_MAGIC);
+    put32(superblock, 76, 1);
+    put32(superblock, 84, GOOD_OLD_FIRST_INO);
+    put16(superblock, 88, GOOD_OLD_INODE_SIZE as u16);
+    put32(superblock, 96, INCOMPAT_FILETYPE);
+
+    let group = &mut image[2048..2048 + GROUP_DESC_SIZE];
+    put32(group, 0, 3);
+    put32(group, 4, 4);
+    put32(group, 8, 5);
+    put16(group, 12, 55);
+    put16(group, 14, 4);
+    put16(group, 16, 1);
+    // Blocks 1 to 8 and inodes 1 to 12 in use
+    image[3 * 1024] = 0xFF;
+    image[4 * 1024] = 0xFF;
+    image[4 * 1024 + 1] = 0x0F;
+
+    for (ino, mode, size, links, block) in [(ROOT_INO, S_IFDIR | 0o755, 1024, 2, 7), (12, S_IFREG | 0o644, 5, 1, 8)] {
+        let mut inode = Inode { raw: [0; GOOD_OLD_INODE_SIZE] };
+        inode.set_mode(mode);
+        inode.set_size(size);
+        inode.set_links(links);
+        inode.set_sectors(2);
+        inode.set_block(0, block);
+        let offset = 5 * 1024 + (ino as usize - 1) * GOOD_OLD_INODE_SIZE;
+        image[offset..offset + GOOD_OLD_INODE_SIZE].copy_from_slice(&inode.raw);
+    }
+
+    let root = &mut image[7 * 1024..8 * 1024];
+    write_dirent(root, 0, ROOT_INO, ".", FT_DIR, 12);
+    write_dirent(root, 12, ROOT_INO, "..", FT_DIR, 12);
+    write_dirent(root, 24, 12, "hello", FT_REG_FILE, 1000);
+    image[8 * 1024..8 * 1024 + 5].copy_from_slice(b"hello");
+    image
+}
+
+/// Files on a RAM volume are looked up, read, created, grown and unlinked
+/// with the free counts following, a failed create is rolled back and a
+/// corrupt entry is refused
+fn selftest() -> Result<(), &'static str> {
+    let ext2 = Ext2::mount(Arc::new(super::RamDisk::new(512, selftest_image()))).map_err(|_| "mount failed")?;
+    let hello = ext2.lookup("/hello").map_err(|_| "lookup failed")?;
+    let mut buf = [0u8; 16];
+    if hello != 12 || ext2.read(hello, 0, &mut buf) != Ok(5) || &buf[..5] != b"hello" || ext2.read(hello, 5, &mut buf) != Ok(0) {
+        return Err("file read wrong");
+    }
+    let free = ext2.free();
+
+    let ino = ext2.create(ROOT_INO, "log", FileKind::File).map_err(|_| "create failed")?;
+    if ext2.lookup("/log") != Ok(ino) || ext2.create(ROOT_INO, "log", FileKind::File) != Err(Ext2Error::AlreadyExists) {
+        return Err("created file not linked once");
+    }
+    let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    ext2.write(ino, 0, &data[..1000]).map_err(|_| "write failed")?;
+    ext2.write(ino, 1000, &data[1000..]).map_err(|_| "growing write failed")?;
+    let mut back = vec![0u8; 4096];
+    if ext2.metadata(ino).map(|m| m.size) != Ok(3000) || ext2.read(ino, 0, &mut back) != Ok(3000) || back[..3000] != data[..] {
+        return Err("grown file read wrong");
+    }
+    if ext2.free() != (free.0 - 3, free.1 - 1) {
+        return Err("free counts wrong after write");
+    }
+
+    ext2.unlink(ROOT_INO, "log").map_err(|_| "unlink failed")?;
+    if ext2.lookup("/log") != Err(Ext2Error::NotFound) || ext2.free() != free {
+        return Err("unlinked file not freed");
+    }
+    if ext2.unlink(ROOT_INO, "..") != Err(Ext2Error::InvalidName) || ext2.lookup("/hello") != Ok(hello) {
+        return Err("unlink touched the wrong entries");
+    }
+
+    // A directory with no block left for it hands its inode back
+    ext2.volume.lock().groups[0].free_blocks = 0;
+    if ext2.create(ROOT_INO, "dir", FileKind::Directory) != Err(Ext2Error::NoSpace)
+        || ext2.lookup("/dir") != Err(Ext2Error::NotFound) || ext2.free() != free
+    {
+        return Err("failed create not rolled back");
+    }
+
+    // A name longer than its record
+    let mut image = selftest_image();
+    image[7 * 1024 + 12 + 6] = 200;
+    let ext2 = Ext2::mount(Arc::new(super::RamDisk::new(512, image))).map_err(|_| "corrupt mount failed")?;
+    if ext2.lookup("/hello") != Err(Ext2Error::Corrupt) || ext2.create(ROOT_INO, "new", FileKind::File) != Err(Ext2Error::Corrupt) {
+        return Err("corrupt entry accepted");
+    }
+    Ok(())
+}
+
+crate::selftest!("ext2", selftest);
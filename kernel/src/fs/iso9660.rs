@@ -0,0 +1,603 @@
+//! ISO9660 Filesystem
+//!
+//! Read-only driver for CD and DVD images, with Rock Ridge names, modes and
+//! symlinks when the volume carries them and plain 8.3 names otherwise. The
+//! El Torito boot catalog is parsed so the default boot image of a bootable
+//! disc can be located.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::{read_bytes, FileKind, S_IFDIR, S_IFREG};
+use crate::dev::{BlockDevice, DevError};
+use crate::time::rtc::DateTime;
+
+/// Logical sector size of every ISO9660 volume this driver accepts
+pub const SECTOR_SIZE: usize = 2048;
+/// First volume descriptor, after the system area
+const DESCRIPTORS_START: u64 = 16;
+/// Volume descriptors scanned before giving up on a terminator
+const MAX_DESCRIPTORS: u64 = 32;
+
+const STANDARD_ID: &[u8] = b"CD001";
+const DESC_BOOT_RECORD: u8 = 0;
+const DESC_PRIMARY: u8 = 1;
+const DESC_TERMINATOR: u8 = 255;
+const EL_TORITO_ID: &[u8] = b"EL TORITO SPECIFICATION";
+
+/// Fixed part of a directory record, before the name
+const RECORD_HEADER: usize = 33;
+const FLAG_DIRECTORY: u8 = 0x02;
+
+/// Continuation areas followed per record, bounds a looping CE chain
+const MAX_CONTINUATIONS: usize = 16;
+
+/// Boot catalog entry marking a bootable image
+const BOOT_INDICATOR: u8 = 0x88;
+
+/// Errors that can occur on ISO9660 volumes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Iso9660Error {
+    /// Underlying block device failed
+    Device(DevError),
+    /// No primary volume descriptor found
+    NotIso9660,
+    /// Descriptor or directory record out of range
+    Corrupt,
+    /// No entry with that name
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+}
+
+impl core::fmt::Display for Iso9660Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Iso9660Error::Device(e) => write!(f, "ISO9660 device error: {}", e),
+            Iso9660Error::NotIso9660 => write!(f, "Not an ISO9660 filesystem"),
+            Iso9660Error::Corrupt => write!(f, "ISO9660 metadata is corrupt"),
+            Iso9660Error::NotFound => write!(f, "No such file or directory"),
+            Iso9660Error::NotADirectory => write!(f, "Not a directory"),
+            Iso9660Error::IsADirectory => write!(f, "Is a directory"),
+        }
+    }
+}
+
+impl From<DevError> for Iso9660Error {
+    fn from(e: DevError) -> Self {
+        Iso9660Error::Device(e)
+    }
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Little-endian half of a both-endian field
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// A file or directory on the volume
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// First sector of the data
+    pub extent: u32,
+    pub size: u32,
+    pub kind: FileKind,
+    /// Permission bits, from Rock Ridge or 0o555 without it
+    pub mode: u16,
+    pub links: u32,
+    /// Recording time, Unix seconds
+    pub mtime: u64,
+    /// Rock Ridge symlink target
+    pub target: Option<String>,
+}
+
+/// One directory entry
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub node: Node,
+}
+
+/// Default entry of an El Torito boot catalog
+#[derive(Debug, Clone, Copy)]
+pub struct BootImage {
+    /// Platform ID of the validation entry, 0 x86, 0xEF EFI
+    pub platform: u8,
+    /// Emulation type, 0 for no emulation
+    pub media: u8,
+    /// First sector of the image
+    pub load_rba: u32,
+    /// Length in 512-byte virtual sectors
+    pub sector_count: u16,
+}
+
+/// What a directory record's Rock Ridge entries say about it
+#[derive(Default)]
+struct RockRidge {
+    name: Option<String>,
+    mode: Option<u16>,
+    links: Option<u32>,
+    target: Option<String>,
+    /// Separator needed before the next symlink component
+    target_separator: bool,
+    /// Directory moved here from deeper in the tree (CL)
+    child_link: Option<u32>,
+    /// Placeholder for a moved directory (RE), hidden from listings
+    relocated: bool,
+}
+
+/// A mounted ISO9660 volume
+pub struct Iso9660 {
+    device: Arc<dyn BlockDevice>,
+    volume_id: String,
+    volume_sectors: u32,
+    root: Node,
+    /// Bytes to skip at the start of each system use area, `None` without
+    /// Rock Ridge
+    susp_skip: Option<usize>,
+    boot_catalog: Option<u32>,
+}
+
+impl Iso9660 {
+    /// Mount the volume on `device`
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Self, Iso9660Error> {
+        if !SECTOR_SIZE.is_multiple_of(device.block_size()) {
+            return Err(Iso9660Error::Device(DevError::InvalidArgument));
+        }
+
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        let mut primary = None;
+        let mut boot_catalog = None;
+        for index in DESCRIPTORS_START..DESCRIPTORS_START + MAX_DESCRIPTORS {
+            read_sectors(&*device, index as u32, &mut sector)?;
+            if &sector[1..6] != STANDARD_ID {
+                break;
+            }
+            match sector[0] {
+                DESC_PRIMARY if primary.is_none() => primary = Some(sector.clone()),
+                DESC_BOOT_RECORD if sector[7..7 + EL_TORITO_ID.len()] == *EL_TORITO_ID => {
+                    boot_catalog = Some(le32(&sector, 0x47));
+                }
+                DESC_TERMINATOR => break,
+                _ => {}
+            }
+        }
+        let primary = primary.ok_or(Iso9660Error::NotIso9660)?;
+        if le16(&primary, 128) as usize != SECTOR_SIZE {
+            return Err(Iso9660Error::Corrupt);
+        }
+
+        let volume_id = String::from_utf8_lossy(&primary[40..72]).trim_end().into();
+        let mut fs = Iso9660 {
+            device,
+            volume_id,
+            volume_sectors: le32(&primary, 80),
+            root: Node {
+                extent: 0,
+                size: 0,
+                kind: FileKind::Directory,
+                mode: 0,
+                links: 0,
+                mtime: 0,
+                target: None,
+            },
+            susp_skip: None,
+            boot_catalog,
+        };
+        let root_record = &primary[156..156 + 34];
+        let (_, root) = fs.parse_record(root_record)?;
+        fs.root = root;
+
+        // Rock Ridge announces itself with an SP entry in the root's `.`
+        let mut dot = vec![0u8; SECTOR_SIZE];
+        fs.read_sector(fs.root.extent, &mut dot)?;
+        let length = dot[0] as usize;
+        if length >= RECORD_HEADER + 1 + 7 {
+            let area = &dot[RECORD_HEADER + 1..length];
+            if &area[0..2] == b"SP" && area[4] == 0xBE && area[5] == 0xEF {
+                fs.susp_skip = Some(area[6] as usize);
+                let (_, root) = fs.parse_record(&dot[..length])?;
+                fs.root = Node { kind: FileKind::Directory, ..root };
+            }
+        }
+        Ok(fs)
+    }
+
+    pub fn volume_id(&self) -> &str {
+        &self.volume_id
+    }
+
+    /// Volume size in bytes
+    pub fn size(&self) -> u64 {
+        self.volume_sectors as u64 * SECTOR_SIZE as u64
+    }
+
+    pub fn has_rock_ridge(&self) -> bool {
+        self.susp_skip.is_some()
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    /// Resolve an absolute path, symlinks are not followed
+    pub fn lookup(&self, path: &str) -> Result<Node, Iso9660Error> {
+        let mut node = self.root.clone();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            node = self
+                .read_dir(&node)?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .ok_or(Iso9660Error::NotFound)?
+                .node;
+        }
+        Ok(node)
+    }
+
+    /// List a directory, including `.` and `..`
+    pub fn read_dir(&self, dir: &Node) -> Result<Vec<DirEntry>, Iso9660Error> {
+        if dir.kind != FileKind::Directory {
+            return Err(Iso9660Error::NotADirectory);
+        }
+        let mut entries = Vec::new();
+        let mut data = vec![0u8; SECTOR_SIZE];
+        let sectors = (dir.size as usize).div_ceil(SECTOR_SIZE) as u32;
+        for index in 0..sectors {
+            crate::task::yield_if_needed();
+            self.read_sector(dir.extent + index, &mut data)?;
+            let mut offset = 0;
+            // Records never cross a sector, a zero length pads to the next
+            while offset < SECTOR_SIZE && data[offset] != 0 {
+                let length = data[offset] as usize;
+                if length < RECORD_HEADER || offset + length > SECTOR_SIZE {
+                    return Err(Iso9660Error::Corrupt);
+                }
+                if let Some(entry) = self.parse_entry(&data[offset..offset + length])? {
+                    entries.push(entry);
+                }
+                offset += length;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Read file data at `offset`, returning the bytes read, 0 at end of file
+    pub fn read(&self, file: &Node, offset: u64, buf: &mut [u8]) -> Result<usize, Iso9660Error> {
+        if file.kind == FileKind::Directory {
+            return Err(Iso9660Error::IsADirectory);
+        }
+        let size = file.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        read_bytes(
+            &*self.device,
+            file.extent as u64 * SECTOR_SIZE as u64 + offset,
+            &mut buf[..len],
+        )?;
+        Ok(len)
+    }
+
+    /// Default boot image of the El Torito catalog, `None` on a disc that
+    /// is not bootable
+    pub fn boot_image(&self) -> Result<Option<BootImage>, Iso9660Error> {
+        let Some(catalog) = self.boot_catalog else {
+            return Ok(None);
+        };
+        let mut data = vec![0u8; SECTOR_SIZE];
+        self.read_sector(catalog, &mut data)?;
+        // Validation entry: header 1, key 55 AA, words summing to zero
+        let checksum = data[..32]
+            .chunks_exact(2)
+            .fold(0u16, |sum, word| sum.wrapping_add(le16(word, 0)));
+        if data[0] != 1 || data[30] != 0x55 || data[31] != 0xAA || checksum != 0 {
+            return Err(Iso9660Error::Corrupt);
+        }
+        let entry = &data[32..64];
+        if entry[0] != BOOT_INDICATOR {
+            return Ok(None);
+        }
+        Ok(Some(BootImage {
+            platform: data[1],
+            media: entry[1],
+            load_rba: le32(entry, 8),
+            sector_count: le16(entry, 6),
+        }))
+    }
+
+    fn read_sector(&self, sector: u32, buf: &mut [u8]) -> Result<(), Iso9660Error> {
+        if sector >= self.volume_sectors {
+            return Err(Iso9660Error::Corrupt);
+        }
+        read_sectors(&*self.device, sector, buf)?;
+        Ok(())
+    }
+
+    /// Directory entry for a record, `None` for relocated directories
+    fn parse_entry(&self, record: &[u8]) -> Result<Option<DirEntry>, Iso9660Error> {
+        let (rock_ridge, mut node) = self.parse_record(record)?;
+        if rock_ridge.relocated {
+            return Ok(None);
+        }
+        let name = match rock_ridge.name {
+            Some(name) => name,
+            None => plain_name(&record[RECORD_HEADER..RECORD_HEADER + record[32] as usize]),
+        };
+        if let Some(extent) = rock_ridge.child_link {
+            // The real directory lives elsewhere, its `.` record has the size
+            let mut data = vec![0u8; SECTOR_SIZE];
+            self.read_sector(extent, &mut data)?;
+            node.extent = extent;
+            node.size = le32(&data, 10);
+            node.kind = FileKind::Directory;
+        }
+        Ok(Some(DirEntry { name, node }))
+    }
+
+    /// Node for a directory record, plus its Rock Ridge entries
+    fn parse_record(&self, record: &[u8]) -> Result<(RockRidge, Node), Iso9660Error> {
+        let name_len = record[32] as usize;
+        if RECORD_HEADER + name_len > record.len() {
+            return Err(Iso9660Error::Corrupt);
+        }
+        let directory = record[25] & FLAG_DIRECTORY != 0;
+        let mut rock_ridge = RockRidge::default();
+        if let Some(skip) = self.susp_skip {
+            // The name is padded to an even offset
+            let start = RECORD_HEADER + name_len + (name_len + 1) % 2 + skip;
+            if start < record.len() {
+                self.parse_susp(&record[start..], &mut rock_ridge)?;
+            }
+        }
+
+        let kind = match rock_ridge.mode {
+            Some(mode) => FileKind::from_mode(mode),
+            None if directory => FileKind::Directory,
+            None => FileKind::File,
+        };
+        let default_mode = if directory { S_IFDIR | 0o555 } else { S_IFREG | 0o444 };
+        let node = Node {
+            extent: le32(record, 2),
+            size: le32(record, 10),
+            kind,
+            mode: rock_ridge.mode.unwrap_or(default_mode) & 0o7777,
+            links: rock_ridge.links.unwrap_or(if directory { 2 } else { 1 }),
+            mtime: recording_time(&record[18..25]),
+            target: rock_ridge.target.take(),
+        };
+        Ok((rock_ridge, node))
+    }
+
+    /// Walk a system use area and the continuation areas it points to
+    fn parse_susp(&self, area: &[u8], rock_ridge: &mut RockRidge) -> Result<(), Iso9660Error> {
+        let mut area = Vec::from(area);
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut offset = 0;
+            while offset + 4 <= area.len() {
+                let length = area[offset + 2] as usize;
+                if length < 4 || offset + length > area.len() {
+                    break;
+                }
+                let entry = &area[offset..offset + length];
+                match &entry[0..2] {
+                    b"CE" if length >= 28 => {
+                        continuation = Some((le32(entry, 4), le32(entry, 12), le32(entry, 20)));
+                    }
+                    b"ST" => break,
+                    _ => parse_rock_ridge(entry, rock_ridge),
+                }
+                offset += length;
+            }
+
+            let Some((sector, start, length)) = continuation else {
+                return Ok(());
+            };
+            if start as usize + length as usize > SECTOR_SIZE {
+                return Err(Iso9660Error::Corrupt);
+            }
+            let mut data = vec![0u8; SECTOR_SIZE];
+            self.read_sector(sector, &mut data)?;
+            area = Vec::from(&data[start as usize..(start + length) as usize]);
+        }
+        Ok(())
+    }
+}
+
+/// Apply one Rock Ridge entry
+fn parse_rock_ridge(entry: &[u8], rock_ridge: &mut RockRidge) {
+    let length = entry.len();
+    match &entry[0..2] {
+        // Alternate name, possibly split over several entries
+        b"NM" if length >= 5 => {
+            let flags = entry[4];
+            let name = rock_ridge.name.get_or_insert_with(String::new);
+            if flags & 0x02 != 0 {
+                name.push('.');
+            } else if flags & 0x04 != 0 {
+                name.push_str("..");
+            } else {
+                name.push_str(&String::from_utf8_lossy(&entry[5..]));
+            }
+        }
+        // POSIX attributes
+        b"PX" if length >= 20 => {
+            rock_ridge.mode = Some(le32(entry, 4) as u16);
+            rock_ridge.links = Some(le32(entry, 12));
+        }
+        // Symlink target as path components
+        b"SL" if length >= 5 => {
+            let target = rock_ridge.target.get_or_insert_with(String::new);
+            let mut offset = 5;
+            while offset + 2 <= length {
+                let flags = entry[offset];
+                let len = entry[offset + 1] as usize;
+                if offset + 2 + len > length {
+                    break;
+                }
+                if flags & 0x08 != 0 {
+                    target.push('/');
+                    rock_ridge.target_separator = false;
+                } else {
+                    if rock_ridge.target_separator {
+                        target.push('/');
+                    }
+                    if flags & 0x02 != 0 {
+                        target.push('.');
+                    } else if flags & 0x04 != 0 {
+                        target.push_str("..");
+                    } else {
+                        target.push_str(&String::from_utf8_lossy(&entry[offset + 2..offset + 2 + len]));
+                    }
+                    // A continued component carries on without a separator
+                    rock_ridge.target_separator = flags & 0x01 == 0;
+                }
+                offset += 2 + len;
+            }
+        }
+        b"CL" if length >= 12 => rock_ridge.child_link = Some(le32(entry, 4)),
+        b"RE" => rock_ridge.relocated = true,
+        _ => {}
+    }
+}
+
+/// Name of a record without Rock Ridge: `FILE.TXT;1` becomes `file.txt`
+fn plain_name(raw: &[u8]) -> String {
+    match raw {
+        [0] => return ".".into(),
+        [1] => return "..".into(),
+        _ => {}
+    }
+    let name = raw.split(|&byte| byte == b';').next().unwrap_or(raw);
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    String::from_utf8_lossy(name).to_ascii_lowercase()
+}
+
+/// Unix time of a 7-byte directory record date
+fn recording_time(date: &[u8]) -> u64 {
+    let time = DateTime {
+        year: 1900 + date[0] as u16,
+        month: date[1].max(1),
+        day: date[2].max(1),
+        hour: date[3],
+        minute: date[4],
+        second: date[5],
+    };
+    // The last byte is the offset from UTC in 15 minute steps
+    let offset = date[6] as i8 as i64 * 15 * 60;
+    (time.to_unix() as i64 - offset).max(0) as u64
+}
+
+fn read_sectors(device: &dyn BlockDevice, sector: u32, buf: &mut [u8]) -> Result<(), DevError> {
+    let per_sector = (SECTOR_SIZE / device.block_size()) as u64;
+    device.read_blocks(sector as u64 * per_sector, buf)
+}
+
+/// Directory record for the self-test image, recorded 2000-01-01
+fn selftest_record(extent: u32, size: u32, flags: u8, name: &[u8], system_use: &[u8]) -> Vec<u8> {
+    let start = RECORD_HEADER + name.len() + (name.len() + 1) % 2;
+    let mut record = vec![0u8; start + system_use.len()];
+    record[0] = record.len() as u8;
+    record[2..6].copy_from_slice(&extent.to_le_bytes());
+    record[6..10].copy_from_slice(&extent.to_be_bytes());
+    record[10..14].copy_from_slice(&size.to_le_bytes());
+    record[14..18].copy_from_slice(&size.to_be_bytes());
+    record[18..21].copy_from_slice(&[100, 1, 1]);
+    record[25] = flags;
+    record[28] = 1;
+    record[32] = name.len() as u8;
+    record[RECORD_HEADER..RECORD_HEADER + name.len()].copy_from_slice(name);
+    record[start..].copy_from_slice(system_use);
+    record
+}
+
+/// A 24-sector Rock Ridge volume: descriptors at 16 to 18, the boot
+/// catalog at 19, the root directory at 20 and the file data at 21
+fn selftest_image() -> Vec<u8> {
+    let mut image = vec![0u8; 24 * SECTOR_SIZE];
+    let sector = |index: usize| index * SECTOR_SIZE;
+
+    let primary = sector(16);
+    image[primary] = DESC_PRIMARY;
+    image[primary + 1..primary + 6].copy_from_slice(STANDARD_ID);
+    image[primary + 40..primary + 72].fill(b' ');
+    image[primary + 40..primary + 48].copy_from_slice(b"SELFTEST");
+    image[primary + 80..primary + 84].copy_from_slice(&24u32.to_le_bytes());
+    image[primary + 128..primary + 130].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    let root = selftest_record(20, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[0], &[]);
+    image[primary + 156..primary + 156 + root.len()].copy_from_slice(&root);
+
+    let boot = sector(17);
+    image[boot] = DESC_BOOT_RECORD;
+    image[boot + 1..boot + 6].copy_from_slice(STANDARD_ID);
+    image[boot + 7..boot + 7 + EL_TORITO_ID.len()].copy_from_slice(EL_TORITO_ID);
+    image[boot + 0x47..boot + 0x4B].copy_from_slice(&19u32.to_le_bytes());
+    image[sector(18)] = DESC_TERMINATOR;
+    image[sector(18) + 1..sector(18) + 6].copy_from_slice(STANDARD_ID);
+
+    let catalog = sector(19);
+    image[catalog] = 1;
+    image[catalog + 30..catalog + 32].copy_from_slice(&[0x55, 0xAA]);
+    let sum = image[catalog..catalog + 32].chunks_exact(2).fold(0u16, |sum, word| sum.wrapping_add(le16(word, 0)));
+    image[catalog + 28..catalog + 30].copy_from_slice(&sum.wrapping_neg().to_le_bytes());
+    image[catalog + 32] = BOOT_INDICATOR;
+    image[catalog + 38..catalog + 40].copy_from_slice(&4u16.to_le_bytes());
+    image[catalog + 40..catalog + 44].copy_from_slice(&22u32.to_le_bytes());
+
+    let records = [
+        selftest_record(20, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[0], b"SP\x07\x01\xBE\xEF\x00"),
+        selftest_record(20, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[1], &[]),
+        selftest_record(21, 11, 0, b"README.TXT;1", &[]),
+        selftest_record(21, 11, 0, b"LONGNA.TXT;1", b"NM\x12\x01\x00Long Name.txt"),
+    ];
+    let mut offset = sector(20);
+    for record in records {
+        image[offset..offset + record.len()].copy_from_slice(&record);
+        offset += record.len();
+    }
+    image[sector(21)..sector(21) + 11].copy_from_slice(b"hello world");
+    image
+}
+
+/// A RAM volume mounts with Rock Ridge, plain and alternate names resolve,
+/// reads stop at the end of the file, and the boot catalog is checked
+fn selftest() -> Result<(), &'static str> {
+    let iso = Iso9660::mount(Arc::new(super::RamDisk::new(512, selftest_image()))).map_err(|_| "mount failed")?;
+    if iso.volume_id() != "SELFTEST" || iso.size() != 24 * SECTOR_SIZE as u64 || !iso.has_rock_ridge() {
+        return Err("primary descriptor read wrong");
+    }
+    let names: Vec<String> = iso.read_dir(iso.root()).map_err(|_| "read_dir failed")?.into_iter().map(|entry| entry.name).collect();
+    if names != [".", "..", "readme.txt", "Long Name.txt"] {
+        return Err("directory names wrong");
+    }
+    let file = iso.lookup("/Long Name.txt").map_err(|_| "Rock Ridge name not found")?;
+    let mut buf = [0u8; 16];
+    if iso.read(&file, 6, &mut buf) != Ok(5) || &buf[..5] != b"world" {
+        return Err("read up to end of file wrong");
+    }
+    if iso.read(&file, 11, &mut buf) != Ok(0) || iso.read(&file, 100, &mut buf) != Ok(0) {
+        return Err("read at or past end of file returned data");
+    }
+    if iso.lookup("/readme.txt").map(|node| node.extent) != Ok(21) || iso.lookup("/README.TXT").is_ok() {
+        return Err("plain name resolved wrong");
+    }
+    match iso.boot_image() {
+        Ok(Some(image)) if image.platform == 0 && image.load_rba == 22 && image.sector_count == 4 => {}
+        _ => return Err("boot image not found"),
+    }
+
+    let mut image = selftest_image();
+    image[19 * SECTOR_SIZE + 28] ^= 1;
+    let iso = Iso9660::mount(Arc::new(super::RamDisk::new(512, image))).map_err(|_| "second mount failed")?;
+    if !matches!(iso.boot_image(), Err(Iso9660Error::Corrupt)) {
+        return Err("bad catalog checksum accepted");
+    }
+    Ok(())
+}
+
+crate::selftest!("iso9660", selftest);
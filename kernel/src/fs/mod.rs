@@ -0,0 +1,105 @@
+//! Filesystems
+
+pub mod ext2;
+pub mod iso9660;
+pub mod tmpfs;
+
+pub use ext2::Ext2;
+pub use iso9660::Iso9660;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::dev::{BlockDevice, DevError};
+
+// POSIX file type bits of a mode, shared by ext2 inodes and Rock Ridge
+pub(crate) const S_IFMT: u16 = 0xF000;
+pub(crate) const S_IFREG: u16 = 0x8000;
+pub(crate) const S_IFDIR: u16 = 0x4000;
+pub(crate) const S_IFLNK: u16 = 0xA000;
+
+/// Kind of a directory entry or inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+impl FileKind {
+    /// Kind encoded in the type bits of a POSIX mode
+    pub fn from_mode(mode: u16) -> Self {
+        match mode & S_IFMT {
+            S_IFREG => FileKind::File,
+            S_IFDIR => FileKind::Directory,
+            S_IFLNK => FileKind::Symlink,
+            _ => FileKind::Other,
+        }
+    }
+}
+
+/// Read bytes at any byte offset of a block device
+pub(crate) fn read_bytes(device: &dyn BlockDevice, offset: u64, buf: &mut [u8]) -> Result<(), DevError> {
+    let sector = device.block_size() as u64;
+    let first = offset / sector;
+    let last = (offset + buf.len() as u64).div_ceil(sector);
+    let mut data = vec![0u8; ((last - first) * sector) as usize];
+    device.read_blocks(first, &mut data)?;
+    let start = (offset - first * sector) as usize;
+    buf.copy_from_slice(&data[start..start + buf.len()]);
+    Ok(())
+}
+
+/// Write bytes at any byte offset of a block device, read-modify-write on
+/// partial blocks
+pub(crate) fn write_bytes(device: &dyn BlockDevice, offset: u64, buf: &[u8]) -> Result<(), DevError> {
+    let sector = device.block_size() as u64;
+    let first = offset / sector;
+    let last = (offset + buf.len() as u64).div_ceil(sector);
+    let mut data = vec![0u8; ((last - first) * sector) as usize];
+    device.read_blocks(first, &mut data)?;
+    let start = (offset - first * sector) as usize;
+    data[start..start + buf.len()].copy_from_slice(buf);
+    device.write_blocks(first, &data)
+}
+
+/// Block device held in memory, for the filesystem self-tests
+pub(crate) struct RamDisk {
+    block_size: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// `image` is cut to whole blocks of `block_size` bytes
+    pub(crate) fn new(block_size: usize, mut image: Vec<u8>) -> Self {
+        image.truncate(image.len() / block_size * block_size);
+        RamDisk { block_size, data: Mutex::new(image) }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), DevError> {
+        let data = self.data.lock();
+        let start = lba as usize * self.block_size;
+        let blocks = data.get(start..start + buf.len()).ok_or(DevError::InvalidArgument)?;
+        buf.copy_from_slice(blocks);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), DevError> {
+        let mut data = self.data.lock();
+        let start = lba as usize * self.block_size;
+        let blocks = data.get_mut(start..start + buf.len()).ok_or(DevError::InvalidArgument)?;
+        blocks.copy_from_slice(buf);
+        Ok(())
+    }
+}
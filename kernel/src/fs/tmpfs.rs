@@ -0,0 +1,112 @@
+//! tmpfs
+//!
+//! Files held in memory until reboot, for what is pushed onto a running
+//! system: test binaries, fonts, configuration. The namespace is flat and
+//! stands in for a `/tmp` mount until there is a VFS; names may be given
+//! with or without the `/tmp/` prefix. Contents are replaced whole, and
+//! together may not grow past `CAPACITY`.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Path prefix of tmpfs files
+pub const TMP_PREFIX: &str = "/tmp/";
+/// Bytes all files together may hold
+pub const CAPACITY: usize = 16 * 1024 * 1024;
+
+const MAX_NAME_LEN: usize = 255;
+
+/// Errors that can occur on tmpfs files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TmpfsError {
+    /// Name empty, too long, `.`, `..` or containing `/`
+    InvalidName,
+    /// No file with that name
+    NotFound,
+    /// The contents would not fit in `CAPACITY`
+    NoSpace,
+}
+
+impl core::fmt::Display for TmpfsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TmpfsError::InvalidName => write!(f, "Invalid tmpfs file name"),
+            TmpfsError::NotFound => write!(f, "No such tmpfs file"),
+            TmpfsError::NoSpace => write!(f, "tmpfs full"),
+        }
+    }
+}
+
+/// Files by name
+static FILES: Mutex<BTreeMap<String, Arc<[u8]>>> = Mutex::new(BTreeMap::new());
+
+/// `name` without the `/tmp/` prefix, if it is a valid file name
+pub fn check_name(name: &str) -> Result<&str, TmpfsError> {
+    let name = name.strip_prefix(TMP_PREFIX).unwrap_or(name);
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('/') || name == "." || name == ".." {
+        return Err(TmpfsError::InvalidName);
+    }
+    Ok(name)
+}
+
+fn used(files: &BTreeMap<String, Arc<[u8]>>) -> usize {
+    files.values().map(|data| data.len()).sum()
+}
+
+/// Create `name` or replace its contents
+pub fn write(name: &str, data: Vec<u8>) -> Result<(), TmpfsError> {
+    let name = check_name(name)?;
+    let mut files = FILES.lock();
+    let replaced = files.get(name).map_or(0, |old| old.len());
+    if used(&files) - replaced + data.len() > CAPACITY {
+        return Err(TmpfsError::NoSpace);
+    }
+    files.insert(name.to_string(), data.into());
+    Ok(())
+}
+
+/// Contents of `name`, which stay valid if the file is replaced or removed
+pub fn read(name: &str) -> Result<Arc<[u8]>, TmpfsError> {
+    let name = check_name(name)?;
+    FILES.lock().get(name).cloned().ok_or(TmpfsError::NotFound)
+}
+
+/// Remove `name`
+pub fn remove(name: &str) -> Result<(), TmpfsError> {
+    let name = check_name(name)?;
+    FILES.lock().remove(name).map(|_| ()).ok_or(TmpfsError::NotFound)
+}
+
+/// Every file as (name, size), in name order
+pub fn list() -> Vec<(String, usize)> {
+    FILES.lock().iter().map(|(name, data)| (name.clone(), data.len())).collect()
+}
+
+/// Bytes still free
+pub fn available() -> usize {
+    CAPACITY - used(&FILES.lock())
+}
+
+/// Files are created, replaced, read and removed, and bad names refused
+fn selftest() -> Result<(), &'static str> {
+    let name = "/tmp/selftest";
+    write(name, b"first".to_vec()).map_err(|_| "write failed")?;
+    let first = read("selftest").map_err(|_| "file not found without prefix")?;
+    write(name, b"second".to_vec()).map_err(|_| "replace failed")?;
+    if &*first != b"first" || &*read(name).map_err(|_| "replaced file lost")? != b"second" {
+        return Err("contents wrong after replacing");
+    }
+    if write("a/b", Vec::new()) != Err(TmpfsError::InvalidName) || write("..", Vec::new()) != Err(TmpfsError::InvalidName) {
+        return Err("invalid name accepted");
+    }
+    remove(name).map_err(|_| "remove failed")?;
+    if read(name) != Err(TmpfsError::NotFound) {
+        return Err("removed file still readable");
+    }
+    Ok(())
+}
+
+crate::selftest!("tmpfs", selftest);
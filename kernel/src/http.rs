@@ -0,0 +1,218 @@
+//! HTTP Stats Server
+//!
+//! Answers HTTP/1.0 requests with the kernel's statistics as plain text,
+//! so a running machine can be watched with `curl` or a browser:
+//!
+//! - `/stats`: uptime, load, CPU time, interrupt, task and memory totals,
+//!   one `name value` pair per line
+//! - `/log`: the kernel log
+//! - `/proc/meminfo`: the `meminfo` report
+//!
+//! `GET` and `HEAD` are served, one request per connection, which is closed
+//! after the response as HTTP/1.0 expects. A request head is read up to
+//! `MAX_REQUEST` bytes; anything after it is ignored.
+//!
+//! Connections reach the server as a `Stream`, so it is ready for a TCP
+//! listener on `PORT` to hand them over.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use crate::console::klog;
+use crate::stats;
+use crate::task;
+use crate::time;
+
+/// Port the server listens on
+pub const PORT: u16 = 80;
+/// Longest request head read
+pub const MAX_REQUEST: usize = 4096;
+
+/// One client connection
+pub trait Stream {
+    /// Read into `buf`, returns how many bytes, 0 once the client is done
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+    fn write(&mut self, data: &[u8]);
+}
+
+/// Response status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    BadRequest,
+    NotFound,
+    NotImplemented,
+}
+
+impl Status {
+    fn line(self) -> &'static str {
+        match self {
+            Status::Ok => "200 OK",
+            Status::BadRequest => "400 Bad Request",
+            Status::NotFound => "404 Not Found",
+            Status::NotImplemented => "501 Not Implemented",
+        }
+    }
+}
+
+/// Serve one request on `stream`
+pub fn serve(stream: &mut dyn Stream) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 512];
+    while !head_complete(&request) && request.len() < MAX_REQUEST {
+        let count = stream.read(&mut buf);
+        if count == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..count]);
+    }
+    stream.write(&respond(&request));
+}
+
+/// Whether `request` holds a whole request head
+fn head_complete(request: &[u8]) -> bool {
+    request.windows(4).any(|window| window == b"\r\n\r\n") || request.windows(2).any(|window| window == b"\n\n")
+}
+
+/// The response to `request`, headers and body
+pub fn respond(request: &[u8]) -> Vec<u8> {
+    let line = request.split(|&byte| byte == b'\n').next().unwrap_or_default();
+    let line = core::str::from_utf8(line).unwrap_or_default().trim_end_matches('\r');
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return response(Status::BadRequest, "Bad request\n", true);
+    };
+    if !version.starts_with("HTTP/1.") || !target.starts_with('/') {
+        return response(Status::BadRequest, "Bad request\n", true);
+    }
+    let head = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return response(Status::NotImplemented, "Only GET and HEAD are served\n", true),
+    };
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    let mut body = String::new();
+    let status = if route(path, &mut body) {
+        Status::Ok
+    } else {
+        body = String::from("Not found\n");
+        Status::NotFound
+    };
+    response(status, &body, !head)
+}
+
+/// Write the page at `path` into `body`, `false` if there is none
+fn route(path: &str, body: &mut String) -> bool {
+    // Writing to a `String` cannot fail
+    let _ = match path {
+        "/stats" => write_stats(body),
+        "/log" => write_log(body),
+        "/proc/meminfo" => stats::memory().write_report(body),
+        _ => return false,
+    };
+    true
+}
+
+fn response(status: Status, body: &str, with_body: bool) -> Vec<u8> {
+    let mut response = String::new();
+    let _ = write!(
+        response,
+        "HTTP/1.0 {}\r\nServer: CosmOS\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status.line(),
+        body.len()
+    );
+    if with_body {
+        response.push_str(body);
+    }
+    response.into_bytes()
+}
+
+fn write_stats(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "uptime_ms {}", time::uptime_ms())?;
+    let loads = task::load::cpus();
+    for cpu in stats::cpus().iter().filter(|cpu| cpu.total_cycles != 0) {
+        if let Some(load) = loads.iter().find(|load| load.cpu == cpu.cpu) {
+            let [one, five, fifteen] = load.averages;
+            writeln!(out, "cpu{}_load {} {} {}", cpu.cpu, one, five, fifteen)?;
+            writeln!(out, "cpu{}_switches {}", cpu.cpu, load.switches)?;
+        }
+        writeln!(out, "cpu{}_busy_percent {}", cpu.cpu, cpu.busy_percent())?;
+        writeln!(out, "cpu{}_idle_percent {}", cpu.cpu, cpu.idle_percent())?;
+        writeln!(out, "cpu{}_irq_percent {}", cpu.cpu, cpu.irq_percent())?;
+    }
+    let interrupts = stats::interrupts();
+    writeln!(out, "interrupts {}", interrupts.total)?;
+    writeln!(out, "interrupts_spurious {}", interrupts.spurious)?;
+    writeln!(out, "interrupts_unhandled {}", interrupts.unhandled)?;
+    writeln!(out, "tasks {}", task::scheduler::tasks().len())?;
+    let memory = stats::memory();
+    writeln!(out, "heap_total_bytes {}", memory.heap.total_size)?;
+    writeln!(out, "heap_used_bytes {}", memory.heap.used_size)?;
+    if let Some(frames) = memory.frames {
+        writeln!(out, "frames_total {}", frames.total_frames)?;
+        writeln!(out, "frames_used {}", frames.allocated_frames)?;
+    }
+    Ok(())
+}
+
+fn write_log(out: &mut dyn Write) -> fmt::Result {
+    let mut log = alloc::vec![0u8; klog::KLOG_SIZE];
+    let count = klog::tail(&mut log).unwrap_or(0);
+    out.write_str(&String::from_utf8_lossy(&log[..count]))
+}
+
+/// Scripted connection for the self-test, handing out the request in
+/// pieces
+struct ScriptStream {
+    pieces: alloc::collections::VecDeque<&'static [u8]>,
+    output: Vec<u8>,
+}
+
+impl Stream for ScriptStream {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let Some(piece) = self.pieces.pop_front() else {
+            return 0;
+        };
+        buf[..piece.len()].copy_from_slice(piece);
+        piece.len()
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        self.output.extend_from_slice(data);
+    }
+}
+
+/// Pages are served, a split request is put together, and bad requests,
+/// unknown paths and other methods get their error status
+fn selftest() -> Result<(), &'static str> {
+    let mut stream = ScriptStream {
+        pieces: [&b"GET /stats?x=1 HT"[..], &b"TP/1.0\r\nHost: cosmos\r\n\r\n"[..]].into_iter().collect(),
+        output: Vec::new(),
+    };
+    serve(&mut stream);
+    let output = core::str::from_utf8(&stream.output).map_err(|_| "response not UTF-8")?;
+    let (head, body) = output.split_once("\r\n\r\n").ok_or("response head not ended")?;
+    if !head.starts_with("HTTP/1.0 200 OK\r\n") || !body.starts_with("uptime_ms ") {
+        return Err("stats not served");
+    }
+    if !head.contains(&alloc::format!("Content-Length: {}\r\n", body.len())) {
+        return Err("wrong content length");
+    }
+
+    let meminfo = respond(b"HEAD /proc/meminfo HTTP/1.1\r\n\r\n");
+    if !meminfo.starts_with(b"HTTP/1.0 200 OK\r\n") || !meminfo.ends_with(b"\r\n\r\n") {
+        return Err("HEAD response wrong");
+    }
+    let cases: [(&[u8], &[u8]); 4] = [
+        (b"GET /missing HTTP/1.0\r\n\r\n", b"HTTP/1.0 404 "),
+        (b"POST /stats HTTP/1.0\r\n\r\n", b"HTTP/1.0 501 "),
+        (b"GET /stats\r\n\r\n", b"HTTP/1.0 400 "),
+        (b"\r\n\r\n", b"HTTP/1.0 400 "),
+    ];
+    if cases.iter().any(|(request, status)| !respond(request).starts_with(status)) {
+        return Err("wrong error status");
+    }
+    Ok(())
+}
+
+crate::selftest!("http", selftest);
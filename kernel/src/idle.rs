@@ -0,0 +1,82 @@
+//! CPU idle management
+//!
+//! Idles with MWAIT when the CPU supports it, otherwise HLT, and accounts
+//! the time spent idle in the CPU statistics.
+
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use crate::arch::x86_64::cpu::{self, MAX_CPUS};
+use crate::stats;
+
+/// How the CPU waits for work
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleMethod {
+    Hlt = 0,
+    Mwait = 1,
+}
+
+impl IdleMethod {
+    /// Method name
+    pub fn name(self) -> &'static str {
+        match self {
+            IdleMethod::Hlt => "hlt",
+            IdleMethod::Mwait => "mwait",
+        }
+    }
+}
+
+/// CPUID.1:ECX MONITOR/MWAIT support
+const CPUID_MONITOR: u32 = 1 << 3;
+
+/// MWAIT hint for C1, the shallowest state with the lowest exit latency
+const MWAIT_HINT_C1: u32 = 0;
+
+static METHOD: AtomicU8 = AtomicU8::new(IdleMethod::Hlt as u8);
+
+/// Cache line monitored by MWAIT, one per CPU
+#[repr(align(64))]
+struct WakeLine(AtomicU64);
+
+static WAKE_LINES: [WakeLine; MAX_CPUS] = [const { WakeLine(AtomicU64::new(0)) }; MAX_CPUS];
+
+/// Pick the idle method for this CPU
+pub fn init() {
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    if features.ecx & CPUID_MONITOR != 0 {
+        METHOD.store(IdleMethod::Mwait as u8, Ordering::Relaxed);
+    }
+    stats::cpu::init();
+    crate::serial_println!("Idle method: {}", method().name());
+}
+
+/// Idle method in use
+pub fn method() -> IdleMethod {
+    match METHOD.load(Ordering::Relaxed) {
+        1 => IdleMethod::Mwait,
+        _ => IdleMethod::Hlt,
+    }
+}
+
+/// Wait for an interrupt, returning with interrupts enabled
+///
+/// Call with interrupts disabled, after checking for work, so a wakeup in
+/// between is not lost: interrupts are enabled atomically with the wait.
+pub fn wait() {
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    match method() {
+        IdleMethod::Hlt => x86_64::instructions::interrupts::enable_and_hlt(),
+        IdleMethod::Mwait => unsafe {
+            let line = &WAKE_LINES[cpu::current_id()].0 as *const AtomicU64;
+            core::arch::asm!("monitor", in("rax") line, in("ecx") 0, in("edx") 0, options(nostack));
+            // STI shadows the next instruction, so no interrupt slips in before MWAIT
+            core::arch::asm!("sti; mwait", in("eax") MWAIT_HINT_C1, in("ecx") 0, options(nostack));
+        },
+    }
+    let cycles = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(start);
+    stats::cpu::record_idle(cycles);
+}
+
+/// Wake a CPU idling in MWAIT without sending an interrupt
+pub fn kick(cpu: usize) {
+    WAKE_LINES[cpu].0.fetch_add(1, Ordering::Release);
+}
@@ -0,0 +1,155 @@
+//! Terminal Escape Sequences
+//!
+//! Serial terminals send editing and cursor keys as ANSI escape
+//! sequences: `ESC [ A` for Up, `ESC [ 3 ~` for Delete, `ESC O A` in
+//! application cursor mode. The decoder turns a byte stream back into
+//! plain bytes and those keys; `encode` goes the other way, so keyboards
+//! can drive a terminal's line editing.
+
+use super::{Key, Modifiers};
+
+const ESC: u8 = 0x1B;
+
+/// One decoded unit of terminal input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoded {
+    /// A byte that is not part of an escape sequence
+    Byte(u8),
+    /// An editing key
+    Key(Key),
+}
+
+/// Progress through an escape sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// ESC received
+    Escape,
+    /// `ESC [` and the first numeric parameter so far
+    Csi(u8),
+    /// Past the first parameter of a CSI sequence, only waiting for its end
+    CsiRest(u8),
+    /// `ESC O`
+    Ss3,
+}
+
+/// Escape sequence decoder for one input stream
+#[derive(Debug, Clone, Copy)]
+pub struct Decoder {
+    state: State,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder { state: State::Ground }
+    }
+
+    /// Drop a partial sequence
+    pub fn reset(&mut self) {
+        self.state = State::Ground;
+    }
+
+    /// Run `byte` through the decoder
+    ///
+    /// Returns `None` while a sequence is incomplete and for sequences that
+    /// are not editing keys, which are dropped.
+    pub fn feed(&mut self, byte: u8) -> Option<Decoded> {
+        match (core::mem::replace(&mut self.state, State::Ground), byte) {
+            (State::Ground | State::Escape, ESC) => self.state = State::Escape,
+            (State::Ground, _) => return Some(Decoded::Byte(byte)),
+            (State::Escape, b'[') => self.state = State::Csi(0),
+            (State::Escape, b'O') => self.state = State::Ss3,
+            // A lone ESC, or Alt with a key: keep the key
+            (State::Escape, _) => return Some(Decoded::Byte(byte)),
+            (State::Csi(param), b'0'..=b'9') => {
+                self.state = State::Csi(param.saturating_mul(10).saturating_add(byte - b'0'));
+            }
+            // Modifier parameters, as in Ctrl-Right, are ignored
+            (State::Csi(param) | State::CsiRest(param), b';' | b'0'..=b'9') => self.state = State::CsiRest(param),
+            (State::Csi(param) | State::CsiRest(param), 0x40..=0x7E) => {
+                let key = match (byte, param) {
+                    (b'~', 1 | 7) => Some(Key::Home),
+                    (b'~', 2) => Some(Key::Insert),
+                    (b'~', 3) => Some(Key::Delete),
+                    (b'~', 4 | 8) => Some(Key::End),
+                    (b'~', 5) => Some(Key::PageUp),
+                    (b'~', 6) => Some(Key::PageDown),
+                    (b'~', _) => None,
+                    _ => final_key(byte),
+                };
+                return key.map(Decoded::Key);
+            }
+            (State::Ss3, _) => return final_key(byte).map(Decoded::Key),
+            // Anything else inside a sequence aborts it
+            (State::Csi(_) | State::CsiRest(_), _) => {}
+        }
+        None
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Key named by the last byte of `ESC [ x` or `ESC O x`
+fn final_key(byte: u8) -> Option<Key> {
+    match byte {
+        b'A' => Some(Key::Up),
+        b'B' => Some(Key::Down),
+        b'C' => Some(Key::Right),
+        b'D' => Some(Key::Left),
+        b'H' => Some(Key::Home),
+        b'F' => Some(Key::End),
+        _ => None,
+    }
+}
+
+/// US layout characters and their shifted forms
+const UNSHIFTED: &[u8] = b"`1234567890-=[]\\;',./";
+const SHIFTED: &[u8] = b"~!@#$%^&*()_+{}|:\"<>?";
+
+/// Bytes a terminal sends for `key`, the inverse of `Decoder`
+///
+/// Shift follows the US layout. Returns how many bytes were written to
+/// `out`, 0 for keys a terminal does not send.
+pub fn encode(key: Key, modifiers: Modifiers, out: &mut [u8; 4]) -> usize {
+    let sequence: &[u8] = match key {
+        Key::Char(ch) if ch.is_ascii() => {
+            let mut byte = ch as u8;
+            if modifiers.contains(Modifiers::CONTROL) && byte.is_ascii_alphabetic() {
+                byte = byte.to_ascii_lowercase() - b'a' + 1;
+            } else if modifiers.contains(Modifiers::SHIFT) {
+                byte = match UNSHIFTED.iter().position(|&unshifted| unshifted == byte) {
+                    Some(index) => SHIFTED[index],
+                    None => byte.to_ascii_uppercase(),
+                };
+            }
+            // Alt sends ESC before the key
+            if modifiers.contains(Modifiers::ALT) {
+                out[..2].copy_from_slice(&[ESC, byte]);
+                return 2;
+            }
+            out[0] = byte;
+            return 1;
+        }
+        Key::Enter => b"\r",
+        Key::Backspace => b"\x7f",
+        Key::Tab => b"\t",
+        Key::Escape => &[ESC],
+        Key::Up => b"\x1b[A",
+        Key::Down => b"\x1b[B",
+        Key::Right => b"\x1b[C",
+        Key::Left => b"\x1b[D",
+        Key::Home => b"\x1b[H",
+        Key::End => b"\x1b[F",
+        Key::Insert => b"\x1b[2~",
+        Key::Delete => b"\x1b[3~",
+        Key::PageUp => b"\x1b[5~",
+        Key::PageDown => b"\x1b[6~",
+        _ => b"",
+    };
+    out[..sequence.len()].copy_from_slice(sequence);
+    sequence.len()
+}
@@ -0,0 +1,314 @@
+//! Keyboard Layouts
+//!
+//! Keyboard drivers report character keys by where they sit, named by
+//! what they produce on a US keyboard. A layout says what each of those
+//! positions types alone, with Shift and with AltGr, the right Alt key.
+//! Layouts without AltGr characters treat it as a plain Alt.
+//!
+//! Accents on European layouts are dead keys: they type nothing until
+//! the next key, then combine with it (`^` then `e` gives `ê`). A key
+//! they do not combine with gets the accent typed in front of it, Space
+//! or the dead key again types the accent alone.
+//!
+//! The layout is picked with `keymap=<name>` on the command line or the
+//! `keymap` shell command.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::{Key, Modifiers};
+
+/// US meaning of each character key position, followed by the extra
+/// key left of Z on ISO keyboards
+const POSITIONS: &str = "`1234567890-=qwertyuiop[]\\asdfghjkl;'#zxcvbnm,./";
+const POSITION_COUNT: usize = POSITIONS.len() + 1;
+
+/// Dead keys are written in the tables as the combining form of their
+/// accent
+const DEAD_KEYS: core::ops::RangeInclusive<char> = '\u{300}'..='\u{36F}';
+
+/// Accented characters each dead key makes, by base letter
+const COMPOSE: &[(char, &str, &str)] = &[
+    ('\u{300}', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('\u{301}', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    ('\u{302}', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ('\u{308}', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+];
+
+/// Errors that can occur choosing a layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeymapError {
+    /// No layout by that name
+    UnknownLayout,
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeymapError::UnknownLayout => write!(f, "Unknown keyboard layout"),
+        }
+    }
+}
+
+/// Characters of every key position
+///
+/// Each table has a character per position in `POSITIONS` order, with a
+/// space where the key types nothing.
+pub struct Layout {
+    pub name: &'static str,
+    pub description: &'static str,
+    normal: &'static str,
+    shift: &'static str,
+    /// Empty if AltGr is a plain Alt
+    altgr: &'static str,
+}
+
+/// Built-in layouts, the first is the default
+pub static LAYOUTS: [Layout; 5] = [
+    Layout {
+        name: "us",
+        description: "US QWERTY",
+        normal: "`1234567890-=qwertyuiop[]\\asdfghjkl;'\\zxcvbnm,./<",
+        shift: "~!@#$%^&*()_+QWERTYUIOP{}|ASDFGHJKL:\"|ZXCVBNM<>?>",
+        altgr: "",
+    },
+    Layout {
+        name: "uk",
+        description: "UK QWERTY",
+        normal: "`1234567890-=qwertyuiop[]#asdfghjkl;'#zxcvbnm,./\\",
+        shift: "¬!\"£$%^&*()_+QWERTYUIOP{}~ASDFGHJKL:@~ZXCVBNM<>?|",
+        altgr: "¦   €          é   úíó    á                      ",
+    },
+    Layout {
+        name: "de",
+        description: "German QWERTZ",
+        normal: "\u{302}1234567890ß\u{301}qwertzuiopü+#asdfghjklöä#yxcvbnm,.-<",
+        shift: "°!\"§$%&/()=?\u{300}QWERTZUIOPÜ*'ASDFGHJKLÖÄ'YXCVBNM;:_>",
+        altgr: "  ²³   {[]}\\ @ €        ~                   µ   |",
+    },
+    Layout {
+        name: "fr",
+        description: "French AZERTY",
+        normal: "²&é\"'(-è_çà)=azertyuiop\u{302}$*qsdfghjklmù*wxcvbn,;:!<",
+        shift: " 1234567890°+AZERTYUIOP\u{308}£µQSDFGHJKLM%µWXCVBN?./§>",
+        altgr: "  ~#{[|`\\^@]}  €        ¤                        ",
+    },
+    Layout {
+        name: "dvorak",
+        description: "US Dvorak",
+        normal: "`1234567890[]',.pyfgcrl/=\\aoeuidhtns-\\;qjkxbmwvz<",
+        shift: "~!@#$%^&*(){}\"<>PYFGCRL?+|AOEUIDHTNS_|:QJKXBMWVZ>",
+        altgr: "",
+    },
+];
+
+/// Index into `LAYOUTS` of the layout in use
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Table index of a character key position
+fn position(key: Key) -> Option<usize> {
+    match key {
+        Key::Char(c) => POSITIONS.find(c),
+        Key::NonUsBackslash => Some(POSITION_COUNT - 1),
+        _ => None,
+    }
+}
+
+/// Character at `index` of `table`, `None` for a space or a short table
+fn lookup(table: &str, index: usize) -> Option<char> {
+    table.chars().nth(index).filter(|&c| c != ' ')
+}
+
+/// The accent a dead key types on its own
+fn spacing(dead: char) -> char {
+    match dead {
+        '\u{300}' => '`',
+        '\u{301}' => '´',
+        '\u{302}' => '^',
+        '\u{308}' => '¨',
+        other => other,
+    }
+}
+
+/// `base` with the accent of `dead`, if there is such a character
+fn compose(dead: char, base: char) -> Option<char> {
+    let (_, bases, composed) = COMPOSE.iter().find(|&&(accent, ..)| accent == dead)?;
+    let index = bases.chars().position(|c| c == base)?;
+    composed.chars().nth(index)
+}
+
+impl Layout {
+    /// Character `key` types with `modifiers`, `None` if it types nothing
+    /// or is not a character key
+    ///
+    /// Control and Alt take the unshifted character, so Ctrl+Z is the key
+    /// marked Z whatever its position.
+    pub fn character(&self, key: Key, modifiers: Modifiers) -> Option<char> {
+        let index = position(key)?;
+        let table = if modifiers.contains(Modifiers::ALT_GR) && !self.altgr.is_empty() {
+            self.altgr
+        } else if modifiers.contains(Modifiers::SHIFT)
+            && !modifiers.contains(Modifiers::CONTROL)
+            && !modifiers.contains(Modifiers::ALT)
+        {
+            self.shift
+        } else {
+            self.normal
+        };
+        lookup(table, index)
+    }
+}
+
+/// Layout in use
+pub fn active() -> &'static Layout {
+    &LAYOUTS[ACTIVE.load(Ordering::Relaxed)]
+}
+
+/// Switch to the layout called `name`
+pub fn set_active(name: &str) -> Result<(), KeymapError> {
+    let index = LAYOUTS.iter().position(|layout| layout.name == name).ok_or(KeymapError::UnknownLayout)?;
+    ACTIVE.store(index, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Pick the layout named by `keymap=` on the command line
+pub fn init() {
+    if let Some(name) = crate::cmdline::get("keymap") {
+        if let Err(e) = set_active(name) {
+            crate::serial_println!("keymap={}: {}, using {}", name, e, active().name);
+        }
+    }
+}
+
+/// Layout translation for one keyboard stream, holding a pending dead key
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Keymap {
+    dead: Option<char>,
+}
+
+impl Keymap {
+    pub const fn new() -> Self {
+        Keymap { dead: None }
+    }
+
+    /// Turn a key press into what it types under `layout`, handing each
+    /// result to `emit`
+    ///
+    /// Character keys come out as `Key::Char` with the character typed,
+    /// Shift and AltGr consumed; Control and Alt are kept. Other keys pass
+    /// through, with AltGr as Alt if the layout has no AltGr characters.
+    pub fn translate(&mut self, layout: &Layout, key: Key, modifiers: Modifiers, mut emit: impl FnMut(Key, Modifiers)) {
+        let mut modifiers = modifiers;
+        if modifiers.contains(Modifiers::ALT_GR) && layout.altgr.is_empty() {
+            modifiers = modifiers.without(Modifiers::ALT_GR).union(Modifiers::ALT);
+        }
+        let plain = modifiers.without(Modifiers::SHIFT).without(Modifiers::ALT_GR);
+
+        let character = match key {
+            Key::Char(' ') => Some(' '),
+            key => layout.character(key, modifiers),
+        };
+        let Some(character) = character else {
+            // Modifiers are pressed on the way to the next character
+            if position(key).is_none() {
+                if !matches!(key, Key::Shift | Key::Control | Key::Alt) {
+                    self.dead = None;
+                }
+                emit(key, modifiers);
+            }
+            return;
+        };
+        if plain.contains(Modifiers::CONTROL) || plain.contains(Modifiers::ALT) {
+            self.dead = None;
+            if !DEAD_KEYS.contains(&character) {
+                emit(Key::Char(character), plain);
+            }
+            return;
+        }
+
+        if let Some(dead) = self.dead.take() {
+            if character == ' ' || character == dead {
+                return emit(Key::Char(spacing(dead)), plain);
+            }
+            if let Some(composed) = compose(dead, character) {
+                return emit(Key::Char(composed), plain);
+            }
+            emit(Key::Char(spacing(dead)), plain);
+        }
+        if DEAD_KEYS.contains(&character) {
+            self.dead = Some(character);
+        } else {
+            emit(Key::Char(character), plain);
+        }
+    }
+}
+
+/// List the layouts, marking the one in use
+pub fn report(out: &mut dyn Write) -> fmt::Result {
+    let active = active().name;
+    for layout in LAYOUTS.iter() {
+        writeln!(out, "{} {:<8} {}", if layout.name == active { '*' } else { ' ' }, layout.name, layout.description)?;
+    }
+    Ok(())
+}
+
+/// Tables cover every position, and dead keys compose and fall back
+fn selftest() -> Result<(), &'static str> {
+    for layout in LAYOUTS.iter() {
+        let count = |table: &str| table.chars().count();
+        if count(layout.normal) != POSITION_COUNT || count(layout.shift) != POSITION_COUNT {
+            return Err("layout table has the wrong length");
+        }
+        if !layout.altgr.is_empty() && count(layout.altgr) != POSITION_COUNT {
+            return Err("AltGr table has the wrong length");
+        }
+    }
+    let layout = |name| LAYOUTS.iter().find(|layout| layout.name == name).ok_or("layout missing");
+    let typed = |layout: &Layout, keys: &[(Key, Modifiers)]| {
+        let mut keymap = Keymap::new();
+        let mut text = cosmos_common::fmt::FixedBuf::<32>::new();
+        for &(key, modifiers) in keys {
+            keymap.translate(layout, key, modifiers, |key, _| {
+                if let Key::Char(c) = key {
+                    let _ = text.write_char(c);
+                }
+            });
+        }
+        text
+    };
+    let none = Modifiers::NONE;
+    let shift = Modifiers::SHIFT;
+
+    let de = layout("de")?;
+    let text = typed(de, &[
+        (Key::Char('z'), none),
+        (Key::Char('7'), shift),
+        (Key::Char('q'), Modifiers::ALT_GR),
+        (Key::Char('`'), none),
+        (Key::Char('e'), none),
+        (Key::Char('='), none),
+        (Key::Char('x'), none),
+        (Key::NonUsBackslash, none),
+    ]);
+    if text.as_str() != "y/@ê´x<" {
+        return Err("German layout typed wrong");
+    }
+    let text = typed(layout("fr")?, &[(Key::Char('['), shift), (Key::Char('o'), shift), (Key::Char('q'), none)]);
+    if text.as_str() != "Öa" {
+        return Err("French dead key did not compose");
+    }
+
+    // Ctrl keeps the key's own letter, and AltGr is Alt without AltGr characters
+    let mut keymap = Keymap::new();
+    let mut last = None;
+    keymap.translate(de, Key::Char('y'), Modifiers::CONTROL, |key, modifiers| last = Some((key, modifiers)));
+    if last != Some((Key::Char('z'), Modifiers::CONTROL)) {
+        return Err("Control lost the layout's letter");
+    }
+    keymap.translate(layout("us")?, Key::Function(2), Modifiers::ALT_GR, |key, modifiers| last = Some((key, modifiers)));
+    if last != Some((Key::Function(2), Modifiers::ALT)) {
+        return Err("AltGr not Alt on a layout without it");
+    }
+    Ok(())
+}
+
+crate::selftest!("keymap", selftest);
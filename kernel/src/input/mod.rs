@@ -0,0 +1,389 @@
+//! Input Events
+//!
+//! Drivers register an input device and report what happens on it;
+//! the input layer stamps each report with the time and its device and
+//! hands it to every subscriber whose mask matches. Keyboards, mice and
+//! serial terminals all come out as the same key, button and motion
+//! events, so the shell or a future GUI need not know where input came
+//! from. Reporting never allocates and is safe from interrupt handlers;
+//! a subscriber that falls behind loses its newest events, not others'.
+
+pub mod escape;
+pub mod keymap;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::sync::{CancelToken, IrqMutex, WaitError, WaitQueue};
+
+pub use escape::{encode, Decoded, Decoder};
+
+/// Events a subscription holds before dropping new ones
+pub const DEFAULT_QUEUE_DEPTH: usize = 64;
+
+/// Kind of input device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Keyboard,
+    Mouse,
+    /// Terminal on a serial line, keys arrive as characters
+    Serial,
+}
+
+impl DeviceKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceKind::Keyboard => "keyboard",
+            DeviceKind::Mouse => "mouse",
+            DeviceKind::Serial => "serial",
+        }
+    }
+}
+
+/// Registered input device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId(u32);
+
+impl DeviceId {
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+}
+
+/// Keys, by meaning rather than scancode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// A key that produces a character, lowercase for letters
+    Char(char),
+    Enter,
+    Backspace,
+    Tab,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    /// Extra key left of Z on ISO keyboards, `\` on a US keyboard that
+    /// has it
+    NonUsBackslash,
+    /// Function key F1 to F24
+    Function(u8),
+    Shift,
+    Control,
+    Alt,
+    /// Unmapped scancode
+    Unknown(u16),
+}
+
+/// Modifier keys held with a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const SHIFT: Modifiers = Modifiers(1 << 0);
+    pub const CONTROL: Modifiers = Modifiers(1 << 1);
+    pub const ALT: Modifiers = Modifiers(1 << 2);
+    /// Right Alt, which picks a layout's third characters
+    pub const ALT_GR: Modifiers = Modifiers(1 << 3);
+
+    pub const fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 | other.0)
+    }
+
+    pub const fn without(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 & !other.0)
+    }
+}
+
+/// Pointer buttons
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Left,
+    Right,
+    Middle,
+    Other(u8),
+}
+
+/// What happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// A key went down or up
+    Key { key: Key, modifiers: Modifiers, pressed: bool },
+    /// A pointer button went down or up
+    Button { button: Button, pressed: bool },
+    /// Relative pointer motion, positive right and down
+    Motion { dx: i32, dy: i32 },
+    /// Wheel movement, positive away from the user
+    Scroll { delta: i32 },
+}
+
+impl EventKind {
+    fn class(&self) -> EventMask {
+        match self {
+            EventKind::Key { .. } => EventMask::KEY,
+            EventKind::Button { .. } | EventKind::Motion { .. } | EventKind::Scroll { .. } => EventMask::POINTER,
+        }
+    }
+}
+
+/// An input event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    /// Milliseconds since boot
+    pub timestamp_ms: u64,
+    pub device: DeviceId,
+    pub kind: EventKind,
+}
+
+/// Classes of events a subscriber wants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventMask(u8);
+
+impl EventMask {
+    pub const KEY: EventMask = EventMask(1 << 0);
+    pub const POINTER: EventMask = EventMask(1 << 1);
+    pub const ALL: EventMask = EventMask(0xFF);
+
+    pub const fn union(self, other: EventMask) -> EventMask {
+        EventMask(self.0 | other.0)
+    }
+
+    fn intersects(self, other: EventMask) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+/// Registered device
+struct Device {
+    id: DeviceId,
+    name: &'static str,
+    kind: DeviceKind,
+    events: AtomicU64,
+}
+
+/// Events waiting for one subscriber
+struct Queue {
+    mask: EventMask,
+    events: IrqMutex<Ring>,
+    readable: WaitQueue,
+}
+
+/// Fixed ring of events, allocated up front so reporting never allocates
+struct Ring {
+    events: Vec<InputEvent>,
+    capacity: usize,
+    head: usize,
+    len: usize,
+    dropped: u64,
+}
+
+impl Ring {
+    fn push(&mut self, event: InputEvent) {
+        let capacity = self.capacity;
+        if self.len == capacity {
+            self.dropped += 1;
+        } else if self.events.len() < capacity {
+            self.events.push(event);
+            self.len += 1;
+        } else {
+            self.events[(self.head + self.len) % capacity] = event;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<InputEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % self.capacity;
+        self.len -= 1;
+        Some(event)
+    }
+}
+
+static DEVICES: IrqMutex<Vec<Device>> = IrqMutex::named("input devices", Vec::new());
+static SUBSCRIBERS: IrqMutex<Vec<Arc<Queue>>> = IrqMutex::named("input subscribers", Vec::new());
+static NEXT_DEVICE: AtomicU32 = AtomicU32::new(1);
+
+/// Register an input device, its events carry the returned id
+pub fn register_device(name: &'static str, kind: DeviceKind) -> DeviceId {
+    let id = DeviceId(NEXT_DEVICE.fetch_add(1, Ordering::Relaxed));
+    DEVICES.lock().push(Device { id, name, kind, events: AtomicU64::new(0) });
+    id
+}
+
+/// Remove an input device
+pub fn unregister_device(id: DeviceId) {
+    DEVICES.lock().retain(|device| device.id != id);
+}
+
+/// Kind of a registered device
+pub fn device_kind(id: DeviceId) -> Option<DeviceKind> {
+    DEVICES.lock().iter().find(|device| device.id == id).map(|device| device.kind)
+}
+
+/// Registered devices as (id, name, kind, events reported)
+pub fn devices() -> Vec<(DeviceId, &'static str, DeviceKind, u64)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|device| (device.id, device.name, device.kind, device.events.load(Ordering::Relaxed)))
+        .collect()
+}
+
+/// Report an event from `device` to every matching subscriber
+pub fn report(device: DeviceId, kind: EventKind) {
+    let event = InputEvent { timestamp_ms: crate::time::uptime_ms(), device, kind };
+    if let Some(device) = DEVICES.lock().iter().find(|registered| registered.id == device) {
+        device.events.fetch_add(1, Ordering::Relaxed);
+    }
+    for queue in SUBSCRIBERS.lock().iter() {
+        if queue.mask.intersects(kind.class()) {
+            queue.events.lock().push(event);
+            queue.readable.wake_all();
+        }
+    }
+}
+
+/// Report a key press followed by its release, for devices like serial
+/// terminals that only say a key was typed
+pub fn report_keystroke(device: DeviceId, key: Key, modifiers: Modifiers) {
+    report(device, EventKind::Key { key, modifiers, pressed: true });
+    report(device, EventKind::Key { key, modifiers, pressed: false });
+}
+
+/// Key and modifiers a terminal means by a decoded byte or key
+pub fn terminal_key(decoded: Decoded) -> Option<(Key, Modifiers)> {
+    let byte = match decoded {
+        Decoded::Key(key) => return Some((key, Modifiers::NONE)),
+        Decoded::Byte(byte) => byte,
+    };
+    match byte {
+        b'\r' | b'\n' => Some((Key::Enter, Modifiers::NONE)),
+        0x08 | 0x7F => Some((Key::Backspace, Modifiers::NONE)),
+        b'\t' => Some((Key::Tab, Modifiers::NONE)),
+        0x1B => Some((Key::Escape, Modifiers::NONE)),
+        // Ctrl-A to Ctrl-Z
+        0x01..=0x1A => Some((Key::Char((b'a' + byte - 1) as char), Modifiers::CONTROL)),
+        b'A'..=b'Z' => Some((Key::Char(byte.to_ascii_lowercase() as char), Modifiers::SHIFT)),
+        0x20..=0x7E => Some((Key::Char(byte as char), Modifiers::NONE)),
+        _ => None,
+    }
+}
+
+/// A subscriber's view of the event stream, unsubscribes when dropped
+pub struct Subscription {
+    queue: Arc<Queue>,
+}
+
+/// Subscribe to events in `mask`, holding up to `depth` undelivered events
+pub fn subscribe(mask: EventMask, depth: usize) -> Subscription {
+    let depth = depth.max(1);
+    let queue = Arc::new(Queue {
+        mask,
+        events: IrqMutex::named("input queue", Ring {
+            events: Vec::with_capacity(depth),
+            capacity: depth,
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }),
+        readable: WaitQueue::new(),
+    });
+    SUBSCRIBERS.lock().push(queue.clone());
+    Subscription { queue }
+}
+
+impl Subscription {
+    /// Next event, blocking until there is one
+    pub fn next(&self) -> InputEvent {
+        let mut event = None;
+        self.queue.readable.wait_until(|| {
+            event = self.queue.events.lock().pop();
+            event.is_some()
+        });
+        event.expect("woken without an event")
+    }
+
+    /// Next event, blocking until there is one or `cancel` ends the wait
+    pub fn next_cancellable(&self, cancel: &CancelToken) -> Result<InputEvent, WaitError> {
+        let mut event = None;
+        self.queue.readable.wait_cancellable(cancel, || {
+            event = self.queue.events.lock().pop();
+            event.is_some()
+        })?;
+        Ok(event.expect("woken without an event"))
+    }
+
+    /// Next event without blocking
+    pub fn try_next(&self) -> Option<InputEvent> {
+        self.queue.events.lock().pop()
+    }
+
+    /// Events lost because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.queue.events.lock().dropped
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        SUBSCRIBERS.lock().retain(|queue| !Arc::ptr_eq(queue, &self.queue));
+    }
+}
+
+/// Delivery to several subscribers by mask, overflow and escape decoding
+fn selftest() -> Result<(), &'static str> {
+    let device = register_device("selftest", DeviceKind::Serial);
+    let keys = subscribe(EventMask::KEY, 2);
+    let all = subscribe(EventMask::ALL, DEFAULT_QUEUE_DEPTH);
+
+    report(device, EventKind::Motion { dx: 3, dy: -1 });
+    let mut decoder = Decoder::new();
+    for &byte in b"\x1b[Dq\x03" {
+        if let Some((key, modifiers)) = decoder.feed(byte).and_then(terminal_key) {
+            report_keystroke(device, key, modifiers);
+        }
+    }
+
+    let first = keys.try_next().ok_or("no key event")?;
+    if first.device != device || first.kind != (EventKind::Key { key: Key::Left, modifiers: Modifiers::NONE, pressed: true }) {
+        return Err("escape sequence not decoded to Left");
+    }
+    if keys.try_next().is_none() || keys.try_next().is_some() || keys.dropped() != 4 {
+        return Err("full queue did not drop new events");
+    }
+
+    let mut events = Vec::new();
+    while let Some(event) = all.try_next() {
+        if event.device == device {
+            events.push(event.kind);
+        }
+    }
+    let ctrl_c = EventKind::Key { key: Key::Char('c'), modifiers: Modifiers::CONTROL, pressed: false };
+    if events.len() != 7 || events[0] != (EventKind::Motion { dx: 3, dy: -1 }) || events[6] != ctrl_c {
+        return Err("unfiltered subscriber missed events");
+    }
+
+    drop(keys);
+    drop(all);
+    unregister_device(device);
+    if devices().iter().any(|&(id, ..)| id == device) {
+        return Err("subscription or device not removed");
+    }
+    Ok(())
+}
+
+crate::selftest!("input", selftest);
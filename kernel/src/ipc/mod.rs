@@ -0,0 +1,5 @@
+//! Inter-Process Communication
+
+pub mod pipe;
+
+pub use pipe::{pipe, PipeError, PipeReader, PipeWriter};
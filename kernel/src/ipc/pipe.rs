@@ -0,0 +1,277 @@
+//! Anonymous Pipes
+//!
+//! A pipe is a fixed-size kernel ring buffer with any number of reader and
+//! writer handles. Reads block until data arrives and return 0 once every
+//! writer is gone; writes block while the buffer is full and fail once
+//! every reader is gone. The `pipe` system call will hand these endpoints
+//! out as file descriptors when processes have descriptor tables.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::{IrqMutex, WaitQueue};
+
+/// Bytes buffered per pipe, writes up to this size are never interleaved
+pub const PIPE_CAPACITY: usize = 4096;
+
+/// Errors that can occur on pipe operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeError {
+    /// Every read end is closed
+    BrokenPipe,
+    /// Operation would block
+    WouldBlock,
+}
+
+impl core::fmt::Display for PipeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PipeError::BrokenPipe => write!(f, "Broken pipe"),
+            PipeError::WouldBlock => write!(f, "Pipe operation would block"),
+        }
+    }
+}
+
+/// Circular byte buffer
+struct Ring {
+    data: Vec<u8>,
+    head: usize,
+    len: usize,
+}
+
+impl Ring {
+    fn free(&self) -> usize {
+        self.data.len() - self.len
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> usize {
+        let count = bytes.len().min(self.free());
+        let capacity = self.data.len();
+        for (i, &byte) in bytes[..count].iter().enumerate() {
+            self.data[(self.head + self.len + i) % capacity] = byte;
+        }
+        self.len += count;
+        count
+    }
+
+    fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len);
+        let capacity = self.data.len();
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            *byte = self.data[(self.head + i) % capacity];
+        }
+        self.head = (self.head + count) % capacity;
+        self.len -= count;
+        count
+    }
+}
+
+/// State shared by all handles of one pipe
+struct Pipe {
+    ring: IrqMutex<Ring>,
+    readers: AtomicUsize,
+    writers: AtomicUsize,
+    /// Readers waiting for data or EOF
+    readable: WaitQueue,
+    /// Writers waiting for space or a broken pipe
+    writable: WaitQueue,
+}
+
+/// Read end of a pipe
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+/// Write end of a pipe
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+/// Create a pipe, returning its read and write ends
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        ring: IrqMutex::named("pipe", Ring { data: vec![0; PIPE_CAPACITY], head: 0, len: 0 }),
+        readers: AtomicUsize::new(1),
+        writers: AtomicUsize::new(1),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+impl PipeReader {
+    /// Read available bytes, blocking until there are some
+    ///
+    /// Returns 0 at end of file, once the pipe is empty and has no writers.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        let mut count = 0;
+        self.pipe.readable.wait_until(|| {
+            count = self.pipe.ring.lock().pop(buf);
+            count > 0 || self.pipe.writers.load(Ordering::Acquire) == 0
+        });
+        if count > 0 {
+            self.pipe.writable.wake_all();
+        }
+        count
+    }
+
+    /// Read available bytes without blocking
+    ///
+    /// Returns `Ok(0)` at end of file.
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<usize, PipeError> {
+        let count = self.pipe.ring.lock().pop(buf);
+        if count > 0 {
+            self.pipe.writable.wake_all();
+            Ok(count)
+        } else if self.pipe.writers.load(Ordering::Acquire) == 0 || buf.is_empty() {
+            Ok(0)
+        } else {
+            Err(PipeError::WouldBlock)
+        }
+    }
+
+    /// Bytes buffered and ready to read
+    pub fn available(&self) -> usize {
+        self.pipe.ring.lock().len
+    }
+}
+
+impl PipeWriter {
+    /// Write all of `buf`, blocking while the pipe is full
+    ///
+    /// Writes of at most `PIPE_CAPACITY` bytes go in as one piece. Fails
+    /// with `BrokenPipe` if the readers close before anything was written;
+    /// otherwise returns how much was written before they closed.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, PipeError> {
+        let atomic = buf.len() <= PIPE_CAPACITY;
+        let mut written = 0;
+        while written < buf.len() {
+            let mut broken = false;
+            self.pipe.writable.wait_until(|| {
+                if self.pipe.readers.load(Ordering::Acquire) == 0 {
+                    broken = true;
+                    return true;
+                }
+                let mut ring = self.pipe.ring.lock();
+                let rest = &buf[written..];
+                if atomic && ring.free() < rest.len() {
+                    return false;
+                }
+                written += ring.push(rest);
+                ring.free() == 0 || written == buf.len()
+            });
+            self.pipe.readable.wake_all();
+            if broken {
+                return if written > 0 { Ok(written) } else { Err(PipeError::BrokenPipe) };
+            }
+        }
+        Ok(written)
+    }
+
+    /// Write as much of `buf` as fits without blocking
+    pub fn try_write(&self, buf: &[u8]) -> Result<usize, PipeError> {
+        if self.pipe.readers.load(Ordering::Acquire) == 0 {
+            return Err(PipeError::BrokenPipe);
+        }
+        let count = {
+            let mut ring = self.pipe.ring.lock();
+            if buf.len() <= PIPE_CAPACITY && ring.free() < buf.len() {
+                0
+            } else {
+                ring.push(buf)
+            }
+        };
+        if count == 0 && !buf.is_empty() {
+            return Err(PipeError::WouldBlock);
+        }
+        self.pipe.readable.wake_all();
+        Ok(count)
+    }
+}
+
+impl core::fmt::Write for PipeWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        match PipeWriter::write(self, s.as_bytes()) {
+            Ok(count) if count == s.len() => Ok(()),
+            _ => Err(core::fmt::Error),
+        }
+    }
+}
+
+impl Clone for PipeReader {
+    fn clone(&self) -> Self {
+        self.pipe.readers.fetch_add(1, Ordering::AcqRel);
+        PipeReader { pipe: self.pipe.clone() }
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> Self {
+        self.pipe.writers.fetch_add(1, Ordering::AcqRel);
+        PipeWriter { pipe: self.pipe.clone() }
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        if self.pipe.readers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Blocked writers must see the broken pipe
+            self.pipe.writable.wake_all();
+        }
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        if self.pipe.writers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Blocked readers must see end of file
+            self.pipe.readable.wake_all();
+        }
+    }
+}
+
+/// Data, wrap-around, end of file and broken pipe without blocking
+fn selftest() -> Result<(), &'static str> {
+    let (reader, writer) = pipe();
+    let mut buf = [0u8; 16];
+    if reader.try_read(&mut buf) != Err(PipeError::WouldBlock) {
+        return Err("empty pipe readable");
+    }
+
+    // Walk the ring past its end
+    let chunk = [0xA5u8; PIPE_CAPACITY - 8];
+    writer.try_write(&chunk).map_err(|_| "write failed")?;
+    let mut sink = vec![0u8; PIPE_CAPACITY];
+    if reader.try_read(&mut sink) != Ok(chunk.len()) {
+        return Err("short read");
+    }
+    writer.try_write(b"hello, pipe").map_err(|_| "write failed")?;
+    if writer.try_write(&[0u8; PIPE_CAPACITY]) != Err(PipeError::WouldBlock) {
+        return Err("atomic write split");
+    }
+    let count = reader.read(&mut buf);
+    if &buf[..count] != b"hello, pipe" {
+        return Err("data corrupted across wrap-around");
+    }
+
+    let second = writer.clone();
+    drop(writer);
+    second.try_write(b"x").map_err(|_| "clone cannot write")?;
+    drop(second);
+    if reader.read(&mut buf) != 1 || reader.read(&mut buf) != 0 {
+        return Err("no end of file after writers closed");
+    }
+
+    let (reader, writer) = pipe();
+    drop(reader);
+    if writer.write(b"x") != Err(PipeError::BrokenPipe) {
+        return Err("write to closed pipe succeeded");
+    }
+    Ok(())
+}
+
+crate::selftest!("pipe", selftest);
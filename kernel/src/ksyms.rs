@@ -0,0 +1,186 @@
+//! Kernel Symbol Table
+//!
+//! `build.rs` turns the linker map of the previous build into a table of
+//! function names and addresses, embedded in `.ksyms`. A table from an
+//! outdated map is detected at boot and ignored, rebuilding once more
+//! brings it up to date.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Table generated by build.rs, see its `encode` for the layout
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin")).len()] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin"));
+
+/// Size of one table entry in bytes
+const ENTRY_SIZE: usize = 16;
+
+// Bounds of `.ksyms`, defined by the linker script. Using these instead of
+// `KSYMS.len()` keeps the table size out of the code, so addresses do not
+// change when it grows.
+unsafe extern "C" {
+    static __ksyms_start: u8;
+    static __ksyms_end: u8;
+}
+
+/// Set once the table is known to match the running kernel
+static VALID: AtomicBool = AtomicBool::new(false);
+
+/// A kernel function symbol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: usize,
+    pub size: usize,
+}
+
+/// Raw table bytes
+fn table() -> &'static [u8] {
+    unsafe {
+        let start = &raw const __ksyms_start;
+        let end = &raw const __ksyms_end;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+fn read_u32(table: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(table[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(table: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(table[offset..offset + 8].try_into().unwrap())
+}
+
+/// Number of symbols, 0 if the table is missing or malformed
+fn count(table: &[u8]) -> usize {
+    if table.len() < 4 {
+        return 0;
+    }
+    let count = read_u32(table, 0) as usize;
+    if 4 + count * ENTRY_SIZE > table.len() {
+        return 0;
+    }
+    count
+}
+
+/// Decode entry `index`
+fn entry(table: &'static [u8], count: usize, index: usize) -> Symbol {
+    let names = 4 + count * ENTRY_SIZE;
+    let offset = 4 + index * ENTRY_SIZE;
+    let name_start = names + read_u32(table, offset + 12) as usize;
+    let name_end = if index + 1 < count {
+        names + read_u32(table, offset + ENTRY_SIZE + 12) as usize
+    } else {
+        table.len()
+    };
+    let name = table
+        .get(name_start..name_end)
+        .and_then(|bytes| core::str::from_utf8(bytes).ok())
+        .unwrap_or("?");
+    Symbol {
+        name,
+        // The table holds link addresses
+        address: (read_u64(table, offset) as usize).wrapping_add(crate::mm::kernel_offset()),
+        size: read_u32(table, offset + 8) as usize,
+    }
+}
+
+/// Check the table against the running kernel and enable lookups
+///
+/// Returns the number of symbols, or `None` if the table is empty or was
+/// generated from an outdated map.
+pub fn init() -> Option<usize> {
+    let table = table();
+    let count = count(table);
+    let stale = (0..count)
+        .map(|i| entry(table, count, i))
+        .find(|s| s.name == "cosmos::ksyms::init")
+        .is_none_or(|s| s.address != init as usize);
+    VALID.store(!stale, Ordering::Release);
+    (!stale).then_some(count)
+}
+
+/// Check whether symbol lookups are available
+pub fn is_available() -> bool {
+    VALID.load(Ordering::Acquire)
+}
+
+/// Find the function containing `addr`, with the offset into it
+pub fn lookup(addr: usize) -> Option<(Symbol, usize)> {
+    if !is_available() {
+        return None;
+    }
+    let table = table();
+    let count = count(table);
+
+    // Last symbol starting at or below addr
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if entry(table, count, mid).address <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let symbol = entry(table, count, low.checked_sub(1)?);
+    let offset = addr - symbol.address;
+    (offset < symbol.size.max(1)).then_some((symbol, offset))
+}
+
+/// Address of the function named `name`, e.g. `cosmos::ksyms::resolve`
+pub fn resolve(name: &str) -> Option<usize> {
+    if !is_available() {
+        return None;
+    }
+    let table = table();
+    let count = count(table);
+    (0..count)
+        .map(|i| entry(table, count, i))
+        .find(|s| s.name == name)
+        .map(|s| s.address)
+}
+
+/// Largest distance between frames that [`backtrace`] follows
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+/// Return addresses of the current call chain, innermost first
+///
+/// Walks saved frame pointers, which the kernel is built with (see
+/// `.cargo/config.toml`). Entries past the end of the walk are zero.
+#[inline(always)]
+pub fn backtrace<const N: usize>() -> [usize; N] {
+    let mut callers = [0; N];
+    let mut frame: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags)) };
+    for caller in callers.iter_mut() {
+        // Saved rbp, then the return address; callers' frames sit higher up
+        if frame == 0 || !frame.is_multiple_of(8) || x86_64::VirtAddr::try_new(frame).is_err() {
+            break;
+        }
+        let (next, return_address) = unsafe { (*(frame as *const u64), *((frame + 8) as *const u64)) };
+        if return_address == 0 {
+            break;
+        }
+        *caller = return_address as usize;
+        if next <= frame || next - frame > MAX_FRAME_SIZE {
+            break;
+        }
+        frame = next;
+    }
+    callers
+}
+
+/// Formats an address as `0x... <name+0xoffset>` when the symbol is known
+pub struct Address(pub usize);
+
+impl core::fmt::Display for Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:#x}", self.0)?;
+        if let Some((symbol, offset)) = lookup(self.0) {
+            write!(f, " <{}+{:#x}>", symbol.name, offset)?;
+        }
+        Ok(())
+    }
+}
@@ -1,14 +1,96 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(thread_local)]
 
 //! CosmOS Kernel Library
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod arch;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod boot;
+pub mod bootreport;
+pub mod bootstat;
+pub mod cmdline;
+pub mod console;
+pub mod crashdump;
+pub mod crypto;
+pub mod dev;
+pub mod efi;
+pub mod font;
+#[cfg(feature = "fs")]
+pub mod fs;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod idle;
+pub mod input;
+pub mod ipc;
+pub mod ksyms;
 pub mod mm;
+#[cfg(feature = "modules")]
+pub mod module;
+#[cfg(feature = "pci")]
+pub mod pci;
+pub mod power;
+pub mod pstore;
+#[cfg(feature = "profiler")]
+pub mod profiler;
+pub mod qemu;
+#[cfg(feature = "selftest")]
+pub mod selftest;
 pub mod serial;
+pub mod shell;
+#[cfg(feature = "sound")]
+pub mod sound;
+pub mod stats;
+pub mod stack_protector;
+pub mod sync;
+pub mod task;
+#[cfg(feature = "tftp")]
+pub mod tftp;
+pub mod time;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod tty;
+#[cfg(feature = "usb")]
+pub mod usb;
+pub mod version;
 pub mod vga;
+#[cfg(feature = "virtio")]
+pub mod virtio;
+pub mod vt;
+pub mod watchdog;
+#[cfg(feature = "xmodem")]
+pub mod xmodem;
+
+/// No-op `trace_event!` when tracepoints are compiled out
+///
+/// The arguments sit behind `if false` so they are type-checked but
+/// never evaluated.
+#[cfg(not(feature = "trace"))]
+#[macro_export]
+macro_rules! trace_event {
+    ($kind:ident, $arg0:expr, $arg1:expr) => {
+        if false {
+            let _ = ($arg0, $arg1);
+        }
+    };
+}
+
+/// No-op `selftest!` when self-tests are compiled out
+///
+/// Referencing the test keeps it from being reported as dead code; it is
+/// not linked in.
+#[cfg(not(feature = "selftest"))]
+#[macro_export]
+macro_rules! selftest {
+    ($name:expr, $run:path) => {
+        const _: fn() -> Result<(), &'static str> = $run;
+    };
+}
 
 /// Halt the CPU in a loop
 pub fn hlt_loop() -> ! {
@@ -6,127 +6,26 @@
 extern crate alloc;
 
 use cosmos::mm::MemoryMap;
+use cosmos::serial;
 use alloc::vec::Vec;
+use cosmos_common::fmt::{format_hex, format_u64_dec, FixedBuf, HumanBytes, DEC_DIGITS, HEX_DIGITS};
 
-/// Simple serial port writer for COM1 (0x3F8)
-struct SerialPort {
-    base: u16,
-}
-
-impl SerialPort {
-    const fn new(base: u16) -> Self {
-        SerialPort { base }
-    }
-    
-    fn init(&self) {
-        unsafe {
-            // Disable interrupts
-            Self::outb(self.base + 1, 0x00);
-            // Enable DLAB (set baud rate divisor)
-            Self::outb(self.base + 3, 0x80);
-            // Set divisor to 3 (38400 baud)
-            Self::outb(self.base + 0, 0x03);
-            Self::outb(self.base + 1, 0x00);
-            // 8 bits, no parity, one stop bit
-            Self::outb(self.base + 3, 0x03);
-            // Enable FIFO, clear them, with 14-byte threshold
-            Self::outb(self.base + 2, 0xC7);
-            // IRQs enabled, RTS/DSR set
-            Self::outb(self.base + 4, 0x0B);
-        }
-    }
-    
-    fn write_byte(&self, byte: u8) {
-        unsafe {
-            // Wait for transmit buffer to be empty
-            while (Self::inb(self.base + 5) & 0x20) == 0 {}
-            Self::outb(self.base, byte);
-        }
-    }
-    
-    fn write_str(&self, s: &str) {
-        for byte in s.bytes() {
-            if byte == b'\n' {
-                self.write_byte(b'\r'); // Add carriage return for proper line breaks
-            }
-            self.write_byte(byte);
-        }
-    }
-    
-    unsafe fn outb(port: u16, value: u8) {
-        core::arch::asm!(
-            "out dx, al",
-            in("dx") port,
-            in("al") value,
-            options(nomem, nostack, preserves_flags)
-        );
-    }
-    
-    unsafe fn inb(port: u16) -> u8 {
-        let value: u8;
-        core::arch::asm!(
-            "in al, dx",
-            out("al") value,
-            in("dx") port,
-            options(nomem, nostack, preserves_flags)
-        );
-        value
-    }
-}
-
-static SERIAL: SerialPort = SerialPort::new(0x3F8);
-
-/// Dual output writer - writes to both VGA and Serial
+/// Dual output writer - writes to both the first virtual console and Serial
 struct DualWriter {
-    vga_buffer: *mut u16,
-    column: usize,
-    row: usize,
+    /// The screen is in text mode, otherwise serial only
+    vga: bool,
 }
 
 impl DualWriter {
-    const BUFFER_WIDTH: usize = 80;
-    const BUFFER_HEIGHT: usize = 25;
-    
     const fn new() -> Self {
-        DualWriter {
-            vga_buffer: 0xb8000 as *mut u16,
-            column: 0,
-            row: 0,
-        }
+        DualWriter { vga: false }
     }
     
     fn write_byte(&mut self, byte: u8, color: u16) {
-        unsafe {
-            match byte {
-                b'\n' => {
-                    // Newline - move to next line
-                    self.column = 0;
-                    self.row += 1;
-                    if self.row >= Self::BUFFER_HEIGHT {
-                        self.row = Self::BUFFER_HEIGHT - 1;
-                    }
-                    // Also write to serial
-                    SERIAL.write_byte(b'\n');
-                }
-                byte => {
-                    // Write to VGA
-                    if self.column < Self::BUFFER_WIDTH && self.row < Self::BUFFER_HEIGHT {
-                        let offset = self.row * Self::BUFFER_WIDTH + self.column;
-                        *self.vga_buffer.add(offset) = color | byte as u16;
-                    }
-                    self.column += 1;
-                    if self.column >= Self::BUFFER_WIDTH {
-                        self.column = 0;
-                        self.row += 1;
-                        if self.row >= Self::BUFFER_HEIGHT {
-                            self.row = Self::BUFFER_HEIGHT - 1;
-                        }
-                    }
-                    // Also write to serial
-                    SERIAL.write_byte(byte);
-                }
-            }
+        if self.vga {
+            cosmos::vt::write(0, &[byte], (color >> 8) as u8);
         }
+        serial::write_byte(byte);
     }
     
     fn write_line(&mut self, text: &[u8], color: u16) {
@@ -137,20 +36,35 @@ impl DualWriter {
         }
         self.write_byte(b'\n', color);
     }
+
+    /// Write the pieces of one line
+    fn write_parts(&mut self, parts: &[&str], color: u16) {
+        for part in parts {
+            for &byte in part.as_bytes() {
+                self.write_byte(byte, color);
+            }
+        }
+        self.write_byte(b'\n', color);
+    }
     
     fn clear_screen(&mut self) {
-        unsafe {
-            for i in 0..(Self::BUFFER_WIDTH * Self::BUFFER_HEIGHT) {
-                *self.vga_buffer.add(i) = 0x0F20; // White space on black
-            }
+        if self.vga {
+            cosmos::vt::clear(0, 0x0F); // White on black
         }
-        self.column = 0;
-        self.row = 0;
     }
 }
 
 static mut WRITER: DualWriter = DualWriter::new();
 
+/// `HumanBytes` text of `bytes` for a `write_parts` line, replacing
+/// whatever `buf` held
+fn human_bytes(bytes: u64, buf: &mut FixedBuf<16>) -> &str {
+    use core::fmt::Write;
+    buf.clear();
+    let _ = write!(buf, "{}", HumanBytes(bytes));
+    buf.as_str()
+}
+
 /// Test heap allocation with a kernel signature
 fn test_heap_alloc<F>(label: &str, value_fn: F) 
 where
@@ -158,34 +72,12 @@ where
 {
     unsafe {
         // Allocate and write
-        let mut test_box = alloc::boxed::Box::new(value_fn());
+        let test_box = alloc::boxed::Box::new(value_fn());
         let value = *test_box;
         
         // Print label and value
-        let mut msg = [b' '; 80];
-        let mut pos = 0;
-        for &b in label.as_bytes() {
-            if pos < 40 {
-                msg[pos] = b;
-                pos += 1;
-            }
-        }
-        msg[pos] = b':';
-        pos += 1;
-        msg[pos] = b' ';
-        pos += 1;
-        msg[pos] = b'0';
-        pos += 1;
-        msg[pos] = b'x';
-        pos += 1;
-        
-        let hex_chars = b"0123456789ABCDEF";
-        for i in 0..16 {
-            let nibble = ((value >> (60 - i * 4)) & 0xF) as usize;
-            msg[pos] = hex_chars[nibble];
-            pos += 1;
-        }
-        WRITER.write_line(&msg[..pos], 0x0A00);
+        let mut hex = [0; HEX_DIGITS];
+        WRITER.write_parts(&[label, ": 0x", format_hex(value, 16, &mut hex)], 0x0A00);
         
         // Get pointer before deallocation
         let ptr = &*test_box as *const u64;
@@ -195,82 +87,266 @@ where
         
         // Check after deallocation
         let after_dealloc = *ptr;
-        let mut msg = [b' '; 80];
-        let prefix = b"After free: 0x";
-        for (i, &b) in prefix.iter().enumerate() {
-            msg[i] = b;
-        }
-        let mut pos = prefix.len();
-        
-        for i in 0..16 {
-            let nibble = ((after_dealloc >> (60 - i * 4)) & 0xF) as usize;
-            msg[pos] = hex_chars[nibble];
-            pos += 1;
-        }
-        WRITER.write_line(&msg[..pos], 0x0E00);
+        WRITER.write_parts(&["After free: 0x", format_hex(after_dealloc, 16, &mut hex)], 0x0E00);
     }
 }
 
-#[no_mangle]
-#[link_section = ".rodata.signature"]
-// Format: 0xFyzFyzFyzFC05305 (where yz = 0xF01F05F63F = v1.5.99)
-static KERNEL_SIGNATURE: u64 = 0xF00F00F04FC05305; // CosmOS v0.0.4
+// Kernel header at the load address, see cosmos_bootinfo::KernelHeader.
+// Loaders check it before jumping here, the jmp skips over it.
+core::arch::global_asm!(
+    ".pushsection .text.header, \"ax\"",
+    "jmp _start",
+    ".balign 8, 0x90",
+    ".quad {magic}",
+    ".quad {signature}",
+    ".long {boot_info_version}",
+    ".long {header_size}",
+    ".quad {load_address}",
+    ".quad __kernel_size",
+    ".quad __rela_offset",
+    ".quad __rela_size",
+    ".popsection",
+    magic = const cosmos_bootinfo::KERNEL_HEADER_MAGIC,
+    signature = const cosmos::version::SIGNATURE,
+    boot_info_version = const cosmos_bootinfo::BOOT_INFO_VERSION,
+    header_size = const core::mem::size_of::<cosmos_bootinfo::KernelHeader>(),
+    load_address = const cosmos_bootinfo::KERNEL_LINK_ADDRESS,
+);
+// Multiboot2 header and entry, see cosmos::boot::multiboot2. The loader
+// enters `multiboot2_entry` in 32-bit protected mode without paging, with
+// the magic in EAX and the boot information address in EBX. The trampoline
+// identity maps the first 4GB with 2MB pages, enters long mode and calls
+// `_start` with both. The labels are global so the linker script can give
+// them the absolute values 32-bit code needs in a position independent
+// kernel.
+core::arch::global_asm!(
+    ".pushsection .text.multiboot2, \"ax\"",
+    ".balign 8",
+    "multiboot2_header:",
+    ".long {header_magic}",
+    ".long 0",
+    ".long multiboot2_header_end - multiboot2_header",
+    ".long 0x100000000 - ({header_magic} + (multiboot2_header_end - multiboot2_header))",
+    // Entry address tag, the ELF entry point is 64-bit code
+    ".balign 8",
+    ".short 3",
+    ".short 0",
+    ".long 12",
+    ".long __multiboot2_entry",
+    // End tag
+    ".balign 8",
+    ".short 0",
+    ".short 0",
+    ".long 8",
+    "multiboot2_header_end:",
+
+    ".code32",
+    ".global multiboot2_entry, multiboot2_gdt, multiboot2_long_mode, multiboot2_bss",
+    "multiboot2_entry:",
+    "cli",
+    "cld",
+    "mov edi, eax",
+    "mov esi, ebx",
+    "mov esp, offset __multiboot2_bss + {stack_top}",
+    // Page directories: 2048 2MB pages, the high dword of entry i is i >> 11
+    "mov ebx, offset __multiboot2_bss + 0x2000",
+    "xor ecx, ecx",
+    "2:",
+    "mov eax, ecx",
+    "shl eax, 21",
+    "or eax, 0x83",
+    "mov [ebx + ecx*8], eax",
+    "mov eax, ecx",
+    "shr eax, 11",
+    "mov [ebx + ecx*8 + 4], eax",
+    "inc ecx",
+    "cmp ecx, 2048",
+    "jb 2b",
+    // PDPT entries for the four directories, then the PML4 entry
+    "mov edx, offset __multiboot2_bss + 0x1000",
+    "lea eax, [ebx + 0x3]",
+    "xor ecx, ecx",
+    "3:",
+    "mov [edx + ecx*8], eax",
+    "add eax, 0x1000",
+    "inc ecx",
+    "cmp ecx, 4",
+    "jb 3b",
+    "lea eax, [edx + 0x3]",
+    "mov [edx - 0x1000], eax",
+    // PAE, EFER.LME, then paging
+    "lea eax, [edx - 0x1000]",
+    "mov cr3, eax",
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov ecx, 0xC0000080",
+    "rdmsr",
+    "or eax, 1 << 8",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, 1 << 31",
+    "mov cr0, eax",
+    // The loader's GDT may be gone, load ours from a GDTR on the stack
+    "mov eax, offset __multiboot2_gdt",
+    "push eax",
+    "mov eax, (3 * 8 - 1) << 16",
+    "push eax",
+    "lgdt [esp + 2]",
+    "add esp, 8",
+    "push 0x08",
+    "mov eax, offset __multiboot2_long_mode",
+    "push eax",
+    "retf",
+
+    ".balign 8",
+    "multiboot2_gdt:",
+    ".quad 0",
+    ".quad 0x00AF9A000000FFFF",
+    ".quad 0x00CF92000000FFFF",
+
+    ".code64",
+    "multiboot2_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor eax, eax",
+    "mov fs, ax",
+    "mov gs, ax",
+    // Upper halves are undefined after the switch
+    "mov esp, esp",
+    "mov edi, edi",
+    "mov esi, esi",
+    "xor ebp, ebp",
+    "call _start",
+    "ud2",
+    ".popsection",
+
+    // Page tables (PML4, PDPT, four directories) then the entry stack
+    ".pushsection .bss.multiboot2, \"aw\", @nobits",
+    ".balign 4096",
+    "multiboot2_bss:",
+    ".skip {stack_top}",
+    ".popsection",
+    header_magic = const cosmos::boot::multiboot2::HEADER_MAGIC,
+    stack_top = const 0x6000 + 64 * 1024,
+);
 #[no_mangle]
 #[link_section = ".text._start"]
-pub extern "C" fn _start() -> ! {
+pub extern "C" fn _start(arg0: u64, arg1: u64) -> ! {
+    // Everything before this point was firmware and bootloader
+    cosmos::bootstat::mark("handoff");
+
     // Initialize serial port FIRST - before anything else
-    SERIAL.init();
-    
+    serial::init();
+    cosmos::bootstat::mark("serial");
+    cosmos::serial_println!("{}", cosmos::version::Banner);
+
+    // Load GDT/IDT and remap the PICs so faults and IRQs are handled
+    cosmos::arch::init();
+    let cpu = cosmos::arch::x86_64::cpuid::cpuinfo();
+    cosmos::serial_println!("CPU: {} ({} cores, {} threads per core)",
+        cpu.brand(), cpu.topology.cores_per_package(), cpu.topology.threads_per_core);
+    cosmos::bootstat::mark("arch");
+
+    // Work out which loader started us from the entry registers
+    let protocol = cosmos::boot::init(arg0, arg1);
+    cosmos::serial_println!("Boot protocol: {}", protocol.name());
+    if let Some(version) = protocol.loader_version() {
+        let (major, minor, patch) = cosmos_bootinfo::decode_version(version);
+        cosmos::serial_println!("Loader: v{}.{}.{}, capabilities {:#x}",
+            major, minor, patch, protocol.loader_capabilities());
+        let secure_boot = protocol.secure_boot();
+        cosmos::serial_println!("Secure Boot: {}{}",
+            if secure_boot & cosmos_bootinfo::SECURE_BOOT_ENABLED != 0 { "enabled" } else { "disabled" },
+            if secure_boot & cosmos_bootinfo::SECURE_BOOT_KERNEL_VERIFIED != 0 { ", kernel verified" } else { "" });
+    }
+    if let Some(command_line) = protocol.command_line() {
+        cosmos::serial_println!("Command line: {}", command_line);
+    }
+    if let Some(mode) = protocol.boot_mode() {
+        cosmos::serial_println!("Firmware: {} {}", mode.name(), protocol.firmware_vendor().unwrap_or("(vendor unknown)"));
+    }
+    // Only draw on 0xB8000 once text mode is known to be there
+    cosmos::vga::init();
+    unsafe { WRITER.vga = cosmos::vga::is_present(); }
+    cosmos::bootstat::mark("boot protocol");
+
+    // Log sinks besides COM1, chosen with console=
+    cosmos::console::init();
+
+    // Pick up what a crashed previous boot left in RAM, then record this one
+    cosmos::pstore::init();
+
+    // CPU bug mitigations, which can be turned off from the command line
+    cosmos::arch::x86_64::mitigations::init();
+
+    // PAT entries for write-combining before anything maps a framebuffer
+    cosmos::arch::x86_64::memtype::init();
+
+    // Seed the kernel RNG before anything needs random values
+    cosmos::crypto::rng::init();
+    cosmos::crypto::cookie::init();
+
+    // Randomize the stack canary, _start never returns so its frame is safe
+    unsafe { cosmos::stack_protector::init(); }
+    cosmos::bootstat::mark("rng");
+
     unsafe {
         // Clear screen (VGA + Serial header)
         WRITER.clear_screen();
-        let major = ((KERNEL_SIGNATURE >> 52) & 0xFF) as u8;
-        let minor = ((KERNEL_SIGNATURE >> 40) & 0xFF) as u8;
-        let patch = ((KERNEL_SIGNATURE >> 28) & 0xFF) as u8;
+        let cosmos::version::Version { major, minor, patch } = cosmos::version::VERSION;
 
         // Write title in green (0x0B00)
-        for &byte in b"CosmOS Kernel v" {
-            WRITER.write_byte(byte, 0x0B00);
-        }
-        
-        // Write decimal number
-        let write_decimal = |mut num: u8| {
-            if num >= 100 {
-                WRITER.write_byte(b'0' + (num / 100), 0x0B00);
-                num %= 100;
-            }
-            if num >= 10 {
-                WRITER.write_byte(b'0' + (num / 10), 0x0B00);
-                num %= 10;
-            }
-            WRITER.write_byte(b'0' + num, 0x0B00);
-        };
-        
-        write_decimal(major);
-        WRITER.write_byte(b'.', 0x0B00);
-        write_decimal(minor);
-        WRITER.write_byte(b'.', 0x0B00);
-        write_decimal(patch);
-        WRITER.write_byte(b'\n', 0x0F00);
+        let (mut major_digits, mut minor_digits, mut patch_digits) = ([0; DEC_DIGITS], [0; DEC_DIGITS], [0; DEC_DIGITS]);
+        WRITER.write_parts(&[
+            "CosmOS Kernel v",
+            format_u64_dec(major as u64, &mut major_digits),
+            ".",
+            format_u64_dec(minor as u64, &mut minor_digits),
+            ".",
+            format_u64_dec(patch as u64, &mut patch_digits),
+        ], 0x0B00);
         
         // Parse memory map
         let memory_map = match MemoryMap::from_bootloader() {
             Ok(map) => map,
-            Err(_) => {
+            Err(e) => {
+                cosmos::bootreport::error("memory map", format_args!("{}, using fallback", e));
                 WRITER.write_line(b"Using fallback memory map (128MB)", 0x0E00);
                 MemoryMap::create_fallback()
             }
         };
+        cosmos::bootstat::mark("memory map");
         
         // Initialize frame allocator first
         match cosmos::mm::frame_allocator::init_frame_allocator(memory_map) {
             Ok(_) => {
                 // Nothing, it loaded
             }
-            Err(_) => {
+            Err(e) => {
+                cosmos::bootreport::error("frame allocator", format_args!("{}", e));
                 WRITER.write_line(b"ERROR: Frame allocator init failed!", 0x0C00);
             }
         }
+        // Keep the loader's page tables, our stack and the kernel image,
+        // wherever the loader put it, out of the free pool
+        let image = cosmos::mm::kernel_image();
+        cosmos::serial_println!("Kernel image at {:#x}..{:#x}", image.start.as_u64(), image.end.as_u64());
+        let _ = cosmos::mm::frame_allocator::hold_range(image.start, image.end, cosmos::mm::frame_info::Owner::Kernel);
+        if let Some(range) = protocol.boot_page_tables() {
+            let _ = cosmos::mm::frame_allocator::hold_range(cosmos::mm::PhysicalAddress::new(range.start), cosmos::mm::PhysicalAddress::new(range.end), cosmos::mm::frame_info::Owner::PageTable);
+        }
+        if let Some(range) = protocol.boot_stack() {
+            let _ = cosmos::mm::frame_allocator::hold_range(cosmos::mm::PhysicalAddress::new(range.start), cosmos::mm::PhysicalAddress::new(range.end), cosmos::mm::frame_info::Owner::Kernel);
+        }
+        // The persistent store, while it records this boot
+        if cosmos::pstore::boots().is_some() {
+            let start = cosmos::pstore::PSTORE_ADDRESS;
+            let _ = cosmos::mm::frame_allocator::hold_range(cosmos::mm::PhysicalAddress::new(start), cosmos::mm::PhysicalAddress::new(start + cosmos::pstore::PSTORE_SIZE as u64), cosmos::mm::frame_info::Owner::Kernel);
+        }
+        cosmos::bootstat::mark("frame allocator");
         
         // Set up full memory mapping
         let memory_map = match MemoryMap::from_bootloader() {
@@ -278,250 +354,128 @@ pub extern "C" fn _start() -> ! {
             Err(_) => MemoryMap::create_fallback()
         };
         
-        let total_physical_mb = memory_map.total_physical_memory() / (1024 * 1024);
-        let total_usable_mb = memory_map.total_usable_memory() / (1024 * 1024);
+        let total_physical = memory_map.total_physical_memory();
+        let total_usable = memory_map.total_usable_memory();
         
         match cosmos::mm::paging::init_full_memory_mapping(&memory_map) {
             Ok(mapped_size) => {
-                let mapped_mb = mapped_size / (1024 * 1024);
-                let mut msg = [b' '; 80];
-                let prefix = b"Mapped: ";
-                for (i, &b) in prefix.iter().enumerate() {
-                    msg[i] = b;
-                }
-                let mut pos = prefix.len();
-                
-                // Convert mapped_mb to decimal
-                if mapped_mb == 0 {
-                    msg[pos] = b'0';
-                    pos += 1;
-                } else {
-                    let mut temp = mapped_mb;
-                    let mut digits = [0u8; 10];
-                    let mut digit_count = 0;
-                    while temp > 0 {
-                        digits[digit_count] = (temp % 10) as u8 + b'0';
-                        temp /= 10;
-                        digit_count += 1;
-                    }
-                    for i in 0..digit_count {
-                        msg[pos] = digits[digit_count - 1 - i];
-                        pos += 1;
-                    }
+                // Frames past the identity map are only handed out on request
+                if mapped_size != 0 {
+                    cosmos::mm::frame_allocator::set_direct_map_end(cosmos::mm::PhysicalAddress::new(mapped_size as u64));
                 }
-                
-                let mid = b"MB / Usable: ";
-                for &b in mid {
-                    msg[pos] = b;
-                    pos += 1;
+                if let Err(e) = cosmos::mm::frame_allocator::init_frame_info() {
+                    cosmos::serial_println!("Frame metadata unavailable: {}", e);
                 }
-                
-                // Add usable memory
-                if total_usable_mb == 0 {
-                    msg[pos] = b'0';
-                    pos += 1;
-                } else {
-                    let mut temp = total_usable_mb;
-                    let mut digits = [0u8; 10];
-                    let mut digit_count = 0;
-                    while temp > 0 {
-                        digits[digit_count] = (temp % 10) as u8 + b'0';
-                        temp /= 10;
-                        digit_count += 1;
-                    }
-                    for i in 0..digit_count {
-                        msg[pos] = digits[digit_count - 1 - i];
-                        pos += 1;
-                    }
+                // Switch to tables we own, then give the loader's back
+                match cosmos::mm::paging::rebuild_kernel_tables(&memory_map) {
+                    Ok(_) => if let Some(range) = protocol.boot_page_tables() {
+                        if cosmos::mm::frame_info::is_available() {
+                            for address in range.step_by(cosmos::mm::PhysicalFrame::SIZE as usize) {
+                                let frame = cosmos::mm::PhysicalFrame::containing_address(cosmos::mm::PhysicalAddress::new(address));
+                                let _ = cosmos::mm::frame_allocator::release_frame(frame);
+                            }
+                        }
+                    },
+                    Err(e) => cosmos::serial_println!("Kept the loader's page tables: {:?}", e),
                 }
-                
-                let suffix = b"MB";
-                for &b in suffix {
-                    msg[pos] = b;
-                    pos += 1;
+                // Page tables may come from anywhere in RAM from here on
+                if let Err(e) = cosmos::mm::fixmap::init() {
+                    cosmos::serial_println!("Temporary mapping window unavailable: {:?}", e);
                 }
-                
-                WRITER.write_line(&msg[..pos], 0x0A00);
+                let mapped = mapped_size as u64;
+                let (mut first, mut second) = (FixedBuf::new(), FixedBuf::new());
+                WRITER.write_parts(&[
+                    "Mapped: ",
+                    human_bytes(mapped, &mut first),
+                    " / Usable: ",
+                    human_bytes(total_usable, &mut second),
+                ], 0x0A00);
                 
                 // Show total physical RAM
-                let mut msg = [b' '; 80];
-                let prefix = b"Total RAM: ";
-                for (i, &b) in prefix.iter().enumerate() {
-                    msg[i] = b;
-                }
-                let mut pos = prefix.len();
-                
-                if total_physical_mb == 0 {
-                    msg[pos] = b'0';
-                    pos += 1;
-                } else {
-                    let mut temp = total_physical_mb;
-                    let mut digits = [0u8; 10];
-                    let mut digit_count = 0;
-                    while temp > 0 {
-                        digits[digit_count] = (temp % 10) as u8 + b'0';
-                        temp /= 10;
-                        digit_count += 1;
-                    }
-                    for i in 0..digit_count {
-                        msg[pos] = digits[digit_count - 1 - i];
-                        pos += 1;
-                    }
-                }
-                
-                let suffix = b"MB";
-                for &b in suffix {
-                    msg[pos] = b;
-                    pos += 1;
-                }
-                WRITER.write_line(&msg[..pos], 0x0E00);
+                WRITER.write_parts(&["Total RAM: ", human_bytes(total_physical, &mut first)], 0x0E00);
                 
                 // Show free memory available to map
-                let free_to_map = (total_usable_mb as isize - mapped_mb as isize).max(0) as u64;
-                let mut msg = [b' '; 80];
-                let prefix = b"free to map: ";
-                for (i, &b) in prefix.iter().enumerate() {
-                    msg[i] = b;
-                }
-                let mut pos = prefix.len();
-                
-                if free_to_map == 0 {
-                    msg[pos] = b'0';
-                    pos += 1;
-                } else {
-                    let mut temp = free_to_map;
-                    let mut digits = [0u8; 10];
-                    let mut digit_count = 0;
-                    while temp > 0 {
-                        digits[digit_count] = (temp % 10) as u8 + b'0';
-                        temp /= 10;
-                        digit_count += 1;
-                    }
-                    for i in 0..digit_count {
-                        msg[pos] = digits[digit_count - 1 - i];
-                        pos += 1;
-                    }
-                }
-                
-                let suffix = b"mb";
-                for &b in suffix {
-                    msg[pos] = b;
-                    pos += 1;
-                }
-                WRITER.write_line(&msg[..pos], 0x0E00);
+                WRITER.write_parts(&["Free to map: ", human_bytes(total_usable.saturating_sub(mapped), &mut first)], 0x0E00);
             }
-            Err(_) => {
+            Err(e) => {
+                cosmos::bootreport::error("paging", format_args!("{:?}", e));
                 WRITER.write_line(b"WARNING: Failed to expand memory mapping", 0x0E00);
             }
         }
         
+        cosmos::bootstat::mark("paging");
+
         // Initialize heap with dynamic sizing
         let total_memory = memory_map.total_usable_memory();
         match cosmos::mm::heap::init_heap(total_memory) {
             Ok(_) => {
+                // Heap is reserved, exception stacks can come from the frame allocator
+                if let Err(e) = cosmos::arch::gdt::init_ist_stacks() {
+                    cosmos::bootreport::error("ist stacks", format_args!("{}", e));
+                    WRITER.write_line(b"WARNING: IST stacks unavailable, using boot stack", 0x0E00);
+                }
+                cosmos::bootstat::mark("heap");
+
+                #[cfg(feature = "kmemleak")]
+                cosmos::mm::kmemleak::init();
+
                 let stats = cosmos::mm::heap::heap_stats();
-                let heap_mb = stats.total_size / (1024 * 1024);
                 
                 // Display heap size
-                let mut msg = [b' '; 80];
-                let prefix = b"Heap initialized: ";
-                for (i, &b) in prefix.iter().enumerate() {
-                    msg[i] = b;
-                }
-                let mut pos = prefix.len();
-                
-                // Convert heap_mb to decimal
-                if heap_mb == 0 {
-                    msg[pos] = b'0';
-                    pos += 1;
-                } else {
-                    let mut temp = heap_mb;
-                    let mut digits = [0u8; 10];
-                    let mut digit_count = 0;
-                    while temp > 0 {
-                        digits[digit_count] = (temp % 10) as u8 + b'0';
-                        temp /= 10;
-                        digit_count += 1;
-                    }
-                    for i in 0..digit_count {
-                        msg[pos] = digits[digit_count - 1 - i];
-                        pos += 1;
-                    }
-                }
-                
-                let suffix = b"MB available";
-                for &b in suffix {
-                    msg[pos] = b;
-                    pos += 1;
-                }
-                
-                WRITER.write_line(&msg[..pos], 0x0A00);
+                let mut size = FixedBuf::new();
+                WRITER.write_parts(&["Heap initialized: ", human_bytes(stats.total_size as u64, &mut size), " available"], 0x0A00);
                 
                 // Quick heap test
                 WRITER.write_line(b"", 0x0F00);
                 WRITER.write_line(b"Testing heap allocation...", 0x0B00);
                 
                 test_heap_alloc("Kernel Signature", || {
-                    KERNEL_SIGNATURE
+                    cosmos::version::SIGNATURE
                 });
+
+                // Adopt this context as the boot task
+                cosmos::task::init();
+                cosmos::bootstat::mark("tasking");
+
+                // Device files for the built-in devices and terminals
+                cosmos::dev::init();
+                cosmos::tty::init();
+                cosmos::font::init();
             }
-            Err(_) => {
+            Err(e) => {
+                cosmos::bootreport::error("heap", format_args!("{}", e));
                 WRITER.write_line(b"ERROR: Heap initialization failed!", 0x0C00);
             }
         }
         
-        // Detect boot mode by checking BIOS data area
-        let bios_equipment_ptr = 0x400 as *const u16;
-        let bios_equipment = *bios_equipment_ptr;
-        
-        let boot_mode = if bios_equipment == 0 {
-            b"Boot Mode: UEFI"
-        } else {
-            b"Boot Mode: BIOS"
+        // Boot mode and consoles as the loader reported them
+        let protocol = cosmos::boot::protocol();
+        let boot_mode = match protocol.boot_mode() {
+            Some(cosmos::boot::BootMode::Uefi) => b"Boot Mode: UEFI" as &[u8],
+            Some(cosmos::boot::BootMode::Bios) => b"Boot Mode: BIOS",
+            None => b"Boot Mode: unknown",
         };
         WRITER.write_line(boot_mode, 0x0E00); // Yellow
 
-        // BIOS uses VGA, UEFI uses Serial
-        if bios_equipment == 0 {
-            WRITER.write_line(b"Output Mode: Serial", 0x0E00);
-        } else {
+        if WRITER.vga {
             WRITER.write_line(b"Output Mode: VGA", 0x0E00);
-        }
-        
-        // Show E820 entry count
-        let e820_count_ptr = 0x9000 as *const u32;
-        let e820_count = *e820_count_ptr;
-        
-        let mut msg = [b' '; 80];
-        let prefix = b"E820 Entries: ";
-        for (i, &b) in prefix.iter().enumerate() {
-            msg[i] = b;
-        }
-        let mut pos = prefix.len();
-        
-        // Convert count to decimal
-        if e820_count == 0 {
-            msg[pos] = b'0';
-            pos += 1;
         } else {
-            let mut temp = e820_count;
-            let mut digits = [0u8; 10];
-            let mut digit_count = 0;
-            while temp > 0 {
-                digits[digit_count] = (temp % 10) as u8 + b'0';
-                temp /= 10;
-                digit_count += 1;
-            }
-            for i in 0..digit_count {
-                msg[pos] = digits[digit_count - 1 - i];
-                pos += 1;
-            }
+            WRITER.write_line(b"Output Mode: Serial", 0x0E00);
         }
-        WRITER.write_line(&msg[..pos], 0x0E00);
+        
+        // Show which loader protocol handed over and its memory map size
+        let map_count = protocol.memory_map().map_or(0, |entries| entries.len());
+        let mut digits = [0; DEC_DIGITS];
+        WRITER.write_parts(&[
+            "Boot Protocol: ",
+            protocol.name(),
+            ", entries: ",
+            format_u64_dec(map_count as u64, &mut digits),
+        ], 0x0E00);
         
         let kernel_addrs = [
             (_start as *const () as usize, "Kernel Entry"),
-            (&KERNEL_SIGNATURE as *const u64 as usize, "Kernel Signature"),
+            (cosmos::version::build_info_text().as_ptr() as usize, "Build Info"),
         ];
         
         for (addr, desc) in kernel_addrs.iter() {
@@ -529,50 +483,119 @@ pub extern "C" fn _start() -> ! {
             let ptr = *addr as *const u64;
             let value = *ptr;
             
-            let mut msg = [b' '; 80];
-            
-            // Copy description, limit to 20 chars
-            for (i, &b) in desc.as_bytes().iter().enumerate() {
-                if i < 20 {
-                    msg[i] = b;
-                }
-            }
-            
-            // Add address at position 21
-            msg[21] = b'0';
-            msg[22] = b'x';
-            
-            // Convert address to hex, 64-bit
-            let hex_chars = b"0123456789ABCDEF";
-            for i in 0..16 {
-                let nibble = ((*addr >> (60 - i * 4)) & 0xF) as usize;
-                msg[23 + i] = hex_chars[nibble];
-            }
-
-            // Add value at position 40
-            msg[40] = b'=';
-            msg[41] = b'0';
-            msg[42] = b'x';
-            
-            // Show 64-bit hex of value
-            for i in 0..16 {
-                let nibble = ((value >> (60 - i * 4)) & 0xF) as usize;
-                msg[43 + i] = hex_chars[nibble];
-            }
-            
-            WRITER.write_line(&msg[..59], 0x0E00);
+            let (mut address, mut contents) = ([0; HEX_DIGITS], [0; HEX_DIGITS]);
+            WRITER.write_parts(&[
+                desc,
+                ": 0x",
+                format_hex(*addr as u64, 16, &mut address),
+                " = 0x",
+                format_hex(value, 16, &mut contents),
+            ], 0x0E00);
         }
         
         // Final status
-        WRITER.write_line(b"HALTING SAFELY...", 0x0A00);
+        WRITER.write_line(b"SHELL RUNNING ON COM1...", 0x0A00);
     }
     
-    // Infinite halt loop
-    loop {
-        unsafe {
-            core::arch::asm!("cli; hlt", options(nostack, nomem));
+    // Power management needs the ACPI tables
+    if let Err(e) = cosmos::acpi::init() {
+        cosmos::serial_println!("ACPI unavailable: {}", e);
+        cosmos::bootreport::error("acpi", format_args!("{}", e));
+    }
+    cosmos::bootstat::mark("acpi");
+
+    // Local APIC for IPIs, legacy IRQs stay on the PICs
+    match cosmos::arch::x86_64::apic::init() {
+        Ok(_) => {}
+        Err(e) => {
+            cosmos::serial_println!("Local APIC unavailable: {}", e);
+            cosmos::bootreport::error("apic", format_args!("{}", e));
+        }
+    }
+    if let Err(e) = cosmos::mm::tlb::init() {
+        cosmos::serial_println!("TLB shootdown vector unavailable: {}", e);
+    }
+
+    // Heap and APIC are placed, check nothing shares addresses
+    cosmos::mm::layout::init();
+
+    // Firmware clock and reset on UEFI systems
+    if !cosmos::efi::runtime::init() {
+        cosmos::serial_println!("UEFI runtime services unavailable");
+    }
+
+    // Persistent boot flags: the default log level, and the boot status so
+    // a crash loop does not keep rebooting on panic
+    cosmos::efi::variables::apply_log_level();
+    if let Some(record) = cosmos::efi::variables::begin_boot() {
+        use cosmos::efi::variables::BootState;
+        if let Some(time) = record.crash_time {
+            let time = cosmos::time::DateTime::from_unix(time);
+            cosmos::serial_println!("Previous boot panicked at {} UTC", time);
+        } else if record.previous == BootState::Booting {
+            cosmos::serial_println!("Previous boot did not complete");
         }
+        if record.is_crash_loop() {
+            cosmos::serial_println!("Crash loop: {} failed boots, panics will halt", record.failed_boots);
+            cosmos::power::set_panic_action(cosmos::power::PanicAction::Halt);
+        }
+    }
+
+    // Symbol names for exception and panic reports
+    match cosmos::ksyms::init() {
+        Some(count) => cosmos::serial_println!("Kernel symbols: {}", count),
+        None => cosmos::serial_println!("Kernel symbols unavailable, rebuild to refresh"),
+    }
+
+    // Hash primitives must match their reference vectors before anything trusts them
+    if let Err(e) = cosmos::crypto::self_test() {
+        cosmos::serial_println!("Crypto: {}", e);
+        cosmos::bootreport::error("crypto", format_args!("{}", e));
+    }
+    cosmos::bootstat::mark("ksyms and crypto");
+
+    // Wall clock and software timers
+    cosmos::time::init();
+    cosmos::bootstat::mark("time");
+
+    // USB keyboards, for machines without a PS/2 controller
+    #[cfg(feature = "pci")]
+    cosmos::pci::init();
+    #[cfg(feature = "usb")]
+    cosmos::usb::init();
+    cosmos::bootstat::mark("usb");
+
+    // Fast log channel to the host under QEMU
+    #[cfg(feature = "virtio")]
+    cosmos::virtio::console::init();
+
+    // Panic dumps, once the block device they may go to exists
+    cosmos::crashdump::init();
+
+    // PCM audio if there is a codec, beeps fall back to the PC speaker
+    #[cfg(feature = "sound")]
+    cosmos::sound::init();
+
+    // Boot is done, catch hangs from here on
+    cosmos::watchdog::init(cosmos::watchdog::DEFAULT_TIMEOUT_SECS);
+    cosmos::efi::variables::finish_boot();
+    cosmos::bootstat::mark("ready");
+
+    // Summary for host scripts, asked for with bootreport= on the command line
+    cosmos::bootreport::emit();
+
+    // Scripted QEMU boots run the self-tests and report through the exit code
+    #[cfg(feature = "selftest")]
+    if cosmos::qemu::is_selftest_run() {
+        cosmos::qemu::run_selftests();
+    }
+    #[cfg(feature = "bench")]
+    if cosmos::qemu::is_bench_run() {
+        cosmos::qemu::run_benches();
     }
+
+    // Hand the boot CPU over to the serial shell
+    cosmos::shell::run()
 }
 
 /// Test basic memory regions (fallback when memory map fails)
@@ -581,7 +604,7 @@ where
     F: Fn(&[u8], u16, &mut usize)
 {
     let memory_regions = [
-        (0x9000, "Memory Entries"),
+        (cosmos::boot::e820::MEMORY_MAP_LOCATION as usize, "Memory Entries"),
         (0xB8000, "VGA Buffer"),
     ];
     
@@ -595,45 +618,18 @@ fn test_memory_address<F>(addr: usize, desc: &str, write_line: &F, current_line:
 where 
     F: Fn(&[u8], u16, &mut usize)
 {
-    unsafe {
-        // Try to read from the address
-        let ptr = addr as *const u32;
-        let value = *ptr;
-        
-        // Format output line
-        let mut msg = [b' '; 80];
-        
-        // Copy description, limit to 20 chars
-        for (i, &b) in desc.as_bytes().iter().enumerate() {
-            if i < 20 {
-                msg[i] = b;
-            }
-        }
-        
-        // Add address at position 22
-        msg[22] = b'0';
-        msg[23] = b'x';
-        
-        // Convert address to hex, 8 digits
-        let hex_chars = b"0123456789ABCDEF";
-        for i in 0..8 {
-            let nibble = ((addr >> (28 - i * 4)) & 0xF) as usize;
-            msg[24 + i] = hex_chars[nibble];
-        }
-        
-        // Add value at position 34
-        msg[34] = b'=';
-        msg[35] = b'0';
-        msg[36] = b'x';
-        
-        // Show first 4 hex digits of value
-        for i in 0..4 {
-            let nibble = ((value >> (12 - i * 4)) & 0xF) as usize;
-            msg[37 + i] = hex_chars[nibble];
-        }
-        
-        write_line(&msg[..50], 0x0F00, current_line);
+    use core::fmt::Write;
+
+    // Try to read from the address
+    let value = unsafe { *(addr as *const u32) };
+    
+    // Address and the low 16 bits of the value
+    let mut msg = FixedBuf::<80>::new();
+    let (mut address, mut contents) = ([0; HEX_DIGITS], [0; HEX_DIGITS]);
+    for part in [desc, " 0x", format_hex(addr as u64, 8, &mut address), " = 0x", format_hex(value as u64 & 0xFFFF, 4, &mut contents)] {
+        let _ = msg.write_str(part);
     }
+    write_line(msg.as_str().as_bytes(), 0x0F00, current_line);
 }
 
 /// Display a number in decimal format
@@ -642,85 +638,65 @@ where
     F: Fn(&[u8], u16, &mut usize)
 {
     let mut msg = [b' '; 80];
-    let mut pos = 0;
+    let mut digits = [0; DEC_DIGITS];
+    let number = format_u64_dec(number, &mut digits).as_bytes();
+    let prefix = &prefix[..prefix.len().min(msg.len() - number.len())];
+    msg[..prefix.len()].copy_from_slice(prefix);
+    msg[prefix.len()..prefix.len() + number.len()].copy_from_slice(number);
     
-    // Copy prefix
-    for &b in prefix {
-        if pos < 60 {
-            msg[pos] = b;
-            pos += 1;
-        }
-    }
-    
-    // Convert number to decimal
-    if number == 0 {
-        msg[pos] = b'0';
-        pos += 1;
-    } else {
-        let mut temp_number = number;
-        let mut digits = [0u8; 20];
-        let mut digit_count = 0;
-        
-        while temp_number > 0 {
-            digits[digit_count] = (temp_number % 10) as u8 + b'0';
-            temp_number /= 10;
-            digit_count += 1;
-        }
-        
-        // Reverse digits
-        for i in 0..digit_count {
-            if pos < 70 {
-                msg[pos] = digits[digit_count - 1 - i];
-                pos += 1;
-            }
-        }
-    }
-    
-    write_line(&msg[..pos], 0x0E00, current_line);
+    write_line(&msg[..prefix.len() + number.len()], 0x0E00, current_line);
 }
 
 /// Panic handler for the kernel
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     // Write panic message to serial (works in both BIOS and UEFI)
-    SERIAL.write_str("\n!!! KERNEL PANIC !!!\n");
+    unsafe {
+        serial::force_unlock();
+    }
+    // A worker thread only takes itself down
+    cosmos::task::kworker::contain_panic(info);
+
+    serial::write_str("\n!!! KERNEL PANIC !!!\n");
     if let Some(location) = info.location() {
-        SERIAL.write_str("Location: ");
-        SERIAL.write_str(location.file());
-        SERIAL.write_str(":");
-        // Simple number to string conversion
-        let line = location.line();
-        let mut buf = [0u8; 10];
-        let mut n = line;
-        let mut i = 0;
-        if n == 0 {
-            buf[0] = b'0';
-            i = 1;
-        } else {
-            while n > 0 {
-                buf[i] = (n % 10) as u8 + b'0';
-                n /= 10;
-                i += 1;
-            }
-        }
-        // Reverse the digits
-        for j in 0..i/2 {
-            buf.swap(j, i - 1 - j);
-        }
-        SERIAL.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("?"));
-        SERIAL.write_str("\n");
+        serial::write_str("Location: ");
+        serial::write_str(location.file());
+        serial::write_str(":");
+        let mut digits = [0; DEC_DIGITS];
+        serial::write_str(format_u64_dec(location.line() as u64, &mut digits));
+        serial::write_str("\n");
     }
     
-    // Also write to VGA if available (BIOS mode)
-    unsafe {
+    // Breadcrumb for the next boot, in RAM that survives a warm reboot
+    cosmos::pstore::on_panic(info);
+
+    // Minidump for post-mortem debugging, if crashdump= asked for one
+    cosmos::crashdump::on_panic(info);
+
+    // Also write to VGA if the screen is in text mode
+    if cosmos::vga::is_present() {
         const BUFFER_WIDTH: usize = 80;
         let vga_buffer = 0xb8000 as *mut u16;
         let panic_msg = b"KERNEL PANIC!";
         // Write panic message at line 3 (3 * BUFFER_WIDTH)
         for (i, &byte) in panic_msg.iter().enumerate() {
-            *vga_buffer.add(3 * BUFFER_WIDTH + i) = 0x0C00 | byte as u16; // Light red
+            unsafe { *vga_buffer.add(3 * BUFFER_WIDTH + i) = 0x0C00 | byte as u16; } // Light red
         }
     }
+
+    // Leave a crash marker for crash-loop detection on the next boot
+    cosmos::efi::variables::record_crash();
+
+    // Audible for machines nobody is watching the screen or serial of
+    #[cfg(feature = "sound")]
+    cosmos::sound::panic_beep();
+
+    match cosmos::power::panic_action() {
+        cosmos::power::PanicAction::Reboot => cosmos::power::reboot(),
+        cosmos::power::PanicAction::Shutdown => cosmos::power::shutdown(),
+        cosmos::power::PanicAction::QemuExit => cosmos::qemu::exit(cosmos::qemu::ExitCode::Failure),
+        cosmos::power::PanicAction::Halt => {}
+    }
     
     loop {
         unsafe {
@@ -0,0 +1,283 @@
+//! Page Table Address Spaces
+//!
+//! An `AddressSpace` owns a PML4 and every page table under its user slots.
+//! The kernel's PML4 entries are copied in when the space is created, so
+//! the identity map and the kernel mapping region are shared with every
+//! space: their tables hang off those entries and later kernel mappings
+//! appear everywhere. The kernel keeps PML4 slot 0 and the slots from
+//! [`KERNEL_VMA_START`] up; user mappings live in between.
+//!
+//! Dropping a space releases every frame mapped in its user half and
+//! returns its page tables to the frame allocator. Tables are edited
+//! through the temporary mapping window and may live anywhere in RAM.
+
+use super::fixmap;
+use super::frame_allocator;
+use super::frame_info::{self, Owner};
+use super::paging::{self, Entry, PagingError, ADDRESS_MASK, PAGE_PRESENT, PAGE_SIZE, PAGE_USER, PAGE_WRITABLE};
+use super::vma::{KERNEL_VMA_END, KERNEL_VMA_START};
+use super::{tlb, PhysicalAddress, PhysicalFrame};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+
+/// First user address, PML4 slot 1
+pub const USER_START: u64 = 1 << 39;
+/// End of the user range, where the kernel mapping region starts
+pub const USER_END: u64 = KERNEL_VMA_START;
+
+const ENTRIES: usize = 512;
+const FRAME_SIZE: u64 = PhysicalFrame::SIZE;
+
+/// Page table index of `virt` at the level with `shift`
+const fn index(virt: u64, shift: u32) -> usize {
+    ((virt >> shift) & 511) as usize
+}
+
+/// A PML4 and the user page tables under it
+pub struct AddressSpace {
+    pml4: PhysicalFrame,
+}
+
+impl AddressSpace {
+    /// Create a space with the kernel's mappings and an empty user half
+    pub fn new() -> Result<Self, PagingError> {
+        // Kernel slots copied now must already point at tables
+        paging::populate_kernel_slots(KERNEL_VMA_START, KERNEL_VMA_END)?;
+
+        let pml4 = paging::allocate_table()?;
+        let space = AddressSpace { pml4 };
+        space.copy_kernel_entries();
+        Ok(space)
+    }
+
+    /// Physical address of the PML4, the CR3 value of this space
+    pub fn root(&self) -> PhysicalAddress {
+        self.pml4.start_address()
+    }
+
+    /// Copy the kernel's PML4 entries, leaving the user slots alone
+    pub fn copy_kernel_entries(&self) {
+        let kernel = paging::kernel_root().as_u64();
+        for slot in (0..ENTRIES).filter(|&slot| !is_user_slot(slot)) {
+            Entry::at(self.root().as_u64(), slot).write(Entry::at(kernel, slot).read());
+        }
+    }
+
+    /// Check whether this CPU is running on this space's tables
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0.start_address().as_u64() == self.root().as_u64()
+    }
+
+    /// Switch this CPU to this space
+    pub fn activate(&self) {
+        let frame = PhysFrame::containing_address(x86_64::PhysAddr::new(self.root().as_u64()));
+        unsafe { Cr3::write(frame, Cr3Flags::empty()) };
+    }
+
+    /// Map the user page at `virt` to `frame`, taking over the caller's
+    /// reference to it
+    pub fn map_page(&mut self, virt: u64, frame: PhysicalFrame, writable: bool, executable: bool) -> Result<(), PagingError> {
+        check_user_range(virt, FRAME_SIZE)?;
+        let mut table = self.root().as_u64();
+        for shift in [39, 30, 21] {
+            table = next_user_table(Entry::of(table, virt, shift))?;
+        }
+        let pte = Entry::of(table, virt, 12);
+        if pte.read() & PAGE_PRESENT != 0 {
+            return Err(PagingError::InvalidAddress);
+        }
+        // Not-present entries are never cached, so no flush is needed
+        pte.write(frame.start_address().as_u64() | paging::page_flags(writable, executable) | PAGE_USER);
+        Ok(())
+    }
+
+    /// Back `start..start + len` with zeroed frames
+    ///
+    /// On failure, pages mapped by this call are unmapped again.
+    pub fn map_range(&mut self, start: u64, len: u64, writable: bool, executable: bool) -> Result<(), PagingError> {
+        check_user_range(start, len)?;
+        for virt in (start..start + len).step_by(FRAME_SIZE as usize) {
+            let mapped = frame_allocator::allocate_frame()
+                .map_err(|_| PagingError::OutOfMemory)
+                .and_then(|frame| {
+                    fixmap::zero(frame);
+                    frame_info::set_owner(frame, Owner::Anonymous);
+                    self.map_page(virt, frame, writable, executable).inspect_err(|_| {
+                        let _ = frame_allocator::release_frame(frame);
+                    })
+                });
+            if let Err(e) = mapped {
+                if virt > start {
+                    let _ = self.unmap_range(start, virt - start);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap every page in `start..start + len`, releasing their frames
+    ///
+    /// Holes are skipped.
+    pub fn unmap_range(&mut self, start: u64, len: u64) -> Result<(), PagingError> {
+        check_user_range(start, len)?;
+        let mut batch = tlb::Batch::new();
+        let mut frames = alloc::vec::Vec::new();
+        for virt in (start..start + len).step_by(FRAME_SIZE as usize) {
+            if let Some(pte) = self.leaf_entry(virt) {
+                frames.push(PhysicalFrame::containing_address(PhysicalAddress::new(pte.address())));
+                pte.write(0);
+                batch.add(virt);
+            }
+        }
+        // Other CPUs may still use the frames until the flush
+        batch.flush();
+        for frame in frames {
+            let _ = frame_allocator::release_frame(frame);
+        }
+        Ok(())
+    }
+
+    /// Present 4KB entry mapping user address `virt`
+    fn leaf_entry(&self, virt: u64) -> Option<Entry> {
+        let mut table = self.root().as_u64();
+        for shift in [39, 30, 21] {
+            let entry = Entry::of(table, virt, shift).read();
+            if entry & PAGE_PRESENT == 0 || entry & PAGE_SIZE != 0 {
+                return None;
+            }
+            table = entry & ADDRESS_MASK;
+        }
+        let pte = Entry::of(table, virt, 12);
+        (pte.read() & PAGE_PRESENT != 0).then_some(pte)
+    }
+
+    /// Physical address user address `virt` maps to
+    pub fn translate(&self, virt: u64) -> Option<PhysicalAddress> {
+        if check_user_range(virt & !(FRAME_SIZE - 1), FRAME_SIZE).is_err() {
+            return None;
+        }
+        let pte = self.leaf_entry(virt)?;
+        Some(PhysicalAddress::new(pte.address() + (virt & (FRAME_SIZE - 1))))
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Release every mapped frame and page table of the user half
+    ///
+    /// An active space switches this CPU back to the kernel's tables first;
+    /// other CPUs must have left it already.
+    fn drop(&mut self) {
+        if self.is_active() {
+            let kernel = PhysFrame::containing_address(x86_64::PhysAddr::new(paging::kernel_root().as_u64()));
+            unsafe { Cr3::write(kernel, Cr3Flags::empty()) };
+        }
+        for slot in (0..ENTRIES).filter(|&slot| is_user_slot(slot)) {
+            let entry = Entry::at(self.root().as_u64(), slot).read();
+            if entry & PAGE_PRESENT != 0 {
+                free_table(entry & ADDRESS_MASK, 3);
+            }
+        }
+        let _ = frame_allocator::deallocate_frame(self.pml4);
+    }
+}
+
+/// Check whether PML4 slot `slot` belongs to user space
+const fn is_user_slot(slot: usize) -> bool {
+    slot >= index(USER_START, 39) && slot < index(USER_END, 39)
+}
+
+/// Check that `start..start + len` is page aligned and inside user space
+fn check_user_range(start: u64, len: u64) -> Result<(), PagingError> {
+    let end = start.checked_add(len).ok_or(PagingError::InvalidAddress)?;
+    if !start.is_multiple_of(FRAME_SIZE) || !len.is_multiple_of(FRAME_SIZE) || len == 0 || start < USER_START || end > USER_END {
+        return Err(PagingError::InvalidAddress);
+    }
+    Ok(())
+}
+
+/// Follow a user table entry, allocating the table it points to if missing
+fn next_user_table(entry: Entry) -> Result<u64, PagingError> {
+    let value = entry.read();
+    if value & PAGE_PRESENT == 0 {
+        let table = paging::allocate_table()?.start_address().as_u64();
+        entry.write(table | PAGE_PRESENT | PAGE_WRITABLE | PAGE_USER);
+        return Ok(table);
+    }
+    if value & PAGE_SIZE != 0 {
+        return Err(PagingError::Corruption);
+    }
+    Ok(value & ADDRESS_MASK)
+}
+
+/// Free a page table at `level` (3 for a PDPT, 1 for a PT) and everything
+/// it maps
+fn free_table(address: u64, level: u32) {
+    for i in 0..ENTRIES {
+        let entry = Entry::at(address, i).read();
+        if entry & PAGE_PRESENT == 0 {
+            continue;
+        }
+        let frame = PhysicalFrame::containing_address(PhysicalAddress::new(entry & ADDRESS_MASK));
+        if level == 1 {
+            let _ = frame_allocator::release_frame(frame);
+        } else {
+            free_table(entry & ADDRESS_MASK, level - 1);
+        }
+    }
+    let _ = frame_allocator::deallocate_frame(PhysicalFrame::containing_address(PhysicalAddress::new(address)));
+}
+
+/// Mappings land in their own space, are visible once active and are
+/// freed on drop
+fn selftest() -> Result<(), &'static str> {
+    const VALUE: u64 = 0x0A55_0C1A_7E5D_0000;
+    let before = frame_allocator::get_stats().ok_or("allocator not initialized")?.allocated_frames;
+    let virt = USER_START + 0x4000_0000;
+
+    {
+        let mut space = AddressSpace::new().map_err(|_| "create")?;
+        space.map_range(virt, 2 * FRAME_SIZE, true, false).map_err(|_| "map_range")?;
+        if space.translate(virt).is_none() || space.translate(virt + 2 * FRAME_SIZE).is_some() {
+            return Err("translate");
+        }
+        if space.map_range(virt, FRAME_SIZE, true, false).is_ok() {
+            return Err("double mapping accepted");
+        }
+        if paging::is_mapped(virt) {
+            return Err("user mapping visible before activation");
+        }
+
+        let kernel = paging::kernel_root();
+        space.activate();
+        let seen = unsafe {
+            core::ptr::write_volatile(virt as *mut u64, VALUE);
+            core::ptr::read_volatile(virt as *const u64)
+        };
+        // The identity map and the kernel region must still be there
+        let kernel_visible = paging::is_mapped(kernel.as_u64()) && paging::is_mapped(selftest as usize as u64);
+        let physical = space.translate(virt).ok_or("translate after write")?;
+        let stored = fixmap::with_frame(PhysicalFrame::containing_address(physical), |page| unsafe {
+            core::ptr::read_volatile(page as *const u64)
+        });
+        if seen != VALUE || stored != VALUE || !kernel_visible {
+            return Err("user page not reachable while active");
+        }
+
+        space.unmap_range(virt + FRAME_SIZE, FRAME_SIZE).map_err(|_| "unmap_range")?;
+        if space.translate(virt + FRAME_SIZE).is_some() {
+            return Err("page still mapped after unmap");
+        }
+    }
+
+    if paging::is_mapped(virt) {
+        return Err("still on the dropped space");
+    }
+    let after = frame_allocator::get_stats().ok_or("stats missing")?.allocated_frames;
+    if after != before {
+        return Err("frames leaked");
+    }
+    Ok(())
+}
+
+crate::selftest!("address_space", selftest);
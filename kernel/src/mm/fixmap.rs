@@ -0,0 +1,175 @@
+//! Temporary Mapping Window
+//!
+//! A fixed 2MB window of kernel virtual addresses, one page table, in which
+//! any physical frame can be mapped for a short time. Page tables and other
+//! frames outside the identity map are read and written through it.
+//!
+//! The window's page table maps itself in slot 0, so its entries are edited
+//! through the window too and never need the identity map. The remaining
+//! slots are split between CPUs; each CPU only touches its own and flushes
+//! them locally. A mapping keeps interrupts off while it lives, so mappings
+//! of one CPU nest strictly and a slot's address is never in use twice.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+use crate::arch::x86_64::cpu::{self, MAX_CPUS};
+use crate::arch::x86_64::interrupts;
+use super::paging::{self, PagingError};
+use super::vma::KERNEL_VMA_END;
+use super::PhysicalFrame;
+
+/// Start of the window, the PML4 slot after the kernel mapping region
+pub const FIXMAP_START: u64 = KERNEL_VMA_END;
+/// End of the window, the 512 pages its one page table covers
+pub const FIXMAP_END: u64 = FIXMAP_START + 512 * PhysicalFrame::SIZE;
+
+/// Mappings one CPU can hold at once
+pub const SLOTS_PER_CPU: usize = 4;
+
+const FRAME_SIZE: u64 = PhysicalFrame::SIZE;
+
+/// Set once the window's page table is mapped
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// End of the identity map, frames below it are used in place
+static DIRECT_MAP_END: AtomicU64 = AtomicU64::new(0);
+
+/// Mappings held by each CPU
+static DEPTH: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// Set up the window in the kernel's page tables
+///
+/// Must run before the first address space is created, which copies the
+/// window's PML4 entry from the kernel.
+pub fn init() -> Result<(), PagingError> {
+    let table = paging::page_table(FIXMAP_START)?;
+    paging::map_page(FIXMAP_START, table, true, false)?;
+    DIRECT_MAP_END.store(paging::get_mapped_memory() as u64, Ordering::Relaxed);
+    READY.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Check whether frames outside the identity map can be reached
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// A frame mapped into this CPU's next free slot until dropped
+pub struct TempMapping {
+    slot: usize,
+    interrupts: bool,
+    /// Slots belong to the CPU that took them
+    _not_send: PhantomData<*mut u8>,
+}
+
+impl TempMapping {
+    /// Map `frame` writable and non-executable
+    ///
+    /// Panics if the window is not set up or this CPU's slots are all taken.
+    pub fn new(frame: PhysicalFrame) -> Self {
+        assert!(is_ready(), "temporary mapping before fixmap init");
+        let interrupts = interrupts::save_and_disable();
+        let cpu = cpu::current_id();
+        let depth = DEPTH[cpu].fetch_add(1, Ordering::Relaxed);
+        assert!(depth < SLOTS_PER_CPU, "fixmap slots exhausted");
+
+        let slot = 1 + cpu * SLOTS_PER_CPU + depth;
+        // Not-present entries are never cached, so no flush is needed
+        unsafe {
+            entries().add(slot).write_volatile(frame.start_address().as_u64() | paging::page_flags(true, false));
+        }
+        TempMapping { slot, interrupts, _not_send: PhantomData }
+    }
+
+    /// Virtual address of the mapped frame
+    pub fn address(&self) -> u64 {
+        FIXMAP_START + self.slot as u64 * FRAME_SIZE
+    }
+
+    /// Pointer to the first byte of the mapped frame
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.address() as *mut u8
+    }
+}
+
+impl Drop for TempMapping {
+    fn drop(&mut self) {
+        unsafe { entries().add(self.slot).write_volatile(0) };
+        x86_64::instructions::tlb::flush(VirtAddr::new(self.address()));
+        DEPTH[cpu::current_id()].fetch_sub(1, Ordering::Relaxed);
+        interrupts::restore(self.interrupts);
+    }
+}
+
+/// Entries of the window's page table, mapped in slot 0
+fn entries() -> *mut u64 {
+    FIXMAP_START as *mut u64
+}
+
+/// Run `f` with a pointer to the contents of `frame`
+///
+/// Frames inside the identity map, and every frame before the window is
+/// set up, are used in place; others get a temporary mapping.
+pub fn with_frame<R>(frame: PhysicalFrame, f: impl FnOnce(*mut u8) -> R) -> R {
+    let address = frame.start_address().as_u64();
+    if !is_ready() || address < DIRECT_MAP_END.load(Ordering::Relaxed) {
+        return f(address as *mut u8);
+    }
+    let mapping = TempMapping::new(frame);
+    f(mapping.as_ptr())
+}
+
+/// Fill `frame` with zeros
+pub fn zero(frame: PhysicalFrame) {
+    with_frame(frame, |page| unsafe { core::ptr::write_bytes(page, 0, FRAME_SIZE as usize) });
+}
+
+/// Nested mappings get their own slots and alias the identity map
+fn selftest() -> Result<(), &'static str> {
+    const OUTER: u64 = 0xF1C5_ED00_0000_0001;
+    const INNER: u64 = 0xF1C5_ED00_0000_0002;
+
+    if !is_ready() {
+        return Err("window not set up");
+    }
+    let first = super::frame_allocator::allocate_frame().map_err(|_| "cannot allocate frame")?;
+    let Ok(second) = super::frame_allocator::allocate_frame() else {
+        let _ = super::frame_allocator::deallocate_frame(first);
+        return Err("cannot allocate frame");
+    };
+
+    let result = (|| {
+        let (outer_address, inner_address) = {
+            let outer = TempMapping::new(first);
+            let inner = TempMapping::new(second);
+            unsafe {
+                core::ptr::write_volatile(outer.as_ptr() as *mut u64, OUTER);
+                core::ptr::write_volatile(inner.as_ptr() as *mut u64, INNER);
+            }
+            if outer.address() == inner.address() || !paging::is_mapped(inner.address()) {
+                return Err("nested mappings share a slot");
+            }
+            (outer.address(), inner.address())
+        };
+        if paging::is_mapped(outer_address) || paging::is_mapped(inner_address) {
+            return Err("slot still mapped after drop");
+        }
+
+        let read = |frame: PhysicalFrame| with_frame(frame, |page| unsafe { core::ptr::read_volatile(page as *const u64) });
+        if read(first) != OUTER || read(second) != INNER {
+            return Err("write through the window lost");
+        }
+        zero(first);
+        if read(first) != 0 {
+            return Err("zero did not clear the frame");
+        }
+        Ok(())
+    })();
+
+    let _ = super::frame_allocator::deallocate_frame(first);
+    let _ = super::frame_allocator::deallocate_frame(second);
+    result
+}
+
+crate::selftest!("fixmap", selftest);
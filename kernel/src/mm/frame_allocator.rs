@@ -1,7 +1,9 @@
 //! Physical Frame Allocator
 
 use super::{PhysicalAddress, PhysicalFrame, MemoryMap};
+use super::frame_info::{self, FrameInfo, Owner};
 use spin::Mutex;
+use crate::sync::SeqLock;
 
 /// Errors that can occur during frame allocation
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -27,65 +29,349 @@ impl core::fmt::Display for AllocationError {
     }
 }
 
+/// Request size classes: single frames, then runs of up to 2, 4, ... 512
+/// and larger contiguous frames
+pub const ORDER_CLASSES: usize = 11;
+
+/// Number of physical memory zones
+pub const ZONE_COUNT: usize = 4;
+
+/// Top of the ISA DMA zone, 16MB
+const DMA_LIMIT: u64 = 16 * 1024 * 1024;
+/// Top of the 32-bit DMA zone, 4GB
+const LOW_LIMIT: u64 = 4 * 1024 * 1024 * 1024;
+/// First frame handed out, the kernel image lives below 4MB
+const KERNEL_END: PhysicalFrame = PhysicalFrame::containing_address(PhysicalAddress::new(4 * 1024 * 1024));
+/// Ranges held with `hold_range` at most
+const MAX_HELD: usize = 4;
+/// Past the last possible frame
+const MAX_FRAME: PhysicalFrame = PhysicalFrame::from_number(u64::MAX / PhysicalFrame::SIZE);
+
+/// Physical memory zones, most constrained first
+///
+/// A request names the highest zone it can use and falls back to lower
+/// ones, so plain allocations leave the DMA zones to the devices that
+/// need them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    /// Below 16MB, reachable by ISA DMA
+    Dma = 0,
+    /// Below 4GB, reachable by 32-bit DMA
+    Low = 1,
+    /// The rest of the kernel's identity map
+    Normal = 2,
+    /// Beyond the identity map, must be mapped before the kernel touches it
+    High = 3,
+}
+
+impl Zone {
+    pub const ALL: [Zone; ZONE_COUNT] = [Zone::Dma, Zone::Low, Zone::Normal, Zone::High];
+
+    /// Zone name
+    pub fn name(self) -> &'static str {
+        match self {
+            Zone::Dma => "DMA",
+            Zone::Low => "Low",
+            Zone::Normal => "Normal",
+            Zone::High => "High",
+        }
+    }
+
+    /// Zones a request limited to this one may use, preferred first
+    fn fallback(self) -> impl Iterator<Item = Zone> {
+        Zone::ALL[..=self as usize].iter().rev().copied()
+    }
+}
+
 /// Simple bitmap-based frame allocator
 pub struct FrameAllocator {
     memory_map: MemoryMap,
-    next_free_frame: PhysicalFrame,
+    /// First frame past the kernel's identity map
+    direct_map_end: PhysicalFrame,
+    /// Next frame to try in each zone
+    cursors: [PhysicalFrame; ZONE_COUNT],
+    /// Usable and allocated frames in each zone
+    zone_frames: [u64; ZONE_COUNT],
+    zone_allocated: [u64; ZONE_COUNT],
     allocated_frames: u64,
     total_frames: u64,
+    peak_allocated_frames: u64,
+    allocations: u64,
+    frees: u64,
+    failures: u64,
+    double_frees: u64,
+    /// Successful requests by order class
+    orders: [u64; ORDER_CLASSES],
+    /// Ranges kept from allocation until the metadata table records them
+    held: [Option<(PhysicalFrame, PhysicalFrame, Owner)>; MAX_HELD],
 }
 
 impl FrameAllocator {
     /// Create a new frame allocator from a memory map
+    ///
+    /// Until [`FrameAllocator::set_direct_map_end`] says otherwise, all of
+    /// memory counts as mapped and the high zone is empty.
     pub fn new(memory_map: MemoryMap) -> Self {
-        // Find the first usable frame after kernel space, assume kernel ends at 4MB
-        let kernel_end = PhysicalAddress::new(4 * 1024 * 1024);
-        let next_free_frame = PhysicalFrame::containing_address(kernel_end);
-        
         // Calculate total available frames
         let total_frames = memory_map.total_usable_memory() / PhysicalFrame::SIZE;
         
-        FrameAllocator {
+        let mut allocator = FrameAllocator {
             memory_map,
-            next_free_frame,
+            direct_map_end: MAX_FRAME,
+            cursors: [KERNEL_END; ZONE_COUNT],
+            zone_frames: [0; ZONE_COUNT],
+            zone_allocated: [0; ZONE_COUNT],
             allocated_frames: 0,
             total_frames,
+            peak_allocated_frames: 0,
+            allocations: 0,
+            frees: 0,
+            failures: 0,
+            double_frees: 0,
+            orders: [0; ORDER_CLASSES],
+            held: [None; MAX_HELD],
+        };
+        allocator.count_zone_frames();
+        allocator
+    }
+
+    /// Move the boundary between the normal and high zones
+    ///
+    /// Call before allocating, frames already handed out keep the zone
+    /// they were counted in.
+    pub fn set_direct_map_end(&mut self, end: PhysicalAddress) {
+        self.direct_map_end = PhysicalFrame::containing_address(end);
+        self.count_zone_frames();
+    }
+
+    /// First and past-the-end frame of a zone
+    fn zone_bounds(&self, zone: Zone) -> (PhysicalFrame, PhysicalFrame) {
+        let frame = |address| PhysicalFrame::containing_address(PhysicalAddress::new(address));
+        let (start, end) = match zone {
+            Zone::Dma => (KERNEL_END, frame(DMA_LIMIT)),
+            Zone::Low => (frame(DMA_LIMIT), frame(LOW_LIMIT)),
+            Zone::Normal => (frame(LOW_LIMIT), MAX_FRAME),
+            Zone::High => return (self.direct_map_end.max(KERNEL_END), MAX_FRAME),
+        };
+        (start.min(self.direct_map_end), end.min(self.direct_map_end))
+    }
+
+    /// Zone containing a frame
+    pub fn zone_of(&self, frame: PhysicalFrame) -> Zone {
+        Zone::ALL
+            .into_iter()
+            .find(|&zone| {
+                let (start, end) = self.zone_bounds(zone);
+                frame >= start && frame < end
+            })
+            .unwrap_or(Zone::Dma)
+    }
+
+    /// Usable frames in `[start, end)`
+    fn usable_frames_between(&self, start: PhysicalFrame, end: PhysicalFrame) -> u64 {
+        self.memory_map
+            .usable_frame_ranges()
+            .map(|region| {
+                let overlap_start = start.max(region.start()).number();
+                let overlap_end = end.min(region.end()).number();
+                overlap_end.saturating_sub(overlap_start)
+            })
+            .sum()
+    }
+
+    fn count_zone_frames(&mut self) {
+        for zone in Zone::ALL {
+            let (start, end) = self.zone_bounds(zone);
+            self.zone_frames[zone as usize] = self.usable_frames_between(start, end);
         }
     }
-    
-    /// Allocate a single physical frame
+
+    /// Allocate a single physical frame from the normal zone or below
     pub fn allocate_frame(&mut self) -> Result<PhysicalFrame, AllocationError> {
-        // TODO: Add more robust bitmap
-        if self.allocated_frames >= self.total_frames {
+        self.allocate_contiguous_in(1, Zone::Normal)
+    }
+
+    /// Allocate `count` physically contiguous frames from the normal zone
+    /// or below, returning the first
+    pub fn allocate_contiguous(&mut self, count: u64) -> Result<PhysicalFrame, AllocationError> {
+        self.allocate_contiguous_in(count, Zone::Normal)
+    }
+
+    /// Allocate `count` physically contiguous frames from `zone` or a lower
+    /// one, returning the first
+    pub fn allocate_contiguous_in(&mut self, count: u64, zone: Zone) -> Result<PhysicalFrame, AllocationError> {
+        if count == 0 || self.allocated_frames + count > self.total_frames {
             return Err(AllocationError::OutOfMemory);
         }
-        
-        // Find next available frame in usable regions
-        for region in self.memory_map.usable_frame_ranges() {
-            if self.next_free_frame >= region.start() && self.next_free_frame < region.end() {
-                let frame = self.next_free_frame;
-                self.next_free_frame = self.next_free_frame + 1;
-                self.allocated_frames += 1;
-                
-                return Ok(frame);
+        zone.fallback()
+            .find_map(|zone| self.allocate_from(zone, count))
+            .ok_or(AllocationError::OutOfMemory)
+    }
+
+    /// Take `count` contiguous frames from one zone
+    fn allocate_from(&mut self, zone: Zone, count: u64) -> Option<PhysicalFrame> {
+        let (zone_start, zone_end) = self.zone_bounds(zone);
+        let cursor = self.cursors[zone as usize].max(zone_start);
+        let start = self.memory_map.usable_frame_ranges().find_map(|region| {
+            let end = region.end().min(zone_end);
+            let mut start = cursor.max(region.start());
+            while start.number() + count <= end.number() {
+                // A free moves the cursor back over frames still in use
+                match (0..count).map(|i| start + i).find(|&frame| is_in_use(frame) || self.is_held(frame)) {
+                    Some(used) => start = used + 1,
+                    None => return Some(start),
+                }
             }
-            
-            // If current frame is before this region, jump to region start
-            if self.next_free_frame < region.start() {
-                self.next_free_frame = region.start();
-                let frame = self.next_free_frame;
-                self.next_free_frame = self.next_free_frame + 1;
-                self.allocated_frames += 1;
-                
-                return Ok(frame);
+            None
+        })?;
+        for frame in (0..count).map(|i| start + i) {
+            if let Some(info) = frame_info::get(frame) {
+                info.claim(Owner::Kernel);
             }
         }
-        
-        Err(AllocationError::OutOfMemory)
+        self.cursors[zone as usize] = start + count;
+        self.zone_allocated[zone as usize] += count;
+        self.allocated_frames += count;
+        Some(start)
     }
-    
+
+    /// Build the frame metadata table, see [`frame_info`]
+    ///
+    /// Frames handed out so far are recorded as kernel-owned. The table is
+    /// written through the identity map, so the direct map end must be set.
+    fn build_frame_info(&mut self) -> Result<(), AllocationError> {
+        let len = self.memory_map.usable_frame_ranges().map(|region| region.end().number()).max().unwrap_or(0);
+        let pages = frame_info::table_size(len).div_ceil(PhysicalFrame::SIZE);
+        let table = self.allocate_contiguous_in(pages, Zone::Normal)?;
+        let base = table.start_address().as_u64() as *mut FrameInfo;
+        let entries = unsafe {
+            core::ptr::write_bytes(base as *mut u8, 0, (pages * PhysicalFrame::SIZE) as usize);
+            core::slice::from_raw_parts(base, len as usize)
+        };
+
+        // Reserved unless a usable region says otherwise
+        for info in entries {
+            info.set_flags(frame_info::UNUSABLE);
+            info.claim(Owner::Reserved);
+        }
+        for zone in Zone::ALL {
+            let (zone_start, zone_end) = self.zone_bounds(zone);
+            let cursor = self.cursors[zone as usize].max(zone_start);
+            for region in self.memory_map.usable_frame_ranges() {
+                let start = zone_start.max(region.start()).number();
+                let end = zone_end.min(region.end()).number();
+                for number in start..end {
+                    let info = &entries[number as usize];
+                    info.clear_flags(frame_info::UNUSABLE);
+                    if number < cursor.number() {
+                        info.set_owner(Owner::Kernel);
+                    } else {
+                        info.drop_ref();
+                    }
+                }
+            }
+        }
+        for i in 0..pages {
+            entries[(table.number() + i) as usize].set_owner(Owner::FrameInfo);
+        }
+        // Held frames are in use from now on like any other
+        for (start, end, owner) in self.held.iter_mut().filter_map(|held| held.take()) {
+            for info in entries.iter().take(end.number() as usize).skip(start.number() as usize) {
+                if info.flags() & frame_info::UNUSABLE == 0 {
+                    info.claim(owner);
+                }
+            }
+        }
+
+        unsafe { frame_info::install(base, len as usize) };
+        Ok(())
+    }
+
+    /// Mark a physical range as in use by `owner` so its frames are never
+    /// handed out
+    pub fn reserve_range(&mut self, start: PhysicalAddress, end: PhysicalAddress, owner: Owner) {
+        let first = PhysicalFrame::containing_address(start);
+        let last = PhysicalFrame::containing_address(end.align_up(PhysicalFrame::SIZE));
+
+        for zone in Zone::ALL {
+            let (zone_start, zone_end) = self.zone_bounds(zone);
+            let from = first.max(zone_start).max(self.cursors[zone as usize]);
+            let to = last.min(zone_end);
+            if from >= to {
+                continue;
+            }
+            // Only frames in usable regions count towards the allocation total
+            let reserved = self.usable_frames_between(from, to);
+            self.zone_allocated[zone as usize] += reserved;
+            self.allocated_frames = (self.allocated_frames + reserved).min(self.total_frames);
+            self.cursors[zone as usize] = to;
+            for number in from.number()..to.number() {
+                if let Some(info) = frame_info::get(PhysicalFrame::from_number(number)) {
+                    if info.flags() & frame_info::UNUSABLE == 0 {
+                        info.claim(owner);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Keep `start..end` from being handed out, for loader structures still
+    /// in use when the allocator starts
+    ///
+    /// Unlike [`FrameAllocator::reserve_range`] the allocation cursor does
+    /// not move, so the range may lie anywhere. Once the metadata table is
+    /// built the frames are allocated to `owner` and can be freed as usual.
+    pub fn hold_range(&mut self, start: PhysicalAddress, end: PhysicalAddress, owner: Owner) -> Result<(), AllocationError> {
+        let first = PhysicalFrame::containing_address(start);
+        let last = PhysicalFrame::containing_address(end.align_up(PhysicalFrame::SIZE));
+        let slot = self.held.iter_mut().find(|held| held.is_none()).ok_or(AllocationError::OutOfMemory)?;
+        *slot = Some((first, last, owner));
+        for zone in Zone::ALL {
+            let (zone_start, zone_end) = self.zone_bounds(zone);
+            let held = self.usable_frames_between(first.max(zone_start), last.min(zone_end));
+            self.zone_allocated[zone as usize] += held;
+            self.allocated_frames = (self.allocated_frames + held).min(self.total_frames);
+        }
+        Ok(())
+    }
+
+    /// Check whether a frame lies in a range kept by `hold_range`
+    fn is_held(&self, frame: PhysicalFrame) -> bool {
+        self.held.iter().flatten().any(|&(start, end, _)| frame >= start && frame < end)
+    }
+
+    /// Take the `count` frames from `first` for `owner`, if all are free
+    ///
+    /// Needs the frame metadata table to tell free frames from used ones.
+    pub fn claim_range(&mut self, first: PhysicalFrame, count: u64, owner: Owner) -> Result<(), AllocationError> {
+        let frames = (0..count).map(|i| first + i);
+        for frame in frames.clone() {
+            let info = frame_info::get(frame).ok_or(AllocationError::InvalidFrame)?;
+            if info.refcount() != 0 || info.flags() & frame_info::UNUSABLE != 0 {
+                return Err(AllocationError::FrameAlreadyAllocated);
+            }
+        }
+        for frame in frames {
+            if let Some(info) = frame_info::get(frame) {
+                info.claim(owner);
+            }
+            self.zone_allocated[self.zone_of(frame) as usize] += 1;
+        }
+        self.allocated_frames += count;
+        self.peak_allocated_frames = self.peak_allocated_frames.max(self.allocated_frames);
+        Ok(())
+    }
+
     /// Deallocate a physical frame
     pub fn deallocate_frame(&mut self, frame: PhysicalFrame) -> Result<(), AllocationError> {
+        self.release_frame(frame).map(|_| ())
+    }
+
+    /// Drop one owner of a frame, freeing it with the last
+    ///
+    /// Returns whether the frame was freed. Frames outside the metadata
+    /// table have a single owner.
+    pub fn release_frame(&mut self, frame: PhysicalFrame) -> Result<bool, AllocationError> {
         // Verify frame is in a usable region
         let mut found_in_region = false;
         for region in self.memory_map.usable_frame_ranges() {
@@ -98,20 +384,38 @@ impl FrameAllocator {
         if !found_in_region {
             return Err(AllocationError::InvalidFrame);
         }
+        if let Some(info) = frame_info::get(frame) {
+            match info.drop_ref() {
+                None => {
+                    self.double_frees += 1;
+                    return Err(AllocationError::FrameNotAllocated);
+                }
+                Some(0) => {}
+                Some(_) => return Ok(false),
+            }
+        }
         
-        // Clear the frame for security
-        self.clear_frame(frame);
+        // Clear the frame for security, high frames have no mapping to
+        // clear them through
+        let zone = self.zone_of(frame);
+        if zone != Zone::High {
+            self.clear_frame(frame);
+        }
         
         // Update allocation count
         if self.allocated_frames > 0 {
             self.allocated_frames -= 1;
         }
+        let allocated = &mut self.zone_allocated[zone as usize];
+        *allocated = allocated.saturating_sub(1);
         
-        // Reset next_free_frame if this frame is earlier
-        if frame < self.next_free_frame {
-            self.next_free_frame = frame;
+        // Reset the zone's cursor if this frame is earlier
+        let cursor = &mut self.cursors[zone as usize];
+        if frame < *cursor {
+            *cursor = frame;
         }
-        Ok(())
+        self.frees += 1;
+        Ok(true)
     }
     
     /// Clear a frame's contents for security
@@ -126,6 +430,18 @@ impl FrameAllocator {
         }
     }
     
+    /// Count a request for `count` frames and its outcome
+    fn record(&mut self, count: u64, result: &Result<PhysicalFrame, AllocationError>) {
+        if result.is_err() {
+            self.failures += 1;
+            return;
+        }
+        self.allocations += 1;
+        let order = (count.next_power_of_two().trailing_zeros() as usize).min(ORDER_CLASSES - 1);
+        self.orders[order] += 1;
+        self.peak_allocated_frames = self.peak_allocated_frames.max(self.allocated_frames);
+    }
+
     /// Get allocation statistics
     pub fn stats(&self) -> FrameAllocatorStats {
         FrameAllocatorStats {
@@ -134,6 +450,17 @@ impl FrameAllocator {
             free_frames: self.total_frames - self.allocated_frames,
             total_memory: self.memory_map.total_usable_memory(),
             allocated_memory: self.allocated_frames * PhysicalFrame::SIZE,
+            peak_allocated_frames: self.peak_allocated_frames,
+            allocations: self.allocations,
+            frees: self.frees,
+            failures: self.failures,
+            double_frees: self.double_frees,
+            orders: self.orders,
+            zones: Zone::ALL.map(|zone| ZoneStats {
+                zone,
+                total_frames: self.zone_frames[zone as usize],
+                allocated_frames: self.zone_allocated[zone as usize],
+            }),
         }
     }
 }
@@ -146,38 +473,235 @@ pub struct FrameAllocatorStats {
     pub free_frames: u64,
     pub total_memory: u64,
     pub allocated_memory: u64,
+    /// Most frames allocated at once since boot
+    pub peak_allocated_frames: u64,
+    /// Successful allocation requests, single or contiguous
+    pub allocations: u64,
+    pub frees: u64,
+    /// Requests that could not be satisfied
+    pub failures: u64,
+    /// Frees of frames that were not allocated
+    pub double_frees: u64,
+    /// Successful requests by size class, see [`ORDER_CLASSES`]
+    pub orders: [u64; ORDER_CLASSES],
+    pub zones: [ZoneStats; ZONE_COUNT],
+}
+
+/// Frame counts for one zone
+#[derive(Debug, Clone, Copy)]
+pub struct ZoneStats {
+    pub zone: Zone,
+    /// Usable frames in the zone
+    pub total_frames: u64,
+    pub allocated_frames: u64,
 }
 
 /// Global frame allocator instance
 static FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
 
+/// Statistics snapshot, readable without taking the allocator lock
+static STATS: SeqLock<Option<FrameAllocatorStats>> = SeqLock::new(None);
+
 /// Initialize the global frame allocator
 pub fn init_frame_allocator(memory_map: MemoryMap) -> Result<(), AllocationError> {
     let mut allocator = FRAME_ALLOCATOR.lock();
-    *allocator = Some(FrameAllocator::new(memory_map));
+    let new_allocator = FrameAllocator::new(memory_map);
+    STATS.write(Some(new_allocator.stats()));
+    *allocator = Some(new_allocator);
     Ok(())
 }
 
-/// Allocate a frame
+/// Set where the kernel's identity map ends, frames past it form the high
+/// zone
+pub fn set_direct_map_end(end: PhysicalAddress) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    if let Some(alloc) = allocator.as_mut() {
+        alloc.set_direct_map_end(end);
+        STATS.write(Some(alloc.stats()));
+    }
+}
+
+/// Allocate a frame from the normal zone or below
 pub fn allocate_frame() -> Result<PhysicalFrame, AllocationError> {
+    allocate_contiguous_frames_in(1, Zone::Normal)
+}
+
+/// Allocate a frame from `zone` or below
+pub fn allocate_frame_in(zone: Zone) -> Result<PhysicalFrame, AllocationError> {
+    allocate_contiguous_frames_in(1, zone)
+}
+
+/// Allocate physically contiguous frames from the normal zone or below
+pub fn allocate_contiguous_frames(count: u64) -> Result<PhysicalFrame, AllocationError> {
+    allocate_contiguous_frames_in(count, Zone::Normal)
+}
+
+/// Allocate physically contiguous frames from `zone` or below
+pub fn allocate_contiguous_frames_in(count: u64, zone: Zone) -> Result<PhysicalFrame, AllocationError> {
     let mut allocator = FRAME_ALLOCATOR.lock();
     match allocator.as_mut() {
-        Some(alloc) => alloc.allocate_frame(),
+        Some(alloc) => {
+            let result = alloc.allocate_contiguous_in(count, zone);
+            alloc.record(count, &result);
+            STATS.write(Some(alloc.stats()));
+            result
+        }
         None => Err(AllocationError::OutOfMemory),
     }
 }
 
-/// Deallocate a frame
-pub fn deallocate_frame(frame: PhysicalFrame) -> Result<(), AllocationError> {
+/// Build the frame metadata table once the direct map end is known
+pub fn init_frame_info() -> Result<(), AllocationError> {
     let mut allocator = FRAME_ALLOCATOR.lock();
-    match allocator.as_mut() {
-        Some(alloc) => alloc.deallocate_frame(frame),
-        None => Err(AllocationError::InvalidFrame),
+    let alloc = allocator.as_mut().ok_or(AllocationError::OutOfMemory)?;
+    let result = alloc.build_frame_info();
+    STATS.write(Some(alloc.stats()));
+    result
+}
+
+/// Reserve a physical range for `owner` so the allocator never hands it out
+pub fn reserve_range(start: PhysicalAddress, end: PhysicalAddress, owner: Owner) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    if let Some(alloc) = allocator.as_mut() {
+        alloc.reserve_range(start, end, owner);
+        STATS.write(Some(alloc.stats()));
     }
 }
 
+/// Keep a loader structure in usable memory from being handed out, see
+/// [`FrameAllocator::hold_range`]
+pub fn hold_range(start: PhysicalAddress, end: PhysicalAddress, owner: Owner) -> Result<(), AllocationError> {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let alloc = allocator.as_mut().ok_or(AllocationError::OutOfMemory)?;
+    let result = alloc.hold_range(start, end, owner);
+    STATS.write(Some(alloc.stats()));
+    result
+}
+
+/// Take specific free frames for `owner`, e.g. to give memory that was
+/// returned to the allocator back to its old user
+pub fn claim_range(first: PhysicalFrame, count: u64, owner: Owner) -> Result<(), AllocationError> {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let alloc = allocator.as_mut().ok_or(AllocationError::OutOfMemory)?;
+    let result = alloc.claim_range(first, count, owner);
+    STATS.write(Some(alloc.stats()));
+    result
+}
+
+/// Deallocate a frame
+pub fn deallocate_frame(frame: PhysicalFrame) -> Result<(), AllocationError> {
+    release_frame(frame).map(|_| ())
+}
+
 /// Get frame allocator statistics
 pub fn get_stats() -> Option<FrameAllocatorStats> {
-    let allocator = FRAME_ALLOCATOR.lock();
-    allocator.as_ref().map(|alloc| alloc.stats())
+    STATS.read()
+}
+
+/// Check whether the metadata table records a frame as allocated
+fn is_in_use(frame: PhysicalFrame) -> bool {
+    frame_info::get(frame).is_some_and(|info| info.refcount() != 0)
+}
+
+/// Number of owners of an allocated frame
+pub fn ref_count(frame: PhysicalFrame) -> u32 {
+    frame_info::get(frame).map_or(1, |info| info.refcount().max(1))
+}
+
+/// Add an owner to an allocated frame, returning the new count
+///
+/// Sharing needs the frame metadata table to count owners.
+pub fn share_frame(frame: PhysicalFrame) -> Result<u32, AllocationError> {
+    let info = frame_info::get(frame).ok_or(AllocationError::InvalidFrame)?;
+    info.add_ref().ok_or(AllocationError::FrameNotAllocated)
+}
+
+/// Drop one owner of a frame, freeing it with the last
+///
+/// Returns whether the frame was freed. A frame that is not allocated is
+/// reported on the console with the caller, as a double free.
+pub fn release_frame(frame: PhysicalFrame) -> Result<bool, AllocationError> {
+    let result = {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let alloc = allocator.as_mut().ok_or(AllocationError::InvalidFrame)?;
+        let result = alloc.release_frame(frame);
+        STATS.write(Some(alloc.stats()));
+        result
+    };
+    if result == Err(AllocationError::FrameNotAllocated) {
+        let callers: [usize; 3] = crate::ksyms::backtrace();
+        crate::serial_println!("frame_allocator: double free of frame {:#x}", frame.start_address().as_u64());
+        for caller in callers.iter().take_while(|&&caller| caller != 0) {
+            crate::serial_println!("  {}", crate::ksyms::Address(*caller));
+        }
+    }
+    result
+}
+
+/// Allocate and free frames, checking alignment, contiguity and accounting
+fn selftest() -> Result<(), &'static str> {
+    let before = get_stats().ok_or("allocator not initialized")?;
+
+    let single = allocate_frame().map_err(|_| "allocate_frame")?;
+    let pair = allocate_contiguous_frames(2).map_err(|_| "allocate_contiguous_frames")?;
+    let frames = [pair + 1, pair, single];
+    if frames.iter().any(|f| !f.start_address().is_aligned(PhysicalFrame::SIZE)) {
+        return Err("frame not page aligned");
+    }
+    if pair == single || pair + 1 == single {
+        return Err("frames overlap");
+    }
+    let after = get_stats().ok_or("stats missing")?;
+    if after.allocated_frames != before.allocated_frames + 3 {
+        return Err("allocated count not updated");
+    }
+    if after.allocations != before.allocations + 2
+        || after.orders[0] != before.orders[0] + 1
+        || after.orders[1] != before.orders[1] + 1
+        || after.peak_allocated_frames < after.allocated_frames
+    {
+        return Err("request counters not updated");
+    }
+
+    // Constrained requests stay below their zone's limit
+    let low = allocate_frame_in(Zone::Low).map_err(|_| "allocate_frame_in(Low)")?;
+    if low.end_address().as_u64() > LOW_LIMIT {
+        return Err("low zone frame above 4GB");
+    }
+    let mut frames = alloc::vec::Vec::from(frames);
+    frames.push(low);
+    if frame_info::is_available()
+        && frames.iter().any(|&f| frame_info::get(f).is_none_or(|info| info.refcount() != 1 || info.owner() != Owner::Kernel))
+    {
+        return Err("allocated frame metadata");
+    }
+    let dma = after.zones[Zone::Dma as usize];
+    if dma.allocated_frames < dma.total_frames {
+        let frame = allocate_frame_in(Zone::Dma).map_err(|_| "allocate_frame_in(Dma)")?;
+        if frame.end_address().as_u64() > DMA_LIMIT {
+            return Err("DMA zone frame above 16MB");
+        }
+        frames.push(frame);
+    }
+
+    // Freeing clears the frame through the identity map, so frames beyond
+    // it are kept rather than faulting
+    let mapped = super::paging::get_mapped_memory() as u64;
+    if frames.iter().all(|f| f.end_address().as_u64() <= mapped) {
+        for &frame in &frames {
+            deallocate_frame(frame).map_err(|_| "deallocate_frame")?;
+        }
+        let freed = get_stats().ok_or("stats missing")?;
+        if freed.allocated_frames != before.allocated_frames {
+            return Err("allocated count not restored");
+        }
+        if frame_info::is_available()
+            && frames.iter().any(|&f| frame_info::get(f).is_none_or(|info| info.refcount() != 0 || info.owner() != Owner::Free))
+        {
+            return Err("freed frame metadata");
+        }
+    }
+    Ok(())
 }
+
+crate::selftest!("frame_allocator", selftest);
@@ -0,0 +1,190 @@
+//! Page Frame Metadata
+//!
+//! One [`FrameInfo`] per physical frame, from frame 0 to the end of the last
+//! usable region, kept in frames the allocator sets aside at boot. It holds
+//! the reference count copy-on-write sharing relies on, state flags and the
+//! subsystem that owns the frame, and lets the allocator catch double frees
+//! and frames handed out twice.
+//!
+//! Entries are atomics, so lookups need no lock; the frame allocator makes
+//! the allocate and free transitions under its own lock.
+
+use core::sync::atomic::{AtomicPtr, AtomicU16, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use super::PhysicalFrame;
+
+/// Not RAM the allocator manages: firmware, MMIO holes, the kernel image
+pub const UNUSABLE: u16 = 1 << 0;
+/// Mapped copy-on-write by more than one owner
+pub const COPY_ON_WRITE: u16 = 1 << 1;
+
+/// Number of owner kinds
+pub const OWNER_COUNT: usize = 8;
+
+/// Entries scanned between preemption points
+const PREEMPT_INTERVAL: usize = 64 * 1024;
+
+/// Subsystem a frame belongs to
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Free = 0,
+    /// Firmware, kernel image or otherwise never allocatable
+    Reserved = 1,
+    /// Allocated without a more specific owner
+    Kernel = 2,
+    Heap = 3,
+    PageTable = 4,
+    /// Backs a user or kernel virtual memory area
+    Anonymous = 5,
+    /// Device DMA buffer
+    Dma = 6,
+    /// The frame metadata table itself
+    FrameInfo = 7,
+}
+
+impl Owner {
+    pub const ALL: [Owner; OWNER_COUNT] = [
+        Owner::Free, Owner::Reserved, Owner::Kernel, Owner::Heap,
+        Owner::PageTable, Owner::Anonymous, Owner::Dma, Owner::FrameInfo,
+    ];
+
+    fn from_u8(value: u8) -> Self {
+        Owner::ALL.get(value as usize).copied().unwrap_or(Owner::Kernel)
+    }
+
+    /// Owner name
+    pub fn name(self) -> &'static str {
+        match self {
+            Owner::Free => "free",
+            Owner::Reserved => "reserved",
+            Owner::Kernel => "kernel",
+            Owner::Heap => "heap",
+            Owner::PageTable => "page table",
+            Owner::Anonymous => "anonymous",
+            Owner::Dma => "dma",
+            Owner::FrameInfo => "frame info",
+        }
+    }
+}
+
+/// Metadata for one physical frame
+#[repr(C)]
+pub struct FrameInfo {
+    refcount: AtomicU32,
+    flags: AtomicU16,
+    owner: AtomicU8,
+    _reserved: u8,
+}
+
+impl FrameInfo {
+    /// Number of owners, 0 for a free frame
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.flags.load(Ordering::Relaxed)
+    }
+
+    pub fn owner(&self) -> Owner {
+        Owner::from_u8(self.owner.load(Ordering::Relaxed))
+    }
+
+    pub fn set_flags(&self, flags: u16) {
+        self.flags.fetch_or(flags, Ordering::Relaxed);
+    }
+
+    pub fn clear_flags(&self, flags: u16) {
+        self.flags.fetch_and(!flags, Ordering::Relaxed);
+    }
+
+    pub fn set_owner(&self, owner: Owner) {
+        self.owner.store(owner as u8, Ordering::Relaxed);
+    }
+
+    /// Mark allocated to `owner` with a single reference
+    pub(super) fn claim(&self, owner: Owner) {
+        self.set_owner(owner);
+        self.refcount.store(1, Ordering::Release);
+    }
+
+    /// Add an owner, returns the new count or `None` if the frame is free
+    pub(super) fn add_ref(&self) -> Option<u32> {
+        self.refcount
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count != 0).then_some(count + 1))
+            .ok()
+            .map(|previous| previous + 1)
+    }
+
+    /// Drop an owner, returns the remaining count or `None` if the frame
+    /// was already free
+    pub(super) fn drop_ref(&self) -> Option<u32> {
+        let previous = self
+            .refcount
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| count.checked_sub(1))
+            .ok()?;
+        if previous == 1 {
+            self.flags.fetch_and(UNUSABLE, Ordering::Relaxed);
+            self.set_owner(Owner::Free);
+        }
+        Some(previous - 1)
+    }
+}
+
+/// Table base and entry count, set once at boot
+static TABLE: AtomicPtr<FrameInfo> = AtomicPtr::new(core::ptr::null_mut());
+static LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Bytes of table needed to cover `frames` frames
+pub(super) const fn table_size(frames: u64) -> u64 {
+    frames * core::mem::size_of::<FrameInfo>() as u64
+}
+
+/// Publish a zeroed table covering frames `0..len`
+///
+/// # Safety
+/// `table` must point to `len` zeroed entries that stay mapped and are
+/// never freed.
+pub(super) unsafe fn install(table: *mut FrameInfo, len: usize) {
+    LEN.store(len, Ordering::Relaxed);
+    TABLE.store(table, Ordering::Release);
+}
+
+/// Check whether the table has been built
+pub fn is_available() -> bool {
+    !TABLE.load(Ordering::Acquire).is_null()
+}
+
+/// Metadata for a frame, `None` before boot builds the table or past its end
+pub fn get(frame: PhysicalFrame) -> Option<&'static FrameInfo> {
+    let table = TABLE.load(Ordering::Acquire);
+    let index = frame.number() as usize;
+    if table.is_null() || index >= LEN.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some(unsafe { &*table.add(index) })
+}
+
+/// Record which subsystem owns an allocated frame
+pub fn set_owner(frame: PhysicalFrame, owner: Owner) {
+    if let Some(info) = get(frame) {
+        info.set_owner(owner);
+    }
+}
+
+/// Frames per owner, indexed by `Owner as usize`
+pub fn owner_counts() -> [u64; OWNER_COUNT] {
+    let mut counts = [0; OWNER_COUNT];
+    let table = TABLE.load(Ordering::Acquire);
+    if table.is_null() {
+        return counts;
+    }
+    let entries = unsafe { core::slice::from_raw_parts(table, LEN.load(Ordering::Relaxed)) };
+    for (index, info) in entries.iter().enumerate() {
+        if index % PREEMPT_INTERVAL == 0 {
+            crate::task::yield_if_needed();
+        }
+        counts[info.owner() as usize] += 1;
+    }
+    counts
+}
@@ -1,18 +1,287 @@
 //! Kernel Heap Allocator
+//!
+//! The heap is a fixed identity-mapped region. When frees leave much more
+//! than [`TRIM_KEEP`] bytes unused, whole free chunks are handed back to
+//! the frame allocator; when free space runs low or an allocation fails,
+//! chunks whose frames are still free are taken back. The gap between the
+//! two watermarks keeps a heap near one of them from trimming and
+//! regrowing on every call.
+//!
+//! Every allocation is followed by an 8-byte canary keyed with a per-boot
+//! cookie and the allocation's address, checked when it is freed, so an
+//! overflow into the next chunk's metadata is caught instead of
+//! corrupting the free list.
 
-use super::frame_allocator::allocate_frame;
-use super::PhysicalFrame;
+use super::frame_allocator::{self, allocate_frame};
+use super::frame_info::{self, Owner};
+use super::{PhysicalAddress, PhysicalFrame};
+use crate::crypto::cookie::HEAP_CANARY;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use linked_list_allocator::LockedHeap;
 use spin::Mutex;
 
-/// Heap configuration constants
+/// Heap configuration constants, the heap starts past the kernel image
+/// when that reaches beyond `HEAP_START`
 pub const HEAP_START: usize = 0x400000; // 4MB
 pub const MIN_HEAP_SIZE: usize = 4 * 1024 * 1024; // 4MB minimum
 pub const MAX_HEAP_SIZE: usize = 256 * 1024 * 1024; // 256MB maximum
 
+/// Bytes returned to the frame allocator at a time
+pub const TRIM_CHUNK: usize = 1024 * 1024;
+/// Free bytes the heap keeps when trimming, and regrows to when low
+pub const TRIM_KEEP: usize = 8 * 1024 * 1024;
+/// Free bytes above [`TRIM_KEEP`] before a trim is started
+const TRIM_SLACK: usize = 4 * 1024 * 1024;
+/// Free bytes below which trimmed chunks are taken back
+const REGROW_BELOW: usize = TRIM_KEEP / 2;
+const MAX_TRIMMED: usize = MAX_HEAP_SIZE / TRIM_CHUNK;
+
+const TRIM_LAYOUT: Layout = match Layout::from_size_align(TRIM_CHUNK, PhysicalFrame::SIZE as usize) {
+    Ok(layout) => layout,
+    Err(_) => panic!("invalid trim chunk layout"),
+};
+
+/// Heap chunks whose frames belong to the frame allocator
+///
+/// Each is held as an allocation, so the heap never hands it out.
+struct Trimmed {
+    chunks: [usize; MAX_TRIMMED],
+    len: usize,
+}
+
+static TRIMMED: Mutex<Trimmed> = Mutex::new(Trimmed { chunks: [0; MAX_TRIMMED], len: 0 });
+
+/// Bytes in trimmed chunks, readable without the lock
+static TRIMMED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Set while a trim or regrow is queued as deferred work
+static REBALANCE_QUEUED: AtomicBool = AtomicBool::new(false);
+
+/// Where `init_heap` put the heap
+static HEAP_BASE: AtomicUsize = AtomicUsize::new(HEAP_START);
+
 /// Global allocator instance
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelAllocator = KernelAllocator(LockedHeap::empty());
+
+/// Kernel heap, the linked-list allocator with alloc/free tracepoints and
+/// optional leak tracking
+struct KernelAllocator(LockedHeap);
+
+impl core::ops::Deref for KernelAllocator {
+    type Target = LockedHeap;
+
+    fn deref(&self) -> &LockedHeap {
+        &self.0
+    }
+}
+
+/// Request size classes: up to 16 bytes, up to 32, ... up to 32 KiB, larger
+pub const SIZE_CLASSES: usize = 13;
+
+/// Allocation counters, updated lock-free next to the heap lock
+struct HeapCounters {
+    allocations: AtomicU64,
+    frees: AtomicU64,
+    failures: AtomicU64,
+    /// Requested bytes in use, without allocator overhead
+    live_bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+    size_classes: [AtomicU64; SIZE_CLASSES],
+}
+
+static COUNTERS: HeapCounters = HeapCounters {
+    allocations: AtomicU64::new(0),
+    frees: AtomicU64::new(0),
+    failures: AtomicU64::new(0),
+    live_bytes: AtomicUsize::new(0),
+    peak_bytes: AtomicUsize::new(0),
+    size_classes: [const { AtomicU64::new(0) }; SIZE_CLASSES],
+};
+
+/// Size class of a request, see [`SIZE_CLASSES`]
+pub fn size_class(size: usize) -> usize {
+    let order = size.max(16).next_power_of_two().trailing_zeros() as usize;
+    (order - 4).min(SIZE_CLASSES - 1)
+}
+
+impl HeapCounters {
+    fn record_alloc(&self, ptr: *mut u8, size: usize) {
+        if ptr.is_null() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.size_classes[size_class(size)].fetch_add(1, Ordering::Relaxed);
+        let live = self.live_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak_bytes.fetch_max(live, Ordering::Relaxed);
+    }
+
+    fn record_free(&self, size: usize) {
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.live_bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+/// Bytes after every allocation holding its canary
+const CANARY_SIZE: usize = 8;
+
+/// Layout of an allocation with room for its canary
+fn padded(layout: Layout) -> Option<Layout> {
+    Layout::from_size_align(layout.size().checked_add(CANARY_SIZE)?, layout.align()).ok()
+}
+
+/// Canary of the `size` byte allocation at `ptr`
+///
+/// Mixing in the address keeps one leaked canary from giving away the
+/// others.
+fn canary(ptr: *const u8, size: usize) -> u64 {
+    HEAP_CANARY.get() ^ (ptr as u64).rotate_left(17) ^ size as u64
+}
+
+unsafe fn write_canary(ptr: *mut u8, size: usize) {
+    ptr.add(size).cast::<u64>().write_unaligned(canary(ptr, size));
+}
+
+/// Whether the canary after the `size` byte allocation at `ptr` is intact
+unsafe fn canary_intact(ptr: *const u8, size: usize) -> bool {
+    ptr.add(size).cast::<u64>().read_unaligned() == canary(ptr, size)
+}
+
+unsafe impl core::alloc::GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let Some(padded) = padded(layout) else {
+            COUNTERS.record_alloc(core::ptr::null_mut(), layout.size());
+            return core::ptr::null_mut();
+        };
+        let mut ptr = self.0.alloc(padded);
+        if ptr.is_null() && super::oom::reclaim() > 0 {
+            ptr = self.0.alloc(padded);
+        }
+        if !ptr.is_null() {
+            write_canary(ptr, layout.size());
+        }
+        COUNTERS.record_alloc(ptr, layout.size());
+        self.check_watermarks();
+        crate::trace_event!(Alloc, ptr as u64, layout.size() as u64);
+        #[cfg(feature = "kmemleak")]
+        super::kmemleak::track_alloc(ptr, layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        if !canary_intact(ptr, layout.size()) {
+            panic!("heap overflow past the {} byte allocation at {:p}", layout.size(), ptr);
+        }
+        crate::trace_event!(Free, ptr as u64, layout.size() as u64);
+        COUNTERS.record_free(layout.size());
+        #[cfg(feature = "kmemleak")]
+        super::kmemleak::track_free(ptr);
+        // Padding cannot fail for a layout that was allocated
+        self.0.dealloc(ptr, padded(layout).unwrap_or(layout));
+        self.check_watermarks();
+    }
+}
+
+impl KernelAllocator {
+    /// Queue a trim or regrow once free space leaves the band between the
+    /// watermarks
+    ///
+    /// Frees may come from interrupt handlers, so the work itself is
+    /// deferred.
+    fn check_watermarks(&self) {
+        let Some(free) = self.0.try_lock().map(|heap| heap.free()) else {
+            return;
+        };
+        let trimmed = TRIMMED_BYTES.load(Ordering::Relaxed);
+        let wanted = free >= TRIM_KEEP + TRIM_SLACK || (trimmed != 0 && free < REGROW_BELOW);
+        if !wanted || !frame_info::is_available() || REBALANCE_QUEUED.swap(true, Ordering::Acquire) {
+            return;
+        }
+        if !crate::task::deferred::queue_work(rebalance, 0) {
+            REBALANCE_QUEUED.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Deferred trim or regrow, whichever the free space calls for now
+fn rebalance(_: usize) {
+    REBALANCE_QUEUED.store(false, Ordering::Release);
+    let free = ALLOCATOR.lock().free();
+    if free >= TRIM_KEEP + TRIM_SLACK {
+        trim();
+    } else if free < REGROW_BELOW {
+        regrow(TRIM_KEEP);
+    }
+}
+
+/// First frame of a heap chunk and its frame count
+fn chunk_frames(chunk: usize) -> (PhysicalFrame, u64) {
+    (PhysicalFrame::containing_address(PhysicalAddress::new(chunk as u64)), (TRIM_CHUNK as u64) / PhysicalFrame::SIZE)
+}
+
+/// Hand free heap chunks to the frame allocator until about [`TRIM_KEEP`]
+/// bytes are left free, returns the bytes trimmed
+///
+/// First-fit allocation leaves the large free runs at the top of the heap,
+/// so that is where chunks come from.
+pub fn trim() -> usize {
+    if !is_initialized() || !frame_info::is_available() {
+        return 0;
+    }
+    let mut trimmed = TRIMMED.lock();
+    let mut bytes = 0;
+    while trimmed.len < MAX_TRIMMED && ALLOCATOR.lock().free() >= TRIM_KEEP + TRIM_CHUNK {
+        let Ok(chunk) = ALLOCATOR.lock().allocate_first_fit(TRIM_LAYOUT) else {
+            break;
+        };
+        let (first, count) = chunk_frames(chunk.as_ptr() as usize);
+        for frame in (0..count).map(|i| first + i) {
+            let _ = frame_allocator::release_frame(frame);
+        }
+        let len = trimmed.len;
+        trimmed.chunks[len] = chunk.as_ptr() as usize;
+        trimmed.len += 1;
+        bytes += TRIM_CHUNK;
+    }
+    TRIMMED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+    bytes
+}
+
+/// Take trimmed chunks back until `target` bytes are free or none are
+/// left, returns the bytes regained
+///
+/// Chunks with frames the allocator handed out meanwhile stay trimmed.
+pub fn regrow(target: usize) -> usize {
+    // Also runs as a shrinker, inside a failed allocation
+    let Some(mut trimmed) = TRIMMED.try_lock() else {
+        return 0;
+    };
+    let mut bytes = 0;
+    let mut i = trimmed.len;
+    while i > 0 && ALLOCATOR.lock().free() < target {
+        i -= 1;
+        let chunk = trimmed.chunks[i];
+        let (first, count) = chunk_frames(chunk);
+        if frame_allocator::claim_range(first, count, Owner::Heap).is_err() {
+            continue;
+        }
+        trimmed.len -= 1;
+        let last = trimmed.chunks[trimmed.len];
+        trimmed.chunks[i] = last;
+        unsafe { ALLOCATOR.lock().deallocate(NonNull::new_unchecked(chunk as *mut u8), TRIM_LAYOUT) };
+        bytes += TRIM_CHUNK;
+    }
+    TRIMMED_BYTES.fetch_sub(bytes, Ordering::Relaxed);
+    bytes
+}
+
+/// Shrinker taking back every trimmed chunk it can
+fn regrow_all() -> usize {
+    regrow(usize::MAX)
+}
 
 /// Heap initialization state
 static HEAP_INITIALIZED: Mutex<bool> = Mutex::new(false);
@@ -65,9 +334,20 @@ pub fn init_heap(total_usable_memory: u64) -> Result<(), HeapError> {
     // Heap gets everything else that's mapped and usable
     let mapped_memory = super::paging::get_mapped_memory();
     
-    // Calculate: mapped memory - heap start address = available for heap
-    // (heap starts at 0x400000, so everything from there to end of mapped memory)
-    let available_for_heap = mapped_memory.saturating_sub(HEAP_START);
+    // Stay clear of the kernel image, which may have outgrown 2MB or been
+    // placed above the heap by the loader
+    let kernel = super::kernel_image();
+    let (kernel_start, kernel_end) = (kernel.start.as_u64() as usize, kernel.end.as_u64() as usize);
+    let mut heap_start = HEAP_START;
+    let mut heap_limit = mapped_memory;
+    if kernel_end > heap_start && kernel_start < heap_start + MIN_HEAP_SIZE {
+        heap_start = kernel_end.next_multiple_of(0x200000);
+    } else if kernel_start >= heap_start {
+        heap_limit = heap_limit.min(kernel_start);
+    }
+    
+    // Calculate: heap limit - heap start address = available for heap
+    let available_for_heap = heap_limit.saturating_sub(heap_start);
     
     // Clamp to min/max bounds
     let final_heap_size = available_for_heap
@@ -81,18 +361,40 @@ pub fn init_heap(total_usable_memory: u64) -> Result<(), HeapError> {
     if final_heap_size < MIN_HEAP_SIZE {
         return Err(HeapError::InvalidConfiguration);
     }
+    if heap_start < kernel_end && heap_start + final_heap_size > kernel_start {
+        return Err(HeapError::InvalidConfiguration);
+    }
     
-    // Store the actual heap size
+    // Store the actual heap placement
     *HEAP_SIZE.lock() = final_heap_size;
+    HEAP_BASE.store(heap_start, Ordering::Relaxed);
+
+    // Keep the frame allocator from handing out heap memory
+    super::frame_allocator::reserve_range(
+        super::PhysicalAddress::new(heap_start as u64),
+        super::PhysicalAddress::new((heap_start + final_heap_size) as u64),
+        super::frame_info::Owner::Heap,
+    );
 
     // Initialize the heap allocator with dynamic size
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START as *mut u8, final_heap_size);
+        ALLOCATOR.lock().init(heap_start as *mut u8, final_heap_size);
     }
     *initialized = true;
+    let _ = super::oom::register_shrinker("heap regrow", regrow_all);
     Ok(())
 }
 
+/// Start of the heap, past the kernel image if that reaches `HEAP_START`
+pub fn heap_start() -> usize {
+    HEAP_BASE.load(Ordering::Relaxed)
+}
+
+/// Size of the heap, 0 before `init_heap`
+pub fn heap_size() -> usize {
+    *HEAP_SIZE.lock()
+}
+
 /// Check if the heap is initialized
 pub fn is_initialized() -> bool {
     *HEAP_INITIALIZED.lock()
@@ -102,12 +404,33 @@ pub fn is_initialized() -> bool {
 pub fn heap_stats() -> HeapStats {
     let heap = ALLOCATOR.lock();
     let total_size = *HEAP_SIZE.lock();
-    HeapStats {
-        total_size,
-        used_size: heap.used(),
-        free_size: heap.free(),
-        start_address: HEAP_START,
-    }
+    HeapStats::new(total_size, heap.used(), heap.free())
+}
+
+/// Heap statistics without waiting, `None` while the heap is locked
+///
+/// For the panic path, where the lock holder may never run again.
+pub fn try_heap_stats() -> Option<HeapStats> {
+    let heap = ALLOCATOR.try_lock()?;
+    let total_size = *HEAP_SIZE.try_lock()?;
+    Some(HeapStats::new(total_size, heap.used(), heap.free()))
+}
+
+/// First return address in `callers` outside the allocator and `alloc`'s
+/// wrappers around it
+pub fn call_site(callers: &[usize]) -> usize {
+    const WRAPPERS: [&str; 6] = [
+        "__rust", "__rg_", "alloc::", "<cosmos::mm::heap::", "cosmos::mm::oom::", "core::",
+    ];
+    callers
+        .iter()
+        .copied()
+        .take_while(|&address| address != 0)
+        .find(|&address| match crate::ksyms::lookup(address) {
+            Some((symbol, _)) => !WRAPPERS.iter().any(|prefix| symbol.name.starts_with(prefix)),
+            None => true,
+        })
+        .unwrap_or(callers.first().copied().unwrap_or(0))
 }
 
 /// Heap statistics
@@ -116,7 +439,35 @@ pub struct HeapStats {
     pub total_size: usize,
     pub used_size: usize,
     pub free_size: usize,
+    /// Bytes of free heap handed to the frame allocator, not in `used_size`
+    pub trimmed_size: usize,
     pub start_address: usize,
+    /// Most requested bytes in use at once since boot
+    pub peak_size: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    /// Requests the heap could not satisfy
+    pub failures: u64,
+    /// Successful requests by size class, see [`SIZE_CLASSES`]
+    pub size_classes: [u64; SIZE_CLASSES],
+}
+
+impl HeapStats {
+    fn new(total_size: usize, used_size: usize, free_size: usize) -> Self {
+        let trimmed_size = TRIMMED_BYTES.load(Ordering::Relaxed);
+        HeapStats {
+            total_size,
+            used_size: used_size.saturating_sub(trimmed_size),
+            free_size,
+            trimmed_size,
+            start_address: heap_start(),
+            peak_size: COUNTERS.peak_bytes.load(Ordering::Relaxed),
+            allocations: COUNTERS.allocations.load(Ordering::Relaxed),
+            frees: COUNTERS.frees.load(Ordering::Relaxed),
+            failures: COUNTERS.failures.load(Ordering::Relaxed),
+            size_classes: core::array::from_fn(|class| COUNTERS.size_classes[class].load(Ordering::Relaxed)),
+        }
+    }
 }
 
 /// Poison memory with a pattern for security
@@ -186,3 +537,113 @@ pub fn secure_dealloc(ptr: *mut u8, size: usize) {
         }
     }
 }
+
+/// Allocate, use and free heap memory of various sizes and alignments
+fn selftest() -> Result<(), &'static str> {
+    use alloc::alloc::{alloc, dealloc, Layout};
+    use alloc::vec::Vec;
+
+    if !is_initialized() {
+        return Err("heap not initialized");
+    }
+    let before = heap_stats().used_size;
+
+    let mut values: Vec<u64> = Vec::new();
+    for i in 0..1024 {
+        values.push(i * 3);
+    }
+    if values.iter().enumerate().any(|(i, &v)| v != i as u64 * 3) {
+        return Err("vector contents");
+    }
+    if heap_stats().used_size <= before {
+        return Err("used size not updated");
+    }
+    drop(values);
+
+    for align in [8, 64, 4096] {
+        let layout = Layout::from_size_align(100, align).map_err(|_| "layout")?;
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            return Err("allocation failed");
+        }
+        if !(ptr as usize).is_multiple_of(align) {
+            unsafe { dealloc(ptr, layout) };
+            return Err("misaligned allocation");
+        }
+        unsafe {
+            core::ptr::write_bytes(ptr, 0x5A, 100);
+            dealloc(ptr, layout);
+        }
+    }
+
+    // A one-byte overflow breaks the canary
+    let layout = Layout::from_size_align(24, 8).map_err(|_| "layout")?;
+    let ptr = unsafe { alloc(layout) };
+    if ptr.is_null() {
+        return Err("allocation failed");
+    }
+    let intact = unsafe { canary_intact(ptr, 24) };
+    let overflowed = unsafe {
+        let byte = ptr.add(24);
+        let saved = byte.read();
+        byte.write(!saved);
+        let overflowed = canary_intact(ptr, 24);
+        byte.write(saved);
+        overflowed
+    };
+    unsafe { dealloc(ptr, layout) };
+    if !intact || overflowed {
+        return Err("heap canary");
+    }
+
+    if heap_stats().used_size != before {
+        return Err("memory leaked");
+    }
+
+    let counted = heap_stats();
+    let layout = Layout::from_size_align(3000, 8).map_err(|_| "layout")?;
+    let ptr = unsafe { alloc(layout) };
+    if ptr.is_null() {
+        return Err("allocation failed");
+    }
+    unsafe { dealloc(ptr, layout) };
+    let after = heap_stats();
+    if after.allocations <= counted.allocations
+        || after.frees <= counted.frees
+        || after.size_classes[size_class(3000)] <= counted.size_classes[size_class(3000)]
+        || after.peak_size < 3000
+    {
+        return Err("allocation counters not updated");
+    }
+    if size_class(1) != 0 || size_class(17) != 1 || size_class(1 << 20) != SIZE_CLASSES - 1 {
+        return Err("size classes");
+    }
+    Ok(())
+}
+
+crate::selftest!("heap", selftest);
+
+/// Trimmed chunks leave the free space and come back on regrow
+fn trim_selftest() -> Result<(), &'static str> {
+    if !frame_info::is_available() {
+        return Err("frame metadata unavailable");
+    }
+    let free = heap_stats().free_size;
+    let trimmed = trim();
+    let after = heap_stats();
+    if free >= TRIM_KEEP + TRIM_CHUNK + TRIM_LAYOUT.align() && trimmed == 0 {
+        return Err("nothing trimmed from a mostly free heap");
+    }
+    if after.free_size >= TRIM_KEEP + TRIM_CHUNK || after.trimmed_size < trimmed {
+        return Err("trim left too much free");
+    }
+    if regrow(free) < trimmed {
+        return Err("trimmed chunks not regained");
+    }
+    if heap_stats().free_size < free {
+        return Err("free space not restored");
+    }
+    Ok(())
+}
+
+crate::selftest!("heap_trim", trim_selftest);
@@ -0,0 +1,253 @@
+//! Memory Inspection
+//!
+//! Reads and writes of arbitrary kernel addresses, for the `peek`, `poke`,
+//! `hexdump` and `search` shell commands. Every page of a range is checked
+//! before it is touched: it must be mapped in the current tables, or lie
+//! in a kernel area the fault handler fills on first touch, and a write
+//! needs write access too. A mistyped address is refused instead of
+//! taking the kernel down with a page fault.
+//!
+//! The checks only keep accesses inside the page tables. Device registers
+//! are mapped like RAM, and reading one can still have side effects.
+
+use core::fmt::{self, Write};
+use super::vma::{self, Protection};
+use super::{paging, PhysicalFrame};
+
+const PAGE_SIZE: u64 = PhysicalFrame::SIZE;
+
+/// Bytes searched between preemption points
+const PREEMPT_INTERVAL: u64 = 64 * 1024;
+
+/// Errors that can occur checking an access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InspectError {
+    /// The range runs past the end of the address space
+    InvalidRange,
+    /// The page at this address is not mapped
+    Unmapped(u64),
+    /// The page at this address cannot be written
+    ReadOnly(u64),
+    /// The address is not a multiple of the access width
+    Misaligned,
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InspectError::InvalidRange => write!(f, "Range runs past the end of memory"),
+            InspectError::Unmapped(page) => write!(f, "Page {:#x} is not mapped", page),
+            InspectError::ReadOnly(page) => write!(f, "Page {:#x} is read-only", page),
+            InspectError::Misaligned => write!(f, "Address is not aligned to the width"),
+        }
+    }
+}
+
+/// Size of a single `read` or `write`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Width {
+    /// Width of `bytes` bytes, `None` unless it is 1, 2, 4 or 8
+    pub fn from_bytes(bytes: u64) -> Option<Self> {
+        match bytes {
+            1 => Some(Width::Byte),
+            2 => Some(Width::Word),
+            4 => Some(Width::Dword),
+            8 => Some(Width::Qword),
+            _ => None,
+        }
+    }
+
+    pub const fn bytes(self) -> u64 {
+        match self {
+            Width::Byte => 1,
+            Width::Word => 2,
+            Width::Dword => 4,
+            Width::Qword => 8,
+        }
+    }
+
+    /// Largest value that fits
+    pub const fn max(self) -> u64 {
+        u64::MAX >> (64 - 8 * self.bytes())
+    }
+}
+
+/// Check that the page at `page` can be accessed, written too if `write`
+fn check_page(page: u64, write: bool) -> Result<(), InspectError> {
+    if paging::is_mapped(page) {
+        if write && !paging::is_writable(page) {
+            return Err(InspectError::ReadOnly(page));
+        }
+        return Ok(());
+    }
+    // Not touched yet, the fault handler backs it if the area allows it
+    let access = if write { Protection::WRITE } else { Protection::READ };
+    match vma::find(page) {
+        Some(area) if area.protection.contains(access) => Ok(()),
+        _ => Err(InspectError::Unmapped(page)),
+    }
+}
+
+/// A range every page of which was checked for the access it is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    start: u64,
+    len: u64,
+}
+
+impl Span {
+    /// Check `start..start + len`, for writes too if `write`
+    pub fn new(start: u64, len: u64, write: bool) -> Result<Self, InspectError> {
+        let end = start.checked_add(len).ok_or(InspectError::InvalidRange)?;
+        let mut page = start & !(PAGE_SIZE - 1);
+        while page < end {
+            check_page(page, write)?;
+            match page.checked_add(PAGE_SIZE) {
+                Some(next) => page = next,
+                None => break,
+            }
+        }
+        Ok(Span { start, len })
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn byte(&self, offset: u64) -> u8 {
+        unsafe { ((self.start + offset) as *const u8).read_volatile() }
+    }
+
+    /// Print the bytes as hex and ASCII, 16 to a line
+    pub fn hexdump(&self, out: &mut dyn Write) -> fmt::Result {
+        let mut offset = 0;
+        while offset < self.len {
+            let count = (self.len - offset).min(16);
+            let mut line = [0u8; 16];
+            for (i, byte) in line[..count as usize].iter_mut().enumerate() {
+                *byte = self.byte(offset + i as u64);
+            }
+            write!(out, "{:016x} ", self.start + offset)?;
+            for (i, byte) in line.iter().enumerate() {
+                let gap = if i == 8 { "  " } else { " " };
+                if i < count as usize {
+                    write!(out, "{}{:02x}", gap, byte)?;
+                } else {
+                    write!(out, "{}  ", gap)?;
+                }
+            }
+            write!(out, "  |")?;
+            for &byte in &line[..count as usize] {
+                out.write_char(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })?;
+            }
+            writeln!(out, "|")?;
+            offset += count;
+        }
+        Ok(())
+    }
+
+    /// Address of the first copy of `pattern` starting at or after `from`
+    pub fn find(&self, pattern: &[u8], from: u64) -> Option<u64> {
+        let pattern_len = pattern.len() as u64;
+        if pattern.is_empty() || pattern_len > self.len {
+            return None;
+        }
+        (from.max(self.start) - self.start..=self.len - pattern_len)
+            .find(|&offset| {
+                if offset % PREEMPT_INTERVAL == 0 {
+                    crate::task::yield_if_needed();
+                }
+                pattern.iter().enumerate().all(|(i, &byte)| self.byte(offset + i as u64) == byte)
+            })
+            .map(|offset| self.start + offset)
+    }
+}
+
+/// Read `width` bytes at `addr`
+pub fn read(addr: u64, width: Width) -> Result<u64, InspectError> {
+    if !addr.is_multiple_of(width.bytes()) {
+        return Err(InspectError::Misaligned);
+    }
+    Span::new(addr, width.bytes(), false)?;
+    let value = unsafe {
+        match width {
+            Width::Byte => (addr as *const u8).read_volatile() as u64,
+            Width::Word => (addr as *const u16).read_volatile() as u64,
+            Width::Dword => (addr as *const u32).read_volatile() as u64,
+            Width::Qword => (addr as *const u64).read_volatile(),
+        }
+    };
+    Ok(value)
+}
+
+/// Write the low `width` bytes of `value` at `addr`
+pub fn write(addr: u64, width: Width, value: u64) -> Result<(), InspectError> {
+    if !addr.is_multiple_of(width.bytes()) {
+        return Err(InspectError::Misaligned);
+    }
+    Span::new(addr, width.bytes(), true)?;
+    unsafe {
+        match width {
+            Width::Byte => (addr as *mut u8).write_volatile(value as u8),
+            Width::Word => (addr as *mut u16).write_volatile(value as u16),
+            Width::Dword => (addr as *mut u32).write_volatile(value as u32),
+            Width::Qword => (addr as *mut u64).write_volatile(value),
+        }
+    }
+    Ok(())
+}
+
+/// Accesses round-trip, dumps and searches see the bytes, and bad ranges
+/// are refused
+fn selftest() -> Result<(), &'static str> {
+    let mut buffer = [0u8; 24];
+    buffer[..6].copy_from_slice(b"Hello\n");
+    let addr = buffer.as_mut_ptr() as u64;
+    // Past the text, whatever the buffer's alignment
+    let aligned = (addr + 15) & !7;
+
+    write(aligned, Width::Dword, 0x1234_5678).map_err(|_| "write refused")?;
+    if read(aligned, Width::Word) != Ok(0x5678) || read(aligned, Width::Dword) != Ok(0x1234_5678) {
+        return Err("read back the wrong value");
+    }
+    if read(aligned + 1, Width::Word) != Err(InspectError::Misaligned) {
+        return Err("misaligned read allowed");
+    }
+
+    let span = Span::new(addr, 6, false).map_err(|_| "stack not readable")?;
+    let mut dump = cosmos_common::fmt::FixedBuf::<96>::new();
+    span.hexdump(&mut dump).map_err(|_| "dump did not fit")?;
+    let bytes = dump.as_str().get(16..).ok_or("dump too short")?;
+    if bytes != "  48 65 6c 6c 6f 0a                                 |Hello.|\n" {
+        return Err("hexdump malformed");
+    }
+    if span.find(b"llo", 0) != Some(addr + 2) || span.find(b"l", addr + 4).is_some() {
+        return Err("search found the wrong place");
+    }
+
+    if Span::new(u64::MAX, 2, false) != Err(InspectError::InvalidRange) {
+        return Err("wrapping range allowed");
+    }
+    // The middle of the non-canonical hole can never be mapped
+    if Span::new(0x8000_0000_0000_0000, 1, false) != Err(InspectError::Unmapped(0x8000_0000_0000_0000)) {
+        return Err("non-canonical address allowed");
+    }
+    Ok(())
+}
+
+crate::selftest!("inspect", selftest);
@@ -0,0 +1,229 @@
+//! Heap Leak Tracking
+//!
+//! While enabled, every heap allocation is entered in a fixed side table
+//! with its size, time and the return addresses of its call chain, and
+//! removed when freed. Whatever is still in the table long after it was
+//! allocated is a leak candidate, reported grouped by call site. The table
+//! never allocates, so the allocator can call into it; allocations made
+//! while it is full are counted but not tracked.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Allocations tracked at once, a power of two
+pub const CAPACITY: usize = 8192;
+/// Return addresses kept per allocation
+pub const DEPTH: usize = 6;
+/// Default age, in seconds, after which `leaks` reports an allocation
+pub const DEFAULT_MIN_AGE_SECS: u64 = 60;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Allocations not tracked because the table was full
+static UNTRACKED: AtomicU64 = AtomicU64::new(0);
+
+/// Tracked allocation
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub address: usize,
+    pub size: usize,
+    pub timestamp_ms: u64,
+    /// Return addresses, innermost first, zero past the end of the walk
+    pub callers: [usize; DEPTH],
+}
+
+/// Open-addressed table keyed by address, 0 marks a free slot
+struct Table {
+    slots: [Allocation; CAPACITY],
+    len: usize,
+}
+
+const EMPTY: Allocation = Allocation { address: 0, size: 0, timestamp_ms: 0, callers: [0; DEPTH] };
+
+static TABLE: Mutex<Table> = Mutex::new(Table { slots: [EMPTY; CAPACITY], len: 0 });
+
+fn home(address: usize) -> usize {
+    // Fibonacci hashing, heap addresses differ mostly in the middle bits
+    (address.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (usize::BITS - CAPACITY.trailing_zeros())) & (CAPACITY - 1)
+}
+
+impl Table {
+    fn insert(&mut self, allocation: Allocation) -> bool {
+        if self.len + 1 >= CAPACITY {
+            return false;
+        }
+        let mut slot = home(allocation.address);
+        while self.slots[slot].address != 0 && self.slots[slot].address != allocation.address {
+            slot = (slot + 1) & (CAPACITY - 1);
+        }
+        if self.slots[slot].address == 0 {
+            self.len += 1;
+        }
+        self.slots[slot] = allocation;
+        true
+    }
+
+    fn remove(&mut self, address: usize) {
+        let mut slot = home(address);
+        loop {
+            match self.slots[slot].address {
+                0 => return,
+                a if a == address => break,
+                _ => slot = (slot + 1) & (CAPACITY - 1),
+            }
+        }
+        self.slots[slot] = EMPTY;
+        self.len -= 1;
+
+        // Shift later entries of the probe run back so lookups still find them
+        let mut hole = slot;
+        let mut next = (slot + 1) & (CAPACITY - 1);
+        while self.slots[next].address != 0 {
+            let wanted = home(self.slots[next].address);
+            // Movable unless its home lies in the cyclic range (hole, next]
+            let stays = if hole <= next { hole < wanted && wanted <= next } else { hole < wanted || wanted <= next };
+            if !stays {
+                self.slots[hole] = self.slots[next];
+                self.slots[next] = EMPTY;
+                hole = next;
+            }
+            next = (next + 1) & (CAPACITY - 1);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.slots.fill(EMPTY);
+        self.len = 0;
+    }
+}
+
+/// Start tracking allocations made from now on
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop tracking and forget every tracked allocation
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+    TABLE.lock().clear();
+    UNTRACKED.store(0, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Turn tracking on if the `kmemleak` flag is on the command line
+pub fn init() {
+    if crate::cmdline::has_flag("kmemleak") {
+        enable();
+    }
+}
+
+/// Allocator hook, after a successful allocation
+#[inline(always)]
+pub fn track_alloc(address: *mut u8, size: usize) {
+    if !is_enabled() || address.is_null() {
+        return;
+    }
+    let allocation = Allocation {
+        address: address as usize,
+        size,
+        timestamp_ms: crate::time::uptime_ms(),
+        callers: crate::ksyms::backtrace(),
+    };
+    if !TABLE.lock().insert(allocation) {
+        UNTRACKED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Allocator hook, before a free
+pub fn track_free(address: *mut u8) {
+    if is_enabled() {
+        TABLE.lock().remove(address as usize);
+    }
+}
+
+/// Tracked and untracked allocation counts
+pub fn counts() -> (usize, u64) {
+    (TABLE.lock().len, UNTRACKED.load(Ordering::Relaxed))
+}
+
+/// Tracked allocations at least `min_age_ms` old, oldest first
+pub fn older_than(min_age_ms: u64) -> Vec<Allocation> {
+    // Reserve before locking, the allocation itself takes the table lock
+    let mut found = Vec::with_capacity(CAPACITY);
+    let now = crate::time::uptime_ms();
+    {
+        let table = TABLE.lock();
+        found.extend(
+            table
+                .slots
+                .iter()
+                .filter(|slot| slot.address != 0 && now.saturating_sub(slot.timestamp_ms) >= min_age_ms)
+                .copied(),
+        );
+    }
+    found.sort_unstable_by_key(|allocation| allocation.timestamp_ms);
+    found
+}
+
+/// First caller outside the allocator and `alloc`'s wrappers around it
+pub fn call_site(allocation: &Allocation) -> usize {
+    super::heap::call_site(&allocation.callers)
+}
+
+/// Freed entries leave no gap in a probe run and leaked ones are reported
+fn selftest() -> Result<(), &'static str> {
+    // Too big for a task stack, build it in place on the heap
+    let mut table = alloc::boxed::Box::<Table>::new_uninit();
+    let mut table = unsafe {
+        let slots = core::ptr::addr_of_mut!((*table.as_mut_ptr()).slots) as *mut Allocation;
+        for i in 0..CAPACITY {
+            slots.add(i).write(EMPTY);
+        }
+        (*table.as_mut_ptr()).len = 0;
+        table.assume_init()
+    };
+
+    // Addresses that share a home slot form one probe run
+    let base = 0x1000usize;
+    let colliding: Vec<usize> = (1..0x100000usize).map(|i| base + i * 16).filter(|&a| home(a) == home(base)).take(3).collect();
+    for &address in [base].iter().chain(&colliding) {
+        table.insert(Allocation { address, ..EMPTY });
+    }
+    table.remove(base);
+    let find = |table: &Table, address: usize| {
+        let mut slot = home(address);
+        while table.slots[slot].address != 0 {
+            if table.slots[slot].address == address {
+                return true;
+            }
+            slot = (slot + 1) & (CAPACITY - 1);
+        }
+        false
+    };
+    if table.len != colliding.len() || colliding.iter().any(|&address| !find(&table, address)) || find(&table, base) {
+        return Err("probe run broken by remove");
+    }
+
+    // A live allocation shows up, a freed one does not
+    let was_enabled = is_enabled();
+    enable();
+    let kept = alloc::boxed::Box::new([0u8; 48]);
+    let freed = alloc::boxed::Box::new([0u8; 48]);
+    let freed_address = &*freed as *const _ as usize;
+    drop(freed);
+    let all = older_than(0);
+    let found = all.iter().any(|a| a.address == &*kept as *const _ as usize && a.size == 48);
+    let leaked_freed = all.iter().any(|a| a.address == freed_address && a.size == 48);
+    if !was_enabled {
+        disable();
+    }
+    if !found || leaked_freed {
+        return Err("live allocation not reported");
+    }
+    Ok(())
+}
+
+crate::selftest!("kmemleak", selftest);
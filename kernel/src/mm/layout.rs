@@ -0,0 +1,245 @@
+//! Kernel Address Space Layout
+//!
+//! Every fixed region the kernel depends on, with its name and access.
+//! The addresses are spread over the loaders, the linker script and
+//! several modules, and nothing but convention kept them apart; `init`
+//! checks that no two overlap, and the `layout` command lists them.
+//!
+//! Low memory is identity mapped, so identity and kernel-virtual regions
+//! share one address space and are checked against each other.
+
+use core::fmt::{self, Write};
+use core::ops::Range;
+use cosmos_bootinfo::{BootInfo, BOOT_INFO_ADDRESS, MEMORY_MAP_ADDRESS};
+use cosmos_common::fmt::HumanBytes;
+use super::{fixmap, heap, vma};
+use crate::arch::x86_64::apic;
+use crate::boot::{self, e820, BootProtocol};
+
+/// Most regions `regions` reports
+const MAX_REGIONS: usize = 16;
+
+/// Legacy VGA memory and option ROMs
+const LEGACY_VGA: Range<u64> = 0xA0000..0xC0000;
+
+/// How a region is addressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+    /// Physical memory reached through the identity map
+    Identity,
+    /// Kernel virtual addresses with no physical counterpart
+    Virtual,
+}
+
+/// Access the kernel needs to a region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access(u8);
+
+impl Access {
+    pub const READ: Access = Access(1 << 0);
+    pub const READ_WRITE: Access = Access(1 << 0 | 1 << 1);
+    pub const READ_EXECUTE: Access = Access(1 << 0 | 1 << 2);
+    pub const ALL: Access = Access(1 << 0 | 1 << 1 | 1 << 2);
+
+    pub fn readable(self) -> bool {
+        self.0 & 1 << 0 != 0
+    }
+
+    pub fn writable(self) -> bool {
+        self.0 & 1 << 1 != 0
+    }
+
+    pub fn executable(self) -> bool {
+        self.0 & 1 << 2 != 0
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set, c| if set { c } else { '-' };
+        write!(f, "{}{}{}", flag(self.readable(), 'r'), flag(self.writable(), 'w'), flag(self.executable(), 'x'))
+    }
+}
+
+/// A named range of the address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: u64,
+    pub end: u64,
+    pub space: Space,
+    pub access: Access,
+}
+
+impl Region {
+    const fn new(name: &'static str, range: Range<u64>, space: Space, access: Access) -> Self {
+        Region { name, start: range.start, end: range.end, space, access }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// Errors found validating the layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutError {
+    /// Two regions share addresses
+    Overlap(Region, Region),
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::Overlap(a, b) => write!(
+                f,
+                "{} {:#x}..{:#x} overlaps {} {:#x}..{:#x}",
+                a.name, a.start, a.end, b.name, b.start, b.end
+            ),
+        }
+    }
+}
+
+/// Regions of the running kernel, sorted by address
+pub struct Regions {
+    entries: [Region; MAX_REGIONS],
+    count: usize,
+}
+
+impl Regions {
+    fn new() -> Self {
+        Regions { entries: [Region::new("", 0..0, Space::Identity, Access::READ); MAX_REGIONS], count: 0 }
+    }
+
+    /// Add a region, empty ones are left out
+    fn push(&mut self, region: Region) {
+        if region.is_empty() {
+            return;
+        }
+        assert!(self.count < MAX_REGIONS, "layout has more than {} regions", MAX_REGIONS);
+        self.entries[self.count] = region;
+        self.count += 1;
+    }
+
+    pub fn as_slice(&self) -> &[Region] {
+        &self.entries[..self.count]
+    }
+}
+
+/// Collect the regions of the running kernel
+///
+/// Regions that depend on the boot, such as the heap or the loader's page
+/// tables, appear once they are known.
+pub fn regions() -> Regions {
+    let mut regions = Regions::new();
+    let boot_info_end = BOOT_INFO_ADDRESS + core::mem::size_of::<BootInfo>() as u64;
+    regions.push(Region::new("boot info", BOOT_INFO_ADDRESS..boot_info_end, Space::Identity, Access::READ));
+    let map_end = MEMORY_MAP_ADDRESS + 4 + (boot::MAX_MEMORY_MAP_ENTRIES * 24) as u64;
+    regions.push(Region::new("E820 map", MEMORY_MAP_ADDRESS..map_end, Space::Identity, Access::READ));
+
+    let protocol = boot::protocol();
+    if protocol.name() == e820::E820.name() {
+        regions.push(Region::new("stage2 page tables", e820::STAGE2_PAGE_TABLES, Space::Identity, Access::READ_WRITE));
+        regions.push(Region::new("stage2 stack", e820::STAGE2_STACK, Space::Identity, Access::READ_WRITE));
+    }
+    if let Some(range) = protocol.boot_page_tables() {
+        regions.push(Region::new("loader page tables", range, Space::Identity, Access::READ_WRITE));
+    }
+    if let Some(range) = protocol.boot_stack() {
+        regions.push(Region::new("loader stack", range, Space::Identity, Access::READ_WRITE));
+    }
+    regions.push(Region::new("legacy VGA", LEGACY_VGA, Space::Identity, Access::READ_WRITE));
+
+    let image = super::kernel_image();
+    regions.push(Region::new("kernel image", image.start.as_u64()..image.end.as_u64(), Space::Identity, Access::ALL));
+    if heap::is_initialized() {
+        let start = heap::heap_start() as u64;
+        regions.push(Region::new("heap", start..start + heap::heap_size() as u64, Space::Identity, Access::READ_WRITE));
+    }
+    if let Some(range) = apic::mmio_range() {
+        regions.push(Region::new("local APIC", range, Space::Identity, Access::READ_WRITE));
+    }
+
+    regions.push(Region::new("kernel VMAs", vma::KERNEL_VMA_START..vma::KERNEL_VMA_END, Space::Virtual, Access::READ_WRITE));
+    regions.push(Region::new("fixmap", fixmap::FIXMAP_START..fixmap::FIXMAP_END, Space::Virtual, Access::READ_WRITE));
+
+    regions.entries[..regions.count].sort_unstable_by_key(|region| (region.start, region.end));
+    regions
+}
+
+/// First pair of overlapping regions in a list sorted by address
+fn find_overlap(regions: &[Region]) -> Option<(Region, Region)> {
+    // Sorted by start, so a region can only overlap one that started earlier
+    let mut furthest: Option<Region> = None;
+    for &region in regions {
+        if let Some(previous) = furthest.filter(|previous| previous.overlaps(&region)) {
+            return Some((previous, region));
+        }
+        if furthest.is_none_or(|previous| region.end > previous.end) {
+            furthest = Some(region);
+        }
+    }
+    None
+}
+
+/// Check that no two regions overlap
+pub fn validate() -> Result<(), LayoutError> {
+    match find_overlap(regions().as_slice()) {
+        Some((a, b)) => Err(LayoutError::Overlap(a, b)),
+        None => Ok(()),
+    }
+}
+
+/// Validate the layout once the heap and APIC are placed, logging conflicts
+pub fn init() {
+    match validate() {
+        Ok(()) => crate::serial_println!("Layout: {} regions, no overlaps", regions().as_slice().len()),
+        Err(e) => crate::serial_println!("Layout: {}", e),
+    }
+}
+
+/// Print every region in address order
+pub fn report(out: &mut dyn Write) -> fmt::Result {
+    for region in regions().as_slice() {
+        let space = match region.space {
+            Space::Identity => "ident",
+            Space::Virtual => "virt",
+        };
+        writeln!(out, "  {:#018x}-{:#018x} {:>9} {} {:<5} {}",
+            region.start, region.end - 1, HumanBytes(region.len()), region.access, space, region.name)?;
+    }
+    match validate() {
+        Ok(()) => Ok(()),
+        Err(e) => writeln!(out, "CONFLICT: {}", e),
+    }
+}
+
+/// The live layout is consistent and overlaps are found
+fn selftest() -> Result<(), &'static str> {
+    validate().map_err(|_| "regions overlap")?;
+    let region = |name, range| Region::new(name, range, Space::Identity, Access::READ);
+    let apart = [region("a", 0x1000..0x3000), region("b", 0x3000..0x4000), region("c", 0x8000..0x9000)];
+    if find_overlap(&apart).is_some() {
+        return Err("adjacent regions reported");
+    }
+    // "b" lies inside "a"
+    let nested = [region("a", 0x1000..0x9000), region("b", 0x2000..0x3000), region("c", 0x8000..0xA000)];
+    if find_overlap(&nested).map(|(a, b)| (a.name, b.name)) != Some(("a", "b")) {
+        return Err("nested region missed");
+    }
+    let crossing = [region("a", 0x1000..0x9000), region("c", 0x8000..0xA000)];
+    if find_overlap(&crossing).map(|(a, b)| (a.name, b.name)) != Some(("a", "c")) {
+        return Err("overlap missed");
+    }
+    Ok(())
+}
+
+crate::selftest!("layout", selftest);
@@ -1,120 +1,38 @@
 //! Memory Map Parsing
 
+use core::fmt::{self, Write};
+use core::ops::Range;
+use cosmos_common::fmt::HumanBytes;
 use super::{PhysicalAddress, PhysicalFrame, PhysicalFrameRange};
 
-/// E820 memory map entry types
-#[repr(u32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum MemoryType {
-    /// Usable RAM
-    Usable = 1,
-    /// Reserved memory
-    Reserved = 2,
-    /// ACPI reclaimable memory
-    AcpiReclaimable = 3,
-    /// ACPI NVS memory
-    AcpiNvs = 4,
-    /// Bad memory
-    BadMemory = 5,
-}
+pub use cosmos_bootinfo::{E820Entry as MemoryMapEntry, MemoryType};
+use cosmos_bootinfo::sanitize_memory_map;
 
-impl MemoryType {
-    /// Create a MemoryType from raw u32 value
-    pub fn from_u32(value: u32) -> Option<Self> {
-        match value {
-            1 => Some(MemoryType::Usable),
-            2 => Some(MemoryType::Reserved),
-            3 => Some(MemoryType::AcpiReclaimable),
-            4 => Some(MemoryType::AcpiNvs),
-            5 => Some(MemoryType::BadMemory),
-            _ => None,
-        }
-    }
-    
-    /// Check if this memory type is usable for allocation
-    pub fn is_usable(self) -> bool {
-        matches!(self, MemoryType::Usable)
-    }
+/// Most entries a sanitized map holds, twice what the in-tree loaders pass
+const MAX_ENTRIES: usize = 256;
+
+/// Kernel copy of the bootloader's map, sorted and without overlaps
+struct Sanitized {
+    entries: [MemoryMapEntry; MAX_ENTRIES],
+    count: usize,
 }
 
-/// A single memory map entry from the bootloader, 24 bytes total
-#[repr(C, packed)]
-#[derive(Debug, Clone, Copy)]
-pub struct MemoryMapEntry {
-    /// Base address of the memory region, 8 bytes
-    pub base_addr: u64,
-    /// Length of the memory region in bytes, 8 bytes
-    pub length: u64,
-    /// Type of memory region, 4 bytes
-    pub entry_type: u32,
-    /// Extended attributes, 4 bytes, usually 1 for valid entries
-    pub attributes: u32,
+static SANITIZED: spin::Once<Sanitized> = spin::Once::new();
+
+/// Check whether an entry is RAM the kernel maps write-back, including
+/// the ACPI regions firmware keeps in it
+fn is_ram(entry: &MemoryMapEntry) -> bool {
+    matches!(
+        entry.memory_type(),
+        Some(MemoryType::Usable) | Some(MemoryType::AcpiReclaimable) | Some(MemoryType::AcpiNvs)
+    )
 }
 
-impl MemoryMapEntry {
-    /// Get the memory type for this entry
-    pub fn memory_type(&self) -> Option<MemoryType> {
-        MemoryType::from_u32(self.entry_type)
-    }
-    
-    /// Get the start address of this memory region
-    pub fn start_address(&self) -> PhysicalAddress {
-        PhysicalAddress::new(self.base_addr)
-    }
-    
-    /// Get the end address of this memory region, exclusive
-    pub fn end_address(&self) -> PhysicalAddress {
-        PhysicalAddress::new(self.base_addr + self.length)
-    }
-    
-    /// Get the range of frames covered by this memory region
-    pub fn frame_range(&self) -> PhysicalFrameRange {
-        let start_frame = PhysicalFrame::containing_address(self.start_address());
-        let end_frame = PhysicalFrame::containing_address(self.end_address() - 1) + 1;
-        PhysicalFrameRange::new(start_frame, end_frame)
-    }
-    
-    /// Check if this entry is valid and usable
-    pub fn is_usable(&self) -> bool {
-        self.attributes == 1 && 
-        self.length > 0 && 
-        self.memory_type().map_or(false, |t| t.is_usable())
-    }
-    
-    /// Check if entry represents system/hardware reserved memory
-    pub fn is_system_reserved(&self) -> bool {
-        // Check for common system reserved regions
-        let start = self.base_addr;
-        let _end = self.base_addr + self.length;
-        
-        // BIOS/VGA regions
-        if start < 0x100000 {
-            return true;
-        }
-        
-        // Check memory type
-        match self.memory_type() {
-            Some(MemoryType::Reserved) | Some(MemoryType::BadMemory) => true,
-            _ => false,
-        }
-    }
-    
-    /// Check if this entry can be reclaimed later
-    pub fn is_reclaimable(&self) -> bool {
-        matches!(self.memory_type(), Some(MemoryType::AcpiReclaimable))
-    }
-    
-    /// Get a human-readable description of this memory region
-    pub fn description(&self) -> &'static str {
-        match self.memory_type() {
-            Some(MemoryType::Usable) => "Usable RAM",
-            Some(MemoryType::Reserved) => "Reserved",
-            Some(MemoryType::AcpiReclaimable) => "ACPI Reclaimable",
-            Some(MemoryType::AcpiNvs) => "ACPI NVS",
-            Some(MemoryType::BadMemory) => "Bad Memory",
-            None => "Unknown",
-        }
-    }
+/// Range of frames covered by a memory map entry
+fn frame_range(entry: &MemoryMapEntry) -> PhysicalFrameRange {
+    let start_frame = PhysicalFrame::containing_address(PhysicalAddress::new(entry.base_addr));
+    let end_frame = PhysicalFrame::containing_address(PhysicalAddress::new(entry.end() - 1)) + 1;
+    PhysicalFrameRange::new(start_frame, end_frame)
 }
 
 /// Memory map provided by the bootloader
@@ -124,9 +42,6 @@ pub struct MemoryMap {
 }
 
 impl MemoryMap {
-    /// Fixed location where bootloader stores memory map
-    const MEMORY_MAP_LOCATION: usize = 0x9000;
-    
     /// Create a fallback memory map when bootloader data is unavailable
     pub fn create_fallback() -> Self {
         // Create a static fallback memory map with reasonable defaults
@@ -153,98 +68,103 @@ impl MemoryMap {
         }
     }
     
-    /// Parse memory map from bootloader data
+    /// Parse memory map from the detected boot protocol
+    ///
+    /// The loader's entries are sanitized once into a kernel copy, so the
+    /// frame allocator never sees unsorted or overlapping regions.
     pub fn from_bootloader() -> Result<Self, MemoryMapError> {
-        unsafe {
-            // Bootloader stores 32-bit entry count, then enters
-            let entry_count_ptr = Self::MEMORY_MAP_LOCATION as *const u32;
-            let raw_entry_count = *entry_count_ptr;
-            
-            // Check if location contains reasonable data
-            if raw_entry_count == 0 || raw_entry_count == 0xFFFFFFFF {
-                return Err(MemoryMapError::NoMemoryMap);
+        let raw = crate::boot::protocol().memory_map()?;
+        let map = SANITIZED.call_once(|| {
+            let mut map = Sanitized {
+                entries: [MemoryMapEntry::new(0, 0, MemoryType::Reserved); MAX_ENTRIES],
+                count: 0,
+            };
+            map.count = sanitize_memory_map(raw, &mut map.entries);
+            if map.count != raw.len() {
+                crate::serial_println!("Memory map: {} entries sanitized to {}", raw.len(), map.count);
             }
-            
-            // Convert to usize and validate
-            let entry_count = raw_entry_count as usize;
-            if entry_count > 64 {
-                return Err(MemoryMapError::InvalidMemoryMap);
+            map
+        });
+        Self::from_entries(&map.entries[..map.count])
+    }
+    
+    /// The sanitized bootloader map, once `from_bootloader` has built it
+    pub fn boot_map() -> Option<Self> {
+        let map = SANITIZED.get()?;
+        Self::from_entries(&map.entries[..map.count]).ok()
+    }
+    
+    /// Validate E820 entries and build a memory map from them
+    pub fn from_entries(entries: &'static [MemoryMapEntry]) -> Result<Self, MemoryMapError> {
+        // Validate entries and calculate total usable memory
+        let mut usable_memory = 0;
+        let mut highest_ram_addr = 0;
+        let mut valid_entries = 0;
+        
+        for entry in entries.iter() {
+            // Basic validation
+            if entry.length == 0 {
+                continue; // Skip zero-length entries
             }
             
-            // Memory map entries start after the count, bootloader uses 4 byte alignment
-            let entries_ptr = (Self::MEMORY_MAP_LOCATION + 4) as *const MemoryMapEntry;
-            let entries = core::slice::from_raw_parts(entries_ptr, entry_count);
+            // Check for address overflow
+            if entry.base_addr.checked_add(entry.length).is_none() {
+                continue; // Skip entries that would overflow
+            }
             
-            // Validate entries and calculate total usable memory
-            let mut usable_memory = 0;
-            let mut highest_ram_addr = 0;
-            let mut valid_entries = 0;
+            // Check for reasonable base address
+            if entry.base_addr < 0x1000 && entry.base_addr != 0 {
+                continue; // Skip suspicious low addresses except 0
+            }
             
-            for entry in entries.iter() {
-                // Basic validation
-                if entry.length == 0 {
-                    continue; // Skip zero-length entries
-                }
-                
-                // Check for address overflow
-                if entry.base_addr.checked_add(entry.length).is_none() {
-                    continue; // Skip entries that would overflow
-                }
-                
-                // Check for reasonable base address
-                if entry.base_addr < 0x1000 && entry.base_addr != 0 {
-                    continue; // Skip suspicious low addresses except 0
-                }
-                
-                // Check memory type is reasonable
-                let mem_type = entry.memory_type();
-                if mem_type.is_none() {
-                    // Allow unknown types but don't count as usable
-                    valid_entries += 1;
-                    continue;
-                }
-                
+            // Check memory type is reasonable
+            let mem_type = entry.memory_type();
+            if mem_type.is_none() {
+                // Allow unknown types but don't count as usable
                 valid_entries += 1;
-                
-                // Track highest reclaimable RAM address
-                if entry.is_usable() || entry.is_reclaimable() {
-                    let end_addr = entry.base_addr + entry.length;
-                    if end_addr > highest_ram_addr && end_addr < 0x100000000 {
-                        highest_ram_addr = end_addr;
-                    }
-                }
-                
-                if entry.is_usable() {
-                    usable_memory += entry.length;
-                }
+                continue;
             }
             
-            if valid_entries == 0 {
-                return Err(MemoryMapError::InvalidMemoryMap);
-            }
+            valid_entries += 1;
             
-            // Estimate from highest RAM address
-            if usable_memory < 16 * 1024 * 1024 || highest_ram_addr > usable_memory * 2 {
-                if highest_ram_addr > 0 {
-                    // Use highest RAM address as the total physical memory
-                    usable_memory = (highest_ram_addr * 3) / 4;
-                }
-                // Ensure minimum of 128MB
-                if usable_memory < 128 * 1024 * 1024 {
-                    usable_memory = 128 * 1024 * 1024;
+            // Track highest reclaimable RAM address
+            if entry.is_usable() || entry.is_reclaimable() {
+                let end_addr = entry.base_addr + entry.length;
+                if end_addr > highest_ram_addr && end_addr < 0x100000000 {
+                    highest_ram_addr = end_addr;
                 }
             }
             
-            let memory_map = MemoryMap {
-                entries,
-                usable_memory,
-            };
-            
-            // Output debug information
-            memory_map.debug_print();
-            
-            Ok(memory_map)
+            if entry.is_usable() {
+                usable_memory += entry.length;
+            }
+        }
+        
+        if valid_entries == 0 {
+            return Err(MemoryMapError::InvalidMemoryMap);
         }
+        
+        // Estimate from highest RAM address
+        if usable_memory < 16 * 1024 * 1024 || highest_ram_addr > usable_memory * 2 {
+            if highest_ram_addr > 0 {
+                // Use highest RAM address as the total physical memory
+                usable_memory = (highest_ram_addr * 3) / 4;
+            }
+            // Ensure minimum of 128MB
+            if usable_memory < 128 * 1024 * 1024 {
+                usable_memory = 128 * 1024 * 1024;
+            }
+        }
+        
+        let memory_map = MemoryMap {
+            entries,
+            usable_memory,
+        };
+        
+        // Output debug information
+        memory_map.debug_print();
+        
+        Ok(memory_map)
     }
     
     /// Get total usable memory in bytes
@@ -276,7 +196,51 @@ impl MemoryMap {
     
     /// Iterator over usable frame ranges
     pub fn usable_frame_ranges(&self) -> impl Iterator<Item = PhysicalFrameRange> + '_ {
-        self.usable_regions().map(|entry| entry.frame_range())
+        self.usable_regions().map(frame_range)
+    }
+    
+    /// Entry covering `addr`, `None` if it falls in a hole of the map
+    pub fn region_containing(&self, addr: u64) -> Option<&MemoryMapEntry> {
+        self.entries.iter().find(|entry| entry.base_addr <= addr && addr < entry.end())
+    }
+    
+    /// Type of the memory at `addr`, `None` in a hole or for unknown types
+    pub fn type_at(&self, addr: u64) -> Option<MemoryType> {
+        self.region_containing(addr).and_then(|entry| entry.memory_type())
+    }
+    
+    /// Check whether any of `range` is RAM, where device registers must
+    /// never be mapped
+    pub fn overlaps_ram(&self, range: Range<u64>) -> bool {
+        self.entries
+            .iter()
+            .filter(|entry| is_ram(entry))
+            .any(|entry| entry.base_addr < range.end && range.start < entry.end())
+    }
+    
+    /// Iterator over the address ranges below the end of the map that are
+    /// not RAM: reserved entries and the gaps between entries, where MMIO
+    /// lives
+    ///
+    /// Expects sorted entries, as `from_bootloader` produces.
+    pub fn holes(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        let mut entries = self.entries.iter();
+        let mut cursor = 0;
+        core::iter::from_fn(move || {
+            let mut hole: Option<Range<u64>> = None;
+            for entry in entries.by_ref() {
+                // A gap before RAM ends the hole, anything else extends it
+                let end = if is_ram(entry) { entry.base_addr } else { entry.end() };
+                if end > cursor {
+                    hole = Some(hole.map_or(cursor, |hole| hole.start)..end);
+                }
+                cursor = cursor.max(entry.end());
+                if is_ram(entry) && hole.is_some() {
+                    return hole;
+                }
+            }
+            hole
+        })
     }
     
     /// Find the largest usable memory region
@@ -325,6 +289,18 @@ impl MemoryMap {
         // TODO: Output physical memory details to serial
     }
     
+    /// Print each entry with its size, then the totals
+    pub fn report(&self, out: &mut dyn Write) -> fmt::Result {
+        for entry in self.entries {
+            // Copied out, the entries are packed
+            let (base, length) = (entry.base_addr, entry.length);
+            writeln!(out, "  {:#018x}-{:#018x} {:>9} {}",
+                base, entry.end() - 1, HumanBytes(length), entry.description())?;
+        }
+        writeln!(out, "Usable: {} of {}",
+            HumanBytes(self.total_usable_memory()), HumanBytes(self.total_physical_memory()))
+    }
+    
     /// Get memory statistics
     pub fn stats(&self) -> MemoryMapStats {
         let mut stats = MemoryMapStats::default();
@@ -396,3 +372,58 @@ pub struct MemoryMapStats {
     pub unknown_regions: u32,
     pub unknown_memory: u64,
 }
+
+fn selftest() -> Result<(), &'static str> {
+    // Unsorted, with a reserved hole and an ACPI entry overlapping RAM
+    let input = [
+        MemoryMapEntry::new(0x200000, 0x100000, MemoryType::Usable),
+        MemoryMapEntry::new(0x100000, 0x100000, MemoryType::Usable),
+        MemoryMapEntry::new(0x180000, 0x10000, MemoryType::Reserved),
+        MemoryMapEntry::new(0x2F0000, 0x20000, MemoryType::AcpiNvs),
+    ];
+    let expected = [
+        (0x100000, 0x80000, MemoryType::Usable),
+        (0x180000, 0x10000, MemoryType::Reserved),
+        (0x190000, 0x160000, MemoryType::Usable),
+        (0x2F0000, 0x20000, MemoryType::AcpiNvs),
+    ];
+    let mut output = [MemoryMapEntry::new(0, 0, MemoryType::Reserved); 8];
+    let count = sanitize_memory_map(&input, &mut output);
+    if count != expected.len() {
+        return Err("sanitized entry count");
+    }
+    for (entry, &(base, length, memory_type)) in output.iter().zip(expected.iter()) {
+        if entry.base_addr != base || entry.length != length || entry.memory_type() != Some(memory_type) {
+            return Err("sanitized entry");
+        }
+    }
+
+    // A full output keeps the lowest regions
+    if sanitize_memory_map(&input, &mut output[..1]) != 1 || output[0].base_addr != 0x100000 {
+        return Err("truncated map");
+    }
+
+    // RAM, a reserved entry, a gap, then RAM again
+    static ENTRIES: [MemoryMapEntry; 3] = [
+        MemoryMapEntry::new(0, 0x9F000, MemoryType::Usable),
+        MemoryMapEntry::new(0xF0000, 0x10000, MemoryType::Reserved),
+        MemoryMapEntry::new(0x100000, 0x100000, MemoryType::Usable),
+    ];
+    let map = MemoryMap { entries: &ENTRIES, usable_memory: 0 };
+    if map.type_at(0x1000) != Some(MemoryType::Usable) || map.type_at(0xF8000) != Some(MemoryType::Reserved) {
+        return Err("type_at");
+    }
+    if map.region_containing(0xA0000).is_some() || map.type_at(0x200000).is_some() {
+        return Err("region_containing in a hole");
+    }
+    let mut holes = map.holes();
+    if holes.next() != Some(0x9F000..0x100000) || holes.next().is_some() {
+        return Err("holes");
+    }
+    if !map.overlaps_ram(0x9E000..0xA0000) || map.overlaps_ram(0xA0000..0x100000) {
+        return Err("overlaps_ram");
+    }
+    Ok(())
+}
+
+crate::selftest!("memory_map", selftest);
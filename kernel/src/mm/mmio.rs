@@ -0,0 +1,112 @@
+//! Memory-Mapped I/O
+//!
+//! Device registers are identity mapped uncached and reached through an
+//! `Mmio` handle rather than raw pointers. Every access is volatile and
+//! checked against the mapped length, so a wrong offset panics instead of
+//! scribbling over whatever sits past the device.
+
+use core::marker::PhantomData;
+use super::paging::{self, PagingError};
+use super::PhysicalAddress;
+use crate::arch::x86_64::memtype::CacheMode;
+
+/// A value MMIO moves in a single access
+pub trait Register: Copy {}
+
+impl Register for u8 {}
+impl Register for u16 {}
+impl Register for u32 {}
+impl Register for u64 {}
+
+/// Mapped device register range, accessed as `T` by default
+///
+/// Mappings are never torn down, so the handle is a plain address and can
+/// be copied into whatever needs to reach the device.
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio<T: Register = u32> {
+    base: u64,
+    len: usize,
+    _width: PhantomData<T>,
+}
+
+/// Map `len` bytes of device memory at `phys` uncached
+pub fn map_region<T: Register>(phys: PhysicalAddress, len: usize) -> Result<Mmio<T>, PagingError> {
+    map_region_with(phys, len, CacheMode::Uncached)
+}
+
+/// Map `len` bytes of device memory at `phys` with memory type `mode`,
+/// e.g. write-combining for a framebuffer
+pub fn map_region_with<T: Register>(phys: PhysicalAddress, len: usize, mode: CacheMode) -> Result<Mmio<T>, PagingError> {
+    if len == 0 {
+        return Err(PagingError::InvalidAddress);
+    }
+    paging::map_device(phys, len as u64, mode)?;
+    Ok(Mmio { base: phys.as_u64(), len, _width: PhantomData })
+}
+
+impl<T: Register> Mmio<T> {
+    /// Physical address of the first register
+    pub fn phys(&self) -> PhysicalAddress {
+        PhysicalAddress::new(self.base)
+    }
+
+    /// Length of the range in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Address of a `U` at `offset`, panicking outside the range
+    fn address<U: Register>(&self, offset: usize) -> u64 {
+        let size = core::mem::size_of::<U>();
+        assert!(
+            offset.checked_add(size).is_some_and(|end| end <= self.len) && offset.is_multiple_of(size),
+            "MMIO access at {:#x}+{:#x} outside {} bytes or misaligned",
+            self.base,
+            offset,
+            self.len
+        );
+        self.base + offset as u64
+    }
+
+    /// Read the register at `offset`
+    pub fn read(&self, offset: usize) -> T {
+        self.read_as(offset)
+    }
+
+    /// Write the register at `offset`
+    pub fn write(&self, offset: usize, value: T) {
+        self.write_as(offset, value)
+    }
+
+    /// Read, change and write back the register at `offset`
+    pub fn modify(&self, offset: usize, f: impl FnOnce(T) -> T) {
+        self.write(offset, f(self.read(offset)));
+    }
+
+    /// Read a register of another width
+    pub fn read_as<U: Register>(&self, offset: usize) -> U {
+        unsafe { core::ptr::read_volatile(self.address::<U>(offset) as *const U) }
+    }
+
+    /// Write a register of another width
+    pub fn write_as<U: Register>(&self, offset: usize, value: U) {
+        unsafe { core::ptr::write_volatile(self.address::<U>(offset) as *mut U, value) }
+    }
+
+    /// Handle to `len` bytes at `offset`, for a register block inside the
+    /// range
+    pub fn subregion(&self, offset: usize, len: usize) -> Mmio<T> {
+        assert!(
+            offset.checked_add(len).is_some_and(|end| end <= self.len),
+            "MMIO subregion {:#x}+{:#x} outside {} bytes",
+            self.base,
+            offset,
+            self.len
+        );
+        Mmio { base: self.base + offset as u64, len, _width: PhantomData }
+    }
+}
@@ -1,13 +1,24 @@
 //! Memory Management Module
 
+pub mod address_space;
+pub mod fixmap;
 pub mod memory_map;
 pub mod frame_allocator;
+pub mod frame_info;
 pub mod heap;
+pub mod inspect;
+#[cfg(feature = "kmemleak")]
+pub mod kmemleak;
+pub mod layout;
+pub mod mmio;
+pub mod oom;
 pub mod paging;
+pub mod tlb;
+pub mod vma;
 
 // Re-export core types
 pub use memory_map::{MemoryMap, MemoryMapEntry, MemoryType, MemoryMapError};
-pub use frame_allocator::{FrameAllocator, AllocationError};
+pub use frame_allocator::{FrameAllocator, AllocationError, Zone};
 
 /// Physical address type with alignment and arithmetic operations
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -171,3 +182,26 @@ impl Iterator for PhysicalFrameRange {
         }
     }
 }
+
+// Bounds of the loaded kernel image, .bss included, from the linker script
+unsafe extern "C" {
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+}
+
+/// Physical range of the running kernel image
+///
+/// The loader may place the kernel away from its link address, so this
+/// comes from where the image actually is.
+pub fn kernel_image() -> core::ops::Range<PhysicalAddress> {
+    let start = &raw const __kernel_start as u64;
+    let end = &raw const __kernel_end as u64;
+    PhysicalAddress::new(start)..PhysicalAddress::new(end)
+}
+
+/// How far the kernel was moved from its link address
+///
+/// Add to addresses from the linker map, such as the symbol table.
+pub fn kernel_offset() -> usize {
+    (&raw const __kernel_start as usize).wrapping_sub(cosmos_bootinfo::KERNEL_LINK_ADDRESS as usize)
+}
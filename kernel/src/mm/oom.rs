@@ -0,0 +1,165 @@
+//! Out-of-Memory Policy
+//!
+//! When the heap cannot satisfy a request, the allocator runs the registered
+//! shrinkers, which drop memory their subsystem can rebuild later, and
+//! retries once. If the retry fails too, the allocation error handler
+//! reports the request, its call chain and the allocator statistics, then
+//! panics so pstore and the crash dump keep the report.
+//!
+//! Shrinkers run inside the failing allocation, possibly with the caller's
+//! locks held, so they must only `try_lock` and must not allocate.
+
+use core::alloc::Layout;
+use core::fmt::Write;
+use cosmos_common::fmt::HumanBytes;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use crate::ksyms::Address;
+use crate::stats::MemoryStats;
+
+/// Registered shrinkers at most
+pub const MAX_SHRINKERS: usize = 8;
+/// Return addresses reported for the failing request
+const REPORT_DEPTH: usize = 8;
+
+/// Reclaim callback, returns the bytes it freed
+#[derive(Clone, Copy)]
+struct Shrinker {
+    name: &'static str,
+    shrink: fn() -> usize,
+}
+
+static SHRINKERS: Mutex<[Option<Shrinker>; MAX_SHRINKERS]> = Mutex::new([None; MAX_SHRINKERS]);
+
+/// Set while shrinkers run, so their own failed allocations do not recurse
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// Reclaim passes run and bytes they freed since boot
+static RECLAIMS: AtomicU64 = AtomicU64::new(0);
+static RECLAIMED: AtomicU64 = AtomicU64::new(0);
+
+/// Errors from shrinker registration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomError {
+    /// A shrinker with that name is already registered
+    AlreadyRegistered,
+    /// All shrinker slots are in use
+    TooManyShrinkers,
+}
+
+impl core::fmt::Display for OomError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            OomError::AlreadyRegistered => write!(f, "Shrinker already registered"),
+            OomError::TooManyShrinkers => write!(f, "Too many shrinkers"),
+        }
+    }
+}
+
+/// Register `shrink` to be run when the heap is exhausted
+pub fn register_shrinker(name: &'static str, shrink: fn() -> usize) -> Result<(), OomError> {
+    let mut shrinkers = SHRINKERS.lock();
+    if shrinkers.iter().flatten().any(|shrinker| shrinker.name == name) {
+        return Err(OomError::AlreadyRegistered);
+    }
+    let slot = shrinkers.iter_mut().find(|slot| slot.is_none()).ok_or(OomError::TooManyShrinkers)?;
+    *slot = Some(Shrinker { name, shrink });
+    Ok(())
+}
+
+/// Remove a shrinker, returns false if none had that name
+pub fn unregister_shrinker(name: &str) -> bool {
+    let mut shrinkers = SHRINKERS.lock();
+    match shrinkers.iter_mut().find(|slot| slot.is_some_and(|shrinker| shrinker.name == name)) {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Run every shrinker, returns the bytes they freed
+///
+/// Called by the allocator after a failed allocation.
+pub fn reclaim() -> usize {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    // Copy out so shrinkers run, and free, without the registry locked
+    let shrinkers = match SHRINKERS.try_lock() {
+        Some(shrinkers) => *shrinkers,
+        None => [None; MAX_SHRINKERS],
+    };
+    let freed: usize = shrinkers.iter().flatten().map(|shrinker| (shrinker.shrink)()).sum();
+    RECLAIMS.fetch_add(1, Ordering::Relaxed);
+    RECLAIMED.fetch_add(freed as u64, Ordering::Relaxed);
+    RECLAIMING.store(false, Ordering::Release);
+    freed
+}
+
+/// Reclaim passes run and bytes freed by them since boot
+pub fn reclaim_stats() -> (u64, u64) {
+    (RECLAIMS.load(Ordering::Relaxed), RECLAIMED.load(Ordering::Relaxed))
+}
+
+/// Describe a request that failed even after reclaim
+fn report(out: &mut dyn Write, layout: Layout, callers: &[usize]) -> core::fmt::Result {
+    writeln!(out, "Out of memory: {} byte request, align {}", layout.size(), layout.align())?;
+    writeln!(out, "Requested by {}", Address(super::heap::call_site(callers)))?;
+    for &caller in callers.iter().take_while(|&&caller| caller != 0) {
+        writeln!(out, "  {}", Address(caller))?;
+    }
+    let (passes, freed) = reclaim_stats();
+    writeln!(out, "Reclaim: {} passes, {} freed", passes, HumanBytes(freed))?;
+
+    // The panic path may follow a failure inside a locked allocator
+    match super::heap::try_heap_stats() {
+        Some(heap) => MemoryStats { heap, frames: super::frame_allocator::get_stats() }.write_report(out),
+        None => writeln!(out, "Heap statistics unavailable, heap locked"),
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let callers: [usize; REPORT_DEPTH] = crate::ksyms::backtrace();
+    let _ = report(&mut crate::console::Console, layout, &callers);
+    panic!("out of memory allocating {} bytes", layout.size());
+}
+
+static SELFTEST_SHRINKS: AtomicU64 = AtomicU64::new(0);
+
+fn selftest_shrink() -> usize {
+    SELFTEST_SHRINKS.fetch_add(1, Ordering::Relaxed);
+    0
+}
+
+/// A failed allocation runs the shrinkers and is counted
+fn selftest() -> Result<(), &'static str> {
+    register_shrinker("selftest", selftest_shrink).map_err(|_| "register")?;
+    if register_shrinker("selftest", selftest_shrink) != Err(OomError::AlreadyRegistered) {
+        unregister_shrinker("selftest");
+        return Err("duplicate shrinker accepted");
+    }
+
+    let before = super::heap::heap_stats().failures;
+    let layout = Layout::from_size_align(1 << 40, 8).map_err(|_| "layout")?;
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    let registered = unregister_shrinker("selftest");
+    if !ptr.is_null() {
+        unsafe { alloc::alloc::dealloc(ptr, layout) };
+        return Err("impossible allocation succeeded");
+    }
+    if !registered || unregister_shrinker("selftest") {
+        return Err("unregister");
+    }
+    if SELFTEST_SHRINKS.load(Ordering::Relaxed) == 0 {
+        return Err("shrinker not run");
+    }
+    if super::heap::heap_stats().failures <= before {
+        return Err("failure not counted");
+    }
+    Ok(())
+}
+
+crate::selftest!("oom", selftest);
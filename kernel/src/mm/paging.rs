@@ -1,17 +1,38 @@
 //! Page Table Management
+//!
+//! Page tables are read and written through the temporary mapping window,
+//! so new tables can come from anywhere in RAM rather than only from the
+//! identity map.
 
-use super::{PhysicalAddress, PhysicalFrame};
+use super::{PhysicalAddress, PhysicalFrame, Zone};
+use super::fixmap;
+use super::frame_allocator;
+use super::frame_info::{self, Owner};
 use super::memory_map::{MemoryMap, MemoryType};
+use super::tlb;
+use core::fmt::{self, Write};
+use crate::arch::x86_64::memtype::{self, CacheMode};
 
 /// Page table entry flags
-const PAGE_PRESENT: u64 = 1 << 0;
-const PAGE_WRITABLE: u64 = 1 << 1;
-const PAGE_SIZE: u64 = 1 << 7; // 2MB pages
+pub(super) const PAGE_PRESENT: u64 = 1 << 0;
+pub(super) const PAGE_WRITABLE: u64 = 1 << 1;
+pub(super) const PAGE_SIZE: u64 = 1 << 7; // 2MB pages
+pub(super) const PAGE_USER: u64 = 1 << 2;
+const PAGE_WRITE_THROUGH: u64 = 1 << 3;
+const PAGE_CACHE_DISABLE: u64 = 1 << 4;
+const PAGE_ACCESSED: u64 = 1 << 5;
+const PAGE_DIRTY: u64 = 1 << 6;
+const PAGE_GLOBAL: u64 = 1 << 8;
+/// Software-available bit marking a read-only page as copy-on-write
+const PAGE_COW: u64 = 1 << 9;
+const PAGE_NO_EXECUTE: u64 = 1 << 63;
+const LARGE_PAGE_PAT: u64 = 1 << 12;
+/// PAT bit of a 4KB entry, the bit that is `PAGE_SIZE` in larger ones
+const PAGE_PAT: u64 = 1 << 7;
 
-/// Page table addresses
-const PML4_ADDRESS: usize = 0x70000;
-const PDPT_ADDRESS: usize = 0x71000;
-const PD_BASE_ADDRESS: usize = 0x72000;
+/// Physical address bits of a page table entry
+pub(super) const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const LARGE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFE0_0000;
 
 /// Errors that can occur during paging operations
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,9 +42,106 @@ pub enum PagingError {
     Corruption,
 }
 
+/// A page table entry, named by its table's physical address and index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Entry {
+    table: u64,
+    index: usize,
+}
+
+impl Entry {
+    /// Entry of the table at `table` covering `virt` at the level with `shift`
+    pub(super) const fn of(table: u64, virt: u64, shift: u32) -> Self {
+        Entry { table, index: ((virt >> shift) & 511) as usize }
+    }
+
+    /// Entry `index` of the table at `table`
+    pub(super) const fn at(table: u64, index: usize) -> Self {
+        Entry { table, index }
+    }
+
+    fn frame(self) -> PhysicalFrame {
+        PhysicalFrame::containing_address(PhysicalAddress::new(self.table))
+    }
+
+    pub(super) fn read(self) -> u64 {
+        fixmap::with_frame(self.frame(), |page| unsafe { (page as *const u64).add(self.index).read_volatile() })
+    }
+
+    pub(super) fn write(self, value: u64) {
+        fixmap::with_frame(self.frame(), |page| unsafe { (page as *mut u64).add(self.index).write_volatile(value) });
+    }
+
+    /// Physical address of the frame or table the entry points to
+    pub(super) fn address(self) -> u64 {
+        self.read() & ADDRESS_MASK
+    }
+}
+
 /// Currently mapped memory size
 static MAPPED_MEMORY: spin::Mutex<usize> = spin::Mutex::new(0);
 
+/// Serializes kernel page table updates
+static MAP_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// The kernel's PML4, recorded at boot
+static KERNEL_ROOT: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+
+/// Physical address of the kernel's PML4
+///
+/// Kernel mappings are changed through it rather than through CR3, which
+/// may hold an address space that only shares the kernel's tables.
+pub fn kernel_root() -> PhysicalAddress {
+    use core::sync::atomic::Ordering;
+    use x86_64::registers::control::Cr3;
+
+    match KERNEL_ROOT.load(Ordering::Relaxed) {
+        0 => PhysicalAddress::new(Cr3::read().0.start_address().as_u64()),
+        root => PhysicalAddress::new(root),
+    }
+}
+
+/// Give every kernel PML4 slot in `start..end` a table
+///
+/// Address spaces copy the kernel's PML4 entries when created, so the
+/// tables under these slots are shared and kernel mappings made later
+/// appear in every space.
+pub(super) fn populate_kernel_slots(start: u64, end: u64) -> Result<(), PagingError> {
+    let _guard = MAP_LOCK.lock();
+    for slot in ((start >> 39) & 511)..=(((end - 1) >> 39) & 511) {
+        next_table(Entry::at(kernel_root().as_u64(), slot as usize))?;
+    }
+    Ok(())
+}
+
+/// Frame of the kernel's last-level page table covering `virt`
+///
+/// Missing tables on the way are allocated.
+pub(super) fn page_table(virt: u64) -> Result<PhysicalFrame, PagingError> {
+    if x86_64::VirtAddr::try_new(virt).is_err() {
+        return Err(PagingError::InvalidAddress);
+    }
+
+    let _guard = MAP_LOCK.lock();
+    let mut table = kernel_root().as_u64();
+    for shift in [39, 30, 21] {
+        table = next_table(Entry::of(table, virt, shift))?;
+    }
+    Ok(PhysicalFrame::containing_address(PhysicalAddress::new(table)))
+}
+
+/// Zeroed frame for a new page table
+///
+/// Once the temporary mapping window is up, tables come from memory
+/// outside the identity map when there is any.
+pub(super) fn allocate_table() -> Result<PhysicalFrame, PagingError> {
+    let zone = if fixmap::is_ready() { Zone::High } else { Zone::Normal };
+    let frame = frame_allocator::allocate_frame_in(zone).map_err(|_| PagingError::OutOfMemory)?;
+    frame_info::set_owner(frame, Owner::PageTable);
+    fixmap::zero(frame);
+    Ok(frame)
+}
+
 /// Initialize paging to map all available physical memory
 pub fn init_full_memory_mapping(memory_map: &MemoryMap) -> Result<usize, PagingError> {
     // Detect how much memory the bootloader actually mapped by checking page tables
@@ -34,6 +152,16 @@ pub fn init_full_memory_mapping(memory_map: &MemoryMap) -> Result<usize, PagingE
     
     // Store the detected mapping
     *MAPPED_MEMORY.lock() = initial_mapped;
+    KERNEL_ROOT.store(
+        x86_64::registers::control::Cr3::read().0.start_address().as_u64(),
+        core::sync::atomic::Ordering::Relaxed,
+    );
+
+    // Kernel writes must fault on read-only pages for copy-on-write
+    unsafe {
+        use x86_64::registers::control::{Cr0, Cr0Flags};
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT));
+    }
     
     // Calculate how much more we need to map
     let target_mapped = total_usable.min(4 * 1024 * 1024 * 1024); // TODO: Dynamically adjust
@@ -46,21 +174,72 @@ pub fn init_full_memory_mapping(memory_map: &MemoryMap) -> Result<usize, PagingE
     }
 }
 
+/// Move the kernel off the loader's page tables onto its own
+///
+/// The loader's tables may sit in memory the frame allocator hands out, so
+/// the identity map is rebuilt with the same 2MB pages in allocated frames
+/// and CR3 switched over. PML4 entries past the identity map are carried
+/// across. Returns the loader's PML4.
+///
+/// Large pages with no RAM in `memory_map` are MMIO or nothing, and are
+/// mapped UC- rather than write-back, so a write-combining MTRR over a
+/// framebuffer still applies.
+///
+/// Must run once the direct map end is set and before anything else maps
+/// memory.
+pub fn rebuild_kernel_tables(memory_map: &MemoryMap) -> Result<PhysicalAddress, PagingError> {
+    use core::sync::atomic::Ordering;
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PhysFrame;
+    const LARGE_PAGE_BYTES: u64 = 2 * 1024 * 1024;
+
+    let _guard = MAP_LOCK.lock();
+    let (loader_root, cr3_flags) = Cr3::read();
+    let loader_root = loader_root.start_address().as_u64();
+    let pml4 = allocate_table()?.start_address().as_u64();
+    let pdpt = allocate_table()?.start_address().as_u64();
+
+    // One PDPT covers the first 512GB, far more than the loader maps
+    let pages = (get_mapped_memory() as u64 / LARGE_PAGE_BYTES).min(512 * 512);
+    for page in 0..pages {
+        let base = page * LARGE_PAGE_BYTES;
+        let pd = next_table(Entry::of(pdpt, base, 30))?;
+        let caching = if memory_map.overlaps_ram(base..base + LARGE_PAGE_BYTES) {
+            memtype::large_page_flags(CacheMode::WriteBack)
+        } else {
+            memtype::large_page_flags(CacheMode::UncachedMinus)
+        };
+        Entry::of(pd, base, 21).write(base | PAGE_PRESENT | PAGE_WRITABLE | PAGE_SIZE | caching);
+    }
+    Entry::at(pml4, 0).write(pdpt | PAGE_PRESENT | PAGE_WRITABLE);
+    for slot in 1..512 {
+        Entry::at(pml4, slot).write(Entry::at(loader_root, slot).read());
+    }
+
+    unsafe { Cr3::write(PhysFrame::containing_address(x86_64::PhysAddr::new(pml4)), cr3_flags) };
+    KERNEL_ROOT.store(pml4, Ordering::Relaxed);
+    Ok(PhysicalAddress::new(loader_root))
+}
+
 /// Detect how much memory is currently mapped by examining page tables
+///
+/// Walks the tables the bootloader left in CR3, wherever it put them.
 fn detect_mapped_memory() -> usize {
+    use x86_64::registers::control::Cr3;
+
     unsafe {
-        let pml4_ptr = PML4_ADDRESS as *const u64;
-        let pdpt_ptr = PDPT_ADDRESS as *const u64;
+        let pml4_ptr = Cr3::read().0.start_address().as_u64() as *const u64;
         
         // Check if PML4[0] is present
-        if (*pml4_ptr & 1) == 0 {
+        if (*pml4_ptr & PAGE_PRESENT) == 0 {
             return 0;
         }
+        let pdpt_ptr = (*pml4_ptr & ADDRESS_MASK) as *const u64;
         
         // Count how many PDPT entries are present
         let mut pd_count = 0;
         for i in 0..512 {
-            if (*pdpt_ptr.add(i) & 1) != 0 {
+            if (*pdpt_ptr.add(i) & PAGE_PRESENT) != 0 {
                 pd_count = i + 1;
             } else {
                 break; // Stop at first non-present entry
@@ -74,7 +253,13 @@ fn detect_mapped_memory() -> usize {
         // Count entries in each PD
         let mut total_pages = 0;
         for pd_idx in 0..pd_count {
-            let pd_ptr = (PD_BASE_ADDRESS + pd_idx * 0x1000) as *const u64;
+            let pdpte = *pdpt_ptr.add(pd_idx);
+            if pdpte & PAGE_SIZE != 0 {
+                // 1GB page
+                total_pages += 512;
+                continue;
+            }
+            let pd_ptr = (pdpte & ADDRESS_MASK) as *const u64;
             for entry_idx in 0..512 {
                 if (*pd_ptr.add(entry_idx) & 1) != 0 {
                     total_pages += 1;
@@ -93,3 +278,562 @@ fn detect_mapped_memory() -> usize {
 pub fn get_mapped_memory() -> usize {
     *MAPPED_MEMORY.lock()
 }
+
+/// Names of the paging levels, from the top
+const LEVEL_NAMES: [&str; 4] = ["PML4", "PDPT", "PD", "PT"];
+
+/// Entry flags as the short names the manuals use
+struct EntryFlags(u64);
+
+impl fmt::Display for EntryFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [(u64, &str); 11] = [
+            (PAGE_PRESENT, "P"),
+            (PAGE_WRITABLE, "W"),
+            (PAGE_USER, "U"),
+            (PAGE_WRITE_THROUGH, "PWT"),
+            (PAGE_CACHE_DISABLE, "PCD"),
+            (PAGE_ACCESSED, "A"),
+            (PAGE_DIRTY, "D"),
+            (PAGE_SIZE, "PS"),
+            (PAGE_GLOBAL, "G"),
+            (PAGE_COW, "COW"),
+            (PAGE_NO_EXECUTE, "NX"),
+        ];
+        let mut first = true;
+        for (bit, name) in NAMES {
+            if self.0 & bit != 0 {
+                if !first {
+                    f.write_char(' ')?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One entry read during a page table walk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkStep {
+    /// Physical address of the table holding the entry
+    pub table: u64,
+    pub index: usize,
+    pub entry: u64,
+}
+
+/// The entries a virtual address goes through in the live page tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Walk {
+    pub virt: u64,
+    /// Entries read, from the PML4 down, ending at the leaf or at the
+    /// first one not present
+    steps: [WalkStep; 4],
+    depth: usize,
+}
+
+impl Walk {
+    /// Walk the tables in CR3 for `virt`, `None` if it is not canonical
+    ///
+    /// Takes no locks, so it is safe from exception handlers, and sees
+    /// whatever address space is loaded rather than only the kernel's.
+    pub fn new(virt: u64) -> Option<Self> {
+        use x86_64::registers::control::Cr3;
+
+        x86_64::VirtAddr::try_new(virt).ok()?;
+
+        let mut walk = Walk { virt, steps: [WalkStep { table: 0, index: 0, entry: 0 }; 4], depth: 0 };
+        let mut table = Cr3::read().0.start_address().as_u64();
+        for shift in [39, 30, 21, 12] {
+            let slot = Entry::of(table, virt, shift);
+            let entry = slot.read();
+            walk.steps[walk.depth] = WalkStep { table, index: slot.index, entry };
+            walk.depth += 1;
+            // The PML4 has no large pages
+            let large = shift != 39 && shift != 12 && entry & PAGE_SIZE != 0;
+            if entry & PAGE_PRESENT == 0 || large {
+                break;
+            }
+            table = entry & ADDRESS_MASK;
+        }
+        Some(walk)
+    }
+
+    /// Entries read, from the PML4 down
+    pub fn steps(&self) -> &[WalkStep] {
+        &self.steps[..self.depth]
+    }
+
+    /// The entry mapping the address, `None` if it is not mapped
+    fn leaf(&self) -> Option<u64> {
+        self.steps().last().map(|step| step.entry).filter(|entry| entry & PAGE_PRESENT != 0)
+    }
+
+    /// Size of the page mapping the address, `None` if it is not mapped
+    pub fn page_size(&self) -> Option<u64> {
+        self.leaf()?;
+        Some(1 << (12 + 9 * (4 - self.depth)))
+    }
+
+    /// Physical address the virtual one translates to
+    pub fn physical(&self) -> Option<PhysicalAddress> {
+        let size = self.page_size()?;
+        let mask = if size == PhysicalFrame::SIZE { ADDRESS_MASK } else { ADDRESS_MASK & !(size - 1) };
+        Some(PhysicalAddress::new((self.leaf()? & mask) | (self.virt & (size - 1))))
+    }
+
+    /// Whether every level allows writes, or the page is copy-on-write and
+    /// the fault handler gives the writer its own copy
+    pub fn writable(&self) -> bool {
+        let Some(leaf) = self.leaf() else {
+            return false;
+        };
+        let upper = self.steps()[..self.depth - 1].iter().all(|step| step.entry & PAGE_WRITABLE != 0);
+        upper && leaf & (PAGE_WRITABLE | PAGE_COW) != 0
+    }
+
+    /// Print each entry with its flags, then the translation
+    pub fn report(&self, out: &mut dyn Write) -> fmt::Result {
+        for (name, step) in LEVEL_NAMES.iter().zip(self.steps()) {
+            writeln!(out, "{:<4} {:#012x}[{:>3}] = {:#018x}  {}",
+                name, step.table, step.index, step.entry, EntryFlags(step.entry))?;
+        }
+        match (self.physical(), self.page_size()) {
+            (Some(physical), Some(size)) => writeln!(out, "{:#x} -> {:#x} ({} page)",
+                self.virt, physical.as_u64(), cosmos_common::fmt::HumanBytes(size)),
+            _ => writeln!(out, "{:#x} is not mapped", self.virt),
+        }
+    }
+}
+
+/// Physical address `virt` maps to in the current tables, `None` if it is
+/// not mapped
+///
+/// Takes no locks. The identity map makes this `virt` for most kernel
+/// memory; it matters for VMAs, the fixmap and split or unmapped pages.
+pub fn translate(virt: u64) -> Option<PhysicalAddress> {
+    Walk::new(virt)?.physical()
+}
+
+/// Check whether a virtual address is mapped, without taking any locks
+///
+/// Safe to call from exception handlers.
+pub fn is_mapped(addr: u64) -> bool {
+    Walk::new(addr).is_some_and(|walk| walk.leaf().is_some())
+}
+
+/// Check whether a virtual address can be written without a fatal fault,
+/// without taking any locks
+///
+/// A copy-on-write page counts, the fault handler gives the writer its own
+/// copy.
+pub fn is_writable(addr: u64) -> bool {
+    Walk::new(addr).is_some_and(|walk| walk.writable())
+}
+
+/// Unmap one 4KB page of the identity map, e.g. as a stack guard page
+///
+/// A 2MB mapping covering the page is split into 4KB pages first.
+pub fn unmap_page(addr: PhysicalAddress) -> Result<(), PagingError> {
+    unmap_range(addr, PhysicalFrame::SIZE)
+}
+
+/// Unmap the 4KB pages of the identity map covering `addr..addr + size`
+///
+/// The TLB flushes of all pages go out as one shootdown. On error the
+/// pages before the failing one stay unmapped.
+pub fn unmap_range(addr: PhysicalAddress, size: u64) -> Result<(), PagingError> {
+    let start = addr.align_down(PhysicalFrame::SIZE).as_u64();
+    let end = addr.as_u64().checked_add(size).ok_or(PagingError::InvalidAddress)?;
+    let mut batch = tlb::Batch::new();
+    for page in (start..end).step_by(PhysicalFrame::SIZE as usize) {
+        unmap_one(page, &mut batch)?;
+    }
+    Ok(())
+}
+
+fn unmap_one(addr: u64, batch: &mut tlb::Batch) -> Result<(), PagingError> {
+    if addr as usize >= get_mapped_memory() {
+        return Err(PagingError::InvalidAddress);
+    }
+
+    let pte = split_leaf_entry(addr, batch)?;
+    pte.write(pte.read() & !PAGE_PRESENT);
+    batch.add(addr);
+    Ok(())
+}
+
+/// Find the 4KB entry mapping `addr`, splitting a 2MB mapping if needed
+fn split_leaf_entry(addr: u64, batch: &mut tlb::Batch) -> Result<Entry, PagingError> {
+    let pml4e = Entry::of(kernel_root().as_u64(), addr, 39).read();
+    if pml4e & PAGE_PRESENT == 0 {
+        return Err(PagingError::InvalidAddress);
+    }
+
+    // 1GB mappings are not split
+    let pdpte = Entry::of(pml4e & ADDRESS_MASK, addr, 30).read();
+    if pdpte & PAGE_PRESENT == 0 || pdpte & PAGE_SIZE != 0 {
+        return Err(PagingError::InvalidAddress);
+    }
+
+    let pde = Entry::of(pdpte & ADDRESS_MASK, addr, 21);
+    let entry = pde.read();
+    if entry & PAGE_PRESENT == 0 {
+        return Err(PagingError::InvalidAddress);
+    }
+    if entry & PAGE_SIZE != 0 {
+        split_large_page(pde, batch)?;
+    }
+    Ok(Entry::of(pde.address(), addr, 12))
+}
+
+/// Find the present 4KB entry mapping `addr`, without taking locks
+fn leaf_entry(addr: u64) -> Option<Entry> {
+    x86_64::VirtAddr::try_new(addr).ok()?;
+    let mut table = kernel_root().as_u64();
+    for shift in [39, 30, 21] {
+        let entry = Entry::of(table, addr, shift).read();
+        if entry & PAGE_PRESENT == 0 || entry & PAGE_SIZE != 0 {
+            return None;
+        }
+        table = entry & ADDRESS_MASK;
+    }
+    let pte = Entry::of(table, addr, 12);
+    (pte.read() & PAGE_PRESENT != 0).then_some(pte)
+}
+
+/// Write-protect the page at `addr` and share its frame copy-on-write
+///
+/// Adds a reference to the frame for the second mapping the caller sets up
+/// with the same flags, as fork does for each private page. The first
+/// write through either mapping faults into `resolve_cow_fault`.
+pub fn share_cow(addr: u64, batch: &mut tlb::Batch) -> Result<PhysicalFrame, PagingError> {
+    if addr as usize >= get_mapped_memory() {
+        return Err(PagingError::InvalidAddress);
+    }
+
+    let _guard = MAP_LOCK.lock();
+    let pte = split_leaf_entry(addr, batch)?;
+    let entry = pte.read();
+    if entry & PAGE_PRESENT == 0 {
+        return Err(PagingError::InvalidAddress);
+    }
+    let frame = PhysicalFrame::containing_address(PhysicalAddress::new(entry & ADDRESS_MASK));
+    frame_allocator::share_frame(frame).map_err(|_| PagingError::InvalidAddress)?;
+    pte.write((entry & !PAGE_WRITABLE) | PAGE_COW);
+    if let Some(info) = frame_info::get(frame) {
+        info.set_flags(frame_info::COPY_ON_WRITE);
+    }
+    batch.add(addr);
+    Ok(frame)
+}
+
+/// Entry flags for a 4KB page with the given access
+///
+/// No-execute is only set when EFER.NXE makes the bit valid.
+pub(super) fn page_flags(writable: bool, executable: bool) -> u64 {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    let mut flags = PAGE_PRESENT;
+    if writable {
+        flags |= PAGE_WRITABLE;
+    }
+    if !executable && Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        flags |= PAGE_NO_EXECUTE;
+    }
+    flags
+}
+
+/// Map the 4KB page at `virt` to `frame`, outside the identity map
+///
+/// Fails if `virt` is already mapped. Missing page tables are allocated.
+pub fn map_page(virt: u64, frame: PhysicalFrame, writable: bool, executable: bool) -> Result<(), PagingError> {
+    if !virt.is_multiple_of(PhysicalFrame::SIZE) || x86_64::VirtAddr::try_new(virt).is_err() {
+        return Err(PagingError::InvalidAddress);
+    }
+
+    let _guard = MAP_LOCK.lock();
+    let mut table = kernel_root().as_u64();
+    for shift in [39, 30, 21] {
+        table = next_table(Entry::of(table, virt, shift))?;
+    }
+    let pte = Entry::of(table, virt, 12);
+    if pte.read() & PAGE_PRESENT != 0 {
+        return Err(PagingError::InvalidAddress);
+    }
+    // Not-present entries are never cached, so no flush is needed
+    pte.write(frame.start_address().as_u64() | page_flags(writable, executable));
+    Ok(())
+}
+
+/// Remove the 4KB mapping at `virt`, returning the frame it mapped
+///
+/// The caller owns the returned frame reference.
+pub fn unmap_virtual(virt: u64, batch: &mut tlb::Batch) -> Option<PhysicalFrame> {
+    let _guard = MAP_LOCK.lock();
+    let pte = leaf_entry(virt)?;
+    let frame = PhysicalFrame::containing_address(PhysicalAddress::new(pte.address()));
+    pte.write(0);
+    batch.add(virt);
+    Some(frame)
+}
+
+/// Change the access of a present 4KB mapping, returning `false` if unmapped
+///
+/// A shared frame only gets copy-on-write access, so the first write still
+/// separates it from its other owners.
+pub fn protect_page(virt: u64, writable: bool, executable: bool, batch: &mut tlb::Batch) -> bool {
+    let _guard = MAP_LOCK.lock();
+    let Some(pte) = leaf_entry(virt) else {
+        return false;
+    };
+    let old = pte.read();
+    let address = old & ADDRESS_MASK;
+    let frame = PhysicalFrame::containing_address(PhysicalAddress::new(address));
+    let shared = writable && frame_allocator::ref_count(frame) > 1;
+    let mut entry = address | page_flags(writable && !shared, executable);
+    if shared {
+        entry |= PAGE_COW;
+    }
+    // Keep the user, cache and PAT bits
+    pte.write(entry | (old & (PAGE_USER | PAGE_WRITE_THROUGH | PAGE_CACHE_DISABLE | PAGE_SIZE)));
+    batch.add(virt);
+    true
+}
+
+/// Give the writer of a copy-on-write page its own copy
+///
+/// Called from the page fault handler for write protection faults. The
+/// last owner of a frame keeps it and just regains write access. Returns
+/// `false` if `addr` is not a copy-on-write page or no frame is free, in
+/// which case the fault is a real one.
+pub fn resolve_cow_fault(addr: u64) -> bool {
+    // Page tables are mid-update if the fault hit a mapping change
+    let Some(_guard) = MAP_LOCK.try_lock() else {
+        return false;
+    };
+    let page = addr & !(PhysicalFrame::SIZE - 1);
+
+    let Some(pte) = leaf_entry(page) else {
+        return false;
+    };
+    let entry = pte.read();
+    if entry & PAGE_COW == 0 {
+        return false;
+    }
+
+    let frame = PhysicalFrame::containing_address(PhysicalAddress::new(entry & ADDRESS_MASK));
+    if frame_allocator::ref_count(frame) == 1 {
+        pte.write((entry & !PAGE_COW) | PAGE_WRITABLE);
+        if let Some(info) = frame_info::get(frame) {
+            info.clear_flags(frame_info::COPY_ON_WRITE);
+        }
+    } else {
+        let Ok(copy) = frame_allocator::allocate_frame() else {
+            return false;
+        };
+        fixmap::with_frame(copy, |target| unsafe {
+            core::ptr::copy_nonoverlapping(page as *const u8, target, PhysicalFrame::SIZE as usize);
+        });
+        pte.write(copy.start_address().as_u64() | (entry & !(ADDRESS_MASK | PAGE_COW)) | PAGE_WRITABLE);
+        let _ = frame_allocator::release_frame(frame);
+    }
+    tlb::flush_page(page);
+    true
+}
+
+/// Identity map a device range with memory type `mode`
+///
+/// Unmapped pages get new 4KB entries. Pages the identity map already
+/// covers keep their mapping with the caching bits replaced, splitting a
+/// 2MB page the range does not cover whole. The MTRRs still apply on top,
+/// see `memtype::mtrr_type`. Only for device memory: RAM mapped with two
+/// types at once is undefined behavior on x86.
+pub fn map_device(addr: PhysicalAddress, size: u64, mode: CacheMode) -> Result<(), PagingError> {
+    const LARGE_PAGE_BYTES: u64 = 2 * 1024 * 1024;
+
+    let start = addr.align_down(PhysicalFrame::SIZE).as_u64();
+    let end = addr.as_u64().checked_add(size).ok_or(PagingError::InvalidAddress)?;
+    if x86_64::VirtAddr::try_new(end).is_err() {
+        return Err(PagingError::InvalidAddress);
+    }
+
+    let _guard = MAP_LOCK.lock();
+    let mut batch = tlb::Batch::new();
+    let mut page = start;
+    while page < end {
+        let mut table = kernel_root().as_u64();
+        for shift in [39, 30] {
+            table = next_table(Entry::of(table, page, shift))?;
+        }
+        let pde = Entry::of(table, page, 21);
+        let entry = pde.read();
+        if entry & PAGE_PRESENT != 0 && entry & PAGE_SIZE != 0 {
+            if page.is_multiple_of(LARGE_PAGE_BYTES) && end - page >= LARGE_PAGE_BYTES {
+                let cache_bits = PAGE_WRITE_THROUGH | PAGE_CACHE_DISABLE | LARGE_PAGE_PAT;
+                pde.write(entry & !cache_bits | memtype::large_page_flags(mode));
+                batch.add(page);
+                page += LARGE_PAGE_BYTES;
+                continue;
+            }
+            split_large_page(pde, &mut batch)?;
+        }
+        let pte = Entry::of(next_table(pde)?, page, 12);
+        let old = pte.read();
+        if old & PAGE_PRESENT != 0 {
+            pte.write(old & !(PAGE_WRITE_THROUGH | PAGE_CACHE_DISABLE | PAGE_PAT) | memtype::page_flags(mode));
+            batch.add(page);
+        } else {
+            pte.write(page | PAGE_PRESENT | PAGE_WRITABLE | memtype::page_flags(mode));
+        }
+        page += PhysicalFrame::SIZE;
+    }
+    Ok(())
+}
+
+/// Follow a table entry, allocating an empty table if it is not present,
+/// and return the table's physical address
+fn next_table(entry: Entry) -> Result<u64, PagingError> {
+    let value = entry.read();
+    if value & PAGE_PRESENT != 0 {
+        if value & PAGE_SIZE != 0 {
+            // Only reached for unmapped pages, which a large page cannot cover
+            return Err(PagingError::Corruption);
+        }
+        return Ok(value & ADDRESS_MASK);
+    }
+
+    let table_addr = allocate_table()?.start_address().as_u64();
+    entry.write(table_addr | PAGE_PRESENT | PAGE_WRITABLE);
+    Ok(table_addr)
+}
+
+/// Replace a 2MB mapping with a page table of equivalent 4KB mappings
+fn split_large_page(pde: Entry, batch: &mut tlb::Batch) -> Result<(), PagingError> {
+    let entry = pde.read();
+    let table_frame = allocate_table()?;
+
+    // PAT moves from bit 12 in a 2MB entry to bit 7 in a 4KB entry
+    let base = entry & LARGE_ADDRESS_MASK;
+    let mut flags = entry & !(ADDRESS_MASK | PAGE_SIZE);
+    if entry & LARGE_PAGE_PAT != 0 {
+        flags |= PAGE_SIZE;
+    }
+
+    fixmap::with_frame(table_frame, |page| {
+        let table = page as *mut u64;
+        for i in 0..512 {
+            unsafe { table.add(i).write_volatile((base + i as u64 * PhysicalFrame::SIZE) | flags) };
+        }
+    });
+
+    pde.write(table_frame.start_address().as_u64() | PAGE_PRESENT | PAGE_WRITABLE | (entry & PAGE_USER));
+    batch.add_all();
+    Ok(())
+}
+
+/// Page table walk over known-mapped and known-unmapped addresses
+fn selftest() -> Result<(), &'static str> {
+    let kernel = selftest as usize as u64;
+    let heap = super::heap::heap_start() as u64;
+    if !is_mapped(kernel) || !is_mapped(heap) {
+        return Err("kernel or heap not mapped");
+    }
+    let end = get_mapped_memory() as u64;
+    if end == 0 || !is_mapped(end - 1) {
+        return Err("end of identity map not mapped");
+    }
+    // Non-canonical and higher-half addresses
+    if is_mapped(0x0000_8000_0000_0000) || is_mapped(0xFFFF_8000_0000_0000) {
+        return Err("unmapped address reported mapped");
+    }
+    Ok(())
+}
+
+crate::selftest!("paging", selftest);
+
+/// A write to a shared page must land in a private copy
+fn cow_selftest() -> Result<(), &'static str> {
+    const BEFORE: u64 = 0xC0FF_EE00_C0FF_EE00;
+    const AFTER: u64 = 0x5EED_5EED_5EED_5EED;
+
+    let frame = frame_allocator::allocate_frame().map_err(|_| "cannot allocate frame")?;
+    let page = frame.start_address().as_u64();
+    if frame.end_address().as_u64() as usize > get_mapped_memory() {
+        return Err("test frame outside the identity map");
+    }
+    unsafe { core::ptr::write_volatile(page as *mut u64, BEFORE) };
+
+    let mut batch = tlb::Batch::new();
+    share_cow(page, &mut batch).map_err(|_| "share_cow failed")?;
+    batch.flush();
+    if frame_allocator::ref_count(frame) != 2 {
+        return Err("shared frame not counted twice");
+    }
+
+    unsafe { core::ptr::write_volatile(page as *mut u64, AFTER) };
+    let pte = leaf_entry(page).ok_or("page vanished")?;
+    let entry = pte.read();
+    let copied = unsafe { core::ptr::read_volatile(page as *const u64) } == AFTER;
+    // Put the identity mapping back
+    pte.write(page | (entry & !(ADDRESS_MASK | PAGE_COW)) | PAGE_WRITABLE);
+    tlb::flush_page(page);
+    let copy = PhysicalFrame::containing_address(PhysicalAddress::new(entry & ADDRESS_MASK));
+    let _ = frame_allocator::release_frame(copy);
+    let original = unsafe { core::ptr::read_volatile(page as *const u64) };
+    let count = frame_allocator::ref_count(frame);
+    // The fault dropped the writer's reference, this drops the last
+    let freed = frame_allocator::release_frame(frame);
+
+    if copy == frame || entry & PAGE_COW != 0 || !copied {
+        return Err("write did not get a private copy");
+    }
+    if count != 1 || freed != Ok(true) {
+        return Err("fault did not drop the writer's reference");
+    }
+    if original != BEFORE {
+        return Err("shared frame modified");
+    }
+    Ok(())
+}
+
+crate::selftest!("cow", cow_selftest);
+
+/// Walks find identity-mapped memory in place and a VMA page at its frame
+fn translate_selftest() -> Result<(), &'static str> {
+    use super::vma::{self, Protection};
+
+    let boxed = alloc::boxed::Box::new(0u64);
+    let heap = &*boxed as *const u64 as u64;
+    if translate(heap).map(PhysicalAddress::as_u64) != Some(heap) {
+        return Err("heap not identity mapped");
+    }
+    if translate(0x8000_0000_0000_0000).is_some() {
+        return Err("non-canonical address translated");
+    }
+
+    let page = vma::map_anonymous(PhysicalFrame::SIZE, Protection::READ | Protection::WRITE).map_err(|_| "mmap failed")?;
+    let unbacked = translate(page).is_none();
+    unsafe { core::ptr::write_volatile((page + 8) as *mut u64, 0x5678) };
+    let walk = Walk::new(page + 8).ok_or("walk refused a canonical address")?;
+    let physical = walk.physical();
+    let through_frame = physical.map(|physical| {
+        let frame = PhysicalFrame::containing_address(physical);
+        fixmap::with_frame(frame, |bytes| unsafe { (bytes.add(8) as *const u64).read_volatile() })
+    });
+    let _ = vma::unmap(page, PhysicalFrame::SIZE);
+
+    if !unbacked {
+        return Err("page backed before first touch");
+    }
+    if walk.steps().len() != 4 || walk.page_size() != Some(PhysicalFrame::SIZE) || !walk.writable() {
+        return Err("VMA page walk wrong");
+    }
+    if through_frame != Some(0x5678) {
+        return Err("translation points at the wrong frame");
+    }
+    Ok(())
+}
+
+crate::selftest!("translate", translate_selftest);
@@ -0,0 +1,165 @@
+//! TLB Shootdown
+//!
+//! Page table changes that remove or restrict a mapping must be flushed
+//! from every CPU's TLB, not just the local one. Changes are collected in
+//! a `Batch` and sent as one IPI; the initiator waits until every other
+//! online CPU has flushed. Batches larger than `MAX_BATCH` pages flush the
+//! whole TLB instead, which is cheaper than many `invlpg`s.
+//!
+//! With one CPU online nothing is sent and flushes stay local.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+use crate::arch::x86_64::{apic, cpu, interrupts};
+
+/// Pages flushed individually per shootdown
+pub const MAX_BATCH: usize = 32;
+
+/// Vector of shootdown IPIs
+pub const SHOOTDOWN_VECTOR: u8 = 0xFD;
+
+/// `REQUEST_COUNT` value asking for a full flush
+const FLUSH_ALL: usize = usize::MAX;
+
+/// Serializes initiators, the request below is shared
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Pages of the current request
+static REQUEST_PAGES: [AtomicU64; MAX_BATCH] = [const { AtomicU64::new(0) }; MAX_BATCH];
+static REQUEST_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// CPUs that have not yet acknowledged the current request
+static PENDING_ACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Shootdowns sent and pages they covered, full flushes count as 0 pages
+static SHOOTDOWNS: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Register the shootdown IPI handler
+pub fn init() -> Result<(), interrupts::InterruptError> {
+    interrupts::register_handler(SHOOTDOWN_VECTOR, handle_shootdown)
+}
+
+/// Pages whose mappings changed, flushed everywhere on `flush` or drop
+pub struct Batch {
+    pages: [u64; MAX_BATCH],
+    count: usize,
+    flush_all: bool,
+}
+
+impl Batch {
+    pub const fn new() -> Self {
+        Batch { pages: [0; MAX_BATCH], count: 0, flush_all: false }
+    }
+
+    /// Queue the page containing `addr`
+    pub fn add(&mut self, addr: u64) {
+        if self.count == MAX_BATCH {
+            self.flush_all = true;
+        } else {
+            self.pages[self.count] = addr & !0xFFF;
+            self.count += 1;
+        }
+    }
+
+    /// Queue a flush of the whole TLB, for example after a table was replaced
+    pub fn add_all(&mut self) {
+        self.flush_all = true;
+    }
+
+    /// Flush the queued pages on every online CPU
+    pub fn flush(&mut self) {
+        if self.count == 0 && !self.flush_all {
+            return;
+        }
+        let pages = if self.flush_all { None } else { Some(&self.pages[..self.count]) };
+        shootdown(pages);
+        self.count = 0;
+        self.flush_all = false;
+    }
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Flush one page on every online CPU
+pub fn flush_page(addr: u64) {
+    let mut batch = Batch::new();
+    batch.add(addr);
+}
+
+/// Flush the whole TLB on every online CPU
+pub fn flush_all() {
+    let mut batch = Batch::new();
+    batch.add_all();
+}
+
+fn flush_local(pages: Option<&[u64]>) {
+    match pages {
+        Some(pages) => {
+            for &page in pages {
+                x86_64::instructions::tlb::flush(VirtAddr::new(page));
+            }
+        }
+        None => x86_64::instructions::tlb::flush_all(),
+    }
+}
+
+/// Flush `pages`, or everything for `None`, here and on all other CPUs
+///
+/// Other CPUs acknowledge from their IPI handler, so with more than one
+/// CPU online this must run with interrupts enabled.
+fn shootdown(pages: Option<&[u64]>) {
+    flush_local(pages);
+    let others = cpu::online_count().saturating_sub(1);
+    if others == 0 || !apic::is_enabled() {
+        return;
+    }
+
+    let _guard = SHOOTDOWN_LOCK.lock();
+    match pages {
+        Some(pages) => {
+            for (slot, &page) in REQUEST_PAGES.iter().zip(pages) {
+                slot.store(page, Ordering::Relaxed);
+            }
+            REQUEST_COUNT.store(pages.len(), Ordering::Relaxed);
+        }
+        None => REQUEST_COUNT.store(FLUSH_ALL, Ordering::Relaxed),
+    }
+    PENDING_ACKS.store(others, Ordering::Release);
+    apic::send_ipi(apic::Destination::AllExcludingSelf, SHOOTDOWN_VECTOR);
+    while PENDING_ACKS.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+
+    SHOOTDOWNS.fetch_add(1, Ordering::Relaxed);
+    SHOOTDOWN_PAGES.fetch_add(pages.map_or(0, |pages| pages.len() as u64), Ordering::Relaxed);
+}
+
+fn handle_shootdown(_frame: &InterruptStackFrame) {
+    let count = REQUEST_COUNT.load(Ordering::Acquire);
+    if count == FLUSH_ALL {
+        flush_local(None);
+    } else {
+        for page in &REQUEST_PAGES[..count.min(MAX_BATCH)] {
+            x86_64::instructions::tlb::flush(VirtAddr::new(page.load(Ordering::Relaxed)));
+        }
+    }
+    PENDING_ACKS.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Shootdowns sent and the pages they covered
+pub fn stats() -> (u64, u64) {
+    (SHOOTDOWNS.load(Ordering::Relaxed), SHOOTDOWN_PAGES.load(Ordering::Relaxed))
+}
@@ -0,0 +1,400 @@
+//! Virtual Memory Areas
+//!
+//! An `AddressSpace` tracks which virtual ranges are mapped and with what
+//! access, independent of the page tables. Anonymous areas are demand-zero:
+//! `mmap` only records the area and the page fault handler maps a zeroed
+//! frame on first touch. The kernel's own space covers a fixed region
+//! above the identity map; process address spaces, the `mmap`, `munmap`
+//! and `mprotect` system calls and file-backed areas build on the same
+//! type once those exist.
+
+use super::{frame_allocator, paging, tlb, PhysicalFrame};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Kernel mapping region, PML4 slot 128 and up, clear of the identity map
+pub const KERNEL_VMA_START: u64 = 0x0000_4000_0000_0000;
+pub const KERNEL_VMA_END: u64 = KERNEL_VMA_START + (1 << 40);
+
+const PAGE_SIZE: u64 = PhysicalFrame::SIZE;
+
+/// Access allowed to an area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection(u8);
+
+impl Protection {
+    pub const NONE: Protection = Protection(0);
+    pub const READ: Protection = Protection(1 << 0);
+    pub const WRITE: Protection = Protection(1 << 1);
+    pub const EXEC: Protection = Protection(1 << 2);
+
+    /// Check if every access in `other` is allowed
+    pub const fn contains(self, other: Protection) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Protection {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Protection(self.0 | rhs.0)
+    }
+}
+
+impl core::fmt::Display for Protection {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let flag = |p, c| if self.contains(p) { c } else { '-' };
+        write!(f, "{}{}{}", flag(Protection::READ, 'r'), flag(Protection::WRITE, 'w'), flag(Protection::EXEC, 'x'))
+    }
+}
+
+/// What fills the pages of an area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zero-filled on first access
+    Anonymous,
+}
+
+/// One mapped range, page aligned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub protection: Protection,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+}
+
+/// Errors that can occur while changing an address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// Address not page aligned or outside the address space
+    InvalidAddress,
+    /// Zero or overflowing length
+    InvalidLength,
+    /// Requested range overlaps an existing area
+    Overlap,
+    /// No free range large enough
+    NoSpace,
+    /// Part of the range is not mapped
+    NotMapped,
+}
+
+impl core::fmt::Display for VmaError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            VmaError::InvalidAddress => write!(f, "Invalid mapping address"),
+            VmaError::InvalidLength => write!(f, "Invalid mapping length"),
+            VmaError::Overlap => write!(f, "Range overlaps an existing mapping"),
+            VmaError::NoSpace => write!(f, "No free virtual range"),
+            VmaError::NotMapped => write!(f, "Range not mapped"),
+        }
+    }
+}
+
+/// Areas of one virtual address space, keyed by start address
+pub struct AddressSpace {
+    areas: BTreeMap<u64, Vma>,
+    start: u64,
+    end: u64,
+}
+
+impl AddressSpace {
+    /// Create an empty space managing `start..end`
+    pub const fn new(start: u64, end: u64) -> Self {
+        AddressSpace { areas: BTreeMap::new(), start, end }
+    }
+
+    /// Area containing `addr`
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.areas.range(..=addr).next_back().map(|(_, vma)| vma).filter(|vma| vma.contains(addr))
+    }
+
+    /// Iterate over all areas in address order
+    pub fn areas(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// Page-aligned end of `addr..addr + len`
+    fn range_end(addr: u64, len: u64) -> Result<u64, VmaError> {
+        if len == 0 {
+            return Err(VmaError::InvalidLength);
+        }
+        addr.checked_add(len)
+            .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+            .ok_or(VmaError::InvalidLength)
+    }
+
+    fn check_range(&self, addr: u64, end: u64) -> Result<(), VmaError> {
+        if !addr.is_multiple_of(PAGE_SIZE) || addr < self.start || end > self.end {
+            return Err(VmaError::InvalidAddress);
+        }
+        Ok(())
+    }
+
+    /// First free range of `len` bytes
+    fn find_free(&self, len: u64) -> Option<u64> {
+        let mut candidate = self.start;
+        for vma in self.areas.values() {
+            if vma.start - candidate >= len {
+                return Some(candidate);
+            }
+            candidate = vma.end;
+        }
+        (self.end - candidate >= len).then_some(candidate)
+    }
+
+    /// Map an anonymous area, at `addr` if given or wherever it fits
+    ///
+    /// Pages are only backed on first access. Returns the start address.
+    pub fn mmap(&mut self, addr: Option<u64>, len: u64, protection: Protection) -> Result<u64, VmaError> {
+        let start = match addr {
+            Some(addr) => {
+                let end = Self::range_end(addr, len)?;
+                self.check_range(addr, end)?;
+                if self.areas.range(..end).next_back().is_some_and(|(_, vma)| vma.end > addr) {
+                    return Err(VmaError::Overlap);
+                }
+                addr
+            }
+            None => {
+                let len = Self::range_end(0, len)?;
+                self.find_free(len).ok_or(VmaError::NoSpace)?
+            }
+        };
+        let end = Self::range_end(start, len)?;
+        self.areas.insert(start, Vma { start, end, protection, backing: Backing::Anonymous });
+        Ok(start)
+    }
+
+    /// Unmap every area page in `addr..addr + len`, freeing backed frames
+    ///
+    /// Unmapped holes inside the range are skipped, as with `munmap`.
+    pub fn munmap(&mut self, addr: u64, len: u64) -> Result<(), VmaError> {
+        let end = Self::range_end(addr, len)?;
+        self.check_range(addr, end)?;
+        self.split(addr);
+        self.split(end);
+
+        let removed: Vec<Vma> = self.areas.range(addr..end).map(|(_, vma)| *vma).collect();
+        let mut batch = tlb::Batch::new();
+        let mut frames = Vec::new();
+        for vma in &removed {
+            self.areas.remove(&vma.start);
+            for page in (vma.start..vma.end).step_by(PAGE_SIZE as usize) {
+                if let Some(frame) = paging::unmap_virtual(page, &mut batch) {
+                    frames.push(frame);
+                }
+            }
+        }
+        // Other CPUs may still use the frames until the flush
+        batch.flush();
+        for frame in frames {
+            let _ = frame_allocator::release_frame(frame);
+        }
+        Ok(())
+    }
+
+    /// Resize the area starting at `addr` in place, as `mremap` without
+    /// moving
+    ///
+    /// Shrinking unmaps the tail and returns its frames to the allocator;
+    /// growing only records the larger area, backed on first touch.
+    pub fn mremap(&mut self, addr: u64, old_len: u64, new_len: u64) -> Result<(), VmaError> {
+        let old_end = Self::range_end(addr, old_len)?;
+        let new_end = Self::range_end(addr, new_len)?;
+        self.check_range(addr, new_end)?;
+        let vma = *self.areas.get(&addr).ok_or(VmaError::NotMapped)?;
+        if vma.end != old_end {
+            return Err(VmaError::InvalidLength);
+        }
+
+        if new_end < old_end {
+            return self.munmap(new_end, old_end - new_end);
+        }
+        if self.areas.range(old_end..new_end).next().is_some() {
+            return Err(VmaError::Overlap);
+        }
+        self.areas.insert(addr, Vma { end: new_end, ..vma });
+        Ok(())
+    }
+
+    /// Change the access of every page in `addr..addr + len`
+    ///
+    /// The whole range must be mapped.
+    pub fn mprotect(&mut self, addr: u64, len: u64, protection: Protection) -> Result<(), VmaError> {
+        let end = Self::range_end(addr, len)?;
+        self.check_range(addr, end)?;
+        let mut covered = addr;
+        for vma in self.areas.range(..end).map(|(_, vma)| vma).skip_while(|vma| vma.end <= addr) {
+            if vma.start > covered {
+                break;
+            }
+            covered = vma.end;
+        }
+        if covered < end {
+            return Err(VmaError::NotMapped);
+        }
+
+        self.split(addr);
+        self.split(end);
+        let mut batch = tlb::Batch::new();
+        for vma in self.areas.range_mut(addr..end).map(|(_, vma)| vma) {
+            vma.protection = protection;
+            for page in (vma.start..vma.end).step_by(PAGE_SIZE as usize) {
+                paging::protect_page(page,
+                    protection.contains(Protection::WRITE),
+                    protection.contains(Protection::EXEC),
+                    &mut batch);
+            }
+        }
+        Ok(())
+    }
+
+    /// Split the area containing `at` so an area starts there
+    fn split(&mut self, at: u64) {
+        let Some(vma) = self.find(at).copied() else {
+            return;
+        };
+        if vma.start == at {
+            return;
+        }
+        self.areas.insert(vma.start, Vma { end: at, ..vma });
+        self.areas.insert(at, Vma { start: at, ..vma });
+    }
+
+    /// Back the page containing `addr` after a not-present fault
+    ///
+    /// Returns `false` if no area allows `access` there, so the fault is a
+    /// real one.
+    pub fn handle_fault(&self, addr: u64, access: Protection) -> bool {
+        let Some(vma) = self.find(addr) else {
+            return false;
+        };
+        if !vma.protection.contains(access) || vma.protection == Protection::NONE {
+            return false;
+        }
+
+        let Ok(frame) = frame_allocator::allocate_frame() else {
+            return false;
+        };
+        let frame_addr = frame.start_address().as_u64();
+        if frame.end_address().as_u64() as usize > paging::get_mapped_memory() {
+            // The frame cannot be zeroed through the identity map, so leak it
+            return false;
+        }
+        unsafe {
+            core::ptr::write_bytes(frame_addr as *mut u8, 0, PAGE_SIZE as usize);
+        }
+        super::frame_info::set_owner(frame, super::frame_info::Owner::Anonymous);
+        let page = addr & !(PAGE_SIZE - 1);
+        let mapped = paging::map_page(page,
+            frame,
+            vma.protection.contains(Protection::WRITE),
+            vma.protection.contains(Protection::EXEC));
+        if mapped.is_err() {
+            let _ = frame_allocator::release_frame(frame);
+            return false;
+        }
+        true
+    }
+}
+
+/// Address space of kernel mappings outside the identity map
+static KERNEL_SPACE: Mutex<AddressSpace> = Mutex::new(AddressSpace::new(KERNEL_VMA_START, KERNEL_VMA_END));
+
+/// Map an anonymous demand-zero area in the kernel space
+pub fn map_anonymous(len: u64, protection: Protection) -> Result<u64, VmaError> {
+    KERNEL_SPACE.lock().mmap(None, len, protection)
+}
+
+/// Area of the kernel space containing `addr`
+pub fn find(addr: u64) -> Option<Vma> {
+    KERNEL_SPACE.lock().find(addr).copied()
+}
+
+/// Unmap a range of the kernel space
+pub fn unmap(addr: u64, len: u64) -> Result<(), VmaError> {
+    KERNEL_SPACE.lock().munmap(addr, len)
+}
+
+/// Resize an area of the kernel space in place
+pub fn remap(addr: u64, old_len: u64, new_len: u64) -> Result<(), VmaError> {
+    KERNEL_SPACE.lock().mremap(addr, old_len, new_len)
+}
+
+/// Change the access of a range of the kernel space
+pub fn protect(addr: u64, len: u64, protection: Protection) -> Result<(), VmaError> {
+    KERNEL_SPACE.lock().mprotect(addr, len, protection)
+}
+
+/// Resolve a not-present fault in the kernel space, from the fault handler
+pub fn handle_fault(addr: u64, access: Protection) -> bool {
+    if !(KERNEL_VMA_START..KERNEL_VMA_END).contains(&addr) {
+        return false;
+    }
+    // Faulting with the lock held means the area is being changed
+    match KERNEL_SPACE.try_lock() {
+        Some(space) => space.handle_fault(addr, access),
+        None => false,
+    }
+}
+
+/// Demand-zero pages appear on touch and disappear on unmap
+fn selftest() -> Result<(), &'static str> {
+    let len = 3 * PAGE_SIZE;
+    let start = map_anonymous(len, Protection::READ | Protection::WRITE).map_err(|_| "mmap failed")?;
+    if paging::is_mapped(start) {
+        unmap(start, len).map_err(|_| "munmap failed")?;
+        return Err("page backed before first touch");
+    }
+
+    let last = start + 2 * PAGE_SIZE;
+    let zero = unsafe { core::ptr::read_volatile(last as *const u64) };
+    unsafe { core::ptr::write_volatile(start as *mut u64, 0x1234) };
+    let value = unsafe { core::ptr::read_volatile(start as *const u64) };
+    let untouched = !paging::is_mapped(start + PAGE_SIZE);
+
+    let protected = protect(start + PAGE_SIZE, PAGE_SIZE, Protection::READ).is_ok()
+        && KERNEL_SPACE.lock().areas().filter(|vma| vma.start >= start && vma.end <= start + len).count() == 3;
+    unmap(start, len).map_err(|_| "munmap failed")?;
+
+    if zero != 0 || value != 0x1234 {
+        return Err("demand-zero page has wrong contents");
+    }
+    if !untouched {
+        return Err("untouched page was backed");
+    }
+    if !protected {
+        return Err("mprotect did not split the area");
+    }
+
+    if paging::is_mapped(start) || paging::is_mapped(last) {
+        return Err("page still mapped after munmap");
+    }
+
+    // Shrinking drops the touched tail page, growing leaves new pages unbacked
+    let start = map_anonymous(2 * PAGE_SIZE, Protection::READ | Protection::WRITE).map_err(|_| "mmap failed")?;
+    let tail = start + PAGE_SIZE;
+    unsafe {
+        core::ptr::write_volatile(start as *mut u64, 1);
+        core::ptr::write_volatile(tail as *mut u64, 2);
+    }
+    let shrunk = remap(start, 2 * PAGE_SIZE, PAGE_SIZE).is_ok() && paging::is_mapped(start) && !paging::is_mapped(tail);
+    let grown = remap(start, PAGE_SIZE, 3 * PAGE_SIZE).is_ok() && !paging::is_mapped(tail);
+    let end = KERNEL_SPACE.lock().find(start).map(|vma| vma.end);
+    unmap(start, 3 * PAGE_SIZE).map_err(|_| "munmap failed")?;
+    if !shrunk || !grown || end != Some(start + 3 * PAGE_SIZE) {
+        return Err("mremap did not resize the area");
+    }
+    Ok(())
+}
+
+crate::selftest!("vma", selftest);
@@ -0,0 +1,299 @@
+//! ELF64 relocatable object linker for modules
+
+use super::{find_export, Image, ModuleError};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_REL: u16 = 1;
+const EM_X86_64: u16 = 62;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u64 = 0x2;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+const SHN_COMMON: u16 = 0xFFF2;
+const STB_WEAK: u8 = 2;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+const R_X86_64_PC64: u32 = 24;
+
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+/// Result of linking, ready for `module_init` to be called
+pub(crate) struct Linked {
+    pub image: Image,
+    pub init: usize,
+    pub exit: Option<usize>,
+}
+
+/// Section header fields the loader needs
+#[derive(Clone, Copy)]
+struct Section {
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: usize,
+    link: usize,
+    info: usize,
+    align: usize,
+    entry_size: usize,
+}
+
+/// Bounds-checked little-endian reads from the object
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], ModuleError> {
+        let end = offset.checked_add(len).ok_or(ModuleError::Truncated)?;
+        self.0.get(offset..end).ok_or(ModuleError::Truncated)
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8, ModuleError> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, ModuleError> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, ModuleError> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into().unwrap()))
+    }
+
+    fn u64(&self, offset: usize) -> Result<u64, ModuleError> {
+        Ok(u64::from_le_bytes(self.bytes(offset, 8)?.try_into().unwrap()))
+    }
+
+    /// NUL-terminated string starting at `offset`
+    fn str(&self, offset: usize) -> Result<&str, ModuleError> {
+        let tail = self.0.get(offset..).ok_or(ModuleError::Truncated)?;
+        let len = tail.iter().position(|&b| b == 0).ok_or(ModuleError::Truncated)?;
+        core::str::from_utf8(&tail[..len]).map_err(|_| ModuleError::InvalidFormat)
+    }
+}
+
+/// Load the allocated sections of `object`, resolve symbols and apply relocations
+pub(crate) fn link(object: &[u8]) -> Result<Linked, ModuleError> {
+    let elf = Reader(object);
+    if elf.bytes(0, 4)? != ELF_MAGIC
+        || elf.u8(4)? != ELFCLASS64
+        || elf.u8(5)? != ELFDATA2LSB
+        || elf.u16(16)? != ET_REL
+        || elf.u16(18)? != EM_X86_64
+        || elf.u16(58)? as usize != SHDR_SIZE
+    {
+        return Err(ModuleError::InvalidFormat);
+    }
+
+    let sections = read_sections(&elf)?;
+    let (image, bases) = load_sections(&elf, &sections)?;
+
+    let symtab_index = sections
+        .iter()
+        .position(|s| s.kind == SHT_SYMTAB)
+        .ok_or(ModuleError::InvalidFormat)?;
+    let symtab = sections[symtab_index];
+    let strtab = *sections.get(symtab.link).ok_or(ModuleError::InvalidFormat)?;
+    let symbols = resolve_symbols(&elf, &symtab, &strtab, &bases)?;
+
+    for section in sections.iter().filter(|s| s.kind == SHT_RELA && s.link == symtab_index) {
+        // Relocations against sections that were not loaded (e.g. debug info) are skipped
+        let Some(&Some(target_base)) = bases.get(section.info) else {
+            continue;
+        };
+        let target_size = sections[section.info].size;
+        apply_relocations(&elf, section, target_base, target_size, &symbols)?;
+    }
+
+    let init = find_symbol(&elf, &symtab, &strtab, &symbols, "module_init")?
+        .ok_or(ModuleError::MissingInit)?;
+    let exit = find_symbol(&elf, &symtab, &strtab, &symbols, "module_exit")?;
+    Ok(Linked { image, init, exit })
+}
+
+/// Parse the section header table
+fn read_sections(elf: &Reader) -> Result<Vec<Section>, ModuleError> {
+    let table = elf.u64(40)? as usize;
+    let count = elf.u16(60)? as usize;
+    let mut sections = Vec::with_capacity(count);
+    for i in 0..count {
+        let header = table
+            .checked_add(i * SHDR_SIZE)
+            .ok_or(ModuleError::Truncated)?;
+        sections.push(Section {
+            kind: elf.u32(header + 4)?,
+            flags: elf.u64(header + 8)?,
+            offset: elf.u64(header + 24)? as usize,
+            size: elf.u64(header + 32)? as usize,
+            link: elf.u32(header + 40)? as usize,
+            info: elf.u32(header + 44)? as usize,
+            align: (elf.u64(header + 48)? as usize).max(1),
+            entry_size: elf.u64(header + 56)? as usize,
+        });
+    }
+    Ok(sections)
+}
+
+/// Copy `SHF_ALLOC` sections into one block, returning each section's load address
+fn load_sections(elf: &Reader, sections: &[Section]) -> Result<(Image, Vec<Option<usize>>), ModuleError> {
+    let mut offsets = vec![None; sections.len()];
+    let mut size = 0usize;
+    let mut align = 1usize;
+    for (i, section) in sections.iter().enumerate() {
+        if section.flags & SHF_ALLOC == 0 || !section.align.is_power_of_two() {
+            continue;
+        }
+        let start = size.checked_next_multiple_of(section.align).ok_or(ModuleError::OutOfMemory)?;
+        size = start.checked_add(section.size).ok_or(ModuleError::OutOfMemory)?;
+        align = align.max(section.align);
+        offsets[i] = Some(start);
+    }
+
+    let image = Image::allocate(size, align)?;
+    let mut bases = vec![None; sections.len()];
+    for (i, section) in sections.iter().enumerate() {
+        let Some(offset) = offsets[i] else {
+            continue;
+        };
+        let base = image.base() + offset;
+        // NOBITS (.bss) stays zeroed
+        if section.kind != SHT_NOBITS {
+            let data = elf.bytes(section.offset, section.size)?;
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), base as *mut u8, data.len()) };
+        }
+        bases[i] = Some(base);
+    }
+    Ok((image, bases))
+}
+
+/// Final address of every symbol table entry
+fn resolve_symbols(
+    elf: &Reader,
+    symtab: &Section,
+    strtab: &Section,
+    bases: &[Option<usize>],
+) -> Result<Vec<usize>, ModuleError> {
+    if symtab.entry_size != SYM_SIZE {
+        return Err(ModuleError::InvalidFormat);
+    }
+    let count = symtab.size / SYM_SIZE;
+    let mut symbols = Vec::with_capacity(count);
+    for i in 0..count {
+        let entry = symtab.offset + i * SYM_SIZE;
+        let binding = elf.u8(entry + 4)? >> 4;
+        let section = elf.u16(entry + 6)?;
+        let value = elf.u64(entry + 8)? as usize;
+
+        let address = match section {
+            SHN_UNDEF if i == 0 => 0,
+            SHN_UNDEF => {
+                let name = elf.str(strtab.offset + elf.u32(entry)? as usize)?;
+                match find_export(name) {
+                    Some(address) => address,
+                    None if binding == STB_WEAK => 0,
+                    None => return Err(ModuleError::UndefinedSymbol(String::from(name))),
+                }
+            }
+            SHN_ABS => value,
+            SHN_COMMON => return Err(ModuleError::InvalidFormat),
+            index => match bases.get(index as usize) {
+                Some(Some(base)) => base + value,
+                // Symbols in sections that were not loaded are never relocated against
+                _ => 0,
+            },
+        };
+        symbols.push(address);
+    }
+    Ok(symbols)
+}
+
+/// Apply one `SHT_RELA` section to its loaded target
+fn apply_relocations(
+    elf: &Reader,
+    rela: &Section,
+    target_base: usize,
+    target_size: usize,
+    symbols: &[usize],
+) -> Result<(), ModuleError> {
+    if rela.entry_size != RELA_SIZE {
+        return Err(ModuleError::InvalidFormat);
+    }
+    for i in 0..rela.size / RELA_SIZE {
+        let entry = rela.offset + i * RELA_SIZE;
+        let offset = elf.u64(entry)? as usize;
+        let info = elf.u64(entry + 8)?;
+        let addend = elf.u64(entry + 16)? as i64;
+        let kind = info as u32;
+        let symbol = *symbols.get((info >> 32) as usize).ok_or(ModuleError::InvalidFormat)?;
+
+        let place = target_base + offset;
+        let value = (symbol as i64).wrapping_add(addend);
+        let relative = value.wrapping_sub(place as i64);
+
+        let width = match kind {
+            R_X86_64_NONE => 0,
+            R_X86_64_64 | R_X86_64_PC64 => 8,
+            R_X86_64_PC32 | R_X86_64_PLT32 | R_X86_64_32 | R_X86_64_32S => 4,
+            _ => return Err(ModuleError::UnsupportedRelocation(kind)),
+        };
+        if offset.checked_add(width).is_none_or(|end| end > target_size) {
+            return Err(ModuleError::Truncated);
+        }
+
+        unsafe {
+            match kind {
+                R_X86_64_NONE => {}
+                R_X86_64_64 => (place as *mut u64).write_unaligned(value as u64),
+                R_X86_64_PC64 => (place as *mut u64).write_unaligned(relative as u64),
+                R_X86_64_PC32 | R_X86_64_PLT32 => {
+                    let field = i32::try_from(relative).map_err(|_| ModuleError::RelocationOverflow(kind))?;
+                    (place as *mut i32).write_unaligned(field);
+                }
+                R_X86_64_32 => {
+                    let field = u32::try_from(value).map_err(|_| ModuleError::RelocationOverflow(kind))?;
+                    (place as *mut u32).write_unaligned(field);
+                }
+                R_X86_64_32S => {
+                    let field = i32::try_from(value).map_err(|_| ModuleError::RelocationOverflow(kind))?;
+                    (place as *mut i32).write_unaligned(field);
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Address of a defined symbol by name
+fn find_symbol(
+    elf: &Reader,
+    symtab: &Section,
+    strtab: &Section,
+    symbols: &[usize],
+    name: &str,
+) -> Result<Option<usize>, ModuleError> {
+    for (i, &address) in symbols.iter().enumerate().skip(1) {
+        let entry = symtab.offset + i * SYM_SIZE;
+        if elf.u16(entry + 6)? == SHN_UNDEF || address == 0 {
+            continue;
+        }
+        if elf.str(strtab.offset + elf.u32(entry)? as usize)? == name {
+            return Ok(Some(address));
+        }
+    }
+    Ok(None)
+}
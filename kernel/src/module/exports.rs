@@ -0,0 +1,52 @@
+//! C ABI kernel services exported to modules
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use crate::export_symbol;
+use crate::stack_protector::{__stack_chk_fail, __stack_chk_guard};
+
+/// Write `len` bytes of UTF-8 text to the kernel log
+extern "C" fn module_print(text: *const u8, len: usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(text, len) };
+    match core::str::from_utf8(bytes) {
+        Ok(text) => crate::serial_print!("{}", text),
+        Err(_) => crate::serial_print!("<invalid utf-8>"),
+    }
+}
+
+/// Allocate kernel heap memory, null on failure
+extern "C" fn kmalloc(size: usize, align: usize) -> *mut u8 {
+    match Layout::from_size_align(size.max(1), align) {
+        Ok(layout) => unsafe { alloc(layout) },
+        Err(_) => core::ptr::null_mut(),
+    }
+}
+
+/// Free memory from `kmalloc` with the same size and alignment
+extern "C" fn kfree(ptr: *mut u8, size: usize, align: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    if let Ok(layout) = Layout::from_size_align(size.max(1), align) {
+        unsafe { dealloc(ptr, layout) };
+    }
+}
+
+/// Milliseconds since boot
+extern "C" fn uptime_ms() -> u64 {
+    crate::time::uptime_ms()
+}
+
+/// Give up the CPU to the next ready task
+extern "C" fn yield_now() {
+    crate::task::yield_now();
+}
+
+export_symbol!(fn module_print);
+export_symbol!(fn kmalloc);
+export_symbol!(fn kfree);
+export_symbol!(fn uptime_ms);
+export_symbol!(fn yield_now);
+
+// Modules built with the stack protector share the kernel canary
+export_symbol!(fn __stack_chk_fail);
+export_symbol!(static __stack_chk_guard);
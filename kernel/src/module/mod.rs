@@ -0,0 +1,229 @@
+//! Loadable Kernel Modules
+//!
+//! A module is an x86_64 ELF relocatable object (`ET_REL`), e.g. built with
+//! `-C relocation-model=static -C panic=abort --emit=obj`. Undefined
+//! symbols are resolved against the kernel export table built by
+//! [`export_symbol!`]. The object must define `module_init`, an
+//! `extern "C" fn() -> i32` returning 0 on success, and may define
+//! `module_exit`, an `extern "C" fn()` run on unload.
+
+pub mod elf;
+pub mod exports;
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+/// Entry point called after relocation, 0 means success
+pub type InitFn = extern "C" fn() -> i32;
+
+/// Cleanup hook called before the module is freed
+pub type ExitFn = extern "C" fn();
+
+/// Symbol the kernel makes available to modules
+#[repr(C)]
+pub struct KernelSymbol {
+    pub name: &'static str,
+    pub address: *const (),
+}
+
+// Entries are immutable and only describe addresses
+unsafe impl Sync for KernelSymbol {}
+
+/// Add a function or static to the module export table
+///
+/// ```ignore
+/// export_symbol!(fn module_print);
+/// export_symbol!(static __stack_chk_guard);
+/// ```
+#[macro_export]
+macro_rules! export_symbol {
+    (fn $name:ident) => {
+        $crate::export_symbol!(@entry $name, $name as *const ());
+    };
+    (static $name:ident) => {
+        $crate::export_symbol!(@entry $name, &raw const $name as *const ());
+    };
+    (@entry $name:ident, $address:expr) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".ksymtab"]
+            static SYMBOL: $crate::module::KernelSymbol = $crate::module::KernelSymbol {
+                name: stringify!($name),
+                address: $address,
+            };
+        };
+    };
+}
+
+// Bounds of `.ksymtab`, defined by the linker script
+unsafe extern "C" {
+    static __ksymtab_start: u8;
+    static __ksymtab_end: u8;
+}
+
+/// All exported kernel symbols
+pub fn exported_symbols() -> &'static [KernelSymbol] {
+    unsafe {
+        let start = &raw const __ksymtab_start as *const KernelSymbol;
+        let end = &raw const __ksymtab_end as *const KernelSymbol;
+        let count = (end as usize - start as usize) / core::mem::size_of::<KernelSymbol>();
+        core::slice::from_raw_parts(start, count)
+    }
+}
+
+/// Look up an exported kernel symbol by name
+pub fn find_export(name: &str) -> Option<usize> {
+    exported_symbols()
+        .iter()
+        .find(|symbol| symbol.name == name)
+        .map(|symbol| symbol.address as usize)
+}
+
+/// Errors that can occur while loading or unloading a module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModuleError {
+    /// Not an x86_64 ELF relocatable object
+    InvalidFormat,
+    /// Header or section data lies outside the image
+    Truncated,
+    /// Symbol not exported by the kernel
+    UndefinedSymbol(String),
+    /// Relocation type the loader cannot apply
+    UnsupportedRelocation(u32),
+    /// Relocated value does not fit the field
+    RelocationOverflow(u32),
+    /// No `module_init` defined
+    MissingInit,
+    /// `module_init` returned the given non-zero code
+    InitFailed(i32),
+    /// A module of that name is already loaded
+    AlreadyLoaded,
+    /// No module of that name is loaded
+    NotLoaded,
+    /// Could not allocate memory for the sections
+    OutOfMemory,
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModuleError::InvalidFormat => write!(f, "Not an x86_64 ELF relocatable object"),
+            ModuleError::Truncated => write!(f, "Module image truncated"),
+            ModuleError::UndefinedSymbol(name) => write!(f, "Undefined symbol: {}", name),
+            ModuleError::UnsupportedRelocation(kind) => write!(f, "Unsupported relocation type {}", kind),
+            ModuleError::RelocationOverflow(kind) => write!(f, "Relocation type {} out of range", kind),
+            ModuleError::MissingInit => write!(f, "Module has no module_init"),
+            ModuleError::InitFailed(code) => write!(f, "module_init failed with {}", code),
+            ModuleError::AlreadyLoaded => write!(f, "Module already loaded"),
+            ModuleError::NotLoaded => write!(f, "Module not loaded"),
+            ModuleError::OutOfMemory => write!(f, "Out of memory"),
+        }
+    }
+}
+
+/// Memory holding a module's allocated sections
+pub(crate) struct Image {
+    base: usize,
+    layout: Layout,
+}
+
+impl Image {
+    /// Allocate zeroed memory for the sections
+    fn allocate(size: usize, align: usize) -> Result<Self, ModuleError> {
+        let layout = Layout::from_size_align(size.max(1), align.max(8))
+            .map_err(|_| ModuleError::InvalidFormat)?;
+        let base = unsafe { alloc_zeroed(layout) };
+        if base.is_null() {
+            return Err(ModuleError::OutOfMemory);
+        }
+        Ok(Image { base: base as usize, layout })
+    }
+
+    /// Start address of the image
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Size of the image in bytes
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.base as *mut u8, self.layout) };
+    }
+}
+
+/// A loaded module
+struct Module {
+    name: String,
+    image: Image,
+    exit: Option<ExitFn>,
+}
+
+/// Summary of a loaded module as (name, base, size)
+pub type ModuleInfo = (String, usize, usize);
+
+/// Loaded modules
+static MODULES: Mutex<Vec<Module>> = Mutex::new(Vec::new());
+
+/// Serializes load and unload so init and exit hooks never overlap
+static LOADER: Mutex<()> = Mutex::new(());
+
+/// Link a module image into the kernel and run its `module_init`
+pub fn load(name: &str, object: &[u8]) -> Result<(), ModuleError> {
+    let _loader = LOADER.lock();
+    if MODULES.lock().iter().any(|m| m.name == name) {
+        return Err(ModuleError::AlreadyLoaded);
+    }
+
+    let linked = elf::link(object)?;
+    let init: InitFn = unsafe { core::mem::transmute(linked.init) };
+    let exit = linked
+        .exit
+        .map(|address| unsafe { core::mem::transmute::<usize, ExitFn>(address) });
+
+    // Hooks run without the module list locked, so they may query it
+    let code = init();
+    if code != 0 {
+        return Err(ModuleError::InitFailed(code));
+    }
+
+    crate::serial_println!("Module {} loaded at {:#x} ({} bytes)",
+        name, linked.image.base(), linked.image.size());
+    MODULES.lock().push(Module { name: String::from(name), image: linked.image, exit });
+    Ok(())
+}
+
+/// Run a module's `module_exit` and free it
+pub fn unload(name: &str) -> Result<(), ModuleError> {
+    let _loader = LOADER.lock();
+    let module = {
+        let mut modules = MODULES.lock();
+        let index = modules
+            .iter()
+            .position(|m| m.name == name)
+            .ok_or(ModuleError::NotLoaded)?;
+        modules.remove(index)
+    };
+
+    if let Some(exit) = module.exit {
+        exit();
+    }
+    drop(module);
+    Ok(())
+}
+
+/// Snapshot of loaded modules
+pub fn modules() -> Vec<ModuleInfo> {
+    MODULES
+        .lock()
+        .iter()
+        .map(|m| (m.name.clone(), m.image.base(), m.image.size()))
+        .collect()
+}
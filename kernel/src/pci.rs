@@ -0,0 +1,324 @@
+//! PCI Configuration Space
+//!
+//! Devices are found by probing every bus, device and function through the
+//! legacy 0xCF8/0xCFC configuration mechanism, which every x86 chipset and
+//! hypervisor still provides. The scan runs once at boot; drivers look up
+//! their devices by class code and then program the BARs the firmware
+//! assigned.
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use crate::arch::x86_64::port::{self, Port};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+/// Address and data registers, with the byte lanes of the data dword
+const CONFIG_PORTS: u16 = 8;
+/// Configuration address enable bit
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+// Configuration space registers
+const VENDOR_ID: u8 = 0x00;
+const COMMAND: u8 = 0x04;
+const CLASS_REVISION: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0E;
+const BAR0: u8 = 0x10;
+const SECONDARY_BUS: u8 = 0x19;
+const INTERRUPT_LINE: u8 = 0x3C;
+
+/// Command register: respond to I/O space accesses
+pub const COMMAND_IO: u16 = 1 << 0;
+/// Command register: respond to memory space accesses
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+/// Command register: allow the device to master the bus for DMA
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Command register: legacy INTx disabled
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// Multi-function bit of the header type
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+/// Header type of PCI-to-PCI bridges
+const HEADER_BRIDGE: u8 = 0x01;
+
+/// Bridge class and subclass
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+// Class codes drivers look for
+pub const CLASS_SERIAL_BUS: u8 = 0x0C;
+pub const SUBCLASS_USB: u8 = 0x03;
+pub const PROG_IF_XHCI: u8 = 0x30;
+pub const CLASS_MULTIMEDIA: u8 = 0x04;
+pub const SUBCLASS_AUDIO: u8 = 0x01;
+
+/// Location of a function in configuration space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress { bus, device, function }
+    }
+
+    fn config_address(self, offset: u8) -> u32 {
+        CONFIG_ENABLE
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    /// Read a configuration dword, `offset` is rounded down to 4 bytes
+    pub fn read_u32(self, offset: u8) -> u32 {
+        let _guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    /// Write a configuration dword, `offset` is rounded down to 4 bytes
+    pub fn write_u32(self, offset: u8, value: u32) {
+        let _guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn read_u16(self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Write a configuration word, keeping the other half of its dword
+    pub fn write_u16(self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, dword | (value as u32) << shift);
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A base address register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { address: u64, size: u64, prefetchable: bool },
+    Io { port: u16, size: u32 },
+}
+
+/// Check a memory BAR against the boot memory map, if there is one
+fn overlaps_ram(address: u64, size: u64) -> bool {
+    crate::mm::memory_map::MemoryMap::boot_map()
+        .is_some_and(|map| map.overlaps_ram(address..address.saturating_add(size)))
+}
+
+/// A function found by the scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// Legacy IRQ the firmware routed INTx to, 0xFF if none
+    pub interrupt_line: u8,
+}
+
+impl PciDevice {
+    fn probe(address: PciAddress) -> Option<Self> {
+        let id = address.read_u32(VENDOR_ID);
+        if id & 0xFFFF == 0xFFFF {
+            return None;
+        }
+        let class = address.read_u32(CLASS_REVISION);
+        Some(PciDevice {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type: address.read_u8(HEADER_TYPE),
+            interrupt_line: address.read_u8(INTERRUPT_LINE),
+        })
+    }
+
+    /// Decode BAR `index`, sizing it by writing all ones
+    ///
+    /// Returns `None` for unimplemented BARs and for the upper half of a
+    /// 64-bit BAR.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        let limit = if self.header_type & 0x7F == HEADER_BRIDGE { 2 } else { 6 };
+        if index >= limit {
+            return None;
+        }
+        let offset = BAR0 + index * 4;
+        let address = self.address;
+
+        // Decoding must be off while the BAR holds the sizing pattern
+        let command = address.read_u16(COMMAND);
+        address.write_u16(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+        let low = address.read_u32(offset);
+        address.write_u32(offset, u32::MAX);
+        let low_mask = address.read_u32(offset);
+        address.write_u32(offset, low);
+
+        let bar = if low & 1 != 0 {
+            let mask = low_mask & 0xFFFC;
+            Some(Bar::Io { port: (low & !0x3) as u16, size: (!mask & 0xFFFF) + 1 })
+        } else {
+            let wide = (low >> 1) & 0x3 == 0x2;
+            let (high, high_mask) = if wide && index + 1 < limit {
+                let high = address.read_u32(offset + 4);
+                address.write_u32(offset + 4, u32::MAX);
+                let mask = address.read_u32(offset + 4);
+                address.write_u32(offset + 4, high);
+                (high, mask)
+            } else {
+                (0, if wide { 0 } else { u32::MAX })
+            };
+            let mask = (high_mask as u64) << 32 | (low_mask & !0xF) as u64;
+            let base = (high as u64) << 32 | (low & !0xF) as u64;
+            Some(Bar::Memory { address: base, size: (!mask).wrapping_add(1), prefetchable: low & 0x8 != 0 })
+        };
+        address.write_u16(COMMAND, command);
+
+        match bar {
+            Some(Bar::Memory { size: 0, .. }) | Some(Bar::Io { size: 0, .. }) => None,
+            _ if low_mask == 0 => None,
+            // Unassigned or misprogrammed, mapping it would alias RAM
+            Some(Bar::Memory { address, size, .. }) if overlaps_ram(address, size) => {
+                crate::serial_println!("PCI {}: BAR{} at {:#x} overlaps RAM, ignored", self.address, index, address);
+                None
+            }
+            bar => bar,
+        }
+    }
+
+    /// Turn on memory decoding and bus mastering for DMA
+    pub fn enable_bus_master(&self) {
+        self.enable(COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    }
+
+    /// Set bits in the command register
+    pub fn enable(&self, bits: u16) {
+        let command = self.address.read_u16(COMMAND);
+        self.address.write_u16(COMMAND, command | bits);
+    }
+
+    /// Human-readable class name for the common classes
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "storage controller",
+            (0x02, _) => "network controller",
+            (0x03, _) => "display controller",
+            (0x04, 0x01) => "audio device",
+            (0x04, 0x03) => "HD audio controller",
+            (0x04, _) => "multimedia device",
+            (0x06, 0x00) => "host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "bridge",
+            (0x07, _) => "communication controller",
+            (0x08, _) => "system peripheral",
+            (0x0C, 0x03) => match self.prog_if {
+                0x00 => "UHCI controller",
+                0x10 => "OHCI controller",
+                0x20 => "EHCI controller",
+                0x30 => "XHCI controller",
+                _ => "USB controller",
+            },
+            (0x0C, 0x05) => "SMBus controller",
+            (0x0C, _) => "serial bus controller",
+            _ => "device",
+        }
+    }
+}
+
+/// Serializes address/data port pairs
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Functions found at boot
+static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+
+/// Scan the buses behind the host bridge
+pub fn init() {
+    if let Err(e) = port::claim(CONFIG_ADDRESS, CONFIG_PORTS, "pci") {
+        crate::serial_println!("PCI: {}", e);
+    }
+    let mut found = Vec::new();
+    let mut visited = [false; 256];
+    scan_bus(0, &mut found, &mut visited);
+    // Host bridges that are functions of device 0:0 root further buses
+    if let Some(host) = PciDevice::probe(PciAddress::new(0, 0, 0)) {
+        if host.header_type & HEADER_MULTIFUNCTION != 0 {
+            for function in 1..8 {
+                if PciDevice::probe(PciAddress::new(0, 0, function)).is_some() {
+                    scan_bus(function, &mut found, &mut visited);
+                }
+            }
+        }
+    }
+    found.sort_by_key(|device| device.address);
+    found.dedup_by_key(|device| device.address);
+    crate::serial_println!("PCI: {} functions", found.len());
+    *DEVICES.lock() = found;
+}
+
+fn scan_bus(bus: u8, found: &mut Vec<PciDevice>, visited: &mut [bool; 256]) {
+    if core::mem::replace(&mut visited[bus as usize], true) {
+        return;
+    }
+    for device in 0..32 {
+        let Some(first) = PciDevice::probe(PciAddress::new(bus, device, 0)) else {
+            continue;
+        };
+        let functions = if first.header_type & HEADER_MULTIFUNCTION != 0 { 8 } else { 1 };
+        for function in 0..functions {
+            let Some(found_device) = PciDevice::probe(PciAddress::new(bus, device, function)) else {
+                continue;
+            };
+            found.push(found_device);
+            if found_device.class == CLASS_BRIDGE && found_device.subclass == SUBCLASS_PCI_BRIDGE {
+                let secondary = found_device.address.read_u8(SECONDARY_BUS);
+                scan_bus(secondary, found, visited);
+            }
+        }
+    }
+}
+
+/// Functions found at boot, in address order
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+/// Functions with the given class, subclass and programming interface
+pub fn find(class: u8, subclass: u8, prog_if: u8) -> Vec<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.class == class && device.subclass == subclass && device.prog_if == prog_if)
+        .copied()
+        .collect()
+}
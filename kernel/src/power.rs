@@ -0,0 +1,154 @@
+//! Reboot, shutdown and panic power policy
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use crate::acpi;
+use crate::arch::x86_64::port::Port;
+use crate::efi;
+use crate::efi::runtime::ResetType;
+
+/// 8042 keyboard controller status/command port
+const KBC_COMMAND_PORT: u16 = 0x64;
+/// 8042 status: input buffer full
+const KBC_INPUT_FULL: u8 = 1 << 1;
+/// 8042 command: pulse the CPU reset line
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// Emulator power-off ports and values
+const EMULATOR_POWEROFF: [(u16, u16); 3] = [
+    (0x604, 0x2000),  // QEMU
+    (0xB004, 0x2000), // Bochs and older QEMU
+    (0x4004, 0x3400), // VirtualBox
+];
+
+/// Polls to wait for the keyboard controller or a reset to take effect
+const RESET_POLLS: usize = 1_000_000;
+
+/// What the panic handler does once the report is written
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    Halt = 0,
+    Reboot = 1,
+    Shutdown = 2,
+    /// Exit QEMU through `isa-debug-exit` with a failure code
+    QemuExit = 3,
+}
+
+impl PanicAction {
+    /// Parse an action name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "halt" => Some(PanicAction::Halt),
+            "reboot" => Some(PanicAction::Reboot),
+            "shutdown" => Some(PanicAction::Shutdown),
+            "qemu-exit" => Some(PanicAction::QemuExit),
+            _ => None,
+        }
+    }
+
+    /// Action name
+    pub fn name(self) -> &'static str {
+        match self {
+            PanicAction::Halt => "halt",
+            PanicAction::Reboot => "reboot",
+            PanicAction::Shutdown => "shutdown",
+            PanicAction::QemuExit => "qemu-exit",
+        }
+    }
+}
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+
+/// Set what happens after a panic
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+/// What happens after a panic
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        1 => PanicAction::Reboot,
+        2 => PanicAction::Shutdown,
+        3 => PanicAction::QemuExit,
+        _ => PanicAction::Halt,
+    }
+}
+
+/// Reboot: ACPI reset register, then UEFI ResetSystem, then 8042 reset
+/// pulse, then triple fault
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    crate::serial_println!("Rebooting...");
+
+    if acpi::pm::reset().is_ok() {
+        settle();
+    }
+
+    if efi::runtime::is_available() {
+        let e = efi::runtime::reset_system(ResetType::Cold);
+        crate::serial_println!("UEFI reset failed: {}", e);
+    }
+
+    unsafe {
+        let command: Port<u8> = Port::new(KBC_COMMAND_PORT);
+        for _ in 0..RESET_POLLS {
+            if command.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        command.write(KBC_PULSE_RESET);
+    }
+    settle();
+
+    triple_fault()
+}
+
+/// Power off: ACPI S5, then UEFI ResetSystem, then emulator power-off ports
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+    crate::serial_println!("Powering off...");
+
+    if let Err(e) = acpi::pm::enter_sleep_state(5) {
+        crate::serial_println!("ACPI S5 failed: {}", e);
+    }
+    settle();
+
+    if efi::runtime::is_available() {
+        let e = efi::runtime::reset_system(ResetType::Shutdown);
+        crate::serial_println!("UEFI power off failed: {}", e);
+    }
+
+    for (port, value) in EMULATOR_POWEROFF {
+        unsafe {
+            Port::<u16>::new(port).write(value);
+        }
+    }
+    settle();
+
+    crate::serial_println!("Power off failed, halting");
+    crate::hlt_loop()
+}
+
+/// Give a reset or power-off request time to take effect
+fn settle() {
+    for _ in 0..RESET_POLLS {
+        core::hint::spin_loop();
+    }
+}
+
+/// Reset the CPU by faulting with an empty IDT
+fn triple_fault() -> ! {
+    use x86_64::structures::DescriptorTablePointer;
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    crate::hlt_loop()
+}
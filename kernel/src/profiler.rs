@@ -0,0 +1,72 @@
+//! Sampling Profiler
+//!
+//! While enabled, the timer interrupt records the interrupted RIP into a
+//! ring buffer. Samples are attributed to functions through the kernel
+//! symbol table when the profile is read.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Samples kept, older ones are overwritten
+pub const SAMPLE_CAPACITY: usize = 4096;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Sampled instruction pointers
+static SAMPLES: [AtomicU64; SAMPLE_CAPACITY] = [const { AtomicU64::new(0) }; SAMPLE_CAPACITY];
+
+/// Samples taken since the last reset, the next write goes to this index modulo the capacity
+static TAKEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Start sampling
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop sampling, the samples taken so far are kept
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+/// Check whether sampling is on
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Discard all samples
+pub fn reset() {
+    TAKEN.store(0, Ordering::Release);
+}
+
+/// Record a sample, called from the timer interrupt
+pub fn sample(rip: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let index = TAKEN.fetch_add(1, Ordering::AcqRel) % SAMPLE_CAPACITY;
+    SAMPLES[index].store(rip, Ordering::Relaxed);
+}
+
+/// Number of samples in the buffer, and how many were overwritten
+pub fn sample_count() -> (usize, usize) {
+    let taken = TAKEN.load(Ordering::Acquire);
+    (taken.min(SAMPLE_CAPACITY), taken.saturating_sub(SAMPLE_CAPACITY))
+}
+
+/// Per-function sample counts, busiest first
+///
+/// Addresses without a symbol are grouped under `"?"`.
+pub fn profile() -> Vec<(&'static str, usize)> {
+    let (count, _) = sample_count();
+    let mut counts: BTreeMap<&'static str, usize> = BTreeMap::new();
+    for sample in &SAMPLES[..count] {
+        let rip = sample.load(Ordering::Relaxed) as usize;
+        let name = crate::ksyms::lookup(rip).map_or("?", |(symbol, _)| symbol.name);
+        *counts.entry(name).or_insert(0) += 1;
+    }
+
+    let mut profile: Vec<_> = counts.into_iter().collect();
+    profile.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    profile
+}
@@ -0,0 +1,321 @@
+//! Persistent Store
+//!
+//! A 64 KiB block of RAM just below the kernel that nothing else uses and
+//! that keeps its contents across a warm reboot. It holds a copy of recent
+//! console output, kept up to date as the kernel prints, and the panic
+//! message of a crash. After a triple fault, or a panic whose screen and
+//! serial output nobody saw, the next boot finds both here. Firmware that
+//! clears RAM on reset, or a cold boot, leaves nothing, which the checksums
+//! tell apart from a real record.
+//!
+//! Layout, every integer little-endian:
+//!
+//! ```text
+//! 0x0000  header       magic "CSMPSTOR", u32 version, u32 CRC-32 of
+//!                      bytes 16..64, u32 boots since the region was
+//!                      formatted, u32 panic record length, u32 CRC-32 of
+//!                      the panic record, u32 reserved, u64 console bytes
+//!                      written, zero up to 64
+//! 0x0040  panic        u64 uptime in milliseconds, then the message
+//! 0x2040  console      ring of console output, the next byte goes at
+//!                      bytes written modulo the ring size
+//! ```
+
+use core::fmt;
+use spin::Mutex;
+use crate::crypto::crc32;
+
+/// Physical address of the region, the 64 KiB below the kernel at 2 MiB
+///
+/// The BIOS loader only uses memory below 1 MiB. The UEFI loader claims
+/// the region before its first allocation, and the kernel holds it out of
+/// the frame allocator while it is in use.
+pub const PSTORE_ADDRESS: u64 = cosmos_bootinfo::PSTORE_ADDRESS;
+pub const PSTORE_SIZE: usize = cosmos_bootinfo::PSTORE_SIZE as usize;
+
+const MAGIC: [u8; 8] = *b"CSMPSTOR";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 64;
+const PANIC_OFFSET: usize = HEADER_SIZE;
+const PANIC_SIZE: usize = 8 * 1024;
+const CONSOLE_OFFSET: usize = PANIC_OFFSET + PANIC_SIZE;
+const CONSOLE_SIZE: usize = PSTORE_SIZE - CONSOLE_OFFSET;
+
+// Header field offsets
+const HEADER_CRC: usize = 12;
+const HEADER_BOOTS: usize = 16;
+const HEADER_PANIC_LEN: usize = 20;
+const HEADER_PANIC_CRC: usize = 24;
+const HEADER_WRITTEN: usize = 32;
+
+/// View of a region laid out as above
+struct Region<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl Region<'_> {
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn set_u64(&mut self, offset: usize, value: u64) {
+        self.bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn header_valid(&self) -> bool {
+        self.bytes[..8] == MAGIC
+            && self.u32(8) == VERSION
+            && self.u32(HEADER_CRC) == crc32::checksum(&self.bytes[16..HEADER_SIZE])
+    }
+
+    fn seal_header(&mut self) {
+        let crc = crc32::checksum(&self.bytes[16..HEADER_SIZE]);
+        self.set_u32(HEADER_CRC, crc);
+    }
+
+    /// Start over, keeping the boot count
+    fn format(&mut self, boots: u32) {
+        self.bytes[..HEADER_SIZE].fill(0);
+        self.bytes[..8].copy_from_slice(&MAGIC);
+        self.set_u32(8, VERSION);
+        self.set_u32(HEADER_BOOTS, boots);
+        self.seal_header();
+    }
+
+    /// Panic record, if there is one and its checksum matches
+    fn panic_record(&self) -> Option<(u64, &str)> {
+        let length = self.u32(HEADER_PANIC_LEN) as usize;
+        if !(8..=PANIC_SIZE).contains(&length) {
+            return None;
+        }
+        let record = &self.bytes[PANIC_OFFSET..PANIC_OFFSET + length];
+        if crc32::checksum(record) != self.u32(HEADER_PANIC_CRC) {
+            return None;
+        }
+        let uptime = u64::from_le_bytes(record[..8].try_into().unwrap());
+        Some((uptime, core::str::from_utf8(&record[8..]).unwrap_or("<not UTF-8>")))
+    }
+
+    fn write_panic(&mut self, uptime_ms: u64, message: fmt::Arguments) {
+        let mut out = Cursor { bytes: &mut self.bytes[PANIC_OFFSET..PANIC_OFFSET + PANIC_SIZE], len: 8 };
+        out.bytes[..8].copy_from_slice(&uptime_ms.to_le_bytes());
+        let _ = fmt::Write::write_fmt(&mut out, message);
+        let length = out.len;
+        let crc = crc32::checksum(&self.bytes[PANIC_OFFSET..PANIC_OFFSET + length]);
+        self.set_u32(HEADER_PANIC_LEN, length as u32);
+        self.set_u32(HEADER_PANIC_CRC, crc);
+        self.seal_header();
+    }
+
+    fn console_write(&mut self, data: &[u8]) {
+        let data = &data[data.len().saturating_sub(CONSOLE_SIZE)..];
+        let written = self.u64(HEADER_WRITTEN);
+        let start = (written % CONSOLE_SIZE as u64) as usize;
+        let first = data.len().min(CONSOLE_SIZE - start);
+        let ring = &mut self.bytes[CONSOLE_OFFSET..];
+        ring[start..start + first].copy_from_slice(&data[..first]);
+        ring[..data.len() - first].copy_from_slice(&data[first..]);
+        self.set_u64(HEADER_WRITTEN, written + data.len() as u64);
+        self.seal_header();
+    }
+
+    /// Console bytes in the ring as `(older, newer)` halves
+    fn console(&self) -> (&[u8], &[u8]) {
+        let written = self.u64(HEADER_WRITTEN);
+        let ring = &self.bytes[CONSOLE_OFFSET..];
+        if written <= CONSOLE_SIZE as u64 {
+            return (&ring[..written as usize], &[]);
+        }
+        let split = (written % CONSOLE_SIZE as u64) as usize;
+        (&ring[split..], &ring[..split])
+    }
+}
+
+/// Bounded `fmt::Write` into a byte slice, cut on character boundaries
+struct Cursor<'a> {
+    bytes: &'a mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for Cursor<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut count = s.len().min(self.bytes.len() - self.len);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Live region, `None` until `init` checks it is RAM
+static LIVE: Mutex<Option<&'static mut [u8]>> = Mutex::new(None);
+
+/// What the previous boot left, copied before the region is reused
+static PREVIOUS: Mutex<Previous> = Mutex::new(Previous { bytes: [0; PSTORE_SIZE], valid: false });
+
+struct Previous {
+    bytes: [u8; PSTORE_SIZE],
+    valid: bool,
+}
+
+/// Check the region is usable RAM, keep what the previous boot left and
+/// start recording this one
+///
+/// Runs before the heap exists, `pstore=off` leaves the region alone.
+pub fn init() {
+    if crate::cmdline::get("pstore") == Some("off") || !is_ram() {
+        return;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts_mut(PSTORE_ADDRESS as *mut u8, PSTORE_SIZE) };
+    let mut region = Region { bytes };
+
+    let mut boots = 0;
+    if region.header_valid() {
+        boots = region.u32(HEADER_BOOTS).wrapping_add(1);
+        let mut previous = PREVIOUS.lock();
+        previous.bytes.copy_from_slice(region.bytes);
+        previous.valid = true;
+        let previous = Region { bytes: &mut previous.bytes };
+        if let Some((uptime, message)) = previous.panic_record() {
+            crate::serial_println!("pstore: previous boot panicked after {} ms: {}", uptime, message);
+        }
+        let (older, newer) = previous.console();
+        crate::serial_println!("pstore: {} bytes of the previous boot's console kept", older.len() + newer.len());
+    }
+    region.format(boots);
+    *LIVE.lock() = Some(region.bytes);
+}
+
+/// Check the memory map lists the region as usable RAM
+fn is_ram() -> bool {
+    let Ok(entries) = crate::boot::protocol().memory_map() else {
+        return false;
+    };
+    let end = PSTORE_ADDRESS + PSTORE_SIZE as u64;
+    entries.iter().any(|entry| {
+        entry.is_usable() && entry.base_addr <= PSTORE_ADDRESS && entry.base_addr + entry.length >= end
+    })
+}
+
+/// Mirror console output, called by the console multiplexer
+///
+/// Skipped while the region is locked, so a print that faults halfway
+/// cannot deadlock the next one, as with the kernel log.
+pub fn console_write(data: &[u8]) {
+    let Some(mut live) = LIVE.try_lock() else {
+        return;
+    };
+    if let Some(bytes) = live.as_deref_mut() {
+        Region { bytes }.console_write(data);
+    }
+}
+
+/// `fmt::Write` sink for the console mirror
+pub struct PstoreConsole;
+
+impl fmt::Write for PstoreConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        console_write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Record a panic, skipped if the region is locked by the panicking CPU
+pub fn on_panic(info: &core::panic::PanicInfo) {
+    let Some(mut live) = LIVE.try_lock() else {
+        return;
+    };
+    let Some(bytes) = live.as_deref_mut() else {
+        return;
+    };
+    let uptime = crate::time::uptime_ms();
+    let mut region = Region { bytes };
+    match info.location() {
+        Some(location) => region.write_panic(uptime, format_args!("{} at {}", info.message(), location)),
+        None => region.write_panic(uptime, format_args!("{}", info.message())),
+    }
+}
+
+/// Boots since the region was formatted, `None` if not in use
+pub fn boots() -> Option<u32> {
+    LIVE.lock().as_deref_mut().map(|bytes| Region { bytes }.u32(HEADER_BOOTS))
+}
+
+/// Report what the previous boot left to `out`
+pub fn show_previous(out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut previous = PREVIOUS.lock();
+    if !previous.valid {
+        return writeln!(out, "No record from a previous boot");
+    }
+    let region = Region { bytes: &mut previous.bytes };
+    match region.panic_record() {
+        Some((uptime, message)) => writeln!(out, "Panicked after {} ms: {}", uptime, message)?,
+        None => writeln!(out, "No panic recorded")?,
+    }
+    let (older, newer) = region.console();
+    writeln!(out, "Console, last {} bytes:", older.len() + newer.len())?;
+    for part in [older, newer] {
+        // The oldest line may start mid-character after wrapping
+        for chunk in part.utf8_chunks() {
+            out.write_str(chunk.valid())?;
+        }
+    }
+    Ok(())
+}
+
+/// Forget the previous boot's record
+pub fn clear_previous() {
+    PREVIOUS.lock().valid = false;
+}
+
+/// Records survive a reformat check and corruption is caught
+fn selftest() -> Result<(), &'static str> {
+    let mut bytes = alloc::vec![0u8; PSTORE_SIZE];
+    let mut region = Region { bytes: &mut bytes };
+    if region.header_valid() {
+        return Err("blank region accepted");
+    }
+    region.format(3);
+    region.console_write(b"hello ");
+    region.console_write(b"world");
+    region.write_panic(42, format_args!("oops {}", 1));
+    if !region.header_valid() || region.u32(HEADER_BOOTS) != 3 {
+        return Err("header");
+    }
+    if region.panic_record() != Some((42, "oops 1")) {
+        return Err("panic record");
+    }
+    if region.console() != (&b"hello world"[..], &[][..]) {
+        return Err("console");
+    }
+
+    // Wrap the console ring
+    region.set_u64(HEADER_WRITTEN, CONSOLE_SIZE as u64 - 3);
+    region.console_write(b"abcdef");
+    let (older, newer) = region.console();
+    if !older.ends_with(b"abc") || newer != b"def" {
+        return Err("console wrap");
+    }
+
+    region.bytes[PANIC_OFFSET + 9] ^= 1;
+    if region.panic_record().is_some() {
+        return Err("corrupt panic record accepted");
+    }
+    region.bytes[HEADER_BOOTS] ^= 1;
+    if region.header_valid() {
+        return Err("corrupt header accepted");
+    }
+    Ok(())
+}
+
+crate::selftest!("pstore", selftest);
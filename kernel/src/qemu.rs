@@ -0,0 +1,176 @@
+//! QEMU integration for scripted runs
+//!
+//! `isa-debug-exit` turns a port write into QEMU's process exit status, so
+//! a script can tell success from failure without scraping the log. An
+//! automated run is requested through fw_cfg:
+//!
+//! ```text
+//! qemu-system-x86_64 ... \
+//!     -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+//!     -fw_cfg name=opt/cosmos/selftest,string=1
+//! ```
+//!
+//! The kernel then runs every self-test and exits with [`ExitCode::Success`]
+//! or [`ExitCode::Failure`]; panics exit with `Failure` too. With
+//! `name=opt/cosmos/bench,string=1` instead, a kernel built with the
+//! `bench` feature runs every benchmark and prints its results on serial.
+
+use alloc::vec::Vec;
+use crate::arch::x86_64::port::Port;
+
+/// Default `isa-debug-exit` I/O port
+pub const DEBUG_EXIT_PORT: u16 = 0xF4;
+
+/// fw_cfg selector and data ports
+const FW_CFG_SELECTOR: u16 = 0x510;
+const FW_CFG_DATA: u16 = 0x511;
+/// fw_cfg keys
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+/// Length of a file name in the fw_cfg directory
+const FW_CFG_NAME_LEN: usize = 56;
+
+/// fw_cfg file that requests an automated self-test run
+pub const SELFTEST_FILE: &str = "opt/cosmos/selftest";
+/// fw_cfg file that requests an automated benchmark run
+pub const BENCH_FILE: &str = "opt/cosmos/bench";
+
+/// CPUID.1:ECX hypervisor present
+const CPUID_HYPERVISOR: u32 = 1 << 31;
+
+/// Values written to the debug exit port
+///
+/// QEMU exits with `(code << 1) | 1`, so 33 for success and 35 for failure.
+/// Neither can be confused with QEMU's own exit status of 0 or 1.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+/// Check whether we run under a hypervisor
+///
+/// Guards the emulator-only ports below, which may belong to something else
+/// on real hardware.
+pub fn is_virtualized() -> bool {
+    let leaf = unsafe { core::arch::x86_64::__cpuid(1) };
+    leaf.ecx & CPUID_HYPERVISOR != 0
+}
+
+/// Exit QEMU with the given code
+///
+/// Halts if no `isa-debug-exit` device is present.
+pub fn exit(code: ExitCode) -> ! {
+    x86_64::instructions::interrupts::disable();
+    if is_virtualized() {
+        unsafe {
+            Port::<u32>::new(DEBUG_EXIT_PORT).write(code as u32);
+        }
+    }
+    crate::serial_println!("QEMU exit device not present, halting");
+    crate::hlt_loop()
+}
+
+/// Select a fw_cfg item and read `buf.len()` bytes of it
+fn fw_cfg_read(key: u16, buf: &mut [u8]) {
+    unsafe {
+        Port::<u16>::new(FW_CFG_SELECTOR).write(key);
+        let data = Port::<u8>::new(FW_CFG_DATA);
+        for byte in buf.iter_mut() {
+            *byte = data.read();
+        }
+    }
+}
+
+/// Check for QEMU's fw_cfg interface
+fn has_fw_cfg() -> bool {
+    if !is_virtualized() {
+        return false;
+    }
+    let mut signature = [0u8; 4];
+    fw_cfg_read(FW_CFG_SIGNATURE, &mut signature);
+    &signature == b"QEMU"
+}
+
+/// Contents of a fw_cfg file passed with `-fw_cfg name=...`
+pub fn fw_cfg_file(name: &str) -> Option<Vec<u8>> {
+    if !has_fw_cfg() {
+        return None;
+    }
+
+    // Directory: big-endian count, then {size, select, reserved, name} entries.
+    // The data port keeps advancing through the item while nothing else selects.
+    let mut count = [0u8; 4];
+    fw_cfg_read(FW_CFG_FILE_DIR, &mut count);
+    let mut found = None;
+    let data = Port::<u8>::new(FW_CFG_DATA);
+    for _ in 0..u32::from_be_bytes(count) {
+        let mut entry = [0u8; 8 + FW_CFG_NAME_LEN];
+        for byte in entry.iter_mut() {
+            *byte = unsafe { data.read() };
+        }
+        let entry_name = &entry[8..];
+        let len = entry_name.iter().position(|&b| b == 0).unwrap_or(FW_CFG_NAME_LEN);
+        if found.is_none() && &entry_name[..len] == name.as_bytes() {
+            let size = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            let select = u16::from_be_bytes([entry[4], entry[5]]);
+            found = Some((select, size as usize));
+        }
+    }
+
+    let (select, size) = found?;
+    let mut contents = alloc::vec![0u8; size];
+    fw_cfg_read(select, &mut contents);
+    Some(contents)
+}
+
+/// Check whether this boot is a scripted self-test run
+#[cfg(feature = "selftest")]
+pub fn is_selftest_run() -> bool {
+    fw_cfg_file(SELFTEST_FILE).is_some()
+}
+
+/// Run every self-test on the console and exit QEMU with the result
+///
+/// Each result also goes to the host channel as a JSON line, see
+/// [`crate::selftest::record`].
+#[cfg(feature = "selftest")]
+pub fn run_selftests() -> ! {
+    use core::fmt::Write;
+    use crate::selftest;
+
+    crate::power::set_panic_action(crate::power::PanicAction::QemuExit);
+    let mut out = crate::console::Console;
+    let mut host = crate::console::Host;
+    let mut failed = 0;
+    for test in selftest::tests() {
+        let outcome = selftest::run(test);
+        let _ = selftest::report(test, &outcome, &mut out);
+        let _ = selftest::record(test, &outcome, &mut host);
+        failed += outcome.result.is_err() as usize;
+    }
+    let _ = writeln!(out, "{} passed, {} failed", selftest::tests().len() - failed, failed);
+    if failed == 0 {
+        exit(ExitCode::Success)
+    } else {
+        exit(ExitCode::Failure)
+    }
+}
+
+/// Check whether this boot is a scripted benchmark run
+#[cfg(feature = "bench")]
+pub fn is_bench_run() -> bool {
+    fw_cfg_file(BENCH_FILE).is_some()
+}
+
+/// Run every benchmark on serial and exit QEMU, with `Failure` if any
+/// could not run
+#[cfg(feature = "bench")]
+pub fn run_benches() -> ! {
+    crate::power::set_panic_action(crate::power::PanicAction::QemuExit);
+    match crate::bench::run_all(&mut crate::serial::Serial) {
+        Ok(0) => exit(ExitCode::Success),
+        _ => exit(ExitCode::Failure),
+    }
+}
@@ -0,0 +1,157 @@
+//! In-kernel self-tests
+//!
+//! Subsystems declare tests next to their code with [`selftest!`]. Entries
+//! are collected in the `.selftests` section, so tests exist from boot
+//! without any registration calls, and can be run from the shell.
+
+use core::fmt::Write;
+
+/// Test body, `Err` carries a short description of what went wrong
+pub type TestFn = fn() -> Result<(), &'static str>;
+
+/// Named self-test
+#[repr(C)]
+pub struct SelfTest {
+    pub name: &'static str,
+    pub run: TestFn,
+}
+
+/// Declare a self-test
+///
+/// ```ignore
+/// selftest!("heap", test_heap);
+/// ```
+#[macro_export]
+macro_rules! selftest {
+    ($name:expr, $run:path) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".selftests"]
+            static TEST: $crate::selftest::SelfTest = $crate::selftest::SelfTest {
+                name: $name,
+                run: $run,
+            };
+        };
+    };
+}
+
+// Bounds of `.selftests`, defined by the linker script
+unsafe extern "C" {
+    static __selftests_start: u8;
+    static __selftests_end: u8;
+}
+
+/// All declared tests
+pub fn tests() -> &'static [SelfTest] {
+    unsafe {
+        let start = &raw const __selftests_start as *const SelfTest;
+        let end = &raw const __selftests_end as *const SelfTest;
+        let count = (end as usize - start as usize) / core::mem::size_of::<SelfTest>();
+        core::slice::from_raw_parts(start, count)
+    }
+}
+
+/// Look up a test by name
+pub fn find(name: &str) -> Option<&'static SelfTest> {
+    tests().iter().find(|test| test.name == name)
+}
+
+/// Result of one test run
+#[derive(Debug, Clone, Copy)]
+pub struct Outcome {
+    pub result: Result<(), &'static str>,
+    /// TSC cycles the test took
+    pub cycles: u64,
+}
+
+/// Run one test
+pub fn run(test: &SelfTest) -> Outcome {
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    let result = (test.run)();
+    let end = unsafe { core::arch::x86_64::_rdtsc() };
+    Outcome { result, cycles: end.wrapping_sub(start) }
+}
+
+/// Run one test and print a PASS/FAIL line, returns whether it passed
+pub fn run_and_report(test: &SelfTest, out: &mut dyn Write) -> Result<bool, core::fmt::Error> {
+    let outcome = run(test);
+    report(test, &outcome, out)?;
+    Ok(outcome.result.is_ok())
+}
+
+/// Print the PASS/FAIL line for a finished test
+pub fn report(test: &SelfTest, outcome: &Outcome, out: &mut dyn Write) -> Result<(), core::fmt::Error> {
+    match outcome.result {
+        Ok(()) => writeln!(out, "PASS  {:<20} {:>12} cycles", test.name, outcome.cycles)?,
+        Err(reason) => writeln!(out, "FAIL  {:<20} {:>12} cycles  {}", test.name, outcome.cycles, reason)?,
+    }
+    Ok(())
+}
+
+/// Write a finished test as one JSON line, for the host to parse
+pub fn record(test: &SelfTest, outcome: &Outcome, out: &mut dyn Write) -> Result<(), core::fmt::Error> {
+    use crate::console::write_json_string;
+
+    out.write_str("{\"test\":")?;
+    write_json_string(out, test.name)?;
+    write!(out, ",\"pass\":{},\"cycles\":{}", outcome.result.is_ok(), outcome.cycles)?;
+    if let Err(reason) = outcome.result {
+        out.write_str(",\"reason\":")?;
+        write_json_string(out, reason)?;
+    }
+    out.write_str("}\n")
+}
+
+/// Run every test, returns (passed, failed)
+pub fn run_all(out: &mut dyn Write) -> Result<(usize, usize), core::fmt::Error> {
+    let mut passed = 0;
+    let mut failed = 0;
+    for test in tests() {
+        if run_and_report(test, out)? {
+            passed += 1;
+        } else {
+            failed += 1;
+        }
+    }
+    writeln!(out, "{} passed, {} failed", passed, failed)?;
+    Ok((passed, failed))
+}
+
+/// Memory routines used by `core` (memcpy, memmove, memset, memcmp)
+fn test_string_routines() -> Result<(), &'static str> {
+    let mut buffer = [0u8; 64];
+    let source: [u8; 32] = core::array::from_fn(|i| i as u8);
+
+    // Unaligned copy
+    buffer[3..35].copy_from_slice(&source);
+    if buffer[3..35] != source || buffer[2] != 0 || buffer[35] != 0 {
+        return Err("memcpy");
+    }
+
+    // Overlapping copy forwards and backwards
+    buffer.copy_within(3..35, 5);
+    if buffer[5..37] != source {
+        return Err("memmove forward");
+    }
+    buffer.copy_within(5..37, 1);
+    if buffer[1..33] != source {
+        return Err("memmove backward");
+    }
+
+    buffer.fill(0xA5);
+    if buffer.iter().any(|&b| b != 0xA5) {
+        return Err("memset");
+    }
+
+    let mut other = buffer;
+    if buffer != other {
+        return Err("memcmp equal");
+    }
+    other[63] = 0;
+    if buffer == other || buffer[..] <= other[..] {
+        return Err("memcmp order");
+    }
+    Ok(())
+}
+
+selftest!("string", test_string_routines);
@@ -1,31 +1,117 @@
 //! Serial port driver for debugging
 
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
+/// COM1 base I/O port
+pub const COM1_BASE: u16 = 0x3F8;
+
+/// Legacy IRQ line of COM1, raised when a byte is received
+pub const COM1_IRQ: u8 = 4;
+
+/// Set once COM1 has been programmed
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// COM1 writer, translates `\n` to `\r\n` for terminal output
+pub struct SerialWriter {
+    port: SerialPort,
+}
+
+impl SerialWriter {
+    /// Write a single byte
+    pub fn write_byte(&mut self, byte: u8) {
+        if byte == b'\n' {
+            self.port.send(b'\r');
+        }
+        self.port.send(byte);
+    }
+}
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Same line endings as the bootloader's log
+        cosmos_common::fmt::crlf(s, |byte| self.port.send(byte));
+        Ok(())
+    }
+}
+
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    pub static ref SERIAL1: Mutex<SerialWriter> = {
+        // The first claim of the boot, it cannot conflict
+        let _ = crate::arch::x86_64::port::claim(COM1_BASE, 8, "serial");
+        let mut serial_port = unsafe { SerialPort::new(COM1_BASE) };
         serial_port.init();
-        Mutex::new(serial_port)
+        INITIALIZED.store(true, Ordering::Release);
+        Mutex::new(SerialWriter { port: serial_port })
     };
 }
 
-#[doc(hidden)]
-pub fn _print(args: core::fmt::Arguments) {
+/// Initialize COM1, safe to call more than once
+pub fn init() {
+    lazy_static::initialize(&SERIAL1);
+}
+
+/// Check if COM1 has been initialized
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Write a single byte, with interrupts disabled while the lock is held
+pub fn write_byte(byte: u8) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        SERIAL1.lock().write_byte(byte);
+    });
+}
+
+/// Write a string, with interrupts disabled while the lock is held
+pub fn write_str(s: &str) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
+        let _ = SERIAL1.lock().write_str(s);
     });
 }
 
-/// Print to the serial port
+/// Read a received byte without blocking
+pub fn try_read_byte() -> Option<u8> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| SERIAL1.lock().port.try_receive().ok())
+}
+
+/// Unlocked `fmt::Write` sink for COM1, locks per write
+pub struct Serial;
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_str(s);
+        Ok(())
+    }
+}
+
+/// Forcibly release the serial lock
+///
+/// # Safety
+/// Only for the panic path, where the lock holder will never run again.
+pub unsafe fn force_unlock() {
+    if is_initialized() {
+        SERIAL1.force_unlock();
+    }
+}
+
+/// Kernel log output, fanned out by the console multiplexer
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    crate::console::_print(args);
+}
+
+/// Print to the kernel console, COM1 and any other selected sinks
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => {
@@ -33,7 +119,7 @@ macro_rules! serial_print {
     };
 }
 
-/// Print to the serial port with a newline
+/// Print to the kernel console with a newline
 #[macro_export]
 macro_rules! serial_println {
     () => ($crate::serial_print!("\n"));
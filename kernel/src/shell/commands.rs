@@ -0,0 +1,944 @@
+//! Built-in Shell Commands
+
+use core::fmt::Write;
+use super::{Command, ShellError};
+use crate::arch::x86_64::pic;
+use crate::mm::inspect::{self, Span, Width};
+use crate::power::{self, PanicAction};
+use crate::stats;
+
+/// Commands always available
+pub static BUILTINS: &[Command] = &[
+    Command { name: "help", help: "List available commands", run: help },
+    Command { name: "echo", help: "Print the arguments: echo <text>", run: echo },
+    Command { name: "bootstat", help: "Show boot stage timings", run: bootstat },
+    Command { name: "cpu", help: "Show busy, idle and interrupt time per CPU, since boot or over an interval: cpu [ms]", run: cpu },
+    Command { name: "cpuinfo", help: "Show CPU model, topology, caches and TLBs", run: cpuinfo },
+    Command { name: "mitigations", help: "Show CPU vulnerabilities and mitigations", run: mitigations },
+    Command { name: "mtrr", help: "Show the PAT and MTRR memory types", run: mtrr },
+    Command { name: "layout", help: "Show the kernel's fixed memory regions", run: layout },
+    Command { name: "meminfo", help: "Show heap and frame allocator statistics", run: meminfo },
+    Command { name: "memmap", help: "Show the bootloader's memory map", run: memmap },
+    Command { name: "peek", help: "Read memory: peek <addr> [1|2|4|8]", run: peek },
+    Command { name: "poke", help: "Write memory: poke <addr> <value> [1|2|4|8]", run: poke },
+    Command { name: "hexdump", help: "Dump memory as hex: hexdump <addr> <len>", run: hexdump },
+    Command { name: "search", help: "Find bytes in memory: search <addr> <len> <0xhex | text>", run: search },
+    Command { name: "vtop", help: "Walk the page tables for an address: vtop <addr>", run: vtop },
+    Command { name: "ioports", help: "Show which driver owns each I/O port range", run: ioports },
+    Command { name: "interrupts", help: "Show interrupt counts and handler latency", run: interrupts },
+    #[cfg(feature = "modules")]
+    Command { name: "insmod", help: "Load a module: insmod <name> <address> <size>", run: insmod },
+    #[cfg(feature = "modules")]
+    Command { name: "rmmod", help: "Unload a module: rmmod <name>", run: rmmod },
+    #[cfg(feature = "modules")]
+    Command { name: "lsmod", help: "List loaded modules", run: lsmod },
+    #[cfg(feature = "profiler")]
+    Command { name: "profile", help: "Sampling profiler: profile [start | stop | reset | <top N>]", run: profile },
+    #[cfg(feature = "kmemleak")]
+    Command { name: "leaks", help: "Heap leak tracking: leaks [on | off | <min age secs>]", run: leaks },
+    Command { name: "reboot", help: "Restart the machine", run: reboot },
+    #[cfg(feature = "bench")]
+    Command { name: "bench", help: "Run micro-benchmarks: bench [list | <name>...]", run: bench },
+    #[cfg(feature = "selftest")]
+    Command { name: "selftest", help: "Run self-tests: selftest [list | <name>...]", run: selftest },
+    Command { name: "test", help: "Raise an exception: test int3 | divzero | pagefault <addr> | df", run: test },
+    Command { name: "sleep", help: "Sleep for the given milliseconds", run: sleep },
+    Command { name: "ps", help: "List tasks", run: ps },
+    Command { name: "top", help: "Show load and CPU use per task over an interval: top [ms]", run: top },
+    Command { name: "kworkers", help: "List worker threads with their jobs and panics", run: kworkers },
+    Command { name: "nice", help: "Show or set a task's priority: nice <task> [low | normal | high]", run: nice },
+    Command { name: "lsdev", help: "List device files in /dev", run: lsdev },
+    #[cfg(feature = "xmodem")]
+    Command { name: "recv", help: "Receive a file into /tmp over the serial console by XMODEM: recv <name> [bytes]", run: recv },
+    #[cfg(feature = "fs")]
+    Command { name: "tmpfs", help: "List files in /tmp, or remove one: tmpfs [rm <name>]", run: tmpfs },
+    Command { name: "lsinput", help: "List input devices", run: lsinput },
+    #[cfg(feature = "pci")]
+    Command { name: "lspci", help: "List PCI functions", run: lspci },
+    #[cfg(feature = "usb")]
+    Command { name: "lsusb", help: "List USB devices", run: lsusb },
+    Command { name: "dmesg", help: "Show the kernel log", run: dmesg },
+    Command { name: "pstore", help: "Show or clear what the previous boot left: pstore [clear]", run: pstore },
+    Command { name: "crashdump", help: "Crash dump target: crashdump [serial | <device> | off | write]", run: crashdump },
+    Command { name: "console", help: "Show or select log sinks: console [<sink>,...]", run: console },
+    Command { name: "chvt", help: "Show virtual consoles, or switch: chvt <n>", run: chvt },
+    Command { name: "keymap", help: "Show keyboard layouts, or switch: keymap <name>", run: keymap },
+    #[cfg(feature = "sound")]
+    Command { name: "beep", help: "Sound a tone: beep [hz] [ms]", run: beep },
+    Command { name: "font", help: "List fonts, or font <name> | scale <n>", run: font },
+    Command { name: "kill", help: "Send a signal: kill <task> [signal]", run: kill },
+    #[cfg(feature = "trace")]
+    Command { name: "trace", help: "Event tracing: trace [start | stop | clear | dump]", run: trace },
+    Command { name: "version", help: "Show the kernel version and build information", run: version },
+    Command { name: "uptime", help: "Show time since boot", run: uptime },
+    Command { name: "date", help: "Show the current date and time (UTC)", run: date },
+    Command { name: "shutdown", help: "Power off the machine", run: shutdown },
+    Command { name: "suspend", help: "Suspend to RAM (ACPI S3)", run: suspend },
+    Command { name: "panic-action", help: "Show or set panic behavior: halt, reboot, shutdown, qemu-exit", run: panic_action },
+];
+
+fn help(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    for command in super::commands() {
+        writeln!(out, "  {:<12} {}", command.name, command.help)?;
+    }
+    Ok(())
+}
+
+fn echo(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    writeln!(out, "{}", args.join(" "))?;
+    Ok(())
+}
+
+fn bootstat(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+    crate::bootstat::report(out)?;
+    Ok(())
+}
+
+fn cpuinfo(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+    let location = crate::arch::x86_64::cpu::current_location();
+    crate::arch::x86_64::cpuid::cpuinfo().report(out)?;
+    writeln!(out, "this CPU: package {} core {} thread {}", location.package, location.core, location.thread)?;
+    Ok(())
+}
+
+fn mitigations(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+    crate::arch::x86_64::mitigations::report(out)?;
+    Ok(())
+}
+
+fn mtrr(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+    crate::arch::x86_64::memtype::report(out)?;
+    Ok(())
+}
+
+fn ioports(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+    crate::arch::x86_64::port::report(out)?;
+    Ok(())
+}
+
+fn layout(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+    crate::mm::layout::report(out)?;
+    Ok(())
+}
+
+fn version(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+    crate::version::report(out)?;
+    Ok(())
+}
+
+fn cpu(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    let mut cpus = stats::cpus();
+    match args {
+        [] => {}
+        [ms] => {
+            let ms = ms.parse().map_err(|_| ShellError::InvalidArguments)?;
+            crate::time::sleep_ms(ms);
+            cpus = stats::cpus().iter().zip(&cpus).map(|(after, before)| after.since(before)).collect();
+        }
+        _ => return Err(ShellError::InvalidArguments),
+    }
+
+    writeln!(out, "idle method: {}", crate::idle::method().name())?;
+    writeln!(out, "CPU  BUSY%  IDLE%   IRQ%  HALTED%   IDLE ENTRIES")?;
+    for cpu in cpus {
+        writeln!(out, "{:>3}  {:>4}%  {:>4}%  {:>4}%  {:>6}%  {:>13}", cpu.cpu, cpu.busy_percent(), cpu.idle_percent(),
+            cpu.irq_percent(), cpu.percent(cpu.halted_cycles), cpu.idle_entries)?;
+    }
+    Ok(())
+}
+
+fn meminfo(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+    stats::memory().write_report(out)?;
+    Ok(())
+}
+
+fn memmap(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+    match crate::mm::memory_map::MemoryMap::boot_map() {
+        Some(map) => map.report(out)?,
+        None => writeln!(out, "Memory map not read yet")?,
+    }
+    Ok(())
+}
+
+/// Most bytes `hexdump` prints
+const HEXDUMP_MAX: u64 = 64 * 1024;
+/// Most bytes `search` scans
+const SEARCH_MAX: u64 = 64 * 1024 * 1024;
+/// Matches `search` lists before giving up
+const SEARCH_MATCHES: usize = 16;
+
+/// Access width argument, 8 bytes if left out
+fn parse_width(text: Option<&&str>) -> Result<Width, ShellError> {
+    match text {
+        None => Ok(Width::Qword),
+        Some(text) => Width::from_bytes(parse_number(text)? as u64).ok_or(ShellError::InvalidArguments),
+    }
+}
+
+/// Bytes to search for, `0x`-prefixed hex in memory order or plain text
+fn parse_pattern(text: &str) -> Result<alloc::vec::Vec<u8>, ShellError> {
+    let Some(hex) = text.strip_prefix("0x") else {
+        return Ok(text.as_bytes().to_vec());
+    };
+    if hex.is_empty() || hex.len() % 2 != 0 {
+        return Err(ShellError::InvalidArguments);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<_>>()
+        .ok_or(ShellError::InvalidArguments)
+}
+
+fn peek(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    let [addr, width @ ..] = args else {
+        return Err(ShellError::InvalidArguments);
+    };
+    if width.len() > 1 {
+        return Err(ShellError::InvalidArguments);
+    }
+    let addr = parse_number(addr)? as u64;
+    let width = parse_width(width.first())?;
+    match inspect::read(addr, width) {
+        Ok(value) => writeln!(out, "{:#x}: {:#0w$x}", addr, value, w = 2 + 2 * width.bytes() as usize)?,
+        Err(e) => writeln!(out, "peek: {}", e)?,
+    }
+    Ok(())
+}
+
+fn poke(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    let [addr, value, width @ ..] = args else {
+        return Err(ShellError::InvalidArguments);
+    };
+    if width.len() > 1 {
+        return Err(ShellError::InvalidArguments);
+    }
+    let addr = parse_number(addr)? as u64;
+    let value = parse_number(value)? as u64;
+    let width = parse_width(width.first())?;
+    if value > width.max() {
+        return Err(ShellError::InvalidArguments);
+    }
+    if let Err(e) = inspect::write(addr, width, value) {
+        writeln!(out, "poke: {}", e)?;
+    }
+    Ok(())
+}
+
+fn hexdump(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    let [addr, len] = args else {
+        return Err(ShellError::InvalidArguments);
+    };
+    let addr = parse_number(addr)? as u64;
+    let len = parse_number(len)? as u64;
+    if len > HEXDUMP_MAX {
+        writeln!(out, "hexdump: At most {} bytes at a time", HEXDUMP_MAX)?;
+        return Ok(());
+    }
+    match Span::new(addr, len, false) {
+        Ok(span) => span.hexdump(out)?,
+        Err(e) => writeln!(out, "hexdump: {}", e)?,
+    }
+    Ok(())
+}
+
+fn search(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    let [addr, len, pattern] = args else {
+        return Err(ShellError::InvalidArguments);
+    };
+    let addr = parse_number(addr)? as u64;
+    let len = parse_number(len)? as u64;
+    let pattern = parse_pattern(pattern)?;
+    if len > SEARCH_MAX {
+        writeln!(out, "search: At most {} bytes at a time", SEARCH_MAX)?;
+        return Ok(());
+    }
+    let span = match Span::new(addr, len, false) {
+        Ok(span) => span,
+        Err(e) => {
+            writeln!(out, "search: {}", e)?;
+            return Ok(());
+        }
+    };
+    let mut from = span.start();
+    let mut found = 0;
+    while let Some(at) = span.find(&pattern, from) {
+        if found == SEARCH_MATCHES {
+            writeln!(out, "More matches after {:#x}", at)?;
+            return Ok(());
+        }
+        writeln!(out, "{:#x}", at)?;
+        found += 1;
+        from = at + 1;
+    }
+    if found == 0 {
+        writeln!(out, "Not found")?;
+    }
+    Ok(())
+}
+
+fn vtop(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    let [addr] = args else {
+        return Err(ShellError::InvalidArguments);
+    };
+    let addr = parse_number(addr)? as u64;
+    match crate::mm::paging::Walk::new(addr) {
+        Some(walk) => walk.report(out)?,
+        None => writeln!(out, "vtop: {:#x} is not a canonical address", addr)?,
+    }
+    Ok(())
+}
+
+fn test(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::arch::x86_64::inject::{self, Injection};
+
+    let injection = match args {
+        ["int3"] => Injection::Breakpoint,
+        ["divzero"] => Injection::DivideError,
+        ["pagefault", addr] => Injection::PageFault(parse_number(addr)? as u64),
+        ["df"] => Injection::DoubleFault,
+        _ => return Err(ShellError::InvalidArguments),
+    };
+    inject::report(injection, out)?;
+    Ok(())
+}
+
+fn interrupts(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+
+    let stats = stats::interrupts();
+    writeln!(out, "VECTOR  IRQ        COUNT  MAX CYCLES")?;
+    for v in &stats.vectors {
+        match pic::vector_to_irq(v.vector) {
+            Some(irq) => write!(out, "{:>6}  {:>3}", v.vector, irq)?,
+            None => write!(out, "{:>6}    -", v.vector)?,
+        }
+        writeln!(out, "  {:>11}  {:>10}", v.count, v.max_latency_cycles)?;
+    }
+    writeln!(out, "total: {}  spurious: {}  unhandled: {}  max nesting: {}",
+        stats.total, stats.spurious, stats.unhandled, stats.max_nesting)?;
+    Ok(())
+}
+
+/// Parse a decimal or `0x`-prefixed hex number
+fn parse_number(text: &str) -> Result<usize, ShellError> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| ShellError::InvalidArguments)
+}
+
+#[cfg(feature = "modules")]
+fn insmod(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    let [name, address, size] = args else {
+        return Err(ShellError::InvalidArguments);
+    };
+    let address = parse_number(address)?;
+    let size = parse_number(size)?;
+    let end = address.checked_add(size).ok_or(ShellError::InvalidArguments)?;
+
+    // The image is read in place, e.g. after QEMU's `-device loader` put it there
+    let mapped = (address & !0xFFF..end).step_by(0x1000).all(|page| crate::mm::paging::is_mapped(page as u64));
+    if size == 0 || !mapped {
+        writeln!(out, "{:#x}..{:#x} is not mapped", address, end)?;
+        return Ok(());
+    }
+
+    let object = unsafe { core::slice::from_raw_parts(address as *const u8, size) };
+    if let Err(e) = crate::module::load(name, object) {
+        writeln!(out, "{}: {}", name, e)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "modules")]
+fn rmmod(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    let [name] = args else {
+        return Err(ShellError::InvalidArguments);
+    };
+    if let Err(e) = crate::module::unload(name) {
+        writeln!(out, "{}: {}", name, e)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "modules")]
+fn lsmod(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    writeln!(out, "MODULE            ADDRESS        SIZE")?;
+    for (name, base, size) in crate::module::modules() {
+        writeln!(out, "{:<16}  {:#012x}  {:>8}", name, base, size)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "bench")]
+fn bench(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    match args {
+        [] => {
+            crate::bench::run_all(out)?;
+        }
+        ["list"] => {
+            for bench in crate::bench::BENCHES {
+                writeln!(out, "  {}", bench.name)?;
+            }
+        }
+        names => {
+            for name in names {
+                match crate::bench::find(name) {
+                    Some(bench) => {
+                        crate::bench::run_and_report(bench, out)?;
+                    }
+                    None => writeln!(out, "no such benchmark: {}", name)?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "selftest")]
+fn selftest(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    match args {
+        [] => {
+            crate::selftest::run_all(out)?;
+        }
+        ["list"] => {
+            for test in crate::selftest::tests() {
+                writeln!(out, "  {}", test.name)?;
+            }
+        }
+        names => {
+            for name in names {
+                match crate::selftest::find(name) {
+                    Some(test) => {
+                        crate::selftest::run_and_report(test, out)?;
+                    }
+                    None => writeln!(out, "no such test: {}", name)?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn sleep(_out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    let [ms] = args else {
+        return Err(ShellError::InvalidArguments);
+    };
+    let ms = ms.parse().map_err(|_| ShellError::InvalidArguments)?;
+    crate::time::sleep_ms(ms);
+    Ok(())
+}
+
+fn ps(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    writeln!(out, "{:>5}  {:<8}  {:<6}  NAME", "ID", "STATE", "PRI")?;
+    for task in crate::task::scheduler::task_stats() {
+        writeln!(out, "{:>5}  {:<8}  {:<6}  {}", task.id.as_u64(), task.state.name(), task.priority.name(), task.name)?;
+    }
+    Ok(())
+}
+
+/// Interval `top` measures over by default
+const TOP_INTERVAL_MS: u64 = 1000;
+
+fn top(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::task::{load, scheduler};
+
+    let ms = match args {
+        [] => TOP_INTERVAL_MS,
+        [ms] => ms.parse().map_err(|_| ShellError::InvalidArguments)?,
+        _ => return Err(ShellError::InvalidArguments),
+    };
+    let before = scheduler::task_stats();
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    crate::time::sleep_ms(ms);
+    let elapsed = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(start).max(1);
+    let mut tasks = scheduler::task_stats();
+
+    for cpu in load::cpus() {
+        let [one, five, fifteen] = cpu.averages;
+        writeln!(out, "cpu {}: load average {} {} {}, {} switches", cpu.cpu, one, five, fifteen, cpu.switches)?;
+    }
+    writeln!(out, "{} runnable", scheduler::runnable())?;
+
+    // Share of the interval, tasks started during it count from zero
+    let usage = |task: &scheduler::TaskStats| {
+        let previous = before.iter().find(|b| b.id == task.id).map_or(0, |b| b.runtime);
+        (task.runtime.saturating_sub(previous) as u128 * 100 / elapsed as u128) as u64
+    };
+    tasks.sort_by_key(|task| core::cmp::Reverse(usage(task)));
+    let hz = crate::time::tsc_hz();
+    writeln!(out, "{:>5}  {:<8}  {:<6}  {:>4}  {:>10}  {:>8}  NAME", "ID", "STATE", "PRI", "CPU%", "TIME(ms)", "SWITCHES")?;
+    for task in &tasks {
+        write!(out, "{:>5}  {:<8}  {:<6}  {:>4}  ", task.id.as_u64(), task.state.name(), task.priority.name(), usage(task))?;
+        match hz {
+            Some(hz) => write!(out, "{:>10}", task.runtime as u128 * 1000 / hz as u128)?,
+            None => write!(out, "{:>10}", "-")?,
+        }
+        writeln!(out, "  {:>8}  {}", task.switches, task.name)?;
+    }
+    Ok(())
+}
+
+fn kworkers(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::task::kworker;
+
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArguments);
+    }
+    let hz = crate::time::tsc_hz();
+    writeln!(out, "{:>5}  {:<9}  {:<5}  {:>8}  {:>6}  {:>10}  NAME", "ID", "KIND", "STATE", "JOBS", "PANICS", "TIME(ms)")?;
+    for worker in kworker::workers() {
+        let state = if worker.alive { "alive" } else { "ended" };
+        write!(out, "{:>5}  {:<9}  {:<5}  {:>8}  {:>6}  ", worker.id.as_u64(), worker.kind.name(), state, worker.jobs, worker.panics)?;
+        match hz {
+            Some(hz) => write!(out, "{:>10}", worker.runtime as u128 * 1000 / hz as u128)?,
+            None => write!(out, "{:>10}", "-")?,
+        }
+        writeln!(out, "  {}", worker.name)?;
+    }
+    writeln!(out, "{} jobs queued", kworker::queued())?;
+    Ok(())
+}
+
+fn nice(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::task::{scheduler, Priority, TaskId};
+
+    let (id, priority) = match args {
+        [id] => (id, None),
+        [id, name] => (id, Some(Priority::from_name(name).ok_or(ShellError::InvalidArguments)?)),
+        _ => return Err(ShellError::InvalidArguments),
+    };
+    let id = TaskId::from_u64(id.parse().map_err(|_| ShellError::InvalidArguments)?);
+    match priority {
+        Some(priority) => {
+            if let Err(e) = scheduler::set_priority(id, priority) {
+                writeln!(out, "nice: {}", e)?;
+            }
+        }
+        None => match scheduler::priority(id) {
+            Some(priority) => writeln!(out, "{}", priority.name())?,
+            None => writeln!(out, "nice: {}", scheduler::SchedulerError::NoTask)?,
+        },
+    }
+    Ok(())
+}
+
+fn lsinput(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    for (id, name, kind, events) in crate::input::devices() {
+        writeln!(out, "{:>3} {:<10} {:<8} {} events", id.as_u32(), name, kind.name(), events)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "pci")]
+fn lspci(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    for device in crate::pci::devices() {
+        writeln!(
+            out,
+            "{} {:04x}:{:04x} [{:02x}{:02x}{:02x}] {}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if,
+            device.class_name()
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "usb")]
+fn lsusb(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    for (controller, port, vendor, product, speed, driver) in crate::usb::xhci::devices() {
+        writeln!(out, "{} port {:<2} {:04x}:{:04x} {:<11} {}", controller, port, vendor, product, speed, driver)?;
+    }
+    Ok(())
+}
+
+fn lsdev(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    for (name, id, kind) in crate::dev::list() {
+        writeln!(out, "{} {:>8}  {}{}", kind, id, crate::dev::DEV_PREFIX, name)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "xmodem")]
+fn recv(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::fs::tmpfs;
+    use crate::xmodem;
+
+    let (name, size) = match args {
+        [name] => (*name, None),
+        [name, size] => (*name, Some(size.parse::<usize>().map_err(|_| ShellError::InvalidArguments)?)),
+        _ => return Err(ShellError::InvalidArguments),
+    };
+    let name = match tmpfs::check_name(name) {
+        Ok(name) => name,
+        Err(e) => {
+            writeln!(out, "recv: {}", e)?;
+            return Ok(());
+        }
+    };
+    writeln!(out, "Receiving {}{}, start the XMODEM send now (Ctrl-X Ctrl-X cancels)", tmpfs::TMP_PREFIX, name)?;
+    let received = xmodem::receive(&mut xmodem::SerialLink::open(), tmpfs::available());
+    let mut data = match received {
+        Ok(data) => data,
+        Err(e) => {
+            writeln!(out, "recv: {}", e)?;
+            return Ok(());
+        }
+    };
+    match size {
+        Some(size) if size > data.len() => {
+            writeln!(out, "recv: {} bytes received, fewer than {}", data.len(), size)?;
+            return Ok(());
+        }
+        Some(size) => data.truncate(size),
+        None => xmodem::trim_padding(&mut data),
+    }
+    let len = data.len();
+    match tmpfs::write(name, data) {
+        Ok(()) => writeln!(out, "{}{}: {} bytes", tmpfs::TMP_PREFIX, name, len)?,
+        Err(e) => writeln!(out, "recv: {}", e)?,
+    }
+    Ok(())
+}
+
+#[cfg(feature = "fs")]
+fn tmpfs(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::fs::tmpfs;
+
+    match args {
+        [] => {
+            for (name, size) in tmpfs::list() {
+                writeln!(out, "{:>10}  {}{}", size, tmpfs::TMP_PREFIX, name)?;
+            }
+            writeln!(out, "{} of {} bytes free", tmpfs::available(), tmpfs::CAPACITY)?;
+        }
+        ["rm", name] => {
+            if let Err(e) = tmpfs::remove(name) {
+                writeln!(out, "tmpfs: {}", e)?;
+            }
+        }
+        _ => return Err(ShellError::InvalidArguments),
+    }
+    Ok(())
+}
+
+fn dmesg(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    use crate::console::klog;
+
+    let mut log = alloc::vec![0u8; klog::KLOG_SIZE];
+    let count = klog::tail(&mut log).unwrap_or(0);
+    out.write_str(&alloc::string::String::from_utf8_lossy(&log[..count]))?;
+    Ok(())
+}
+
+fn pstore(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::pstore;
+
+    match args {
+        [] => {
+            match pstore::boots() {
+                Some(boots) => writeln!(out, "pstore at {:#x}, {} warm boots", pstore::PSTORE_ADDRESS, boots)?,
+                None => writeln!(out, "pstore not in use")?,
+            }
+            pstore::show_previous(out)?;
+        }
+        ["clear"] => pstore::clear_previous(),
+        _ => return Err(ShellError::InvalidArguments),
+    }
+    Ok(())
+}
+
+fn crashdump(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::crashdump;
+
+    match args {
+        [] => match crashdump::target() {
+            Some(target) => writeln!(out, "crash dumps go to {}", target.name())?,
+            None => writeln!(out, "crash dumps off")?,
+        },
+        ["write"] => match crashdump::write(format_args!("requested from the shell")) {
+            Ok(length) => writeln!(out, "wrote {} bytes", length)?,
+            Err(e) => writeln!(out, "crashdump: {}", e)?,
+        },
+        [target] => {
+            if let Err(e) = crashdump::set_target(target) {
+                writeln!(out, "crashdump: {}", e)?;
+            }
+        }
+        _ => return Err(ShellError::InvalidArguments),
+    }
+    Ok(())
+}
+
+fn console(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    match args {
+        [] => {
+            for (sink, present, selected) in crate::console::sinks() {
+                let marker = if present && selected { '*' } else { ' ' };
+                writeln!(out, "{} {:<9} {}", marker, sink.name(), if present { "present" } else { "absent" })?;
+            }
+        }
+        [names] => {
+            if let Err(e) = crate::console::select(names) {
+                writeln!(out, "console: {}", e)?;
+            }
+        }
+        _ => return Err(ShellError::InvalidArguments),
+    }
+    Ok(())
+}
+
+fn chvt(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    match args {
+        [] => crate::vt::report(out)?,
+        [number] => {
+            let number: usize = number.parse().map_err(|_| ShellError::InvalidArguments)?;
+            if let Err(e) = crate::vt::switch(number.wrapping_sub(1)) {
+                writeln!(out, "chvt: {}", e)?;
+            }
+        }
+        _ => return Err(ShellError::InvalidArguments),
+    }
+    Ok(())
+}
+
+fn keymap(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    match args {
+        [] => crate::input::keymap::report(out)?,
+        [name] => {
+            if let Err(e) = crate::input::keymap::set_active(name) {
+                writeln!(out, "keymap: {}", e)?;
+            }
+        }
+        _ => return Err(ShellError::InvalidArguments),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sound")]
+fn beep(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    let (frequency, ms) = match args {
+        [] => (880, 200),
+        [hz] => (hz.parse().map_err(|_| ShellError::InvalidArguments)?, 200),
+        [hz, ms] => (
+            hz.parse().map_err(|_| ShellError::InvalidArguments)?,
+            ms.parse().map_err(|_| ShellError::InvalidArguments)?,
+        ),
+        _ => return Err(ShellError::InvalidArguments),
+    };
+    crate::sound::beep(frequency, ms);
+    writeln!(out, "{} Hz for {} ms on {}", frequency, ms, crate::sound::backend())?;
+    Ok(())
+}
+
+fn font(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::font;
+
+    match args {
+        [] => {
+            let current = font::current();
+            for (name, width, height, glyphs) in font::list() {
+                let marker = if name == current.name() { '*' } else { ' ' };
+                writeln!(out, "{} {:<16} {:>2}x{:<2} {:>4} glyphs", marker, name, width, height, glyphs)?;
+            }
+            writeln!(out, "scale {}", font::scale())?;
+        }
+        ["scale", n] => {
+            let scale = n.parse().map_err(|_| ShellError::InvalidArguments)?;
+            font::set_scale(scale).map_err(|_| ShellError::InvalidArguments)?;
+        }
+        [name] => {
+            if let Err(e) = font::select(name) {
+                writeln!(out, "font: {}", e)?;
+            }
+        }
+        _ => return Err(ShellError::InvalidArguments),
+    }
+    Ok(())
+}
+
+fn kill(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::task::signal::{self, Signal};
+    use crate::task::TaskId;
+
+    let (id, signal) = match args {
+        [id] => (id, Signal::Terminate),
+        [id, name] => (id, Signal::parse(name).ok_or(ShellError::InvalidArguments)?),
+        _ => return Err(ShellError::InvalidArguments),
+    };
+    let id = TaskId::from_u64(id.parse().map_err(|_| ShellError::InvalidArguments)?);
+    if let Err(e) = signal::send(id, signal) {
+        writeln!(out, "kill: {}", e)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "trace")]
+fn trace(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::trace;
+
+    match args {
+        [] => {
+            let (held, lost) = trace::event_count();
+            writeln!(out, "tracing {}, {} events ({} overwritten)",
+                if trace::is_enabled() { "on" } else { "off" }, held, lost)?;
+        }
+        ["start"] => trace::enable(),
+        ["stop"] => trace::disable(),
+        ["clear"] => trace::clear(),
+        ["dump"] => trace::dump(out)?,
+        _ => return Err(ShellError::InvalidArguments),
+    }
+    Ok(())
+}
+
+fn uptime(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    let ms = crate::time::uptime_ms();
+    let secs = ms / 1000;
+    write!(out, "up {}:{:02}:{:02}.{:03}", secs / 3600, secs / 60 % 60, secs % 60, ms % 1000)?;
+    if let Some(cpu) = crate::task::load::cpus().first() {
+        let [one, five, fifteen] = cpu.averages;
+        write!(out, ", load average {} {} {}", one, five, fifteen)?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+fn date(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    writeln!(out, "{} UTC", crate::time::now())?;
+    Ok(())
+}
+
+#[cfg(feature = "profiler")]
+fn profile(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use crate::profiler;
+
+    match args {
+        ["start"] => profiler::enable(),
+        ["stop"] => profiler::disable(),
+        ["reset"] => profiler::reset(),
+        [] | [_] => {
+            let top = match args {
+                [n] => n.parse().map_err(|_| ShellError::InvalidArguments)?,
+                _ => 20,
+            };
+            let (count, dropped) = profiler::sample_count();
+            writeln!(out, "{} samples ({} overwritten), sampling {}",
+                count, dropped, if profiler::is_enabled() { "on" } else { "off" })?;
+            if count == 0 {
+                return Ok(());
+            }
+            if !crate::ksyms::is_available() {
+                writeln!(out, "kernel symbols unavailable, all samples show as ?")?;
+            }
+            writeln!(out, "SAMPLES      %  FUNCTION")?;
+            for (name, samples) in profiler::profile().into_iter().take(top) {
+                writeln!(out, "{:>7}  {:>4}%  {}", samples, samples * 100 / count, name)?;
+            }
+        }
+        _ => return Err(ShellError::InvalidArguments),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "kmemleak")]
+fn leaks(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    use alloc::collections::BTreeMap;
+    use crate::mm::kmemleak;
+
+    let min_age_secs = match args {
+        ["on"] => {
+            kmemleak::enable();
+            return Ok(());
+        }
+        ["off"] => {
+            kmemleak::disable();
+            return Ok(());
+        }
+        [secs] => secs.parse().map_err(|_| ShellError::InvalidArguments)?,
+        [] => kmemleak::DEFAULT_MIN_AGE_SECS,
+        _ => return Err(ShellError::InvalidArguments),
+    };
+    let (tracked, untracked) = kmemleak::counts();
+    writeln!(out, "{} allocations tracked ({} untracked, table full), tracking {}",
+        tracked, untracked, if kmemleak::is_enabled() { "on" } else { "off" })?;
+
+    // (allocations, bytes, oldest timestamp) per call site
+    let mut sites: BTreeMap<usize, (usize, usize, u64)> = BTreeMap::new();
+    for allocation in kmemleak::older_than(min_age_secs * 1000) {
+        let site = sites.entry(kmemleak::call_site(&allocation)).or_insert((0, 0, u64::MAX));
+        site.0 += 1;
+        site.1 += allocation.size;
+        site.2 = site.2.min(allocation.timestamp_ms);
+    }
+    let mut sites: alloc::vec::Vec<_> = sites.into_iter().collect();
+    sites.sort_unstable_by_key(|&(_, (_, bytes, _))| core::cmp::Reverse(bytes));
+
+    let now = crate::time::uptime_ms();
+    writeln!(out, "Unfreed after {}s: {} call sites", min_age_secs, sites.len())?;
+    writeln!(out, " ALLOCS      BYTES  OLDEST  CALL SITE")?;
+    for (site, (count, bytes, oldest)) in sites.into_iter().take(20) {
+        writeln!(out, "{:>7} {:>10} {:>6}s  {}", count, bytes, (now - oldest) / 1000, crate::ksyms::Address(site))?;
+    }
+    Ok(())
+}
+
+fn reboot(_out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    power::reboot()
+}
+
+fn shutdown(_out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    power::shutdown()
+}
+
+fn suspend(out: &mut dyn Write, _args: &[&str]) -> Result<(), ShellError> {
+    match crate::acpi::sleep::suspend_to_ram() {
+        Ok(()) => writeln!(out, "Resumed from S3")?,
+        Err(e) => writeln!(out, "Suspend failed: {}", e)?,
+    }
+    Ok(())
+}
+
+fn panic_action(out: &mut dyn Write, args: &[&str]) -> Result<(), ShellError> {
+    match args {
+        [] => writeln!(out, "{}", power::panic_action().name())?,
+        [name] => power::set_panic_action(PanicAction::from_name(name).ok_or(ShellError::InvalidArguments)?),
+        _ => return Err(ShellError::InvalidArguments),
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//! Kernel Shell
+
+pub mod commands;
+pub mod script;
+
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use crate::serial;
+
+/// Maximum length of an input line
+pub const MAX_LINE: usize = 256;
+
+/// Shell prompt
+pub const PROMPT: &str = "cosmos> ";
+
+/// Command entry point, receives the output sink and arguments after the name
+pub type CommandFn = fn(&mut dyn Write, &[&str]) -> Result<(), ShellError>;
+
+/// Shell command descriptor
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub run: CommandFn,
+}
+
+/// Shell errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellError {
+    UnknownCommand,
+    InvalidArguments,
+    AlreadyRegistered,
+    NotRegistered,
+    Output,
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellError::UnknownCommand => write!(f, "Unknown command"),
+            ShellError::InvalidArguments => write!(f, "Invalid arguments"),
+            ShellError::AlreadyRegistered => write!(f, "Command already registered"),
+            ShellError::NotRegistered => write!(f, "Command not registered"),
+            ShellError::Output => write!(f, "Output error"),
+        }
+    }
+}
+
+impl From<fmt::Error> for ShellError {
+    fn from(_: fmt::Error) -> Self {
+        ShellError::Output
+    }
+}
+
+/// Commands registered at runtime, in addition to the built-ins
+static REGISTERED: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+/// Register a command
+pub fn register(command: Command) -> Result<(), ShellError> {
+    let mut registered = REGISTERED.lock();
+    if commands::BUILTINS.iter().chain(registered.iter()).any(|c| c.name == command.name) {
+        return Err(ShellError::AlreadyRegistered);
+    }
+    registered.push(command);
+    Ok(())
+}
+
+/// Unregister a runtime command
+pub fn unregister(name: &str) -> Result<(), ShellError> {
+    let mut registered = REGISTERED.lock();
+    let index = registered
+        .iter()
+        .position(|c| c.name == name)
+        .ok_or(ShellError::NotRegistered)?;
+    registered.remove(index);
+    Ok(())
+}
+
+/// Look up a command by name
+pub fn find(name: &str) -> Option<Command> {
+    commands::BUILTINS
+        .iter()
+        .find(|c| c.name == name)
+        .copied()
+        .or_else(|| REGISTERED.lock().iter().find(|c| c.name == name).copied())
+}
+
+/// All commands, sorted by name
+pub fn commands() -> Vec<Command> {
+    let mut all: Vec<Command> = commands::BUILTINS.to_vec();
+    all.extend(REGISTERED.lock().iter().copied());
+    all.sort_by_key(|c| c.name);
+    all
+}
+
+/// Parse and execute a command line
+pub fn execute(line: &str, out: &mut dyn Write) -> Result<(), ShellError> {
+    let mut args: Vec<&str> = line.split_whitespace().collect();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let name = args.remove(0);
+    let command = find(name).ok_or(ShellError::UnknownCommand)?;
+    (command.run)(out, &args)
+}
+
+/// Run the shell on `ttyS0`, never returns
+///
+/// Line editing, echo and Ctrl-C come from the terminal's canonical mode.
+/// The startup script runs first.
+pub fn run() -> ! {
+    use crate::tty::{self, TtyError};
+
+    let mut out = serial::Serial;
+    let mut line = [0u8; MAX_LINE];
+    let mut runner = script::Runner::new();
+
+    script::run_startup(&mut out);
+    let _ = write!(out, "\n{}", PROMPT);
+    loop {
+        // Between commands nothing is held, so signals are taken here
+        crate::task::signal::checkpoint();
+        tty::poll_serial();
+        let len = match tty::TTY_S0.try_read(&mut line) {
+            Ok(len) => len,
+            Err(TtyError::Interrupted) => {
+                let _ = out.write_str(PROMPT);
+                continue;
+            }
+            Err(TtyError::WouldBlock | TtyError::TimedOut | TtyError::Cancelled) => {
+                crate::task::scheduler::yield_now();
+                core::hint::spin_loop();
+                continue;
+            }
+        };
+
+        // The terminal only passes printable ASCII, so this cannot fail
+        let text = core::str::from_utf8(&line[..len]).unwrap_or("");
+        runner.run_line(text, &mut out, None);
+        let _ = out.write_str(PROMPT);
+    }
+}
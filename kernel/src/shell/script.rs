@@ -0,0 +1,140 @@
+//! Shell Scripts
+//!
+//! A script is shell lines run in order. `#` starts a comment, `;`
+//! separates commands on a line and `a && b` runs `b` only if `a`
+//! succeeded. A line starting with `if-ok` runs only if the line before
+//! it succeeded, so a step can be skipped when the one it needs failed.
+//! Failures are reported with the script and line number, and the script
+//! carries on. Typed lines get the same treatment.
+//!
+//! The shell runs `/etc/rc` before its first prompt. Until there is an
+//! initrd the file is handed to QEMU as the fw_cfg file `opt/cosmos/rc`,
+//! which `cargo xtask run --rc <file>` does; `norc` on the command line
+//! skips it.
+
+use core::fmt::Write;
+
+/// Path the startup script stands for
+pub const RC_PATH: &str = "/etc/rc";
+
+/// fw_cfg file the startup script is read from
+pub const FW_CFG_RC: &str = "opt/cosmos/rc";
+
+/// Keyword running the rest of a line only if the line before succeeded
+const IF_OK: &str = "if-ok";
+
+/// Line with a `#` comment removed, a `#` inside a word is kept
+fn strip_comment(line: &str) -> &str {
+    let mut previous = ' ';
+    for (index, c) in line.char_indices() {
+        if c == '#' && previous.is_whitespace() {
+            return &line[..index];
+        }
+        previous = c;
+    }
+    line
+}
+
+/// Runs lines, remembering whether the last one succeeded for `if-ok`
+pub struct Runner {
+    last_ok: bool,
+}
+
+impl Runner {
+    pub const fn new() -> Self {
+        Runner { last_ok: true }
+    }
+
+    /// Run a line, reporting failures to `out` after `location`, returns
+    /// whether it succeeded
+    ///
+    /// A line succeeds if the last `&&` chain on it ran to the end.
+    pub fn run_line(&mut self, line: &str, out: &mut dyn Write, location: Option<(&str, usize)>) -> bool {
+        let mut line = strip_comment(line).trim();
+        if line.is_empty() {
+            return self.last_ok;
+        }
+        let guarded = line.strip_prefix(IF_OK).filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+        if let Some(rest) = guarded {
+            if !self.last_ok {
+                return false;
+            }
+            line = rest;
+        }
+        let mut ok = true;
+        for list in line.split(';') {
+            ok = list.split("&&").all(|command| run_command(command, out, location));
+        }
+        self.last_ok = ok;
+        ok
+    }
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Execute one command, reporting a failure
+fn run_command(command: &str, out: &mut dyn Write, location: Option<(&str, usize)>) -> bool {
+    let Err(e) = super::execute(command, out) else {
+        return true;
+    };
+    let name = command.split_whitespace().next().unwrap_or("");
+    let _ = match location {
+        Some((script, line)) => writeln!(out, "{}:{}: {}: {}", script, line, name, e),
+        None => writeln!(out, "{}: {}", name, e),
+    };
+    false
+}
+
+/// Run every line of `text`, `name` labels failures, returns whether all
+/// lines succeeded
+pub fn run(name: &str, text: &str, out: &mut dyn Write) -> bool {
+    let mut runner = Runner::new();
+    let mut all_ok = true;
+    for (index, line) in text.lines().enumerate() {
+        all_ok &= runner.run_line(line, out, Some((name, index + 1)));
+    }
+    all_ok
+}
+
+/// Run the startup script, if there is one and `norc` was not given
+pub fn run_startup(out: &mut dyn Write) {
+    if crate::cmdline::has_flag("norc") {
+        return;
+    }
+    let Some(data) = crate::qemu::fw_cfg_file(FW_CFG_RC) else {
+        return;
+    };
+    let Ok(text) = core::str::from_utf8(&data) else {
+        let _ = writeln!(out, "{}: not valid UTF-8", RC_PATH);
+        return;
+    };
+    let _ = writeln!(out, "Running {}", RC_PATH);
+    if !run(RC_PATH, text, out) {
+        let _ = writeln!(out, "{}: some commands failed", RC_PATH);
+    }
+}
+
+/// Comments, `;`, `&&` and `if-ok` run and skip the right commands
+fn selftest() -> Result<(), &'static str> {
+    let mut out = cosmos_common::fmt::FixedBuf::<256>::new();
+    let script = "# setup\n\
+        echo a; echo b # trailing\n\
+        \n\
+        no-such-command && echo skipped\n\
+        if-ok echo skipped\n\
+        echo c && echo d#e\n\
+        if-ok echo f\n";
+    if run("test", script, &mut out) {
+        return Err("failing script reported success");
+    }
+    if out.as_str() != "a\nb\ntest:4: no-such-command: Unknown command\nc\nd#e\nf\n" {
+        return Err("script ran the wrong commands");
+    }
+    Ok(())
+}
+
+crate::selftest!("script", selftest);
@@ -0,0 +1,246 @@
+//! AC'97 Audio
+//!
+//! The ICH AC'97 controller, which QEMU emulates as `-device AC97`. The
+//! mixer (NAM) and bus master (NABM) registers are both in I/O space.
+//! Playback fills one DMA buffer with 16-bit stereo samples, describes it
+//! in the buffer descriptor list and starts the PCM out channel, which
+//! stops by itself after the last descriptor.
+
+use super::SoundError;
+use crate::arch::x86_64::port::{self, PortRange};
+use crate::mm::{frame_allocator, PhysicalFrame, Zone};
+use crate::pci::{self, Bar, PciDevice};
+
+// Mixer registers
+const NAM_RESET: u16 = 0x00;
+const NAM_MASTER_VOLUME: u16 = 0x02;
+const NAM_PCM_OUT_VOLUME: u16 = 0x18;
+const NAM_EXTENDED_ID: u16 = 0x28;
+const NAM_EXTENDED_CONTROL: u16 = 0x2A;
+const NAM_FRONT_DAC_RATE: u16 = 0x2C;
+/// Extended ID and control: variable rate audio
+const EXTENDED_VRA: u16 = 1 << 0;
+/// Attenuation of 12 dB on both channels
+const PCM_VOLUME: u16 = 0x0808;
+
+// Bus master registers of the PCM out channel
+const PO_BDBAR: u16 = 0x10;
+const PO_CIV: u16 = 0x14;
+const PO_LVI: u16 = 0x15;
+const PO_SR: u16 = 0x16;
+const PO_CR: u16 = 0x1B;
+const GLOBAL_CONTROL: u16 = 0x2C;
+const GLOBAL_STATUS: u16 = 0x30;
+
+/// Status: DMA controller halted
+const SR_HALTED: u16 = 1 << 0;
+/// Status bits written as 1 to clear them
+const SR_CLEAR: u16 = 0x1C;
+/// Control: run bus master
+const CR_RUN: u8 = 1 << 0;
+/// Control: reset channel registers
+const CR_RESET: u8 = 1 << 1;
+/// Global control: leave cold reset
+const GLOBAL_COLD_RESET: u32 = 1 << 1;
+/// Global status: primary codec ready
+const GLOBAL_CODEC_READY: u32 = 1 << 8;
+
+/// Buffer descriptor: interrupt on completion
+const BD_IOC: u32 = 1 << 31;
+/// Buffer descriptor: last valid buffer, play silence after it
+const BD_LAST: u32 = 1 << 30;
+/// Descriptors in the list
+const BD_COUNT: usize = 32;
+/// Samples one descriptor covers, an even number for stereo
+const BD_MAX_SAMPLES: usize = 0xFFFE;
+
+/// Pages in the sample buffer, about five seconds at 48 kHz
+const BUFFER_PAGES: u64 = 256;
+/// Fixed rate when the codec lacks variable rate audio
+const FIXED_RATE: u32 = 48_000;
+
+/// Polls of the codec ready and channel reset bits
+const READY_POLLS: usize = 1_000_000;
+
+pub struct Ac97 {
+    pci: PciDevice,
+    mixer: PortRange,
+    bus_master: PortRange,
+    variable_rate: bool,
+    descriptors: u64,
+    buffer: u64,
+}
+
+impl Ac97 {
+    /// Find the first AC'97 function on PCI and bring up its codec
+    pub fn probe() -> Option<Result<Self, SoundError>> {
+        let pci = pci::find(pci::CLASS_MULTIMEDIA, pci::SUBCLASS_AUDIO, 0).into_iter().next()?;
+        Some(Self::new(pci))
+    }
+
+    fn new(pci: PciDevice) -> Result<Self, SoundError> {
+        let (Some(Bar::Io { port: mixer, size: mixer_size }), Some(Bar::Io { port: bus_master, size: bus_master_size })) =
+            (pci.bar(0), pci.bar(1))
+        else {
+            return Err(SoundError::NoDevice);
+        };
+
+        // Descriptors and samples are addressed with 32 bits
+        let descriptors = frame_allocator::allocate_frame_in(Zone::Low)
+            .map_err(|_| SoundError::NoMemory)?
+            .start_address()
+            .as_u64();
+        let buffer = frame_allocator::allocate_contiguous_frames_in(BUFFER_PAGES, Zone::Low)
+            .map_err(|_| SoundError::NoMemory)?
+            .start_address()
+            .as_u64();
+
+        let mixer = port::claim(mixer, mixer_size as u16, "ac97").map_err(SoundError::Ports)?;
+        let bus_master = match port::claim(bus_master, bus_master_size as u16, "ac97") {
+            Ok(range) => range,
+            Err(e) => {
+                port::release(mixer);
+                return Err(SoundError::Ports(e));
+            }
+        };
+        pci.enable(pci::COMMAND_IO | pci::COMMAND_BUS_MASTER);
+
+        let mut device = Ac97 { pci, mixer, bus_master, variable_rate: false, descriptors, buffer };
+        device.write_bus_master32(GLOBAL_CONTROL, GLOBAL_COLD_RESET);
+        if !(0..READY_POLLS).any(|_| device.read_bus_master32(GLOBAL_STATUS) & GLOBAL_CODEC_READY != 0) {
+            return Err(SoundError::NoDevice);
+        }
+        device.write_mixer(NAM_RESET, 0);
+        device.write_mixer(NAM_MASTER_VOLUME, 0);
+        device.write_mixer(NAM_PCM_OUT_VOLUME, PCM_VOLUME);
+        if device.read_mixer(NAM_EXTENDED_ID) & EXTENDED_VRA != 0 {
+            let control = device.read_mixer(NAM_EXTENDED_CONTROL);
+            device.write_mixer(NAM_EXTENDED_CONTROL, control | EXTENDED_VRA);
+            device.variable_rate = true;
+        }
+        device.reset_channel();
+        crate::serial_println!(
+            "AC97 {}: codec ready, {} rate",
+            device.pci.address,
+            if device.variable_rate { "variable" } else { "fixed 48 kHz" }
+        );
+        Ok(device)
+    }
+
+    /// Stereo frames the sample buffer holds
+    pub fn capacity(&self) -> usize {
+        (BUFFER_PAGES * PhysicalFrame::SIZE) as usize / 4
+    }
+
+    /// Whether `rate` can be played
+    pub fn supports_rate(&self, rate: u32) -> bool {
+        rate == FIXED_RATE || (self.variable_rate && (8_000..=48_000).contains(&rate))
+    }
+
+    /// Play interleaved stereo frames at `rate`, replacing what is playing
+    pub fn play(&mut self, frames: impl ExactSizeIterator<Item = (i16, i16)>, rate: u32) -> Result<(), SoundError> {
+        if !self.supports_rate(rate) {
+            return Err(SoundError::UnsupportedFormat);
+        }
+        let count = frames.len();
+        if count > self.capacity() {
+            return Err(SoundError::TooLong);
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        self.stop();
+
+        let samples = self.buffer as *mut i16;
+        for (index, (left, right)) in frames.enumerate() {
+            unsafe {
+                samples.add(index * 2).write_volatile(left);
+                samples.add(index * 2 + 1).write_volatile(right);
+            }
+        }
+        let total = count * 2;
+        let entries = total.div_ceil(BD_MAX_SAMPLES).min(BD_COUNT);
+        let list = self.descriptors as *mut u32;
+        for entry in 0..entries {
+            let first = entry * BD_MAX_SAMPLES;
+            let length = (total - first).min(BD_MAX_SAMPLES) as u32;
+            let mut control = length;
+            if entry + 1 == entries {
+                control |= BD_IOC | BD_LAST;
+            }
+            unsafe {
+                list.add(entry * 2).write_volatile((self.buffer + first as u64 * 2) as u32);
+                list.add(entry * 2 + 1).write_volatile(control);
+            }
+        }
+
+        if self.variable_rate {
+            self.write_mixer(NAM_FRONT_DAC_RATE, rate as u16);
+        }
+        self.write_bus_master32(PO_BDBAR, self.descriptors as u32);
+        self.write_bus_master8(PO_LVI, (entries - 1) as u8);
+        self.write_bus_master16(PO_SR, SR_CLEAR);
+        self.write_bus_master8(PO_CR, CR_RUN);
+        Ok(())
+    }
+
+    /// Stop playback and rewind the channel
+    pub fn stop(&mut self) {
+        self.write_bus_master8(PO_CR, 0);
+        let _ = (0..READY_POLLS).any(|_| self.read_bus_master16(PO_SR) & SR_HALTED != 0);
+        self.reset_channel();
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.read_bus_master8(PO_CR) & CR_RUN != 0 && self.read_bus_master16(PO_SR) & SR_HALTED == 0
+    }
+
+    /// Descriptor being played
+    pub fn position(&self) -> u8 {
+        self.read_bus_master8(PO_CIV)
+    }
+
+    fn reset_channel(&mut self) {
+        self.write_bus_master8(PO_CR, CR_RESET);
+        let _ = (0..READY_POLLS).any(|_| self.read_bus_master8(PO_CR) & CR_RESET == 0);
+    }
+
+    fn read_mixer(&self, register: u16) -> u16 {
+        self.mixer.read(register)
+    }
+
+    fn write_mixer(&mut self, register: u16, value: u16) {
+        self.mixer.write(register, value)
+    }
+
+    fn read_bus_master8(&self, register: u16) -> u8 {
+        self.bus_master.read(register)
+    }
+
+    fn read_bus_master16(&self, register: u16) -> u16 {
+        self.bus_master.read(register)
+    }
+
+    fn read_bus_master32(&self, register: u16) -> u32 {
+        self.bus_master.read(register)
+    }
+
+    fn write_bus_master8(&mut self, register: u16, value: u8) {
+        self.bus_master.write(register, value)
+    }
+
+    fn write_bus_master16(&mut self, register: u16, value: u16) {
+        self.bus_master.write(register, value)
+    }
+
+    fn write_bus_master32(&mut self, register: u16, value: u32) {
+        self.bus_master.write(register, value)
+    }
+}
+
+impl Drop for Ac97 {
+    fn drop(&mut self) {
+        port::release(self.mixer);
+        port::release(self.bus_master);
+    }
+}
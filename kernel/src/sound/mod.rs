@@ -0,0 +1,142 @@
+//! Sound
+//!
+//! `beep` and `play` go to an AC'97 codec when there is one; without it,
+//! beeps fall back to the PC speaker, which every machine has, and PCM
+//! playback is unavailable. Intel HDA, found on real hardware since 2004,
+//! is not supported yet.
+
+pub mod ac97;
+pub mod speaker;
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use ac97::Ac97;
+use crate::arch::x86_64::port::PortError;
+
+/// Rate beeps are synthesized at
+pub const BEEP_RATE: u32 = 48_000;
+/// Square wave amplitude of synthesized beeps, a quarter of full scale
+const BEEP_AMPLITUDE: i16 = 8192;
+/// Panic beep pitch and length
+const PANIC_FREQUENCY: u32 = 880;
+const PANIC_BEEP_MS: u64 = 300;
+
+/// Errors that can occur playing sound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundError {
+    /// No PCM device
+    NoDevice,
+    /// No memory for DMA buffers
+    NoMemory,
+    /// Sample rate or channel count the device cannot play
+    UnsupportedFormat,
+    /// More samples than the device buffer holds
+    TooLong,
+    /// The device's I/O ports could not be claimed
+    Ports(PortError),
+}
+
+impl fmt::Display for SoundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SoundError::NoDevice => write!(f, "No sound device"),
+            SoundError::NoMemory => write!(f, "Out of memory for sound buffers"),
+            SoundError::UnsupportedFormat => write!(f, "Unsupported sample format"),
+            SoundError::TooLong => write!(f, "Sound too long for the buffer"),
+            SoundError::Ports(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+static AC97: Mutex<Option<Ac97>> = Mutex::new(None);
+
+/// Find a PCM device
+pub fn init() {
+    speaker::init();
+    match Ac97::probe() {
+        Some(Ok(device)) => *AC97.lock() = Some(device),
+        Some(Err(e)) => crate::serial_println!("AC97: {}, beeps use the PC speaker", e),
+        None => {}
+    }
+}
+
+/// Name of the device beeps go to
+pub fn backend() -> &'static str {
+    if AC97.lock().is_some() {
+        "ac97"
+    } else {
+        "pc-speaker"
+    }
+}
+
+/// Play 16-bit PCM, `channels` samples per frame interleaved
+///
+/// Returns once playback has started; whatever was playing stops.
+pub fn play(samples: &[i16], rate: u32, channels: u8) -> Result<(), SoundError> {
+    let mut device = AC97.lock();
+    let device = device.as_mut().ok_or(SoundError::NoDevice)?;
+    match channels {
+        1 => device.play(samples.iter().map(|&sample| (sample, sample)), rate),
+        2 => device.play(samples.chunks_exact(2).map(|frame| (frame[0], frame[1])), rate),
+        _ => Err(SoundError::UnsupportedFormat),
+    }
+}
+
+/// Stop PCM playback and the speaker
+pub fn stop() {
+    if let Some(device) = AC97.lock().as_mut() {
+        device.stop();
+    }
+    speaker::stop();
+}
+
+pub fn is_playing() -> bool {
+    AC97.lock().as_ref().is_some_and(|device| device.is_playing())
+}
+
+/// Sound a tone without waiting for it to end
+pub fn beep(frequency: u32, duration_ms: u64) {
+    let frequency = frequency.clamp(speaker::MIN_FREQUENCY, speaker::MAX_FREQUENCY);
+    if AC97.lock().is_some() {
+        let samples = square_wave(frequency, duration_ms, BEEP_RATE);
+        if play(&samples, BEEP_RATE, 1).is_ok() {
+            return;
+        }
+    }
+    speaker::beep(frequency, duration_ms);
+}
+
+/// Audible panic signal through the PC speaker, which needs no locks
+pub fn panic_beep() {
+    speaker::beep_blocking(PANIC_FREQUENCY, PANIC_BEEP_MS);
+}
+
+/// Mono square wave of `frequency` lasting `duration_ms`
+pub fn square_wave(frequency: u32, duration_ms: u64, rate: u32) -> Vec<i16> {
+    let count = (rate as u64 * duration_ms / 1000) as usize;
+    let frequency = frequency.max(1) as u64;
+    (0..count as u64)
+        .map(|index| {
+            // Position within the period, in units of 1/(2 * rate)
+            let phase = index * frequency * 2 / rate as u64;
+            if phase.is_multiple_of(2) { BEEP_AMPLITUDE } else { -BEEP_AMPLITUDE }
+        })
+        .collect()
+}
+
+/// Synthesized beeps have the requested length and pitch
+fn selftest() -> Result<(), &'static str> {
+    let samples = square_wave(1000, 10, BEEP_RATE);
+    if samples.len() != 480 {
+        return Err("wrong beep length");
+    }
+    // 1 kHz at 48 kHz: 24 samples high, 24 low, ten periods
+    let edges = samples.windows(2).filter(|pair| pair[0] != pair[1]).count();
+    if edges != 19 || samples[..24].iter().any(|&sample| sample != BEEP_AMPLITUDE) || samples[24] != -BEEP_AMPLITUDE {
+        return Err("wrong beep pitch");
+    }
+    Ok(())
+}
+
+crate::selftest!("sound", selftest);
@@ -0,0 +1,86 @@
+//! PC Speaker
+//!
+//! PIT channel 2 drives the speaker with a square wave once port 0x61
+//! gates it through. There is no volume and only one tone at a time, but
+//! every PC and emulator has one and it needs no memory, so it also works
+//! from the panic handler.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::arch::x86_64::pit::PIT_FREQUENCY;
+use crate::arch::x86_64::port::{self, Port};
+
+const CHANNEL2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+/// System control port B: bit 0 gates channel 2, bit 1 enables the speaker
+const CONTROL_PORT: u16 = 0x61;
+const SPEAKER_ENABLE: u8 = 0x03;
+
+/// Channel 2, lobyte/hibyte access, mode 3 (square wave), binary
+const CHANNEL2_SQUARE_WAVE: u8 = 0b1011_0110;
+
+/// Lowest and highest frequency the PIT divisor can produce
+pub const MIN_FREQUENCY: u32 = 19;
+pub const MAX_FREQUENCY: u32 = 20_000;
+
+/// Bumped by every beep, so a finished beep's timer leaves a newer one alone
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Claim port B, channel 2 itself comes with the PIT's ports
+pub fn init() {
+    if let Err(e) = port::claim(CONTROL_PORT, 1, "speaker") {
+        crate::serial_println!("PC speaker: {}", e);
+    }
+}
+
+/// Start a continuous tone
+pub fn start(frequency: u32) {
+    let divisor = PIT_FREQUENCY / frequency.clamp(MIN_FREQUENCY, MAX_FREQUENCY);
+    unsafe {
+        Port::<u8>::new(COMMAND_PORT).write(CHANNEL2_SQUARE_WAVE);
+        let channel2 = Port::<u8>::new(CHANNEL2_PORT);
+        channel2.write(divisor as u8);
+        channel2.write((divisor >> 8) as u8);
+        let control = Port::<u8>::new(CONTROL_PORT);
+        let value = control.read();
+        control.write(value | SPEAKER_ENABLE);
+    }
+}
+
+/// Silence the speaker
+pub fn stop() {
+    unsafe {
+        let control = Port::<u8>::new(CONTROL_PORT);
+        let value = control.read();
+        control.write(value & !SPEAKER_ENABLE);
+    }
+}
+
+/// Sound a tone for `duration_ms` without waiting for it to end
+pub fn beep(frequency: u32, duration_ms: u64) {
+    let generation = GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+    start(frequency);
+    crate::time::timers::schedule_after(duration_ms, stop_if_current, generation as usize);
+}
+
+fn stop_if_current(generation: usize) {
+    if GENERATION.load(Ordering::Acquire) == generation as u64 {
+        stop();
+    }
+}
+
+/// Sound a tone and busy-wait until it ends, timed by the TSC
+///
+/// For contexts without timers, such as the panic handler. Does nothing
+/// until the TSC frequency is known.
+pub fn beep_blocking(frequency: u32, duration_ms: u64) {
+    let Some(hz) = crate::time::tsc_hz() else {
+        return;
+    };
+    let cycles = hz / 1000 * duration_ms;
+    let start_tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    start(frequency);
+    while unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(start_tsc) < cycles {
+        core::hint::spin_loop();
+    }
+    stop();
+}
@@ -0,0 +1,63 @@
+//! Stack-smashing protection support
+//!
+//! Functions compiled with `-Z stack-protector` store `__stack_chk_guard` in
+//! their frame and call `__stack_chk_fail` if it changed before returning.
+
+/// Stack canary compared by protected function epilogues
+///
+/// Starts as a fixed value with a zero low byte, so string overflows cannot
+/// reproduce it, and is replaced with a random value by `init`.
+#[no_mangle]
+pub static mut __stack_chk_guard: u64 = 0x595E_9FBD_94FD_A700;
+
+/// Replace the boot canary with a random one
+///
+/// This function keeps no arrays or borrowed locals, so it is not
+/// instrumented itself.
+///
+/// # Safety
+///
+/// Must be called before any protected frame returns, from a frame that
+/// never returns such as `_start`: a protected function still on the stack
+/// holds the old value and would fail its check.
+#[inline(never)]
+pub unsafe fn init() {
+    let guard = random_guard();
+    unsafe {
+        core::ptr::write_volatile(&raw mut __stack_chk_guard, guard);
+    }
+}
+
+/// Random canary with a zero low byte, so string copies cannot forge it
+///
+/// Kept out of line: the RNG buffers would get `init` instrumented.
+#[inline(never)]
+fn random_guard() -> u64 {
+    crate::crypto::rng::next_u64() & !0xFF
+}
+
+/// Called by a protected function whose canary was overwritten
+///
+/// Passes the return address, which points into the offending function,
+/// on to the report.
+///
+/// # Safety
+///
+/// Only called by the epilogue of a protected function, with its return
+/// address on top of the stack.
+#[no_mangle]
+#[unsafe(naked)]
+pub unsafe extern "C" fn __stack_chk_fail() -> ! {
+    core::arch::naked_asm!(
+        "mov rdi, [rsp]",
+        // Realign the stack for the call
+        "sub rsp, 8",
+        "call {report}",
+        "ud2",
+        report = sym stack_chk_fail_report,
+    );
+}
+
+extern "C" fn stack_chk_fail_report(return_address: u64) -> ! {
+    panic!("stack smashing detected in function at {}", crate::ksyms::Address(return_address as usize));
+}
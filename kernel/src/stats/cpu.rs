@@ -0,0 +1,196 @@
+//! CPU Statistics
+//!
+//! Each CPU's time is split into busy time running tasks, idle time in the
+//! idle task and time in interrupt handlers. The split is kept by reading
+//! the TSC whenever the CPU moves from one to another: at a context switch
+//! and on entry to and exit from the outermost interrupt handler.
+//! Interrupts taken while idle count as interrupt time, not idle time.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::arch::x86_64::cpu::{current_id as current_cpu, MAX_CPUS};
+
+/// Per-CPU time counters
+struct CpuCounters {
+    busy_cycles: AtomicU64,
+    idle_cycles: AtomicU64,
+    irq_cycles: AtomicU64,
+    /// Cycles halted waiting for an interrupt, part of idle and interrupt time
+    halted_cycles: AtomicU64,
+    idle_entries: AtomicU64,
+    /// TSC at the last move between busy, idle and interrupt time
+    last_change: AtomicU64,
+    /// The idle task is running
+    in_idle: AtomicBool,
+}
+
+static COUNTERS: [CpuCounters; MAX_CPUS] = [const {
+    CpuCounters {
+        busy_cycles: AtomicU64::new(0),
+        idle_cycles: AtomicU64::new(0),
+        irq_cycles: AtomicU64::new(0),
+        halted_cycles: AtomicU64::new(0),
+        idle_entries: AtomicU64::new(0),
+        last_change: AtomicU64::new(0),
+        in_idle: AtomicBool::new(false),
+    }
+}; MAX_CPUS];
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Start the accounting period
+pub fn init() {
+    let now = rdtsc();
+    for cpu in COUNTERS.iter() {
+        cpu.last_change.store(now, Ordering::Relaxed);
+    }
+}
+
+/// Add the cycles since the last change to `counter`
+///
+/// Nothing is charged before `init`.
+fn charge(cpu: &CpuCounters, counter: impl Fn(&CpuCounters) -> &AtomicU64) {
+    let now = rdtsc();
+    let last = cpu.last_change.swap(now, Ordering::Relaxed);
+    if last != 0 {
+        counter(cpu).fetch_add(now.wrapping_sub(last), Ordering::Relaxed);
+    }
+}
+
+/// Counter the running task's time goes to
+fn task_counter(cpu: &CpuCounters) -> &AtomicU64 {
+    if cpu.in_idle.load(Ordering::Relaxed) {
+        &cpu.idle_cycles
+    } else {
+        &cpu.busy_cycles
+    }
+}
+
+/// Mark entry into the outermost interrupt handler
+pub fn enter_interrupt() {
+    charge(&COUNTERS[current_cpu()], task_counter);
+}
+
+/// Mark exit from the outermost interrupt handler
+pub fn exit_interrupt() {
+    charge(&COUNTERS[current_cpu()], |cpu| &cpu.irq_cycles);
+}
+
+/// Mark a context switch, to the idle task if `to_idle`
+///
+/// Called with interrupts disabled.
+pub fn switch_task(to_idle: bool) {
+    let cpu = &COUNTERS[current_cpu()];
+    charge(cpu, task_counter);
+    cpu.in_idle.store(to_idle, Ordering::Relaxed);
+}
+
+/// Record time spent halted, in TSC cycles
+pub fn record_idle(cycles: u64) {
+    let cpu = &COUNTERS[current_cpu()];
+    cpu.halted_cycles.fetch_add(cycles, Ordering::Relaxed);
+    cpu.idle_entries.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Statistics for one CPU
+#[derive(Debug, Clone, Copy)]
+pub struct CpuStats {
+    pub cpu: usize,
+    /// Cycles running tasks other than the idle task
+    pub busy_cycles: u64,
+    /// Cycles in the idle task
+    pub idle_cycles: u64,
+    /// Cycles in interrupt handlers
+    pub irq_cycles: u64,
+    pub halted_cycles: u64,
+    pub idle_entries: u64,
+    /// Cycles accounted, busy, idle and interrupt time together
+    pub total_cycles: u64,
+}
+
+impl CpuStats {
+    /// `cycles` as a share of the total, in percent
+    pub fn percent(&self, cycles: u64) -> u64 {
+        if self.total_cycles == 0 {
+            return 0;
+        }
+        (cycles as u128 * 100 / self.total_cycles as u128) as u64
+    }
+
+    /// Share of time spent running tasks, in percent
+    pub fn busy_percent(&self) -> u64 {
+        self.percent(self.busy_cycles)
+    }
+
+    /// Share of time spent idle, in percent
+    pub fn idle_percent(&self) -> u64 {
+        self.percent(self.idle_cycles)
+    }
+
+    /// Share of time spent in interrupt handlers, in percent
+    pub fn irq_percent(&self) -> u64 {
+        self.percent(self.irq_cycles)
+    }
+
+    /// Statistics for the time between `earlier` and these
+    pub fn since(&self, earlier: &CpuStats) -> CpuStats {
+        CpuStats {
+            cpu: self.cpu,
+            busy_cycles: self.busy_cycles.saturating_sub(earlier.busy_cycles),
+            idle_cycles: self.idle_cycles.saturating_sub(earlier.idle_cycles),
+            irq_cycles: self.irq_cycles.saturating_sub(earlier.irq_cycles),
+            halted_cycles: self.halted_cycles.saturating_sub(earlier.halted_cycles),
+            idle_entries: self.idle_entries.saturating_sub(earlier.idle_entries),
+            total_cycles: self.total_cycles.saturating_sub(earlier.total_cycles),
+        }
+    }
+}
+
+/// Snapshot statistics for every CPU
+///
+/// The calling CPU's time up to now is charged first, so its busy, idle and
+/// interrupt time add up to the total.
+pub fn cpus() -> Vec<CpuStats> {
+    charge(&COUNTERS[current_cpu()], task_counter);
+    COUNTERS
+        .iter()
+        .enumerate()
+        .map(|(cpu, counters)| {
+            let busy_cycles = counters.busy_cycles.load(Ordering::Relaxed);
+            let idle_cycles = counters.idle_cycles.load(Ordering::Relaxed);
+            let irq_cycles = counters.irq_cycles.load(Ordering::Relaxed);
+            CpuStats {
+                cpu,
+                busy_cycles,
+                idle_cycles,
+                irq_cycles,
+                halted_cycles: counters.halted_cycles.load(Ordering::Relaxed),
+                idle_entries: counters.idle_entries.load(Ordering::Relaxed),
+                total_cycles: busy_cycles + idle_cycles + irq_cycles,
+            }
+        })
+        .collect()
+}
+
+/// Time spent spinning in a task is accounted, and as busy time
+fn selftest() -> Result<(), &'static str> {
+    let before = cpus();
+    let start = rdtsc();
+    while rdtsc().wrapping_sub(start) < 1_000_000 {
+        core::hint::spin_loop();
+    }
+    let after = cpus();
+    let cpu = current_cpu();
+    let spent = after[cpu].since(&before[cpu]);
+    if spent.total_cycles < 1_000_000 {
+        return Err("time went unaccounted");
+    }
+    if spent.busy_cycles < 1_000_000 - spent.irq_cycles.min(1_000_000) {
+        return Err("spinning not counted as busy");
+    }
+    Ok(())
+}
+
+crate::selftest!("cpustats", selftest);
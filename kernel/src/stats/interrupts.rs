@@ -0,0 +1,98 @@
+//! Interrupt Statistics
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::arch::x86_64::cpu::{current_id as current_cpu, MAX_CPUS};
+
+/// Per-CPU interrupt counters, updated lock-free from handlers
+struct CpuCounters {
+    counts: [AtomicU64; 256],
+    max_cycles: [AtomicU64; 256],
+    spurious: AtomicU64,
+    unhandled: AtomicU64,
+    max_nesting: AtomicU64,
+}
+
+impl CpuCounters {
+    const fn new() -> Self {
+        CpuCounters {
+            counts: [const { AtomicU64::new(0) }; 256],
+            max_cycles: [const { AtomicU64::new(0) }; 256],
+            spurious: AtomicU64::new(0),
+            unhandled: AtomicU64::new(0),
+            max_nesting: AtomicU64::new(0),
+        }
+    }
+}
+
+static COUNTERS: [CpuCounters; MAX_CPUS] = [const { CpuCounters::new() }; MAX_CPUS];
+
+/// Record a handled interrupt and its handler latency in TSC cycles
+pub fn record(vector: u8, cycles: u64) {
+    let cpu = &COUNTERS[current_cpu()];
+    cpu.counts[vector as usize].fetch_add(1, Ordering::Relaxed);
+    cpu.max_cycles[vector as usize].fetch_max(cycles, Ordering::Relaxed);
+}
+
+/// Record a spurious PIC interrupt
+pub fn record_spurious() {
+    COUNTERS[current_cpu()].spurious.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record an interrupt on a vector with no handler
+pub fn record_unhandled() {
+    COUNTERS[current_cpu()].unhandled.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record the interrupt nesting depth reached
+pub fn record_nesting(depth: usize) {
+    COUNTERS[current_cpu()].max_nesting.fetch_max(depth as u64, Ordering::Relaxed);
+}
+
+/// Statistics for a single vector
+#[derive(Debug, Clone, Copy)]
+pub struct VectorStats {
+    pub vector: u8,
+    pub count: u64,
+    pub max_latency_cycles: u64,
+}
+
+/// Interrupt statistics summed over all CPUs
+#[derive(Debug, Clone, Default)]
+pub struct InterruptStats {
+    /// Vectors that have fired at least once
+    pub vectors: Vec<VectorStats>,
+    pub total: u64,
+    pub spurious: u64,
+    pub unhandled: u64,
+    pub max_nesting: u64,
+}
+
+/// Snapshot interrupt statistics
+pub fn interrupts() -> InterruptStats {
+    let mut stats = InterruptStats::default();
+
+    for vector in 0..256 {
+        let mut count = 0;
+        let mut max_latency_cycles = 0;
+        for cpu in COUNTERS.iter() {
+            count += cpu.counts[vector].load(Ordering::Relaxed);
+            max_latency_cycles = max_latency_cycles.max(cpu.max_cycles[vector].load(Ordering::Relaxed));
+        }
+        if count > 0 {
+            stats.total += count;
+            stats.vectors.push(VectorStats {
+                vector: vector as u8,
+                count,
+                max_latency_cycles,
+            });
+        }
+    }
+
+    for cpu in COUNTERS.iter() {
+        stats.spurious += cpu.spurious.load(Ordering::Relaxed);
+        stats.unhandled += cpu.unhandled.load(Ordering::Relaxed);
+        stats.max_nesting = stats.max_nesting.max(cpu.max_nesting.load(Ordering::Relaxed));
+    }
+    stats
+}
@@ -0,0 +1,84 @@
+//! Memory Statistics
+
+use core::fmt::{self, Write};
+use cosmos_common::fmt::HumanBytes;
+use crate::mm::frame_allocator::{self, FrameAllocatorStats, ORDER_CLASSES};
+use crate::mm::frame_info::{self, Owner};
+use crate::mm::heap::{self, HeapStats, SIZE_CLASSES};
+use crate::mm::PhysicalFrame;
+
+/// Heap and physical frame statistics
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    pub heap: HeapStats,
+    /// `None` before the frame allocator is initialized
+    pub frames: Option<FrameAllocatorStats>,
+}
+
+/// Snapshot heap and frame allocator statistics
+pub fn memory() -> MemoryStats {
+    MemoryStats {
+        heap: heap::heap_stats(),
+        frames: frame_allocator::get_stats(),
+    }
+}
+
+/// Upper bound of a heap size class, 0 for the open-ended last class
+fn size_class_limit(class: usize) -> usize {
+    if class == SIZE_CLASSES - 1 { 0 } else { 16 << class }
+}
+
+impl MemoryStats {
+    /// Write a text report, as shown by `meminfo` and `/dev/meminfo`
+    pub fn write_report(&self, out: &mut dyn Write) -> fmt::Result {
+        let heap = &self.heap;
+        let bytes = |size: usize| HumanBytes(size as u64);
+        writeln!(out, "Heap:       {:>10} total, {} used, {} free, {} trimmed",
+            bytes(heap.total_size), bytes(heap.used_size), bytes(heap.free_size), bytes(heap.trimmed_size))?;
+        writeln!(out, "Heap peak:  {:>10} requested", bytes(heap.peak_size))?;
+        writeln!(out, "Heap ops:   {:>10} allocs, {} frees, {} failed",
+            heap.allocations, heap.frees, heap.failures)?;
+        for (class, &count) in heap.size_classes.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            match size_class_limit(class) {
+                0 => writeln!(out, "  > {:>6} B {:>10}", size_class_limit(class - 1), count)?,
+                limit => writeln!(out, "  <= {:>5} B {:>10}", limit, count)?,
+            }
+        }
+
+        let Some(frames) = &self.frames else {
+            return writeln!(out, "Frames:     not initialized");
+        };
+        writeln!(out, "Frames:     {:>10} total, {} used, {} free ({} each)",
+            frames.total_frames, frames.allocated_frames, frames.free_frames, HumanBytes(PhysicalFrame::SIZE))?;
+        writeln!(out, "Frame mem:  {:>10} total, {} used",
+            HumanBytes(frames.total_memory), HumanBytes(frames.allocated_memory))?;
+        writeln!(out, "Frame peak: {:>10} frames, {}",
+            frames.peak_allocated_frames, HumanBytes(frames.peak_allocated_frames * PhysicalFrame::SIZE))?;
+        writeln!(out, "Frame ops:  {:>10} allocs, {} frees, {} failed, {} double frees",
+            frames.allocations, frames.frees, frames.failures, frames.double_frees)?;
+        for zone in frames.zones.iter().filter(|zone| zone.total_frames != 0) {
+            writeln!(out, "  {:<8} {:>8} frames, {} used", zone.zone.name(), zone.total_frames, zone.allocated_frames)?;
+        }
+        if frame_info::is_available() {
+            for (owner, count) in Owner::ALL.iter().zip(frame_info::owner_counts()) {
+                if count != 0 && *owner != Owner::Free {
+                    writeln!(out, "  {:<10} {:>8} frames", owner.name(), count)?;
+                }
+            }
+        }
+        for (order, &count) in frames.orders.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            if order == ORDER_CLASSES - 1 {
+                writeln!(out, "  > {:>5} frames {:>8}", 1 << (order - 1), count)?;
+            } else {
+                writeln!(out, "  <= {:>4} frames {:>8}", 1 << order, count)?;
+            }
+        }
+        Ok(())
+    }
+}
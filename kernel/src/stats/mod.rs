@@ -0,0 +1,10 @@
+//! Kernel Statistics
+
+pub mod cpu;
+pub mod interrupts;
+pub mod memory;
+
+// Re-export core APIs
+pub use self::cpu::{cpus, CpuStats};
+pub use self::interrupts::{interrupts, InterruptStats, VectorStats};
+pub use self::memory::{memory, MemoryStats};
@@ -0,0 +1,129 @@
+//! Cancellation Tokens
+//!
+//! A token handed to a blocking call lets another task, an interrupt
+//! handler or a deadline end the wait with an error, so a device that
+//! never answers fails the caller instead of wedging it. Clones share one
+//! state: cancelling any of them ends every wait on all of them.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::{WaitError, WaitQueue};
+use crate::time;
+
+struct Shared {
+    cancelled: AtomicBool,
+    /// Tick the token expires at
+    deadline: Option<u64>,
+    /// Tasks blocked in a wait using the token
+    waiters: WaitQueue,
+}
+
+/// Shared flag that ends waits when set or once its deadline passes
+#[derive(Clone)]
+pub struct CancelToken {
+    shared: Arc<Shared>,
+}
+
+impl CancelToken {
+    /// Create a token that only `cancel` ends
+    pub fn new() -> Self {
+        Self::build(None)
+    }
+
+    /// Create a token that also expires `timeout_ms` milliseconds from now
+    pub fn with_timeout(timeout_ms: u64) -> Self {
+        Self::build(Some(time::ticks() + time::ms_to_ticks(timeout_ms)))
+    }
+
+    fn build(deadline: Option<u64>) -> Self {
+        CancelToken {
+            shared: Arc::new(Shared {
+                cancelled: AtomicBool::new(false),
+                deadline,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Cancel the token and wake every task waiting with it, safe from
+    /// interrupt handlers
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::Release);
+        self.shared.waiters.wake_all();
+    }
+
+    /// Check if `cancel` was called
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Tick the token expires at, if it has a deadline
+    pub fn deadline(&self) -> Option<u64> {
+        self.shared.deadline
+    }
+
+    /// Error once cancelled or expired, for polling loops
+    pub fn check(&self) -> Result<(), WaitError> {
+        if self.is_cancelled() {
+            return Err(WaitError::Cancelled);
+        }
+        match self.shared.deadline {
+            Some(deadline) if time::ticks() >= deadline => Err(WaitError::TimedOut),
+            _ => Ok(()),
+        }
+    }
+
+    /// Queue the waits using the token block on as well
+    pub(super) fn waiters(&self) -> &WaitQueue {
+        &self.shared.waiters
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        CancelToken::new()
+    }
+}
+
+/// Timer callback, `arg` holds a reference to a clone of the token
+fn selftest_cancel(arg: usize) {
+    let token = unsafe { Arc::from_raw(arg as *const CancelToken) };
+    token.cancel();
+}
+
+/// Waits end at their deadline or when cancelled, and leave no entries
+/// behind
+fn selftest() -> Result<(), &'static str> {
+    if crate::task::current_id().is_none() {
+        return Err("tasking not initialized");
+    }
+    let queue = WaitQueue::new();
+    let start = time::ticks();
+    if queue.wait_timeout(20, || false) != Err(WaitError::TimedOut) {
+        return Err("wait did not time out");
+    }
+    if time::ticks() - start < time::ms_to_ticks(20) || !queue.is_empty() {
+        return Err("timed out early or stayed queued");
+    }
+    if queue.wait_cancellable(&CancelToken::with_timeout(20), || false) != Err(WaitError::TimedOut) {
+        return Err("token deadline did not end the wait");
+    }
+
+    // The deadline only bounds the test if the cancel never comes
+    let token = CancelToken::with_timeout(1000);
+    let arg = Arc::into_raw(Arc::new(token.clone())) as usize;
+    let timer = time::schedule_after(10, selftest_cancel, arg);
+    let result = queue.wait_cancellable(&token, || false);
+    if time::cancel(timer) {
+        drop(unsafe { Arc::from_raw(arg as *const CancelToken) });
+    }
+    if result != Err(WaitError::Cancelled) {
+        return Err("cancel did not end the wait");
+    }
+    if !queue.is_empty() || !token.waiters().is_empty() {
+        return Err("cancelled waiter stayed queued");
+    }
+    Ok(())
+}
+
+crate::selftest!("cancel", selftest);
@@ -0,0 +1,53 @@
+//! Manual-reset Event
+
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::WaitQueue;
+
+/// Event flag that tasks can block on until it is set
+pub struct Event {
+    signaled: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Event {
+    /// Create an unsignaled event
+    pub const fn new() -> Self {
+        Event {
+            signaled: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Block until the event is set
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.is_set());
+    }
+
+    /// Wait in a future until the event is set
+    pub fn wait_async(&self) -> impl Future<Output = ()> + '_ {
+        self.waiters.until(|| self.is_set())
+    }
+
+    /// Set the event and wake all waiters, safe from interrupt handlers
+    pub fn set(&self) {
+        self.signaled.store(true, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    /// Clear the event
+    pub fn reset(&self) {
+        self.signaled.store(false, Ordering::Release);
+    }
+
+    /// Check if the event is set
+    pub fn is_set(&self) -> bool {
+        self.signaled.load(Ordering::Acquire)
+    }
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Event::new()
+    }
+}
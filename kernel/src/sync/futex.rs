@@ -0,0 +1,193 @@
+//! Futexes
+//!
+//! Wait and wake keyed by the address of a 32-bit word, the primitive
+//! user-space mutexes and condition variables are built on: the fast path
+//! stays in user space with atomics and only contended waits enter the
+//! kernel. Waiters are kept in a fixed set of buckets hashed by address,
+//! so no state exists for uncontended words. The `futex` system call
+//! passes user addresses through to `wait` and `wake` once user mode exists.
+
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicU32, Ordering};
+use super::IrqMutex;
+use crate::arch::x86_64::interrupts;
+use crate::task::{self, TaskId};
+use crate::time::{self, timers};
+
+/// Number of hash buckets, a power of two
+const BUCKET_COUNT: usize = 64;
+
+/// Errors returned by `wait`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexError {
+    /// Word no longer held the expected value
+    WouldBlock,
+    /// Timeout expired before a wake
+    TimedOut,
+}
+
+impl core::fmt::Display for FutexError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FutexError::WouldBlock => write!(f, "Futex value changed"),
+            FutexError::TimedOut => write!(f, "Futex wait timed out"),
+        }
+    }
+}
+
+/// Tasks waiting on words whose addresses hash to one bucket
+struct Bucket {
+    waiters: IrqMutex<VecDeque<(u64, TaskId)>>,
+}
+
+static BUCKETS: [Bucket; BUCKET_COUNT] = [const {
+    Bucket { waiters: IrqMutex::named("futex bucket", VecDeque::new()) }
+}; BUCKET_COUNT];
+
+fn bucket(key: u64) -> &'static Bucket {
+    // Words are 4-byte aligned, mix the higher bits in
+    let hash = (key >> 2).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 58;
+    &BUCKETS[hash as usize % BUCKET_COUNT]
+}
+
+fn remove(bucket: &Bucket, key: u64, id: TaskId) -> bool {
+    let mut waiters = bucket.waiters.lock();
+    match waiters.iter().position(|&waiter| waiter == (key, id)) {
+        Some(index) => {
+            waiters.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+fn wake_task(arg: usize) {
+    task::wake(TaskId::from_u64(arg as u64));
+}
+
+/// Block until woken, if `word` still holds `expected`
+///
+/// The check and the queueing happen under the bucket lock, so a `wake`
+/// after the waker changed the word cannot be missed. Wakeups may be
+/// spurious from the caller's point of view, which must recheck its
+/// condition as with any futex.
+pub fn wait(word: &AtomicU32, expected: u32, timeout_ms: Option<u64>) -> Result<(), FutexError> {
+    let key = word as *const AtomicU32 as u64;
+    let bucket = bucket(key);
+    let Some(current) = task::current_id() else {
+        // No tasking, nothing could wake us but an interrupt handler
+        while word.load(Ordering::Acquire) == expected {
+            interrupts::disable();
+            crate::idle::wait();
+        }
+        return Err(FutexError::WouldBlock);
+    };
+
+    let were_enabled = interrupts::save_and_disable();
+    {
+        let mut waiters = bucket.waiters.lock();
+        if word.load(Ordering::Acquire) != expected {
+            drop(waiters);
+            interrupts::restore(were_enabled);
+            return Err(FutexError::WouldBlock);
+        }
+        waiters.push_back((key, current));
+    }
+
+    let deadline = timeout_ms.map(|ms| time::uptime_ms() + ms);
+    let timer = timeout_ms.map(|ms| timers::schedule_after(ms, wake_task, current.as_u64() as usize));
+    let result = loop {
+        task::block_current();
+        if !bucket.waiters.lock().contains(&(key, current)) {
+            break Ok(());
+        }
+        if deadline.is_some_and(|deadline| time::uptime_ms() >= deadline) && remove(bucket, key, current) {
+            break Err(FutexError::TimedOut);
+        }
+    };
+    if let Some(timer) = timer {
+        timers::cancel(timer);
+    }
+    interrupts::restore(were_enabled);
+    result
+}
+
+/// Wake up to `count` tasks waiting on `word`, returns how many were woken
+pub fn wake(word: &AtomicU32, count: usize) -> usize {
+    let key = word as *const AtomicU32 as u64;
+    let bucket = bucket(key);
+    let mut woken = alloc::vec::Vec::new();
+    {
+        let mut waiters = bucket.waiters.lock();
+        waiters.retain(|&(waiter_key, id)| {
+            if waiter_key == key && woken.len() < count {
+                woken.push(id);
+                false
+            } else {
+                true
+            }
+        });
+    }
+    for &id in &woken {
+        task::wake(id);
+    }
+    woken.len()
+}
+
+/// Number of tasks waiting on `word`
+pub fn waiters(word: &AtomicU32) -> usize {
+    let key = word as *const AtomicU32 as u64;
+    bucket(key).waiters.lock().iter().filter(|&&(waiter_key, _)| waiter_key == key).count()
+}
+
+static SELFTEST_WORD: AtomicU32 = AtomicU32::new(0);
+static SELFTEST_WOKEN: AtomicU32 = AtomicU32::new(0);
+
+fn selftest_waiter() {
+    while SELFTEST_WORD.load(Ordering::Acquire) == 0 {
+        let _ = wait(&SELFTEST_WORD, 0, Some(1000));
+    }
+    SELFTEST_WOKEN.fetch_add(1, Ordering::Release);
+}
+
+/// Stale values, timeouts and a wake handed to a blocked task
+fn selftest() -> Result<(), &'static str> {
+    if task::current_id().is_none() {
+        return Err("tasking not initialized");
+    }
+    let word = AtomicU32::new(1);
+    if wait(&word, 0, None) != Err(FutexError::WouldBlock) {
+        return Err("wait on changed value blocked");
+    }
+    if wait(&word, 1, Some(20)) != Err(FutexError::TimedOut) || waiters(&word) != 0 {
+        return Err("timed out waiter not removed");
+    }
+
+    SELFTEST_WORD.store(0, Ordering::Release);
+    SELFTEST_WOKEN.store(0, Ordering::Release);
+    task::spawn("futex-test", selftest_waiter).ok_or("spawn failed")?;
+    for _ in 0..100 {
+        if waiters(&SELFTEST_WORD) == 1 {
+            break;
+        }
+        task::yield_now();
+    }
+    let queued = waiters(&SELFTEST_WORD) == 1;
+    SELFTEST_WORD.store(1, Ordering::Release);
+    let woken = wake(&SELFTEST_WORD, usize::MAX);
+    for _ in 0..100 {
+        if SELFTEST_WOKEN.load(Ordering::Acquire) == 1 {
+            break;
+        }
+        task::yield_now();
+    }
+    if !queued || woken != 1 {
+        return Err("waiter not queued on the word");
+    }
+    if SELFTEST_WOKEN.load(Ordering::Acquire) != 1 {
+        return Err("woken task did not run");
+    }
+    Ok(())
+}
+
+crate::selftest!("futex", selftest);
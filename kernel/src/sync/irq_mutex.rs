@@ -0,0 +1,193 @@
+//! Interrupt-safe Mutex
+//!
+//! Debug builds panic with the holder and requester call sites instead of
+//! spinning forever on a lock that can never be released.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use crate::arch::x86_64::interrupts;
+
+/// Spin iterations before a contended lock is reported as a deadlock
+#[cfg(debug_assertions)]
+const DEADLOCK_SPIN_LIMIT: u64 = 100_000_000;
+
+/// Mutex that disables interrupts while held
+pub struct IrqMutex<T: ?Sized> {
+    name: &'static str,
+    holder: AtomicPtr<Location<'static>>,
+    inner: spin::Mutex<T>,
+}
+
+/// Interrupt-safe lock protecting no data
+pub type IrqSpinlock = IrqMutex<()>;
+
+impl<T> IrqMutex<T> {
+    /// Create a new unlocked mutex
+    pub const fn new(value: T) -> Self {
+        Self::named("anonymous", value)
+    }
+
+    /// Create a new unlocked mutex with a name used in deadlock reports
+    pub const fn named(name: &'static str, value: T) -> Self {
+        IrqMutex {
+            name,
+            holder: AtomicPtr::new(core::ptr::null_mut()),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    /// Consume the mutex, returning the protected value
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    /// Acquire the lock, disabling interrupts until the guard is dropped
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let were_enabled = interrupts::save_and_disable();
+        let guard = self.acquire(Location::caller());
+        IrqMutexGuard {
+            mutex: self,
+            guard: ManuallyDrop::new(guard),
+            were_enabled,
+        }
+    }
+
+    /// Try to acquire the lock without spinning
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let were_enabled = interrupts::save_and_disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                self.set_holder(Location::caller());
+                Some(IrqMutexGuard {
+                    mutex: self,
+                    guard: ManuallyDrop::new(guard),
+                    were_enabled,
+                })
+            }
+            None => {
+                interrupts::restore(were_enabled);
+                None
+            }
+        }
+    }
+
+    /// Check if the lock is currently held
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Name given at construction
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Call site of the current holder, if held
+    pub fn holder(&self) -> Option<&'static Location<'static>> {
+        let ptr = self.holder.load(Ordering::Relaxed);
+        if ptr.is_null() {
+            None
+        } else {
+            Some(unsafe { &*ptr })
+        }
+    }
+
+    /// Forcibly release the lock
+    ///
+    /// # Safety
+    /// Only for the panic path, where the holder will never run again.
+    pub unsafe fn force_unlock(&self) {
+        self.holder.store(core::ptr::null_mut(), Ordering::Relaxed);
+        self.inner.force_unlock();
+    }
+
+    fn set_holder(&self, location: &'static Location<'static>) {
+        self.holder.store(location as *const _ as *mut _, Ordering::Relaxed);
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn acquire(&self, location: &'static Location<'static>) -> spin::MutexGuard<'_, T> {
+        let guard = self.inner.lock();
+        self.set_holder(location);
+        guard
+    }
+
+    #[cfg(debug_assertions)]
+    fn acquire(&self, location: &'static Location<'static>) -> spin::MutexGuard<'_, T> {
+        let mut spins: u64 = 0;
+        loop {
+            if let Some(guard) = self.inner.try_lock() {
+                self.set_holder(location);
+                return guard;
+            }
+
+            spins += 1;
+            if spins >= DEADLOCK_SPIN_LIMIT {
+                self.report_deadlock(location);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn report_deadlock(&self, location: &'static Location<'static>) -> ! {
+        let context = if interrupts::in_interrupt() {
+            "interrupt"
+        } else {
+            "task"
+        };
+        match self.holder() {
+            Some(holder) => panic!(
+                "Deadlock on lock `{}`: requested at {} from {} context, held since {}",
+                self.name, location, context, holder
+            ),
+            None => panic!(
+                "Deadlock on lock `{}`: requested at {} from {} context",
+                self.name, location, context
+            ),
+        }
+    }
+}
+
+impl<T: Default> Default for IrqMutex<T> {
+    fn default() -> Self {
+        IrqMutex::new(T::default())
+    }
+}
+
+/// Guard that releases the lock and restores interrupts on drop
+pub struct IrqMutexGuard<'a, T: ?Sized> {
+    mutex: &'a IrqMutex<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    were_enabled: bool,
+}
+
+impl<T: ?Sized> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.holder.store(core::ptr::null_mut(), Ordering::Relaxed);
+        // Release the lock before interrupts can fire again
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        interrupts::restore(self.were_enabled);
+    }
+}
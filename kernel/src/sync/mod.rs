@@ -0,0 +1,19 @@
+//! Synchronization Primitives
+
+pub mod cancel;
+pub mod event;
+pub mod futex;
+pub mod irq_mutex;
+pub mod rcu;
+pub mod semaphore;
+pub mod seqlock;
+pub mod wait_queue;
+
+// Re-export core types
+pub use cancel::CancelToken;
+pub use event::Event;
+pub use irq_mutex::{IrqMutex, IrqMutexGuard, IrqSpinlock};
+pub use rcu::{Rcu, RcuReadGuard};
+pub use semaphore::Semaphore;
+pub use seqlock::SeqLock;
+pub use wait_queue::{WaitError, WaitQueue};
@@ -0,0 +1,110 @@
+//! Read-Copy-Update
+//!
+//! Readers take no lock: they pin the current version with a counter and
+//! dereference it. Writers publish a new heap-allocated version, then wait
+//! for readers of the old version to finish before freeing it. Updates must
+//! not be made from interrupt context, as a preempted reader would never
+//! finish and the grace period would never end.
+
+use alloc::boxed::Box;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// RCU-protected value
+pub struct Rcu<T> {
+    current: AtomicPtr<T>,
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    writer: spin::Mutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T> Rcu<T> {
+    /// Create a new RCU cell holding `value`
+    pub fn new(value: T) -> Self {
+        Rcu {
+            current: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: spin::Mutex::new(()),
+        }
+    }
+
+    /// Pin and access the current version
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        let slot = loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let slot = epoch & 1;
+            self.readers[slot].fetch_add(1, Ordering::SeqCst);
+            // Retry if a writer flipped the epoch before we were counted
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                break slot;
+            }
+            self.readers[slot].fetch_sub(1, Ordering::SeqCst);
+        };
+        let value = self.current.load(Ordering::SeqCst);
+        RcuReadGuard {
+            rcu: self,
+            slot,
+            value,
+        }
+    }
+
+    /// Publish a new version and free the old one after a grace period
+    pub fn replace(&self, value: T) {
+        let new = Box::into_raw(Box::new(value));
+        let old = {
+            // Writers stay serialized until the grace period ends
+            let _guard = self.writer.lock();
+            let old = self.current.swap(new, Ordering::SeqCst);
+            // New readers use the other slot and can only see the new version
+            let slot = self.epoch.fetch_add(1, Ordering::SeqCst) & 1;
+            self.synchronize(slot);
+            old
+        };
+        drop(unsafe { Box::from_raw(old) });
+    }
+
+    /// Publish a modified copy of the current version
+    pub fn update<F: FnOnce(&T) -> T>(&self, f: F) {
+        let value = f(&self.read());
+        self.replace(value);
+    }
+
+    /// Wait until all readers of an epoch slot have finished
+    fn synchronize(&self, slot: usize) {
+        while self.readers[slot].load(Ordering::SeqCst) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        let ptr = *self.current.get_mut();
+        drop(unsafe { Box::from_raw(ptr) });
+    }
+}
+
+/// Pinned reference to an RCU version
+pub struct RcuReadGuard<'a, T> {
+    rcu: &'a Rcu<T>,
+    slot: usize,
+    value: *const T,
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value }
+    }
+}
+
+impl<T> Drop for RcuReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rcu.readers[self.slot].fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,43 @@
+//! Counting Semaphore
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::WaitQueue;
+
+/// Counting semaphore with blocking acquire
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    /// Create a semaphore with `count` initial permits
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Take a permit, blocking until one is available
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Take a permit if one is available
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| count.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Return a permit, waking one waiter, safe from interrupt handlers
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Number of available permits
+    pub fn available(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,77 @@
+//! Sequence Lock
+//!
+//! Readers never block or take a lock: they retry if a writer was active
+//! while they copied the value. Suited to small `Copy` data that is read far
+//! more often than written, including from interrupt handlers.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use super::IrqSpinlock;
+
+/// Sequence lock protecting a `Copy` value
+pub struct SeqLock<T: Copy> {
+    sequence: AtomicUsize,
+    writer: IrqSpinlock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    /// Create a new sequence lock
+    pub const fn new(value: T) -> Self {
+        SeqLock {
+            sequence: AtomicUsize::new(0),
+            writer: IrqSpinlock::named("seqlock writer", ()),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Read a consistent copy of the value without locking
+    pub fn read(&self) -> T {
+        loop {
+            let start = self.sequence.load(Ordering::Acquire);
+            if start & 1 != 0 {
+                // Writer in progress
+                core::hint::spin_loop();
+                continue;
+            }
+
+            let value = unsafe { core::ptr::read_volatile(self.data.get()) };
+            fence(Ordering::Acquire);
+
+            if self.sequence.load(Ordering::Relaxed) == start {
+                return value;
+            }
+        }
+    }
+
+    /// Replace the value
+    pub fn write(&self, value: T) {
+        self.update(|data| *data = value);
+    }
+
+    /// Modify the value in place, writers are serialized with interrupts disabled
+    pub fn update<F: FnOnce(&mut T)>(&self, f: F) {
+        let _guard = self.writer.lock();
+
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        f(unsafe { &mut *self.data.get() });
+
+        self.sequence.fetch_add(1, Ordering::Release);
+    }
+
+    /// Current sequence number, odd while a write is in progress
+    pub fn sequence(&self) -> usize {
+        self.sequence.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Copy + Default> Default for SeqLock<T> {
+    fn default() -> Self {
+        SeqLock::new(T::default())
+    }
+}
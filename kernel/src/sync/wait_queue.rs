@@ -0,0 +1,261 @@
+//! Wait Queues
+//!
+//! Tasks block on a wait queue until another task or an interrupt handler
+//! wakes them. Waiters always recheck their condition after waking, so
+//! spurious wakeups are harmless. A single wakeup goes to the waiter of
+//! the highest priority, the longest waiting among equals.
+//!
+//! `wait_timeout` and `wait_cancellable` give up with an error once a
+//! deadline passes or a `CancelToken` is cancelled. A waiter that gives up
+//! after being picked by `wake_one` passes the wakeup on.
+//!
+//! Futures wait with `until`, which queues the waker of the task polling
+//! them. Tasks are woken ahead of futures.
+
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use super::{CancelToken, IrqMutex};
+use crate::arch::x86_64::interrupts;
+use crate::task::{self, Priority, TaskId};
+use crate::time;
+
+/// Reasons a wait ended without its condition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// The deadline passed
+    TimedOut,
+    /// The wait's token was cancelled
+    Cancelled,
+}
+
+impl core::fmt::Display for WaitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            WaitError::TimedOut => write!(f, "Wait timed out"),
+            WaitError::Cancelled => write!(f, "Wait cancelled"),
+        }
+    }
+}
+
+/// Queue of tasks waiting for a condition
+pub struct WaitQueue {
+    /// Waiters in arrival order, with their priority when they queued
+    waiters: IrqMutex<VecDeque<(TaskId, Priority)>>,
+    /// Wakers of waiting futures in arrival order
+    wakers: IrqMutex<VecDeque<Waker>>,
+}
+
+impl WaitQueue {
+    /// Create an empty wait queue
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqMutex::named("wait queue", VecDeque::new()),
+            wakers: IrqMutex::named("wait queue wakers", VecDeque::new()),
+        }
+    }
+
+    /// Block the current task until `condition` returns true
+    pub fn wait_until<F: FnMut() -> bool>(&self, condition: F) {
+        // Without a deadline or token the wait only ends with the condition
+        let _ = self.wait(None, None, condition);
+    }
+
+    /// Block the current task until `condition` returns true, or at most
+    /// `timeout_ms` milliseconds
+    pub fn wait_timeout<F: FnMut() -> bool>(&self, timeout_ms: u64, condition: F) -> Result<(), WaitError> {
+        self.wait(Some(time::ticks() + time::ms_to_ticks(timeout_ms)), None, condition)
+    }
+
+    /// Block the current task until `condition` returns true, or until
+    /// `cancel` is cancelled or reaches its deadline
+    pub fn wait_cancellable<F: FnMut() -> bool>(&self, cancel: &CancelToken, condition: F) -> Result<(), WaitError> {
+        self.wait(None, Some(cancel), condition)
+    }
+
+    fn wait<F: FnMut() -> bool>(
+        &self,
+        deadline: Option<u64>,
+        cancel: Option<&CancelToken>,
+        mut condition: F,
+    ) -> Result<(), WaitError> {
+        let deadline = match (deadline, cancel.and_then(CancelToken::deadline)) {
+            (Some(own), Some(token)) => Some(own.min(token)),
+            (own, token) => own.or(token),
+        };
+        // Whether the last wakeup was handed to this waiter by `wake_one`
+        let mut handed = false;
+        loop {
+            if condition() {
+                return Ok(());
+            }
+            if let Err(e) = check(deadline, cancel) {
+                if handed {
+                    self.wake_one();
+                }
+                return Err(e);
+            }
+
+            let were_enabled = interrupts::save_and_disable();
+            let current = task::current_id();
+            if let Some(id) = current {
+                self.enqueue(id);
+                if let Some(cancel) = cancel {
+                    cancel.waiters().enqueue(id);
+                }
+            }
+
+            // Recheck after queueing so a wakeup in between is not lost
+            let done = condition();
+            if !done && check(deadline, cancel).is_ok() {
+                match deadline {
+                    Some(deadline) => task::scheduler::sleep_until(deadline),
+                    None => task::block_current(),
+                }
+            }
+
+            // Woken by the deadline or the token, the entry is still queued
+            if let Some(id) = current {
+                handed = !self.remove(id);
+                if let Some(cancel) = cancel {
+                    cancel.waiters().remove(id);
+                }
+            }
+            interrupts::restore(were_enabled);
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Wait in a future until `condition` returns true
+    pub fn until<F: FnMut() -> bool + Unpin>(&self, condition: F) -> Until<'_, F> {
+        Until { queue: self, condition, waker: None }
+    }
+
+    /// Wake the longest-waiting task of the highest priority, or failing
+    /// that the longest-waiting future, returns false if none were waiting
+    pub fn wake_one(&self) -> bool {
+        let next = {
+            let mut waiters = self.waiters.lock();
+            let highest = waiters.iter().map(|&(_, priority)| priority).max();
+            let index = waiters.iter().position(|&(_, priority)| Some(priority) == highest);
+            index.and_then(|index| waiters.remove(index))
+        };
+        match next {
+            Some((id, _)) => {
+                task::wake(id);
+                true
+            }
+            None => {
+                // Wake outside the lock, the waker may take locks of its own
+                let waker = self.wakers.lock().pop_front();
+                waker.map(Waker::wake).is_some()
+            }
+        }
+    }
+
+    /// Wake every waiting task and future, returns how many were woken
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let mut count = waiters.len();
+        for (id, _) in waiters {
+            task::wake(id);
+        }
+        // One at a time, so waking never frees the queue's storage here
+        while let Some(waker) = self.wakers.lock().pop_front() {
+            waker.wake();
+            count += 1;
+        }
+        count
+    }
+
+    /// Number of tasks and futures currently waiting
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len() + self.wakers.lock().len()
+    }
+
+    /// Check if nothing is waiting
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn enqueue(&self, id: TaskId) {
+        let priority = task::scheduler::priority(id).unwrap_or(Priority::Normal);
+        self.waiters.lock().push_back((id, priority));
+    }
+
+    /// Take `id` off the queue, returns false if a wakeup already took it
+    fn remove(&self, id: TaskId) -> bool {
+        let mut waiters = self.waiters.lock();
+        let before = waiters.len();
+        waiters.retain(|&(waiter, _)| waiter != id);
+        waiters.len() != before
+    }
+}
+
+/// Error once `cancel` is cancelled or either deadline tick has passed
+fn check(deadline: Option<u64>, cancel: Option<&CancelToken>) -> Result<(), WaitError> {
+    if let Some(cancel) = cancel {
+        cancel.check()?;
+    }
+    match deadline {
+        Some(deadline) if time::ticks() >= deadline => Err(WaitError::TimedOut),
+        _ => Ok(()),
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        WaitQueue::new()
+    }
+}
+
+/// Future of `WaitQueue::until`
+pub struct Until<'a, F> {
+    queue: &'a WaitQueue,
+    condition: F,
+    /// Waker queued by the last poll
+    waker: Option<Waker>,
+}
+
+impl<F> Until<'_, F> {
+    fn unregister(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            self.queue.wakers.lock().retain(|queued| !queued.will_wake(&waker));
+        }
+    }
+}
+
+impl<F: FnMut() -> bool + Unpin> Future for Until<'_, F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if (this.condition)() {
+            this.unregister();
+            return Poll::Ready(());
+        }
+        {
+            let mut wakers = this.queue.wakers.lock();
+            if !wakers.iter().any(|queued| queued.will_wake(cx.waker())) {
+                wakers.push_back(cx.waker().clone());
+            }
+        }
+        this.waker = Some(cx.waker().clone());
+
+        // Recheck after queueing so a wakeup in between is not lost
+        if (this.condition)() {
+            this.unregister();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl<F> Drop for Until<'_, F> {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
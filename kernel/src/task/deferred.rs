@@ -0,0 +1,162 @@
+//! Deferred Work
+//!
+//! Interrupt handlers raise softirqs or queue work items and return
+//! immediately; the `kworker/deferred` task runs them later in task context,
+//! where blocking and heap allocation are safe. Queueing never allocates.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::sync::{IrqMutex, WaitQueue};
+
+/// Maximum queued work items before new ones are dropped
+pub const WORK_QUEUE_CAPACITY: usize = 256;
+
+/// Fixed softirq vectors, run in priority order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SoftIrq {
+    /// Timer expiry processing
+    Timer = 0,
+    /// Network receive processing
+    NetworkRx = 1,
+    /// Block device completions
+    Block = 2,
+    /// Miscellaneous driver work
+    Tasklet = 3,
+}
+
+/// Number of softirq vectors
+pub const SOFTIRQ_COUNT: usize = 4;
+
+/// Deferred work item
+#[derive(Clone, Copy)]
+struct WorkItem {
+    func: fn(usize),
+    arg: usize,
+}
+
+/// Fixed-capacity ring of pending work
+struct WorkRing {
+    items: [Option<WorkItem>; WORK_QUEUE_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl WorkRing {
+    const fn new() -> Self {
+        WorkRing {
+            items: [None; WORK_QUEUE_CAPACITY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, item: WorkItem) -> bool {
+        if self.len == WORK_QUEUE_CAPACITY {
+            return false;
+        }
+        let tail = (self.head + self.len) % WORK_QUEUE_CAPACITY;
+        self.items[tail] = Some(item);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<WorkItem> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % WORK_QUEUE_CAPACITY;
+        self.len -= 1;
+        item
+    }
+}
+
+/// Pending softirq bitmask
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+/// Handler slot for each softirq vector
+type HandlerTable = [Option<fn()>; SOFTIRQ_COUNT];
+
+/// Registered softirq handlers
+static HANDLERS: IrqMutex<HandlerTable> = IrqMutex::named("softirq handlers", [None; SOFTIRQ_COUNT]);
+
+/// Queued work items
+static WORK: IrqMutex<WorkRing> = IrqMutex::named("work queue", WorkRing::new());
+
+/// Worker wakeup
+static WAKEUP: WaitQueue = WaitQueue::new();
+
+/// Work items dropped because the queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Register the handler for a softirq vector
+pub fn register_softirq(softirq: SoftIrq, handler: fn()) {
+    HANDLERS.lock()[softirq as usize] = Some(handler);
+}
+
+/// Mark a softirq pending, safe from interrupt handlers
+pub fn raise(softirq: SoftIrq) {
+    PENDING.fetch_or(1 << softirq as u32, Ordering::Release);
+    WAKEUP.wake_one();
+}
+
+/// Queue `func(arg)` to run in task context, safe from interrupt handlers
+///
+/// Returns false if the queue is full and the work was dropped.
+pub fn queue_work(func: fn(usize), arg: usize) -> bool {
+    let queued = WORK.lock().push(WorkItem { func, arg });
+    if queued {
+        WAKEUP.wake_one();
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    queued
+}
+
+/// Check if any softirq or work item is pending
+pub fn has_pending() -> bool {
+    PENDING.load(Ordering::Acquire) != 0 || WORK.lock().len != 0
+}
+
+/// Number of work items dropped since boot
+pub fn dropped_count() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Run all pending softirqs and work items in the calling context
+pub fn run_pending() {
+    let pending = PENDING.swap(0, Ordering::AcqRel);
+    if pending != 0 {
+        let handlers = *HANDLERS.lock();
+        for (vector, handler) in handlers.iter().enumerate() {
+            if pending & (1 << vector) != 0 {
+                if let Some(handler) = handler {
+                    handler();
+                }
+            }
+        }
+    }
+
+    // Pop one at a time so work can be queued while we run
+    loop {
+        let item = WORK.lock().pop();
+        match item {
+            Some(item) => (item.func)(item.arg),
+            None => break,
+        }
+    }
+}
+
+/// Worker task body
+fn worker() {
+    loop {
+        WAKEUP.wait_until(has_pending);
+        run_pending();
+    }
+}
+
+/// Start the deferred work task
+pub fn init() {
+    // Softirqs stand in for interrupt work, so they go ahead of tasks
+    super::spawn_with_priority("kworker/deferred", worker, super::Priority::High);
+}
@@ -0,0 +1,342 @@
+//! Async Executor
+//!
+//! Futures spawned here run on the `executor` task, so a driver can be
+//! written as an async function that awaits its device instead of a chain
+//! of callbacks. A future waits on a `WaitQueue` with `until` or on an
+//! `Event` with `wait_async`, both of which interrupt handlers can signal,
+//! and waking a future never allocates. `sleep_ms` waits on a timer.
+//!
+//! Every spawned future shares the one task: a future that computes for
+//! long without awaiting holds up the rest, so long loops await
+//! `yield_now`. `block_on` runs a future on the calling task instead,
+//! blocking it between polls.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use crate::sync::{Event, IrqMutex, WaitQueue};
+use crate::time::timers::{self, TimerId};
+use super::TaskId;
+
+/// Errors that can occur spawning futures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutorError {
+    /// The executor task has not been started
+    NotRunning,
+}
+
+impl fmt::Display for ExecutorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutorError::NotRunning => write!(f, "Executor not running"),
+        }
+    }
+}
+
+type BoxedFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A spawned future, also its own waker
+struct Spawned {
+    name: &'static str,
+    /// Taken once the future completes
+    future: spin::Mutex<Option<BoxedFuture>>,
+    /// Woken since it was last polled
+    woken: AtomicBool,
+}
+
+impl Wake for Spawned {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        PENDING.store(true, Ordering::Release);
+        WAKEUP.wake_one();
+    }
+}
+
+/// Live futures by spawn order
+static FUTURES: IrqMutex<BTreeMap<u64, Arc<Spawned>>> = IrqMutex::named("executor", BTreeMap::new());
+
+/// Some future was woken since the executor last looked
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Executor task wakeup
+static WAKEUP: WaitQueue = WaitQueue::new();
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static SPAWNED: AtomicU64 = AtomicU64::new(0);
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+static POLLS: AtomicU64 = AtomicU64::new(0);
+
+/// Start the executor task
+pub fn init() {
+    if super::spawn("executor", run).is_some() {
+        RUNNING.store(true, Ordering::Release);
+    }
+}
+
+/// Run `future` on the executor task until it completes, `name` labels it
+/// in statistics
+pub fn spawn(
+    name: &'static str,
+    future: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), ExecutorError> {
+    if !RUNNING.load(Ordering::Acquire) {
+        return Err(ExecutorError::NotRunning);
+    }
+    let spawned = Arc::new(Spawned {
+        name,
+        future: spin::Mutex::new(Some(Box::pin(future))),
+        woken: AtomicBool::new(false),
+    });
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    FUTURES.lock().insert(id, spawned.clone());
+    SPAWNED.fetch_add(1, Ordering::Relaxed);
+    // First poll
+    spawned.wake();
+    Ok(())
+}
+
+fn run() {
+    loop {
+        WAKEUP.wait_until(|| PENDING.load(Ordering::Acquire));
+        PENDING.store(false, Ordering::Release);
+
+        // Wakeups from here on set `PENDING` again and are seen next round
+        let woken: Vec<(u64, Arc<Spawned>)> = FUTURES
+            .lock()
+            .iter()
+            .filter(|(_, spawned)| spawned.woken.swap(false, Ordering::AcqRel))
+            .map(|(&id, spawned)| (id, spawned.clone()))
+            .collect();
+        for (id, spawned) in woken {
+            let waker = Waker::from(spawned.clone());
+            let mut cx = Context::from_waker(&waker);
+            let mut future = spawned.future.lock();
+            let done = future.as_mut().is_none_or(|future| future.as_mut().poll(&mut cx).is_ready());
+            POLLS.fetch_add(1, Ordering::Relaxed);
+            if done {
+                *future = None;
+                drop(future);
+                FUTURES.lock().remove(&id);
+                COMPLETED.fetch_add(1, Ordering::Relaxed);
+            }
+            super::yield_if_needed();
+        }
+    }
+}
+
+/// Waker of a task running `block_on`
+struct TaskWaker {
+    /// None before tasking starts
+    task: Option<TaskId>,
+    woken: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        if let Some(task) = self.task {
+            super::wake(task);
+        }
+    }
+}
+
+/// Run `future` to completion on the calling task
+///
+/// The task blocks while the future is pending. Before tasking starts the
+/// CPU halts between polls instead.
+pub fn block_on<T>(future: impl Future<Output = T>) -> T {
+    let mut future = pin!(future);
+    let task_waker = Arc::new(TaskWaker { task: super::current_id(), woken: AtomicBool::new(false) });
+    let waker = Waker::from(task_waker.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+            return value;
+        }
+        // A wakeup before blocking leaves the task ready, so none is lost
+        while !task_waker.woken.swap(false, Ordering::AcqRel) {
+            match task_waker.task {
+                Some(_) => super::block_current(),
+                None => {
+                    crate::arch::x86_64::interrupts::disable();
+                    crate::idle::wait();
+                }
+            }
+        }
+    }
+}
+
+/// Timer state shared with the callback of a `Sleep`
+struct SleepState {
+    fired: AtomicBool,
+    waker: IrqMutex<Option<Waker>>,
+}
+
+/// Future of `sleep_ms`
+pub struct Sleep {
+    ms: u64,
+    /// Timer, armed on the first poll
+    timer: Option<(TimerId, Arc<SleepState>)>,
+}
+
+/// Wait at least `ms` milliseconds in a future
+pub fn sleep_ms(ms: u64) -> Sleep {
+    Sleep { ms, timer: None }
+}
+
+/// Timer callback, `arg` holds a reference to the `SleepState`
+fn sleep_expired(arg: usize) {
+    let state = unsafe { Arc::from_raw(arg as *const SleepState) };
+    state.fired.store(true, Ordering::Release);
+    let waker = state.waker.lock().take();
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let ms = self.ms;
+        let (_, state) = self.timer.get_or_insert_with(|| {
+            let state = Arc::new(SleepState { fired: AtomicBool::new(false), waker: IrqMutex::new(None) });
+            let arg = Arc::into_raw(state.clone()) as usize;
+            (timers::schedule_after(ms, sleep_expired, arg), state)
+        });
+        *state.waker.lock() = Some(cx.waker().clone());
+        // Checked after storing the waker so an expiry in between is seen
+        if state.fired.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some((timer, state)) = self.timer.take() {
+            // A cancelled timer never runs, so its reference is dropped here
+            if timers::cancel(timer) {
+                drop(unsafe { Arc::from_raw(Arc::as_ptr(&state)) });
+            }
+        }
+    }
+}
+
+/// Future of `yield_now`
+pub struct YieldNow {
+    yielded: bool,
+}
+
+/// Let the other woken futures run before continuing
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Executor figures
+#[derive(Debug, Clone, Copy)]
+pub struct ExecutorStats {
+    /// Futures not yet completed
+    pub live: usize,
+    pub spawned: u64,
+    pub completed: u64,
+    pub polls: u64,
+}
+
+/// Snapshot the executor figures
+pub fn stats() -> ExecutorStats {
+    ExecutorStats {
+        live: FUTURES.lock().len(),
+        spawned: SPAWNED.load(Ordering::Relaxed),
+        completed: COMPLETED.load(Ordering::Relaxed),
+        polls: POLLS.load(Ordering::Relaxed),
+    }
+}
+
+/// Names of the futures not yet completed, in spawn order
+pub fn futures() -> Vec<&'static str> {
+    FUTURES.lock().values().map(|spawned| spawned.name).collect()
+}
+
+static SELFTEST_EVENT: Event = Event::new();
+/// Steps the self-test future got through
+static SELFTEST_STEPS: AtomicU32 = AtomicU32::new(0);
+
+async fn selftest_future() {
+    SELFTEST_EVENT.wait_async().await;
+    SELFTEST_STEPS.store(1, Ordering::Release);
+    sleep_ms(20).await;
+    SELFTEST_STEPS.store(2, Ordering::Release);
+}
+
+/// A spawned future waits for its event and its timer, and `block_on`
+/// completes
+fn selftest() -> Result<(), &'static str> {
+    if block_on(async {
+        yield_now().await;
+        2 + 2
+    }) != 4
+    {
+        return Err("block_on returned the wrong value");
+    }
+    if !RUNNING.load(Ordering::Acquire) || !crate::arch::x86_64::interrupts::are_enabled() {
+        return Ok(());
+    }
+
+    SELFTEST_EVENT.reset();
+    SELFTEST_STEPS.store(0, Ordering::Release);
+    spawn("selftest", selftest_future()).map_err(|_| "spawn failed")?;
+    for _ in 0..10 {
+        super::yield_now();
+    }
+    if SELFTEST_STEPS.load(Ordering::Acquire) != 0 {
+        return Err("future ran before its event");
+    }
+    SELFTEST_EVENT.set();
+    for _ in 0..100 {
+        if SELFTEST_STEPS.load(Ordering::Acquire) == 2 {
+            break;
+        }
+        crate::time::sleep_ms(5);
+    }
+    if SELFTEST_STEPS.load(Ordering::Acquire) != 2 {
+        return Err("future did not complete");
+    }
+    if futures().contains(&"selftest") {
+        return Err("completed future still live");
+    }
+    Ok(())
+}
+
+crate::selftest!("executor", selftest);
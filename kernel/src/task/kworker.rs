@@ -0,0 +1,280 @@
+//! Kernel Worker Threads
+//!
+//! `spawn` starts a dedicated thread for a long-running background loop,
+//! such as polling a device. `queue` hands a short job to a pool of
+//! `POOL_SIZE` shared threads. Unlike deferred work, jobs may block and
+//! take their time, and queueing allocates, so it is for task context
+//! only.
+//!
+//! A worker that panics does not take the kernel down: the panic is
+//! reported and counted against the worker, and only its thread ends. A
+//! pool job that panics is lost, and the pool starts a replacement thread
+//! the next time work is queued. This needs the panic to happen in task
+//! context with interrupts and preemption enabled; a panic in an interrupt
+//! handler, under an `IrqMutex` or with preemption disabled still halts
+//! the kernel. Anything else the worker held, a plain spinlock included,
+//! stays as the worker left it.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::arch::x86_64::interrupts;
+use crate::sync::{IrqMutex, WaitQueue};
+use super::TaskId;
+
+/// Threads in the shared pool
+pub const POOL_SIZE: usize = 2;
+
+/// Names of the pool threads, one per slot
+const POOL_NAMES: [&str; POOL_SIZE] = ["kworker/0", "kworker/1"];
+
+/// How a worker gets its work
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerKind {
+    /// Runs one function for as long as it likes
+    Dedicated,
+    /// Takes queued jobs
+    Pool,
+}
+
+impl WorkerKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            WorkerKind::Dedicated => "dedicated",
+            WorkerKind::Pool => "pool",
+        }
+    }
+}
+
+/// Errors that can occur starting workers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KworkerError {
+    /// The scheduler could not start the thread
+    SpawnFailed,
+}
+
+impl fmt::Display for KworkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KworkerError::SpawnFailed => write!(f, "Could not start the worker thread"),
+        }
+    }
+}
+
+struct Worker {
+    id: TaskId,
+    name: &'static str,
+    kind: WorkerKind,
+    /// Function a dedicated worker runs
+    entry: Option<fn()>,
+    /// Name of the job running, for panic reports
+    job: Option<&'static str>,
+    jobs: u64,
+    panics: u64,
+    alive: bool,
+}
+
+struct Job {
+    name: &'static str,
+    func: fn(usize),
+    arg: usize,
+}
+
+/// Every worker started, ended ones kept for their statistics
+static WORKERS: IrqMutex<Vec<Worker>> = IrqMutex::named("kworkers", Vec::new());
+
+/// Jobs waiting for a pool thread
+static JOBS: IrqMutex<VecDeque<Job>> = IrqMutex::named("kworker jobs", VecDeque::new());
+
+/// Pool thread wakeup
+static WAKEUP: WaitQueue = WaitQueue::new();
+
+/// Start a task for a worker and record it, under the worker lock so the
+/// task cannot look for its record before it is there
+fn start(
+    workers: &mut Vec<Worker>,
+    name: &'static str,
+    kind: WorkerKind,
+    entry: Option<fn()>,
+) -> Result<TaskId, KworkerError> {
+    let main: fn() = match kind {
+        WorkerKind::Dedicated => dedicated_main,
+        WorkerKind::Pool => pool_main,
+    };
+    let id = super::spawn(name, main).ok_or(KworkerError::SpawnFailed)?;
+    workers.push(Worker { id, name, kind, entry, job: None, jobs: 0, panics: 0, alive: true });
+    Ok(id)
+}
+
+/// Run `entry` on a thread of its own
+pub fn spawn(name: &'static str, entry: fn()) -> Result<TaskId, KworkerError> {
+    start(&mut WORKERS.lock(), name, WorkerKind::Dedicated, Some(entry))
+}
+
+/// Start pool threads until `POOL_SIZE` are running
+fn fill_pool() -> Result<(), KworkerError> {
+    let mut workers = WORKERS.lock();
+    for name in POOL_NAMES {
+        if !workers.iter().any(|worker| worker.alive && worker.name == name) {
+            start(&mut workers, name, WorkerKind::Pool, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run `func(arg)` on a pool thread, `name` labels it in panic reports
+pub fn queue(name: &'static str, func: fn(usize), arg: usize) -> Result<(), KworkerError> {
+    fill_pool()?;
+    JOBS.lock().push_back(Job { name, func, arg });
+    WAKEUP.wake_one();
+    Ok(())
+}
+
+/// Number of jobs waiting for a pool thread
+pub fn queued() -> usize {
+    JOBS.lock().len()
+}
+
+/// Run `f` on the running task's worker record
+fn with_current<R>(f: impl FnOnce(&mut Worker) -> R) -> Option<R> {
+    let id = super::current_id()?;
+    WORKERS.lock().iter_mut().find(|worker| worker.id == id).map(f)
+}
+
+fn dedicated_main() {
+    let entry = with_current(|worker| {
+        worker.job = Some(worker.name);
+        worker.entry
+    });
+    if let Some(Some(entry)) = entry {
+        entry();
+    }
+    with_current(|worker| {
+        worker.job = None;
+        worker.jobs += 1;
+        worker.alive = false;
+    });
+}
+
+fn pool_main() {
+    loop {
+        WAKEUP.wait_until(|| !JOBS.lock().is_empty());
+        let Some(job) = JOBS.lock().pop_front() else {
+            continue;
+        };
+        with_current(|worker| worker.job = Some(job.name));
+        (job.func)(job.arg);
+        with_current(|worker| {
+            worker.job = None;
+            worker.jobs += 1;
+        });
+    }
+}
+
+/// End the running task instead of the kernel if it is a worker that can
+/// be stopped here, called by the panic handler
+///
+/// Returns if the panic must halt the kernel.
+pub fn contain_panic(info: &PanicInfo) {
+    if interrupts::in_interrupt() || !interrupts::are_enabled() || !super::preempt::is_enabled() {
+        return;
+    }
+    let Some(id) = super::current_id() else {
+        return;
+    };
+    let Some(mut workers) = WORKERS.try_lock() else {
+        return;
+    };
+    let Some(worker) = workers.iter_mut().find(|worker| worker.id == id && worker.alive) else {
+        return;
+    };
+    worker.panics += 1;
+    worker.alive = false;
+    let (name, job) = (worker.name, worker.job.take().unwrap_or("job"));
+    drop(workers);
+
+    match info.location() {
+        Some(location) => crate::serial_println!("{}: {} panicked at {}: {}", name, job, location, info.message()),
+        None => crate::serial_println!("{}: {} panicked: {}", name, job, info.message()),
+    }
+    super::scheduler::exit_current()
+}
+
+/// Figures of one worker
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerStats {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub kind: WorkerKind,
+    /// Jobs finished, 1 for a dedicated worker whose function returned
+    pub jobs: u64,
+    pub panics: u64,
+    pub alive: bool,
+    /// TSC cycles the thread has run, 0 once it is gone
+    pub runtime: u64,
+}
+
+/// Snapshot the figures of every worker
+pub fn workers() -> Vec<WorkerStats> {
+    let tasks = super::scheduler::task_stats();
+    WORKERS
+        .lock()
+        .iter()
+        .map(|worker| WorkerStats {
+            id: worker.id,
+            name: worker.name,
+            kind: worker.kind,
+            jobs: worker.jobs,
+            panics: worker.panics,
+            alive: worker.alive,
+            runtime: tasks.iter().find(|task| task.id == worker.id).map_or(0, |task| task.runtime),
+        })
+        .collect()
+}
+
+/// Jobs run by the self-test
+static SELFTEST_RAN: AtomicUsize = AtomicUsize::new(0);
+
+fn selftest_job(arg: usize) {
+    SELFTEST_RAN.fetch_add(arg, Ordering::Relaxed);
+}
+
+fn selftest_panic() {
+    panic!("self-test");
+}
+
+/// Queued jobs run, and a panicking worker ends alone
+fn selftest() -> Result<(), &'static str> {
+    SELFTEST_RAN.store(0, Ordering::Relaxed);
+    for _ in 0..3 {
+        queue("selftest", selftest_job, 1).map_err(|_| "queue failed")?;
+    }
+    // The worker inherits the interrupt state, and cannot be stopped
+    // without interrupts
+    let panicking = if interrupts::are_enabled() {
+        Some(spawn("kworker-test", selftest_panic).map_err(|_| "spawn failed")?)
+    } else {
+        None
+    };
+    let ended = |id| !workers().iter().any(|worker| worker.id == id && worker.alive);
+    for _ in 0..100 {
+        if SELFTEST_RAN.load(Ordering::Relaxed) == 3 && panicking.is_none_or(ended) {
+            break;
+        }
+        super::yield_now();
+    }
+    if SELFTEST_RAN.load(Ordering::Relaxed) != 3 {
+        return Err("queued jobs did not run");
+    }
+    let Some(id) = panicking else {
+        return Ok(());
+    };
+    match workers().iter().find(|worker| worker.id == id) {
+        Some(worker) if !worker.alive && worker.panics == 1 => Ok(()),
+        _ => Err("panic not contained to the worker"),
+    }
+}
+
+crate::selftest!("kworker", selftest);
@@ -0,0 +1,105 @@
+//! Load Averages
+//!
+//! Every five seconds the timer counts the tasks on each CPU that are
+//! running or ready to run, the idle task aside, and folds the count into
+//! averages decaying over one, five and fifteen minutes. The arithmetic
+//! is fixed point with 11 fraction bits, as on Unix, so the figures read
+//! the same as `uptime` elsewhere.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::arch::x86_64::cpu::MAX_CPUS;
+use crate::time::TICK_HZ;
+
+const FSHIFT: u32 = 11;
+const FIXED_1: u64 = 1 << FSHIFT;
+
+/// Ticks between samples
+pub const SAMPLE_TICKS: u64 = 5 * TICK_HZ;
+
+/// Weight an average keeps per sample, e^(-5s / period) in fixed point
+const DECAY: [u64; 3] = [1884, 2014, 2037];
+
+/// One, five and fifteen minute averages per CPU
+static AVERAGES: [[AtomicU64; 3]; MAX_CPUS] = [const { [const { AtomicU64::new(0) }; 3] }; MAX_CPUS];
+/// Context switches per CPU
+static SWITCHES: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// A load average in fixed point
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LoadAverage(u64);
+
+impl fmt::Display for LoadAverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Round to the hundredth shown
+        let value = self.0 + FIXED_1 / 200;
+        write!(f, "{}.{:02}", value >> FSHIFT, ((value & (FIXED_1 - 1)) * 100) >> FSHIFT)
+    }
+}
+
+/// `average` after a sample of `runnable` tasks
+fn fold(average: u64, decay: u64, runnable: usize) -> u64 {
+    let active = runnable as u64 * FIXED_1;
+    let mut next = average * decay + active * (FIXED_1 - decay);
+    // Round towards the sample so a steady load is reached, not approached
+    if active >= average {
+        next += FIXED_1 - 1;
+    }
+    next / FIXED_1
+}
+
+/// Fold a sample into the averages of `cpu`, from the timer interrupt
+pub(super) fn sample(cpu: usize, runnable: usize) {
+    for (average, decay) in AVERAGES[cpu].iter().zip(DECAY) {
+        average.store(fold(average.load(Ordering::Relaxed), decay, runnable), Ordering::Relaxed);
+    }
+}
+
+/// Count a context switch on `cpu`
+pub(super) fn record_switch(cpu: usize) {
+    SWITCHES[cpu].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Load figures of one CPU
+#[derive(Debug, Clone, Copy)]
+pub struct CpuLoad {
+    pub cpu: usize,
+    /// Over one, five and fifteen minutes
+    pub averages: [LoadAverage; 3],
+    pub switches: u64,
+}
+
+/// Snapshot the load of every CPU
+pub fn cpus() -> Vec<CpuLoad> {
+    (0..MAX_CPUS)
+        .map(|cpu| CpuLoad {
+            cpu,
+            averages: AVERAGES[cpu].each_ref().map(|average| LoadAverage(average.load(Ordering::Relaxed))),
+            switches: SWITCHES[cpu].load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// A minute at a load of one gives the figures Unix shows
+fn selftest() -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    let mut averages = [0; 3];
+    // One minute of samples
+    for _ in 0..12 {
+        for (average, decay) in averages.iter_mut().zip(DECAY) {
+            *average = fold(*average, decay, 1);
+        }
+    }
+    let mut text = cosmos_common::fmt::FixedBuf::<16>::new();
+    for average in averages {
+        let _ = write!(text, "{} ", LoadAverage(average));
+    }
+    if text.as_str() != "0.63 0.18 0.06 " {
+        return Err("averages decay at the wrong rate");
+    }
+    Ok(())
+}
+
+crate::selftest!("load", selftest);
@@ -0,0 +1,205 @@
+//! Kernel Tasks
+
+pub mod deferred;
+pub mod executor;
+pub mod kworker;
+pub mod load;
+pub mod preempt;
+pub mod scheduler;
+pub mod signal;
+pub mod tls;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::arch::x86_64::fpu::FpuState;
+use signal::SignalState;
+use tls::TlsBlock;
+
+// Re-export core functions
+pub use scheduler::{spawn, spawn_with_priority, yield_now, current_id, block_current, wake};
+pub use preempt::yield_if_needed;
+
+/// Default kernel task stack size, 64KB
+pub const TASK_STACK_SIZE: usize = 64 * 1024;
+
+/// Unique task identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    /// Allocate a new unique task ID
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Get the raw ID value
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Rebuild an ID from its raw value
+    pub const fn from_u64(id: u64) -> Self {
+        TaskId(id)
+    }
+}
+
+/// Task scheduling state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Waiting in the run queue
+    Ready,
+    /// Currently executing
+    Running,
+    /// Waiting to be woken
+    Blocked,
+    /// Waiting for a tick, or to be woken
+    Sleeping,
+    /// Returned from its entry point, waiting to be reaped
+    Finished,
+}
+
+impl TaskState {
+    pub fn name(self) -> &'static str {
+        match self {
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Blocked => "blocked",
+            TaskState::Sleeping => "sleeping",
+            TaskState::Finished => "finished",
+        }
+    }
+}
+
+/// Scheduling priority, the scheduler picks from the highest priority
+/// with a ready task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl Priority {
+    /// Every priority, lowest first
+    pub const ALL: [Priority; 3] = [Priority::Low, Priority::Normal, Priority::High];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    /// Priority called `name`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|priority| priority.name() == name)
+    }
+}
+
+/// Kernel task control block
+pub struct Task {
+    id: TaskId,
+    name: &'static str,
+    state: TaskState,
+    priority: Priority,
+    /// Tick a sleeping task wakes at
+    wake_at: u64,
+    /// TSC cycles spent running, up to the last switch away
+    runtime: u64,
+    /// Times switched to
+    switches: u64,
+    /// Saved stack pointer while switched out
+    rsp: u64,
+    /// Owned stack, `None` for the boot task running on the bootloader stack
+    stack: Option<Box<[u8]>>,
+    /// Entry point, taken on first run
+    entry: Option<fn()>,
+    /// Wakeup arrived before the task blocked
+    wake_pending: bool,
+    /// FPU, SSE and AVX registers while switched out
+    fpu: FpuState,
+    /// Copy of the `#[thread_local]` statics
+    tls: TlsBlock,
+    /// Pending and blocked signals and their actions
+    signals: SignalState,
+}
+
+impl Task {
+    /// Create the task representing the already-running boot context
+    fn boot() -> Self {
+        Task {
+            id: TaskId::new(),
+            name: "kernel",
+            state: TaskState::Running,
+            priority: Priority::Normal,
+            wake_at: 0,
+            runtime: 0,
+            switches: 0,
+            rsp: 0,
+            stack: None,
+            entry: None,
+            wake_pending: false,
+            fpu: FpuState::new(),
+            tls: TlsBlock::new(),
+            signals: SignalState::new(),
+        }
+    }
+
+    /// Create a new task with its own stack
+    fn new(name: &'static str, entry: fn(), priority: Priority) -> Self {
+        let mut stack = vec![0u8; TASK_STACK_SIZE].into_boxed_slice();
+        let rsp = crate::arch::x86_64::context::init_stack(&mut stack, scheduler::task_trampoline);
+        Task {
+            id: TaskId::new(),
+            name,
+            state: TaskState::Ready,
+            priority,
+            wake_at: 0,
+            runtime: 0,
+            switches: 0,
+            rsp,
+            stack: Some(stack),
+            entry: Some(entry),
+            wake_pending: false,
+            fpu: FpuState::new(),
+            tls: TlsBlock::new(),
+            signals: SignalState::new(),
+        }
+    }
+
+    /// Get the task ID
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Get the task name
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Get the scheduling state
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+
+    /// Get the scheduling priority
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Size of the owned stack in bytes, 0 for the boot task
+    pub fn stack_size(&self) -> usize {
+        self.stack.as_ref().map_or(0, |stack| stack.len())
+    }
+}
+
+/// Initialize tasking, adopting the current context as the boot task
+pub fn init() {
+    crate::idle::init();
+    scheduler::init();
+    deferred::init();
+    executor::init();
+}
@@ -0,0 +1,108 @@
+//! Preemption Points
+//!
+//! Tasks only switch when they yield, so a long loop in the kernel keeps
+//! every other task waiting, the shell's echo included. The timer marks
+//! the running task due once it has run for `TIME_SLICE_TICKS`, and long
+//! operations call `yield_if_needed` between steps to give up the CPU
+//! then.
+//!
+//! A yield is skipped inside interrupt handlers, with interrupts disabled,
+//! which covers holding an `IrqMutex`, and while preemption is disabled.
+//! Code holding a plain spinlock another task could wait on must call
+//! `disable` and `enable` around anything that reaches a preemption point.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use crate::arch::x86_64::cpu::{self, MAX_CPUS};
+use crate::arch::x86_64::interrupts;
+
+/// Ticks a task runs before a preemption point gives up the CPU
+pub const TIME_SLICE_TICKS: u64 = 2;
+
+/// Nested `disable` calls per CPU
+static DISABLED: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+/// Set by the timer once the running task's slice is used up
+static NEED_RESCHED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+/// Tick the running task's slice started at
+static SLICE_START: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Keep the running task from yielding at preemption points until the
+/// matching `enable`
+///
+/// Calls nest. Explicit yields and blocking still switch tasks.
+pub fn disable() {
+    DISABLED[cpu::current_id()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Undo one `disable`
+pub fn enable() {
+    let previous = DISABLED[cpu::current_id()].fetch_sub(1, Ordering::Relaxed);
+    debug_assert!(previous != 0, "preemption enabled more often than disabled");
+}
+
+/// Check if preemption points may yield on this CPU
+pub fn is_enabled() -> bool {
+    DISABLED[cpu::current_id()].load(Ordering::Relaxed) == 0
+}
+
+/// Check if the running task has used up its slice
+pub fn need_resched() -> bool {
+    NEED_RESCHED[cpu::current_id()].load(Ordering::Relaxed)
+}
+
+/// Start a new slice, called by the scheduler whenever a task passes
+/// through it
+pub(super) fn start_slice() {
+    let cpu = cpu::current_id();
+    SLICE_START[cpu].store(crate::time::ticks(), Ordering::Relaxed);
+    NEED_RESCHED[cpu].store(false, Ordering::Relaxed);
+}
+
+/// Mark the running task due now, for a task of a higher priority that
+/// became ready
+pub(super) fn request() {
+    NEED_RESCHED[cpu::current_id()].store(true, Ordering::Relaxed);
+}
+
+/// Mark the running task due once its slice is over, from the timer
+/// interrupt
+pub fn tick(now: u64) {
+    let cpu = cpu::current_id();
+    if now.saturating_sub(SLICE_START[cpu].load(Ordering::Relaxed)) >= TIME_SLICE_TICKS {
+        NEED_RESCHED[cpu].store(true, Ordering::Relaxed);
+    }
+}
+
+/// Yield if the running task has used up its slice and may be switched
+/// out here
+pub fn yield_if_needed() {
+    if need_resched() && is_enabled() && interrupts::are_enabled() && !interrupts::in_interrupt() {
+        super::yield_now();
+    }
+}
+
+/// A due task yields only with preemption enabled
+fn selftest() -> Result<(), &'static str> {
+    let cpu = cpu::current_id();
+    disable();
+    NEED_RESCHED[cpu].store(true, Ordering::Relaxed);
+    yield_if_needed();
+    let held = need_resched();
+    enable();
+    if !held {
+        return Err("yielded with preemption disabled");
+    }
+    if !interrupts::are_enabled() {
+        return Ok(());
+    }
+    // The slice restarts in the scheduler, the flag could be set again by
+    // the time this task runs
+    SLICE_START[cpu].store(0, Ordering::Relaxed);
+    NEED_RESCHED[cpu].store(true, Ordering::Relaxed);
+    yield_if_needed();
+    if SLICE_START[cpu].load(Ordering::Relaxed) == 0 {
+        return Err("due task did not yield");
+    }
+    Ok(())
+}
+
+crate::selftest!("preempt", selftest);
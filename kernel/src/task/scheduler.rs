@@ -0,0 +1,516 @@
+//! Cooperative Priority Scheduler
+//!
+//! Ready tasks wait in one queue per priority and take turns within it,
+//! the scheduler always picking from the highest priority that has a
+//! ready task. A task that yields still gives way to a lower priority when
+//! nothing else is ready, so a polling loop cannot starve the system.
+//!
+//! Sleeping tasks wait in a queue ordered by the tick they wake at, which
+//! the timer interrupt drains. The scheduler also keeps how long each task
+//! has run, for `top`.
+
+use super::{load, preempt, Priority, Task, TaskId, TaskState};
+use crate::arch::x86_64::{context, cpu, fpu, interrupts};
+use crate::sync::IrqMutex;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BinaryHeap, VecDeque};
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Scheduler state
+struct Scheduler {
+    tasks: BTreeMap<TaskId, Box<Task>>,
+    /// Ready tasks, one queue per priority
+    ready: [VecDeque<TaskId>; Priority::ALL.len()],
+    /// Sleeping tasks by wake tick, entries of tasks woken early are skipped
+    sleepers: BinaryHeap<Reverse<(u64, TaskId)>>,
+    current: TaskId,
+    /// Runs only when nothing else is ready, never queued
+    idle: Option<TaskId>,
+    /// Interrupt state of the last task to switch away, inherited by new tasks
+    switch_interrupts: bool,
+    /// TSC when the running task was switched to
+    switched_at: u64,
+}
+
+/// Global scheduler instance
+static SCHEDULER: IrqMutex<Option<Scheduler>> = IrqMutex::named("scheduler", None);
+
+/// Earliest wake tick in the sleep queue, read lock-free by the tick handler
+static NEXT_WAKE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Errors that can occur changing a task's scheduling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerError {
+    /// No task with that ID
+    NoTask,
+    /// The idle task runs only when nothing else can
+    IdleTask,
+}
+
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedulerError::NoTask => write!(f, "No such task"),
+            SchedulerError::IdleTask => write!(f, "The idle task has no priority"),
+        }
+    }
+}
+
+/// Adopt the running context as the boot task and create the idle task
+pub(super) fn init() {
+    let mut scheduler = SCHEDULER.lock();
+    if scheduler.is_some() {
+        return;
+    }
+    let boot = Box::new(Task::boot());
+    // The boot task starts from a clean FPU state like every other task
+    fpu::switch_to(&boot.fpu);
+    super::tls::init(&boot.tls, boot.id);
+    let idle = Box::new(Task::new("idle", idle_task, Priority::Low));
+    let current = boot.id;
+    let idle_id = idle.id;
+    let mut tasks = BTreeMap::new();
+    tasks.insert(current, boot);
+    tasks.insert(idle_id, idle);
+    *scheduler = Some(Scheduler {
+        tasks,
+        ready: core::array::from_fn(|_| VecDeque::new()),
+        sleepers: BinaryHeap::new(),
+        current,
+        idle: Some(idle_id),
+        switch_interrupts: false,
+        switched_at: unsafe { core::arch::x86_64::_rdtsc() },
+    });
+}
+
+/// Check if the scheduler has been initialized
+pub fn is_initialized() -> bool {
+    SCHEDULER.lock().is_some()
+}
+
+/// Spawn a new kernel task at normal priority
+pub fn spawn(name: &'static str, entry: fn()) -> Option<TaskId> {
+    spawn_with_priority(name, entry, Priority::Normal)
+}
+
+/// Spawn a new kernel task at `priority`
+pub fn spawn_with_priority(name: &'static str, entry: fn(), priority: Priority) -> Option<TaskId> {
+    let task = Box::new(Task::new(name, entry, priority));
+    let id = task.id;
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut()?;
+    scheduler.tasks.insert(id, task);
+    scheduler.enqueue(id, priority);
+    Some(id)
+}
+
+/// Get the ID of the running task
+pub fn current_id() -> Option<TaskId> {
+    super::tls::current_task()
+}
+
+/// Current task id without blocking, `None` if the scheduler lock is held
+///
+/// For interrupt context, where waiting on the lock could deadlock.
+pub fn try_current_id() -> Option<Option<TaskId>> {
+    SCHEDULER.try_lock().map(|s| s.as_ref().map(|s| s.current))
+}
+
+/// Give up the CPU to the next ready task
+pub fn yield_now() {
+    schedule(TaskState::Ready);
+}
+
+/// Block the running task until `wake` is called for it
+///
+/// Without a scheduler this halts until the next interrupt, so callers
+/// must recheck their wait condition.
+pub fn block_current() {
+    schedule(TaskState::Blocked);
+}
+
+/// Sleep the running task until tick `deadline`
+///
+/// Returns at once if the tick has passed, and early if the task is woken
+/// with `wake`, so callers loop until their deadline.
+pub fn sleep_until(deadline: u64) {
+    if with_current(|task| task.wake_at = deadline).is_some() {
+        schedule(TaskState::Sleeping);
+    }
+}
+
+/// Make a blocked or sleeping task runnable
+pub fn wake(id: TaskId) {
+    let mut scheduler = SCHEDULER.lock();
+    let Some(scheduler) = scheduler.as_mut() else {
+        return;
+    };
+    let Some(task) = scheduler.tasks.get_mut(&id) else {
+        return;
+    };
+    match task.state {
+        TaskState::Blocked | TaskState::Sleeping => {
+            task.state = TaskState::Ready;
+            let priority = task.priority;
+            scheduler.enqueue(id, priority);
+        }
+        TaskState::Running | TaskState::Ready => task.wake_pending = true,
+        TaskState::Finished => {}
+    }
+}
+
+/// Priority of task `id`
+pub fn priority(id: TaskId) -> Option<Priority> {
+    with_task(id, |task, _| task.priority)
+}
+
+/// Change the priority of task `id`, a ready task moves to its new queue
+pub fn set_priority(id: TaskId, priority: Priority) -> Result<(), SchedulerError> {
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut().ok_or(SchedulerError::NoTask)?;
+    if scheduler.idle == Some(id) {
+        return Err(SchedulerError::IdleTask);
+    }
+    let task = scheduler.tasks.get_mut(&id).ok_or(SchedulerError::NoTask)?;
+    let previous = core::mem::replace(&mut task.priority, priority);
+    if task.state == TaskState::Ready && previous != priority {
+        scheduler.ready[previous as usize].retain(|&queued| queued != id);
+        scheduler.enqueue(id, priority);
+    }
+    Ok(())
+}
+
+/// Run `f` on task `id`, also passing whether it is the boot or idle task
+pub(super) fn with_task<R>(id: TaskId, f: impl FnOnce(&mut Task, bool) -> R) -> Option<R> {
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut()?;
+    let protected = scheduler.idle == Some(id);
+    let task = scheduler.tasks.get_mut(&id)?;
+    let protected = protected || task.stack.is_none();
+    Some(f(task, protected))
+}
+
+/// Run `f` on the running task
+pub(super) fn with_current<R>(f: impl FnOnce(&mut Task) -> R) -> Option<R> {
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut()?;
+    let current = scheduler.current;
+    let task = scheduler.tasks.get_mut(&current)?;
+    Some(f(task))
+}
+
+/// Snapshot of all tasks as (id, name, state)
+pub fn tasks() -> Vec<(TaskId, &'static str, TaskState)> {
+    let scheduler = SCHEDULER.lock();
+    match scheduler.as_ref() {
+        Some(s) => s.tasks.values().map(|t| (t.id, t.name, t.state)).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Scheduling figures of one task
+#[derive(Debug, Clone, Copy)]
+pub struct TaskStats {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: TaskState,
+    pub priority: Priority,
+    /// TSC cycles spent running
+    pub runtime: u64,
+    /// Times switched to
+    pub switches: u64,
+}
+
+/// Snapshot the scheduling figures of every task
+pub fn task_stats() -> Vec<TaskStats> {
+    let scheduler = SCHEDULER.lock();
+    let Some(scheduler) = scheduler.as_ref() else {
+        return Vec::new();
+    };
+    // The running task's current turn is not in its runtime yet
+    let running = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(scheduler.switched_at);
+    scheduler
+        .tasks
+        .values()
+        .map(|task| TaskStats {
+            id: task.id,
+            name: task.name,
+            state: task.state,
+            priority: task.priority,
+            runtime: task.runtime + if task.id == scheduler.current { running } else { 0 },
+            switches: task.switches,
+        })
+        .collect()
+}
+
+/// Number of tasks running or ready to run, the idle task aside
+pub fn runnable() -> usize {
+    SCHEDULER.lock().as_ref().map_or(0, |s| s.runnable())
+}
+
+/// Tick hook, called from the timer interrupt
+///
+/// Wakes the sleepers that are due and samples the load average.
+pub fn tick(now: u64) {
+    let sample = now.is_multiple_of(load::SAMPLE_TICKS);
+    if now < NEXT_WAKE.load(Ordering::Acquire) && !sample {
+        return;
+    }
+    let mut guard = SCHEDULER.lock();
+    let Some(scheduler) = guard.as_mut() else {
+        return;
+    };
+    scheduler.wake_sleepers(now);
+    if sample {
+        load::sample(cpu::current_id(), scheduler.runnable());
+    }
+}
+
+/// Switch away from the running task, leaving it in `new_state`
+fn schedule(new_state: TaskState) {
+    let were_enabled = interrupts::save_and_disable();
+    preempt::start_slice();
+
+    loop {
+        // Every pass through the scheduler counts as forward progress
+        crate::watchdog::pet();
+
+        let mut guard = SCHEDULER.lock();
+        let Some(scheduler) = guard.as_mut() else {
+            // No tasking yet, wait for an interrupt instead
+            drop(guard);
+            if new_state == TaskState::Blocked {
+                crate::idle::wait();
+                interrupts::disable();
+            }
+            break;
+        };
+
+        scheduler.reap();
+
+        let current_id = scheduler.current;
+        let current = scheduler.tasks.get_mut(&current_id).expect("current task missing");
+        if new_state == TaskState::Blocked && current.wake_pending {
+            current.wake_pending = false;
+            break;
+        }
+        let wake_at = current.wake_at;
+        if new_state == TaskState::Sleeping && wake_at <= crate::time::ticks() {
+            break;
+        }
+
+        let is_idle = scheduler.idle == Some(current_id);
+        let next_id = match (scheduler.dequeue(), scheduler.idle) {
+            (Some(id), _) => id,
+            // Nothing else to run, hand the CPU to the idle task
+            (None, Some(idle)) if new_state != TaskState::Ready && !is_idle => idle,
+            (None, _) if matches!(new_state, TaskState::Blocked | TaskState::Sleeping) => {
+                drop(guard);
+                crate::idle::wait();
+                interrupts::disable();
+                continue;
+            }
+            (None, _) => break,
+        };
+
+        let now = unsafe { core::arch::x86_64::_rdtsc() };
+        let ran = now.wrapping_sub(scheduler.switched_at);
+        scheduler.switched_at = now;
+        let current = scheduler.tasks.get_mut(&current_id).expect("current task missing");
+        current.state = new_state;
+        current.runtime += ran;
+        let priority = current.priority;
+        let old_rsp = &mut current.rsp as *mut u64;
+        match new_state {
+            TaskState::Ready if !is_idle => scheduler.enqueue(current_id, priority),
+            TaskState::Sleeping => {
+                scheduler.sleepers.push(Reverse((wake_at, current_id)));
+                NEXT_WAKE.fetch_min(wake_at, Ordering::Release);
+            }
+            _ => {}
+        }
+
+        let next = scheduler.tasks.get_mut(&next_id).expect("ready task missing");
+        next.state = TaskState::Running;
+        next.switches += 1;
+        fpu::switch_to(&next.fpu);
+        next.tls.activate();
+        let new_rsp = next.rsp;
+        scheduler.current = next_id;
+        scheduler.switch_interrupts = were_enabled;
+
+        // Task boxes stay put in the map, so the pointer outlives the guard
+        let to_idle = scheduler.idle == Some(next_id);
+        drop(guard);
+        load::record_switch(cpu::current_id());
+        crate::stats::cpu::switch_task(to_idle);
+        crate::trace_event!(ContextSwitch, current_id.as_u64(), next_id.as_u64());
+        unsafe {
+            context::switch_context(old_rsp, new_rsp);
+        }
+        break;
+    }
+
+    interrupts::restore(were_enabled);
+}
+
+impl Scheduler {
+    /// Free finished tasks other than the one currently running
+    fn reap(&mut self) {
+        let current = self.current;
+        self.tasks
+            .retain(|id, task| *id == current || task.state != TaskState::Finished);
+    }
+
+    /// Queue a ready task, asking the running task to give way at its next
+    /// preemption point if `priority` is above its own
+    fn enqueue(&mut self, id: TaskId, priority: Priority) {
+        self.ready[priority as usize].push_back(id);
+        if self.tasks.get(&self.current).is_some_and(|current| priority > current.priority) {
+            preempt::request();
+        }
+    }
+
+    /// Take the next task to run, from the highest priority with one ready
+    fn dequeue(&mut self) -> Option<TaskId> {
+        self.ready.iter_mut().rev().find_map(|queue| queue.pop_front())
+    }
+
+    /// Tasks running or ready to run, the idle task aside
+    fn runnable(&self) -> usize {
+        let running = usize::from(self.idle != Some(self.current));
+        self.ready.iter().map(VecDeque::len).sum::<usize>() + running
+    }
+
+    /// Make the sleepers due by `now` ready
+    fn wake_sleepers(&mut self, now: u64) {
+        while let Some(&Reverse((wake_at, id))) = self.sleepers.peek() {
+            if wake_at > now {
+                break;
+            }
+            self.sleepers.pop();
+            let Some(task) = self.tasks.get_mut(&id) else {
+                continue;
+            };
+            if task.state == TaskState::Sleeping && task.wake_at == wake_at {
+                task.state = TaskState::Ready;
+                let priority = task.priority;
+                self.enqueue(id, priority);
+            }
+        }
+        let next = self.sleepers.peek().map_or(u64::MAX, |Reverse((wake_at, _))| *wake_at);
+        NEXT_WAKE.store(next, Ordering::Release);
+    }
+}
+
+/// First code run on a new task's stack
+pub(super) extern "C" fn task_trampoline() -> ! {
+    let (entry, enable_interrupts) = {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("scheduler not initialized");
+        let current = scheduler.current;
+        super::tls::set_current_task(current);
+        let entry = scheduler
+            .tasks
+            .get_mut(&current)
+            .and_then(|task| task.entry.take());
+        (entry, scheduler.switch_interrupts)
+    };
+
+    interrupts::restore(enable_interrupts);
+    super::signal::checkpoint();
+    if let Some(entry) = entry {
+        entry();
+    }
+
+    exit_current()
+}
+
+/// Terminate the running task
+pub fn exit_current() -> ! {
+    loop {
+        schedule(TaskState::Finished);
+        // Only reached when nothing else is runnable
+        interrupts::disable();
+        crate::idle::wait();
+    }
+}
+
+/// Check whether any task is waiting to run
+fn has_ready() -> bool {
+    SCHEDULER.lock().as_ref().is_some_and(|s| s.ready.iter().any(|queue| !queue.is_empty()))
+}
+
+/// Idle task body, waits for interrupts until work shows up
+fn idle_task() {
+    loop {
+        interrupts::disable();
+        if !has_ready() {
+            crate::idle::wait();
+        }
+        interrupts::enable();
+        yield_now();
+    }
+}
+
+/// Order the priority test tasks ran in, one digit each
+static SELFTEST_ORDER: AtomicU64 = AtomicU64::new(0);
+static SELFTEST_WOKE: AtomicBool = AtomicBool::new(false);
+
+fn selftest_record(digit: u64) {
+    let _ = SELFTEST_ORDER.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |order| Some(order * 10 + digit));
+}
+
+fn selftest_low() {
+    selftest_record(1);
+}
+
+fn selftest_high() {
+    selftest_record(2);
+}
+
+fn selftest_sleeper() {
+    crate::time::sleep_ms(30);
+    SELFTEST_WOKE.store(true, Ordering::Release);
+}
+
+/// Yield until `condition` holds, giving up after a while, for the
+/// self-tests of the task modules
+pub(super) fn yield_until(condition: impl Fn() -> bool) -> bool {
+    for _ in 0..100 {
+        if condition() {
+            return true;
+        }
+        yield_now();
+    }
+    condition()
+}
+
+/// Higher priorities run first, and sleepers wait in the queue for their
+/// tick
+fn selftest() -> Result<(), &'static str> {
+    SELFTEST_ORDER.store(0, Ordering::Relaxed);
+    SELFTEST_WOKE.store(false, Ordering::Release);
+
+    spawn_with_priority("sched-low", selftest_low, Priority::Low).ok_or("spawn failed")?;
+    spawn_with_priority("sched-high", selftest_high, Priority::High).ok_or("spawn failed")?;
+    yield_until(|| SELFTEST_ORDER.load(Ordering::Relaxed) >= 10);
+    if SELFTEST_ORDER.load(Ordering::Relaxed) != 21 {
+        return Err("priorities ran out of order");
+    }
+
+    let id = spawn("sched-sleep", selftest_sleeper).ok_or("spawn failed")?;
+    if !yield_until(|| with_task(id, |task, _| task.state) == Some(TaskState::Sleeping)) {
+        return Err("task did not sleep");
+    }
+    crate::time::sleep_ms(60);
+    if !SELFTEST_WOKE.load(Ordering::Acquire) {
+        return Err("sleeper not woken");
+    }
+    Ok(())
+}
+
+crate::selftest!("scheduler", selftest);
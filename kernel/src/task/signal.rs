@@ -0,0 +1,327 @@
+//! Signals
+//!
+//! POSIX-style signals for kernel tasks: each task has pending and blocked
+//! sets and an action per signal. Kernel code cannot be stopped at an
+//! arbitrary instruction, nor at every yield, where it may still hold a
+//! lock or be halfway through updating shared state. Signals are delivered
+//! only when the task starts and when it calls `checkpoint`, at a point
+//! where it holds nothing. Sending also wakes a blocked receiver, so
+//! interruptible waits can check `has_pending` and return early, for the
+//! caller to unwind to its checkpoint. Delivery on return to user mode and
+//! the `kill`, `sigaction` and `sigreturn` system calls build on this once
+//! user processes exist.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use super::{scheduler, TaskId};
+
+/// Signals the kernel knows, numbered as on Linux
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Signal {
+    Hangup = 1,
+    Interrupt = 2,
+    Quit = 3,
+    IllegalInstruction = 4,
+    Trap = 5,
+    Bus = 7,
+    FloatingPoint = 8,
+    Kill = 9,
+    User1 = 10,
+    SegmentationFault = 11,
+    User2 = 12,
+    Pipe = 13,
+    Alarm = 14,
+    Terminate = 15,
+    Child = 17,
+}
+
+/// What happens to a signal whose action is `Action::Default`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+}
+
+impl Signal {
+    pub const ALL: [Signal; 15] = [
+        Signal::Hangup, Signal::Interrupt, Signal::Quit, Signal::IllegalInstruction, Signal::Trap,
+        Signal::Bus, Signal::FloatingPoint, Signal::Kill, Signal::User1, Signal::SegmentationFault,
+        Signal::User2, Signal::Pipe, Signal::Alarm, Signal::Terminate, Signal::Child,
+    ];
+
+    pub const fn number(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_number(number: u8) -> Option<Signal> {
+        Self::ALL.into_iter().find(|signal| signal.number() == number)
+    }
+
+    /// Parse a signal number or a name with or without the `SIG` prefix
+    pub fn parse(text: &str) -> Option<Signal> {
+        if let Ok(number) = text.parse() {
+            return Self::from_number(number);
+        }
+        let name = text.strip_prefix("SIG").unwrap_or(text);
+        Self::ALL.into_iter().find(|signal| signal.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::Hangup => "HUP",
+            Signal::Interrupt => "INT",
+            Signal::Quit => "QUIT",
+            Signal::IllegalInstruction => "ILL",
+            Signal::Trap => "TRAP",
+            Signal::Bus => "BUS",
+            Signal::FloatingPoint => "FPE",
+            Signal::Kill => "KILL",
+            Signal::User1 => "USR1",
+            Signal::SegmentationFault => "SEGV",
+            Signal::User2 => "USR2",
+            Signal::Pipe => "PIPE",
+            Signal::Alarm => "ALRM",
+            Signal::Terminate => "TERM",
+            Signal::Child => "CHLD",
+        }
+    }
+
+    pub fn default_action(self) -> DefaultAction {
+        match self {
+            Signal::Child => DefaultAction::Ignore,
+            _ => DefaultAction::Terminate,
+        }
+    }
+
+    /// `Kill` can be neither caught, ignored nor blocked
+    pub fn is_catchable(self) -> bool {
+        self != Signal::Kill
+    }
+}
+
+impl core::fmt::Display for Signal {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "SIG{}", self.name())
+    }
+}
+
+/// Set of signals, bit n for signal number n
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SigSet(u64);
+
+impl SigSet {
+    pub const EMPTY: SigSet = SigSet(0);
+
+    pub fn contains(self, signal: Signal) -> bool {
+        self.0 & (1 << signal.number()) != 0
+    }
+
+    pub fn insert(&mut self, signal: Signal) {
+        self.0 |= 1 << signal.number();
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        self.0 &= !(1 << signal.number());
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// Disposition of one signal
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    Default,
+    Ignore,
+    /// Run on the receiving task with the signal blocked
+    Handler(fn(Signal)),
+}
+
+/// Errors that can occur while sending or configuring signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    /// No such task
+    NoTask,
+    /// Target is the boot or idle task, which must not be signalled
+    PermissionDenied,
+    /// `Kill` cannot be caught, ignored or blocked
+    Uncatchable,
+}
+
+impl core::fmt::Display for SignalError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SignalError::NoTask => write!(f, "No such task"),
+            SignalError::PermissionDenied => write!(f, "Task cannot be signalled"),
+            SignalError::Uncatchable => write!(f, "Signal cannot be caught or blocked"),
+        }
+    }
+}
+
+/// Per-task signal state
+pub struct SignalState {
+    pending: SigSet,
+    blocked: SigSet,
+    actions: [Action; 32],
+}
+
+impl SignalState {
+    pub(super) const fn new() -> Self {
+        SignalState { pending: SigSet::EMPTY, blocked: SigSet::EMPTY, actions: [Action::Default; 32] }
+    }
+
+    /// Take the lowest pending signal that is not blocked
+    fn take_deliverable(&mut self) -> Option<(Signal, Action)> {
+        let signal = Signal::ALL
+            .into_iter()
+            .find(|&signal| self.pending.contains(signal) && !self.blocked.contains(signal))?;
+        self.pending.remove(signal);
+        Some((signal, self.actions[signal.number() as usize]))
+    }
+}
+
+/// Send `signal` to task `id`, waking it if blocked
+pub fn send(id: TaskId, signal: Signal) -> Result<(), SignalError> {
+    scheduler::with_task(id, |task, protected| {
+        if protected {
+            return Err(SignalError::PermissionDenied);
+        }
+        // Ignored signals are discarded at send time, as POSIX allows
+        if signal.is_catchable() && matches!(task.signals.actions[signal.number() as usize], Action::Ignore) {
+            return Ok(());
+        }
+        task.signals.pending.insert(signal);
+        Ok(())
+    })
+    .ok_or(SignalError::NoTask)??;
+    crate::trace_event!(Signal, id.as_u64(), signal.number() as u64);
+    super::wake(id);
+    Ok(())
+}
+
+/// Set the running task's action for `signal`, returning the previous one
+pub fn set_action(signal: Signal, action: Action) -> Result<Action, SignalError> {
+    if !signal.is_catchable() {
+        return Err(SignalError::Uncatchable);
+    }
+    scheduler::with_current(|task| {
+        let slot = &mut task.signals.actions[signal.number() as usize];
+        if matches!(action, Action::Ignore) {
+            task.signals.pending.remove(signal);
+        }
+        core::mem::replace(slot, action)
+    })
+    .ok_or(SignalError::NoTask)
+}
+
+/// Block or unblock `signal` for the running task
+pub fn set_blocked(signal: Signal, blocked: bool) -> Result<(), SignalError> {
+    if !signal.is_catchable() {
+        return Err(SignalError::Uncatchable);
+    }
+    scheduler::with_current(|task| {
+        if blocked {
+            task.signals.blocked.insert(signal);
+        } else {
+            task.signals.blocked.remove(signal);
+        }
+    })
+    .ok_or(SignalError::NoTask)
+}
+
+/// Check if the running task has a signal waiting to be delivered
+pub fn has_pending() -> bool {
+    scheduler::with_current(|task| {
+        let signals = &task.signals;
+        Signal::ALL.into_iter().any(|s| signals.pending.contains(s) && !signals.blocked.contains(s))
+    })
+    .unwrap_or(false)
+}
+
+/// Deliver the running task's pending signals
+///
+/// Runs handlers in signal number order and terminates the task for an
+/// uncaught signal whose default action says so. Call only where the task
+/// holds no locks and has no shared state half updated.
+pub fn checkpoint() {
+    while let Some((signal, action)) = scheduler::with_current(|task| task.signals.take_deliverable()).flatten() {
+        match action {
+            Action::Handler(handler) if signal.is_catchable() => {
+                // Blocked while the handler runs, restored as sigreturn would
+                let was_blocked = scheduler::with_current(|task| {
+                    let was_blocked = task.signals.blocked.contains(signal);
+                    task.signals.blocked.insert(signal);
+                    was_blocked
+                })
+                .unwrap_or(false);
+                handler(signal);
+                if !was_blocked {
+                    let _ = set_blocked(signal, false);
+                }
+            }
+            Action::Ignore if signal.is_catchable() => {}
+            _ if signal.default_action() == DefaultAction::Ignore => {}
+            _ => terminate(signal),
+        }
+    }
+}
+
+/// End the running task as an uncaught `signal` does
+///
+/// Also used for faults the task cannot continue past, which have nothing
+/// to return to even if it set a handler.
+pub fn terminate(signal: Signal) -> ! {
+    crate::serial_println!("Task {} terminated by {}", super::current_id().map_or(0, |id| id.as_u64()), signal);
+    scheduler::exit_current()
+}
+
+static SELFTEST_READY: AtomicBool = AtomicBool::new(false);
+static SELFTEST_HANDLED: AtomicU32 = AtomicU32::new(0);
+
+fn selftest_handler(_signal: Signal) {
+    SELFTEST_HANDLED.fetch_add(1, Ordering::Relaxed);
+}
+
+fn selftest_task() {
+    let _ = set_action(Signal::User1, Action::Handler(selftest_handler));
+    SELFTEST_READY.store(true, Ordering::Release);
+    loop {
+        super::yield_now();
+        checkpoint();
+    }
+}
+
+/// Caught signal runs its handler, uncaught one terminates the task
+fn selftest() -> Result<(), &'static str> {
+    let current = super::current_id().ok_or("tasking not initialized")?;
+    SELFTEST_READY.store(false, Ordering::Release);
+    SELFTEST_HANDLED.store(0, Ordering::Relaxed);
+
+    let id = super::spawn("signal-test", selftest_task).ok_or("spawn failed")?;
+    if !scheduler::yield_until(|| SELFTEST_READY.load(Ordering::Acquire)) {
+        return Err("test task did not start");
+    }
+    send(id, Signal::User1).map_err(|_| "send failed")?;
+    let handled = scheduler::yield_until(|| SELFTEST_HANDLED.load(Ordering::Relaxed) == 1);
+    send(id, Signal::Terminate).map_err(|_| "send failed")?;
+    let alive = |id| {
+        scheduler::tasks()
+            .iter()
+            .any(|&(task, _, state)| task == id && state != super::TaskState::Finished)
+    };
+    if !scheduler::yield_until(|| !alive(id)) {
+        return Err("task survived SIGTERM");
+    }
+    if !handled {
+        return Err("handler did not run");
+    }
+    if scheduler::with_task(current, |_, protected| protected) == Some(true)
+        && send(current, Signal::Terminate) != Err(SignalError::PermissionDenied)
+    {
+        return Err("boot task accepted a signal");
+    }
+    Ok(())
+}
+
+crate::selftest!("signal", selftest);
@@ -0,0 +1,140 @@
+//! Kernel Thread-Local Storage
+//!
+//! Every task owns a copy of the `.tdata` and `.tbss` image, laid out as
+//! x86_64 TLS variant II: the block ends at the thread pointer, whose first
+//! word points to itself, and FS base holds the thread pointer while the
+//! task runs. `#[thread_local]` statics compile to fixed offsets from it, so
+//! they must not be touched before `task::init` loads the boot task's block.
+
+use super::TaskId;
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::model_specific::FsBase;
+use x86_64::VirtAddr;
+
+unsafe extern "C" {
+    static __tdata_start: u8;
+    static __tdata_end: u8;
+    static __tls_end: u8;
+    /// Absolute symbol, its address is the TLS segment alignment
+    static __tls_align: u8;
+}
+
+/// Size of the thread control block, just the self pointer
+const TCB_SIZE: usize = 8;
+
+/// Set once the boot task's block is loaded
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+#[thread_local]
+static CURRENT_TASK: Cell<Option<TaskId>> = Cell::new(None);
+
+/// Initialized data, size of the whole segment and its alignment
+fn image() -> (&'static [u8], usize, usize) {
+    unsafe {
+        let start = &raw const __tdata_start;
+        let data_len = (&raw const __tdata_end) as usize - start as usize;
+        let size = (&raw const __tls_end) as usize - start as usize;
+        let align = ((&raw const __tls_align) as usize).max(TCB_SIZE);
+        (core::slice::from_raw_parts(start, data_len), size, align)
+    }
+}
+
+/// One task's copy of the thread-local statics
+pub struct TlsBlock {
+    area: *mut u8,
+    layout: Layout,
+    thread_pointer: u64,
+}
+
+// Only the owning task reads the block, through FS
+unsafe impl Send for TlsBlock {}
+
+impl TlsBlock {
+    /// Allocate a block holding the initial values of every thread-local
+    pub fn new() -> Self {
+        let (data, size, align) = image();
+        // The linker places the segment so it ends aligned at the thread pointer
+        let tls_size = size.next_multiple_of(align);
+        let layout = Layout::from_size_align(tls_size + TCB_SIZE, align)
+            .expect("invalid TLS layout");
+        let area = unsafe { alloc_zeroed(layout) };
+        if area.is_null() {
+            handle_alloc_error(layout);
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), area, data.len());
+            let tcb = area.add(tls_size) as *mut u64;
+            tcb.write(tcb as u64);
+            TlsBlock { area, layout, thread_pointer: tcb as u64 }
+        }
+    }
+
+    /// Address loaded into FS base, `fs:0` reads it back
+    pub fn thread_pointer(&self) -> u64 {
+        self.thread_pointer
+    }
+
+    /// Point FS base at this block, called with interrupts disabled on switch
+    pub fn activate(&self) {
+        FsBase::write(VirtAddr::new(self.thread_pointer));
+    }
+}
+
+impl Default for TlsBlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TlsBlock {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area, self.layout) };
+    }
+}
+
+/// Load the boot task's block and record its id
+pub(super) fn init(boot: &TlsBlock, id: TaskId) {
+    boot.activate();
+    ACTIVE.store(true, Ordering::Release);
+    set_current_task(id);
+}
+
+/// Record the task owning the active block, once on its first run
+pub(super) fn set_current_task(id: TaskId) {
+    CURRENT_TASK.set(Some(id));
+}
+
+/// Id of the running task without taking the scheduler lock
+///
+/// `None` before tasking is initialized.
+pub fn current_task() -> Option<TaskId> {
+    if !ACTIVE.load(Ordering::Acquire) {
+        return None;
+    }
+    CURRENT_TASK.get()
+}
+
+/// Thread-locals of two tasks must live in separate blocks
+fn selftest() -> Result<(), &'static str> {
+    if !ACTIVE.load(Ordering::Acquire) {
+        return Err("TLS not initialized");
+    }
+    let fs = FsBase::read().as_u64();
+    let self_pointer = unsafe { *(fs as *const u64) };
+    if self_pointer != fs {
+        return Err("fs:0 does not point to itself");
+    }
+    let (_, size, align) = image();
+    let local = &raw const CURRENT_TASK as u64;
+    if local >= fs || local < fs - size.next_multiple_of(align) as u64 {
+        return Err("thread-local outside the active block");
+    }
+    if super::scheduler::try_current_id() != Some(current_task()) {
+        return Err("thread-local task id out of date");
+    }
+    Ok(())
+}
+
+crate::selftest!("tls", selftest);
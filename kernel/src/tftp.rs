@@ -0,0 +1,238 @@
+//! TFTP Client
+//!
+//! Reads a file from a TFTP server (RFC 1350) in octet mode, 512 bytes per
+//! block, each block acknowledged before the next comes. The server
+//! answers a read request from a port of its own, and the rest of the
+//! transfer stays on that port; datagrams from any other port are turned
+//! away without disturbing it. A lost block or acknowledgement is resent
+//! after `TIMEOUT_MS`, up to `RETRIES` times.
+//!
+//! The protocol runs over a `Transport` that carries datagrams to and from
+//! one server host, so it is ready for the UDP layer to drive.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Server port read requests go to
+pub const PORT: u16 = 69;
+/// Data bytes per block, a shorter block ends the file
+pub const BLOCK_SIZE: usize = 512;
+/// Wait for an answer before sending again
+pub const TIMEOUT_MS: u64 = 1000;
+/// Times a packet is resent before the transfer is abandoned
+pub const RETRIES: u32 = 5;
+
+const OP_RRQ: u16 = 1;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+
+// Error codes sent to the server
+const ERROR_DISK_FULL: u16 = 3;
+const ERROR_UNKNOWN_TID: u16 = 5;
+
+/// Longest file name a read request carries
+const MAX_NAME_LEN: usize = 255;
+
+/// Errors that can end a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TftpError {
+    /// File name empty, too long or containing a NUL
+    InvalidName,
+    /// The server stopped answering
+    Timeout,
+    /// The server sent an error packet with this code
+    Server(u16),
+    /// The server sent a packet that makes no sense here
+    Malformed,
+    /// The file is larger than the client takes
+    TooLarge,
+}
+
+impl fmt::Display for TftpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TftpError::InvalidName => write!(f, "Invalid TFTP file name"),
+            TftpError::Timeout => write!(f, "TFTP server not answering"),
+            TftpError::Server(code) => match code {
+                1 => write!(f, "TFTP: file not found"),
+                2 => write!(f, "TFTP: access violation"),
+                3 => write!(f, "TFTP: disk full"),
+                4 => write!(f, "TFTP: illegal operation"),
+                5 => write!(f, "TFTP: unknown transfer ID"),
+                6 => write!(f, "TFTP: file already exists"),
+                7 => write!(f, "TFTP: no such user"),
+                _ => write!(f, "TFTP server error {}", code),
+            },
+            TftpError::Malformed => write!(f, "Malformed TFTP packet"),
+            TftpError::TooLarge => write!(f, "File too large"),
+        }
+    }
+}
+
+/// Datagrams to and from one server host
+pub trait Transport {
+    /// Send `packet` to the server's port `port`
+    fn send(&mut self, port: u16, packet: &[u8]);
+    /// Next datagram from the server into `buf` as (length, source port),
+    /// `None` if nothing arrives within `timeout_ms`
+    fn recv(&mut self, buf: &mut [u8], timeout_ms: u64) -> Option<(usize, u16)>;
+}
+
+fn read_request(file: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(2 + file.len() + 7);
+    packet.extend_from_slice(&OP_RRQ.to_be_bytes());
+    packet.extend_from_slice(file.as_bytes());
+    packet.push(0);
+    packet.extend_from_slice(b"octet\0");
+    packet
+}
+
+fn ack(block: u16) -> [u8; 4] {
+    let [op_high, op_low] = OP_ACK.to_be_bytes();
+    let [high, low] = block.to_be_bytes();
+    [op_high, op_low, high, low]
+}
+
+fn send_error(transport: &mut dyn Transport, port: u16, code: u16) {
+    let mut packet = Vec::with_capacity(5);
+    packet.extend_from_slice(&OP_ERROR.to_be_bytes());
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.push(0);
+    transport.send(port, &packet);
+}
+
+/// Download `file`, of at most `limit` bytes
+pub fn get(transport: &mut dyn Transport, file: &str, limit: usize) -> Result<Vec<u8>, TftpError> {
+    if file.is_empty() || file.len() > MAX_NAME_LEN || file.contains('\0') {
+        return Err(TftpError::InvalidName);
+    }
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4 + BLOCK_SIZE];
+    // Last packet sent, resent when the server goes quiet
+    let mut last: Vec<u8> = read_request(file);
+    let mut last_port = PORT;
+    // Server port once it answered
+    let mut server = None;
+    let mut block: u16 = 0;
+    let mut retries = 0;
+    transport.send(last_port, &last);
+    loop {
+        let Some((len, port)) = transport.recv(&mut buf, TIMEOUT_MS) else {
+            retries += 1;
+            if retries > RETRIES {
+                return Err(TftpError::Timeout);
+            }
+            transport.send(last_port, &last);
+            continue;
+        };
+        if server.is_some_and(|server| server != port) {
+            send_error(transport, port, ERROR_UNKNOWN_TID);
+            continue;
+        }
+        let packet = &buf[..len];
+        if packet.len() < 4 {
+            return Err(TftpError::Malformed);
+        }
+        let opcode = u16::from_be_bytes([packet[0], packet[1]]);
+        let number = u16::from_be_bytes([packet[2], packet[3]]);
+        match opcode {
+            OP_DATA if number == block.wrapping_add(1) => {
+                server = Some(port);
+                let payload = &packet[4..];
+                if data.len() + payload.len() > limit {
+                    send_error(transport, port, ERROR_DISK_FULL);
+                    return Err(TftpError::TooLarge);
+                }
+                data.extend_from_slice(payload);
+                block = number;
+                retries = 0;
+                last = ack(block).to_vec();
+                last_port = port;
+                transport.send(port, &last);
+                if payload.len() < BLOCK_SIZE {
+                    return Ok(data);
+                }
+            }
+            // A repeat, the server missed the acknowledgement
+            OP_DATA if server.is_some() && number == block => transport.send(port, &last),
+            OP_ERROR => return Err(TftpError::Server(number)),
+            _ => return Err(TftpError::Malformed),
+        }
+    }
+}
+
+/// Scripted server for the self-test
+struct ScriptTransport {
+    /// Datagrams to hand out as (source port, bytes), `None` for silence
+    incoming: alloc::collections::VecDeque<Option<(u16, Vec<u8>)>>,
+    sent: Vec<(u16, Vec<u8>)>,
+}
+
+impl Transport for ScriptTransport {
+    fn send(&mut self, port: u16, packet: &[u8]) {
+        self.sent.push((port, packet.to_vec()));
+    }
+
+    fn recv(&mut self, buf: &mut [u8], _timeout_ms: u64) -> Option<(usize, u16)> {
+        let (port, packet) = self.incoming.pop_front()??;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Some((packet.len(), port))
+    }
+}
+
+fn data_packet(block: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&OP_DATA.to_be_bytes());
+    packet.extend_from_slice(&block.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// A transfer over two blocks survives a lost block, a repeat and a stray
+/// port, and server errors come through
+fn selftest() -> Result<(), &'static str> {
+    const SERVER: u16 = 4000;
+    let first: Vec<u8> = (0..BLOCK_SIZE).map(|i| i as u8).collect();
+    let mut transport = ScriptTransport {
+        incoming: [
+            None,
+            Some((SERVER, data_packet(1, &first))),
+            Some((SERVER, data_packet(1, &first))),
+            Some((4001, data_packet(2, b"stray"))),
+            Some((SERVER, data_packet(2, b"end"))),
+        ]
+        .into_iter()
+        .collect(),
+        sent: Vec::new(),
+    };
+    let data = get(&mut transport, "kernel.bin", 4096).map_err(|_| "transfer failed")?;
+    if data.len() != BLOCK_SIZE + 3 || data[..BLOCK_SIZE] != first[..] || &data[BLOCK_SIZE..] != b"end" {
+        return Err("received data wrong");
+    }
+    let request = read_request("kernel.bin");
+    let expected: [(u16, &[u8]); 6] = [
+        (PORT, &request),
+        (PORT, &request),
+        (SERVER, &ack(1)),
+        (SERVER, &ack(1)),
+        (4001, &[0, 5, 0, 5, 0]),
+        (SERVER, &ack(2)),
+    ];
+    if transport.sent.len() != expected.len()
+        || transport.sent.iter().zip(expected).any(|((port, packet), (want_port, want))| *port != want_port || packet != want)
+    {
+        return Err("wrong packets sent");
+    }
+
+    let mut transport = ScriptTransport {
+        incoming: [Some((SERVER, [&[0u8, 5, 0, 1][..], &b"File not found\0"[..]].concat()))].into_iter().collect(),
+        sent: Vec::new(),
+    };
+    if get(&mut transport, "missing", 4096) != Err(TftpError::Server(1)) {
+        return Err("server error not reported");
+    }
+    Ok(())
+}
+
+crate::selftest!("tftp", selftest);
@@ -0,0 +1,83 @@
+//! Kernel Clock and Time of Day
+//!
+//! Monotonic time comes from the PIT tick; wall-clock time is the UEFI
+//! clock, or the CMOS RTC without UEFI, read at boot plus uptime.
+
+pub mod rtc;
+pub mod timers;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::arch::x86_64::pit;
+
+// Re-export core types
+pub use rtc::DateTime;
+pub use timers::{cancel, schedule_after, schedule_periodic, sleep_ms, TimerId};
+
+/// Ticks per second of the monotonic clock
+pub const TICK_HZ: u64 = pit::TICK_HZ as u64;
+
+/// Unix time at tick 0, 0 until `init`
+static BOOT_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// TSC and tick at `init`, the reference for TSC calibration
+static TSC_REFERENCE: AtomicU64 = AtomicU64::new(0);
+static TICK_REFERENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Ticks that must pass after `init` before the TSC rate is trusted
+const TSC_CALIBRATION_TICKS: u64 = TICK_HZ / 10;
+
+/// Read the wall clock and start timer processing
+pub fn init() {
+    rtc::init();
+    let now = crate::efi::runtime::get_time().unwrap_or_else(|_| rtc::read());
+    let uptime_secs = uptime_ms() / 1000;
+    BOOT_EPOCH.store(now.to_unix().saturating_sub(uptime_secs), Ordering::Relaxed);
+    TICK_REFERENCE.store(ticks(), Ordering::Relaxed);
+    TSC_REFERENCE.store(unsafe { core::arch::x86_64::_rdtsc() }, Ordering::Relaxed);
+    timers::init();
+    crate::serial_println!("Clock: {} UTC", now);
+}
+
+/// Ticks since boot
+pub fn ticks() -> u64 {
+    pit::ticks()
+}
+
+/// Milliseconds since boot
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(ticks())
+}
+
+/// Convert ticks to milliseconds
+pub const fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * 1000 / TICK_HZ
+}
+
+/// Convert milliseconds to ticks, rounding up so waits are never short
+pub const fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TICK_HZ).div_ceil(1000)
+}
+
+/// TSC frequency in Hz, measured against the tick since `init`
+///
+/// `None` until enough ticks have passed for a usable estimate; the
+/// estimate sharpens as uptime grows.
+pub fn tsc_hz() -> Option<u64> {
+    let reference = TSC_REFERENCE.load(Ordering::Relaxed);
+    let elapsed = ticks().saturating_sub(TICK_REFERENCE.load(Ordering::Relaxed));
+    if reference == 0 || elapsed < TSC_CALIBRATION_TICKS {
+        return None;
+    }
+    let cycles = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(reference);
+    Some((cycles as u128 * TICK_HZ as u128 / elapsed as u128) as u64)
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    BOOT_EPOCH.load(Ordering::Relaxed) + uptime_ms() / 1000
+}
+
+/// Current UTC date and time
+pub fn now() -> DateTime {
+    DateTime::from_unix(unix_time())
+}
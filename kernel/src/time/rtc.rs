@@ -0,0 +1,170 @@
+//! CMOS Real-Time Clock
+
+use core::fmt;
+use crate::arch::x86_64::port::{self, Port};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// Setting bit 7 of the address keeps NMIs disabled during the access
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status A: update in progress
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: 24-hour mode
+const MODE_24_HOUR: u8 = 1 << 1;
+/// Status B: binary rather than BCD values
+const MODE_BINARY: u8 = 1 << 2;
+/// Hours register: PM flag in 12-hour mode
+const HOUR_PM: u8 = 1 << 7;
+
+/// Calendar date and time, UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the Unix epoch
+    pub fn to_unix(&self) -> u64 {
+        // Days from civil, Howard Hinnant's algorithm
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let month = self.month as i64;
+        let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        let secs = days * 86400
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64;
+        secs.max(0) as u64
+    }
+
+    /// Date and time for seconds since the Unix epoch
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86400) as i64 + 719468;
+        let rem = secs % 86400;
+        let era = days.div_euclid(146097);
+        let doe = days - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = (yoe + era * 400 + (month <= 2) as i64) as u16;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Claim the CMOS index and data ports
+pub fn init() {
+    if let Err(e) = port::claim(CMOS_ADDRESS, 2, "rtc") {
+        crate::serial_println!("RTC: {}", e);
+    }
+}
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn read_raw() -> [u8; 7] {
+    while read_register(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    let century = crate::acpi::fadt::fadt().map_or(0, |fadt| fadt.century);
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        if century != 0 { read_register(century) } else { 0 },
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Read the RTC, assumed to run in UTC
+pub fn read() -> DateTime {
+    // Read until two consecutive readings agree, so no update tore the values
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year, mut century] = raw;
+    let pm = hour & HOUR_PM != 0;
+    hour &= !HOUR_PM;
+
+    if status_b & MODE_BINARY == 0 {
+        second = from_bcd(second);
+        minute = from_bcd(minute);
+        hour = from_bcd(hour);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+        century = from_bcd(century);
+    }
+    if status_b & MODE_24_HOUR == 0 {
+        hour = match (hour, pm) {
+            (12, false) => 0,
+            (12, true) => 12,
+            (h, true) => h + 12,
+            (h, false) => h,
+        };
+    }
+
+    let century = if century != 0 { century as u16 } else { 20 };
+    DateTime {
+        year: century * 100 + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
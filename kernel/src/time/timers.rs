@@ -0,0 +1,184 @@
+//! Software timers
+//!
+//! Timers sit in a binary heap ordered by deadline tick. The timer interrupt
+//! only compares the tick against the earliest deadline and raises the timer
+//! softirq; callbacks run in task context on the deferred-work worker.
+
+use alloc::collections::{BTreeMap, BinaryHeap};
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::{ms_to_ticks, ticks};
+use crate::sync::IrqMutex;
+use crate::task::{self, deferred};
+
+/// Timer handle, used to cancel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+struct Timer {
+    deadline: u64,
+    period: Option<u64>,
+    callback: fn(usize),
+    arg: usize,
+}
+
+struct TimerQueue {
+    /// Deadlines, entries for cancelled or re-armed timers are skipped
+    heap: BinaryHeap<Reverse<(u64, TimerId)>>,
+    timers: BTreeMap<TimerId, Timer>,
+    next_id: u64,
+}
+
+static TIMERS: IrqMutex<TimerQueue> = IrqMutex::named("timers", TimerQueue {
+    heap: BinaryHeap::new(),
+    timers: BTreeMap::new(),
+    next_id: 0,
+});
+
+/// Earliest pending deadline, read lock-free by the tick handler
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Hook timer expiry processing into the timer softirq
+pub(super) fn init() {
+    deferred::register_softirq(deferred::SoftIrq::Timer, run_expired);
+}
+
+/// Tick hook, called from the timer interrupt
+pub fn tick(now: u64) {
+    if now >= NEXT_DEADLINE.load(Ordering::Acquire) {
+        deferred::raise(deferred::SoftIrq::Timer);
+    }
+}
+
+fn arm(deadline: u64, period: Option<u64>, callback: fn(usize), arg: usize) -> TimerId {
+    let mut queue = TIMERS.lock();
+    let id = TimerId(queue.next_id);
+    queue.next_id += 1;
+    queue.timers.insert(id, Timer { deadline, period, callback, arg });
+    queue.heap.push(Reverse((deadline, id)));
+    NEXT_DEADLINE.fetch_min(deadline, Ordering::Release);
+    id
+}
+
+/// Run `callback(arg)` once, `ms` milliseconds from now
+pub fn schedule_after(ms: u64, callback: fn(usize), arg: usize) -> TimerId {
+    arm(ticks() + ms_to_ticks(ms).max(1), None, callback, arg)
+}
+
+/// Run `callback(arg)` every `period_ms` milliseconds
+pub fn schedule_periodic(period_ms: u64, callback: fn(usize), arg: usize) -> TimerId {
+    let period = ms_to_ticks(period_ms).max(1);
+    arm(ticks() + period, Some(period), callback, arg)
+}
+
+/// Cancel a timer, returns false if it already fired or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    TIMERS.lock().timers.remove(&id).is_some()
+}
+
+/// Number of armed timers
+pub fn pending() -> usize {
+    TIMERS.lock().timers.len()
+}
+
+/// Timer softirq handler, runs every expired callback outside the lock
+fn run_expired() {
+    let now = ticks();
+    loop {
+        let (callback, arg) = {
+            let mut queue = TIMERS.lock();
+            let Some(&Reverse((deadline, id))) = queue.heap.peek() else {
+                break;
+            };
+            if deadline > now {
+                break;
+            }
+            queue.heap.pop();
+
+            let Some(timer) = queue.timers.get_mut(&id) else {
+                continue;
+            };
+            if timer.deadline != deadline {
+                continue;
+            }
+            let fired = (timer.callback, timer.arg);
+            match timer.period {
+                Some(period) => {
+                    // Skip missed periods rather than firing in a burst
+                    let next = (deadline + period).max(now + 1);
+                    timer.deadline = next;
+                    queue.heap.push(Reverse((next, id)));
+                }
+                None => {
+                    queue.timers.remove(&id);
+                }
+            }
+            fired
+        };
+        callback(arg);
+    }
+
+    let queue = TIMERS.lock();
+    let next = queue.heap.peek().map_or(u64::MAX, |Reverse((deadline, _))| *deadline);
+    NEXT_DEADLINE.store(next, Ordering::Release);
+}
+
+/// Block the running task for at least `ms` milliseconds
+///
+/// The task waits in the scheduler's sleep queue instead of polling the
+/// tick.
+pub fn sleep_ms(ms: u64) {
+    let deadline = ticks() + ms_to_ticks(ms);
+    if task::current_id().is_none() {
+        // No tasking, wait out the ticks
+        while ticks() < deadline {
+            crate::arch::x86_64::interrupts::disable();
+            crate::idle::wait();
+        }
+        return;
+    }
+
+    while ticks() < deadline {
+        task::scheduler::sleep_until(deadline);
+    }
+}
+
+/// Fire counter for the self-test callbacks
+static SELFTEST_FIRED: AtomicU64 = AtomicU64::new(0);
+
+fn selftest_count(_arg: usize) {
+    SELFTEST_FIRED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// One-shot, periodic and cancelled timers
+fn selftest() -> Result<(), &'static str> {
+    SELFTEST_FIRED.store(0, Ordering::Relaxed);
+
+    let cancelled = schedule_after(20, selftest_count, 0);
+    if !cancel(cancelled) || cancel(cancelled) {
+        return Err("cancel");
+    }
+    let once = schedule_after(20, selftest_count, 0);
+    sleep_ms(60);
+    if SELFTEST_FIRED.load(Ordering::Relaxed) != 1 || cancel(once) {
+        return Err("one-shot did not fire exactly once");
+    }
+
+    SELFTEST_FIRED.store(0, Ordering::Relaxed);
+    let periodic = schedule_periodic(20, selftest_count, 0);
+    sleep_ms(110);
+    if !cancel(periodic) {
+        return Err("periodic timer missing");
+    }
+    let fired = SELFTEST_FIRED.load(Ordering::Relaxed);
+    if fired < 3 {
+        return Err("periodic timer fired too rarely");
+    }
+    sleep_ms(40);
+    if SELFTEST_FIRED.load(Ordering::Relaxed) != fired {
+        return Err("cancelled timer fired");
+    }
+    Ok(())
+}
+
+crate::selftest!("timers", selftest);
@@ -0,0 +1,177 @@
+//! Event Tracing
+//!
+//! Static tracepoints record fixed-size binary events into a per-CPU ring
+//! buffer. Without the `trace` feature this module is left out and
+//! [`trace_event!`] expands to nothing; with it, a disabled tracepoint costs
+//! one relaxed load.
+//!
+//! `dump` writes one CSV line per event after a `#` header:
+//!
+//! ```text
+//! # cosmos-trace v1 tsc_hz=<hz or 0>
+//! tsc,cpu,event,arg0,arg1
+//! 1234567,0,irq_enter,32,0
+//! ```
+
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::arch::x86_64::cpu::{self, MAX_CPUS};
+
+/// Events kept per CPU, older ones are overwritten
+pub const EVENTS_PER_CPU: usize = 4096;
+
+/// Tracepoint identifiers
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// arg0: vector
+    IrqEnter = 1,
+    /// arg0: vector
+    IrqExit = 2,
+    /// arg0: address, arg1: size
+    Alloc = 3,
+    /// arg0: address, arg1: size
+    Free = 4,
+    /// arg0: previous task id, arg1: next task id
+    ContextSwitch = 5,
+    /// arg0: faulting address (CR2), arg1: error code
+    PageFault = 6,
+    /// arg0: target task id, arg1: signal number
+    Signal = 7,
+}
+
+impl EventKind {
+    /// Name used in dumps
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::IrqEnter => "irq_enter",
+            EventKind::IrqExit => "irq_exit",
+            EventKind::Alloc => "alloc",
+            EventKind::Free => "free",
+            EventKind::ContextSwitch => "context_switch",
+            EventKind::PageFault => "page_fault",
+            EventKind::Signal => "signal",
+        }
+    }
+}
+
+/// One recorded event, 32 bytes
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub tsc: u64,
+    pub kind: EventKind,
+    pub cpu: u16,
+    pub args: [u64; 2],
+}
+
+/// Per-CPU event ring
+struct Ring {
+    events: UnsafeCell<[Option<Event>; EVENTS_PER_CPU]>,
+    /// Events recorded since the last clear
+    recorded: AtomicUsize,
+}
+
+// Each slot is claimed by one writer through `recorded`
+unsafe impl Sync for Ring {}
+
+static RINGS: [Ring; MAX_CPUS] = [const {
+    Ring {
+        events: UnsafeCell::new([None; EVENTS_PER_CPU]),
+        recorded: AtomicUsize::new(0),
+    }
+}; MAX_CPUS];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Record an event at a static tracepoint
+///
+/// ```ignore
+/// trace_event!(IrqEnter, vector as u64, 0);
+/// ```
+#[macro_export]
+macro_rules! trace_event {
+    ($kind:ident, $arg0:expr, $arg1:expr) => {
+        if $crate::trace::is_enabled() {
+            $crate::trace::record($crate::trace::EventKind::$kind, $arg0, $arg1);
+        }
+    };
+}
+
+/// Start recording
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop recording, the events recorded so far are kept
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+/// Check whether tracepoints record
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Discard all recorded events
+pub fn clear() {
+    for ring in &RINGS {
+        ring.recorded.store(0, Ordering::Release);
+    }
+}
+
+/// Append an event to the running CPU's ring
+///
+/// Safe in any context, including nested interrupts; never allocates.
+pub fn record(kind: EventKind, arg0: u64, arg1: u64) {
+    let cpu = cpu::current_id();
+    let ring = &RINGS[cpu];
+    let index = ring.recorded.fetch_add(1, Ordering::AcqRel) % EVENTS_PER_CPU;
+    let event = Event {
+        tsc: unsafe { core::arch::x86_64::_rdtsc() },
+        kind,
+        cpu: cpu as u16,
+        args: [arg0, arg1],
+    };
+    unsafe {
+        (*ring.events.get())[index] = Some(event);
+    }
+}
+
+/// Events held and events overwritten, over all CPUs
+pub fn event_count() -> (usize, usize) {
+    RINGS.iter().fold((0, 0), |(held, lost), ring| {
+        let recorded = ring.recorded.load(Ordering::Acquire);
+        (held + recorded.min(EVENTS_PER_CPU), lost + recorded.saturating_sub(EVENTS_PER_CPU))
+    })
+}
+
+/// Write every recorded event, oldest first per CPU
+///
+/// Recording is paused during the dump so slots are not rewritten while
+/// being read.
+pub fn dump(out: &mut dyn Write) -> core::fmt::Result {
+    let was_enabled = ENABLED.swap(false, Ordering::AcqRel);
+    let result = write_events(out);
+    ENABLED.store(was_enabled, Ordering::Release);
+    result
+}
+
+fn write_events(out: &mut dyn Write) -> core::fmt::Result {
+    writeln!(out, "# cosmos-trace v1 tsc_hz={}", crate::time::tsc_hz().unwrap_or(0))?;
+    writeln!(out, "tsc,cpu,event,arg0,arg1")?;
+    for ring in &RINGS {
+        let recorded = ring.recorded.load(Ordering::Acquire);
+        let first = recorded.saturating_sub(EVENTS_PER_CPU);
+        for i in first..recorded {
+            let event = unsafe { (*ring.events.get())[i % EVENTS_PER_CPU] };
+            if let Some(event) = event {
+                writeln!(out, "{},{},{},{:#x},{:#x}",
+                    event.tsc, event.cpu, event.kind.name(), event.args[0], event.args[1])?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,652 @@
+//! Terminals
+//!
+//! A TTY sits between an input driver and the task reading from it. In
+//! canonical mode it collects a line with echo and editing (backspace,
+//! Ctrl-U, Ctrl-W) and hands it out on Enter; in raw mode every byte is
+//! passed through as it arrives. Ctrl-C discards the line and sends
+//! `SIGINT` to the foreground task, Ctrl-D ends input with EOF. `ttyS0` is
+//! the COM1 console and `tty1` to `tty4` the virtual consoles on the VGA
+//! screen, which the keyboard types into while they are shown; device
+//! files for them come with `/dev`.
+//!
+//! Canonical mode also understands the ANSI escape sequences a serial
+//! terminal sends for its editing keys: the arrows move the cursor and
+//! step through recent lines, Home, End and Delete do what they say. This
+//! makes the COM1 console usable on machines with no keyboard, fed by the
+//! UART receive interrupt.
+
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::arch::x86_64::interrupts;
+use crate::input::{self, Decoded, Decoder, Key};
+use alloc::vec::Vec;
+use crate::sync::{CancelToken, IrqMutex, WaitError, WaitQueue};
+use crate::task::signal::{self, Signal};
+use crate::task::TaskId;
+
+/// Longest line canonical mode collects, further input is dropped
+pub const MAX_CANON: usize = 255;
+/// Bytes buffered for readers before input is dropped
+pub const INPUT_CAPACITY: usize = 4096;
+/// Lines canonical mode remembers for Up and Down
+pub const HISTORY_SIZE: usize = 16;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+/// Set once COM1 input arrives by interrupt rather than polling
+static SERIAL_IRQ: AtomicBool = AtomicBool::new(false);
+
+/// Errors returned by reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyError {
+    /// No input available
+    WouldBlock,
+    /// Ctrl-C discarded the line being read, the reader should start over
+    Interrupted,
+    /// No input arrived before the read's deadline
+    TimedOut,
+    /// The read's token was cancelled
+    Cancelled,
+}
+
+impl core::fmt::Display for TtyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TtyError::WouldBlock => write!(f, "No terminal input"),
+            TtyError::Interrupted => write!(f, "Terminal input interrupted"),
+            TtyError::TimedOut => write!(f, "Terminal read timed out"),
+            TtyError::Cancelled => write!(f, "Terminal read cancelled"),
+        }
+    }
+}
+
+impl From<WaitError> for TtyError {
+    fn from(e: WaitError) -> Self {
+        match e {
+            WaitError::TimedOut => TtyError::TimedOut,
+            WaitError::Cancelled => TtyError::Cancelled,
+        }
+    }
+}
+
+/// Line discipline settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Hand out whole edited lines rather than single bytes
+    pub canonical: bool,
+    /// Echo input back to the output
+    pub echo: bool,
+    /// Turn Ctrl-C into `SIGINT` rather than passing it through
+    pub signals: bool,
+}
+
+impl Settings {
+    pub const COOKED: Settings = Settings { canonical: true, echo: true, signals: true };
+    pub const RAW: Settings = Settings { canonical: false, echo: false, signals: false };
+}
+
+struct State {
+    settings: Settings,
+    /// Line being edited in canonical mode
+    line: Vec<u8>,
+    /// Cursor position within `line`
+    cursor: usize,
+    /// Entered lines, oldest first
+    history: VecDeque<Vec<u8>>,
+    /// History entry being shown, counted back from the newest
+    recall: Option<usize>,
+    escape: Decoder,
+    /// Last byte was a carriage return, a following line feed is swallowed
+    after_cr: bool,
+    /// Input ready for readers
+    ready: VecDeque<u8>,
+    /// Ctrl-D on an empty line, the next read returns 0
+    eof: bool,
+    /// Ctrl-C arrived, reported once to a non-blocking reader
+    interrupted: bool,
+    foreground: Option<TaskId>,
+}
+
+/// One terminal
+pub struct Tty {
+    name: &'static str,
+    output: fn(&[u8]),
+    state: IrqMutex<State>,
+    readable: WaitQueue,
+}
+
+impl Tty {
+    pub const fn new(name: &'static str, output: fn(&[u8])) -> Self {
+        Tty {
+            name,
+            output,
+            state: IrqMutex::named("tty", State {
+                settings: Settings::COOKED,
+                line: Vec::new(),
+                cursor: 0,
+                history: VecDeque::new(),
+                recall: None,
+                escape: Decoder::new(),
+                after_cr: false,
+                ready: VecDeque::new(),
+                eof: false,
+                interrupted: false,
+                foreground: None,
+            }),
+            readable: WaitQueue::new(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.state.lock().settings
+    }
+
+    /// Change the line discipline, a partial line is kept for canonical mode
+    pub fn set_settings(&self, settings: Settings) {
+        let mut state = self.state.lock();
+        if !settings.canonical {
+            // Leaving canonical mode releases what was typed so far
+            let line = core::mem::take(&mut state.line);
+            state.ready.extend(line);
+            state.cursor = 0;
+            state.escape.reset();
+        }
+        state.settings = settings;
+    }
+
+    /// Task that receives `SIGINT` for Ctrl-C
+    pub fn set_foreground(&self, task: Option<TaskId>) {
+        self.state.lock().foreground = task;
+    }
+
+    /// Write output to the terminal
+    pub fn write(&self, bytes: &[u8]) {
+        (self.output)(bytes);
+    }
+
+    /// Feed one input byte from the driver
+    ///
+    /// Not for interrupt handlers: echo allocates, and Ctrl-C signals the
+    /// foreground task under the scheduler lock. A driver buffers its bytes
+    /// in the handler and feeds them from deferred work, as COM1 does.
+    pub fn receive(&self, byte: u8) {
+        let mut echo: Vec<u8> = Vec::new();
+        let mut signal_target = None;
+        let mut wake = false;
+        let settings = {
+            let mut state = self.state.lock();
+            let settings = state.settings;
+            if settings.signals && byte == CTRL_C {
+                state.line.clear();
+                state.cursor = 0;
+                state.escape.reset();
+                state.interrupted = true;
+                signal_target = state.foreground;
+                echo.extend_from_slice(b"^C\n");
+                wake = true;
+            } else if !settings.canonical {
+                if state.ready.len() < INPUT_CAPACITY {
+                    state.ready.push_back(byte);
+                    wake = true;
+                }
+                echo.push(byte);
+            } else {
+                wake = edit_line(&mut state, byte, &mut echo);
+            }
+            settings
+        };
+
+        if !echo.is_empty() && settings.echo {
+            self.write(&echo);
+        }
+        if let Some(task) = signal_target {
+            let _ = signal::send(task, Signal::Interrupt);
+        }
+        if wake {
+            self.readable.wake_all();
+        }
+    }
+
+    /// Read input, blocking until some is available
+    ///
+    /// In canonical mode at most one line is returned. Returns 0 at EOF
+    /// and `Interrupted` after Ctrl-C or when a signal is pending.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, TtyError> {
+        let mut result = Err(TtyError::WouldBlock);
+        self.readable.wait_until(|| {
+            result = self.take_interruptible(buf);
+            result != Err(TtyError::WouldBlock)
+        });
+        result
+    }
+
+    /// Read input as `read` does, giving up with `TimedOut` or `Cancelled`
+    /// when `cancel` expires or is cancelled first
+    pub fn read_cancellable(&self, buf: &mut [u8], cancel: &CancelToken) -> Result<usize, TtyError> {
+        let mut result = Err(TtyError::WouldBlock);
+        self.readable.wait_cancellable(cancel, || {
+            result = self.take_interruptible(buf);
+            result != Err(TtyError::WouldBlock)
+        })?;
+        result
+    }
+
+    /// Read input without blocking
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<usize, TtyError> {
+        self.take(buf)
+    }
+
+    /// `take`, with a pending signal interrupting a read that found nothing
+    fn take_interruptible(&self, buf: &mut [u8]) -> Result<usize, TtyError> {
+        match self.take(buf) {
+            Err(TtyError::WouldBlock) if signal::has_pending() => Err(TtyError::Interrupted),
+            other => other,
+        }
+    }
+
+    fn take(&self, buf: &mut [u8]) -> Result<usize, TtyError> {
+        let mut state = self.state.lock();
+        if core::mem::take(&mut state.interrupted) {
+            return Err(TtyError::Interrupted);
+        }
+        if state.ready.is_empty() {
+            if core::mem::take(&mut state.eof) {
+                return Ok(0);
+            }
+            return Err(TtyError::WouldBlock);
+        }
+        let canonical = state.settings.canonical;
+        let mut count = 0;
+        while count < buf.len() {
+            let Some(byte) = state.ready.pop_front() else {
+                break;
+            };
+            buf[count] = byte;
+            count += 1;
+            if canonical && byte == b'\n' {
+                break;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Apply one byte to the canonical line, returns whether input became ready
+fn edit_line(state: &mut State, byte: u8, echo: &mut Vec<u8>) -> bool {
+    // Terminals send CR, CR LF or LF for Enter
+    if core::mem::replace(&mut state.after_cr, byte == b'\r') && byte == b'\n' {
+        return false;
+    }
+    let Some(input) = state.escape.feed(byte) else {
+        return false;
+    };
+    match input {
+        Decoded::Byte(b'\r' | b'\n') => {
+            let line = core::mem::take(&mut state.line);
+            if !line.is_empty() && state.history.back() != Some(&line) {
+                if state.history.len() == HISTORY_SIZE {
+                    state.history.pop_front();
+                }
+                state.history.push_back(line.clone());
+            }
+            state.ready.extend(line);
+            state.ready.push_back(b'\n');
+            state.cursor = 0;
+            state.recall = None;
+            echo.push(b'\n');
+            return true;
+        }
+        Decoded::Byte(CTRL_D) => {
+            if state.line.is_empty() {
+                state.eof = true;
+            } else {
+                let line = core::mem::take(&mut state.line);
+                state.ready.extend(line);
+                state.cursor = 0;
+            }
+            return true;
+        }
+        _ => {}
+    }
+
+    let old_line = state.line.clone();
+    let old_cursor = state.cursor;
+    let cursor = state.cursor;
+    match input {
+        Decoded::Byte(BACKSPACE | DELETE) if cursor > 0 => {
+            state.line.remove(cursor - 1);
+            state.cursor -= 1;
+        }
+        Decoded::Key(Key::Delete) if cursor < state.line.len() => {
+            state.line.remove(cursor);
+        }
+        Decoded::Byte(CTRL_U) => {
+            state.line.drain(..cursor);
+            state.cursor = 0;
+        }
+        Decoded::Byte(CTRL_W) => {
+            let mut start = cursor;
+            while start > 0 && state.line[start - 1] == b' ' {
+                start -= 1;
+            }
+            while start > 0 && state.line[start - 1] != b' ' {
+                start -= 1;
+            }
+            state.line.drain(start..cursor);
+            state.cursor = start;
+        }
+        Decoded::Key(Key::Left) => state.cursor = cursor.saturating_sub(1),
+        Decoded::Key(Key::Right) => state.cursor = (cursor + 1).min(state.line.len()),
+        Decoded::Key(Key::Home) => state.cursor = 0,
+        Decoded::Key(Key::End) => state.cursor = state.line.len(),
+        Decoded::Key(Key::Up) => {
+            let next = state.recall.map_or(0, |recall| recall + 1);
+            if next < state.history.len() {
+                state.line = state.history[state.history.len() - 1 - next].clone();
+                state.cursor = state.line.len();
+                state.recall = Some(next);
+            }
+        }
+        Decoded::Key(Key::Down) => {
+            match state.recall {
+                Some(0) => {
+                    state.line.clear();
+                    state.recall = None;
+                }
+                Some(recall) => {
+                    state.line = state.history[state.history.len() - recall].clone();
+                    state.recall = Some(recall - 1);
+                }
+                None => {}
+            }
+            state.cursor = state.line.len();
+        }
+        Decoded::Byte(0x20..=0x7E) if state.line.len() < MAX_CANON => {
+            state.line.insert(cursor, byte);
+            state.cursor += 1;
+        }
+        _ => {}
+    }
+    redraw(echo, &old_line, old_cursor, &state.line, state.cursor);
+    false
+}
+
+/// Echo what turns `old` with the cursor at `old_cursor` on screen into
+/// `new` with the cursor at `new_cursor`, using only backspace and spaces
+fn redraw(echo: &mut Vec<u8>, old: &[u8], old_cursor: usize, new: &[u8], new_cursor: usize) {
+    if old == new && old_cursor == new_cursor {
+        return;
+    }
+    let common = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let start = common.min(old_cursor);
+    echo.resize(echo.len() + old_cursor - start, BACKSPACE);
+    echo.extend_from_slice(&new[start..]);
+    let end = old.len().max(new.len());
+    echo.resize(echo.len() + end - new.len(), b' ');
+    echo.resize(echo.len() + end - new_cursor, BACKSPACE);
+}
+
+fn serial_output(bytes: &[u8]) {
+    for &byte in bytes {
+        crate::serial::write_byte(byte);
+    }
+}
+
+fn vt_output<const CONSOLE: usize>(bytes: &[u8]) {
+    crate::vt::write(CONSOLE, bytes, crate::vt::DEFAULT_ATTRIBUTE);
+}
+
+/// COM1 console
+pub static TTY_S0: Tty = Tty::new("ttyS0", serial_output);
+/// Virtual consoles, `tty1` to `tty4`
+pub static VTS: [Tty; crate::vt::COUNT] = [
+    Tty::new("tty1", vt_output::<0>),
+    Tty::new("tty2", vt_output::<1>),
+    Tty::new("tty3", vt_output::<2>),
+    Tty::new("tty4", vt_output::<3>),
+];
+
+/// Terminal of the virtual console on the screen
+pub fn active() -> &'static Tty {
+    &VTS[crate::vt::active()]
+}
+
+/// All terminals
+pub fn all() -> [&'static Tty; crate::vt::COUNT + 1] {
+    [&VTS[0], &VTS[1], &VTS[2], &VTS[3], &TTY_S0]
+}
+
+/// Look up a terminal by name
+pub fn find(name: &str) -> Option<&'static Tty> {
+    all().into_iter().find(|tty| tty.name == name)
+}
+
+/// Bytes the COM1 interrupt can hold before `ttyS0` takes them
+const SERIAL_RX_CAPACITY: usize = 256;
+
+/// Bytes read from COM1, waiting for task context
+///
+/// `Tty::receive` allocates, so the interrupt handler only empties the
+/// UART into this buffer and leaves the rest to deferred work.
+struct RxBuffer {
+    data: [u8; SERIAL_RX_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl RxBuffer {
+    fn push(&mut self, byte: u8) {
+        if self.len < SERIAL_RX_CAPACITY {
+            self.data[(self.head + self.len) % SERIAL_RX_CAPACITY] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len);
+        for byte in buf[..count].iter_mut() {
+            *byte = self.data[self.head];
+            self.head = (self.head + 1) % SERIAL_RX_CAPACITY;
+        }
+        self.len -= count;
+        count
+    }
+}
+
+static SERIAL_RX: IrqMutex<RxBuffer> =
+    IrqMutex::named("serial rx", RxBuffer { data: [0; SERIAL_RX_CAPACITY], head: 0, len: 0 });
+
+/// Held while moving bytes into `ttyS0`, so they arrive in order, with
+/// the decoder for the input events they also become
+static SERIAL_FEED: spin::Mutex<Decoder> = spin::Mutex::new(Decoder::new());
+
+/// COM1 as an input device
+static SERIAL_INPUT: spin::Once<input::DeviceId> = spin::Once::new();
+
+/// Take COM1 input by interrupt and report it as input events, and feed
+/// keyboards to the virtual consoles
+pub fn init() {
+    input::keymap::init();
+    SERIAL_INPUT.call_once(|| input::register_device("ttyS0", input::DeviceKind::Serial));
+    crate::task::spawn("vt/keyboard", keyboard_input);
+    match interrupts::register_irq(crate::serial::COM1_IRQ, serial_interrupt) {
+        Ok(()) => {
+            SERIAL_IRQ.store(true, Ordering::Release);
+            // Bytes that arrived before the line was unmasked raise no interrupt
+            poll_serial();
+        }
+        Err(e) => crate::serial_println!("ttyS0: {}, polling for input", e),
+    }
+}
+
+/// Whether COM1 input arrives by interrupt
+pub fn serial_irq_enabled() -> bool {
+    SERIAL_IRQ.load(Ordering::Acquire)
+}
+
+/// Move bytes received on COM1 into `ttyS0`
+pub fn poll_serial() {
+    read_uart();
+    feed_serial(0);
+}
+
+/// Empty the UART into `SERIAL_RX`
+fn read_uart() {
+    let mut rx = SERIAL_RX.lock();
+    while let Some(byte) = crate::serial::try_read_byte() {
+        rx.push(byte);
+    }
+}
+
+/// Deferred work: hand buffered COM1 bytes to `ttyS0`
+fn feed_serial(_: usize) {
+    let mut chunk = [0u8; 64];
+    while let Some(mut decoder) = SERIAL_FEED.try_lock() {
+        let count = SERIAL_RX.lock().pop(&mut chunk);
+        if count == 0 {
+            break;
+        }
+        for &byte in &chunk[..count] {
+            TTY_S0.receive(byte);
+            let key = decoder.feed(byte).and_then(input::terminal_key);
+            if let (Some(&device), Some((key, modifiers))) = (SERIAL_INPUT.get(), key) {
+                input::report_keystroke(device, key, modifiers);
+            }
+        }
+    }
+}
+
+/// Console hotkeys, returns whether `key` was one
+///
+/// Alt+F1..F4 switches consoles, Shift+PageUp and Shift+PageDown page
+/// through scrollback half a screen at a time.
+fn console_hotkey(key: Key, modifiers: input::Modifiers) -> bool {
+    const PAGE: isize = (crate::vga::BUFFER_HEIGHT / 2) as isize;
+    match key {
+        Key::Function(n) if modifiers.contains(input::Modifiers::ALT) && (1..=crate::vt::COUNT).contains(&(n as usize)) => {
+            let _ = crate::vt::switch(n as usize - 1);
+        }
+        Key::PageUp if modifiers.contains(input::Modifiers::SHIFT) => crate::vt::scroll(PAGE),
+        Key::PageDown if modifiers.contains(input::Modifiers::SHIFT) => crate::vt::scroll(-PAGE),
+        _ => return false,
+    }
+    true
+}
+
+/// Send what `key` types to `tty`
+///
+/// Characters outside ASCII go in the screen's code page, as the console
+/// draws them.
+fn type_key(tty: &Tty, key: Key, modifiers: input::Modifiers) {
+    if let Key::Char(ch) = key {
+        if !ch.is_ascii() {
+            if let Some(byte) = crate::vga::cp437(ch) {
+                tty.receive(byte);
+            }
+            return;
+        }
+    }
+    let mut bytes = [0u8; 4];
+    let count = input::encode(key, modifiers, &mut bytes);
+    for &byte in &bytes[..count] {
+        tty.receive(byte);
+    }
+}
+
+/// Type keyboard key presses into the shown virtual console, through the
+/// keyboard layout
+fn keyboard_input() {
+    let events = input::subscribe(input::EventMask::KEY, input::DEFAULT_QUEUE_DEPTH);
+    let mut keymap = input::keymap::Keymap::new();
+    loop {
+        let event = events.next();
+        let input::EventKind::Key { key, modifiers, pressed: true } = event.kind else {
+            continue;
+        };
+        if input::device_kind(event.device) != Some(input::DeviceKind::Keyboard) {
+            continue;
+        }
+        keymap.translate(input::keymap::active(), key, modifiers, |key, modifiers| {
+            if !console_hotkey(key, modifiers) {
+                type_key(active(), key, modifiers);
+            }
+        });
+    }
+}
+
+fn serial_interrupt(_frame: &InterruptStackFrame) {
+    // Reading the receive buffer empty clears the interrupt
+    read_uart();
+    crate::task::deferred::queue_work(feed_serial, 0);
+}
+
+fn selftest_output(_bytes: &[u8]) {}
+
+/// Line editing, EOF, Ctrl-C, raw mode and a cancelled read on a detached
+/// terminal
+fn selftest() -> Result<(), &'static str> {
+    let tty = Tty::new("test", selftest_output);
+    let feed = |bytes: &[u8]| bytes.iter().for_each(|&byte| tty.receive(byte));
+    let mut buf = [0u8; 64];
+
+    feed(b"helo\x08lo wrld\x17world");
+    if tty.try_read(&mut buf) != Err(TtyError::WouldBlock) {
+        return Err("partial line readable");
+    }
+    feed(b"\rnext\n");
+    let count = tty.try_read(&mut buf).map_err(|_| "line not ready")?;
+    if &buf[..count] != b"hello world\n" {
+        return Err("line editing wrong");
+    }
+    let count = tty.try_read(&mut buf).map_err(|_| "second line not ready")?;
+    if &buf[..count] != b"next\n" {
+        return Err("lines not split");
+    }
+
+    feed(b"gone\x03");
+    if tty.try_read(&mut buf) != Err(TtyError::Interrupted) || tty.try_read(&mut buf) != Err(TtyError::WouldBlock) {
+        return Err("Ctrl-C did not discard the line");
+    }
+    feed(&[CTRL_D]);
+    if tty.try_read(&mut buf) != Ok(0) {
+        return Err("Ctrl-D did not give EOF");
+    }
+
+    // Left, insert, Home, Delete, End, and an Enter sent as CR LF
+    feed(b"ac\x1b[Db\x1b[H\x1b[3~\x1bOFd\r\n");
+    let count = tty.try_read(&mut buf).map_err(|_| "edited line not ready")?;
+    if &buf[..count] != b"bcd\n" || tty.try_read(&mut buf) != Err(TtyError::WouldBlock) {
+        return Err("escape sequence editing wrong");
+    }
+    // Up twice recalls the line before, modifiers are ignored
+    feed(b"\x1b[A\x1b[A\x1b[1;5C!\n");
+    let count = tty.try_read(&mut buf).map_err(|_| "recalled line not ready")?;
+    if &buf[..count] != b"next!\n" {
+        return Err("history recall wrong");
+    }
+
+    tty.set_settings(Settings::RAW);
+    feed(&[b'a', CTRL_C]);
+    if tty.try_read(&mut buf) != Ok(2) || buf[..2] != [b'a', CTRL_C] {
+        return Err("raw mode altered input");
+    }
+
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    if tty.read_cancellable(&mut buf, &cancel) != Err(TtyError::Cancelled) {
+        return Err("cancelled read blocked");
+    }
+    Ok(())
+}
+
+crate::selftest!("tty", selftest);
@@ -0,0 +1,182 @@
+//! USB HID Boot Keyboards
+//!
+//! Keyboards in the boot protocol send a fixed 8-byte report: a modifier
+//! bitmap, a reserved byte and up to six pressed keys as usage IDs. The
+//! driver compares each report with the last and turns the difference
+//! into key press and release events, so no report descriptor parsing is
+//! needed. Keys are reported by their US layout meaning, `input::keymap`
+//! turns them into the characters of the layout in use.
+
+use super::Interface;
+use crate::input::{self, DeviceId, EventKind, Key, Modifiers};
+
+pub const CLASS_HID: u8 = 3;
+pub const SUBCLASS_BOOT: u8 = 1;
+pub const PROTOCOL_KEYBOARD: u8 = 1;
+
+/// HID class request selecting boot or report protocol
+pub const REQUEST_SET_PROTOCOL: u8 = 0x0B;
+/// HID class request limiting how often unchanged reports repeat
+pub const REQUEST_SET_IDLE: u8 = 0x0A;
+/// SET_PROTOCOL value for the boot protocol
+pub const BOOT_PROTOCOL: u16 = 0;
+
+/// Size of a boot keyboard report
+pub const REPORT_SIZE: usize = 8;
+
+/// Usage ID in every key slot when too many keys are held
+const ERROR_ROLLOVER: u8 = 0x01;
+
+/// Whether an interface speaks the keyboard boot protocol
+pub fn is_boot_keyboard(interface: &Interface) -> bool {
+    interface.class == CLASS_HID && interface.subclass == SUBCLASS_BOOT && interface.protocol == PROTOCOL_KEYBOARD
+}
+
+/// State of one boot keyboard
+pub struct Keyboard {
+    device: DeviceId,
+    previous: [u8; REPORT_SIZE],
+}
+
+impl Keyboard {
+    pub fn new(device: DeviceId) -> Self {
+        Keyboard { device, previous: [0; REPORT_SIZE] }
+    }
+
+    pub fn device(&self) -> DeviceId {
+        self.device
+    }
+
+    /// Turn a report into key events
+    pub fn report(&mut self, report: &[u8]) {
+        if report.len() < REPORT_SIZE || report[2] == ERROR_ROLLOVER {
+            return;
+        }
+        let mut current = [0u8; REPORT_SIZE];
+        current.copy_from_slice(&report[..REPORT_SIZE]);
+        let previous = core::mem::replace(&mut self.previous, current);
+
+        let changed = previous[0] ^ current[0];
+        for bit in 0..8 {
+            if changed & (1 << bit) != 0 {
+                let key = match bit % 4 {
+                    0 => Key::Control,
+                    1 => Key::Shift,
+                    2 => Key::Alt,
+                    // GUI keys have no meaning here
+                    _ => continue,
+                };
+                let pressed = current[0] & (1 << bit) != 0;
+                self.send(key, current[0], pressed);
+            }
+        }
+
+        for &usage in previous[2..].iter().filter(|&&usage| usage != 0 && !current[2..].contains(&usage)) {
+            self.send(usage_key(usage), current[0], false);
+        }
+        for &usage in current[2..].iter().filter(|&&usage| usage != 0 && !previous[2..].contains(&usage)) {
+            self.send(usage_key(usage), current[0], true);
+        }
+    }
+
+    fn send(&self, key: Key, modifier_bits: u8, pressed: bool) {
+        input::report(self.device, EventKind::Key { key, modifiers: modifiers(modifier_bits), pressed });
+    }
+}
+
+/// Right Alt in the report's bitmap
+const RIGHT_ALT: u8 = 0x40;
+
+/// Modifiers from the report's bitmap, left and right alike except Alt,
+/// where the right one is AltGr
+fn modifiers(bits: u8) -> Modifiers {
+    let either = bits | bits >> 4;
+    let mut modifiers = Modifiers::NONE;
+    if either & 0x01 != 0 {
+        modifiers = modifiers.union(Modifiers::CONTROL);
+    }
+    if either & 0x02 != 0 {
+        modifiers = modifiers.union(Modifiers::SHIFT);
+    }
+    if bits & 0x04 != 0 {
+        modifiers = modifiers.union(Modifiers::ALT);
+    }
+    if bits & RIGHT_ALT != 0 {
+        modifiers = modifiers.union(Modifiers::ALT_GR);
+    }
+    modifiers
+}
+
+/// Characters of usages 0x2D to 0x38 on a US keyboard
+const PUNCTUATION: &[u8; 12] = b"-=[]\\#;'`,./";
+
+/// Key for a keyboard page usage ID
+pub fn usage_key(usage: u8) -> Key {
+    match usage {
+        0x04..=0x1D => Key::Char((b'a' + usage - 0x04) as char),
+        0x1E..=0x26 => Key::Char((b'1' + usage - 0x1E) as char),
+        0x27 => Key::Char('0'),
+        0x28 | 0x58 => Key::Enter,
+        0x29 => Key::Escape,
+        0x2A => Key::Backspace,
+        0x2B => Key::Tab,
+        0x2C => Key::Char(' '),
+        0x2D..=0x38 => Key::Char(PUNCTUATION[(usage - 0x2D) as usize] as char),
+        0x3A..=0x45 => Key::Function(usage - 0x3A + 1),
+        0x49 => Key::Insert,
+        0x4A => Key::Home,
+        0x4B => Key::PageUp,
+        0x4C => Key::Delete,
+        0x4D => Key::End,
+        0x4E => Key::PageDown,
+        0x4F => Key::Right,
+        0x50 => Key::Left,
+        0x51 => Key::Down,
+        0x52 => Key::Up,
+        0x64 => Key::NonUsBackslash,
+        0x68..=0x73 => Key::Function(usage - 0x68 + 13),
+        _ => Key::Unknown(usage as u16),
+    }
+}
+
+/// Reports become presses and releases in order, with modifiers
+fn selftest() -> Result<(), &'static str> {
+    let device = input::register_device("hid-selftest", input::DeviceKind::Keyboard);
+    let events = input::subscribe(input::EventMask::KEY, 16);
+    let mut keyboard = Keyboard::new(device);
+
+    // Shift down, then 'a' with shift, then 'a' up, then all up
+    keyboard.report(&[0x02, 0, 0, 0, 0, 0, 0, 0]);
+    keyboard.report(&[0x02, 0, 0x04, 0, 0, 0, 0, 0]);
+    keyboard.report(&[0x02, 0, ERROR_ROLLOVER, ERROR_ROLLOVER, 0, 0, 0, 0]);
+    keyboard.report(&[0x02, 0, 0, 0, 0, 0, 0, 0]);
+    keyboard.report(&[0, 0, 0, 0, 0, 0, 0, 0]);
+
+    let mut kinds = alloc::vec::Vec::new();
+    while let Some(event) = events.try_next() {
+        if event.device == device {
+            kinds.push(event.kind);
+        }
+    }
+    input::unregister_device(device);
+
+    let key = |key, modifiers, pressed| EventKind::Key { key, modifiers, pressed };
+    let expected = [
+        key(Key::Shift, Modifiers::SHIFT, true),
+        key(Key::Char('a'), Modifiers::SHIFT, true),
+        key(Key::Char('a'), Modifiers::SHIFT, false),
+        key(Key::Shift, Modifiers::NONE, false),
+    ];
+    if kinds != expected {
+        return Err("key events wrong");
+    }
+    if usage_key(0x38) != Key::Char('/') || usage_key(0x45) != Key::Function(12) {
+        return Err("usage table wrong");
+    }
+    if modifiers(RIGHT_ALT | 0x02) != Modifiers::ALT_GR.union(Modifiers::SHIFT) {
+        return Err("right Alt not AltGr");
+    }
+    Ok(())
+}
+
+crate::selftest!("hid", selftest);
@@ -0,0 +1,280 @@
+//! USB
+//!
+//! Host controller drivers enumerate the devices on their root ports and
+//! bind class drivers to the interfaces they recognise. Only XHCI and the
+//! HID boot keyboard are supported: enough to type on machines that have
+//! no PS/2 controller. Hubs are not walked, so devices must sit on a root
+//! port, which is where QEMU's `usb-kbd` and most laptop keyboards are.
+
+pub mod hid;
+pub mod xhci;
+
+use alloc::vec::Vec;
+use core::fmt;
+
+// Standard requests
+pub const REQUEST_GET_DESCRIPTOR: u8 = 6;
+pub const REQUEST_SET_CONFIGURATION: u8 = 9;
+
+// Descriptor types
+pub const DESCRIPTOR_DEVICE: u8 = 1;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 2;
+pub const DESCRIPTOR_INTERFACE: u8 = 4;
+pub const DESCRIPTOR_ENDPOINT: u8 = 5;
+
+// bmRequestType fields
+pub const REQUEST_DEVICE_TO_HOST: u8 = 0x80;
+pub const REQUEST_TYPE_CLASS: u8 = 0x20;
+pub const REQUEST_RECIPIENT_INTERFACE: u8 = 0x01;
+
+/// Endpoint address bit for IN endpoints
+pub const ENDPOINT_IN: u8 = 0x80;
+/// Endpoint attributes transfer type for interrupt endpoints
+pub const ENDPOINT_INTERRUPT: u8 = 0x03;
+
+/// Errors that can occur talking to USB devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    /// Controller or device did not answer in time
+    Timeout,
+    /// Device stalled the request
+    Stall,
+    /// Transfer or command failed with this completion code
+    Completion(u8),
+    /// No memory for DMA structures
+    NoMemory,
+    /// Controller cannot be driven
+    Unsupported,
+    /// Descriptor shorter or stranger than the spec allows
+    BadDescriptor,
+}
+
+impl fmt::Display for UsbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsbError::Timeout => write!(f, "USB timeout"),
+            UsbError::Stall => write!(f, "USB request stalled"),
+            UsbError::Completion(code) => write!(f, "USB completion code {}", code),
+            UsbError::NoMemory => write!(f, "Out of memory for USB"),
+            UsbError::Unsupported => write!(f, "Unsupported USB controller"),
+            UsbError::BadDescriptor => write!(f, "Malformed USB descriptor"),
+        }
+    }
+}
+
+/// Link speed of a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+impl Speed {
+    /// Default control endpoint packet size before the device descriptor
+    /// says otherwise
+    pub fn default_max_packet(self) -> u16 {
+        match self {
+            Speed::Low | Speed::Full => 8,
+            Speed::High => 64,
+            Speed::Super => 512,
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Speed::Low => "low speed",
+            Speed::Full => "full speed",
+            Speed::High => "high speed",
+            Speed::Super => "SuperSpeed",
+        })
+    }
+}
+
+/// Control request, the setup stage of a control transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// GET_DESCRIPTOR for `kind` number `index`
+    pub fn get_descriptor(kind: u8, index: u8, length: u16) -> Self {
+        SetupPacket {
+            request_type: REQUEST_DEVICE_TO_HOST,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: (kind as u16) << 8 | index as u16,
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_configuration(value: u8) -> Self {
+        SetupPacket { request_type: 0, request: REQUEST_SET_CONFIGURATION, value: value as u16, index: 0, length: 0 }
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.request_type & REQUEST_DEVICE_TO_HOST != 0
+    }
+
+    /// The packet as the 8 bytes sent on the wire, little-endian
+    pub fn to_u64(self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+}
+
+/// Device descriptor fields drivers use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const SIZE: usize = 18;
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, UsbError> {
+        if bytes.len() < Self::SIZE || bytes[1] != DESCRIPTOR_DEVICE {
+            return Err(UsbError::BadDescriptor);
+        }
+        Ok(DeviceDescriptor {
+            usb_version: u16::from_le_bytes([bytes[2], bytes[3]]),
+            class: bytes[4],
+            max_packet_size0: bytes[7],
+            vendor_id: u16::from_le_bytes([bytes[8], bytes[9]]),
+            product_id: u16::from_le_bytes([bytes[10], bytes[11]]),
+            configurations: bytes[17],
+        })
+    }
+}
+
+/// Endpoint of an interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl Endpoint {
+    pub fn number(&self) -> u8 {
+        self.address & 0x0F
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.address & ENDPOINT_IN != 0
+    }
+
+    pub fn is_interrupt(&self) -> bool {
+        self.attributes & 0x03 == ENDPOINT_INTERRUPT
+    }
+}
+
+/// Interface of a configuration, with its endpoints
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub number: u8,
+    pub alternate: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<Endpoint>,
+}
+
+/// Configuration descriptor with the interfaces that follow it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Configuration {
+    pub value: u8,
+    pub interfaces: Vec<Interface>,
+}
+
+impl Configuration {
+    /// Parse a full configuration descriptor, as long as its total length
+    pub fn parse(bytes: &[u8]) -> Result<Self, UsbError> {
+        if bytes.len() < 9 || bytes[1] != DESCRIPTOR_CONFIGURATION {
+            return Err(UsbError::BadDescriptor);
+        }
+        let total = (u16::from_le_bytes([bytes[2], bytes[3]]) as usize).min(bytes.len());
+        let mut configuration = Configuration { value: bytes[5], interfaces: Vec::new() };
+
+        let mut offset = bytes[0] as usize;
+        while offset + 2 <= total {
+            let length = bytes[offset] as usize;
+            if length < 2 || offset + length > total {
+                return Err(UsbError::BadDescriptor);
+            }
+            let descriptor = &bytes[offset..offset + length];
+            match descriptor[1] {
+                DESCRIPTOR_INTERFACE if length >= 9 => configuration.interfaces.push(Interface {
+                    number: descriptor[2],
+                    alternate: descriptor[3],
+                    class: descriptor[5],
+                    subclass: descriptor[6],
+                    protocol: descriptor[7],
+                    endpoints: Vec::new(),
+                }),
+                DESCRIPTOR_ENDPOINT if length >= 7 => {
+                    if let Some(interface) = configuration.interfaces.last_mut() {
+                        interface.endpoints.push(Endpoint {
+                            address: descriptor[2],
+                            attributes: descriptor[3],
+                            max_packet_size: u16::from_le_bytes([descriptor[4], descriptor[5]]) & 0x7FF,
+                            interval: descriptor[6],
+                        });
+                    }
+                }
+                // Class-specific descriptors, such as HID's, are not needed
+                _ => {}
+            }
+            offset += length;
+        }
+        Ok(configuration)
+    }
+}
+
+/// Find USB host controllers and the keyboards on them
+pub fn init() {
+    xhci::init();
+}
+
+/// A keyboard's configuration descriptor parses into its boot interface
+fn selftest() -> Result<(), &'static str> {
+    // Configuration, interface (HID, boot, keyboard), HID, endpoint 0x81
+    let bytes = [
+        9, 2, 34, 0, 1, 1, 0, 0xA0, 50,
+        9, 4, 0, 0, 1, 3, 1, 1, 0,
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0,
+        7, 5, 0x81, 3, 8, 0, 10,
+    ];
+    let configuration = Configuration::parse(&bytes).map_err(|_| "configuration rejected")?;
+    let interface = configuration.interfaces.first().ok_or("no interface")?;
+    if !hid::is_boot_keyboard(interface) || configuration.value != 1 {
+        return Err("boot keyboard not recognised");
+    }
+    let endpoint = interface.endpoints.first().ok_or("no endpoint")?;
+    if !endpoint.is_in() || !endpoint.is_interrupt() || endpoint.number() != 1 || endpoint.max_packet_size != 8 {
+        return Err("endpoint misparsed");
+    }
+    if Configuration::parse(&bytes[..20]).is_ok() || Configuration::parse(&[9, 2, 40, 0, 1, 1, 0, 0, 0, 200, 4]).is_ok() {
+        return Err("truncated descriptor accepted");
+    }
+    Ok(())
+}
+
+crate::selftest!("usb", selftest);
@@ -0,0 +1,772 @@
+//! XHCI Host Controller
+//!
+//! The driver resets each XHCI controller found on PCI, gives it a command
+//! ring and one event ring, and enumerates the devices already attached to
+//! its root ports. Boot keyboards get an interrupt endpoint whose reports
+//! go to the HID driver. Completions are polled from a worker thread
+//! rather than taken by interrupt, and ports are only scanned at boot, so
+//! hotplug is not supported. All DMA structures are single identity-mapped
+//! pages.
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use super::{hid, Configuration, DeviceDescriptor, SetupPacket, Speed, UsbError};
+use super::{DESCRIPTOR_CONFIGURATION, DESCRIPTOR_DEVICE, REQUEST_RECIPIENT_INTERFACE, REQUEST_TYPE_CLASS};
+use crate::input;
+use crate::mm::mmio::{self, Mmio};
+use crate::mm::{frame_allocator, PhysicalAddress, PhysicalFrame};
+use crate::pci::{self, Bar, PciDevice};
+
+// Capability registers
+const CAPLENGTH: usize = 0x00;
+const HCSPARAMS1: usize = 0x04;
+const HCSPARAMS2: usize = 0x08;
+const HCCPARAMS1: usize = 0x10;
+const DBOFF: usize = 0x14;
+const RTSOFF: usize = 0x18;
+
+// Operational registers
+const USBCMD: usize = 0x00;
+const USBSTS: usize = 0x04;
+const CRCR: usize = 0x18;
+const DCBAAP: usize = 0x30;
+const CONFIG: usize = 0x38;
+const PORTSC: usize = 0x400;
+const PORT_STRIDE: usize = 0x10;
+
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_RESET: u32 = 1 << 1;
+const USBSTS_HALTED: u32 = 1 << 0;
+const USBSTS_NOT_READY: u32 = 1 << 11;
+/// HCCPARAMS1: contexts are 64 bytes rather than 32
+const HCC_CONTEXT_64: u32 = 1 << 2;
+
+const PORTSC_CONNECTED: u32 = 1 << 0;
+const PORTSC_ENABLED: u32 = 1 << 1;
+const PORTSC_RESET: u32 = 1 << 4;
+const PORTSC_RESET_CHANGE: u32 = 1 << 21;
+/// Change bits that writing 1 clears, and the enable bit writing 1 clears
+const PORTSC_WRITE_CLEAR: u32 = 0x7F << 17 | PORTSC_ENABLED;
+
+// Interrupter 0, relative to the runtime registers
+const ERSTSZ: usize = 0x28;
+const ERSTBA: usize = 0x30;
+const ERDP: usize = 0x38;
+/// Event handler busy, written as 1 to clear it
+const ERDP_BUSY: u64 = 1 << 3;
+
+/// USB legacy support extended capability, BIOS/OS ownership handoff
+const EXT_CAP_LEGACY: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+/// SMI status bits of the legacy control register, written as 1 to clear
+const LEGACY_SMI_STATUS: u32 = 0xE000_0000;
+
+// TRB types
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_SET_DEQUEUE: u32 = 16;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+
+// TRB control bits
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_INTERRUPT_ON_SHORT: u32 = 1 << 2;
+const TRB_INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
+const TRB_IMMEDIATE_DATA: u32 = 1 << 6;
+const TRB_DIRECTION_IN: u32 = 1 << 16;
+/// Setup TRB transfer types
+const TRANSFER_OUT: u32 = 2 << 16;
+const TRANSFER_IN: u32 = 3 << 16;
+
+// Completion codes
+const COMPLETION_SUCCESS: u8 = 1;
+const COMPLETION_STALL: u8 = 6;
+const COMPLETION_SHORT_PACKET: u8 = 13;
+
+// Endpoint context types
+const EP_CONTROL: u32 = 4;
+const EP_INTERRUPT_IN: u32 = 7;
+/// Error retries for an endpoint
+const EP_ERROR_COUNT: u32 = 3;
+
+/// TRBs per ring, one page, the last being the link back to the start
+const RING_SIZE: usize = 256;
+const TRB_SIZE: u64 = 16;
+/// Device context index of the default control endpoint
+const EP0: u8 = 1;
+/// Offset of interrupt transfer buffers in a device's data page
+const REPORT_OFFSET: u64 = 2048;
+/// Largest interrupt report accepted
+const MAX_REPORT: u16 = 64;
+
+/// Time allowed for the controller, a command or a transfer
+const TIMEOUT_MS: u64 = 1000;
+/// Recovery time after a port reset, per the USB 2.0 spec
+const RESET_RECOVERY_MS: u64 = 10;
+/// How often completions are polled
+const POLL_MS: u64 = 10;
+
+/// Transfer request block
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, align(16))]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(kind: u32, parameter: u64, status: u32, flags: u32) -> Self {
+        Trb { parameter, status, control: kind << 10 | flags }
+    }
+
+    /// Command aimed at an endpoint of a device slot
+    fn endpoint_command(kind: u32, parameter: u64, slot: u8, dci: u8) -> Self {
+        Trb::new(kind, parameter, 0, (dci as u32) << 16 | (slot as u32) << 24)
+    }
+
+    fn kind(&self) -> u32 {
+        (self.control >> 10) & 0x3F
+    }
+
+    fn completion(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    /// Bytes not transferred, for transfer events
+    fn residual(&self) -> usize {
+        (self.status & 0xFF_FFFF) as usize
+    }
+
+    fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    fn endpoint(&self) -> u8 {
+        ((self.control >> 16) & 0x1F) as u8
+    }
+}
+
+/// A zeroed page for the controller to read and write
+fn dma_page() -> Result<u64, UsbError> {
+    let frame = frame_allocator::allocate_frame().map_err(|_| UsbError::NoMemory)?;
+    let address = frame.start_address().as_u64();
+    unsafe { core::ptr::write_bytes(address as *mut u8, 0, PhysicalFrame::SIZE as usize) };
+    Ok(address)
+}
+
+/// Spin until `condition` holds or the timeout passes
+fn wait_for(mut condition: impl FnMut() -> bool) -> Result<(), UsbError> {
+    let deadline = crate::time::uptime_ms() + TIMEOUT_MS;
+    while !condition() {
+        if crate::time::uptime_ms() >= deadline {
+            return Err(UsbError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+fn delay_ms(ms: u64) {
+    let deadline = crate::time::uptime_ms() + ms;
+    while crate::time::uptime_ms() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// 64-bit registers written low half first, as 32-bit controllers need
+fn write64(registers: &Mmio, offset: usize, value: u64) {
+    registers.write(offset, value as u32);
+    registers.write(offset + 4, (value >> 32) as u32);
+}
+
+/// Write dword `index` of a context at `address`
+fn write_context(address: u64, index: usize, value: u32) {
+    unsafe { write_volatile((address as *mut u32).add(index), value) }
+}
+
+/// Producer ring: the command ring and transfer rings
+struct Ring {
+    base: u64,
+    index: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new() -> Result<Self, UsbError> {
+        let base = dma_page()?;
+        let link = Trb::new(TRB_LINK, base, 0, TRB_TOGGLE_CYCLE);
+        unsafe { write_volatile((base as *mut Trb).add(RING_SIZE - 1), link) };
+        Ok(Ring { base, index: 0, cycle: true })
+    }
+
+    /// Queue a TRB, returning its address
+    fn push(&mut self, trb: Trb) -> u64 {
+        let slot = unsafe { (self.base as *mut Trb).add(self.index) };
+        unsafe {
+            write_volatile(&mut (*slot).parameter, trb.parameter);
+            write_volatile(&mut (*slot).status, trb.status);
+            // The cycle bit hands the TRB over, it must land last
+            fence(Ordering::Release);
+            write_volatile(&mut (*slot).control, (trb.control & !TRB_CYCLE) | self.cycle as u32);
+        }
+        self.index += 1;
+        if self.index == RING_SIZE - 1 {
+            unsafe {
+                let link = (self.base as *mut Trb).add(self.index);
+                let control = read_volatile(&(*link).control);
+                write_volatile(&mut (*link).control, (control & !TRB_CYCLE) | self.cycle as u32);
+            }
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        slot as u64
+    }
+
+    /// Next enqueue position with the cycle state, as dequeue pointers take it
+    fn dequeue_pointer(&self) -> u64 {
+        (self.base + self.index as u64 * TRB_SIZE) | self.cycle as u64
+    }
+}
+
+/// Consumer ring the controller posts events to
+struct EventRing {
+    base: u64,
+    index: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn pop(&mut self) -> Option<Trb> {
+        let slot = unsafe { (self.base as *const Trb).add(self.index) };
+        let control = unsafe { read_volatile(&(*slot).control) };
+        if (control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let event = unsafe { read_volatile(slot) };
+        self.index += 1;
+        if self.index == RING_SIZE {
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(event)
+    }
+
+    fn dequeue_pointer(&self) -> u64 {
+        self.base + self.index as u64 * TRB_SIZE
+    }
+}
+
+/// Interrupt IN endpoint of a boot keyboard
+struct KeyboardEndpoint {
+    dci: u8,
+    ring: Ring,
+    length: u16,
+    keyboard: hid::Keyboard,
+}
+
+/// An addressed device
+struct Device {
+    slot: u8,
+    port: u8,
+    speed: Speed,
+    input_context: u64,
+    ep0: Ring,
+    /// Page for control transfer data and interrupt reports
+    buffer: u64,
+    descriptor: Option<DeviceDescriptor>,
+    keyboard: Option<KeyboardEndpoint>,
+}
+
+impl Device {
+    fn report_buffer(&self) -> u64 {
+        self.buffer + REPORT_OFFSET
+    }
+}
+
+struct Controller {
+    pci: PciDevice,
+    operational: Mmio,
+    runtime: Mmio,
+    doorbells: Mmio,
+    max_ports: u8,
+    context_size: usize,
+    dcbaa: u64,
+    commands: Ring,
+    events: EventRing,
+    devices: Vec<Device>,
+}
+
+impl Controller {
+    /// Reset the controller and start it with empty rings
+    fn new(pci: PciDevice) -> Result<Self, UsbError> {
+        let Some(Bar::Memory { address, size, .. }) = pci.bar(0) else {
+            return Err(UsbError::Unsupported);
+        };
+        let registers: Mmio = mmio::map_region(PhysicalAddress::new(address), size as usize)
+            .map_err(|_| UsbError::Unsupported)?;
+        pci.enable_bus_master();
+
+        let block = |offset: usize| registers.subregion(offset, registers.len() - offset);
+        let cap_length = registers.read(CAPLENGTH) & 0xFF;
+        let structural = registers.read(HCSPARAMS1);
+        let max_slots = structural & 0xFF;
+        let max_ports = (structural >> 24) as u8;
+        let structural2 = registers.read(HCSPARAMS2);
+        let scratchpads = ((structural2 >> 21) & 0x1F) << 5 | structural2 >> 27;
+        let capabilities = registers.read(HCCPARAMS1);
+        let context_size = if capabilities & HCC_CONTEXT_64 != 0 { 64 } else { 32 };
+
+        take_ownership(&registers, ((capabilities >> 16) as usize) << 2);
+
+        let operational = block(cap_length as usize);
+        operational.modify(USBCMD, |command| command & !USBCMD_RUN);
+        wait_for(|| operational.read(USBSTS) & USBSTS_HALTED != 0)?;
+        operational.write(USBCMD, USBCMD_RESET);
+        wait_for(|| {
+            operational.read(USBCMD) & USBCMD_RESET == 0 && operational.read(USBSTS) & USBSTS_NOT_READY == 0
+        })?;
+
+        operational.write(CONFIG, max_slots);
+        let dcbaa = dma_page()?;
+        if scratchpads > 0 {
+            if scratchpads as u64 > PhysicalFrame::SIZE / 8 {
+                return Err(UsbError::Unsupported);
+            }
+            let array = dma_page()?;
+            for index in 0..scratchpads as usize {
+                let page = dma_page()?;
+                write_context(array, index * 2, page as u32);
+                write_context(array, index * 2 + 1, (page >> 32) as u32);
+            }
+            write_context(dcbaa, 0, array as u32);
+            write_context(dcbaa, 1, (array >> 32) as u32);
+        }
+        write64(&operational, DCBAAP, dcbaa);
+
+        let commands = Ring::new()?;
+        write64(&operational, CRCR, commands.dequeue_pointer());
+
+        // One event ring segment, described by a one-entry table
+        let events = EventRing { base: dma_page()?, index: 0, cycle: true };
+        let table = dma_page()?;
+        write_context(table, 0, events.base as u32);
+        write_context(table, 1, (events.base >> 32) as u32);
+        write_context(table, 2, RING_SIZE as u32);
+        let runtime = block((registers.read(RTSOFF) & !0x1F) as usize);
+        runtime.write(ERSTSZ, 1);
+        write64(&runtime, ERDP, events.base);
+        write64(&runtime, ERSTBA, table);
+
+        operational.write(USBCMD, USBCMD_RUN);
+        wait_for(|| operational.read(USBSTS) & USBSTS_HALTED == 0)?;
+
+        crate::serial_println!("xhci {}: {} ports, {} slots", pci.address, max_ports, max_slots);
+        Ok(Controller {
+            pci,
+            operational,
+            runtime,
+            doorbells: block((registers.read(DBOFF) & !0x3) as usize),
+            max_ports,
+            context_size,
+            dcbaa,
+            commands,
+            events,
+            devices: Vec::new(),
+        })
+    }
+
+    /// Offset of a port's PORTSC in the operational registers
+    fn port_register(&self, port: u8) -> usize {
+        PORTSC + (port as usize - 1) * PORT_STRIDE
+    }
+
+    /// Attach whatever is connected to the root ports
+    fn scan_ports(&mut self) {
+        for port in 1..=self.max_ports {
+            if self.operational.read(self.port_register(port)) & PORTSC_CONNECTED == 0 {
+                continue;
+            }
+            let result = self.reset_port(port).and_then(|speed| self.attach(port, speed));
+            if let Err(e) = result {
+                crate::serial_println!("xhci {}: port {}: {}", self.pci.address, port, e);
+            }
+        }
+    }
+
+    /// Reset a USB 2 port, USB 3 ports come up enabled by themselves
+    fn reset_port(&self, port: u8) -> Result<Speed, UsbError> {
+        let (operational, register) = (&self.operational, self.port_register(port));
+        let status = operational.read(register);
+        if status & PORTSC_ENABLED == 0 {
+            operational.write(register, (status & !PORTSC_WRITE_CLEAR) | PORTSC_RESET);
+            wait_for(|| operational.read(register) & PORTSC_RESET_CHANGE != 0)?;
+            let status = operational.read(register);
+            operational.write(register, (status & !PORTSC_WRITE_CLEAR) | PORTSC_RESET_CHANGE);
+            delay_ms(RESET_RECOVERY_MS);
+        }
+        let status = operational.read(register);
+        if status & PORTSC_ENABLED == 0 {
+            return Err(UsbError::Timeout);
+        }
+        Ok(match (status >> 10) & 0xF {
+            1 => Speed::Full,
+            2 => Speed::Low,
+            3 => Speed::High,
+            _ => Speed::Super,
+        })
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        ring_doorbell(&self.doorbells, slot, target);
+    }
+
+    /// Address of context `index` in an input context: 0 is the input
+    /// control context, 1 the slot and 2 on the endpoints
+    fn context(&self, input_context: u64, index: usize) -> u64 {
+        input_context + (index * self.context_size) as u64
+    }
+
+    /// Enable a slot for the device on `port`, address it and bind a driver
+    fn attach(&mut self, port: u8, speed: Speed) -> Result<(), UsbError> {
+        let slot = self.command(Trb::new(TRB_ENABLE_SLOT, 0, 0, 0))?.slot();
+        let output_context = dma_page()?;
+        write_context(self.dcbaa, slot as usize * 2, output_context as u32);
+        write_context(self.dcbaa, slot as usize * 2 + 1, (output_context >> 32) as u32);
+
+        let mut device = Device {
+            slot,
+            port,
+            speed,
+            input_context: dma_page()?,
+            ep0: Ring::new()?,
+            buffer: dma_page()?,
+            descriptor: None,
+            keyboard: None,
+        };
+
+        // Slot and default control endpoint
+        let control = self.context(device.input_context, 0);
+        write_context(control, 1, 1 << 0 | 1 << 1);
+        let slot_context = self.context(device.input_context, 1);
+        write_context(slot_context, 0, speed_id(speed) << 20 | 1 << 27);
+        write_context(slot_context, 1, (port as u32) << 16);
+        let ep0 = self.context(device.input_context, 1 + EP0 as usize);
+        let dequeue = device.ep0.dequeue_pointer();
+        write_context(ep0, 1, EP_ERROR_COUNT << 1 | EP_CONTROL << 3 | (speed.default_max_packet() as u32) << 16);
+        write_context(ep0, 2, dequeue as u32);
+        write_context(ep0, 3, (dequeue >> 32) as u32);
+        write_context(ep0, 4, 8);
+        self.command(Trb::new(TRB_ADDRESS_DEVICE, device.input_context, 0, (slot as u32) << 24))?;
+
+        // Full speed devices may use a larger control packet than assumed
+        self.control(&mut device, SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 8))?;
+        let max_packet = match speed {
+            Speed::Super => 512,
+            _ => device.data(8)[7] as u32,
+        };
+        if max_packet != speed.default_max_packet() as u32 && max_packet != 0 {
+            write_context(control, 1, 1 << EP0);
+            write_context(ep0, 1, EP_ERROR_COUNT << 1 | EP_CONTROL << 3 | max_packet << 16);
+            self.command(Trb::new(TRB_EVALUATE_CONTEXT, device.input_context, 0, (slot as u32) << 24))?;
+        }
+
+        let length = self.control(&mut device, SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 18))?;
+        let descriptor = DeviceDescriptor::parse(device.data(length))?;
+        device.descriptor = Some(descriptor);
+
+        self.control(&mut device, SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, 9))?;
+        let total = u16::from_le_bytes([device.data(9)[2], device.data(9)[3]]).min(REPORT_OFFSET as u16);
+        let length = self.control(&mut device, SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, total))?;
+        let configuration = Configuration::parse(device.data(length))?;
+
+        crate::serial_println!(
+            "usb: port {}: {:04x}:{:04x} class {:02x}, {}",
+            port,
+            descriptor.vendor_id,
+            descriptor.product_id,
+            descriptor.class,
+            speed
+        );
+        let keyboard = configuration.interfaces.iter().find(|interface| hid::is_boot_keyboard(interface));
+        if let Some(interface) = keyboard {
+            let endpoint = interface.endpoints.iter().find(|endpoint| endpoint.is_in() && endpoint.is_interrupt());
+            if let Some(&endpoint) = endpoint {
+                let interface = interface.number;
+                self.control(&mut device, SetupPacket::set_configuration(configuration.value))?;
+                self.start_keyboard(&mut device, interface, endpoint)?;
+            }
+        }
+        self.devices.push(device);
+        Ok(())
+    }
+
+    /// Switch a keyboard to the boot protocol and start its reports
+    fn start_keyboard(&mut self, device: &mut Device, interface: u8, endpoint: super::Endpoint) -> Result<(), UsbError> {
+        let class_request = |request, value| SetupPacket {
+            request_type: REQUEST_TYPE_CLASS | REQUEST_RECIPIENT_INTERFACE,
+            request,
+            value,
+            index: interface as u16,
+            length: 0,
+        };
+        self.control(device, class_request(hid::REQUEST_SET_PROTOCOL, hid::BOOT_PROTOCOL))?;
+        // Reports only on change; keyboards may refuse, which is harmless
+        match self.control(device, class_request(hid::REQUEST_SET_IDLE, 0)) {
+            Ok(_) | Err(UsbError::Stall) => {}
+            Err(e) => return Err(e),
+        }
+
+        let dci = endpoint.number() * 2 + 1;
+        let ring = Ring::new()?;
+        let length = endpoint.max_packet_size.clamp(hid::REPORT_SIZE as u16, MAX_REPORT);
+        let control = self.context(device.input_context, 0);
+        write_context(control, 0, 0);
+        write_context(control, 1, 1 << 0 | 1 << dci);
+        let slot_context = self.context(device.input_context, 1);
+        write_context(slot_context, 0, speed_id(device.speed) << 20 | (dci as u32) << 27);
+        let context = self.context(device.input_context, 1 + dci as usize);
+        let dequeue = ring.dequeue_pointer();
+        write_context(context, 0, interrupt_interval(device.speed, endpoint.interval) << 16);
+        write_context(context, 1, EP_ERROR_COUNT << 1 | EP_INTERRUPT_IN << 3 | (endpoint.max_packet_size as u32) << 16);
+        write_context(context, 2, dequeue as u32);
+        write_context(context, 3, (dequeue >> 32) as u32);
+        write_context(context, 4, (endpoint.max_packet_size as u32) << 16 | hid::REPORT_SIZE as u32);
+        self.command(Trb::new(TRB_CONFIGURE_ENDPOINT, device.input_context, 0, (device.slot as u32) << 24))?;
+
+        let input_device = input::register_device("usbkbd", input::DeviceKind::Keyboard);
+        device.keyboard = Some(KeyboardEndpoint { dci, ring, length, keyboard: hid::Keyboard::new(input_device) });
+        queue_report(&self.doorbells, device);
+        crate::serial_println!("usb: port {}: boot keyboard, input device {}", device.port, input_device.as_u32());
+        Ok(())
+    }
+
+    /// Run a command and wait for its completion
+    fn command(&mut self, trb: Trb) -> Result<Trb, UsbError> {
+        let address = self.commands.push(trb);
+        self.ring_doorbell(0, 0);
+        let event = self.wait_event(|event| event.kind() == TRB_COMMAND_COMPLETION && event.parameter == address)?;
+        match event.completion() {
+            COMPLETION_SUCCESS => Ok(event),
+            code => Err(UsbError::Completion(code)),
+        }
+    }
+
+    /// Run a control transfer on EP0, data goes through the device's buffer
+    ///
+    /// Returns the number of bytes transferred in the data stage.
+    fn control(&mut self, device: &mut Device, setup: SetupPacket) -> Result<usize, UsbError> {
+        let length = setup.length as usize;
+        let mut flags = TRB_IMMEDIATE_DATA;
+        if length > 0 {
+            flags |= if setup.is_in() { TRANSFER_IN } else { TRANSFER_OUT };
+        }
+        device.ep0.push(Trb::new(TRB_SETUP, setup.to_u64(), 8, flags));
+        if length > 0 {
+            let direction = if setup.is_in() { TRB_DIRECTION_IN } else { 0 };
+            device.ep0.push(Trb::new(TRB_DATA, device.buffer, length as u32, direction | TRB_INTERRUPT_ON_SHORT));
+        }
+        // The status stage runs opposite the data, IN when there is none
+        let direction = if length > 0 && setup.is_in() { 0 } else { TRB_DIRECTION_IN };
+        let status = device.ep0.push(Trb::new(TRB_STATUS, 0, 0, direction | TRB_INTERRUPT_ON_COMPLETION));
+        self.ring_doorbell(device.slot, EP0);
+
+        let slot = device.slot;
+        let mut transferred = length;
+        loop {
+            let event = self.wait_event(|event| {
+                event.kind() == TRB_TRANSFER_EVENT && event.slot() == slot && event.endpoint() == EP0
+            })?;
+            match event.completion() {
+                COMPLETION_SHORT_PACKET => transferred = length.saturating_sub(event.residual()),
+                COMPLETION_SUCCESS if event.parameter == status => return Ok(transferred),
+                COMPLETION_SUCCESS => {}
+                code => {
+                    // A halted endpoint must be reset before it takes more transfers
+                    self.command(Trb::endpoint_command(TRB_RESET_ENDPOINT, 0, slot, EP0))?;
+                    let dequeue = device.ep0.dequeue_pointer();
+                    self.command(Trb::endpoint_command(TRB_SET_DEQUEUE, dequeue, slot, EP0))?;
+                    return Err(if code == COMPLETION_STALL { UsbError::Stall } else { UsbError::Completion(code) });
+                }
+            }
+        }
+    }
+
+    /// Take events until one is `wanted`, handling the others on the way
+    fn wait_event(&mut self, mut wanted: impl FnMut(&Trb) -> bool) -> Result<Trb, UsbError> {
+        let deadline = crate::time::uptime_ms() + TIMEOUT_MS;
+        loop {
+            while let Some(event) = self.next_event() {
+                if wanted(&event) {
+                    return Ok(event);
+                }
+                self.handle_event(event);
+            }
+            if crate::time::uptime_ms() >= deadline {
+                return Err(UsbError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn next_event(&mut self) -> Option<Trb> {
+        let event = self.events.pop()?;
+        write64(&self.runtime, ERDP, self.events.dequeue_pointer() | ERDP_BUSY);
+        Some(event)
+    }
+
+    /// Hand keyboard reports to their driver and queue the next
+    ///
+    /// Port status changes are dropped: hotplug is not supported.
+    fn handle_event(&mut self, event: Trb) {
+        if event.kind() != TRB_TRANSFER_EVENT {
+            return;
+        }
+        let doorbells = self.doorbells;
+        let Some(device) = self.devices.iter_mut().find(|device| device.slot == event.slot()) else {
+            return;
+        };
+        let report_buffer = device.report_buffer();
+        let Some(endpoint) = device.keyboard.as_mut().filter(|endpoint| endpoint.dci == event.endpoint()) else {
+            return;
+        };
+        match event.completion() {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => {
+                let length = (endpoint.length as usize).saturating_sub(event.residual());
+                let report = unsafe { core::slice::from_raw_parts(report_buffer as *const u8, length) };
+                endpoint.keyboard.report(report);
+            }
+            // A failed transfer halts the endpoint, the keyboard goes quiet
+            _ => return,
+        }
+        queue_report(&doorbells, device);
+    }
+
+    /// Drain pending events
+    fn poll(&mut self) {
+        while let Some(event) = self.next_event() {
+            self.handle_event(event);
+        }
+    }
+}
+
+impl Device {
+    /// First `length` bytes of the control transfer buffer
+    fn data(&self, length: usize) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.buffer as *const u8, length.min(REPORT_OFFSET as usize)) }
+    }
+}
+
+fn ring_doorbell(doorbells: &Mmio, slot: u8, target: u8) {
+    fence(Ordering::SeqCst);
+    doorbells.write(slot as usize * 4, target as u32);
+}
+
+/// Queue a transfer for the next keyboard report
+fn queue_report(doorbells: &Mmio, device: &mut Device) {
+    let buffer = device.report_buffer();
+    if let Some(endpoint) = device.keyboard.as_mut() {
+        let flags = TRB_INTERRUPT_ON_COMPLETION | TRB_INTERRUPT_ON_SHORT;
+        endpoint.ring.push(Trb::new(TRB_NORMAL, buffer, endpoint.length as u32, flags));
+        ring_doorbell(doorbells, device.slot, endpoint.dci);
+    }
+}
+
+/// Slot context speed, the protocol speed IDs of the default mapping
+fn speed_id(speed: Speed) -> u32 {
+    match speed {
+        Speed::Full => 1,
+        Speed::Low => 2,
+        Speed::High => 3,
+        Speed::Super => 4,
+    }
+}
+
+/// Endpoint context interval, a power of two in 125 us units
+fn interrupt_interval(speed: Speed, interval: u8) -> u32 {
+    match speed {
+        // Full and low speed intervals count 1 ms frames
+        Speed::Low | Speed::Full => (interval.max(1) as u32 * 8).ilog2().clamp(3, 10),
+        Speed::High | Speed::Super => interval.clamp(1, 16) as u32 - 1,
+    }
+}
+
+/// Take the controller from the firmware, which may be driving it for
+/// legacy keyboard emulation
+fn take_ownership(registers: &Mmio, mut offset: usize) {
+    while offset != 0 {
+        let capability = registers.read(offset);
+        if capability & 0xFF == EXT_CAP_LEGACY {
+            // The OS semaphore is the byte above the BIOS one
+            registers.write_as::<u8>(offset + 3, 1);
+            if wait_for(|| registers.read(offset) & LEGACY_BIOS_OWNED == 0).is_err() {
+                crate::serial_println!("xhci: firmware kept ownership, taking over");
+            }
+            registers.write(offset + 4, LEGACY_SMI_STATUS);
+        }
+        let next = ((capability >> 8) & 0xFF) as usize;
+        if next == 0 {
+            break;
+        }
+        offset += next * 4;
+    }
+}
+
+static CONTROLLERS: Mutex<Vec<Controller>> = Mutex::new(Vec::new());
+
+/// Start every XHCI controller and poll them for keyboard reports
+pub fn init() {
+    let mut controllers = Vec::new();
+    for pci in pci::find(pci::CLASS_SERIAL_BUS, pci::SUBCLASS_USB, pci::PROG_IF_XHCI) {
+        match Controller::new(pci) {
+            Ok(mut controller) => {
+                controller.scan_ports();
+                controllers.push(controller);
+            }
+            Err(e) => crate::serial_println!("xhci {}: {}", pci.address, e),
+        }
+    }
+    if controllers.is_empty() {
+        return;
+    }
+    *CONTROLLERS.lock() = controllers;
+    if let Err(e) = crate::task::kworker::spawn("usb/xhci", poll) {
+        crate::serial_println!("xhci: {}, not polling", e);
+    }
+}
+
+/// Worker thread draining every controller's events each `POLL_MS`
+fn poll() {
+    loop {
+        crate::time::sleep_ms(POLL_MS);
+        for controller in CONTROLLERS.lock().iter_mut() {
+            controller.poll();
+        }
+    }
+}
+
+/// Attached devices as (controller, port, vendor, product, speed, driver)
+pub fn devices() -> Vec<(pci::PciAddress, u8, u16, u16, Speed, &'static str)> {
+    let controllers = CONTROLLERS.lock();
+    let mut list = Vec::new();
+    for controller in controllers.iter() {
+        for device in &controller.devices {
+            let (vendor, product) = device.descriptor.map_or((0, 0), |d| (d.vendor_id, d.product_id));
+            let driver = if device.keyboard.is_some() { "hid-keyboard" } else { "none" };
+            list.push((controller.pci.address, device.port, vendor, product, device.speed, driver));
+        }
+    }
+    list
+}
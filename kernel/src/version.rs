@@ -0,0 +1,113 @@
+//! Kernel Version and Build Information
+//!
+//! `build.rs` records the version, git commit, build time, compiler and
+//! enabled features. They are constants here and plain text in the
+//! `.buildinfo` section of the image. The loaders still only see the
+//! packed signature in the kernel header, built from the same version.
+
+use core::fmt::{self, Write};
+use crate::time::rtc::DateTime;
+
+mod build {
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+}
+
+pub use build::{BUILD_TIMESTAMP, FEATURES, GIT_DIRTY, GIT_HASH, PROFILE, RUSTC_VERSION};
+
+/// `key=value` lines from build.rs, kept in the image for host tools
+#[used]
+#[link_section = ".buildinfo"]
+static BUILD_INFO: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/build_info.txt")).len()] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/build_info.txt"));
+
+/// Semantic version of the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Version of the running kernel, from the workspace manifest
+pub const VERSION: Version = Version { major: build::MAJOR, minor: build::MINOR, patch: build::PATCH };
+
+/// Full version string, including any pre-release suffix
+pub const VERSION_STRING: &str = build::VERSION_STRING;
+
+/// Kernel header signature the loaders check, see
+/// `cosmos_bootinfo::kernel_signature`
+pub const SIGNATURE: u64 = cosmos_bootinfo::kernel_signature(VERSION.major, VERSION.minor, VERSION.patch);
+
+/// When the kernel was built, UTC
+pub fn build_time() -> DateTime {
+    DateTime::from_unix(BUILD_TIMESTAMP)
+}
+
+/// Build information as embedded in `.buildinfo`
+pub fn build_info_text() -> &'static str {
+    core::str::from_utf8(&BUILD_INFO).unwrap_or("")
+}
+
+/// One-line banner: `CosmOS 0.0.4 (1a2b3c4d5e6f, 2026-01-01 12:00:00)`
+pub struct Banner;
+
+impl fmt::Display for Banner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CosmOS {} ({}{}, {})", VERSION_STRING, GIT_HASH, if GIT_DIRTY { "-dirty" } else { "" }, build_time())
+    }
+}
+
+/// Print everything known about this build
+pub fn report(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "{}", Banner)?;
+    writeln!(out, "  commit:    {}{}", GIT_HASH, if GIT_DIRTY { " (uncommitted changes)" } else { "" })?;
+    writeln!(out, "  built:     {} UTC", build_time())?;
+    writeln!(out, "  compiler:  {}", RUSTC_VERSION)?;
+    writeln!(out, "  profile:   {}", PROFILE)?;
+    write!(out, "  features: ")?;
+    if FEATURES.is_empty() {
+        write!(out, " none")?;
+    }
+    for feature in FEATURES {
+        write!(out, " {}", feature)?;
+    }
+    writeln!(out)?;
+    writeln!(out, "  signature: {:#018x}", SIGNATURE)
+}
+
+/// The signature decodes to the version and the section matches the constants
+fn selftest() -> Result<(), &'static str> {
+    let header = cosmos_bootinfo::KernelHeader {
+        jump: [0; 8],
+        magic: cosmos_bootinfo::KERNEL_HEADER_MAGIC,
+        signature: SIGNATURE,
+        boot_info_version: cosmos_bootinfo::BOOT_INFO_VERSION,
+        header_size: core::mem::size_of::<cosmos_bootinfo::KernelHeader>() as u32,
+        load_address: 0,
+        memory_size: 0,
+        rela_offset: 0,
+        rela_size: 0,
+    };
+    if !header.is_valid() {
+        return Err("signature magic");
+    }
+    if header.kernel_version() != (VERSION.major, VERSION.minor, VERSION.patch) {
+        return Err("signature version");
+    }
+    let text = build_info_text();
+    if !text.lines().any(|line| line.strip_prefix("version=") == Some(VERSION_STRING)) {
+        return Err(".buildinfo version");
+    }
+    if !text.lines().any(|line| line.strip_prefix("git=").is_some_and(|git| git.starts_with(GIT_HASH))) {
+        return Err(".buildinfo commit");
+    }
+    Ok(())
+}
+
+crate::selftest!("version", selftest);
@@ -1,8 +1,71 @@
 //! VGA text mode driver
+//!
+//! UEFI machines with only a GOP framebuffer have no text buffer at
+//! 0xB8000, and writing there can land in whatever RAM or MMIO happens to
+//! be decoded. `init` checks the boot info and then the VGA registers
+//! themselves, and until it finds text mode the writer stays off and
+//! `print!` goes to COM1 instead.
+//!
+//! The screen is shared by the virtual consoles in [`crate::vt`], which
+//! draw through the primitives here. The hardware cursor follows the
+//! shown console through the CRT controller, so whatever is typed shows
+//! where the next character goes.
 
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
+use crate::arch::x86_64::port::{self, PortRange};
+
+/// VGA register block, 0x3C0-0x3DF
+const PORT_BASE: u16 = 0x3C0;
+const PORT_COUNT: u16 = 0x20;
+
+/// Register offsets from `PORT_BASE`
+const MISC_OUTPUT_READ: u16 = 0x0C;
+const GC_INDEX: u16 = 0x0E;
+const GC_DATA: u16 = 0x0F;
+const CRTC_INDEX: u16 = 0x14;
+const CRTC_DATA: u16 = 0x15;
+
+/// CRT controller cursor registers
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+const CRTC_CURSOR_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOW: u8 = 0x0F;
+
+/// `CRTC_CURSOR_START` bit that hides the cursor
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+/// Underline cursor on the bottom two scanlines of the 16-line font
+const CURSOR_FIRST_SCANLINE: u8 = 14;
+const CURSOR_LAST_SCANLINE: u8 = 15;
+
+/// Graphics controller miscellaneous register, bit 0 set in graphics modes
+const GC_MISC: u8 = 0x06;
+const GC_MISC_GRAPHICS: u8 = 1 << 0;
+
+/// Text mode was found and the buffer may be written
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// VGA registers, claimed once text mode is found
+static PORTS: spin::Once<PortRange> = spin::Once::new();
+
+/// Write CRT controller register `index`
+fn write_crtc(index: u8, value: u8) {
+    if let Some(ports) = PORTS.get() {
+        ports.write(CRTC_INDEX, index);
+        ports.write(CRTC_DATA, value);
+    }
+}
+
+/// Read CRT controller register `index`
+fn read_crtc(index: u8) -> u8 {
+    PORTS.get().map_or(0, |ports| {
+        ports.write(CRTC_INDEX, index);
+        ports.read(CRTC_DATA)
+    })
+}
 
 /// VGA color enumeration
 #[allow(dead_code)]
@@ -48,89 +111,105 @@ struct ScreenChar {
 }
 
 /// VGA text buffer dimensions
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
-/// VGA buffer wrapper
-struct VgaBuffer(*mut u16);
+/// Physical and identity-mapped address of the text buffer
+const BUFFER_ADDRESS: usize = 0xb8000;
+
+/// Characters of code page 437 bytes 0x80 to 0xFF, the text mode font
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}";
+
+/// Byte the text mode font draws as `c`, `None` if it has no glyph for it
+pub fn cp437(c: char) -> Option<u8> {
+    if c.is_ascii() {
+        return Some(c as u8);
+    }
+    CP437_HIGH.chars().position(|glyph| glyph == c).map(|index| 0x80 + index as u8)
+}
+
+/// Draw `cell` at `row` and `column`, if there is a text screen
+pub(crate) fn write_cell(row: usize, column: usize, cell: u16) {
+    if is_present() && row < BUFFER_HEIGHT && column < BUFFER_WIDTH {
+        unsafe {
+            core::ptr::write_volatile((BUFFER_ADDRESS as *mut u16).add(row * BUFFER_WIDTH + column), cell);
+        }
+    }
+}
+
+/// Draw a whole line of cells at `row`
+pub(crate) fn write_line(row: usize, cells: &[u16; BUFFER_WIDTH]) {
+    for (column, &cell) in cells.iter().enumerate() {
+        write_cell(row, column, cell);
+    }
+}
 
-unsafe impl Send for VgaBuffer {}
-unsafe impl Sync for VgaBuffer {}
+/// Move the hardware cursor to `row` and `column`
+///
+/// A row past the bottom puts the cursor off screen, hiding it without
+/// touching its shape.
+pub(crate) fn move_cursor(row: usize, column: usize) {
+    let offset = (row * BUFFER_WIDTH + column) as u16;
+    write_crtc(CRTC_CURSOR_HIGH, (offset >> 8) as u8);
+    write_crtc(CRTC_CURSOR_LOW, offset as u8);
+}
 
-/// Simple VGA text writer
+/// Text writer for one virtual console
+///
+/// The cells and cursor live in [`crate::vt`], which draws them while the
+/// console is shown.
 pub struct Writer {
-    column_position: usize,
-    row_position: usize,
+    console: usize,
     color_code: ColorCode,
-    buffer: VgaBuffer,
 }
 
 impl Writer {
     /// Write a byte
     pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
-                }
-
-                let offset = self.row_position * BUFFER_WIDTH + self.column_position;
-                let color_byte = self.color_code.0 as u16;
-                let char_with_color = (color_byte << 8) | byte as u16;
-                
-                unsafe {
-                    *self.buffer.0.add(offset) = char_with_color;
-                }
-                
-                self.column_position += 1;
-            }
-        }
+        crate::vt::write(self.console, &[byte], self.color_code.0);
     }
 
     /// Write a string to the current position
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // Printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // Not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+        let mut chunk = [0u8; 64];
+        for piece in s.as_bytes().chunks(chunk.len()) {
+            for (out, &byte) in chunk.iter_mut().zip(piece) {
+                *out = match byte {
+                    // Printable ASCII byte, newline or backspace
+                    0x20..=0x7e | b'\n' | 0x08 => byte,
+                    // Not part of printable ASCII range
+                    _ => 0xfe,
+                };
             }
+            crate::vt::write(self.console, &chunk[..piece.len()], self.color_code.0);
         }
     }
 
-    /// Move to new line
-    fn new_line(&mut self) {
-        self.column_position = 0;
-        if self.row_position < BUFFER_HEIGHT - 1 {
-            self.row_position += 1;
-        } else {
-            self.scroll_up();
-        }
+    /// Current row and column
+    pub fn position(&self) -> (usize, usize) {
+        crate::vt::position(self.console)
     }
 
-    /// Scroll the screen up by one line
-    fn scroll_up(&mut self) {
-        unsafe {
-            // Move all lines up by one
-            for row in 1..BUFFER_HEIGHT {
-                let src_offset = row * BUFFER_WIDTH;
-                let dst_offset = (row - 1) * BUFFER_WIDTH;
-                
-                for col in 0..BUFFER_WIDTH {
-                    let src_val = *self.buffer.0.add(src_offset + col);
-                    *self.buffer.0.add(dst_offset + col) = src_val;
-                }
-            }
-            
-            // Clear the last line
-            let blank_char = (self.color_code.0 as u16) << 8 | b' ' as u16;
-            let last_line_offset = (BUFFER_HEIGHT - 1) * BUFFER_WIDTH;
-            for col in 0..BUFFER_WIDTH {
-                *self.buffer.0.add(last_line_offset + col) = blank_char;
-            }
-        }
+    /// Move to `row` and `column`, clamped to the screen
+    pub fn set_position(&mut self, row: usize, column: usize) {
+        crate::vt::set_position(self.console, row, column);
+    }
+
+    /// Blank the screen in the current colors and go to the top left
+    pub fn clear_screen(&mut self) {
+        crate::vt::clear(self.console, self.color_code.0);
+    }
+
+    /// Show the hardware cursor as an underline
+    pub fn show_cursor(&mut self) {
+        // Bits 6-7 of both registers are reserved and kept
+        write_crtc(CRTC_CURSOR_START, read_crtc(CRTC_CURSOR_START) & 0xC0 | CURSOR_FIRST_SCANLINE);
+        write_crtc(CRTC_CURSOR_END, read_crtc(CRTC_CURSOR_END) & 0xE0 | CURSOR_LAST_SCANLINE);
+    }
+
+    /// Hide the hardware cursor
+    pub fn hide_cursor(&mut self) {
+        write_crtc(CRTC_CURSOR_START, read_crtc(CRTC_CURSOR_START) | CURSOR_DISABLE);
     }
 }
 
@@ -142,19 +221,70 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
+    /// Writer for `print!`, on the first virtual console
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        row_position: 0,
+        console: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: VgaBuffer(0xb8000 as *mut u16),
     });
 }
 
-/// Internal print function
+/// Whether the screen is in VGA text mode
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Acquire)
+}
+
+/// Check that the VGA registers answer and are set for text mode
+///
+/// Ports nothing decodes read back 0xFF, so a missing adapter fails the
+/// first check. The graphics controller index is restored afterwards.
+fn probe(ports: &PortRange) -> bool {
+    if ports.read::<u8>(MISC_OUTPUT_READ) == 0xFF {
+        return false;
+    }
+    let index = ports.read::<u8>(GC_INDEX);
+    ports.write(GC_INDEX, GC_MISC);
+    let misc = ports.read::<u8>(GC_DATA);
+    ports.write(GC_INDEX, index);
+    misc != 0xFF && misc & GC_MISC_GRAPHICS == 0
+}
+
+/// Turn the writer on if the screen is in text mode
+///
+/// A loader that reports no VGA text is believed without touching the
+/// hardware. Otherwise the registers have the final say, since loaders
+/// that do not report consoles are only assumed to leave text mode.
+pub fn init() {
+    if !crate::boot::has_vga_text() {
+        crate::serial_println!("vga: no text mode according to the loader, output on serial only");
+        return;
+    }
+    let ports = match port::claim(PORT_BASE, PORT_COUNT, "vga") {
+        Ok(ports) => ports,
+        Err(e) => {
+            crate::serial_println!("vga: {}", e);
+            return;
+        }
+    };
+    if probe(&ports) {
+        PORTS.call_once(|| ports);
+        PRESENT.store(true, Ordering::Release);
+        WRITER.lock().show_cursor();
+        crate::vt::redraw();
+    } else {
+        port::release(ports);
+        crate::serial_println!("vga: registers do not show text mode, output on serial only");
+    }
+}
+
+/// Internal print function, COM1 when there is no text mode
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+    if is_present() {
+        WRITER.lock().write_fmt(args).unwrap();
+    } else {
+        crate::serial::_print(args);
+    }
 }
 
 /// Print macro for formatted output
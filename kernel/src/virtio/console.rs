@@ -0,0 +1,119 @@
+//! Virtio Console
+//!
+//! Port 0 of a virtio-serial device, `hvc0`, attached in QEMU with
+//! `-device virtio-serial-pci -device virtconsole,chardev=<id>`. Output
+//! collects in a page and goes to the device one `print` at a time, so a
+//! log line costs a single notification.
+
+use core::fmt;
+use spin::Mutex;
+use super::{Buffer, LegacyDevice, Virtqueue, VirtioError};
+use crate::console::{self, Sink};
+use crate::mm::{frame_allocator, PhysicalFrame};
+
+/// Transitional virtio console PCI device
+const DEVICE_ID_LEGACY: u16 = 0x1003;
+/// Modern-only virtio console PCI device
+const DEVICE_ID_MODERN: u16 = 0x1043;
+/// Port 0 transmit queue, receive is queue 0
+const TRANSMIT_QUEUE: u16 = 1;
+/// Polls for the device to consume a buffer
+const FLUSH_POLLS: usize = 1_000_000;
+
+struct VirtioConsole {
+    device: LegacyDevice,
+    transmit: Virtqueue,
+    buffer: u64,
+    len: usize,
+    /// The device stopped consuming output, drop it from here on
+    broken: bool,
+}
+
+impl VirtioConsole {
+    fn write_bytes(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() && !self.broken {
+            let count = bytes.len().min(PhysicalFrame::SIZE as usize - self.len);
+            unsafe {
+                core::ptr::copy_nonoverlapping(bytes.as_ptr(), (self.buffer as *mut u8).add(self.len), count);
+            }
+            self.len += count;
+            bytes = &bytes[count..];
+            if self.len == PhysicalFrame::SIZE as usize {
+                self.flush();
+            }
+        }
+    }
+
+    /// Hand the buffered output to the device and wait until it is consumed
+    fn flush(&mut self) {
+        if self.len == 0 || self.broken {
+            return;
+        }
+        let buffer = Buffer { address: self.buffer, length: self.len as u32, writable: false };
+        self.len = 0;
+        if self.transmit.push(&[buffer]).is_err() {
+            self.broken = true;
+            return;
+        }
+        self.device.notify(&self.transmit);
+        if !(0..FLUSH_POLLS).any(|_| self.transmit.pop_used().is_some()) {
+            self.broken = true;
+        }
+    }
+}
+
+impl fmt::Write for VirtioConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+static CONSOLE: Mutex<Option<VirtioConsole>> = Mutex::new(None);
+
+/// Find a virtio console and make it a console sink
+pub fn init() {
+    let Some(pci) = crate::pci::devices()
+        .into_iter()
+        .find(|device| device.vendor_id == super::VENDOR_ID && matches!(device.device_id, DEVICE_ID_LEGACY | DEVICE_ID_MODERN))
+    else {
+        return;
+    };
+    let address = pci.address;
+    match probe(pci) {
+        Ok(console) => {
+            *CONSOLE.lock() = Some(console);
+            console::attach(Sink::Virtio);
+            crate::serial_println!("virtio console {}: hvc0", address);
+        }
+        Err(e) => crate::serial_println!("virtio console {}: {}", address, e),
+    }
+}
+
+fn probe(pci: crate::pci::PciDevice) -> Result<VirtioConsole, VirtioError> {
+    // No features: without MULTIPORT only port 0 exists
+    let (mut device, _) = LegacyDevice::new(pci, 0)?;
+    let queues = device.setup_queue(TRANSMIT_QUEUE).and_then(|transmit| {
+        let buffer = frame_allocator::allocate_frame().map_err(|_| VirtioError::NoMemory)?;
+        Ok((transmit, buffer.start_address().as_u64()))
+    });
+    let (transmit, buffer) = match queues {
+        Ok(queues) => queues,
+        Err(e) => {
+            device.fail();
+            return Err(e);
+        }
+    };
+    device.driver_ok();
+    Ok(VirtioConsole { device, transmit, buffer, len: 0, broken: false })
+}
+
+/// Write to hvc0 and flush, dropped if there is none
+pub fn write_fmt(args: fmt::Arguments) {
+    use fmt::Write;
+
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        let _ = console.write_fmt(args);
+        console.flush();
+    }
+}
@@ -0,0 +1,273 @@
+//! Virtio
+//!
+//! The legacy PCI transport, which QEMU's transitional devices still offer
+//! next to the modern one: a single I/O BAR holds the common registers and
+//! each queue is one physically contiguous block, its page number written
+//! to `QUEUE_PFN`. Queues are polled, their interrupts suppressed.
+
+pub mod console;
+
+use core::fmt;
+use core::sync::atomic::{fence, Ordering};
+use crate::arch::x86_64::port::{self, PortError, PortRange};
+use crate::mm::{frame_allocator, PhysicalFrame};
+use crate::pci::{self, Bar, PciDevice};
+
+/// PCI vendor of every virtio device
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+// Legacy register offsets in BAR0
+const REG_HOST_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// Descriptor flags: chained to `next`, written by the device
+const DESC_NEXT: u16 = 1 << 0;
+const DESC_WRITE: u16 = 1 << 1;
+/// Available ring flag: no interrupt when buffers are used
+const AVAIL_NO_INTERRUPT: u16 = 1 << 0;
+/// Legacy queue alignment of the used ring
+const QUEUE_ALIGN: usize = 4096;
+
+/// Errors that can occur driving a virtio device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// No legacy I/O BAR, the device is modern-only
+    NotLegacy,
+    /// The queue does not exist
+    NoQueue,
+    /// No memory for queues or buffers
+    NoMemory,
+    /// More buffers than free descriptors
+    QueueFull,
+    /// The device did not use a buffer in time
+    Timeout,
+    /// The I/O BAR could not be claimed
+    Ports(PortError),
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VirtioError::NotLegacy => write!(f, "No legacy virtio interface"),
+            VirtioError::NoQueue => write!(f, "Virtqueue not present"),
+            VirtioError::NoMemory => write!(f, "Out of memory for virtqueues"),
+            VirtioError::QueueFull => write!(f, "Virtqueue full"),
+            VirtioError::Timeout => write!(f, "Virtio device timed out"),
+            VirtioError::Ports(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Device on the legacy PCI transport
+pub struct LegacyDevice {
+    pub pci: PciDevice,
+    io: PortRange,
+}
+
+impl LegacyDevice {
+    /// Reset the device and negotiate features, keeping those in `wanted`
+    pub fn new(pci: PciDevice, wanted: u32) -> Result<(Self, u32), VirtioError> {
+        let Some(Bar::Io { port, size }) = pci.bar(0) else {
+            return Err(VirtioError::NotLegacy);
+        };
+        let io = port::claim(port, size as u16, "virtio").map_err(VirtioError::Ports)?;
+        pci.enable(pci::COMMAND_IO | pci::COMMAND_BUS_MASTER);
+        let mut device = LegacyDevice { pci, io };
+        device.write8(REG_STATUS, 0);
+        device.write8(REG_STATUS, STATUS_ACKNOWLEDGE);
+        device.write8(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let features = device.read32(REG_HOST_FEATURES) & wanted;
+        device.write32(REG_GUEST_FEATURES, features);
+        Ok((device, features))
+    }
+
+    /// Allocate queue `index` at the size the device asks for
+    pub fn setup_queue(&mut self, index: u16) -> Result<Virtqueue, VirtioError> {
+        self.write16(REG_QUEUE_SELECT, index);
+        let size = self.read16(REG_QUEUE_SIZE);
+        if size == 0 {
+            return Err(VirtioError::NoQueue);
+        }
+        let queue = Virtqueue::new(index, size)?;
+        self.write32(REG_QUEUE_PFN, (queue.base / PhysicalFrame::SIZE) as u32);
+        Ok(queue)
+    }
+
+    /// Finish initialization, the device may use its queues from here on
+    pub fn driver_ok(&mut self) {
+        let status = self.read8(REG_STATUS);
+        self.write8(REG_STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    /// Give up on the device
+    pub fn fail(&mut self) {
+        let status = self.read8(REG_STATUS);
+        self.write8(REG_STATUS, status | STATUS_FAILED);
+    }
+
+    /// Tell the device a queue has new buffers
+    pub fn notify(&mut self, queue: &Virtqueue) {
+        self.write16(REG_QUEUE_NOTIFY, queue.index);
+    }
+
+    fn read8(&self, register: u16) -> u8 {
+        self.io.read(register)
+    }
+
+    fn read16(&self, register: u16) -> u16 {
+        self.io.read(register)
+    }
+
+    fn read32(&self, register: u16) -> u32 {
+        self.io.read(register)
+    }
+
+    fn write8(&mut self, register: u16, value: u8) {
+        self.io.write(register, value)
+    }
+
+    fn write16(&mut self, register: u16, value: u16) {
+        self.io.write(register, value)
+    }
+
+    fn write32(&mut self, register: u16, value: u32) {
+        self.io.write(register, value)
+    }
+}
+
+impl Drop for LegacyDevice {
+    fn drop(&mut self) {
+        port::release(self.io);
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Buffer handed to the device
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: u64,
+    pub length: u32,
+    /// The device writes the buffer rather than reading it
+    pub writable: bool,
+}
+
+/// Split virtqueue in the legacy layout: descriptors, then the available
+/// ring, then the used ring on the next page
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    base: u64,
+    used_offset: usize,
+    free_head: u16,
+    free_count: u16,
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16) -> Result<Self, VirtioError> {
+        let count = size as usize;
+        let used_offset = (16 * count + 6 + 2 * count).next_multiple_of(QUEUE_ALIGN);
+        let bytes = used_offset + (6 + 8 * count).next_multiple_of(QUEUE_ALIGN);
+        let pages = (bytes as u64).div_ceil(PhysicalFrame::SIZE);
+        let base = frame_allocator::allocate_contiguous_frames(pages)
+            .map_err(|_| VirtioError::NoMemory)?
+            .start_address()
+            .as_u64();
+        unsafe { core::ptr::write_bytes(base as *mut u8, 0, bytes) };
+
+        let mut queue = Virtqueue { index, size, base, used_offset, free_head: 0, free_count: size, last_used: 0 };
+        for i in 0..size {
+            queue.descriptor(i).next = i.wrapping_add(1);
+        }
+        queue.set_avail(0, AVAIL_NO_INTERRUPT);
+        Ok(queue)
+    }
+
+    fn descriptor(&mut self, index: u16) -> &mut Descriptor {
+        unsafe { &mut *(self.base as *mut Descriptor).add(index as usize) }
+    }
+
+    /// Write the available ring's u16 at `slot`: 0 flags, 1 index, 2.. ring
+    fn set_avail(&mut self, slot: usize, value: u16) {
+        let avail = (self.base as usize + 16 * self.size as usize) as *mut u16;
+        unsafe { avail.add(slot).write_volatile(value) }
+    }
+
+    fn avail_index(&self) -> u16 {
+        let avail = (self.base as usize + 16 * self.size as usize) as *const u16;
+        unsafe { avail.add(1).read_volatile() }
+    }
+
+    /// Offer a chain of buffers, returns its head descriptor
+    pub fn push(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return Err(VirtioError::QueueFull);
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let last = i + 1 == buffers.len();
+            let descriptor = self.descriptor(index);
+            descriptor.address = buffer.address;
+            descriptor.length = buffer.length;
+            descriptor.flags = if buffer.writable { DESC_WRITE } else { 0 } | if last { 0 } else { DESC_NEXT };
+            let next = descriptor.next;
+            if last {
+                self.free_head = next;
+            } else {
+                index = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let avail = self.avail_index();
+        self.set_avail(2 + (avail % self.size) as usize, head);
+        // The device must see the ring entry before the new index
+        fence(Ordering::Release);
+        self.set_avail(1, avail.wrapping_add(1));
+        Ok(head)
+    }
+
+    /// Take the next used chain as `(head, bytes written)`, freeing it
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = (self.base as usize + self.used_offset) as *const u16;
+        if unsafe { used.add(1).read_volatile() } == self.last_used {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let element = unsafe { (used.add(2) as *const u32).add(2 * (self.last_used % self.size) as usize) };
+        let (head, written) = unsafe { (element.read_volatile() as u16, element.add(1).read_volatile()) };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Return the chain to the free list
+        let mut tail = head;
+        let mut count = 1;
+        while self.descriptor(tail).flags & DESC_NEXT != 0 {
+            tail = self.descriptor(tail).next;
+            count += 1;
+        }
+        let free_head = self.free_head;
+        self.descriptor(tail).next = free_head;
+        self.free_head = head;
+        self.free_count += count;
+        Some((head, written))
+    }
+}
@@ -0,0 +1,290 @@
+//! Virtual Consoles
+//!
+//! The VGA text screen is shared by `COUNT` consoles. Each keeps its own
+//! screen, cursor and a scrollback of the lines that scrolled off the
+//! top. Only the active console is drawn, the others are updated in
+//! memory and redrawn when Alt+F1..F4 switches to them. Shift+PageUp and
+//! Shift+PageDown page through the active console's scrollback, and new
+//! output snaps back to the bottom.
+//!
+//! The first console carries `print!` and the boot messages. Each console
+//! also has a terminal, `tty1` to `tty4`, which the keyboard types into
+//! while it is shown.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::vga::{self, BUFFER_HEIGHT as HEIGHT, BUFFER_WIDTH as WIDTH};
+
+/// Number of virtual consoles
+pub const COUNT: usize = 4;
+/// Lines each console keeps after they scroll off the screen
+pub const SCROLLBACK_LINES: usize = 100;
+/// Light grey on black, what terminals write in
+pub const DEFAULT_ATTRIBUTE: u8 = 0x07;
+
+const BACKSPACE: u8 = 0x08;
+
+type Line = [u16; WIDTH];
+
+const fn blank(attribute: u8) -> u16 {
+    (attribute as u16) << 8 | b' ' as u16
+}
+
+/// Errors that can occur switching consoles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VtError {
+    /// No console with that number
+    NoSuchConsole,
+}
+
+impl fmt::Display for VtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VtError::NoSuchConsole => write!(f, "No such console, there are {}", COUNT),
+        }
+    }
+}
+
+/// One console's text and cursor
+///
+/// Cells start out zero, which VGA shows as blank, so the consoles take
+/// no room in the kernel image.
+struct Console {
+    screen: [Line; HEIGHT],
+    scrollback: [Line; SCROLLBACK_LINES],
+    /// Slot the next line scrolled off the screen goes to
+    scrollback_next: usize,
+    scrollback_len: usize,
+    row: usize,
+    column: usize,
+    /// Lines scrolled back from the bottom, 0 while following output
+    view: usize,
+}
+
+impl Console {
+    const fn new() -> Self {
+        Console {
+            screen: [[0; WIDTH]; HEIGHT],
+            scrollback: [[0; WIDTH]; SCROLLBACK_LINES],
+            scrollback_next: 0,
+            scrollback_len: 0,
+            row: 0,
+            column: 0,
+            view: 0,
+        }
+    }
+
+    /// Line `index`, counted from the oldest line of scrollback
+    fn line(&self, index: usize) -> &Line {
+        if index < self.scrollback_len {
+            let oldest = (self.scrollback_next + SCROLLBACK_LINES - self.scrollback_len) % SCROLLBACK_LINES;
+            &self.scrollback[(oldest + index) % SCROLLBACK_LINES]
+        } else {
+            &self.screen[index - self.scrollback_len]
+        }
+    }
+
+    /// Draw every line and the cursor
+    fn draw(&self) {
+        let top = self.scrollback_len - self.view;
+        for row in 0..HEIGHT {
+            vga::write_line(row, self.line(top + row));
+        }
+        self.draw_cursor();
+    }
+
+    fn draw_cursor(&self) {
+        if self.view == 0 {
+            // Past the end of a full line the next character wraps from here
+            vga::move_cursor(self.row, self.column.min(WIDTH - 1));
+        } else {
+            // Off screen while looking back
+            vga::move_cursor(HEIGHT, 0);
+        }
+    }
+
+    /// Write one byte, drawing it too if the console is `shown`
+    fn put(&mut self, byte: u8, attribute: u8, shown: bool) {
+        match byte {
+            b'\n' => self.new_line(attribute, shown),
+            BACKSPACE => self.column = self.column.saturating_sub(1),
+            byte => {
+                if self.column >= WIDTH {
+                    self.new_line(attribute, shown);
+                }
+                let cell = (attribute as u16) << 8 | byte as u16;
+                self.screen[self.row][self.column] = cell;
+                if shown {
+                    vga::write_cell(self.row, self.column, cell);
+                }
+                self.column += 1;
+            }
+        }
+    }
+
+    /// Move to the start of the next line, scrolling at the bottom
+    fn new_line(&mut self, attribute: u8, shown: bool) {
+        self.column = 0;
+        if self.row < HEIGHT - 1 {
+            self.row += 1;
+            return;
+        }
+        self.scrollback[self.scrollback_next] = self.screen[0];
+        self.scrollback_next = (self.scrollback_next + 1) % SCROLLBACK_LINES;
+        self.scrollback_len = (self.scrollback_len + 1).min(SCROLLBACK_LINES);
+        self.screen.copy_within(1.., 0);
+        self.screen[HEIGHT - 1] = [blank(attribute); WIDTH];
+        if shown {
+            self.draw();
+        }
+    }
+
+    /// Blank the screen and go to the top left, scrollback is kept
+    fn clear(&mut self, attribute: u8) {
+        self.screen = [[blank(attribute); WIDTH]; HEIGHT];
+        self.row = 0;
+        self.column = 0;
+        self.view = 0;
+    }
+
+    /// Look `lines` further back, or forward if negative
+    fn scroll(&mut self, lines: isize) {
+        self.view = self.view.saturating_add_signed(lines).min(self.scrollback_len);
+    }
+}
+
+/// All consoles, behind a plain spinlock so they stay in .bss
+static CONSOLES: Mutex<[Console; COUNT]> = Mutex::new([const { Console::new() }; COUNT]);
+
+/// Console on the screen
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Run `f` on the consoles with interrupts off, terminals echo from them
+fn with_consoles<R>(f: impl FnOnce(&mut [Console; COUNT], usize) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut CONSOLES.lock(), active()))
+}
+
+/// Index of the console on the screen
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Write bytes to `console` in VGA `attribute` colors
+///
+/// Newline and backspace move the cursor, everything else is drawn as a
+/// character.
+pub fn write(console: usize, bytes: &[u8], attribute: u8) {
+    with_consoles(|consoles, active| {
+        let shown = console == active;
+        let target = &mut consoles[console];
+        if target.view != 0 {
+            target.view = 0;
+            if shown {
+                target.draw();
+            }
+        }
+        for &byte in bytes {
+            target.put(byte, attribute, shown);
+        }
+        if shown {
+            target.draw_cursor();
+        }
+    })
+}
+
+/// Row and column of `console`'s cursor
+pub fn position(console: usize) -> (usize, usize) {
+    with_consoles(|consoles, _| (consoles[console].row, consoles[console].column))
+}
+
+/// Move `console`'s cursor, clamped to the screen
+pub fn set_position(console: usize, row: usize, column: usize) {
+    with_consoles(|consoles, active| {
+        let target = &mut consoles[console];
+        target.row = row.min(HEIGHT - 1);
+        target.column = column.min(WIDTH - 1);
+        if console == active {
+            target.draw_cursor();
+        }
+    })
+}
+
+/// Blank `console` in `attribute` colors
+pub fn clear(console: usize, attribute: u8) {
+    with_consoles(|consoles, active| {
+        consoles[console].clear(attribute);
+        if console == active {
+            consoles[console].draw();
+        }
+    })
+}
+
+/// Show `console`
+pub fn switch(console: usize) -> Result<(), VtError> {
+    if console >= COUNT {
+        return Err(VtError::NoSuchConsole);
+    }
+    with_consoles(|consoles, _| {
+        ACTIVE.store(console, Ordering::Relaxed);
+        consoles[console].draw();
+    });
+    Ok(())
+}
+
+/// Page the active console `lines` back into scrollback, forward if
+/// negative
+pub fn scroll(lines: isize) {
+    with_consoles(|consoles, active| {
+        consoles[active].scroll(lines);
+        consoles[active].draw();
+    })
+}
+
+/// Draw the active console again, once the screen is known to be there
+pub fn redraw() {
+    with_consoles(|consoles, active| consoles[active].draw())
+}
+
+/// Print each console, its cursor and scrollback
+pub fn report(out: &mut dyn Write) -> fmt::Result {
+    let consoles: [(usize, usize, usize); COUNT] = with_consoles(|consoles, _| {
+        core::array::from_fn(|i| (consoles[i].row, consoles[i].column, consoles[i].scrollback_len))
+    });
+    let active = active();
+    for (i, (row, column, scrollback)) in consoles.into_iter().enumerate() {
+        writeln!(out, "{} tty{}  cursor {},{}  {} lines of scrollback",
+            if i == active { '*' } else { ' ' }, i + 1, row, column, scrollback)?;
+    }
+    Ok(())
+}
+
+/// Scrolling keeps lines in order and the view stays inside scrollback
+fn selftest() -> Result<(), &'static str> {
+    // Far too big for a task stack, and zero is a valid empty console
+    let mut console = unsafe { alloc::boxed::Box::<Console>::new_zeroed().assume_init() };
+    for line in 0..HEIGHT + 3 {
+        console.put(b'a' + line as u8, DEFAULT_ATTRIBUTE, false);
+        console.put(b'\n', DEFAULT_ATTRIBUTE, false);
+    }
+    if console.scrollback_len != 4 || console.row != HEIGHT - 1 {
+        return Err("lines did not scroll off");
+    }
+    if console.line(0)[0] as u8 != b'a' || console.line(4)[0] as u8 != b'e' {
+        return Err("scrollback out of order");
+    }
+    console.scroll(10);
+    if console.view != 4 {
+        return Err("view scrolled past scrollback");
+    }
+    console.scroll(-10);
+    console.put(BACKSPACE, DEFAULT_ATTRIBUTE, false);
+    console.clear(DEFAULT_ATTRIBUTE);
+    if console.view != 0 || console.row != 0 || console.screen[HEIGHT - 1][0] != blank(DEFAULT_ATTRIBUTE) {
+        return Err("clear left text behind");
+    }
+    Ok(())
+}
+
+crate::selftest!("vt", selftest);
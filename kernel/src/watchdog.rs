@@ -0,0 +1,120 @@
+//! Software watchdog for detecting kernel hangs
+//!
+//! The scheduler pets the watchdog each time it runs; the timer interrupt
+//! panics with diagnostics if no pet arrives within the timeout. The check
+//! stays in the interrupt rather than a software timer, since timer
+//! callbacks run on a task that a hang would starve.
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::time;
+
+/// Default hang timeout in seconds
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_SECS * time::TICK_HZ);
+static LAST_PET: AtomicU64 = AtomicU64::new(0);
+
+/// Name of the long-running operation in progress, for the hang report
+static OPERATION_PTR: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+static OPERATION_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Arm the watchdog with a timeout in seconds
+pub fn init(timeout_secs: u64) {
+    set_timeout(timeout_secs);
+    enable();
+    crate::serial_println!("Watchdog armed with {}s timeout", timeout_secs);
+}
+
+/// Start checking, counting from now
+pub fn enable() {
+    pet();
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop checking
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+/// Check if the watchdog is armed
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Change the hang timeout
+pub fn set_timeout(timeout_secs: u64) {
+    TIMEOUT_TICKS.store(time::ms_to_ticks(timeout_secs.max(1) * 1000), Ordering::Relaxed);
+}
+
+/// Report that the kernel is making progress
+pub fn pet() {
+    LAST_PET.store(time::ticks(), Ordering::Relaxed);
+}
+
+/// A named long-running operation, which should `pet` as it makes progress
+pub struct Operation {
+    previous: (*mut u8, usize),
+}
+
+/// Start a long-running operation, named in the hang report until dropped
+pub fn begin(name: &'static str) -> Operation {
+    let previous = (
+        OPERATION_PTR.swap(name.as_ptr() as *mut u8, Ordering::Relaxed),
+        OPERATION_LEN.swap(name.len(), Ordering::Relaxed),
+    );
+    pet();
+    Operation { previous }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        OPERATION_PTR.store(self.previous.0, Ordering::Relaxed);
+        OPERATION_LEN.store(self.previous.1, Ordering::Relaxed);
+        pet();
+    }
+}
+
+fn operation() -> Option<&'static str> {
+    let ptr = OPERATION_PTR.load(Ordering::Relaxed);
+    if ptr.is_null() {
+        return None;
+    }
+    let len = OPERATION_LEN.load(Ordering::Relaxed);
+    // Only ever set from a &'static str
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+    core::str::from_utf8(bytes).ok()
+}
+
+/// Timer tick hook, panics if the kernel has not checked in
+pub fn check(now: u64, frame: &InterruptStackFrame) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let elapsed = now.saturating_sub(LAST_PET.load(Ordering::Relaxed));
+    if elapsed < TIMEOUT_TICKS.load(Ordering::Relaxed) {
+        return;
+    }
+    ENABLED.store(false, Ordering::Release);
+
+    // The hung code may hold the serial lock, and we never return to it
+    unsafe { crate::serial::force_unlock() };
+
+    let elapsed_ms = time::ticks_to_ms(elapsed);
+    crate::serial_println!("[WATCHDOG] No check-in for {} ms", elapsed_ms);
+    crate::serial_println!(
+        "Interrupted at RIP {:#x}, RSP {:#x}",
+        frame.instruction_pointer.as_u64(),
+        frame.stack_pointer.as_u64()
+    );
+    if let Some(name) = operation() {
+        crate::serial_println!("Operation in progress: {}", name);
+    }
+    match crate::task::scheduler::try_current_id() {
+        Some(Some(id)) => crate::serial_println!("Running task: {:?}", id),
+        Some(None) => crate::serial_println!("Running task: none, tasking not started"),
+        None => crate::serial_println!("Running task: unknown, scheduler lock held"),
+    }
+    panic!("watchdog: kernel hung for {} ms", elapsed_ms);
+}
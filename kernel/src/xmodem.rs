@@ -0,0 +1,281 @@
+//! XMODEM Receiver
+//!
+//! Takes a file over a byte link with XMODEM, in 128-byte blocks or
+//! XMODEM-1K's 1024-byte ones, checked by CRC-16 or by the original
+//! additive checksum when the sender knows no better. Any terminal program
+//! can send that way (`sx`, minicom, Tera Term, PuTTY with lrzsz), so files
+//! can be pushed onto a running machine over the serial console.
+//!
+//! XMODEM has no file length: the last block is padded with SUB bytes,
+//! which `trim_padding` takes off again. A sender cancels with two CAN
+//! bytes, Ctrl-X Ctrl-X from a terminal.
+
+use alloc::vec::Vec;
+use core::fmt;
+use crate::console::{self, Sink};
+use crate::crypto::crc16;
+use crate::sync::CancelToken;
+use crate::task;
+use crate::tty::{self, Settings, TtyError};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1A;
+/// Sent instead of NAK to ask for CRC-16 blocks
+const CRC_REQUEST: u8 = b'C';
+
+/// Times the receiver asks for the first block before giving up
+const START_TRIES: u32 = 20;
+/// Of those, the tries asking for CRC-16 before settling for checksums
+const CRC_TRIES: u32 = 10;
+const START_TIMEOUT_MS: u64 = 3000;
+/// Wait for the next block after an answer
+const BLOCK_TIMEOUT_MS: u64 = 10_000;
+/// Wait for each byte within a block
+const BYTE_TIMEOUT_MS: u64 = 1000;
+/// Bad blocks in a row before the transfer is abandoned
+const MAX_ERRORS: u32 = 10;
+
+/// Errors that can end a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmodemError {
+    /// Nothing was sent in answer to the start requests
+    NoSender,
+    /// The sender cancelled
+    Cancelled,
+    /// A block arrived that was neither the next one nor a repeat
+    OutOfSequence,
+    /// Too many bad or missing blocks in a row
+    TooManyErrors,
+    /// The file is larger than the receiver takes
+    TooLarge,
+}
+
+impl fmt::Display for XmodemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XmodemError::NoSender => write!(f, "No XMODEM sender"),
+            XmodemError::Cancelled => write!(f, "Transfer cancelled by the sender"),
+            XmodemError::OutOfSequence => write!(f, "XMODEM block out of sequence"),
+            XmodemError::TooManyErrors => write!(f, "Too many XMODEM errors"),
+            XmodemError::TooLarge => write!(f, "File too large"),
+        }
+    }
+}
+
+/// Byte link a transfer runs over
+pub trait Link {
+    /// Next byte, `None` if nothing arrives within `timeout_ms`
+    fn read_byte(&mut self, timeout_ms: u64) -> Option<u8>;
+    fn write_byte(&mut self, byte: u8);
+}
+
+/// Receive a file of at most `limit` bytes, padding included
+pub fn receive(link: &mut dyn Link, limit: usize) -> Result<Vec<u8>, XmodemError> {
+    let (mut header, crc) = start(link)?;
+    let mut data = Vec::new();
+    let mut block = [0u8; 1024];
+    let mut expected: u8 = 1;
+    let mut errors = 0;
+    loop {
+        let reply = match header {
+            Some(EOT) => {
+                link.write_byte(ACK);
+                return Ok(data);
+            }
+            Some(CAN) if link.read_byte(BYTE_TIMEOUT_MS) == Some(CAN) => return Err(XmodemError::Cancelled),
+            Some(kind @ (SOH | STX)) => {
+                let block = &mut block[..if kind == SOH { 128 } else { 1024 }];
+                match read_block(link, block, crc) {
+                    Some(number) if number == expected => {
+                        if data.len() + block.len() > limit {
+                            cancel(link);
+                            return Err(XmodemError::TooLarge);
+                        }
+                        data.extend_from_slice(block);
+                        expected = expected.wrapping_add(1);
+                        errors = 0;
+                        ACK
+                    }
+                    // The sender missed the last ACK and sent the block again
+                    Some(number) if number == expected.wrapping_sub(1) => ACK,
+                    Some(_) => {
+                        cancel(link);
+                        return Err(XmodemError::OutOfSequence);
+                    }
+                    None => NAK,
+                }
+            }
+            _ => NAK,
+        };
+        if reply == NAK {
+            errors += 1;
+            if errors >= MAX_ERRORS {
+                cancel(link);
+                return Err(XmodemError::TooManyErrors);
+            }
+            // Let the rest of a bad block go by before asking again
+            while link.read_byte(BYTE_TIMEOUT_MS).is_some() {}
+        }
+        link.write_byte(reply);
+        header = link.read_byte(BLOCK_TIMEOUT_MS);
+    }
+}
+
+/// Ask for the first block, returns its header byte and whether blocks
+/// carry CRC-16
+fn start(link: &mut dyn Link) -> Result<(Option<u8>, bool), XmodemError> {
+    for attempt in 0..START_TRIES {
+        let crc = attempt < CRC_TRIES;
+        link.write_byte(if crc { CRC_REQUEST } else { NAK });
+        if let Some(header) = link.read_byte(START_TIMEOUT_MS) {
+            return Ok((Some(header), crc));
+        }
+    }
+    Err(XmodemError::NoSender)
+}
+
+/// Read the rest of a block after its header into `data`, returns the
+/// block number if it arrived intact
+fn read_block(link: &mut dyn Link, data: &mut [u8], crc: bool) -> Option<u8> {
+    let number = link.read_byte(BYTE_TIMEOUT_MS)?;
+    let complement = link.read_byte(BYTE_TIMEOUT_MS)?;
+    for byte in data.iter_mut() {
+        *byte = link.read_byte(BYTE_TIMEOUT_MS)?;
+    }
+    let intact = if crc {
+        let high = link.read_byte(BYTE_TIMEOUT_MS)?;
+        let low = link.read_byte(BYTE_TIMEOUT_MS)?;
+        crc16::checksum(data) == u16::from_be_bytes([high, low])
+    } else {
+        let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        link.read_byte(BYTE_TIMEOUT_MS)? == sum
+    };
+    (intact && number == !complement).then_some(number)
+}
+
+fn cancel(link: &mut dyn Link) {
+    link.write_byte(CAN);
+    link.write_byte(CAN);
+}
+
+/// Take the SUB bytes padding the last block off a received file
+///
+/// A file that really ends in SUB bytes loses them too; pass its length
+/// and truncate instead.
+pub fn trim_padding(data: &mut Vec<u8>) {
+    let len = data.iter().rposition(|&byte| byte != SUB).map_or(0, |last| last + 1);
+    data.truncate(len);
+}
+
+/// COM1, through `ttyS0` switched to raw mode, with log output kept off it
+pub struct SerialLink {
+    settings: Settings,
+    _quiet: console::Quiet,
+}
+
+impl SerialLink {
+    /// Take over COM1 until dropped
+    pub fn open() -> Self {
+        let settings = tty::TTY_S0.settings();
+        tty::TTY_S0.set_settings(Settings::RAW);
+        SerialLink { settings, _quiet: console::quiet(Sink::Serial) }
+    }
+}
+
+impl Link for SerialLink {
+    fn read_byte(&mut self, timeout_ms: u64) -> Option<u8> {
+        let cancel = CancelToken::with_timeout(timeout_ms);
+        let mut byte = [0u8; 1];
+        loop {
+            // Without the receive interrupt input only arrives by polling
+            let result = if tty::serial_irq_enabled() {
+                tty::TTY_S0.read_cancellable(&mut byte, &cancel)
+            } else {
+                tty::poll_serial();
+                tty::TTY_S0.try_read(&mut byte)
+            };
+            match result {
+                Ok(1) => return Some(byte[0]),
+                Err(TtyError::WouldBlock) if cancel.check().is_ok() => task::yield_now(),
+                _ => return None,
+            }
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        crate::serial::write_byte(byte);
+    }
+}
+
+impl Drop for SerialLink {
+    fn drop(&mut self) {
+        tty::TTY_S0.set_settings(self.settings);
+    }
+}
+
+/// Scripted link for the self-test, `None` entries stand for silence
+struct ScriptLink {
+    input: alloc::collections::VecDeque<Option<u8>>,
+    output: Vec<u8>,
+}
+
+impl Link for ScriptLink {
+    fn read_byte(&mut self, _timeout_ms: u64) -> Option<u8> {
+        self.input.pop_front().flatten()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+}
+
+/// Append a CRC-16 block to `script`, spoiling its CRC if `corrupt`
+fn script_block(script: &mut Vec<Option<u8>>, number: u8, data: &[u8], corrupt: bool) {
+    let crc = crc16::checksum(data) ^ corrupt as u16;
+    script.extend([if data.len() == 128 { SOH } else { STX }, number, !number].map(Some));
+    script.extend(data.iter().copied().map(Some));
+    script.extend(crc.to_be_bytes().map(Some));
+}
+
+/// A bad block is asked for again, a repeat is acknowledged and dropped,
+/// and both block sizes are taken
+fn selftest() -> Result<(), &'static str> {
+    let mut first = [SUB; 128];
+    first[..5].copy_from_slice(b"hello");
+    let second: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+
+    let mut script = Vec::new();
+    script_block(&mut script, 1, &first, true);
+    script.push(None);
+    script_block(&mut script, 1, &first, false);
+    script_block(&mut script, 1, &first, false);
+    script_block(&mut script, 2, &second, false);
+    script.push(Some(EOT));
+    let mut link = ScriptLink { input: script.into_iter().collect(), output: Vec::new() };
+    let data = receive(&mut link, 2048).map_err(|_| "transfer failed")?;
+    if link.output != [CRC_REQUEST, NAK, ACK, ACK, ACK, ACK] {
+        return Err("wrong answers to the sender");
+    }
+    if data.len() != 1152 || data[..128] != first || data[128..] != second[..] {
+        return Err("received data wrong");
+    }
+
+    let mut padded = first.to_vec();
+    trim_padding(&mut padded);
+    if padded != b"hello" {
+        return Err("padding not trimmed");
+    }
+
+    let mut link = ScriptLink { input: [Some(CAN), Some(CAN)].into_iter().collect(), output: Vec::new() };
+    if receive(&mut link, 2048) != Err(XmodemError::Cancelled) {
+        return Err("sender cancel not seen");
+    }
+    Ok(())
+}
+
+crate::selftest!("xmodem", selftest);
@@ -0,0 +1,446 @@
+//! ext2 formatter for the data partition
+//!
+//! Writes a single block group revision 1 volume with 4 KiB blocks, the
+//! layout the kernel's ext2 driver reads and writes, and copies a host
+//! directory tree into it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const BLOCK: usize = 4096;
+const LOG_BLOCK_SIZE: u32 = 2;
+/// One bitmap block covers this many blocks, the size limit of one group
+const BLOCKS_PER_GROUP: usize = BLOCK * 8;
+const INODE_SIZE: usize = 128;
+const INODES_PER_BLOCK: usize = BLOCK / INODE_SIZE;
+const ROOT_INO: u32 = 2;
+const LOST_FOUND_INO: u32 = 11;
+const FIRST_INO: u32 = 11;
+/// One inode per this many bytes of volume, as mke2fs defaults to
+const BYTES_PER_INODE: usize = 16384;
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const GDT_BLOCK: u32 = 1;
+const BLOCK_BITMAP: u32 = 2;
+const INODE_BITMAP: u32 = 3;
+const INODE_TABLE: u32 = 4;
+
+const EXT2_MAGIC: u16 = 0xEF53;
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+
+const DIRECT_BLOCKS: usize = 12;
+const POINTERS_PER_BLOCK: usize = BLOCK / 4;
+
+/// Host files to copy onto the volume
+pub enum Tree {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, Tree>),
+}
+
+impl Tree {
+    pub fn empty() -> Self {
+        Tree::Dir(BTreeMap::new())
+    }
+
+    /// Read a host directory recursively, skipping anything but regular
+    /// files and directories
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut entries = BTreeMap::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let kind = entry.file_type()?;
+            if kind.is_dir() {
+                entries.insert(name, Tree::load(&entry.path())?);
+            } else if kind.is_file() {
+                entries.insert(name, Tree::File(fs::read(entry.path())?));
+            } else {
+                eprintln!("  skipping {}: not a file or directory", entry.path().display());
+            }
+        }
+        Ok(Tree::Dir(entries))
+    }
+
+    fn count(&self) -> usize {
+        match self {
+            Tree::File(_) => 1,
+            Tree::Dir(entries) => 1 + entries.values().map(Tree::count).sum::<usize>(),
+        }
+    }
+}
+
+struct Volume<'a> {
+    image: &'a mut [u8],
+    blocks: u32,
+    next_block: u32,
+    next_ino: u32,
+    used_dirs: u16,
+    epoch: u32,
+}
+
+impl Volume<'_> {
+    fn alloc(&mut self) -> Result<u32, String> {
+        if self.next_block >= self.blocks {
+            return Err("data partition is full".into());
+        }
+        self.next_block += 1;
+        Ok(self.next_block - 1)
+    }
+
+    fn block_mut(&mut self, block: u32) -> &mut [u8] {
+        let offset = block as usize * BLOCK;
+        &mut self.image[offset..offset + BLOCK]
+    }
+
+    /// Store `data` in fresh blocks and return the inode's block map and
+    /// block count in 512-byte units
+    fn write_data(&mut self, data: &[u8]) -> Result<([u32; 15], u32), String> {
+        let mut map = [0u32; 15];
+        let mut allocated = 0;
+        let mut indirect = 0;
+        let mut double = 0;
+        let mut table = 0;
+        for (index, chunk) in data.chunks(BLOCK).enumerate() {
+            let block = self.alloc()?;
+            allocated += 1;
+            self.block_mut(block)[..chunk.len()].copy_from_slice(chunk);
+
+            if index < DIRECT_BLOCKS {
+                map[index] = block;
+                continue;
+            }
+            let index = index - DIRECT_BLOCKS;
+            let (pointers, slot) = if index < POINTERS_PER_BLOCK {
+                if indirect == 0 {
+                    indirect = self.alloc()?;
+                    allocated += 1;
+                    map[12] = indirect;
+                }
+                (indirect, index)
+            } else {
+                let index = index - POINTERS_PER_BLOCK;
+                if index >= POINTERS_PER_BLOCK * POINTERS_PER_BLOCK {
+                    return Err("file too large for doubly indirect blocks".into());
+                }
+                if double == 0 {
+                    double = self.alloc()?;
+                    allocated += 1;
+                    map[13] = double;
+                }
+                if index.is_multiple_of(POINTERS_PER_BLOCK) {
+                    table = self.alloc()?;
+                    allocated += 1;
+                    let slot = index / POINTERS_PER_BLOCK * 4;
+                    self.block_mut(double)[slot..slot + 4].copy_from_slice(&table.to_le_bytes());
+                }
+                (table, index % POINTERS_PER_BLOCK)
+            };
+            self.block_mut(pointers)[slot * 4..slot * 4 + 4].copy_from_slice(&block.to_le_bytes());
+        }
+        Ok((map, allocated * (BLOCK / 512) as u32))
+    }
+
+    fn write_inode(&mut self, ino: u32, mode: u16, size: u64, links: u16, map: [u32; 15], sectors: u32) {
+        let offset = INODE_TABLE as usize * BLOCK + (ino as usize - 1) * INODE_SIZE;
+        let inode = &mut self.image[offset..offset + INODE_SIZE];
+        inode[0..2].copy_from_slice(&mode.to_le_bytes());
+        inode[4..8].copy_from_slice(&(size as u32).to_le_bytes());
+        for time in [8, 12, 16] {
+            inode[time..time + 4].copy_from_slice(&self.epoch.to_le_bytes());
+        }
+        inode[26..28].copy_from_slice(&links.to_le_bytes());
+        inode[28..32].copy_from_slice(&sectors.to_le_bytes());
+        for (slot, block) in map.iter().enumerate() {
+            inode[40 + slot * 4..44 + slot * 4].copy_from_slice(&block.to_le_bytes());
+        }
+        // High half of the size of regular files
+        inode[108..112].copy_from_slice(&((size >> 32) as u32).to_le_bytes());
+    }
+
+    /// Write directory `ino` holding `entries`, then everything below it
+    fn write_dir(&mut self, ino: u32, parent: u32, entries: &BTreeMap<String, Tree>, lost_found: bool) -> Result<(), String> {
+        let mut children = Vec::new();
+        if lost_found {
+            children.push(("lost+found".to_string(), LOST_FOUND_INO, None));
+        }
+        for (name, tree) in entries {
+            if name.len() > 255 {
+                return Err(format!("{} is longer than 255 bytes", name));
+            }
+            children.push((name.clone(), self.next_ino, Some(tree)));
+            self.next_ino += 1;
+        }
+
+        let mut listing: Vec<(&str, u32, u8)> = vec![(".", ino, FT_DIR), ("..", parent, FT_DIR)];
+        for (name, child, tree) in &children {
+            let kind = match tree {
+                Some(Tree::File(_)) => FT_REG_FILE,
+                _ => FT_DIR,
+            };
+            listing.push((name, *child, kind));
+        }
+        let data = pack_dirents(&listing);
+        let (map, sectors) = self.write_data(&data)?;
+        let subdirs = listing[2..].iter().filter(|entry| entry.2 == FT_DIR).count();
+        let links = u16::try_from(2 + subdirs).map_err(|_| "too many subdirectories".to_string())?;
+        self.write_inode(ino, S_IFDIR | 0o755, data.len() as u64, links, map, sectors);
+        self.used_dirs += 1;
+
+        for (_, child, tree) in children {
+            match tree {
+                None => self.write_dir(child, ino, &BTreeMap::new(), false)?,
+                Some(Tree::Dir(entries)) => self.write_dir(child, ino, entries, false)?,
+                Some(Tree::File(contents)) => {
+                    let (map, sectors) = self.write_data(contents)?;
+                    self.write_inode(child, S_IFREG | 0o644, contents.len() as u64, 1, map, sectors);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lay out directory entries in blocks, the last entry of each block
+/// stretching to its end
+fn pack_dirents(entries: &[(&str, u32, u8)]) -> Vec<u8> {
+    let mut data = vec![0u8; BLOCK];
+    let mut block_start = 0;
+    let mut offset = 0;
+    let mut last = 0;
+    for &(name, ino, kind) in entries {
+        let len = (8 + name.len()).next_multiple_of(4);
+        if offset + len > block_start + BLOCK {
+            // Stretch the previous entry over the rest of its block
+            let rec_len = (block_start + BLOCK - last) as u16;
+            data[last + 4..last + 6].copy_from_slice(&rec_len.to_le_bytes());
+            block_start += BLOCK;
+            offset = block_start;
+            data.resize(block_start + BLOCK, 0);
+        }
+        data[offset..offset + 4].copy_from_slice(&ino.to_le_bytes());
+        data[offset + 4..offset + 6].copy_from_slice(&(len as u16).to_le_bytes());
+        data[offset + 6] = name.len() as u8;
+        data[offset + 7] = kind;
+        data[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+        last = offset;
+        offset += len;
+    }
+    let rec_len = (block_start + BLOCK - last) as u16;
+    data[last + 4..last + 6].copy_from_slice(&rec_len.to_le_bytes());
+    data
+}
+
+fn set_bits(bitmap: &mut [u8], bits: std::ops::Range<usize>) {
+    for bit in bits {
+        bitmap[bit / 8] |= 1 << (bit % 8);
+    }
+}
+
+/// Format `image` as ext2 holding `tree`, stamping `epoch` on every inode
+pub fn format(image: &mut [u8], label: &str, uuid: [u8; 16], tree: &Tree, epoch: u64) -> Result<(), String> {
+    let blocks = image.len() / BLOCK;
+    if blocks > BLOCKS_PER_GROUP {
+        return Err(format!("data partition over {} MiB needs several block groups", (BLOCKS_PER_GROUP * BLOCK) >> 20));
+    }
+    let Tree::Dir(root) = tree else {
+        return Err("data partition root must be a directory".into());
+    };
+    let inodes = (image.len() / BYTES_PER_INODE)
+        .max(FIRST_INO as usize + 1 + tree.count())
+        .next_multiple_of(INODES_PER_BLOCK);
+    let inode_blocks = inodes / INODES_PER_BLOCK;
+    if inodes > BLOCK * 8 || INODE_TABLE as usize + inode_blocks >= blocks {
+        return Err("data partition too small for its inode table".into());
+    }
+
+    image.fill(0);
+    let mut volume = Volume {
+        image,
+        blocks: blocks as u32,
+        next_block: INODE_TABLE + inode_blocks as u32,
+        next_ino: FIRST_INO + 1,
+        used_dirs: 0,
+        epoch: epoch as u32,
+    };
+    volume.write_dir(ROOT_INO, ROOT_INO, root, true)?;
+    let used_blocks = volume.next_block as usize;
+    let used_inodes = volume.next_ino as usize - 1;
+    let used_dirs = volume.used_dirs;
+
+    let bitmap = volume.block_mut(BLOCK_BITMAP);
+    set_bits(bitmap, 0..used_blocks);
+    // Bits past the end of the volume are permanently in use
+    set_bits(bitmap, blocks..BLOCK * 8);
+    let bitmap = volume.block_mut(INODE_BITMAP);
+    set_bits(bitmap, 0..used_inodes);
+    set_bits(bitmap, inodes..BLOCK * 8);
+
+    let free_blocks = (blocks - used_blocks) as u32;
+    let free_inodes = (inodes - used_inodes) as u32;
+    let gdt = volume.block_mut(GDT_BLOCK);
+    gdt[0..4].copy_from_slice(&BLOCK_BITMAP.to_le_bytes());
+    gdt[4..8].copy_from_slice(&INODE_BITMAP.to_le_bytes());
+    gdt[8..12].copy_from_slice(&INODE_TABLE.to_le_bytes());
+    gdt[12..14].copy_from_slice(&(free_blocks as u16).to_le_bytes());
+    gdt[14..16].copy_from_slice(&(free_inodes as u16).to_le_bytes());
+    gdt[16..18].copy_from_slice(&used_dirs.to_le_bytes());
+
+    let sb = &mut volume.image[SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + 1024];
+    let fields: [(usize, u32); 16] = [
+        (0, inodes as u32),
+        (4, blocks as u32),
+        (12, free_blocks),
+        (16, free_inodes),
+        (20, 0),
+        (24, LOG_BLOCK_SIZE),
+        (28, LOG_BLOCK_SIZE),
+        (32, BLOCKS_PER_GROUP as u32),
+        (36, BLOCKS_PER_GROUP as u32),
+        (40, inodes as u32),
+        (48, epoch as u32),
+        (64, epoch as u32),
+        (76, 1),
+        (84, FIRST_INO),
+        (96, INCOMPAT_FILETYPE),
+        (100, 0),
+    ];
+    for (offset, value) in fields {
+        sb[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    // Unlimited mounts between checks, clean state, continue on errors
+    sb[54..56].copy_from_slice(&0xFFFFu16.to_le_bytes());
+    sb[56..58].copy_from_slice(&EXT2_MAGIC.to_le_bytes());
+    sb[58..60].copy_from_slice(&1u16.to_le_bytes());
+    sb[60..62].copy_from_slice(&1u16.to_le_bytes());
+    sb[88..90].copy_from_slice(&(INODE_SIZE as u16).to_le_bytes());
+    sb[104..120].copy_from_slice(&uuid);
+    for (slot, byte) in sb[120..136].iter_mut().zip(label.bytes()) {
+        *slot = byte;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spans the direct blocks into the indirect block
+    const BIG: usize = (DIRECT_BLOCKS + 2) * BLOCK + 100;
+
+    fn le16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn le32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn formatted() -> Vec<u8> {
+        let bin = Tree::Dir(BTreeMap::from([("big".to_string(), Tree::File(contents(BIG)))]));
+        let tree = Tree::Dir(BTreeMap::from([
+            ("bin".to_string(), bin),
+            ("hello".to_string(), Tree::File(b"hello".to_vec())),
+        ]));
+        let mut image = vec![0u8; 8 << 20];
+        format(&mut image, "test", [7; 16], &tree, 1_000_000).unwrap();
+        image
+    }
+
+    fn block(image: &[u8], block: u32) -> &[u8] {
+        &image[block as usize * BLOCK..(block as usize + 1) * BLOCK]
+    }
+
+    fn inode(image: &[u8], ino: u32) -> &[u8] {
+        let offset = INODE_TABLE as usize * BLOCK + (ino as usize - 1) * INODE_SIZE;
+        &image[offset..offset + INODE_SIZE]
+    }
+
+    /// Clear bits among the first `count` of a bitmap block
+    fn clear_bits(bitmap: &[u8], count: usize) -> u32 {
+        (0..count).filter(|&bit| bitmap[bit / 8] & (1 << (bit % 8)) == 0).count() as u32
+    }
+
+    /// Inode of `name` in directory `ino`, walking its first block
+    fn find(image: &[u8], ino: u32, name: &str) -> u32 {
+        let data = block(image, le32(inode(image, ino), 40));
+        let mut offset = 0;
+        while offset < BLOCK {
+            let len = data[offset + 6] as usize;
+            if &data[offset + 8..offset + 8 + len] == name.as_bytes() {
+                return le32(data, offset);
+            }
+            offset += le16(data, offset + 4) as usize;
+        }
+        panic!("{} not found", name);
+    }
+
+    /// File data through the direct and indirect pointers
+    fn read_file(image: &[u8], ino: u32) -> Vec<u8> {
+        let inode = inode(image, ino);
+        let size = le32(inode, 4) as usize;
+        let indirect = le32(inode, 40 + 12 * 4);
+        let mut data = Vec::new();
+        for index in 0..size.div_ceil(BLOCK) {
+            let physical = if index < DIRECT_BLOCKS {
+                le32(inode, 40 + index * 4)
+            } else {
+                le32(block(image, indirect), (index - DIRECT_BLOCKS) * 4)
+            };
+            data.extend_from_slice(block(image, physical));
+        }
+        data.truncate(size);
+        data
+    }
+
+    #[test]
+    fn superblock_and_group_descriptor_agree_with_bitmaps() {
+        let image = formatted();
+        let sb = &image[SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + 1024];
+        assert_eq!(le16(sb, 56), EXT2_MAGIC);
+        assert_eq!((le32(sb, 24), le32(sb, 76), le32(sb, 84)), (LOG_BLOCK_SIZE, 1, FIRST_INO));
+        assert_eq!(le16(sb, 88) as usize, INODE_SIZE);
+        assert_eq!(le32(sb, 96), INCOMPAT_FILETYPE);
+        assert_eq!(le32(sb, 20), 0, "first data block of a 4 KiB volume");
+        let blocks = le32(sb, 4) as usize;
+        let inodes = le32(sb, 0) as usize;
+        assert_eq!(blocks, image.len() / BLOCK);
+        assert_eq!(le32(sb, 40) as usize, inodes, "one group holds every inode");
+        assert_eq!(&sb[120..124], b"test");
+
+        let gdt = block(&image, GDT_BLOCK);
+        assert_eq!((le32(gdt, 0), le32(gdt, 4), le32(gdt, 8)), (BLOCK_BITMAP, INODE_BITMAP, INODE_TABLE));
+        let free_blocks = clear_bits(block(&image, BLOCK_BITMAP), blocks);
+        let free_inodes = clear_bits(block(&image, INODE_BITMAP), inodes);
+        assert_eq!((le32(sb, 12), le16(gdt, 12) as u32), (free_blocks, free_blocks));
+        assert_eq!((le32(sb, 16), le16(gdt, 14) as u32), (free_inodes, free_inodes));
+        // Root, lost+found and bin
+        assert_eq!(le16(gdt, 16), 3);
+        assert_eq!(clear_bits(block(&image, BLOCK_BITMAP), BLOCK * 8), free_blocks, "bits past the volume set");
+    }
+
+    #[test]
+    fn tree_is_readable() {
+        let image = formatted();
+        assert_eq!(find(&image, ROOT_INO, "lost+found"), LOST_FOUND_INO);
+        assert_eq!(find(&image, ROOT_INO, ".."), ROOT_INO);
+        let bin = find(&image, ROOT_INO, "bin");
+        assert_eq!(le16(inode(&image, bin), 0) & 0xF000, S_IFDIR);
+        assert_eq!(le16(inode(&image, ROOT_INO), 26), 4, "root links");
+        assert_eq!(read_file(&image, find(&image, ROOT_INO, "hello")), b"hello");
+        let big = find(&image, bin, "big");
+        assert_eq!(read_file(&image, big), contents(BIG));
+        // Data blocks plus the indirect block
+        let blocks = BIG.div_ceil(BLOCK) + 1;
+        assert_eq!(le32(inode(&image, big), 28) as usize, blocks * BLOCK / 512);
+    }
+}
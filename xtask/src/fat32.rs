@@ -0,0 +1,390 @@
+//! FAT32 formatter for the EFI System Partition
+//!
+//! Lays out a fresh volume with one sector per cluster and writes a fixed
+//! set of files into it. Names must fit 8.3; all-lowercase names keep their
+//! case through the NT case flags, which is how `kernel.bin` stays lowercase.
+
+use std::collections::BTreeMap;
+
+const SECTOR: usize = 512;
+const RESERVED_SECTORS: usize = 32;
+const FAT_COUNT: usize = 2;
+const ROOT_CLUSTER: u32 = 2;
+const FSINFO_SECTOR: usize = 1;
+const BACKUP_BOOT_SECTOR: usize = 6;
+/// Fewer clusters than this make the volume FAT16 by definition
+const MIN_CLUSTERS: usize = 65525;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+const DIR_ENTRY: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// NT case flags: base name and extension stored lowercase
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+#[derive(Default)]
+struct Dir {
+    dirs: BTreeMap<String, Dir>,
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+/// Volume being written, clusters are handed out in order
+struct Volume<'a> {
+    image: &'a mut [u8],
+    fat: Vec<u32>,
+    next_cluster: u32,
+    data_start: usize,
+    date: u16,
+    time: u16,
+}
+
+impl Volume<'_> {
+    /// Allocate a contiguous chain for `len` bytes, 0 for an empty file
+    fn alloc(&mut self, len: usize) -> Result<u32, String> {
+        let count = len.div_ceil(SECTOR) as u32;
+        if count == 0 {
+            return Ok(0);
+        }
+        let first = self.next_cluster;
+        if (first + count) as usize > self.fat.len() {
+            return Err("ESP is full".into());
+        }
+        for cluster in first..first + count {
+            self.fat[cluster as usize] = if cluster + 1 == first + count { END_OF_CHAIN } else { cluster + 1 };
+        }
+        self.next_cluster += count;
+        Ok(first)
+    }
+
+    fn write(&mut self, cluster: u32, data: &[u8]) {
+        let offset = self.data_start + (cluster as usize - 2) * SECTOR;
+        self.image[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    fn entry(&self, name: [u8; 11], case: u8, attr: u8, cluster: u32, size: u32) -> [u8; DIR_ENTRY] {
+        let mut entry = [0u8; DIR_ENTRY];
+        entry[0..11].copy_from_slice(&name);
+        entry[11] = attr;
+        entry[12] = case;
+        for offset in [14, 22] {
+            entry[offset..offset + 2].copy_from_slice(&self.time.to_le_bytes());
+        }
+        for offset in [16, 18, 24] {
+            entry[offset..offset + 2].copy_from_slice(&self.date.to_le_bytes());
+        }
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    /// Write `dir` and everything below it, returning its first cluster
+    fn write_dir(&mut self, dir: &Dir, parent: Option<u32>, label: &str) -> Result<u32, String> {
+        // `.` and `..` in subdirectories, the volume label in the root
+        let count = 2 + dir.dirs.len() + dir.files.len();
+        let cluster = self.alloc(count * DIR_ENTRY)?;
+        let mut entries = Vec::with_capacity(count * DIR_ENTRY);
+        match parent {
+            Some(parent) => {
+                entries.extend(self.entry(*b".          ", 0, ATTR_DIRECTORY, cluster, 0));
+                // The root is cluster 0 in `..` entries
+                let parent = if parent == ROOT_CLUSTER { 0 } else { parent };
+                entries.extend(self.entry(*b"..         ", 0, ATTR_DIRECTORY, parent, 0));
+            }
+            None => entries.extend(self.entry(padded_label(label), 0, ATTR_VOLUME_ID, 0, 0)),
+        }
+        for (name, child) in &dir.dirs {
+            let (short, case) = short_name(name)?;
+            let child_cluster = self.write_dir(child, Some(cluster), label)?;
+            entries.extend(self.entry(short, case, ATTR_DIRECTORY, child_cluster, 0));
+        }
+        for (name, data) in &dir.files {
+            let (short, case) = short_name(name)?;
+            let size = u32::try_from(data.len()).map_err(|_| format!("{} is over 4 GiB", name))?;
+            let file_cluster = self.alloc(data.len())?;
+            if file_cluster != 0 {
+                self.write(file_cluster, data);
+            }
+            entries.extend(self.entry(short, case, ATTR_ARCHIVE, file_cluster, size));
+        }
+        self.write(cluster, &entries);
+        Ok(cluster)
+    }
+}
+
+/// Format `image` as FAT32 holding `files`, given as `/`-separated paths
+///
+/// `hidden_sectors` is the partition's first LBA on the disk and `epoch`
+/// the Unix time stamped on every entry.
+pub fn format(image: &mut [u8], hidden_sectors: u64, label: &str, files: &[(&str, &[u8])], epoch: u64) -> Result<(), String> {
+    let total = image.len() / SECTOR;
+    // The FAT covers the clusters that remain after the FATs themselves
+    let mut fat_sectors = 1;
+    loop {
+        let clusters = total - RESERVED_SECTORS - FAT_COUNT * fat_sectors;
+        let needed = ((clusters + 2) * 4).div_ceil(SECTOR);
+        if needed <= fat_sectors {
+            break;
+        }
+        fat_sectors = needed;
+    }
+    let data_start = RESERVED_SECTORS + FAT_COUNT * fat_sectors;
+    let clusters = total - data_start;
+    if clusters < MIN_CLUSTERS {
+        return Err(format!("ESP of {} sectors is too small for FAT32", total));
+    }
+
+    let mut root = Dir::default();
+    for (path, data) in files {
+        let mut dir = &mut root;
+        let mut parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        let name = parts.pop().ok_or("empty ESP path")?;
+        for part in parts {
+            dir = dir.dirs.entry(part.into()).or_default();
+        }
+        dir.files.insert(name.into(), data.to_vec());
+    }
+
+    image.fill(0);
+    let (date, time) = dos_timestamp(epoch);
+    let mut volume = Volume {
+        image,
+        fat: vec![0; clusters + 2],
+        next_cluster: ROOT_CLUSTER,
+        data_start: data_start * SECTOR,
+        date,
+        time,
+    };
+    volume.fat[0] = 0x0FFF_FFF8;
+    volume.fat[1] = END_OF_CHAIN;
+    volume.write_dir(&root, None, label)?;
+    let used = volume.next_cluster as usize - 2;
+
+    let mut fat = Vec::with_capacity(fat_sectors * SECTOR);
+    for entry in &volume.fat {
+        fat.extend_from_slice(&entry.to_le_bytes());
+    }
+    for copy in 0..FAT_COUNT {
+        let offset = (RESERVED_SECTORS + copy * fat_sectors) * SECTOR;
+        image[offset..offset + fat.len()].copy_from_slice(&fat);
+    }
+
+    let boot = boot_sector(total, fat_sectors, hidden_sectors, label, epoch);
+    let mut fsinfo = [0u8; SECTOR];
+    fsinfo[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    fsinfo[488..492].copy_from_slice(&((clusters - used) as u32).to_le_bytes());
+    fsinfo[492..496].copy_from_slice(&(used as u32 + 2).to_le_bytes());
+    fsinfo[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+    for base in [0, BACKUP_BOOT_SECTOR] {
+        image[base * SECTOR..(base + 1) * SECTOR].copy_from_slice(&boot);
+        let offset = (base + FSINFO_SECTOR) * SECTOR;
+        image[offset..offset + SECTOR].copy_from_slice(&fsinfo);
+    }
+    Ok(())
+}
+
+fn boot_sector(total: usize, fat_sectors: usize, hidden_sectors: u64, label: &str, epoch: u64) -> [u8; SECTOR] {
+    let mut boot = [0u8; SECTOR];
+    boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"COSMOS  ");
+    boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    boot[16] = FAT_COUNT as u8;
+    boot[21] = 0xF8;
+    boot[24..26].copy_from_slice(&32u16.to_le_bytes());
+    boot[26..28].copy_from_slice(&64u16.to_le_bytes());
+    boot[28..32].copy_from_slice(&(hidden_sectors as u32).to_le_bytes());
+    boot[32..36].copy_from_slice(&(total as u32).to_le_bytes());
+    boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+    boot[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+    boot[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+    boot[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    boot[67..71].copy_from_slice(&(epoch as u32).to_le_bytes());
+    boot[71..82].copy_from_slice(&padded_label(label));
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    boot
+}
+
+fn padded_label(label: &str) -> [u8; 11] {
+    let mut padded = [b' '; 11];
+    for (slot, byte) in padded.iter_mut().zip(label.bytes()) {
+        *slot = byte.to_ascii_uppercase();
+    }
+    padded
+}
+
+/// 8.3 directory name and NT case flags for `name`
+fn short_name(name: &str) -> Result<([u8; 11], u8), String> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let valid = |part: &str| part.bytes().all(|b| b.is_ascii_alphanumeric() || b"_-~!#$%&".contains(&b));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || !valid(base) || !valid(ext) {
+        return Err(format!("{} is not an 8.3 name", name));
+    }
+    let mut case = 0;
+    for (part, flag) in [(base, CASE_LOWER_BASE), (ext, CASE_LOWER_EXT)] {
+        let lower = part.bytes().any(|b| b.is_ascii_lowercase());
+        let upper = part.bytes().any(|b| b.is_ascii_uppercase());
+        if lower && upper {
+            return Err(format!("{} mixes case, which needs long file names", name));
+        }
+        if lower {
+            case |= flag;
+        }
+    }
+    let mut short = [b' '; 11];
+    for (slot, byte) in short[..8].iter_mut().zip(base.bytes()) {
+        *slot = byte.to_ascii_uppercase();
+    }
+    for (slot, byte) in short[8..].iter_mut().zip(ext.bytes()) {
+        *slot = byte.to_ascii_uppercase();
+    }
+    Ok((short, case))
+}
+
+/// DOS date and time for a Unix time, clamped to the 1980 FAT epoch
+fn dos_timestamp(epoch: u64) -> (u16, u16) {
+    let (year, month, day) = civil_from_days((epoch / 86400) as i64);
+    if year < 1980 {
+        return ((1 << 5) | 1, 0);
+    }
+    let seconds = epoch % 86400;
+    let date = (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = (((seconds / 3600) as u16) << 11) | ((((seconds / 60) % 60) as u16) << 5) | ((seconds % 60) / 2) as u16;
+    (date, time)
+}
+
+/// Year, month and day for days since the Unix epoch
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILES: [(&str, usize); 3] = [("EFI/BOOT/BOOTX64.EFI", 1500), ("kernel.bin", 600), ("empty", 0)];
+
+    fn le16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn le32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn formatted() -> Vec<u8> {
+        let data: Vec<(&str, Vec<u8>)> = FILES.iter().map(|&(path, len)| (path, contents(len))).collect();
+        let files: Vec<(&str, &[u8])> = data.iter().map(|(path, data)| (*path, &data[..])).collect();
+        let mut image = vec![0u8; 64 << 20];
+        format(&mut image, 2048, "TEST ESP", &files, 0).unwrap();
+        image
+    }
+
+    /// Clusters of the chain starting at `first`, read from the first FAT
+    fn chain(image: &[u8], first: u32) -> Vec<u32> {
+        let fat = RESERVED_SECTORS * SECTOR;
+        let mut clusters = vec![first];
+        loop {
+            let next = le32(image, fat + *clusters.last().unwrap() as usize * 4);
+            if next == END_OF_CHAIN {
+                return clusters;
+            }
+            assert!(next >= ROOT_CLUSTER && clusters.len() < 1 << 16, "broken chain from {}", first);
+            clusters.push(next);
+        }
+    }
+
+    fn data_start(image: &[u8]) -> usize {
+        (RESERVED_SECTORS + FAT_COUNT * le32(image, 36) as usize) * SECTOR
+    }
+
+    /// Data of every cluster in the chain starting at `first`
+    fn read_chain(image: &[u8], first: u32) -> Vec<u8> {
+        let start = data_start(image);
+        chain(image, first)
+            .iter()
+            .flat_map(|&cluster| {
+                let offset = start + (cluster as usize - 2) * SECTOR;
+                image[offset..offset + SECTOR].to_vec()
+            })
+            .collect()
+    }
+
+    /// Directory entry for `path` as (first cluster, size, case flags)
+    fn lookup(image: &[u8], path: &str) -> (u32, u32, u8) {
+        let mut cluster = ROOT_CLUSTER;
+        let mut found = (ROOT_CLUSTER, 0, 0);
+        for part in path.split('/') {
+            let (short, _) = short_name(part).unwrap();
+            let dir = read_chain(image, cluster);
+            let entry = dir.chunks_exact(DIR_ENTRY).find(|entry| entry[0..11] == short).unwrap();
+            cluster = (le16(entry, 20) as u32) << 16 | le16(entry, 26) as u32;
+            found = (cluster, le32(entry, 28), entry[12]);
+        }
+        found
+    }
+
+    #[test]
+    fn boot_sector_describes_fat32() {
+        let image = formatted();
+        assert_eq!(&image[510..512], &[0x55, 0xAA]);
+        assert_eq!(&image[82..90], b"FAT32   ");
+        assert_eq!(le32(&image, 28), 2048);
+        assert_eq!(le32(&image, 32) as usize, image.len() / SECTOR);
+        assert_eq!(image[..SECTOR], image[BACKUP_BOOT_SECTOR * SECTOR..(BACKUP_BOOT_SECTOR + 1) * SECTOR]);
+        let clusters = image.len() / SECTOR - data_start(&image) / SECTOR;
+        assert!(clusters >= MIN_CLUSTERS);
+        assert!((le32(&image, 36) as usize) * SECTOR >= (clusters + 2) * 4);
+    }
+
+    #[test]
+    fn chains_hold_the_files() {
+        let image = formatted();
+        let fat = RESERVED_SECTORS * SECTOR;
+        let fat_len = le32(&image, 36) as usize * SECTOR;
+        assert_eq!(image[fat..fat + fat_len], image[fat + fat_len..fat + 2 * fat_len], "FAT copies differ");
+        assert_eq!((le32(&image, fat), le32(&image, fat + 4)), (0x0FFF_FFF8, END_OF_CHAIN));
+        assert_eq!(chain(&image, ROOT_CLUSTER), [ROOT_CLUSTER]);
+
+        for (path, len) in FILES {
+            let (first, size, _) = lookup(&image, path);
+            assert_eq!(size as usize, len, "{}", path);
+            if len == 0 {
+                assert_eq!(first, 0, "empty file has a cluster");
+                continue;
+            }
+            assert_eq!(chain(&image, first).len(), len.div_ceil(SECTOR), "{}", path);
+            let data = read_chain(&image, first);
+            assert_eq!(data[..len], contents(len)[..], "{}", path);
+        }
+    }
+
+    #[test]
+    fn lowercase_names_keep_their_case() {
+        let image = formatted();
+        assert_eq!(lookup(&image, "kernel.bin").2, CASE_LOWER_BASE | CASE_LOWER_EXT);
+        assert_eq!(lookup(&image, "EFI/BOOT/BOOTX64.EFI").2, 0);
+        assert!(short_name("Kernel.bin").is_err());
+        assert!(short_name("toolongname.bin").is_err());
+    }
+}
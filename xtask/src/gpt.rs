@@ -0,0 +1,192 @@
+//! GUID Partition Table
+
+use std::ops::Range;
+
+pub const SECTOR: u64 = 512;
+/// Partition entries in each table, the minimum the spec allows
+const ENTRY_COUNT: u64 = 128;
+const ENTRY_SIZE: u64 = 128;
+/// Sectors of one partition entry array
+const TABLE_SECTORS: u64 = ENTRY_COUNT * ENTRY_SIZE / SECTOR;
+const HEADER_SIZE: usize = 92;
+
+/// EFI System Partition
+pub const ESP_TYPE: Guid = Guid::parse("C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+/// Linux filesystem data
+pub const LINUX_DATA_TYPE: Guid = Guid::parse("0FC63DAF-8483-4772-8E79-3D69D8477DE4");
+
+/// GUID in its mixed-endian on-disk layout
+#[derive(Clone, Copy)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    /// Parse the canonical `XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX` form
+    pub const fn parse(text: &str) -> Self {
+        let text = text.as_bytes();
+        let mut raw = [0u8; 16];
+        let mut nibbles = 0;
+        let mut i = 0;
+        while i < text.len() {
+            let digit = match text[i] {
+                b'0'..=b'9' => text[i] - b'0',
+                b'A'..=b'F' => text[i] - b'A' + 10,
+                b'a'..=b'f' => text[i] - b'a' + 10,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            raw[nibbles / 2] |= digit << (4 * (1 - nibbles % 2));
+            nibbles += 1;
+            i += 1;
+        }
+        // The first three fields are stored little-endian
+        Guid([
+            raw[3], raw[2], raw[1], raw[0], raw[5], raw[4], raw[7], raw[6],
+            raw[8], raw[9], raw[10], raw[11], raw[12], raw[13], raw[14], raw[15],
+        ])
+    }
+
+    /// Version 4 style GUID derived from a name, so rebuilt images are
+    /// byte-identical
+    pub fn from_name(name: &str) -> Self {
+        let mut raw = [0u8; 16];
+        for (i, chunk) in raw.chunks_exact_mut(4).enumerate() {
+            let seed = format!("{}:{}", name, i);
+            chunk.copy_from_slice(&crc32(seed.as_bytes()).to_le_bytes());
+        }
+        raw[7] = (raw[7] & 0x0F) | 0x40;
+        raw[8] = (raw[8] & 0x3F) | 0x80;
+        Guid(raw)
+    }
+
+    pub fn bytes(&self) -> [u8; 16] {
+        self.0
+    }
+}
+
+pub struct Partition {
+    pub name: &'static str,
+    pub type_guid: Guid,
+    /// First and one past last sector
+    pub sectors: Range<u64>,
+}
+
+/// Write a protective MBR and primary and backup GPTs into `disk`
+pub fn write(disk: &mut [u8], disk_guid: Guid, partitions: &[Partition]) {
+    let total = disk.len() as u64 / SECTOR;
+    let last = total - 1;
+
+    // Protective MBR covering the whole disk with one 0xEE partition
+    let mbr = &mut disk[..SECTOR as usize];
+    let entry = &mut mbr[446..462];
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    entry[4] = 0xEE;
+    entry[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&(last.min(u32::MAX as u64) as u32).to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+
+    let mut table = vec![0u8; (ENTRY_COUNT * ENTRY_SIZE) as usize];
+    for (entry, partition) in table.chunks_exact_mut(ENTRY_SIZE as usize).zip(partitions) {
+        entry[0..16].copy_from_slice(&partition.type_guid.bytes());
+        entry[16..32].copy_from_slice(&Guid::from_name(partition.name).bytes());
+        entry[32..40].copy_from_slice(&partition.sectors.start.to_le_bytes());
+        entry[40..48].copy_from_slice(&(partition.sectors.end - 1).to_le_bytes());
+        for (i, unit) in partition.name.encode_utf16().take(36).enumerate() {
+            entry[56 + i * 2..58 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let table_crc = crc32(&table);
+
+    let first_usable = 2 + TABLE_SECTORS;
+    let last_usable = last - 1 - TABLE_SECTORS;
+    let backup_table = last - TABLE_SECTORS;
+    for (header_lba, other_lba, table_lba) in [(1, last, 2), (last, 1, backup_table)] {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&header_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&other_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&first_usable.to_le_bytes());
+        header[48..56].copy_from_slice(&last_usable.to_le_bytes());
+        header[56..72].copy_from_slice(&disk_guid.bytes());
+        header[72..80].copy_from_slice(&table_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRY_COUNT as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&table_crc.to_le_bytes());
+        let header_crc = crc32(&header);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+        let offset = (header_lba * SECTOR) as usize;
+        disk[offset..offset + HEADER_SIZE].copy_from_slice(&header);
+        let offset = (table_lba * SECTOR) as usize;
+        disk[offset..offset + table.len()].copy_from_slice(&table);
+    }
+}
+
+/// Sectors at the end of the disk taken by the backup table and header
+pub const fn backup_sectors() -> u64 {
+    TABLE_SECTORS + 1
+}
+
+/// CRC-32 (IEEE 802.3), as used by GPT headers and tables
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn le32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn le64(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn headers_and_tables_carry_their_crcs() {
+        let total = 4096;
+        let mut disk = vec![0u8; (total * SECTOR) as usize];
+        let partitions = [Partition { name: "EFI System", type_guid: ESP_TYPE, sectors: 2048..3072 }];
+        write(&mut disk, Guid::from_name("test-disk"), &partitions);
+        assert_eq!(&disk[510..512], &[0x55, 0xAA]);
+
+        for (header_lba, other_lba) in [(1, total - 1), (total - 1, 1)] {
+            let offset = (header_lba * SECTOR) as usize;
+            let mut header = disk[offset..offset + HEADER_SIZE].to_vec();
+            assert_eq!(&header[0..8], b"EFI PART");
+            assert_eq!(le64(&header, 24), header_lba);
+            assert_eq!(le64(&header, 32), other_lba);
+
+            let stored = le32(&header, 16);
+            header[16..20].fill(0);
+            assert_eq!(crc32(&header), stored, "header CRC at LBA {}", header_lba);
+
+            let table_lba = le64(&header, 72);
+            let table_len = (le32(&header, 80) * le32(&header, 84)) as usize;
+            let offset = (table_lba * SECTOR) as usize;
+            let table = &disk[offset..offset + table_len];
+            assert_eq!(crc32(table), le32(&header, 88), "entry CRC of the table at LBA {}", table_lba);
+            assert_eq!(&table[0..16], &ESP_TYPE.bytes());
+            assert_eq!((le64(table, 32), le64(table, 40)), (2048, 3071));
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! CosmOS build tasks
+//!
+//! `cargo xtask image` builds the kernel and UEFI bootloader and assembles
+//! a bootable GPT disk image: an EFI System Partition with
+//! `EFI/BOOT/BOOTX64.EFI` and `kernel.bin`, followed by an ext2 data
+//! partition filled from `rootfs/`. With `--bios` it builds the legacy
+//! image instead: the NASM stage 1 and 2 loaders followed by the kernel.
+//! The UEFI loader is built after the kernel with kernel.bin's SHA-256,
+//! which it checks before entering the kernel under Secure Boot.
+//! `run`, `test` and `debug` boot the image in QEMU (see [`qemu`]).
+//! Images are reproducible: GUIDs derive from fixed names and every time
+//! stamp comes from `SOURCE_DATE_EPOCH` (0 if unset).
+
+mod ext2;
+mod fat32;
+mod gpt;
+mod qemu;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+
+use cosmos_common::sha256;
+use gpt::{Guid, Partition, SECTOR};
+
+const KERNEL_TARGET: &str = "x86_64-unknown-none";
+const UEFI_TARGET: &str = "x86_64-unknown-uefi";
+
+const MIB: u64 = 1024 * 1024;
+/// Partitions start on 1 MiB boundaries
+const ALIGNMENT: u64 = MIB / SECTOR;
+const ESP_SIZE: u64 = 64 * MIB;
+const DATA_SIZE: u64 = 64 * MIB;
+
+/// BIOS image layout, as stage 2 expects it
+const BIOS_IMAGE_SIZE: usize = 64 * MIB as usize;
+const BIOS_STAGE2_OFFSET: usize = 512;
+const BIOS_KERNEL_OFFSET: usize = 66 * 512;
+
+/// NASM locations tried when it is not on PATH
+const NASM_PATHS: &[&str] = &["C:\\ProgramData\\chocolatey\\bin\\nasm.exe", "C:\\Program Files\\NASM\\nasm.exe"];
+
+type Result<T> = std::result::Result<T, String>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Firmware {
+    Uefi,
+    Bios,
+}
+
+#[derive(Clone)]
+struct Options {
+    release: bool,
+    no_build: bool,
+    firmware: Firmware,
+    rootfs: Option<PathBuf>,
+    output: Option<PathBuf>,
+    /// Also write the serial output here
+    serial_log: Option<PathBuf>,
+    /// Attach QEMU's debugcon, writing to this file
+    debugcon_log: Option<PathBuf>,
+    /// Shell script the kernel runs at boot as `/etc/rc`
+    rc: Option<PathBuf>,
+    /// Seconds before `test` gives up on the guest
+    timeout: u64,
+    gdb_port: u16,
+    /// Arguments after `--`, passed to QEMU
+    extra: Vec<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: cargo xtask <command> [options]");
+    eprintln!();
+    eprintln!("Commands:");
+    eprintln!("  build   Build the kernel, kernel.bin and the bootloader");
+    eprintln!("  image   Build, then assemble the disk image");
+    eprintln!("  run     Build the image and boot it in QEMU");
+    eprintln!("  test    Boot the image, run every self-test and exit with the result");
+    eprintln!("  debug   Boot the image paused, with a gdb stub");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --release          Release profile (default: dev)");
+    eprintln!("  --uefi             GPT image booted through OVMF (default)");
+    eprintln!("  --bios             Legacy image with the NASM stage 1 and 2 loaders");
+    eprintln!("  --no-build         Reuse the artifacts of a previous build");
+    eprintln!("  --rootfs <dir>     Data partition contents (default: rootfs/ if present)");
+    eprintln!("  -o <path>          Image path (default: next to the kernel)");
+    eprintln!("  --serial <path>    Also write the serial output to a file");
+    eprintln!("  --debugcon <path>  Attach debugcon, log and test records go to the file");
+    eprintln!("  --rc <path>        Shell script to run at boot as /etc/rc");
+    eprintln!("  --timeout <secs>   Time limit for test (default: {})", qemu::TEST_TIMEOUT);
+    eprintln!("  --gdb-port <port>  gdb stub port for debug (default: {})", qemu::GDB_PORT);
+    eprintln!("  -- <args>          Extra QEMU arguments");
+    exit(2);
+}
+
+fn parse_number<T: std::str::FromStr>(value: Option<String>) -> T {
+    value.and_then(|value| value.parse().ok()).unwrap_or_else(|| usage())
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let command = args.next().unwrap_or_else(|| usage());
+    let mut options = Options {
+        release: false,
+        no_build: false,
+        firmware: Firmware::Uefi,
+        rootfs: None,
+        output: None,
+        serial_log: None,
+        debugcon_log: None,
+        rc: None,
+        timeout: qemu::TEST_TIMEOUT,
+        gdb_port: qemu::GDB_PORT,
+        extra: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--release" => options.release = true,
+            "--no-build" => options.no_build = true,
+            "--uefi" => options.firmware = Firmware::Uefi,
+            "--bios" => options.firmware = Firmware::Bios,
+            "--serial" => options.serial_log = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--debugcon" => options.debugcon_log = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--rc" => options.rc = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--timeout" => options.timeout = parse_number(args.next()),
+            "--gdb-port" => options.gdb_port = parse_number(args.next()),
+            "--rootfs" => options.rootfs = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "-o" | "--output" => options.output = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--" => options.extra.extend(args.by_ref()),
+            _ => usage(),
+        }
+    }
+
+    let result = match command.as_str() {
+        "build" => build(&options).map(|_| ()),
+        "image" => image(&options).map(|_| ()),
+        "run" => image(&options).and_then(|image| qemu::run(&image, &options)),
+        "test" => image(&options).and_then(|image| qemu::test(&image, &options)),
+        "debug" => build(&options).and_then(|artifacts| {
+            let image = image(&Options { no_build: true, ..options.clone() })?;
+            qemu::debug(&image, &artifacts.kernel_elf, &options)
+        }),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+}
+
+/// Workspace root, the parent of this crate
+fn workspace() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap().to_path_buf()
+}
+
+fn profile_dir(options: &Options) -> &'static str {
+    if options.release { "release" } else { "debug" }
+}
+
+/// Paths of the build artifacts
+struct Artifacts {
+    kernel_elf: PathBuf,
+    kernel_bin: PathBuf,
+    /// BOOTX64.EFI for UEFI, the stage 1 and 2 binaries for BIOS
+    bootloader: Vec<PathBuf>,
+}
+
+fn build(options: &Options) -> Result<Artifacts> {
+    let root = workspace();
+    let target = root.join("target");
+    let kernel_dir = target.join(KERNEL_TARGET).join(profile_dir(options));
+    let kernel_elf = kernel_dir.join("cosmos");
+    let kernel_bin = kernel_dir.join("kernel.bin");
+    let bootloader = match options.firmware {
+        Firmware::Uefi => vec![target.join(UEFI_TARGET).join(profile_dir(options)).join("cosmosbootloader-uefi.efi")],
+        Firmware::Bios => vec![kernel_dir.join("stage1.bin"), kernel_dir.join("stage2.bin")],
+    };
+    let artifacts = Artifacts { kernel_elf: kernel_elf.clone(), kernel_bin: kernel_bin.clone(), bootloader };
+    if options.no_build {
+        return Ok(artifacts);
+    }
+
+    println!("[1/3] Building kernel...");
+    cargo_build(&root, &["--package", "cosmos", "--target", KERNEL_TARGET], &[], options)?;
+
+    println!("[2/3] Creating flat kernel binary...");
+    let objcopy = find_objcopy()?;
+    let status = Command::new(&objcopy)
+        .args(["-O", "binary"])
+        .arg(&kernel_elf)
+        .arg(&kernel_bin)
+        .status()
+        .map_err(|e| format!("{}: {}", objcopy.display(), e))?;
+    if !status.success() {
+        return Err("kernel conversion failed".into());
+    }
+
+    match options.firmware {
+        Firmware::Uefi => {
+            // The loader checks kernel.bin against this under Secure Boot
+            let digest = sha256::digest(&read(&kernel_bin)?);
+            let digest: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+            println!("[3/3] Building UEFI bootloader for kernel {}...", digest);
+            cargo_build(
+                &root,
+                &["--package", "cosmosbootloader", "--bin", "cosmosbootloader-uefi", "--target", UEFI_TARGET],
+                &[("COSMOS_KERNEL_SHA256", &digest)],
+                options,
+            )?;
+        }
+        Firmware::Bios => {
+            println!("[3/3] Assembling BIOS bootloader...");
+            let nasm = find_nasm()?;
+            for (source, output) in ["stage1.asm", "stage2.asm"].iter().zip(&artifacts.bootloader) {
+                let status = Command::new(&nasm)
+                    .args(["-f", "bin"])
+                    .arg(root.join("boot/src").join(source))
+                    .arg("-o")
+                    .arg(output)
+                    .status()
+                    .map_err(|e| format!("{}: {}", nasm.display(), e))?;
+                if !status.success() {
+                    return Err(format!("assembling {} failed", source));
+                }
+            }
+        }
+    }
+    Ok(artifacts)
+}
+
+fn cargo_build(root: &Path, args: &[&str], envs: &[(&str, &str)], options: &Options) -> Result<()> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let mut command = Command::new(cargo);
+    command.current_dir(root).arg("build").args(args).envs(envs.iter().copied());
+    if options.release {
+        command.arg("--release");
+    }
+    let status = command.status().map_err(|e| format!("cargo: {}", e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("cargo build {} failed", args.join(" ")))
+    }
+}
+
+/// llvm-objcopy from the llvm-tools component, or from PATH
+fn find_objcopy() -> Result<PathBuf> {
+    let name = if cfg!(windows) { "llvm-objcopy.exe" } else { "llvm-objcopy" };
+    let output = Command::new("rustc").args(["--print", "sysroot"]).output();
+    let host = Command::new("rustc").args(["--print", "host-tuple"]).output();
+    if let (Ok(sysroot), Ok(host)) = (output, host) {
+        let sysroot = String::from_utf8_lossy(&sysroot.stdout).trim().to_string();
+        let host = String::from_utf8_lossy(&host.stdout).trim().to_string();
+        let path = Path::new(&sysroot).join("lib/rustlib").join(host).join("bin").join(name);
+        if path.exists() {
+            return Ok(path);
+        }
+    }
+    if Command::new(name).arg("--version").output().is_ok() {
+        return Ok(name.into());
+    }
+    Err("llvm-objcopy not found, install it with: rustup component add llvm-tools-preview".into())
+}
+
+fn find_nasm() -> Result<PathBuf> {
+    if Command::new("nasm").arg("-v").output().is_ok() {
+        return Ok("nasm".into());
+    }
+    NASM_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.exists())
+        .ok_or_else(|| "NASM not found, install it from https://www.nasm.us/".into())
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Build and assemble the disk image, returning its path
+fn image(options: &Options) -> Result<PathBuf> {
+    let artifacts = build(options)?;
+    let kernel = read(&artifacts.kernel_bin)?;
+    let disk = match options.firmware {
+        Firmware::Uefi => uefi_image(options, &kernel, &read(&artifacts.bootloader[0])?)?,
+        Firmware::Bios => bios_image(&kernel, &read(&artifacts.bootloader[0])?, &read(&artifacts.bootloader[1])?)?,
+    };
+
+    let name = match options.firmware {
+        Firmware::Uefi => "cosmos.img",
+        Firmware::Bios => "bootimage-cosmos.bin",
+    };
+    let output = options.output.clone().unwrap_or_else(|| artifacts.kernel_bin.with_file_name(name));
+    fs::write(&output, &disk).map_err(|e| format!("{}: {}", output.display(), e))?;
+    println!("Image: {} ({} MiB)", output.display(), disk.len() as u64 / MIB);
+    Ok(output)
+}
+
+/// Stage 1 in the MBR, stage 2 in the following sectors, then the kernel
+fn bios_image(kernel: &[u8], stage1: &[u8], stage2: &[u8]) -> Result<Vec<u8>> {
+    if stage1.len() != 512 || BIOS_STAGE2_OFFSET + stage2.len() > BIOS_KERNEL_OFFSET {
+        return Err("BIOS stages do not fit their sectors".into());
+    }
+    if BIOS_KERNEL_OFFSET + kernel.len() > BIOS_IMAGE_SIZE {
+        return Err("kernel does not fit the BIOS image".into());
+    }
+    let mut disk = vec![0u8; BIOS_IMAGE_SIZE];
+    disk[..512].copy_from_slice(stage1);
+    disk[BIOS_STAGE2_OFFSET..BIOS_STAGE2_OFFSET + stage2.len()].copy_from_slice(stage2);
+    disk[BIOS_KERNEL_OFFSET..BIOS_KERNEL_OFFSET + kernel.len()].copy_from_slice(kernel);
+    Ok(disk)
+}
+
+/// GPT disk with the ESP and the ext2 data partition
+fn uefi_image(options: &Options, kernel: &[u8], bootloader: &[u8]) -> Result<Vec<u8>> {
+    let root = workspace();
+    let rootfs = options.rootfs.clone().or_else(|| Some(root.join("rootfs")).filter(|path| path.is_dir()));
+    let tree = match &rootfs {
+        Some(path) => ext2::Tree::load(path).map_err(|e| format!("{}: {}", path.display(), e))?,
+        None => ext2::Tree::empty(),
+    };
+    let epoch = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value.parse().map_err(|_| format!("SOURCE_DATE_EPOCH={} is not a number", value))?,
+        Err(_) => 0,
+    };
+
+    let esp = ALIGNMENT..ALIGNMENT + ESP_SIZE / SECTOR;
+    let data = esp.end..esp.end + DATA_SIZE / SECTOR;
+    let total = (data.end + gpt::backup_sectors()).next_multiple_of(ALIGNMENT);
+    let mut disk = vec![0u8; (total * SECTOR) as usize];
+
+    println!("Writing ESP ({} MiB)...", ESP_SIZE / MIB);
+    let files: [(&str, &[u8]); 2] = [("EFI/BOOT/BOOTX64.EFI", bootloader), ("kernel.bin", kernel)];
+    let region = &mut disk[(esp.start * SECTOR) as usize..(esp.end * SECTOR) as usize];
+    fat32::format(region, esp.start, "COSMOS ESP", &files, epoch)?;
+
+    match &rootfs {
+        Some(path) => println!("Writing data partition ({} MiB) from {}...", DATA_SIZE / MIB, path.display()),
+        None => println!("Writing empty data partition ({} MiB)...", DATA_SIZE / MIB),
+    }
+    let region = &mut disk[(data.start * SECTOR) as usize..(data.end * SECTOR) as usize];
+    ext2::format(region, "cosmos-data", Guid::from_name("cosmos-data-fs").bytes(), &tree, epoch)?;
+
+    let partitions = [
+        Partition { name: "EFI System", type_guid: gpt::ESP_TYPE, sectors: esp },
+        Partition { name: "CosmOS data", type_guid: gpt::LINUX_DATA_TYPE, sectors: data },
+    ];
+    gpt::write(&mut disk, Guid::from_name("cosmos-disk"), &partitions);
+    Ok(disk)
+}
@@ -0,0 +1,189 @@
+//! QEMU orchestration
+//!
+//! Every boot gets the `isa-debug-exit` device the kernel uses to report
+//! scripted results (see `kernel/src/qemu.rs`), serial on stdio, and OVMF
+//! for UEFI images. `--debugcon` adds the faster debugcon log channel, where
+//! `test` also leaves one JSON record per self-test. `test` asks the kernel for a self-test run through
+//! fw_cfg and turns the exit device's code into the process status;
+//! `debug` starts the guest paused with a gdb stub.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{Firmware, Options, Result};
+
+/// Seconds a self-test run may take before it counts as hung
+pub const TEST_TIMEOUT: u64 = 300;
+pub const GDB_PORT: u16 = 1234;
+
+/// Must match `qemu::DEBUG_EXIT_PORT` in the kernel
+const DEBUG_EXIT_DEVICE: &str = "isa-debug-exit,iobase=0xf4,iosize=0x04";
+/// Must match `qemu::SELFTEST_FILE` in the kernel
+const SELFTEST_FW_CFG: &str = "name=opt/cosmos/selftest,string=1";
+/// Must match `shell::script::FW_CFG_RC` in the kernel
+const RC_FW_CFG: &str = "opt/cosmos/rc";
+/// QEMU exit statuses for the kernel's `ExitCode::Success` and `Failure`
+const EXIT_SUCCESS: i32 = (0x10 << 1) | 1;
+const EXIT_FAILURE: i32 = (0x11 << 1) | 1;
+
+/// OVMF firmware locations tried when `OVMF_CODE` is unset
+const OVMF_PATHS: &[&str] = &[
+    "/usr/share/OVMF/OVMF_CODE.fd",
+    "/usr/share/OVMF/OVMF_CODE_4M.fd",
+    "/usr/share/edk2/x64/OVMF_CODE.fd",
+    "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+    "/usr/share/qemu/OVMF.fd",
+    "/usr/share/ovmf/OVMF.fd",
+    "/opt/homebrew/share/qemu/edk2-x86_64-code.fd",
+    "/usr/local/share/qemu/edk2-x86_64-code.fd",
+    "C:\\Program Files\\qemu\\share\\edk2-x86_64-code.fd",
+];
+
+fn find_ovmf() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("OVMF_CODE") {
+        return Ok(path.into());
+    }
+    OVMF_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.exists())
+        .ok_or_else(|| "OVMF firmware not found, set OVMF_CODE to its path".into())
+}
+
+/// QEMU command line shared by every mode, without the extra arguments
+fn command(image: &Path, options: &Options) -> Result<Command> {
+    let qemu = std::env::var("QEMU").unwrap_or_else(|_| "qemu-system-x86_64".into());
+    let mut command = Command::new(qemu);
+    if options.firmware == Firmware::Uefi {
+        let ovmf = find_ovmf()?;
+        command.arg("-drive").arg(format!("if=pflash,format=raw,readonly=on,file={}", ovmf.display()));
+    }
+    command
+        .arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()))
+        .args(["-serial", "stdio", "-m", "1024M"])
+        .args(["-device", DEBUG_EXIT_DEVICE]);
+    if let Some(path) = &options.debugcon_log {
+        command.arg("-debugcon").arg(format!("file:{}", path.display()));
+    }
+    if let Some(path) = &options.rc {
+        command.arg("-fw_cfg").arg(format!("name={},file={}", RC_FW_CFG, path.display()));
+    }
+    Ok(command)
+}
+
+/// Running QEMU and the thread teeing its serial output, if any
+struct Guest {
+    child: Child,
+    tee: Option<JoinHandle<()>>,
+}
+
+impl Guest {
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        let status = self.child.try_wait().map_err(|e| format!("waiting for QEMU: {}", e))?;
+        if status.is_some() {
+            self.join_tee();
+        }
+        Ok(status)
+    }
+
+    fn wait(&mut self) -> Result<ExitStatus> {
+        let status = self.child.wait().map_err(|e| format!("waiting for QEMU: {}", e))?;
+        self.join_tee();
+        Ok(status)
+    }
+
+    fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.wait();
+    }
+
+    /// Let the log catch up with everything QEMU wrote before exiting
+    fn join_tee(&mut self) {
+        if let Some(tee) = self.tee.take() {
+            let _ = tee.join();
+        }
+    }
+}
+
+/// Start QEMU, copying its serial output to the log file if one was asked for
+fn spawn(mut command: Command, options: &Options) -> Result<Guest> {
+    command.args(&options.extra);
+    let log = match &options.serial_log {
+        Some(path) => Some(File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?),
+        None => None,
+    };
+    if log.is_some() {
+        command.stdout(Stdio::piped());
+    }
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command.spawn().map_err(|e| format!("{}: {}", program, e))?;
+    let mut tee = None;
+    if let (Some(mut log), Some(mut stdout)) = (log, child.stdout.take()) {
+        tee = Some(thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok(count) = stdout.read(&mut buf) {
+                if count == 0 {
+                    break;
+                }
+                let _ = io::stdout().write_all(&buf[..count]);
+                let _ = io::stdout().flush();
+                let _ = log.write_all(&buf[..count]);
+            }
+        }));
+    }
+    Ok(Guest { child, tee })
+}
+
+/// Boot the image interactively
+pub fn run(image: &Path, options: &Options) -> Result<()> {
+    println!("Starting QEMU...");
+    let mut guest = spawn(command(image, options)?, options)?;
+    match guest.wait()?.code() {
+        Some(0) | Some(EXIT_SUCCESS) => Ok(()),
+        Some(EXIT_FAILURE) => Err("kernel reported failure through the exit device".into()),
+        status => Err(format!("QEMU exited with {:?}", status)),
+    }
+}
+
+/// Run every in-kernel self-test headless and report the result
+pub fn test(image: &Path, options: &Options) -> Result<()> {
+    println!("Running self-tests in QEMU (timeout {}s)...", options.timeout);
+    let mut command = command(image, options)?;
+    command.args(["-display", "none", "-no-reboot", "-fw_cfg", SELFTEST_FW_CFG]);
+    let mut guest = spawn(command, options)?;
+
+    let deadline = Instant::now() + Duration::from_secs(options.timeout);
+    let status = loop {
+        if let Some(status) = guest.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            guest.kill();
+            return Err(format!("self-tests did not finish within {}s", options.timeout));
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    match status.code() {
+        Some(EXIT_SUCCESS) => {
+            println!("Self-tests passed");
+            Ok(())
+        }
+        Some(EXIT_FAILURE) => Err("self-tests failed".into()),
+        code => Err(format!("QEMU exited with {:?} before the kernel reported a result", code)),
+    }
+}
+
+/// Boot the image paused with a gdb stub listening
+pub fn debug(image: &Path, kernel_elf: &Path, options: &Options) -> Result<()> {
+    let mut command = command(image, options)?;
+    command.arg("-gdb").arg(format!("tcp::{}", options.gdb_port)).arg("-S");
+    println!("QEMU paused, gdb stub on port {}. Attach with:", options.gdb_port);
+    println!("  gdb {} -ex \"target remote :{}\"", kernel_elf.display(), options.gdb_port);
+    spawn(command, options)?.wait()?;
+    Ok(())
+}